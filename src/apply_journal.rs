@@ -0,0 +1,334 @@
+#![allow(dead_code)]
+
+//! Write-ahead journal for applying a batch of sync operations. While a
+//! batch is open, written files go to fsynced staging files under
+//! `.syncmd/apply/` and deletions, renames and new directories are only
+//! recorded. Committing
+//! first saves the batch's steps as committed, and only then moves them into
+//! place, so after a crash a batch is either not applied at all, and its
+//! staging files are dropped on the next start, or committed and replayed to
+//! the end. Every step can be replayed, so the progress recorded after each
+//! one is not waited on to reach the disk.
+
+use crate::types::{FilePermissions, SyncError};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const APPLY_DIR_NAME: &str = "apply";
+const JOURNAL_FILE_NAME: &str = "journal.json";
+const STAGED_EXTENSION: &str = "staged";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Step {
+    /// Move the staging file of this name into place at `path`
+    Write { path: PathBuf, staged: String },
+    /// Move the file to the root's trash
+    Trash { path: PathBuf },
+    Rename { from: PathBuf, to: PathBuf },
+    /// Create the directory, with its parents
+    MkDir { path: PathBuf },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Journal {
+    steps: Vec<Step>,
+    /// Steps already applied
+    done: usize,
+}
+
+/// What became of a batch a crash interrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// It wasn't committed; none of its steps were applied
+    RolledBack,
+    /// It was committed; these steps were left to apply and now are
+    Replayed(usize),
+}
+
+/// Operations staged to be applied to a root together. Paths are relative
+/// to the root.
+pub struct ApplyBatch {
+    root: PathBuf,
+    dir: PathBuf,
+    steps: Vec<Step>,
+}
+
+fn apply_dir(root: &Path) -> PathBuf {
+    crate::state::dir(root).join(APPLY_DIR_NAME)
+}
+
+/// Whether a batch a crash interrupted is waiting for [`recover`].
+pub fn is_pending(root: &Path) -> bool {
+    apply_dir(root).join(JOURNAL_FILE_NAME).exists()
+}
+
+/// Makes a rename or a new file in `dir` survive a crash.
+fn sync_dir(dir: &Path) -> Result<(), SyncError> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn write_synced(path: &Path, content: &[u8]) -> Result<(), SyncError> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Replaces the file at `path` in one step, so it is never half-written.
+/// The new file gets `permissions`, or those of the file it replaces.
+pub fn write_atomic(
+    root: &Path,
+    path: &Path,
+    content: &[u8],
+    permissions: Option<&FilePermissions>,
+) -> Result<(), SyncError> {
+    let dir = apply_dir(root);
+    fs::create_dir_all(&dir)?;
+    let staged = dir.join(format!("{}.tmp", uuid::Uuid::new_v4()));
+    write_synced(&staged, content)?;
+    permissions_for(&root.join(path), permissions).apply(&staged)?;
+    move_into_place(root, &staged, path)
+}
+
+/// What replacing `target` gives the new file: `permissions`, or without
+/// them those `target` has.
+fn permissions_for(target: &Path, permissions: Option<&FilePermissions>) -> FilePermissions {
+    match permissions {
+        Some(permissions) => permissions.for_target(target),
+        None => fs::metadata(target).map(|existing| FilePermissions::of(&existing)).unwrap_or_default(),
+    }
+}
+
+fn move_into_place(root: &Path, staged: &Path, path: &Path) -> Result<(), SyncError> {
+    let target = root.join(path);
+    let parent = target.parent().unwrap_or(root);
+    fs::create_dir_all(parent)?;
+    if let Err(e) = fs::rename(staged, &target) {
+        let _ = fs::remove_file(staged);
+        return Err(e.into());
+    }
+    sync_dir(parent)
+}
+
+impl Journal {
+    fn save(&self, dir: &Path, synced: bool) -> Result<(), SyncError> {
+        let temp = dir.join(format!("{}.tmp", JOURNAL_FILE_NAME));
+        let content = serde_json::to_vec(self)?;
+        if synced {
+            write_synced(&temp, &content)?;
+        } else {
+            fs::write(&temp, content)?;
+        }
+        fs::rename(&temp, dir.join(JOURNAL_FILE_NAME))?;
+        if synced {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the remaining steps, recording each as done.
+    fn run(&mut self, root: &Path, dir: &Path) -> Result<(), SyncError> {
+        while let Some(step) = self.steps.get(self.done) {
+            match step {
+                // Gone once it was moved into place before
+                Step::Write { path, staged } if dir.join(staged).exists() => move_into_place(root, &dir.join(staged), path)?,
+                Step::Trash { path } if root.join(path).exists() => {
+                    crate::trash::Trash::new(root).put(path)?;
+                }
+                Step::Rename { from, to } if root.join(from).exists() => {
+                    let target = root.join(to);
+                    fs::create_dir_all(target.parent().unwrap_or(root))?;
+                    fs::rename(root.join(from), target)?;
+                }
+                Step::MkDir { path } => fs::create_dir_all(root.join(path))?,
+                _ => {}
+            }
+            self.done += 1;
+            self.save(dir, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl ApplyBatch {
+    /// Opens a batch. A batch left over from a crash is recovered first.
+    pub fn begin(root: &Path) -> Result<(Self, Option<Recovery>), SyncError> {
+        let recovery = recover(root)?;
+        let dir = apply_dir(root);
+        fs::create_dir_all(&dir)?;
+        Ok((Self { root: root.to_path_buf(), dir, steps: Vec::new() }, recovery))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Stages a file's new content, with `permissions` if given and the
+    /// replaced file's otherwise; moving it into place keeps them.
+    pub fn stage_write(
+        &mut self,
+        path: &Path,
+        content: &[u8],
+        permissions: Option<&FilePermissions>,
+    ) -> Result<(), SyncError> {
+        let staged = format!("{}.{}", self.steps.len(), STAGED_EXTENSION);
+        write_synced(&self.dir.join(&staged), content)?;
+        permissions_for(&self.root.join(path), permissions).apply(&self.dir.join(&staged))?;
+        self.steps.push(Step::Write { path: path.to_path_buf(), staged });
+        Ok(())
+    }
+
+    pub fn stage_trash(&mut self, path: &Path) {
+        self.steps.push(Step::Trash { path: path.to_path_buf() });
+    }
+
+    pub fn stage_rename(&mut self, from: &Path, to: &Path) {
+        self.steps.push(Step::Rename { from: from.to_path_buf(), to: to.to_path_buf() });
+    }
+
+    pub fn stage_mkdir(&mut self, path: &Path) {
+        self.steps.push(Step::MkDir { path: path.to_path_buf() });
+    }
+
+    /// Records the batch as committed and applies it. Returns how many
+    /// steps there were.
+    pub fn commit(self) -> Result<usize, SyncError> {
+        let count = self.steps.len();
+        if count > 0 {
+            sync_dir(&self.dir)?;
+            let mut journal = Journal { steps: self.steps, done: 0 };
+            journal.save(&self.dir, true)?;
+            journal.run(&self.root, &self.dir)?;
+        }
+        fs::remove_dir_all(&self.dir)?;
+        Ok(count)
+    }
+
+    /// Drops the staged files without applying anything.
+    pub fn abort(self) -> Result<(), SyncError> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Finishes or rolls back a batch a crash interrupted, `None` if there was
+/// none.
+pub fn recover(root: &Path) -> Result<Option<Recovery>, SyncError> {
+    let dir = apply_dir(root);
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let recovery = match fs::read(dir.join(JOURNAL_FILE_NAME)) {
+        Ok(content) => {
+            let mut journal: Journal = serde_json::from_slice(&content)?;
+            let left = journal.steps.len().saturating_sub(journal.done);
+            journal.run(root, &dir)?;
+            Recovery::Replayed(left)
+        }
+        // Left over from single writes, not from a batch
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !has_staged_files(&dir)? => {
+            fs::remove_dir_all(&dir)?;
+            return Ok(None);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Recovery::RolledBack,
+        Err(e) => return Err(e.into()),
+    };
+    fs::remove_dir_all(&dir)?;
+    Ok(Some(recovery))
+}
+
+fn has_staged_files(dir: &Path) -> Result<bool, SyncError> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .any(|entry| entry.path().extension().is_some_and(|ext| ext == STAGED_EXTENSION)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_is_all_or_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::write(root.join("a.md"), "# A").unwrap();
+        fs::write(root.join("old.md"), "# Old").unwrap();
+
+        // Nothing lands before the commit, and an interrupted batch is dropped
+        let (mut batch, _) = ApplyBatch::begin(root).unwrap();
+        batch.stage_mkdir(Path::new("projects/2024"));
+        batch.stage_write(Path::new("a.md"), b"# A, half", None).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.md")).unwrap(), "# A");
+        drop(batch);
+        assert!(!root.join("projects").exists());
+        assert_eq!(recover(root).unwrap(), Some(Recovery::RolledBack));
+        assert!(!apply_dir(root).exists());
+
+        let (mut batch, recovery) = ApplyBatch::begin(root).unwrap();
+        assert_eq!(recovery, None);
+        batch.stage_write(Path::new("notes/b.md"), b"# B", None).unwrap();
+        batch.stage_write(Path::new("a.md"), b"# A, new", None).unwrap();
+        batch.stage_rename(Path::new("old.md"), Path::new("archive/old.md"));
+        batch.stage_mkdir(Path::new("projects/2024"));
+        assert_eq!(batch.commit().unwrap(), 4);
+        assert!(root.join("projects/2024").is_dir());
+        assert_eq!(fs::read_to_string(root.join("notes/b.md")).unwrap(), "# B");
+        assert_eq!(fs::read_to_string(root.join("a.md")).unwrap(), "# A, new");
+        assert!(root.join("archive/old.md").exists());
+        assert!(!apply_dir(root).exists());
+
+        // A committed batch that crashed halfway is finished on recovery
+        let dir = apply_dir(root);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.staged"), "# C").unwrap();
+        let journal = Journal {
+            steps: vec![
+                Step::Write { path: PathBuf::from("a.md"), staged: "0.staged".to_string() },
+                Step::Write { path: PathBuf::from("c.md"), staged: "1.staged".to_string() },
+                Step::Trash { path: PathBuf::from("notes/b.md") },
+            ],
+            done: 1,
+        };
+        journal.save(&dir, true).unwrap();
+        assert_eq!(recover(root).unwrap(), Some(Recovery::Replayed(2)));
+        assert_eq!(fs::read_to_string(root.join("c.md")).unwrap(), "# C");
+        assert!(!root.join("notes/b.md").exists());
+        assert!(!dir.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_kept() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mode = |path: &str| fs::metadata(root.join(path)).unwrap().permissions().mode() & 0o7777;
+
+        let script = FilePermissions { mode: Some(0o755), readonly: false };
+        write_atomic(root, Path::new("build.sh"), b"#!/bin/sh", Some(&script)).unwrap();
+        assert_eq!(mode("build.sh"), 0o755);
+        assert_eq!(FilePermissions::of(&fs::metadata(root.join("build.sh")).unwrap()), script);
+
+        // Mode bits from where they are known, kept where only read-only is
+        let (mut batch, _) = ApplyBatch::begin(root).unwrap();
+        batch.stage_write(Path::new("a.md"), b"# A", Some(&FilePermissions { mode: Some(0o444), readonly: true })).unwrap();
+        batch.stage_write(Path::new("build.sh"), b"#!/bin/sh\n", Some(&FilePermissions { mode: None, readonly: true })).unwrap();
+        batch.commit().unwrap();
+        assert_eq!((mode("a.md"), mode("build.sh")), (0o444, 0o555));
+        write_atomic(root, Path::new("a.md"), b"# A", Some(&FilePermissions { mode: Some(0o644), readonly: false })).unwrap();
+        write_atomic(root, Path::new("build.sh"), b"#!/bin/sh\n", None).unwrap();
+        assert_eq!((mode("a.md"), mode("build.sh")), (0o644, 0o555));
+        write_atomic(root, Path::new("build.sh"), b"#!/bin/sh\n", Some(&FilePermissions::default())).unwrap();
+        assert_eq!(mode("build.sh"), 0o755);
+    }
+
+}
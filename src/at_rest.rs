@@ -0,0 +1,186 @@
+#![allow(dead_code)]
+
+//! Encryption at rest for a server's shares, for when disk encryption alone
+//! isn't enough. Unlike `encryption.rs` the server holds the keys: clients
+//! send and receive plaintext and only the blobs written to the share's
+//! storage are sealed.
+//!
+//! Each share has a keyring in the config directory, outside the share, so
+//! a copy of the storage alone doesn't reveal anything. Rotating adds a key
+//! that new blobs are sealed with; older keys stay to open what was sealed
+//! before, until the server rewrites those blobs on its next start.
+//!
+//! A sealed blob is `MAGIC`, the key id (big endian u32), a random nonce and
+//! the AES-256-GCM ciphertext with the blob's path as associated data, so
+//! blobs can't be swapped between paths unnoticed.
+
+use crate::types::SyncError;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 5] = b"SMAR1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_LEN;
+const KEYRING_DIR_NAME: &str = "share-keys";
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ShareKey {
+    id: u32,
+    key: String,
+    created: chrono::DateTime<chrono::Utc>,
+}
+
+/// The keys of one share, oldest first. The newest one seals new blobs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ShareKeyring {
+    share: PathBuf,
+    keys: Vec<ShareKey>,
+}
+
+impl std::fmt::Debug for ShareKeyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShareKeyring").field("share", &self.share).finish_non_exhaustive()
+    }
+}
+
+impl ShareKeyring {
+    /// A keyring with one fresh key, not saved yet.
+    pub fn generate(share: &Path) -> Self {
+        let mut keyring = Self { share: share.to_path_buf(), keys: Vec::new() };
+        keyring.rotate();
+        keyring
+    }
+
+    /// Where the keyring of `share` is kept: named after a hash of the
+    /// share's path, under the config directory.
+    pub fn keyring_path(share: &Path) -> Result<PathBuf, SyncError> {
+        let config_dir = dirs::config_dir()
+            .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))?;
+        let share = share.canonicalize().unwrap_or_else(|_| share.to_path_buf());
+        let id = blake3::hash(share.to_string_lossy().as_bytes()).to_hex();
+        Ok(config_dir.join("syncmd").join(KEYRING_DIR_NAME).join(format!("{}.json", &id[..16])))
+    }
+
+    pub fn load(share: &Path) -> Result<Option<Self>, SyncError> {
+        match std::fs::read_to_string(Self::keyring_path(share)?) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn load_or_create(share: &Path) -> Result<Self, SyncError> {
+        if let Some(keyring) = Self::load(share)? {
+            return Ok(keyring);
+        }
+        let keyring = Self::generate(share);
+        keyring.save()?;
+        Ok(keyring)
+    }
+
+    /// Writes the keyring readable by the owner only, replacing the old one
+    /// in one step: it's the only copy of the keys.
+    pub fn save(&self) -> Result<(), SyncError> {
+        crate::security::write_private(&Self::keyring_path(&self.share)?, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Adds a key that seals from now on and returns its id.
+    pub fn rotate(&mut self) -> u32 {
+        let mut key = [0u8; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        let id = self.keys.last().map_or(1, |key| key.id + 1);
+        self.keys.push(ShareKey { id, key: BASE64.encode(key), created: chrono::Utc::now() });
+        id
+    }
+
+    pub fn current_id(&self) -> u32 {
+        self.keys.last().map_or(0, |key| key.id)
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn created(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.keys.last().map(|key| key.created)
+    }
+
+    fn cipher(&self, id: u32) -> Result<Aes256Gcm, SyncError> {
+        let key = self.keys.iter()
+            .find(|key| key.id == id)
+            .ok_or_else(|| SyncError::Encryption(format!("Key {} is not in the keyring of {}", id, self.share.display())))?;
+        let key = BASE64.decode(&key.key)
+            .ok()
+            .filter(|key| key.len() == KEY_LEN)
+            .ok_or_else(|| SyncError::Encryption(format!("Key {} of {} is malformed", id, self.share.display())))?;
+        Ok(Aes256Gcm::new(Key::from_slice(&key)))
+    }
+
+    /// Seals `content` stored at `path` with the current key.
+    pub fn seal(&self, path: &str, content: &[u8]) -> Result<Vec<u8>, SyncError> {
+        let id = self.current_id();
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self.cipher(id)?
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: content, aad: path.as_bytes() })
+            .map_err(|_| SyncError::Encryption(format!("Sealing {} failed", path)))?;
+
+        let mut blob = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        blob.extend_from_slice(MAGIC);
+        blob.extend_from_slice(&id.to_be_bytes());
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&ciphertext);
+        Ok(blob)
+    }
+
+    /// The content of a blob stored at `path`. Blobs written before the
+    /// share was encrypted are returned as they are.
+    pub fn open(&self, path: &str, blob: &[u8]) -> Result<Vec<u8>, SyncError> {
+        let Some(id) = sealed_with(blob) else {
+            return Ok(blob.to_vec());
+        };
+        let nonce = &blob[MAGIC.len() + 4..HEADER_LEN];
+        self.cipher(id)?
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: &blob[HEADER_LEN..], aad: path.as_bytes() })
+            .map_err(|_| SyncError::Encryption(format!("{} doesn't decrypt, it was altered or moved", path)))
+    }
+}
+
+/// The id of the key a blob was sealed with, `None` for plaintext.
+pub fn sealed_with(blob: &[u8]) -> Option<u32> {
+    if blob.len() < HEADER_LEN || !blob.starts_with(MAGIC) {
+        return None;
+    }
+    Some(u32::from_be_bytes(blob[MAGIC.len()..MAGIC.len() + 4].try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_and_rotate() {
+        let mut keyring = ShareKeyring::generate(Path::new("/srv/notes"));
+        let sealed = keyring.seal("daily/today.md", b"# Today").unwrap();
+        assert_eq!(sealed_with(&sealed), Some(1));
+        assert!(!sealed.windows(7).any(|window| window == b"# Today"));
+        assert_eq!(keyring.open("daily/today.md", &sealed).unwrap(), b"# Today");
+        // Bound to its path
+        assert!(keyring.open("daily/other.md", &sealed).is_err());
+        // Stored before encryption was enabled
+        assert_eq!(keyring.open("old.md", b"plain").unwrap(), b"plain");
+
+        assert_eq!(keyring.rotate(), 2);
+        let resealed = keyring.seal("daily/today.md", b"# Today").unwrap();
+        assert_eq!(sealed_with(&resealed), Some(2));
+        assert_eq!(keyring.open("daily/today.md", &sealed).unwrap(), b"# Today");
+
+        let other = ShareKeyring::generate(Path::new("/srv/work"));
+        assert!(other.open("daily/today.md", &sealed).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+#![allow(dead_code)]
+
+//! Exponential backoff with jitter between reconnect attempts. The delay
+//! doubles with each failure up to a ceiling, and a random part keeps
+//! clients that lost the same server from all coming back at once.
+
+use rand::Rng;
+use std::time::Duration;
+
+/// First delay after a connection broke
+pub const RECONNECT_INITIAL: Duration = Duration::from_secs(1);
+/// Longest delay between attempts while the server stays away
+pub const RECONNECT_MAX: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, failures: 0 }
+    }
+
+    pub fn reconnect() -> Self {
+        Self::new(RECONNECT_INITIAL, RECONNECT_MAX)
+    }
+
+    /// The delay before the next attempt, between half and all of the
+    /// current step, which then doubles.
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self.initial.saturating_mul(2u32.saturating_pow(self.failures)).min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        let half = step / 2;
+        half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    /// Starts over from the initial delay, once connected again.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_to_ceiling() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let delays: Vec<Duration> = (0..8).map(|_| backoff.next_delay()).collect();
+        for (delay, step) in delays.iter().zip([1, 2, 4, 8, 10, 10, 10, 10]) {
+            let step = Duration::from_secs(step);
+            assert!(*delay >= step / 2 && *delay <= step, "{:?} outside {:?}", delay, step);
+        }
+        assert_eq!(backoff.failures(), 8);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,194 @@
+#![allow(dead_code)]
+
+//! Bandwidth limits for a remote that follow the time of day, e.g. 200 KB/s
+//! during working hours so a background sync stays out of the way of video
+//! calls, and unlimited at night. All connections to a remote share one
+//! limit. Connections are registered when they're opened, so transfer code
+//! finds the limiter from the stream alone.
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// A limit that applies between two times of day. A range whose end is
+/// before its start crosses midnight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthProfile {
+    pub from: NaiveTime,
+    pub until: NaiveTime,
+    /// Bytes per second, unlimited when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+impl BandwidthProfile {
+    /// Parses `HH:MM-HH:MM=SIZE`, e.g. `09:00-17:00=200KB` or
+    /// `22:00-07:00=unlimited`. The size is per second.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (range, limit) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Expected HH:MM-HH:MM=SIZE, got {}", spec))?;
+        let (from, until) = range
+            .split_once('-')
+            .ok_or_else(|| format!("Expected a time range like 09:00-17:00, got {}", range))?;
+        let time = |value: &str| {
+            NaiveTime::parse_from_str(value.trim(), "%H:%M").map_err(|_| format!("Invalid time of day: {}", value))
+        };
+        let limit = match limit.trim() {
+            "unlimited" => None,
+            size => match crate::plan::parse_size(size.trim_end_matches("/s"))? {
+                0 => return Err("A limit must be above zero, use 'unlimited' for none".to_string()),
+                bytes => Some(bytes),
+            },
+        };
+        Ok(Self { from: time(from)?, until: time(until)?, limit })
+    }
+
+    pub fn applies_at(&self, time: NaiveTime) -> bool {
+        if self.from <= self.until {
+            self.from <= time && time < self.until
+        } else {
+            time >= self.from || time < self.until
+        }
+    }
+}
+
+impl std::fmt::Display for BandwidthProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{} ", self.from.format("%H:%M"), self.until.format("%H:%M"))?;
+        match self.limit {
+            Some(bytes) => write!(f, "{}/s", crate::plan::format_size(bytes)),
+            None => write!(f, "unlimited"),
+        }
+    }
+}
+
+/// The limit at `time`: that of the first profile covering it, unlimited
+/// outside all of them.
+pub fn limit_at(profiles: &[BandwidthProfile], time: NaiveTime) -> Option<u64> {
+    profiles.iter().find(|profile| profile.applies_at(time)).and_then(|profile| profile.limit)
+}
+
+struct Bucket {
+    /// Bytes that may be sent right away; negative after a message larger
+    /// than the budget, paid back by waiting
+    available: f64,
+    updated: Instant,
+}
+
+/// Token bucket that holds a remote's transfers to its profiles' limit at
+/// the current local time.
+pub struct RateLimiter {
+    profiles: Vec<BandwidthProfile>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(profiles: Vec<BandwidthProfile>) -> Self {
+        Self { profiles, bucket: Mutex::new(Bucket { available: 0.0, updated: Instant::now() }) }
+    }
+
+    /// Accounts for `bytes` just transferred, waiting as long as they put
+    /// the remote over its current limit.
+    pub async fn throttle(&self, bytes: usize) {
+        let limit = limit_at(&self.profiles, chrono::Local::now().time());
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let Some(limit) = limit else {
+                bucket.available = 0.0;
+                bucket.updated = now;
+                return;
+            };
+            let limit = limit as f64;
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * limit;
+            // At most a second's worth saved up, so an idle spell doesn't
+            // allow a burst
+            bucket.available = (bucket.available + refill).min(limit) - bytes as f64;
+            bucket.updated = now;
+            Duration::from_secs_f64((-bucket.available / limit).max(0.0))
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    /// By remote name, so its connections share the limit
+    remotes: HashMap<String, Arc<RateLimiter>>,
+    /// By the connection's local address, which is unique while it is open
+    connections: HashMap<SocketAddr, Arc<RateLimiter>>,
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Puts a new connection to `remote` under the remote's profiles, if it has
+/// any.
+pub fn register(remote: &crate::remote::Remote, stream: &TcpStream) {
+    let Ok(local) = stream.local_addr() else {
+        return;
+    };
+    let mut registry = registry().lock().unwrap();
+    // The address may be reused from a closed connection
+    registry.connections.remove(&local);
+    if remote.bandwidth.is_empty() {
+        return;
+    }
+    let limiter = match registry.remotes.get(&remote.name) {
+        Some(limiter) if limiter.profiles == remote.bandwidth => limiter.clone(),
+        _ => {
+            let limiter = Arc::new(RateLimiter::new(remote.bandwidth.clone()));
+            registry.remotes.insert(remote.name.clone(), limiter.clone());
+            limiter
+        }
+    };
+    registry.connections.insert(local, limiter);
+}
+
+pub fn limiter(stream: &TcpStream) -> Option<Arc<RateLimiter>> {
+    let local = stream.local_addr().ok()?;
+    registry().lock().unwrap().connections.get(&local).cloned()
+}
+
+/// Accounts for `bytes` transferred over `stream`; a no-op for connections
+/// without a limit.
+pub async fn throttle(stream: &TcpStream, bytes: usize) {
+    if let Some(limiter) = limiter(stream) {
+        limiter.throttle(bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let at = |value: &str| NaiveTime::parse_from_str(value, "%H:%M").unwrap();
+        let profiles = vec![
+            BandwidthProfile::parse("09:00-17:00=200KB").unwrap(),
+            BandwidthProfile::parse("22:00-07:00=1MB/s").unwrap(),
+        ];
+        assert_eq!(limit_at(&profiles, at("09:00")), Some(200 * 1024));
+        assert_eq!(limit_at(&profiles, at("16:59")), Some(200 * 1024));
+        assert_eq!(limit_at(&profiles, at("17:00")), None);
+        assert_eq!(limit_at(&profiles, at("23:30")), Some(1024 * 1024));
+        assert_eq!(limit_at(&profiles, at("03:00")), Some(1024 * 1024));
+        assert_eq!(limit_at(&profiles, at("07:00")), None);
+        assert_eq!(profiles[0].to_string(), "09:00-17:00 200.0 KB/s");
+
+        assert_eq!(BandwidthProfile::parse("00:00-08:00=unlimited").unwrap().limit, None);
+        assert!(BandwidthProfile::parse("09:00-17:00").is_err());
+        assert!(BandwidthProfile::parse("9am-5pm=200KB").is_err());
+        assert!(BandwidthProfile::parse("09:00-17:00=0").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+#![allow(dead_code)]
+
+//! A read-only web file browser for a share, so a note can be fetched from
+//! a borrowed computer without installing syncmd. The server serves it over
+//! HTTP on the share's `browser_port`. The browser's login prompt takes a
+//! device token as the password, any user name, and the token's grants
+//! decide what is listed. Folders are listed, markdown notes shown rendered
+//! and any file downloaded; nothing can be changed.
+//!
+//! The token is sent with every request in plain HTTP, so a port reachable
+//! from outside belongs behind a proxy terminating TLS.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
+use std::collections::BTreeMap;
+
+/// What the browser shows for a target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrowserRequest {
+    /// Relative to the share, `/`-separated, without leading or trailing
+    /// slashes; empty for the share itself
+    pub path: String,
+    /// The file itself rather than its preview
+    pub download: bool,
+}
+
+impl BrowserRequest {
+    /// Parses a request target like `/Notes/plan%20b.md?download`. `None`
+    /// for targets leaving the share.
+    pub fn parse(target: &str) -> Option<Self> {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = percent_decode(path)?;
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        if segments.iter().any(|segment| *segment == "." || *segment == "..") {
+            return None;
+        }
+        Some(Self {
+            path: segments.join("/"),
+            download: query.split('&').any(|parameter| parameter == "download"),
+        })
+    }
+}
+
+/// The token in a basic `Authorization` header; the user name is ignored.
+pub fn token_from_authorization(value: &str) -> Option<String> {
+    let (scheme, credentials) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(BASE64.decode(credentials.trim()).ok()?).ok()?;
+    let (_, token) = credentials.split_once(':')?;
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+/// A file or folder directly inside the listed folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub directory: bool,
+    /// Of the file, or of every file under the folder
+    pub size: u64,
+}
+
+/// The files and folders directly inside `directory`, folders first, out
+/// of every file's path and size.
+pub fn entries<'a>(directory: &str, files: impl Iterator<Item = (&'a str, u64)>) -> Vec<Entry> {
+    let mut directories: BTreeMap<String, u64> = BTreeMap::new();
+    let mut listed = Vec::new();
+    for (path, size) in files {
+        let relative = match directory.is_empty() {
+            true => Some(path),
+            false => path.strip_prefix(directory).and_then(|rest| rest.strip_prefix('/')),
+        };
+        let Some(relative) = relative else {
+            continue;
+        };
+        match relative.split_once('/') {
+            Some((folder, _)) => *directories.entry(folder.to_string()).or_default() += size,
+            None => listed.push(Entry { name: relative.to_string(), directory: false, size }),
+        }
+    }
+    listed.sort_by(|a, b| a.name.cmp(&b.name));
+    directories
+        .into_iter()
+        .map(|(name, size)| Entry { name, directory: true, size })
+        .chain(listed)
+        .collect()
+}
+
+/// A folder's listing.
+pub fn listing_page(share: &str, directory: &str, entries: &[Entry]) -> String {
+    let mut body = String::from("<ul class=\"listing\">\n");
+    for entry in entries {
+        let path = join(directory, &entry.name);
+        let (href, name) = match entry.directory {
+            true => (format!("/{}/", encode_path(&path)), format!("{}/", entry.name)),
+            false => (format!("/{}", encode_path(&path)), entry.name.clone()),
+        };
+        body.push_str(&format!(
+            "<li><a href=\"{}\">{}</a> <span class=\"size\">{}</span></li>\n",
+            escape(&href),
+            escape(&name),
+            crate::plan::format_size(entry.size)
+        ));
+    }
+    if entries.is_empty() {
+        body.push_str("<li class=\"size\">Nothing here</li>\n");
+    }
+    body.push_str("</ul>\n");
+    page(share, directory, &body)
+}
+
+/// A markdown note rendered, with a link to download it.
+pub fn note_page(share: &str, path: &str, markdown: &str) -> String {
+    let body = format!(
+        "<p class=\"size\"><a href=\"/{}?download\">Download</a></p>\n<article>\n{}</article>\n",
+        escape(&encode_path(path)),
+        render_markdown(markdown)
+    );
+    page(share, path, &body)
+}
+
+/// Markdown as HTML. HTML inside the note is shown as text and links and
+/// images only lead to web pages, mail or other files in the share, so a
+/// synced note can't run scripts in a page holding the token.
+pub fn render_markdown(markdown: &str) -> String {
+    let markdown = strip_frontmatter(markdown);
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let events = Parser::new_ext(markdown, options).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::Link { link_type, dest_url, title, id }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image { link_type, dest_url, title, id }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        event => event,
+    });
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events);
+    html
+}
+
+/// `url` if it is relative or for http, https or mail, else a link to
+/// nowhere. Browsers ignore whitespace and control characters in a scheme,
+/// so `java\tscript:` is caught as well.
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    let cleaned: String = url.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_control()).collect();
+    let scheme = match cleaned.find([':', '/', '?', '#']) {
+        Some(end) if cleaned[end..].starts_with(':') => Some(cleaned[..end].to_ascii_lowercase()),
+        _ => None,
+    };
+    match scheme.as_deref() {
+        None | Some("http" | "https" | "mailto") => url,
+        Some(_) => CowStr::Borrowed("#"),
+    }
+}
+
+fn strip_frontmatter(markdown: &str) -> &str {
+    markdown
+        .strip_prefix("---\n")
+        .and_then(|rest| rest.find("\n---\n").map(|end| &rest[end + 5..]))
+        .unwrap_or(markdown)
+}
+
+/// A page with links to every folder above `path`.
+fn page(share: &str, path: &str, body: &str) -> String {
+    let mut crumbs = format!("<a href=\"/\">{}</a>", escape(share));
+    let mut above = String::new();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        above = join(&above, segment);
+        crumbs.push_str(&format!(" / <a href=\"/{}/\">{}</a>", escape(&encode_path(&above)), escape(segment)));
+    }
+    let title = path.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or(share);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<nav>{}</nav>\n{}</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        crumbs,
+        body
+    )
+}
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:50rem;margin:2rem auto;padding:0 1rem;line-height:1.5}\
+    nav{margin-bottom:1rem}.listing{list-style:none;padding:0}.listing li{padding:.2rem 0}\
+    .size{color:#777;font-size:.9em}pre{background:#f4f4f4;padding:.5rem;overflow:auto}\
+    table{border-collapse:collapse}td,th{border:1px solid #ccc;padding:.2rem .5rem}";
+
+fn join(directory: &str, name: &str) -> String {
+    match directory.is_empty() {
+        true => name.to_string(),
+        false => format!("{}/{}", directory, name),
+    }
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Percent-encodes each segment of a `/`-separated path.
+pub fn encode_path(path: &str) -> String {
+    let mut encoded = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser_pages() {
+        assert_eq!(
+            BrowserRequest::parse("/Notes/plan%20b.md?download"),
+            Some(BrowserRequest { path: "Notes/plan b.md".to_string(), download: true })
+        );
+        assert_eq!(BrowserRequest::parse("/"), Some(BrowserRequest { path: String::new(), download: false }));
+        assert_eq!(BrowserRequest::parse("/Notes/%2E%2E/secret"), None);
+        let header = format!("Basic {}", BASE64.encode("anyone:tok-123"));
+        assert_eq!(token_from_authorization(&header).as_deref(), Some("tok-123"));
+        assert_eq!(token_from_authorization("Bearer tok-123"), None);
+
+        let files = [("Notes/a.md", 10), ("Notes/deep/b.md", 5), ("Notes/deep/c.md", 5), ("top.md", 1)];
+        let listed = entries("Notes", files.iter().copied());
+        assert_eq!(listed, vec![
+            Entry { name: "deep".to_string(), directory: true, size: 10 },
+            Entry { name: "a.md".to_string(), directory: false, size: 10 },
+        ]);
+        let page = listing_page("share", "Notes", &listed);
+        assert!(page.contains("href=\"/Notes/deep/\"") && page.contains("href=\"/Notes/a.md\""));
+
+        let note = note_page("share", "Notes/a.md", "---\ntags: x\n---\n# Title\n\n<script>alert(1)</script>\n");
+        assert!(note.contains("<h1>Title</h1>"));
+        assert!(!note.contains("<script>") && !note.contains("tags: x"));
+
+        // Links and images only to pages, mail and the share's own files
+        let note = render_markdown(
+            "[a](javascript:alert(1)) [b](<JaVa\tScRiPt:alert(1)>) ![c](javascript:alert(1)) [d](data:text/html,x)\n\
+             [e](https://example.com) [f](mailto:me@example.com) [g](other.md) [h](/Notes/a.md#top) [i](a:b/c)",
+        );
+        assert!(!note.to_lowercase().contains("script:") && !note.contains("data:"), "{}", note);
+        assert_eq!(note.matches("href=\"#\"").count(), 4);
+        assert!(note.contains("src=\"#\""));
+        for kept in ["https://example.com", "mailto:me@example.com", "other.md", "/Notes/a.md#top"] {
+            assert!(note.contains(&format!("href=\"{}\"", kept)), "{}", kept);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+#![allow(dead_code)]
+
+//! Optional protocol features. Both sides list what they support during
+//! authentication and only the intersection is used, so clients and servers
+//! of different versions can talk to each other.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static COMPRESSION: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    /// Small files carried inside the sync response
+    InlineContent,
+    /// Sync requests and listings split into pages
+    PagedMetadata,
+    /// Server-side filtering by subscribed path prefixes
+    Subscriptions,
+    /// Expired tokens can be exchanged for new ones
+    TokenRefresh,
+    CompressionZstd,
+    /// The server pushes change notifications
+    Notifications,
+    /// Word lists of the server's files can be downloaded for offline search
+    SearchIndex,
+    /// Single chunks of a file can be requested, so large files download
+    /// from several servers at once
+    ChunkedDownload,
+    /// Directory hashes are compared before sync requests, which then only
+    /// carry the subtrees that differ
+    MerkleSync,
+    /// The server answers every upload, saying why when it refused one
+    UploadResults,
+    /// The server issues download links for people without syncmd
+    ShareLinks,
+    /// The server's change journal can be replicated, for history across
+    /// devices
+    JournalReplication,
+    /// Messages carrying file content are sent as binary frames instead of
+    /// JSON
+    BinaryFrames,
+    /// Uploads carry a key, so one retried after a lost answer is stored
+    /// once
+    IdempotencyKeys,
+    /// Directories are synced too, so empty ones exist on every device
+    Directories,
+    /// Large files move as a window of acknowledged chunks that resumes
+    /// where an earlier attempt stopped, see `file_transfer.rs`
+    WindowedTransfer,
+    /// Anything a newer peer supports that this build doesn't know about
+    #[serde(other)]
+    Unknown,
+}
+
+/// What this build implements.
+pub const SUPPORTED: &[Capability] = &[
+    Capability::InlineContent,
+    Capability::PagedMetadata,
+    Capability::Subscriptions,
+    Capability::TokenRefresh,
+    Capability::SearchIndex,
+    Capability::ChunkedDownload,
+    Capability::Notifications,
+    Capability::MerkleSync,
+    Capability::CompressionZstd,
+    Capability::UploadResults,
+    Capability::ShareLinks,
+    Capability::JournalReplication,
+    Capability::BinaryFrames,
+    Capability::IdempotencyKeys,
+    Capability::Directories,
+    Capability::WindowedTransfer,
+];
+
+/// Leaves compression out of what this process offers, for `--no-compress`.
+pub fn disable_compression() {
+    COMPRESSION.store(false, Ordering::Relaxed);
+}
+
+/// What this process offers peers: everything supported, less what the
+/// command line turned off.
+pub fn offered() -> Vec<Capability> {
+    let compression = COMPRESSION.load(Ordering::Relaxed);
+    SUPPORTED
+        .iter()
+        .copied()
+        .filter(|capability| compression || *capability != Capability::CompressionZstd)
+        .collect()
+}
+
+impl Capability {
+    pub fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+/// The capabilities both sides support, sorted.
+pub fn negotiate(ours: &[Capability], theirs: &[Capability]) -> Vec<Capability> {
+    let mut common: Vec<Capability> = ours
+        .iter()
+        .filter(|capability| **capability != Capability::Unknown && theirs.contains(capability))
+        .copied()
+        .collect();
+    common.sort();
+    common.dedup();
+    common
+}
+
+/// Comma-separated names, for display.
+pub fn describe(capabilities: &[Capability]) -> String {
+    if capabilities.is_empty() {
+        return "none".to_string();
+    }
+    capabilities.iter().map(Capability::name).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let theirs: Vec<Capability> =
+            serde_json::from_str(r#"["token-refresh", "inline-content", "quantum-sync"]"#).unwrap();
+        assert_eq!(theirs[2], Capability::Unknown);
+
+        let common = negotiate(SUPPORTED, &theirs);
+        assert_eq!(common, vec![Capability::InlineContent, Capability::TokenRefresh]);
+        assert_eq!(describe(&common), "inline-content, token-refresh");
+        assert!(negotiate(SUPPORTED, &[]).is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+#![allow(dead_code)]
+
+//! Fault injection for resilience testing, turned on with the hidden
+//! `--chaos` option or `SYNCMD_CHAOS`. Every connection to a server then
+//! runs through a relay on the loopback interface that delays what passes,
+//! drops some of it, cuts frames short and hangs up at random, so
+//! reconnection, resumption and idempotency keys get exercised the way a
+//! bad network would. The protocol code sees an ordinary stream.
+//!
+//! A spec lists faults by name, e.g.
+//! `latency=200ms,jitter=50ms,drop=0.01,truncate=0.005,disconnect=0.002`;
+//! probabilities are per write the relay forwards. Faults left out are off,
+//! `default` turns on a bit of each, and a `seed` makes a run repeatable.
+
+use crate::types::SyncError;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The environment variable taking a spec, like `--chaos`
+pub const ENV_VAR: &str = "SYNCMD_CHAOS";
+/// What the relay reads at once, and so the most one fault affects
+const RELAY_BUFFER: usize = 64 * 1024;
+
+/// The faults to inject.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// Added before each write is forwarded
+    pub latency: Duration,
+    /// Up to this much more, chosen at random each time
+    pub jitter: Duration,
+    /// Chance a write is left out, the connection carrying on
+    pub drop: f64,
+    /// Chance only part of a write arrives before the connection ends
+    pub truncate: f64,
+    /// Chance the connection ends before a write
+    pub disconnect: f64,
+    /// Seeds the faults of the first connection, one more for each next
+    pub seed: Option<u64>,
+}
+
+impl ChaosConfig {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut config = ChaosConfig::default();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            if part == "default" {
+                config = ChaosConfig {
+                    latency: Duration::from_millis(50),
+                    jitter: Duration::from_millis(50),
+                    drop: 0.01,
+                    truncate: 0.01,
+                    disconnect: 0.005,
+                    seed: config.seed,
+                };
+                continue;
+            }
+            let (name, value) = part.split_once('=').ok_or_else(|| format!("Expected name=value, got {}", part))?;
+            let probability = || match value.parse::<f64>() {
+                Ok(chance) if (0.0..=1.0).contains(&chance) => Ok(chance),
+                _ => Err(format!("{} must be a probability from 0 to 1, got {}", name, value)),
+            };
+            match name {
+                "latency" => config.latency = parse_duration(value)?,
+                "jitter" => config.jitter = parse_duration(value)?,
+                "drop" => config.drop = probability()?,
+                "truncate" => config.truncate = probability()?,
+                "disconnect" => config.disconnect = probability()?,
+                "seed" => config.seed = Some(value.parse().map_err(|_| format!("Invalid seed: {}", value))?),
+                _ => return Err(format!("Unknown fault {}, expected latency, jitter, drop, truncate, disconnect or seed", name)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl std::fmt::Display for ChaosConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "latency {:?} + up to {:?}, drop {}, truncate {}, disconnect {}",
+            self.latency, self.jitter, self.drop, self.truncate, self.disconnect
+        )
+    }
+}
+
+/// `250ms`, `2s`, or milliseconds without a unit.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {}", value);
+    if let Some(millis) = value.strip_suffix("ms") {
+        millis.parse().map(Duration::from_millis).map_err(|_| invalid())
+    } else if let Some(secs) = value.strip_suffix('s') {
+        secs.parse().map(Duration::from_secs_f64).map_err(|_| invalid())
+    } else {
+        value.parse().map(Duration::from_millis).map_err(|_| invalid())
+    }
+}
+
+static CHAOS: OnceLock<ChaosConfig> = OnceLock::new();
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Injects `config`'s faults into this process's connections from now on.
+pub fn enable(config: ChaosConfig) {
+    let _ = CHAOS.set(config);
+}
+
+pub fn enabled() -> Option<&'static ChaosConfig> {
+    CHAOS.get()
+}
+
+/// The stream to use for a connection to a server: `stream` itself, or
+/// while chaos is enabled, one through a relay injecting its faults.
+pub async fn wrap(stream: TcpStream) -> Result<TcpStream, SyncError> {
+    match enabled() {
+        Some(config) => wrap_with(stream, config.clone()).await,
+        None => Ok(stream),
+    }
+}
+
+pub async fn wrap_with(stream: TcpStream, config: ChaosConfig) -> Result<TcpStream, SyncError> {
+    let (near, relayed) = crate::network::loopback_pair().await?;
+    let connection = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    let rng = |direction: u64| match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(connection * 2 + direction)),
+        None => StdRng::from_entropy(),
+    };
+    let (outgoing, incoming) = (rng(0), rng(1));
+    tokio::spawn(async move {
+        let (mut relayed_read, mut relayed_write) = relayed.into_split();
+        let (mut remote_read, mut remote_write) = stream.into_split();
+        // Once either direction ends both sockets close, like a hung up
+        // connection
+        tokio::select! {
+            _ = relay(&mut relayed_read, &mut remote_write, &config, outgoing) => {}
+            _ = relay(&mut remote_read, &mut relayed_write, &config, incoming) => {}
+        }
+    });
+    Ok(near)
+}
+
+/// Forwards what `from` reads to `to`, with faults, until either side or a
+/// fault ends the connection.
+async fn relay<R, W>(from: &mut R, to: &mut W, config: &ChaosConfig, mut rng: StdRng)
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let mut buffer = vec![0; RELAY_BUFFER];
+    loop {
+        let read = match from.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+        if rng.gen_bool(config.disconnect) {
+            tracing::debug!("chaos: disconnecting");
+            return;
+        }
+        if rng.gen_bool(config.drop) {
+            tracing::debug!("chaos: dropped {} bytes", read);
+            continue;
+        }
+        let jitter = config.jitter.mul_f64(rng.gen::<f64>());
+        tokio::time::sleep(config.latency + jitter).await;
+        if rng.gen_bool(config.truncate) {
+            let cut = rng.gen_range(0..read);
+            tracing::debug!("chaos: truncated {} bytes to {} and disconnecting", read, cut);
+            let _ = to.write_all(&buffer[..cut]).await;
+            return;
+        }
+        if to.write_all(&buffer[..read]).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection to an echo server, through a relay with `config`.
+    async fn echo_connection(config: ChaosConfig) -> TcpStream {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            let _ = tokio::io::copy(&mut read, &mut write).await;
+        });
+        wrap_with(TcpStream::connect(address).await.unwrap(), config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chaos_relay() {
+        let config = ChaosConfig::parse("default,latency=5ms,seed=7").unwrap();
+        assert_eq!((config.latency, config.drop, config.seed), (Duration::from_millis(5), 0.01, Some(7)));
+        assert!(ChaosConfig::parse("drop=2").is_err());
+        assert!(ChaosConfig::parse("flood=1").is_err());
+
+        let mut stream = echo_connection(ChaosConfig { latency: Duration::from_millis(5), ..Default::default() }).await;
+        stream.write_all(b"hello").await.unwrap();
+        let mut echoed = [0; 5];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+
+        // A relay that always hangs up ends the connection on the first write
+        let mut stream = echo_connection(ChaosConfig::parse("disconnect=1").unwrap()).await;
+        stream.write_all(b"hello").await.unwrap();
+        let mut rest = Vec::new();
+        assert!(matches!(stream.read_to_end(&mut rest).await, Ok(0) | Err(_)));
+
+        let mut stream = echo_connection(ChaosConfig::parse("truncate=1,seed=1").unwrap()).await;
+        stream.write_all(b"a longer message").await.unwrap();
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest).await;
+        assert!(rest.len() < 16);
+    }
+}
@@ -1,11 +1,13 @@
 #![allow(dead_code)]
 
+use crate::secrets::{self, EncryptedSecrets, Secrets, SecretsKey};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "syncmd")]
 #[command(about = "Efficient markdown file synchronization tool")]
+#[command(after_help = crate::exit_codes::HELP)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
@@ -13,6 +15,28 @@ pub struct Cli {
     /// Verbose output
     #[arg(short, long)]
     pub verbose: bool,
+
+    /// When to color output; NO_COLOR is honoured in auto mode
+    #[arg(long, global = true, value_enum, default_value_t = crate::style::ColorChoice::Auto)]
+    pub color: crate::style::ColorChoice,
+
+    /// How to print the error a command fails with, on stderr
+    #[arg(long, global = true, value_enum, default_value_t = crate::exit_codes::ErrorFormat::Text)]
+    pub errors: crate::exit_codes::ErrorFormat,
+
+    /// Don't offer servers compression, e.g. when debugging the protocol
+    #[arg(long, global = true)]
+    pub no_compress: bool,
+
+    /// Inject latency, drops, truncated frames and disconnects into
+    /// connections to servers, see `chaos.rs`; also read from SYNCMD_CHAOS
+    #[arg(long, global = true, hide = true, num_args = 0..=1, default_missing_value = "default")]
+    pub chaos: Option<String>,
+
+    /// Append every protocol message sent or received, content left out,
+    /// as a JSON line to this file; see `syncmd trace analyze`
+    #[arg(long, global = true, value_name = "FILE")]
+    pub trace_protocol: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -23,7 +47,7 @@ pub enum Commands {
         #[arg(short, long)]
         path: PathBuf,
         
-        /// Connect to a remote server
+        /// Connect to a known peer, a named remote, a host:port address, or a ws:// or wss:// URL to sync over WebSocket
         #[arg(short, long)]
         connect: Option<String>,
         
@@ -34,13 +58,36 @@ pub enum Commands {
         /// Port to listen on (server mode)
         #[arg(long, default_value = "8080")]
         port: u16,
+
+        /// Ask before an initial sync that transfers more than this, e.g. 500MB
+        #[arg(long, value_parser = crate::plan::parse_size)]
+        confirm_over: Option<u64>,
+
+        /// Fail the sync round if any file is skipped or can't be read
+        #[arg(long)]
+        strict: bool,
+
+        /// Look for servers on the local network and pick one to connect to
+        #[arg(long, conflicts_with_all = ["connect", "server"])]
+        discover: bool,
+
+        /// Print transfer progress as JSON lines on stdout instead of bars
+        #[arg(long)]
+        json: bool,
     },
     
     /// List connected clients
     ListClients,
     
     /// Show current sync status
-    Status,
+    Status {
+        /// List files in each root that are excluded from sync, and why
+        #[arg(long)]
+        ignored: bool,
+    },
+
+    /// Live dashboard of the running daemon
+    Ui,
     
     /// Initialize a new sync configuration
     Init {
@@ -55,30 +102,846 @@ pub enum Commands {
         /// Authentication token for server access
         #[arg(long)]
         auth_token: Option<String>,
+
+        /// Create a starter structure, .syncignore and policies
+        #[arg(long, value_enum)]
+        template: Option<crate::templates::RootTemplate>,
+    },
+
+    /// Adopt a folder previously synced with Syncthing or rsync
+    Import {
+        /// Tool the folder was synced with before
+        #[arg(long, value_enum)]
+        from: crate::import::ImportSource,
+
+        /// Folder to import
+        path: PathBuf,
+
+        /// rsync exclude file to convert into .syncignore (rsync only)
+        #[arg(long)]
+        exclude_from: Option<PathBuf>,
+
+        /// Don't convert ignore patterns into .syncignore
+        #[arg(long)]
+        no_ignores: bool,
+    },
+
+    /// Export changes since a journal sequence for rsync-based mirrors
+    ExportBatch {
+        /// Sync root to export from
+        #[arg(short, long)]
+        path: PathBuf,
+
+        /// Only include changes after this journal sequence number
+        #[arg(long, default_value = "0")]
+        since: u64,
+
+        /// Output format
+        #[arg(long, value_enum, default_value = "files-from")]
+        format: crate::export::ExportFormat,
+
+        /// Directory to write the batch into
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+
+    /// Write or check a signed checksum manifest of a root, to verify
+    /// copies made outside syncmd
+    Manifest {
+        #[command(subcommand)]
+        action: ManifestAction,
+    },
+
+    /// Snapshot the index and drop journal entries past the retention window
+    Compact {
+        /// Sync root to compact
+        #[arg(short, long)]
+        path: PathBuf,
+
+        /// Keep journal entries younger than this many days
+        #[arg(long, default_value_t = crate::index_store::DEFAULT_JOURNAL_RETENTION_DAYS)]
+        retention_days: i64,
+    },
+
+    /// Name the current state of a sync root, to diff against, export or
+    /// restore later
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+
+    /// Manage the configuration file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Show changes to a file since it was last synced
+    Diff {
+        /// File inside a sync root
+        path: PathBuf,
+
+        /// Compare against the server's current version instead
+        #[arg(long)]
+        remote: Option<String>,
+    },
+
+    /// Show or rename this device
+    Device {
+        #[command(subcommand)]
+        action: DeviceAction,
+    },
+
+    /// Inspect which files are excluded from sync
+    Filters {
+        #[command(subcommand)]
+        action: FiltersAction,
+    },
+
+    /// Encrypt a folder's content and file names before they leave this
+    /// device, so servers only store ciphertext
+    Encrypt {
+        /// Path to the sync root
+        #[arg(short, long)]
+        path: PathBuf,
+
+        /// Salt printed by the first device that encrypted the folder
+        #[arg(long)]
+        salt: Option<String>,
+    },
+
+    /// Sync only some subfolders of a root on this device; without
+    /// options, print the current selection
+    Select {
+        /// Path to the sync root
+        path: PathBuf,
+
+        /// Subfolder to sync, relative to the root; may be repeated
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Subfolder to leave out, even inside an included one
+        #[arg(long)]
+        exclude: Vec<String>,
+
+        /// Drop a subfolder from both lists
+        #[arg(long)]
+        remove: Vec<String>,
+
+        /// Sync the whole root again
+        #[arg(long)]
+        clear: bool,
+    },
+
+    /// List conflict copies, next to their files or in `.syncmd/conflicts`
+    Conflicts {
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        path: Option<PathBuf>,
+
+        /// Show each file and its conflict copy rendered next to each other
+        #[arg(long)]
+        preview: bool,
+    },
+
+    /// List or restore files deleted by a sync
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+
+    /// Show or clean up where a root keeps syncmd's own state
+    State {
+        #[command(subcommand)]
+        action: StateAction,
+    },
+
+    /// Manage named remotes and their connection settings
+    Remote {
+        #[command(subcommand)]
+        action: RemoteAction,
+    },
+
+    /// List devices announcing themselves on the LAN
+    Peers {
+        #[command(subcommand)]
+        action: Option<PeersAction>,
+
+        /// Seconds to listen for beacons
+        #[arg(long, default_value = "6")]
+        listen: u64,
+    },
+
+    /// Replicate a folder one way to a backup directory or server
+    Mirror {
+        /// Folder to back up
+        #[arg(short, long)]
+        path: PathBuf,
+
+        /// Backup directory, e.g. a mounted drive
+        #[arg(long, required_unless_present = "remote", conflicts_with = "remote")]
+        to: Option<PathBuf>,
+
+        /// Peer, remote name or address to upload to instead
+        #[arg(long)]
+        remote: Option<String>,
+
+        /// Also delete files from the target that were deleted from the folder
+        #[arg(long)]
+        delete: bool,
+
+        /// Re-check the hash of every file written to the target
+        #[arg(long)]
+        verify: bool,
+
+        /// Write the report as JSON to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+
+        /// Keep running, mirroring again every this many minutes
+        #[arg(long)]
+        every: Option<u64>,
+    },
+
+    /// Sync two devices directly using a shared passphrase, without a server
+    Pair {
+        #[command(subcommand)]
+        action: PairAction,
+    },
+
+    /// Sync every enabled root in the background, controllable through a
+    /// local socket
+    Daemon {
+        /// Peer, remote name or address to sync the roots with
+        #[arg(short, long)]
+        connect: Option<String>,
+
+        /// Also accept clients on this port
+        #[arg(long)]
+        port: Option<u16>,
+    },
+
+    /// Pause syncing in the running daemon: the root is neither watched
+    /// nor changed until it is resumed, e.g. during a large reorganization
+    Pause {
+        /// Root to pause, all roots when omitted
+        path: Option<PathBuf>,
+    },
+
+    /// Resume syncing in the running daemon, catching up with everything
+    /// that changed while paused
+    Resume {
+        /// Root to resume, all roots when omitted
+        path: Option<PathBuf>,
+    },
+
+    /// Sync changes the running daemon is still holding back right away
+    Flush,
+
+    /// Tell the running daemon a file is open in an editor, so its changes
+    /// sync right away and ahead of other transfers; for editor plugins
+    Focus {
+        /// The file
+        path: PathBuf,
+
+        /// The file was closed again
+        #[arg(long)]
+        closed: bool,
+    },
+
+    /// Show, enable or disable the opt-in reliability metrics
+    Telemetry {
+        #[command(subcommand)]
+        action: TelemetryAction,
+    },
+
+    /// List or cancel the running daemon's file transfers
+    Queue {
+        #[command(subcommand)]
+        action: QueueAction,
+    },
+
+    /// Search notes by the words they contain, including ones only on the
+    /// server when its search index was downloaded
+    Search {
+        /// Words to look for; the last one may be incomplete
+        query: String,
+
+        /// Sync root to search (defaults to the one containing the current directory)
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+
+        /// Update the index from this server first
+        #[arg(short, long)]
+        connect: Option<String>,
+
+        /// Maximum number of results
+        #[arg(long, default_value = "20")]
+        limit: usize,
+    },
+
+    /// Show the changes recorded for a sync root, newest first
+    History {
+        /// Sync root (defaults to the one containing the current directory)
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+
+        /// Changes every device made, from the server's replicated journal,
+        /// instead of this device's own
+        #[arg(long)]
+        all_devices: bool,
+
+        /// Replicate the server's journal from this server first
+        #[arg(short, long)]
+        connect: Option<String>,
+
+        /// Maximum number of changes
+        #[arg(long, default_value = "50")]
+        limit: usize,
+    },
+
+    /// Get a link to a file or folder on the server that people without
+    /// syncmd can download it with until the link expires
+    ShareLink {
+        /// File or folder inside a sync root
+        path: PathBuf,
+
+        /// Server to issue the link: a named remote or a host:port address
+        #[arg(short, long)]
+        connect: String,
+
+        /// How long the link works, e.g. 30m, 48h or 7d
+        #[arg(long, default_value = "24h", value_parser = crate::share_links::parse_duration)]
+        expires: std::time::Duration,
+    },
+
+    /// Run a scripted scenario against virtual devices and report convergence
+    Simulate {
+        /// Number of virtual devices
+        #[arg(long, default_value = "2")]
+        devices: usize,
+
+        /// Scenario file describing edits and syncs
+        #[arg(long)]
+        script: PathBuf,
+
+        /// Keep the devices' temporary directories for inspection
+        #[arg(long)]
+        keep: bool,
+    },
+
+    /// Move this device to a new machine, keeping its identity and indexes
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+
+    /// Inspect protocol traces written with --trace-protocol
+    Trace {
+        #[command(subcommand)]
+        action: TraceAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TraceAction {
+    /// Summarize round trips by message type and flag protocol stalls
+    Analyze {
+        /// The trace file
+        file: PathBuf,
+
+        /// Seconds a reply may take before the wait counts as a stall
+        #[arg(long, default_value_t = 5.0)]
+        stall_after: f64,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MigrateAction {
+    /// Write the config, identity, peers and the index of every root into
+    /// an archive
+    Export {
+        /// The archive to write
+        output: PathBuf,
+    },
+
+    /// Set this machine up as the device in an archive; copy the roots'
+    /// files over first so they aren't downloaded again
+    Import {
+        /// Archive written by `migrate export`
+        archive: PathBuf,
+
+        /// Roots at or below OLD are below NEW on this machine, as OLD=NEW;
+        /// may be repeated
+        #[arg(long = "root", value_parser = crate::migrate::RootRemap::parse)]
+        remap: Vec<crate::migrate::RootRemap>,
+
+        /// Replace a different device already set up here
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DeviceAction {
+    /// Print the device id, name and slug
+    Show,
+
+    /// Change the device's display name
+    Rename {
+        name: String,
+
+        /// Also update the name on this server
+        #[arg(long)]
+        connect: Option<String>,
+    },
+
+    /// List past renames recorded in the sync roots' journals
+    History,
+}
+
+#[derive(Subcommand)]
+pub enum PairAction {
+    /// Wait for the other device to connect
+    Listen {
+        /// Folder to sync
+        #[arg(short, long)]
+        path: PathBuf,
+
+        #[arg(long, default_value = "21128")]
+        port: u16,
+    },
+
+    /// Connect to a device running `syncmd pair listen`
+    Connect {
+        /// host:port of the listening device
+        address: String,
+
+        /// Folder to sync
+        #[arg(short, long)]
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum FiltersAction {
+    /// Explain whether a file is synced and which rule excludes it; for a
+    /// directory, list the excluded files under it
+    Explain {
+        /// File or directory inside a sync root (defaults to the current directory)
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TrashAction {
+    /// List the files in a root's trash, most recently deleted first
+    List {
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        path: Option<PathBuf>,
+    },
+
+    /// Put a deleted file back where it was; the next sync shares it again
+    Restore {
+        /// Where the file was, inside a sync root
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotAction {
+    /// Tag the root's current state, e.g. `v1.0-launch`
+    Tag {
+        name: String,
+
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+    },
+
+    /// List the root's tags, newest first
+    List {
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+    },
+
+    /// Show what changed since a tag
+    Diff {
+        name: String,
+
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+    },
+
+    /// Write the files of a tag into a tar archive
+    Export {
+        name: String,
+
+        /// The archive to write
+        output: PathBuf,
+
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+    },
+
+    /// Write the files of a tag into a new folder
+    Restore {
+        name: String,
+
+        /// The folder, which must not exist yet or be empty
+        target: PathBuf,
+
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StateAction {
+    /// Print a root's state directory
+    Path {
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        path: Option<PathBuf>,
+    },
+
+    /// Remove partial downloads and leftovers of finished syncs, keeping the index
+    Clean {
+        /// Sync root, or a directory inside one (defaults to the current directory)
+        path: Option<PathBuf>,
+
+        /// Empty the trash too
+        #[arg(long)]
+        trash: bool,
     },
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Subcommand)]
+pub enum ManifestAction {
+    /// Write the paths and hashes of a root's files, readable by `b3sum --check`,
+    /// with the signature next to it
+    Export {
+        /// Sync root to describe
+        #[arg(short, long)]
+        path: PathBuf,
+
+        /// Manifest file to write
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+
+    /// Check a copy of a root against a manifest and its signature
+    Verify {
+        /// Directory holding the copy
+        #[arg(short, long)]
+        path: PathBuf,
+
+        /// Manifest written by `manifest export`
+        #[arg(short, long)]
+        manifest: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TelemetryAction {
+    /// Whether metrics are collected, and the report exactly as it would be sent
+    Status,
+
+    /// Count sync rounds and conflicts and send the totals once a day
+    Enable {
+        /// http:// URL to post reports to, required the first time
+        #[arg(long)]
+        endpoint: Option<String>,
+    },
+
+    /// Stop collecting and delete what was collected
+    Disable,
+}
+
+#[derive(Subcommand)]
+pub enum QueueAction {
+    /// List transfers in progress
+    List,
+
+    /// Stop a transfer; a partly received file is discarded
+    Cancel {
+        /// Transfer id, or the path of the file being transferred
+        target: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeersAction {
+    /// List known peers with the addresses they were last reachable at
+    Known,
+
+    /// Print this device's id and public key, to share with peers
+    Identity,
+
+    /// Trust beacons from a device signed with the given public key
+    Trust {
+        device_id: String,
+
+        public_key: String,
+
+        /// Display name for the peer
+        #[arg(long)]
+        name: Option<String>,
+    },
+
+    /// Stop trusting a device
+    Forget {
+        device_id: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    /// Encrypt secrets (auth token) in the config with a passphrase
+    Encrypt,
+
+    /// Store secrets in plaintext again
+    Decrypt,
+
+    /// Print a setting, e.g. `device_name` or `sync_roots.0.enabled`
+    Get {
+        key: String,
+    },
+
+    /// Change a setting (values are parsed as JSON when possible)
+    Set {
+        key: String,
+        value: String,
+    },
+
+    /// Remove a setting, restoring its default
+    Unset {
+        key: String,
+    },
+
+    /// Open the configuration in $EDITOR and validate it before saving
+    Edit,
+}
+
+#[derive(Subcommand)]
+pub enum RemoteAction {
+    /// Add or replace a remote
+    Add {
+        /// Name used with `sync --connect`
+        name: String,
+
+        /// Address as host:port
+        address: String,
+
+        /// TLS requirement
+        #[arg(long, value_enum, default_value = "preferred")]
+        tls: crate::remote::TlsMode,
+
+        /// Pinned SHA-256 certificate fingerprint
+        #[arg(long)]
+        fingerprint: Option<String>,
+
+        /// Preferred transport
+        #[arg(long, value_enum, default_value = "tcp")]
+        transport: crate::remote::TransportKind,
+
+        /// HTTP proxy to tunnel through, e.g. http://proxy:3128
+        #[arg(long)]
+        proxy: Option<String>,
+
+        /// Compression preference
+        #[arg(long, value_enum, default_value = "auto")]
+        compression: crate::remote::CompressionPreference,
+
+        /// Bandwidth limit for a time of day, e.g. 09:00-17:00=200KB or
+        /// 22:00-07:00=unlimited; repeat for several
+        #[arg(long, value_parser = crate::bandwidth::BandwidthProfile::parse)]
+        bandwidth: Vec<crate::bandwidth::BandwidthProfile>,
+    },
+
+    /// Remove a remote
+    Remove {
+        name: String,
+    },
+
+    /// List the files stored on a remote, fetched page by page
+    Files {
+        /// Remote name, known peer or address
+        name: String,
+
+        /// Only list files under this path
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+
+    /// List remotes
+    List {
+        /// Show TLS, fingerprint, transport, proxy and compression settings
+        #[arg(long)]
+        verbose: bool,
+    },
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     pub device_id: String,
     pub device_name: String,
     pub sync_roots: Vec<SyncRoot>,
     pub auth_token: Option<String>,
+    /// When present, secrets live here and are kept out of the plaintext fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_secrets: Option<EncryptedSecrets>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remotes: Vec<crate::remote::Remote>,
+    /// Devices whose discovery beacons are trusted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub known_peers: Vec<crate::discovery::KnownPeer>,
+    /// WireGuard or other overlay networks (CIDR) preferred over the public
+    /// relay; Tailscale is detected automatically
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlay_networks: Vec<String>,
+    /// Seconds a changed file must be quiet before it is synced, so a burst
+    /// of saves transfers only the final state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coalesce_secs: Option<u64>,
+    /// Opt-in aggregate reliability metrics, see `telemetry.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<crate::telemetry::TelemetrySettings>,
+    /// Central directory for the state of all roots, instead of a `.syncmd`
+    /// in each, see `state.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
+    #[serde(skip)]
+    secrets_key: Option<SecretsKey>,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// The config file's name in the `syncmd` config directory
+pub const CONFIG_FILE_NAME: &str = "config.json";
+
+/// Seconds a changed file has to stay untouched before it is synced
+pub const DEFAULT_COALESCE_SECS: u64 = 5;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SyncRoot {
     pub path: PathBuf,
     pub enabled: bool,
     pub last_sync: Option<chrono::DateTime<chrono::Utc>>,
+    /// External program deciding which files get synced, see `filter.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter_cmd: Option<String>,
+    /// Content transforms applied before hashing and upload
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<crate::transform::TransformRule>,
+    /// Template the root was created from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<crate::templates::RootTemplate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict_strategy: Option<crate::sync::ConflictStrategy>,
+    /// `push-only`, `pull-only` or `backup` to sync one way, both ways if
+    /// unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction: Option<crate::sync::SyncDirection>,
+    /// Where conflict copies go: `sibling`, next to the file, or
+    /// `directory`, under `.syncmd/conflicts/` where they don't sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict_layout: Option<crate::conflicts::ConflictLayout>,
+    /// File categories to sync, all when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<crate::types::FileCategory>,
+    /// Sync files of any type, not only known note, image, code, document
+    /// and data types
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sync_all_files: bool,
+    /// Files larger than this many bytes are left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    /// Per-key policies for conflicts that only touch frontmatter
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub frontmatter_policies: std::collections::BTreeMap<String, crate::sync::FrontmatterPolicy>,
+    /// Experimental: glob patterns of markdown files merged section by
+    /// section, split at top-level headings, e.g. `journal/*.md`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub split_sections: Vec<String>,
+    /// Path prefixes to receive changes for from the server, all when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscriptions: Vec<String>,
+    /// Subfolders synced on this device, the whole root when empty; set
+    /// with `syncmd select`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Subfolders left out on this device, even inside included ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Days files deleted by a sync are kept in the root's trash, 30 if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash_retention_days: Option<u32>,
+    /// Download the server's search index when syncing, so files that
+    /// aren't on this device can be searched offline
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub offline_search: bool,
+    /// Sync the contents of `.git`, `.hg` and `.svn` directories, which are
+    /// skipped by default
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sync_vcs_dirs: bool,
+    /// Don't sync files the enclosing git repository ignores
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub respect_gitignore: bool,
+    /// Patterns left out like `.syncignore` lines, on top of editor temp
+    /// files, by the indexer and the watcher
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+    /// Other remotes with the same files, used alongside the connected one
+    /// to download large files in parallel
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub download_sources: Vec<String>,
+    /// When the daemon syncs the root on its own, every 30 seconds if
+    /// unset, see `schedule.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<crate::schedule::ScheduleSettings>,
+    /// End-to-end encryption, set up with `syncmd encrypt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<crate::encryption::FolderEncryption>,
+}
+
+
+impl SyncRoot {
+    pub fn file_types(&self) -> crate::types::FileTypeFilter {
+        crate::types::FileTypeFilter {
+            sync_all_files: self.sync_all_files,
+            max_file_size: self.max_file_size,
+        }
+    }
+
+    pub fn filter_command(&self) -> Option<crate::filter::FilterCommand> {
+        self.filter_cmd.clone().map(crate::filter::FilterCommand::new)
+    }
+
+    pub fn transform_pipeline(&self) -> crate::transform::TransformPipeline {
+        crate::transform::TransformPipeline::new(&self.transforms)
+    }
+
+    pub fn selection(&self) -> crate::types::PathSelection {
+        crate::types::PathSelection::new(&self.include, &self.exclude)
+    }
 }
 
 impl Config {
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
         let config_path = Self::config_path()?;
         if config_path.exists() {
-            let content = std::fs::read_to_string(config_path)?;
-            Ok(serde_json::from_str(&content)?)
+            let content = std::fs::read_to_string(&config_path)?;
+            let mut config: Self = serde_json::from_str(&content).map_err(|e| {
+                crate::types::SyncError::Config(format!("{} is malformed: {}", config_path.display(), e))
+            })?;
+            // Secrets stay locked unless the passphrase is in the environment,
+            // commands that need them call unlock_secrets()
+            if let Some(passphrase) = secrets::passphrase_from_env() {
+                config.unlock_with(&passphrase)?;
+            }
+            crate::state::set_central(config.state_dir.clone());
+            Ok(config)
         } else {
             Ok(Self::default())
         }
@@ -89,24 +952,107 @@ impl Config {
         if let Some(parent) = config_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
-        let content = serde_json::to_string_pretty(self)?;
+
+        let content = if let Some(existing) = &self.encrypted_secrets {
+            let blob = match &self.secrets_key {
+                Some(key) => secrets::encrypt(&self.secrets(), key)?,
+                None if self.auth_token.is_some() => {
+                    return Err("Config secrets are locked, unlock them before changing secrets".into());
+                }
+                None => existing.clone(),
+            };
+            let mut on_disk = self.clone();
+            on_disk.auth_token = None;
+            on_disk.encrypted_secrets = Some(blob);
+            serde_json::to_string_pretty(&on_disk)?
+        } else {
+            serde_json::to_string_pretty(self)?
+        };
         std::fs::write(config_path, content)?;
         Ok(())
     }
 
+    /// Replaces all settings from their JSON form, keeping unlocked secrets
+    /// unlocked.
+    pub fn replace_from_value(&mut self, value: serde_json::Value) -> Result<(), serde_json::Error> {
+        let mut replacement: Config = serde_json::from_value(value)?;
+        replacement.secrets_key = self.secrets_key.take();
+        *self = replacement;
+        Ok(())
+    }
+
+    /// Changes whenever the stored secrets do, whether encrypted or not.
+    pub fn secrets_fingerprint(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&(&self.auth_token, &self.encrypted_secrets))
+    }
+
+    pub fn secrets_encrypted(&self) -> bool {
+        self.encrypted_secrets.is_some()
+    }
+
+    fn secrets(&self) -> Secrets {
+        Secrets {
+            auth_token: self.auth_token.clone(),
+        }
+    }
+
+    fn unlock_with(&mut self, passphrase: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(blob) = &self.encrypted_secrets {
+            let key = SecretsKey::for_blob(passphrase, blob)?;
+            let secrets = secrets::decrypt(blob, &key)?;
+            self.auth_token = secrets.auth_token;
+            self.secrets_key = Some(key);
+        }
+        Ok(())
+    }
+
+    /// Decrypts the secrets section, prompting for the passphrase if needed.
+    pub fn unlock_secrets(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.secrets_encrypted() && self.secrets_key.is_none() {
+            let passphrase = secrets::read_passphrase("Config passphrase: ")?;
+            self.unlock_with(&passphrase)?;
+        }
+        Ok(())
+    }
+
+    /// Switches to encrypted secrets; takes effect on the next save.
+    pub fn encrypt_secrets(&mut self, passphrase: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let key = SecretsKey::generate(passphrase);
+        self.encrypted_secrets = Some(secrets::encrypt(&self.secrets(), &key)?);
+        self.secrets_key = Some(key);
+        Ok(())
+    }
+
+    /// Switches back to plaintext secrets; takes effect on the next save.
+    pub fn decrypt_secrets(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.unlock_secrets()?;
+        self.encrypted_secrets = None;
+        self.secrets_key = None;
+        Ok(())
+    }
+
     fn config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
-        let config_dir = dirs::config_dir()
-            .ok_or("Could not find config directory")?
-            .join("syncmd");
-        Ok(config_dir.join("config.json"))
+        Ok(Self::config_dir()?.join(CONFIG_FILE_NAME))
+    }
+
+    pub fn config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+        Ok(dirs::config_dir().ok_or("Could not find config directory")?.join("syncmd"))
     }
 
-    fn default() -> Self {
+    pub(crate) fn default() -> Self {
         Self {
             device_id: uuid::Uuid::new_v4().to_string(),
             device_name: "syncmd-client".to_string(),
             sync_roots: Vec::new(),
             auth_token: None,
+            encrypted_secrets: None,
+            remotes: Vec::new(),
+            known_peers: Vec::new(),
+            overlay_networks: Vec::new(),
+            coalesce_secs: None,
+            telemetry: None,
+            state_dir: None,
+            secrets_key: None,
         }
     }
 
@@ -115,10 +1061,68 @@ impl Config {
             path,
             enabled: true,
             last_sync: None,
+            filter_cmd: None,
+            transforms: Vec::new(),
+            template: None,
+            conflict_strategy: None,
+            direction: None,
+            conflict_layout: None,
+            categories: Vec::new(),
+            sync_all_files: false,
+            max_file_size: None,
+            frontmatter_policies: std::collections::BTreeMap::new(),
+            split_sections: Vec::new(),
+            subscriptions: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            trash_retention_days: None,
+            offline_search: false,
+            sync_vcs_dirs: false,
+            respect_gitignore: false,
+            ignore: Vec::new(),
+            download_sources: Vec::new(),
+            schedule: None,
+            encryption: None,
         });
     }
 
     pub fn get_sync_root(&self, path: &PathBuf) -> Option<&SyncRoot> {
         self.sync_roots.iter().find(|root| root.path == *path)
     }
+
+    /// Resolves `--connect` to a named remote, or an ad-hoc one for a plain
+    /// address.
+    pub fn resolve_remote(&self, name_or_address: &str) -> crate::remote::Remote {
+        self.remotes
+            .iter()
+            .find(|remote| remote.name == name_or_address)
+            .cloned()
+            .unwrap_or_else(|| crate::remote::Remote::from_address(name_or_address))
+    }
+
+    /// The innermost configured root that contains `path`.
+    pub fn root_containing(&self, path: &std::path::Path) -> Option<&SyncRoot> {
+        let wanted = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.sync_roots
+            .iter()
+            .filter(|root| {
+                let root_path = root.path.canonicalize().unwrap_or_else(|_| root.path.clone());
+                wanted.starts_with(root_path)
+            })
+            .max_by_key(|root| root.path.components().count())
+    }
+
+    /// How long the watcher holds back a changed file for further changes.
+    pub fn coalesce_window(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.coalesce_secs.unwrap_or(DEFAULT_COALESCE_SECS))
+    }
+
+    /// Looks up a sync root, treating relative and canonical forms of the
+    /// same directory as equal.
+    pub fn find_sync_root(&self, path: &std::path::Path) -> Option<&SyncRoot> {
+        let wanted = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.sync_roots.iter().find(|root| {
+            root.path == wanted || root.path.canonicalize().map(|p| p == wanted).unwrap_or(false)
+        })
+    }
 }
\ No newline at end of file
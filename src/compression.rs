@@ -0,0 +1,98 @@
+#![allow(dead_code)]
+
+//! zstd compression for what goes over the wire: file chunks, and messages
+//! large enough to be worth it. Markdown shrinks to a fraction of its size,
+//! and so does file content in JSON. Peers only compress once both offered
+//! the compression-zstd capability, so older peers keep working.
+
+use crate::types::SyncError;
+use std::io::Read;
+
+/// Payloads smaller than this are sent as they are
+pub const MIN_SIZE: usize = 512;
+const LEVEL: i32 = 3;
+/// Larger payloads are refused when decompressing, so a small malicious
+/// payload can't expand without bound
+const MAX_DECOMPRESSED_SIZE: u64 = 512 * 1024 * 1024;
+
+/// `data` compressed, or `None` when it is too small or doesn't shrink.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < MIN_SIZE {
+        return None;
+    }
+    zstd::bulk::compress(data, LEVEL)
+        .ok()
+        .filter(|compressed| compressed.len() < data.len())
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, SyncError> {
+    let mut decompressed = Vec::new();
+    zstd::stream::read::Decoder::new(data)?
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(SyncError::Network("Compressed payload expands beyond the size limit".to_string()));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let note = "# Meeting notes\n\n- [ ] follow up with the team\n".repeat(50);
+        let compressed = compress(note.as_bytes()).unwrap();
+        assert!(compressed.len() * 10 < note.len());
+        assert_eq!(decompress(&compressed).unwrap(), note.as_bytes());
+
+        assert!(compress(b"# Short").is_none());
+        let random: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        assert!(compress(&random).is_none());
+        assert!(decompress(b"not zstd").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_message() {
+        use crate::network::{encode_message, Encoding, MessageReader, NetworkMessage};
+
+        let request = NetworkMessage::FileRequest { path: "notes/".repeat(200) };
+        let plain = encode_message(&request, Encoding::default()).unwrap();
+        let compressed = encode_message(&request, Encoding { compress: true, binary: false }).unwrap();
+        assert!(compressed.len() < plain.len() / 4);
+
+        // Content goes as a binary frame once negotiated
+        let response = NetworkMessage::FileResponse {
+            path: "notes/a.md".to_string(),
+            found: true,
+            content: Some(vec![7; 10_000]),
+            metadata: Some(crate::types::FileMetadata { version: 2, ..crate::types::FileMetadata::for_test("notes/a.md", &[7; 10_000]) }),
+        };
+        let binary = encode_message(&response, Encoding { compress: false, binary: true }).unwrap();
+        assert_eq!(binary[0], crate::wire::FRAME_MARKER);
+        assert!(binary.len() < 10_200);
+        let NetworkMessage::FileResponse { content: Some(content), metadata: Some(metadata), .. } = &response else {
+            unreachable!()
+        };
+        let upload = NetworkMessage::FileTransfer {
+            path: "notes/a.md".to_string(),
+            content: content.clone(),
+            metadata: metadata.clone(),
+            idempotency_key: Some("key-1".to_string()),
+        };
+        let keyed = encode_message(&upload, Encoding { compress: false, binary: true }).unwrap();
+
+        let mut wire: &[u8] = &[compressed, plain, binary, keyed].concat();
+        let mut reader = MessageReader::new();
+        for _ in 0..2 {
+            let message = reader.next(&mut wire).await.unwrap();
+            assert!(matches!(message, Some(NetworkMessage::FileRequest { path }) if path.len() == 1200));
+        }
+        let message = reader.next(&mut wire).await.unwrap();
+        assert!(matches!(message, Some(NetworkMessage::FileResponse { content: Some(content), metadata: Some(metadata), .. })
+            if content.len() == 10_000 && metadata.version == 2));
+        let message = reader.next(&mut wire).await.unwrap();
+        assert!(matches!(message, Some(NetworkMessage::FileTransfer { idempotency_key: Some(key), .. }) if key == "key-1"));
+    }
+}
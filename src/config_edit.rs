@@ -0,0 +1,214 @@
+#![allow(dead_code)]
+
+use crate::cli::Config;
+use crate::types::SyncError;
+use serde_json::Value;
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Keys holding secrets, which need the config unlocked to read or change.
+const SECRET_KEYS: &[&str] = &["auth_token"];
+
+pub fn is_secret_key(key: &str) -> bool {
+    SECRET_KEYS.contains(&key)
+}
+
+/// Looks up a dotted key such as `device_name` or `sync_roots.0.enabled`.
+pub fn get(config: &Config, key: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    let value = serde_json::to_value(config)?;
+    let mut current = &value;
+    for segment in key.split('.') {
+        current = match current {
+            Value::Object(map) => map.get(segment),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        }
+        .ok_or_else(|| SyncError::Config(format!("Unknown config key: {}", key)))?;
+    }
+    Ok(current.clone())
+}
+
+/// Sets a dotted key. The value is parsed as JSON when possible, so `true`,
+/// `42` and `["a"]` keep their types, and anything else is stored as a string.
+pub fn set(config: &mut Config, key: &str, raw_value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let new_value = serde_json::from_str(raw_value).unwrap_or_else(|_| Value::String(raw_value.to_string()));
+    update(config, key, |parent, last| match parent {
+        Value::Object(map) => {
+            map.insert(last.to_string(), new_value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            let slot = last
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| items.get_mut(i))
+                .ok_or_else(|| format!("No element {} in {}", last, key))?;
+            *slot = new_value;
+            Ok(())
+        }
+        _ => Err(format!("Cannot set {}: parent is not an object or list", key).into()),
+    })
+}
+
+/// Removes a dotted key, falling back to its default if it has one.
+pub fn unset(config: &mut Config, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    update(config, key, |parent, last| match parent {
+        Value::Object(map) => map
+            .remove(last)
+            .map(|_| ())
+            .ok_or_else(|| SyncError::Config(format!("Unknown config key: {}", key)).into()),
+        Value::Array(items) => match last.parse::<usize>() {
+            Ok(i) if i < items.len() => {
+                items.remove(i);
+                Ok(())
+            }
+            _ => Err(format!("No element {} in {}", last, key).into()),
+        },
+        _ => Err(format!("Cannot unset {}", key).into()),
+    })
+}
+
+fn update<F>(config: &mut Config, key: &str, apply: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&mut Value, &str) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let mut value = serde_json::to_value(&*config)?;
+    let (parent_key, last) = match key.rsplit_once('.') {
+        Some((parent, last)) => (Some(parent), last),
+        None => (None, key),
+    };
+
+    let mut parent = &mut value;
+    if let Some(parent_key) = parent_key {
+        for segment in parent_key.split('.') {
+            parent = match parent {
+                Value::Object(map) => map.get_mut(segment),
+                Value::Array(items) => segment.parse::<usize>().ok().and_then(move |i| items.get_mut(i)),
+                _ => None,
+            }
+            .ok_or_else(|| SyncError::Config(format!("Unknown config key: {}", key)))?;
+        }
+    }
+
+    apply(parent, last)?;
+    config
+        .replace_from_value(value)
+        .map_err(|e| SyncError::Config(format!("Invalid value for {}: {}", key, e)))?;
+    Ok(())
+}
+
+/// Opens the config in `$VISUAL`/`$EDITOR` and only applies the result once it
+/// parses as a valid configuration.
+pub fn edit(config: &mut Config) -> Result<bool, Box<dyn std::error::Error>> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| if cfg!(windows) { "notepad".to_string() } else { "vi".to_string() });
+    let dir = Config::config_dir()?;
+    std::fs::create_dir_all(&dir)?;
+    edit_with(config, &editor, &dir)
+}
+
+/// Edits the config with `editor` in a file in `dir`. The file holds the
+/// unlocked secrets, so only the user can read it, and it is removed however
+/// editing ends.
+fn edit_with(config: &mut Config, editor: &str, dir: &std::path::Path) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut temp = tempfile::Builder::new().prefix("syncmd-config-").suffix(".json").tempfile_in(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        temp.as_file().set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    temp.write_all(serde_json::to_string_pretty(&*config)?.as_bytes())?;
+    temp.flush()?;
+    let temp_path = temp.path().to_path_buf();
+
+    let result = loop {
+        let status = if cfg!(windows) {
+            std::process::Command::new("cmd").arg("/C").arg(editor).arg(&temp_path).status()?
+        } else {
+            std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", editor))
+                .arg("sh")
+                .arg(&temp_path)
+                .status()?
+        };
+        if !status.success() {
+            break Err(format!("Editor '{}' exited with {}", editor, status).into());
+        }
+
+        let edited = std::fs::read_to_string(&temp_path)?;
+        let parsed = serde_json::from_str::<Value>(&edited)
+            .map_err(|e| e.to_string())
+            .and_then(|value| config.replace_from_value(value).map_err(|e| e.to_string()));
+
+        match parsed {
+            Ok(()) => break Ok(true),
+            Err(e) => {
+                eprintln!("Invalid configuration: {}", e);
+                if !ask_retry()? {
+                    break Ok(false);
+                }
+            }
+        }
+    };
+
+    // Dropping it removes the file
+    drop(temp);
+    result
+}
+
+fn ask_retry() -> Result<bool, Box<dyn std::error::Error>> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!("Re-open the editor? [Y/n] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(!answer.trim().eq_ignore_ascii_case("n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_set_unset() {
+        let mut config = Config::default();
+        set(&mut config, "device_name", "laptop").unwrap();
+        assert_eq!(get(&config, "device_name").unwrap(), Value::String("laptop".to_string()));
+        set(&mut config, "sync_roots", r#"[{"path": "/notes", "enabled": true}]"#).unwrap();
+        set(&mut config, "sync_roots.0.enabled", "false").unwrap();
+        assert_eq!(get(&config, "sync_roots.0.enabled").unwrap(), Value::Bool(false));
+        assert!(set(&mut config, "sync_roots.3.enabled", "true").is_err());
+        assert!(set(&mut config, "sync_roots.0.enabled", "\"yes\"").is_err());
+        assert!(get(&config, "no_such_key").is_err());
+
+        unset(&mut config, "sync_roots.0").unwrap();
+        assert_eq!(get(&config, "sync_roots").unwrap(), Value::Array(Vec::new()));
+        assert!(unset(&mut config, "no_such_key").is_err());
+        assert!(is_secret_key("auth_token") && !is_secret_key("device_name"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_edit_leaves_no_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mode = dir.path().join("mode");
+        let mut config = Config::default();
+        let editor = format!(
+            r#"f() {{ stat -c %a "$1" > {:?}; sed -i 's/"device_name": "[^"]*"/"device_name": "edited"/' "$1"; }}; f"#,
+            mode
+        );
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work).unwrap();
+        assert!(edit_with(&mut config, &editor, &work).unwrap());
+        assert_eq!(config.device_name, "edited");
+        assert_eq!(std::fs::read_to_string(&mode).unwrap().trim(), "600");
+        assert_eq!(std::fs::read_dir(&work).unwrap().count(), 0);
+
+        // Nor when the editor fails
+        assert!(edit_with(&mut config, "false", &work).is_err());
+        assert_eq!(std::fs::read_dir(&work).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+#![allow(dead_code)]
+
+//! Where conflict copies go. By default a copy sits next to the file it
+//! conflicts with, `notes/a.md` getting `notes/a.conflict-laptop.md`, and
+//! syncs like any other file. Roots that want a clean vault can keep copies
+//! under `.syncmd/conflicts/` instead, at the same relative path, where they
+//! stay on the device that made them.
+
+use crate::index_store::STATE_DIR_NAME;
+use crate::types::SyncError;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const CONFLICTS_DIR_NAME: &str = "conflicts";
+const MARKER: &str = ".conflict-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictLayout {
+    /// Next to the conflicting file
+    #[default]
+    Sibling,
+    /// Under `.syncmd/conflicts/`
+    Directory,
+}
+
+/// A conflict copy found in a root, with paths relative to the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictCopy {
+    pub original: PathBuf,
+    pub copy: PathBuf,
+    pub layout: ConflictLayout,
+}
+
+/// Where the copy of `path` made for `label`, usually a device slug, goes.
+pub fn copy_path(path: &Path, label: &str, layout: ConflictLayout) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}{}{}.{}", stem, MARKER, label, ext.to_string_lossy()),
+        None => format!("{}{}{}", stem, MARKER, label),
+    };
+    let copy = path.with_file_name(name);
+    match layout {
+        ConflictLayout::Sibling => copy,
+        ConflictLayout::Directory => Path::new(STATE_DIR_NAME).join(CONFLICTS_DIR_NAME).join(copy),
+    }
+}
+
+/// The name of the file a conflict copy was made of, `None` if `name`
+/// isn't a conflict copy.
+fn original_name(name: &str) -> Option<String> {
+    let (stem, rest) = name.rsplit_once(MARKER)?;
+    Some(match rest.split_once('.') {
+        Some((_, ext)) => format!("{}.{}", stem, ext),
+        None => stem.to_string(),
+    })
+}
+
+/// Whether `path` is named like a conflict copy.
+pub fn is_copy(path: &Path) -> bool {
+    path.file_name().and_then(|name| name.to_str()).and_then(original_name).is_some()
+}
+
+/// The file a conflict copy at `copy`, relative to its root, was made of.
+pub fn original_of(copy: &Path) -> Option<PathBuf> {
+    let original = copy.file_name()?.to_str().and_then(original_name)?;
+    let copy = copy.strip_prefix(Path::new(STATE_DIR_NAME).join(CONFLICTS_DIR_NAME)).unwrap_or(copy);
+    Some(copy.with_file_name(original))
+}
+
+/// Conflict copies in the root in either layout, sorted by original path.
+pub fn find(sync_root: &Path) -> Result<Vec<ConflictCopy>, SyncError> {
+    let conflicts_dir = sync_root.join(STATE_DIR_NAME).join(CONFLICTS_DIR_NAME);
+    let mut copies = Vec::new();
+    let entries = walkdir::WalkDir::new(sync_root)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != STATE_DIR_NAME)
+        .chain(walkdir::WalkDir::new(&conflicts_dir));
+    for entry in entries.filter_map(Result::ok) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Some(original) = entry.file_name().to_str().and_then(original_name) else {
+            continue;
+        };
+        let (layout, copy) = match entry.path().strip_prefix(&conflicts_dir) {
+            Ok(copy) => (ConflictLayout::Directory, copy),
+            Err(_) => (ConflictLayout::Sibling, entry.path().strip_prefix(sync_root)?),
+        };
+        copies.push(ConflictCopy {
+            original: copy.with_file_name(original),
+            copy: entry.path().strip_prefix(sync_root)?.to_path_buf(),
+            layout,
+        });
+    }
+    copies.sort_by(|a, b| a.original.cmp(&b.original).then_with(|| a.copy.cmp(&b.copy)));
+    Ok(copies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflict_layouts() {
+        let path = Path::new("notes/a.md");
+        assert_eq!(copy_path(path, "laptop", ConflictLayout::Sibling), Path::new("notes/a.conflict-laptop.md"));
+        let in_dir = copy_path(path, "phone", ConflictLayout::Directory);
+        assert_eq!(in_dir, Path::new(".syncmd/conflicts/notes/a.conflict-phone.md"));
+
+        let root = tempfile::tempdir().unwrap();
+        for copy in [PathBuf::from("notes/a.conflict-laptop.md"), in_dir, PathBuf::from("Makefile.conflict-pc")] {
+            std::fs::create_dir_all(root.path().join(&copy).parent().unwrap()).unwrap();
+            std::fs::write(root.path().join(copy), "x").unwrap();
+        }
+        std::fs::write(root.path().join("notes/a.md"), "x").unwrap();
+
+        let found = find(root.path()).unwrap();
+        let summary: Vec<(&str, ConflictLayout)> = found.iter()
+            .map(|copy| (copy.original.to_str().unwrap(), copy.layout))
+            .collect();
+        assert_eq!(summary, [
+            ("Makefile", ConflictLayout::Sibling),
+            ("notes/a.md", ConflictLayout::Directory),
+            ("notes/a.md", ConflictLayout::Sibling),
+        ]);
+        assert_eq!(found[1].copy, Path::new(".syncmd/conflicts/notes/a.conflict-phone.md"));
+        assert_eq!(original_of(&found[1].copy).unwrap(), Path::new("notes/a.md"));
+    }
+}
@@ -0,0 +1,422 @@
+#![allow(dead_code)]
+
+//! Control socket of `syncmd daemon`. Other invocations of `syncmd` connect
+//! to it to ask the running process for live status or to pause syncing,
+//! instead of reading whatever was last written to the config file.
+//!
+//! Requests and responses are single lines of JSON.
+
+use crate::events::{EventLog, SyncEvent};
+use crate::file_transfer::{QueuedTransfer, TransferQueue};
+use crate::maintenance::CleanupStats;
+use crate::network::ClientManager;
+use crate::open_files::OpenFiles;
+use crate::types::SyncError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+pub const SOCKET_NAME: &str = "daemon.sock";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    Status,
+    ListClients,
+    /// Pause one root, or all of them
+    Pause { path: Option<PathBuf> },
+    Resume { path: Option<PathBuf> },
+    /// Sync changes held back by the coalescing window now
+    Flush,
+    /// Transfers in progress
+    Transfers,
+    /// Stop a transfer, by id or by the path of its file
+    CancelTransfer { target: String },
+    /// What happened since event `after`
+    Events { after: u64 },
+    /// An editor opened or closed a file, see `open_files.rs`
+    Focus { path: PathBuf, open: bool },
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Status {
+        pid: u32,
+        started: chrono::DateTime<chrono::Utc>,
+        roots: Vec<RootStatus>,
+        /// What the periodic cleanup removed since the daemon started
+        #[serde(default)]
+        cleanup: CleanupStats,
+    },
+    Clients { clients: Vec<ClientSummary> },
+    Transfers { transfers: Vec<QueuedTransfer> },
+    Events { events: Vec<SyncEvent> },
+    Done { message: String },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RootStatus {
+    pub path: PathBuf,
+    pub paused: bool,
+    /// Remote the root is currently connected to
+    pub connected_to: Option<String>,
+    pub files: usize,
+    pub last_sync: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<String>,
+    /// Latest flush request the root has synced for
+    #[serde(default)]
+    pub flushed: u64,
+    /// The root's sync task ended with `last_error`
+    #[serde(default)]
+    pub stopped: bool,
+    /// The root directory is gone, e.g. an unmounted drive, and syncing
+    /// waits for it to return
+    #[serde(default)]
+    pub missing: bool,
+}
+
+/// A connected client, without its token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientSummary {
+    pub id: String,
+    pub name: String,
+    pub address: String,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+}
+
+/// State shared between the per-root sync tasks and the control socket.
+#[derive(Clone)]
+pub struct DaemonState {
+    started: chrono::DateTime<chrono::Utc>,
+    roots: Arc<Mutex<BTreeMap<PathBuf, RootStatus>>>,
+    clients: Arc<ClientManager>,
+    shutdown: Arc<tokio::sync::Notify>,
+    flush: Arc<tokio::sync::watch::Sender<u64>>,
+    /// Bumped whenever a root is paused or resumed
+    pauses: Arc<tokio::sync::watch::Sender<u64>>,
+    cleanup: Arc<Mutex<CleanupStats>>,
+    transfers: TransferQueue,
+    events: EventLog,
+    open_files: OpenFiles,
+}
+
+/// How long a flush over the control socket waits for the roots
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl DaemonState {
+    pub fn new(clients: Arc<ClientManager>) -> Self {
+        Self {
+            started: chrono::Utc::now(),
+            roots: Arc::new(Mutex::new(BTreeMap::new())),
+            clients,
+            shutdown: Arc::new(tokio::sync::Notify::new()),
+            flush: Arc::new(tokio::sync::watch::channel(0).0),
+            pauses: Arc::new(tokio::sync::watch::channel(0).0),
+            cleanup: Arc::new(Mutex::new(CleanupStats::default())),
+            transfers: TransferQueue::global().clone(),
+            events: EventLog::global().clone(),
+            open_files: OpenFiles::global().clone(),
+        }
+    }
+
+    pub fn add_root(&self, path: PathBuf) {
+        let status = RootStatus {
+            path: path.clone(),
+            ..Default::default()
+        };
+        self.roots.lock().unwrap().insert(path, status);
+    }
+
+    pub fn update(&self, path: &Path, change: impl FnOnce(&mut RootStatus)) {
+        if let Some(status) = self.roots.lock().unwrap().get_mut(path) {
+            change(status);
+        }
+    }
+
+    pub fn is_paused(&self, path: &Path) -> bool {
+        self.roots.lock().unwrap().get(path).is_some_and(|status| status.paused)
+    }
+
+    /// Pauses or resumes the root at `path`, or every root when `None`.
+    /// Returns how many roots changed.
+    pub fn set_paused(&self, path: Option<&Path>, paused: bool) -> usize {
+        let mut roots = self.roots.lock().unwrap();
+        let mut changed = 0;
+        for status in roots.values_mut() {
+            if path.is_some_and(|path| path != status.path) || status.paused == paused {
+                continue;
+            }
+            status.paused = paused;
+            changed += 1;
+        }
+        drop(roots);
+        if changed > 0 {
+            self.pauses.send_modify(|generation| *generation += 1);
+        }
+        changed
+    }
+
+    pub fn root_paths(&self) -> Vec<PathBuf> {
+        self.roots.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn clients(&self) -> &ClientManager {
+        &self.clients
+    }
+
+    /// Adds a cleanup run to the totals reported in the status.
+    pub fn record_cleanup(&self, run: &CleanupStats) {
+        self.cleanup.lock().unwrap().record(run);
+    }
+
+    pub fn has_root(&self, path: &Path) -> bool {
+        self.roots.lock().unwrap().contains_key(path)
+    }
+
+    /// Resolves when a shutdown was requested over the socket.
+    pub async fn shutdown_requested(&self) {
+        self.shutdown.notified().await
+    }
+
+    /// Asks every root to sync now and returns the request's generation,
+    /// which roots store in `RootStatus::flushed` once they have.
+    pub fn request_flush(&self) -> u64 {
+        self.flush.send_modify(|generation| *generation += 1);
+        *self.flush.borrow()
+    }
+
+    /// Changes whenever a root is paused or resumed.
+    pub fn pause_changes(&self) -> tokio::sync::watch::Receiver<u64> {
+        self.pauses.subscribe()
+    }
+
+    /// Changes whenever a flush is requested.
+    pub fn flush_requests(&self) -> tokio::sync::watch::Receiver<u64> {
+        self.flush.subscribe()
+    }
+
+    /// Waits until every running, unpaused root has synced for flush
+    /// `generation`. Returns false if that took longer than `timeout`.
+    pub async fn wait_flushed(&self, generation: u64, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let done = self.roots.lock().unwrap().values()
+                .all(|status| status.paused || status.stopped || status.flushed >= generation);
+            if done {
+                return true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn handle(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Status => ControlResponse::Status {
+                pid: std::process::id(),
+                started: self.started,
+                roots: self.roots.lock().unwrap().values().cloned().collect(),
+                cleanup: self.cleanup.lock().unwrap().clone(),
+            },
+            ControlRequest::ListClients => ControlResponse::Clients {
+                clients: self.clients.list_clients().await
+                    .into_iter()
+                    .map(|client| ClientSummary {
+                        id: client.id,
+                        name: client.name,
+                        address: client.address,
+                        last_seen: client.last_seen,
+                    })
+                    .collect(),
+            },
+            ControlRequest::Pause { path } => self.pause_response(path, true),
+            ControlRequest::Resume { path } => self.pause_response(path, false),
+            ControlRequest::Flush => {
+                let generation = self.request_flush();
+                if self.wait_flushed(generation, FLUSH_TIMEOUT).await {
+                    ControlResponse::Done { message: "Synced pending changes".to_string() }
+                } else {
+                    ControlResponse::Error { message: "Timed out waiting for roots to sync".to_string() }
+                }
+            }
+            ControlRequest::Transfers => ControlResponse::Transfers { transfers: self.transfers.list() },
+            ControlRequest::CancelTransfer { target } => match self.transfers.cancel(&target).len() {
+                0 => ControlResponse::Error { message: format!("No transfer matches {}", target) },
+                cancelled => ControlResponse::Done { message: format!("Cancelling {} transfer(s)", cancelled) },
+            },
+            ControlRequest::Events { after } => ControlResponse::Events { events: self.events.since(after) },
+            ControlRequest::Focus { path, open } => {
+                if !self.root_paths().iter().any(|root| path.starts_with(root)) {
+                    return ControlResponse::Error { message: format!("{} is not in a root the daemon syncs", path.display()) };
+                }
+                self.open_files.report(&path, open);
+                let state = if open { "open" } else { "closed" };
+                ControlResponse::Done { message: format!("{} is {}", path.display(), state) }
+            }
+            ControlRequest::Shutdown => {
+                self.shutdown.notify_one();
+                ControlResponse::Done { message: "Shutting down".to_string() }
+            }
+        }
+    }
+
+    fn pause_response(&self, path: Option<PathBuf>, paused: bool) -> ControlResponse {
+        if let Some(path) = path.as_deref().filter(|path| !self.has_root(path)) {
+            return ControlResponse::Error {
+                message: format!("{} is not synced by the daemon", path.display()),
+            };
+        }
+        let changed = self.set_paused(path.as_deref(), paused);
+        let verb = if paused { "Paused" } else { "Resumed" };
+        ControlResponse::Done {
+            message: format!("{} {} root(s)", verb, changed),
+        }
+    }
+}
+
+/// Where the control socket lives: the runtime directory when there is one,
+/// otherwise next to the config file.
+pub fn socket_path() -> Result<PathBuf, SyncError> {
+    let dir = dirs::runtime_dir()
+        .or_else(dirs::config_dir)
+        .ok_or_else(|| SyncError::Io(std::io::Error::other("Could not find a runtime or config directory")))?;
+    Ok(dir.join("syncmd").join(SOCKET_NAME))
+}
+
+#[cfg(unix)]
+pub type ControlListener = tokio::net::UnixListener;
+
+#[cfg(not(unix))]
+pub struct ControlListener;
+
+/// Claims the control socket. Fails if another daemon is already listening
+/// on it.
+#[cfg(unix)]
+pub async fn bind(socket: &Path) -> Result<ControlListener, SyncError> {
+    if socket.exists() {
+        if tokio::net::UnixStream::connect(socket).await.is_ok() {
+            return Err(SyncError::Io(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("A daemon is already running on {}", socket.display()),
+            )));
+        }
+        // Left behind by a daemon that didn't shut down cleanly
+        std::fs::remove_file(socket)?;
+    }
+    if let Some(parent) = socket.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(tokio::net::UnixListener::bind(socket)?)
+}
+
+#[cfg(not(unix))]
+pub async fn bind(_socket: &Path) -> Result<ControlListener, SyncError> {
+    Err(SyncError::Io(std::io::Error::other("The daemon control socket needs Unix domain sockets")))
+}
+
+/// Answers control connections until the process exits.
+#[cfg(unix)]
+pub async fn serve(state: DaemonState, listener: ControlListener) -> Result<(), SyncError> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&state, stream).await {
+                tracing::debug!("Control connection error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_state: DaemonState, _listener: ControlListener) -> Result<(), SyncError> {
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn handle_connection(state: &DaemonState, stream: tokio::net::UnixStream) -> Result<(), SyncError> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => state.handle(request).await,
+            Err(e) => ControlResponse::Error { message: format!("Invalid request: {}", e) },
+        };
+        let mut encoded = serde_json::to_vec(&response)?;
+        encoded.push(b'\n');
+        write_half.write_all(&encoded).await?;
+    }
+    Ok(())
+}
+
+/// Sends one request to the running daemon. Returns `None` when no daemon
+/// is listening, so callers can fall back to the config file.
+#[cfg(unix)]
+pub async fn query(socket: &Path, request: &ControlRequest) -> Result<Option<ControlResponse>, SyncError> {
+    let stream = match tokio::net::UnixStream::connect(socket).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused) => {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let (read_half, mut write_half) = stream.into_split();
+    let mut encoded = serde_json::to_vec(request)?;
+    encoded.push(b'\n');
+    write_half.write_all(&encoded).await?;
+    let line = BufReader::new(read_half).lines().next_line().await?
+        .ok_or_else(|| SyncError::Network("The daemon closed the control connection".to_string()))?;
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+#[cfg(not(unix))]
+pub async fn query(_socket: &Path, _request: &ControlRequest) -> Result<Option<ControlResponse>, SyncError> {
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join(SOCKET_NAME);
+        assert!(query(&socket, &ControlRequest::Status).await.unwrap().is_none());
+
+        let state = DaemonState::new(Arc::new(ClientManager::new()));
+        state.add_root(PathBuf::from("/notes"));
+        state.add_root(PathBuf::from("/work"));
+        let listener = bind(&socket).await.unwrap();
+        let server = tokio::spawn(serve(state.clone(), listener));
+        let pause_changes = state.pause_changes();
+
+        let pause = ControlRequest::Pause { path: Some(PathBuf::from("/notes")) };
+        let response = query(&socket, &pause).await.unwrap().unwrap();
+        assert!(matches!(response, ControlResponse::Done { .. }));
+        assert!(state.is_paused(Path::new("/notes")));
+        assert!(!state.is_paused(Path::new("/work")));
+        // The root's sync task is woken to stop watching
+        assert!(pause_changes.has_changed().unwrap());
+
+        let unknown = ControlRequest::Resume { path: Some(PathBuf::from("/elsewhere")) };
+        let response = query(&socket, &unknown).await.unwrap().unwrap();
+        assert!(matches!(response, ControlResponse::Error { .. }));
+
+        let Some(ControlResponse::Status { roots, .. }) = query(&socket, &ControlRequest::Status).await.unwrap() else {
+            panic!("expected a status response");
+        };
+        assert_eq!(roots.iter().filter(|root| root.paused).count(), 1);
+
+        assert!(bind(&socket).await.is_err());
+        server.abort();
+    }
+}
@@ -0,0 +1,264 @@
+#![allow(dead_code)]
+
+use crate::types::SyncError;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// UDP port beacons are broadcast to and listened for on.
+pub const DISCOVERY_PORT: u16 = 21127;
+const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+/// Beacons older than this are treated as replays and dropped.
+const MAX_BEACON_AGE_SECS: i64 = 60;
+pub const IDENTITY_FILE_NAME: &str = "identity.key";
+const MAX_BEACON_SIZE: usize = 2048;
+
+/// What a device tells the LAN about itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub device_id: String,
+    pub name: String,
+    pub shares: Vec<String>,
+    pub port: u16,
+    pub public_key: String,
+    pub timestamp: i64,
+}
+
+/// An announcement plus an ed25519 signature over its exact JSON encoding.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedBeacon {
+    payload: String,
+    signature: String,
+}
+
+/// A peer whose public key was exchanged out of band and whose beacons are
+/// therefore trusted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub device_id: String,
+    pub name: String,
+    pub public_key: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerTrust {
+    /// Signed by the key on record for this device
+    Verified,
+    /// Validly signed, but the device isn't a known peer
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredPeer {
+    pub announcement: Announcement,
+    pub address: SocketAddr,
+    pub trust: PeerTrust,
+}
+
+/// This device's signing key, created on first use and kept next to the
+/// config file.
+pub struct DeviceIdentity {
+    keypair: Keypair,
+}
+
+impl DeviceIdentity {
+    pub fn load_or_create() -> Result<Self, SyncError> {
+        let path = Self::identity_path()?;
+        let secret_bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut bytes = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, &bytes)?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+                }
+                bytes
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let secret = SecretKey::from_bytes(&secret_bytes)
+            .map_err(|e| SyncError::Auth(format!("Invalid identity key: {}", e)))?;
+        let public = PublicKey::from(&secret);
+        Ok(Self { keypair: Keypair { secret, public } })
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.keypair.public.as_bytes())
+    }
+
+    fn identity_path() -> Result<PathBuf, SyncError> {
+        let config_dir = dirs::config_dir()
+            .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))?;
+        Ok(config_dir.join("syncmd").join(IDENTITY_FILE_NAME))
+    }
+
+    /// Base64 ed25519 signature over `data`.
+    pub fn sign_bytes(&self, data: &[u8]) -> String {
+        BASE64.encode(self.keypair.sign(data).to_bytes())
+    }
+
+    fn sign(&self, announcement: &Announcement) -> Result<Vec<u8>, SyncError> {
+        let payload = serde_json::to_string(announcement)?;
+        let signature = self.keypair.sign(payload.as_bytes());
+        let beacon = SignedBeacon {
+            payload,
+            signature: BASE64.encode(signature.to_bytes()),
+        };
+        Ok(serde_json::to_vec(&beacon)?)
+    }
+}
+
+/// Broadcasts a signed beacon every few seconds until the task is dropped.
+pub async fn broadcast_beacons(
+    identity: DeviceIdentity,
+    device_id: String,
+    name: String,
+    shares: Vec<String>,
+    port: u16,
+) -> Result<(), SyncError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    let target = SocketAddr::from(([255, 255, 255, 255], DISCOVERY_PORT));
+
+    let mut interval = tokio::time::interval(BEACON_INTERVAL);
+    loop {
+        interval.tick().await;
+        let announcement = Announcement {
+            device_id: device_id.clone(),
+            name: name.clone(),
+            shares: shares.clone(),
+            port,
+            public_key: identity.public_key(),
+            timestamp: chrono::Utc::now().timestamp(),
+        };
+        if let Err(e) = socket.send_to(&identity.sign(&announcement)?, target).await {
+            tracing::debug!("Failed to send discovery beacon: {}", e);
+        }
+    }
+}
+
+/// Listens for beacons for the given time and returns the latest valid one
+/// per device. Beacons claiming a known device but signed by another key are
+/// dropped as spoofed.
+pub async fn listen(duration: Duration, known_peers: &[KnownPeer]) -> Result<Vec<DiscoveredPeer>, SyncError> {
+    let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT)).await?;
+    let mut found: HashMap<String, DiscoveredPeer> = HashMap::new();
+    let mut buffer = vec![0u8; MAX_BEACON_SIZE];
+
+    let deadline = tokio::time::Instant::now() + duration;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (n, address) = received?;
+        match verify_beacon(&buffer[..n], known_peers) {
+            Ok((announcement, trust)) => {
+                found.insert(
+                    announcement.device_id.clone(),
+                    DiscoveredPeer { announcement, address, trust },
+                );
+            }
+            Err(e) => tracing::warn!("Ignoring beacon from {}: {}", address, e),
+        }
+    }
+
+    let mut peers: Vec<DiscoveredPeer> = found.into_values().collect();
+    peers.sort_by(|a, b| a.announcement.name.cmp(&b.announcement.name));
+    Ok(peers)
+}
+
+fn verify_beacon(data: &[u8], known_peers: &[KnownPeer]) -> Result<(Announcement, PeerTrust), SyncError> {
+    let beacon: SignedBeacon = serde_json::from_slice(data)?;
+    let announcement: Announcement = serde_json::from_str(&beacon.payload)?;
+
+    let age = chrono::Utc::now().timestamp() - announcement.timestamp;
+    if age.abs() > MAX_BEACON_AGE_SECS {
+        return Err(SyncError::Auth("Stale beacon".to_string()));
+    }
+
+    // A known device must sign with the key on record, not the one it claims
+    let known = known_peers.iter().find(|p| p.device_id == announcement.device_id);
+    let (public_key, trust) = match known {
+        Some(peer) => (&peer.public_key, PeerTrust::Verified),
+        None => (&announcement.public_key, PeerTrust::Unknown),
+    };
+
+    let public_key = decode_public_key(public_key)?;
+    let signature_bytes = BASE64
+        .decode(&beacon.signature)
+        .map_err(|e| SyncError::Auth(format!("Invalid signature encoding: {}", e)))?;
+    let signature = Signature::try_from(signature_bytes.as_slice())
+        .map_err(|e| SyncError::Auth(format!("Invalid signature: {}", e)))?;
+    public_key
+        .verify_strict(beacon.payload.as_bytes(), &signature)
+        .map_err(|_| SyncError::Auth(format!("Bad signature for device {}", announcement.device_id)))?;
+
+    Ok((announcement, trust))
+}
+
+/// Checks a signature made by [`DeviceIdentity::sign_bytes`].
+pub fn verify_bytes(public_key: &str, data: &[u8], signature: &str) -> Result<(), SyncError> {
+    let public_key = decode_public_key(public_key)?;
+    let signature_bytes = BASE64
+        .decode(signature)
+        .map_err(|e| SyncError::Auth(format!("Invalid signature encoding: {}", e)))?;
+    let signature = Signature::try_from(signature_bytes.as_slice())
+        .map_err(|e| SyncError::Auth(format!("Invalid signature: {}", e)))?;
+    public_key
+        .verify_strict(data, &signature)
+        .map_err(|_| SyncError::Auth("Bad signature".to_string()))
+}
+
+pub fn decode_public_key(encoded: &str) -> Result<PublicKey, SyncError> {
+    let bytes = BASE64
+        .decode(encoded)
+        .map_err(|e| SyncError::Auth(format!("Invalid public key encoding: {}", e)))?;
+    PublicKey::from_bytes(&bytes).map_err(|e| SyncError::Auth(format!("Invalid public key: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(seed: u8) -> DeviceIdentity {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        DeviceIdentity { keypair: Keypair { secret, public } }
+    }
+
+    #[test]
+    fn test_spoofed_beacon_is_rejected() {
+        let real = identity(1);
+        let spoofer = identity(2);
+        let known = vec![KnownPeer {
+            device_id: "laptop".to_string(),
+            name: "Laptop".to_string(),
+            public_key: real.public_key(),
+        }];
+        let announcement = |identity: &DeviceIdentity| Announcement {
+            device_id: "laptop".to_string(),
+            name: "Laptop".to_string(),
+            shares: vec!["notes".to_string()],
+            port: 8080,
+            public_key: identity.public_key(),
+            timestamp: chrono::Utc::now().timestamp(),
+        };
+
+        let genuine = real.sign(&announcement(&real)).unwrap();
+        assert_eq!(verify_beacon(&genuine, &known).unwrap().1, PeerTrust::Verified);
+
+        let spoofed = spoofer.sign(&announcement(&spoofer)).unwrap();
+        assert!(verify_beacon(&spoofed, &known).is_err());
+        assert_eq!(verify_beacon(&spoofed, &[]).unwrap().1, PeerTrust::Unknown);
+    }
+}
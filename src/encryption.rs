@@ -0,0 +1,324 @@
+#![allow(dead_code)]
+
+//! End-to-end encryption of a sync root, for servers that shouldn't see the
+//! notes they store. Every device of the folder derives the same key from a
+//! shared passphrase and salt; content and file names are encrypted before
+//! they leave the device and decrypted after they arrive.
+//!
+//! File names are encrypted per path component and deterministically, so
+//! the server can still diff, page and filter by subscription without
+//! learning them. Content uses a random nonce and the plaintext path as
+//! associated data, so the server can't swap files between paths. Hashes
+//! the server sees are keyed, so they can't be matched against known files.
+
+use crate::types::{FileMetadata, InlineContent, SyncError, SyncOperation};
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, engine::general_purpose::URL_SAFE_NO_PAD as NAME, Engine};
+use hmac::Hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::{Component, Path, PathBuf};
+
+/// Environment variable consulted before prompting for a folder passphrase.
+pub const PASSPHRASE_ENV: &str = "SYNCMD_FOLDER_PASSPHRASE";
+
+const KDF_NAME: &str = "pbkdf2-sha256";
+const KDF_ITERATIONS: u32 = 200_000;
+/// Bounds on the iterations a root's settings may ask for: below, the key
+/// stretching is too weak to matter, above, unlocking never finishes
+const MIN_KDF_ITERATIONS: u32 = 100_000;
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Per-root settings kept in the config. Nothing here is secret; devices
+/// need the same salt and passphrase to end up with the same key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FolderEncryption {
+    pub kdf: String,
+    pub iterations: u32,
+    pub salt: String,
+    /// Derived from the key, to tell a mistyped passphrase from a
+    /// different folder
+    pub check: String,
+}
+
+#[derive(Clone)]
+pub struct FolderKey {
+    content: Aes256Gcm,
+    names: Aes256Gcm,
+    name_nonces: [u8; 32],
+    hashes: [u8; 32],
+}
+
+impl std::fmt::Debug for FolderKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FolderKey").finish_non_exhaustive()
+    }
+}
+
+impl FolderEncryption {
+    /// Settings with a fresh salt, or the salt another device printed when
+    /// it enabled encryption for the folder.
+    pub fn new(passphrase: &str, salt: Option<&str>) -> Result<(Self, FolderKey), SyncError> {
+        let salt = match salt {
+            Some(salt) => decode(salt)?,
+            None => {
+                let mut salt = vec![0u8; SALT_LEN];
+                rand::thread_rng().fill_bytes(&mut salt);
+                salt
+            }
+        };
+        let master = derive_master(passphrase, &salt, KDF_ITERATIONS);
+        let settings = Self {
+            kdf: KDF_NAME.to_string(),
+            iterations: KDF_ITERATIONS,
+            salt: BASE64.encode(&salt),
+            check: check_value(&master),
+        };
+        Ok((settings, FolderKey::from_master(&master)))
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<FolderKey, SyncError> {
+        if self.kdf != KDF_NAME {
+            return Err(SyncError::Encryption(format!("Unsupported key derivation: {}", self.kdf)));
+        }
+        if !(MIN_KDF_ITERATIONS..=MAX_KDF_ITERATIONS).contains(&self.iterations) {
+            return Err(SyncError::Encryption(format!(
+                "The folder's settings ask for {} key derivation iterations, expected {} to {}",
+                self.iterations, MIN_KDF_ITERATIONS, MAX_KDF_ITERATIONS
+            )));
+        }
+        let master = derive_master(passphrase, &decode(&self.salt)?, self.iterations);
+        if check_value(&master) != self.check {
+            return Err(SyncError::Encryption("Wrong folder passphrase".to_string()));
+        }
+        Ok(FolderKey::from_master(&master))
+    }
+}
+
+impl FolderKey {
+    fn from_master(master: &[u8; 32]) -> Self {
+        let content = blake3::derive_key("syncmd 2024 folder content", master);
+        let names = blake3::derive_key("syncmd 2024 folder names", master);
+        Self {
+            content: Aes256Gcm::new(Key::from_slice(&content)),
+            names: Aes256Gcm::new(Key::from_slice(&names)),
+            name_nonces: blake3::derive_key("syncmd 2024 folder name nonces", master),
+            hashes: blake3::derive_key("syncmd 2024 folder hashes", master),
+        }
+    }
+
+    pub fn encrypt_path(&self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => self.encrypt_name(&name.to_string_lossy()),
+                other => other.as_os_str().to_string_lossy().to_string(),
+            })
+            .collect()
+    }
+
+    /// The plaintext of a path from the server, which isn't trusted with
+    /// anything else either: every component must be an encrypted name, and
+    /// each must decrypt to a single plain name.
+    pub fn decrypt_path(&self, path: &Path) -> Result<PathBuf, SyncError> {
+        let unsafe_path = || SyncError::Encryption(format!("{} is not a path inside the folder", path.display()));
+        let decrypted: PathBuf = path.components()
+            .map(|component| match component {
+                Component::Normal(name) => self.decrypt_name(&name.to_string_lossy()),
+                _ => Err(unsafe_path()),
+            })
+            .map(|name| match name {
+                Ok(name) if Path::new(&name).components().count() == 1 => Ok(name),
+                Ok(_) => Err(unsafe_path()),
+                Err(e) => Err(e),
+            })
+            .collect::<Result<_, _>>()?;
+        match crate::types::is_contained(&decrypted) {
+            true => Ok(decrypted),
+            false => Err(unsafe_path()),
+        }
+    }
+
+    /// A nonce derived from the name itself, so equal names encrypt alike
+    /// and different ones never share a nonce.
+    fn encrypt_name(&self, name: &str) -> String {
+        let digest = blake3::keyed_hash(&self.name_nonces, name.as_bytes());
+        let nonce = &digest.as_bytes()[..NONCE_LEN];
+        let ciphertext = self.names
+            .encrypt(Nonce::from_slice(nonce), name.as_bytes())
+            .expect("AES-GCM encryption of a short name can't fail");
+        NAME.encode([nonce, &ciphertext].concat())
+    }
+
+    fn decrypt_name(&self, encoded: &str) -> Result<String, SyncError> {
+        let bytes = NAME.decode(encoded)
+            .map_err(|_| SyncError::Encryption(format!("{} is not an encrypted name", encoded)))?;
+        let plaintext = open(&self.names, &bytes, &[])
+            .ok_or_else(|| SyncError::Encryption(format!("{} wasn't encrypted with this folder's key", encoded)))?;
+        String::from_utf8(plaintext).map_err(|_| SyncError::Encryption("Encrypted name isn't UTF-8".to_string()))
+    }
+
+    /// `path` is the plaintext relative path the content belongs to.
+    pub fn encrypt_content(&self, path: &Path, content: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = path.to_string_lossy();
+        let ciphertext = self.content
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: content, aad: aad.as_bytes() })
+            .expect("AES-GCM encryption can't fail for in-memory content");
+        [&nonce[..], &ciphertext].concat()
+    }
+
+    pub fn decrypt_content(&self, path: &Path, content: &[u8]) -> Result<Vec<u8>, SyncError> {
+        open(&self.content, content, path.to_string_lossy().as_bytes())
+            .ok_or_else(|| SyncError::Encryption(format!("Content of {} failed to decrypt", path.display())))
+    }
+
+    fn keyed_hash(&self, hash: &str) -> String {
+        blake3::keyed_hash(&self.hashes, hash.as_bytes()).to_hex().to_string()
+    }
+
+    /// What the server is told about a local file.
+    pub fn encrypt_metadata(&self, metadata: &FileMetadata) -> FileMetadata {
+        FileMetadata {
+            path: self.encrypt_path(&metadata.path),
+            hash: self.keyed_hash(&metadata.hash),
+            size: metadata.size + (NONCE_LEN + TAG_LEN) as u64,
+            ..metadata.clone()
+        }
+    }
+
+    /// Turns the server's answer back into plaintext paths and content.
+    /// Entries that don't decrypt, such as files uploaded before the folder
+    /// was encrypted, are left out with a warning. Downloaded files keep a
+    /// keyed hash until their content arrives; inlined ones get their real
+    /// hash straight away.
+    pub fn decrypt_operations(
+        &self,
+        operations: Vec<SyncOperation>,
+        mut inline: InlineContent,
+    ) -> (Vec<SyncOperation>, InlineContent) {
+        let mut decrypted_inline = InlineContent::default();
+        let mut metadata = |encrypted: FileMetadata| -> Result<FileMetadata, SyncError> {
+            let path = self.decrypt_path(&encrypted.path)?;
+            let mut decrypted = FileMetadata {
+                path,
+                size: encrypted.size.saturating_sub((NONCE_LEN + TAG_LEN) as u64),
+                ..encrypted.clone()
+            };
+            if let Some(ciphertext) = inline.take_unverified(&encrypted.path) {
+                let content = self.decrypt_content(&decrypted.path, &ciphertext)?;
+                decrypted.hash = blake3::hash(&content).to_hex().to_string();
+                decrypted_inline.insert(decrypted.path.clone(), &content);
+            }
+            Ok(decrypted)
+        };
+
+        let mut decrypted = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = match operation {
+                SyncOperation::Add(encrypted) => metadata(encrypted).map(SyncOperation::Add),
+                SyncOperation::Update(encrypted) => metadata(encrypted).map(SyncOperation::Update),
+                SyncOperation::Delete(path) => self.decrypt_path(&path).map(SyncOperation::Delete),
+                SyncOperation::Rename { from, to } => self.decrypt_path(&from)
+                    .and_then(|from| Ok(SyncOperation::Rename { from, to: metadata(to)? })),
+                SyncOperation::MkDir(path) => self.decrypt_path(&path).map(SyncOperation::MkDir),
+                SyncOperation::RmDir(path) => self.decrypt_path(&path).map(SyncOperation::RmDir),
+            };
+            match result {
+                Ok(operation) => decrypted.push(operation),
+                Err(e) => eprintln!("Skipping server entry: {}", e),
+            }
+        }
+        (decrypted, decrypted_inline)
+    }
+}
+
+/// Reads the folder passphrase from the environment, or interactively from
+/// the terminal without echoing it.
+pub fn read_passphrase(prompt: &str) -> Result<String, SyncError> {
+    if let Some(passphrase) = passphrase_from_env() {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password(prompt)?;
+    if passphrase.is_empty() {
+        return Err(SyncError::Encryption("Empty passphrase".to_string()));
+    }
+    Ok(passphrase)
+}
+
+pub fn passphrase_from_env() -> Option<String> {
+    std::env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty())
+}
+
+fn derive_master(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut master = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, iterations, &mut master);
+    master
+}
+
+fn check_value(master: &[u8; 32]) -> String {
+    blake3::derive_key("syncmd 2024 folder key check", master)
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Decrypts nonce-prefixed ciphertext.
+fn open(cipher: &Aes256Gcm, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }).ok()
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, SyncError> {
+    BASE64
+        .decode(value)
+        .map_err(|e| SyncError::Encryption(format!("Invalid salt: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folder_round_trip() {
+        let (settings, key) = FolderEncryption::new("correct horse", None).unwrap();
+        let other_device = FolderEncryption::new("correct horse", Some(&settings.salt)).unwrap().1;
+        assert!(settings.unlock("wrong horse").is_err());
+
+        let path = Path::new("journal/2024/notes.md");
+        let encrypted = key.encrypt_path(path);
+        assert_eq!(encrypted.components().count(), 3);
+        assert!(!encrypted.to_string_lossy().contains("notes"));
+        assert_eq!(other_device.encrypt_path(path), encrypted);
+        assert_eq!(other_device.decrypt_path(&encrypted).unwrap(), path);
+        // Subscriptions still match by directory
+        assert!(encrypted.starts_with(key.encrypt_path(Path::new("journal"))));
+        assert!(key.decrypt_path(Path::new("journal/notes.md")).is_err());
+        // Nothing the server sends leads outside the folder
+        let escaping = Path::new("..").join("..").join(key.encrypt_path(Path::new(".bashrc")));
+        assert!(key.decrypt_path(&escaping).is_err());
+        assert!(key.decrypt_path(&Path::new("/").join(&encrypted)).is_err());
+        assert!(key.decrypt_path(&key.encrypt_path(Path::new(".."))).is_err());
+
+        let sealed = key.encrypt_content(path, b"# Secret");
+        assert_ne!(sealed, key.encrypt_content(path, b"# Secret"));
+        assert_eq!(other_device.decrypt_content(path, &sealed).unwrap(), b"# Secret");
+        assert!(key.decrypt_content(Path::new("other.md"), &sealed).is_err());
+
+        // Iterations come from the config and are only taken within bounds
+        for iterations in [0, 1, MIN_KDF_ITERATIONS - 1, MAX_KDF_ITERATIONS + 1, u32::MAX] {
+            let tampered = FolderEncryption { iterations, ..settings.clone() };
+            assert!(matches!(tampered.unlock("correct horse"), Err(SyncError::Encryption(_))));
+        }
+        assert!(settings.unlock("correct horse").is_ok());
+    }
+}
@@ -0,0 +1,112 @@
+#![allow(dead_code)]
+
+//! What the sync engine did, kept in memory for `syncmd ui`. Sync code
+//! anywhere in the process records events in the global log, and the
+//! daemon's control socket hands out the ones a client hasn't seen yet.
+//! Only the latest [`MAX_EVENTS`] are kept.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+pub const MAX_EVENTS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    /// A sync round changed files
+    Synced,
+    Downloaded,
+    Deleted,
+    /// A conflict copy was made
+    Conflict,
+    Connected,
+    Disconnected,
+    /// The root directory went away
+    RootMissing,
+    RootReturned,
+    /// Syncing the root was paused or resumed on request
+    Paused,
+    Resumed,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncEvent {
+    /// Increases by one with every event, starting at 1
+    pub seq: u64,
+    pub time: DateTime<Utc>,
+    /// The root it happened in, if any
+    pub root: Option<PathBuf>,
+    pub kind: EventKind,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    inner: Arc<Mutex<Recorded>>,
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    latest: u64,
+    events: VecDeque<SyncEvent>,
+}
+
+impl EventLog {
+    /// The log of this process, the one the daemon's control socket sees.
+    pub fn global() -> &'static EventLog {
+        static LOG: OnceLock<EventLog> = OnceLock::new();
+        LOG.get_or_init(EventLog::default)
+    }
+
+    pub fn record(&self, root: Option<&Path>, kind: EventKind, message: impl Into<String>) {
+        let mut recorded = self.inner.lock().unwrap();
+        recorded.latest += 1;
+        let event = SyncEvent {
+            seq: recorded.latest,
+            time: Utc::now(),
+            root: root.map(Path::to_path_buf),
+            kind,
+            message: message.into(),
+        };
+        if recorded.events.len() == MAX_EVENTS {
+            recorded.events.pop_front();
+        }
+        recorded.events.push_back(event);
+    }
+
+    /// Events after sequence number `after`, oldest first.
+    pub fn since(&self, after: u64) -> Vec<SyncEvent> {
+        let recorded = self.inner.lock().unwrap();
+        recorded.events.iter().filter(|event| event.seq > after).cloned().collect()
+    }
+}
+
+/// Records an event in the global log.
+pub fn record(root: Option<&Path>, kind: EventKind, message: impl Into<String>) {
+    EventLog::global().record(root, kind, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log_keeps_latest() {
+        let log = EventLog::default();
+        for i in 0..MAX_EVENTS + 10 {
+            log.record(Some(Path::new("/notes")), EventKind::Downloaded, format!("file {}", i));
+        }
+        let events = log.since(0);
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0].seq, 11);
+        assert_eq!(events.last().unwrap().message, format!("file {}", MAX_EVENTS + 9));
+
+        log.record(None, EventKind::Conflict, "notes/a.md");
+        let latest = events.last().unwrap().seq;
+        assert!(matches!(&log.since(latest)[..], [event] if event.kind == EventKind::Conflict));
+    }
+}
@@ -0,0 +1,169 @@
+#![allow(dead_code)]
+
+//! Exit codes of the `syncmd` commands. They are stable, so wrappers and
+//! scripts can branch on why a command failed; `--errors json` adds the
+//! details as one JSON object on stderr.
+
+use crate::types::SyncError;
+use serde::Serialize;
+use std::error::Error;
+
+/// Listed in `syncmd --help`.
+pub const HELP: &str = "\
+Exit codes:
+  0  success
+  1  any other error
+  2  invalid arguments
+  3  configuration error
+  4  authentication error
+  5  network error
+  6  unresolved conflict
+  7  finished, but some files failed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ErrorFormat {
+    #[default]
+    Text,
+    /// One JSON object with the code, kind and message
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    Other,
+    Usage,
+    Config,
+    Auth,
+    Network,
+    Conflict,
+    Partial,
+}
+
+impl FailureKind {
+    pub fn code(&self) -> u8 {
+        match self {
+            FailureKind::Other => 1,
+            FailureKind::Usage => 2,
+            FailureKind::Config => 3,
+            FailureKind::Auth => 4,
+            FailureKind::Network => 5,
+            FailureKind::Conflict => 6,
+            FailureKind::Partial => 7,
+        }
+    }
+
+    /// What kind of failure `error` is, going by the first error in its
+    /// chain that says.
+    pub fn of(error: &(dyn Error + 'static)) -> Self {
+        let mut current = Some(error);
+        while let Some(error) = current {
+            if let Some(kind) = Self::classify(error) {
+                return kind;
+            }
+            current = error.source();
+        }
+        FailureKind::Other
+    }
+
+    fn classify(error: &(dyn Error + 'static)) -> Option<Self> {
+        if let Some(error) = error.downcast_ref::<SyncError>() {
+            return match error {
+                SyncError::Config(_) => Some(FailureKind::Config),
+                SyncError::Auth(_)
+                | SyncError::PermissionDenied(_)
+                | SyncError::TokenExpired
+                | SyncError::TokenRevoked
+                | SyncError::InvalidToken
+                | SyncError::SessionExpired => Some(FailureKind::Auth),
+                SyncError::Network(_) => Some(FailureKind::Network),
+                SyncError::Conflict(_) => Some(FailureKind::Conflict),
+                SyncError::Strict(_) | SyncError::Partial(_) => Some(FailureKind::Partial),
+                SyncError::Io(error) => Self::classify(error),
+                _ => None,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind;
+            return match error.kind() {
+                ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::AddrNotAvailable
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::UnexpectedEof => Some(FailureKind::Network),
+                _ => None,
+            };
+        }
+        if error.is::<tokio::time::error::Elapsed>() {
+            return Some(FailureKind::Network);
+        }
+        None
+    }
+}
+
+/// What `--errors json` prints.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub code: u8,
+    pub kind: FailureKind,
+    pub message: String,
+    /// The errors that led to this one, outermost first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<String>,
+}
+
+impl ErrorReport {
+    pub fn new(error: &(dyn Error + 'static)) -> Self {
+        let kind = FailureKind::of(error);
+        let mut causes = Vec::new();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        Self { code: kind.code(), kind, message: error.to_string(), causes }
+    }
+}
+
+/// Prints `error` to stderr in `format` and returns the code to exit with.
+pub fn report(error: &(dyn Error + 'static), format: ErrorFormat) -> std::process::ExitCode {
+    let report = ErrorReport::new(error);
+    match format {
+        ErrorFormat::Text => {
+            eprintln!("{}", crate::style::deleted(format!("Error: {}", report.message)));
+            for cause in &report.causes {
+                eprintln!("  caused by: {}", cause);
+            }
+        }
+        ErrorFormat::Json => match serde_json::to_string(&report) {
+            Ok(json) => eprintln!("{}", json),
+            Err(_) => eprintln!("Error: {}", report.message),
+        },
+    }
+    std::process::ExitCode::from(report.code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_kinds() {
+        let error: Box<dyn Error> = SyncError::TokenRevoked.into();
+        assert_eq!(FailureKind::of(error.as_ref()).code(), 4);
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(FailureKind::of(&SyncError::Io(refused)), FailureKind::Network);
+        assert_eq!(FailureKind::of(&SyncError::Io(std::io::ErrorKind::NotFound.into())), FailureKind::Other);
+        let error: Box<dyn Error> = "something else".into();
+        assert_eq!(FailureKind::of(error.as_ref()), FailureKind::Other);
+
+        let report = ErrorReport::new(&SyncError::Config("No remote named nas".to_string()));
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"code":3,"kind":"config","message":"Configuration error: No remote named nas"}"#
+        );
+    }
+}
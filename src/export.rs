@@ -0,0 +1,101 @@
+#![allow(dead_code)]
+
+use crate::index_store::{IndexStore, JournalOp};
+use crate::indexer::FileIndexer;
+use crate::types::SyncError;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const FILE_LIST_NAME: &str = "files.txt";
+pub const DELETED_LIST_NAME: &str = "deleted.txt";
+pub const ARCHIVE_NAME: &str = "changes.tar";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// Plain file list for `rsync --files-from`
+    FilesFrom,
+    /// File list plus a tarball with the changed files
+    Tar,
+}
+
+#[derive(Debug)]
+pub struct ExportReport {
+    pub since: u64,
+    pub until: u64,
+    pub changed: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    pub out_dir: PathBuf,
+    /// The journal no longer reaches back to `since`, so every file was
+    /// exported and deletions are unknown
+    pub full: bool,
+}
+
+/// Exports the changes recorded in the journal after `since` in a form that
+/// rsync-only infrastructure can consume.
+pub fn export_batch(
+    indexer: &FileIndexer,
+    since: u64,
+    format: ExportFormat,
+    out_dir: &Path,
+) -> Result<ExportReport, SyncError> {
+    let sync_root = indexer.sync_root().as_path();
+
+    // Bring the journal up to date before reading from it
+    let state = indexer.index_directory()?;
+    let mut store = IndexStore::open(sync_root)?;
+    store.save_state(&state)?;
+
+    // Only the final operation per path matters for a mirror
+    let mut latest: BTreeMap<PathBuf, JournalOp> = BTreeMap::new();
+    let full = since < store.compacted_through()?;
+    if full {
+        for path in state.local_files.keys() {
+            latest.insert(path.clone(), JournalOp::Add);
+        }
+    } else {
+        for entry in store.journal_since(since)? {
+            latest.insert(entry.path, entry.op);
+        }
+    }
+
+    let (deleted, changed): (Vec<_>, Vec<_>) = latest
+        .into_iter()
+        .partition(|(_, op)| *op == JournalOp::Delete);
+    let changed: Vec<PathBuf> = changed
+        .into_iter()
+        .map(|(path, _)| path)
+        .filter(|path| indexer.local_path(path).is_file())
+        .collect();
+    let deleted: Vec<PathBuf> = deleted.into_iter().map(|(path, _)| path).collect();
+
+    std::fs::create_dir_all(out_dir)?;
+    write_path_list(&out_dir.join(FILE_LIST_NAME), &changed)?;
+    write_path_list(&out_dir.join(DELETED_LIST_NAME), &deleted)?;
+
+    if format == ExportFormat::Tar {
+        let archive = std::fs::File::create(out_dir.join(ARCHIVE_NAME))?;
+        let mut builder = tar::Builder::new(archive);
+        for path in &changed {
+            builder.append_path_with_name(indexer.local_path(path), path)?;
+        }
+        builder.finish()?;
+    }
+
+    Ok(ExportReport {
+        since,
+        until: store.latest_seq()?,
+        changed,
+        deleted,
+        out_dir: out_dir.to_path_buf(),
+        full,
+    })
+}
+
+fn write_path_list(list_path: &Path, paths: &[PathBuf]) -> Result<(), SyncError> {
+    let mut file = std::fs::File::create(list_path)?;
+    for path in paths {
+        writeln!(file, "{}", path.to_string_lossy())?;
+    }
+    Ok(())
+}
@@ -1,15 +1,32 @@
 #![allow(dead_code)]
 
 use crate::types::{SyncError, FileMetadata, TransferProgress};
+use crate::wire::FrameReader;
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::io::{Read, Seek, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use serde::{Serialize, Deserialize};
 use std::time::Instant;
 
 const CHUNK_SIZE: usize = 64 * 1024; // 64KB chunks
 const MAX_RETRIES: u32 = 3;
 const MAX_CONCURRENT_TRANSFERS: usize = 5;
+/// Chunks a sender keeps in flight before waiting for acks
+const SEND_WINDOW: usize = 32;
+/// A receiver that acks nothing for this long lost the chunks in flight
+const ACK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Files from this size on go as a windowed transfer once the peer
+/// negotiated windowed-transfer; smaller ones go whole in one message
+pub const WINDOWED_TRANSFER_SIZE: u64 = (CHUNK_SIZE * SEND_WINDOW) as u64;
+/// Under the state directory, one file per content hash being received
+pub const PARTIAL_DIR_NAME: &str = "partial";
+/// Transfers without a chunk for this long are given up, keeping their
+/// partial file for the next attempt
+pub const STALLED_TRANSFER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+/// Progress updates a subscriber can fall behind by before it misses some
+const PROGRESS_CAPACITY: usize = 256;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileTransferHeader {
@@ -18,27 +35,228 @@ pub struct FileTransferHeader {
     pub chunks: u32,
     pub metadata: FileMetadata,
     pub transfer_id: String,
+    /// blake3 of every chunk, so a receiver whose file doesn't verify can
+    /// tell which chunks are bad and ask for only those again
+    #[serde(default)]
+    pub chunk_hashes: Vec<String>,
+    /// Merkle root over `chunk_hashes`, checked before they are trusted
+    #[serde(default)]
+    pub chunk_root: String,
+}
+
+impl FileTransferHeader {
+    /// The chunk hashes add up to the Merkle root and cover every chunk.
+    /// Headers without chunk hashes, from older senders, pass.
+    pub fn chunk_hashes_valid(&self) -> bool {
+        self.chunk_hashes.is_empty()
+            || (self.chunk_hashes.len() == self.chunks as usize
+                && crate::merkle::chunk_root(&self.chunk_hashes) == self.chunk_root)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileChunk {
     pub transfer_id: String,
     pub chunk_index: u32,
+    #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
+    /// Of the uncompressed data
     pub checksum: String,
+    /// `data` is zstd-compressed
+    #[serde(default)]
+    pub compressed: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FileTransferMessage {
     StartTransfer(FileTransferHeader),
     Chunk(FileChunk),
+    /// The receiver's answer to `StartTransfer`: the first chunk it still
+    /// needs, non-zero when an earlier transfer of the same content, from
+    /// any source, was interrupted
+    ResumeFrom { transfer_id: String, chunk_index: u32 },
+    /// Acknowledges that one chunk arrived intact. Several chunks can be in
+    /// flight, each is acked on its own
     AckChunk { transfer_id: String, chunk_index: u32 },
     CompleteTransfer { transfer_id: String },
     TransferError { transfer_id: String, error: String },
+    /// Either side gave the transfer up on purpose; the receiver drops what
+    /// it has so far
+    Cancel { transfer_id: String },
+    /// The receiver's answer to `CompleteTransfer` when the file came out
+    /// corrupt: the chunks whose hashes don't match the header, to be sent
+    /// again before the next `CompleteTransfer`
+    RequestChunks { transfer_id: String, chunks: Vec<std::ops::Range<u32>> },
+    /// The receiver's answer to `CompleteTransfer` when the file verified
+    TransferVerified { transfer_id: String },
+    /// The chunk arrived with a bad checksum; the sender sends it again
+    NackChunk { transfer_id: String, chunk_index: u32 },
+}
+
+/// A transfer running in this process, as listed by `syncmd queue`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedTransfer {
+    pub id: String,
+    /// Local file being sent or received
+    pub path: PathBuf,
+    pub size: u64,
+    pub started: chrono::DateTime<chrono::Utc>,
+    /// Bytes moved so far, for transfers that report progress
+    #[serde(default)]
+    pub transferred: u64,
+}
+
+/// Transfers in progress, each with a flag the code moving its data checks
+/// between chunks, so it can be cancelled from elsewhere. Their progress is
+/// broadcast to subscribers as it moves.
+#[derive(Debug, Clone)]
+pub struct TransferQueue {
+    transfers: Arc<Mutex<std::collections::HashMap<String, QueueEntry>>>,
+    progress: tokio::sync::broadcast::Sender<TransferProgress>,
+}
+
+impl Default for TransferQueue {
+    fn default() -> Self {
+        Self {
+            transfers: Arc::default(),
+            progress: tokio::sync::broadcast::channel(PROGRESS_CAPACITY).0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct QueueEntry {
+    transfer: QueuedTransfer,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Keeps a transfer in its queue until dropped.
+#[derive(Debug)]
+pub struct TransferHandle {
+    queue: TransferQueue,
+    id: String,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TransferQueue {
+    /// The queue of this process, the one the daemon's control socket sees.
+    pub fn global() -> &'static TransferQueue {
+        static QUEUE: OnceLock<TransferQueue> = OnceLock::new();
+        QUEUE.get_or_init(TransferQueue::default)
+    }
+
+    pub fn register(&self, id: &str, path: &Path, size: u64) -> TransferHandle {
+        let transfer = QueuedTransfer {
+            id: id.to_string(),
+            path: path.to_path_buf(),
+            size,
+            started: chrono::Utc::now(),
+            transferred: 0,
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.transfers.lock().unwrap().insert(id.to_string(), QueueEntry { transfer, cancelled: cancelled.clone() });
+        TransferHandle { queue: self.clone(), id: id.to_string(), cancelled }
+    }
+
+    /// Oldest first.
+    pub fn list(&self) -> Vec<QueuedTransfer> {
+        let mut transfers: Vec<QueuedTransfer> = self.transfers.lock().unwrap()
+            .values()
+            .map(|entry| entry.transfer.clone())
+            .collect();
+        transfers.sort_by(|a, b| a.started.cmp(&b.started).then_with(|| a.id.cmp(&b.id)));
+        transfers
+    }
+
+    /// Progress of every transfer from now on: an update whenever bytes
+    /// move, and a last one, `finished`, when the transfer ends either way.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<TransferProgress> {
+        self.progress.subscribe()
+    }
+
+    fn publish(&self, transfer: &QueuedTransfer, finished: bool) {
+        // Nobody listening is fine
+        let _ = self.progress.send(progress_of(transfer, finished));
+    }
+
+    /// Asks the transfer with id `target`, or every transfer of a file whose
+    /// path ends with `target`, to stop. Returns the transfers asked.
+    pub fn cancel(&self, target: &str) -> Vec<QueuedTransfer> {
+        let transfers = self.transfers.lock().unwrap();
+        let by_id = transfers.get(target).into_iter().collect::<Vec<_>>();
+        let matched = if by_id.is_empty() && !target.is_empty() {
+            transfers.values().filter(|entry| entry.transfer.path.ends_with(target)).collect()
+        } else {
+            by_id
+        };
+        matched.into_iter()
+            .map(|entry| {
+                entry.cancelled.store(true, Ordering::SeqCst);
+                entry.transfer.clone()
+            })
+            .collect()
+    }
+}
+
+impl TransferHandle {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Records how many bytes of the transfer have been moved.
+    pub fn set_transferred(&self, bytes: u64) {
+        let mut transfers = self.queue.transfers.lock().unwrap();
+        if let Some(entry) = transfers.get_mut(&self.id) {
+            entry.transfer.transferred = bytes;
+            self.queue.publish(&entry.transfer, false);
+        }
+    }
+}
+
+impl Drop for TransferHandle {
+    fn drop(&mut self) {
+        if let Some(entry) = self.queue.transfers.lock().unwrap().remove(&self.id) {
+            self.queue.publish(&entry.transfer, true);
+        }
+    }
+}
+
+/// Where a transfer stands, counting chunks of `CHUNK_SIZE`.
+fn progress_of(transfer: &QueuedTransfer, finished: bool) -> TransferProgress {
+    let elapsed = (chrono::Utc::now() - transfer.started).to_std().unwrap_or_default().as_secs_f64();
+    let progress = match transfer.size {
+        0 => 100.0,
+        size => transfer.transferred as f64 * 100.0 / size as f64,
+    };
+    let speed = match elapsed > 0.0 {
+        true => transfer.transferred as f64 / elapsed / 1024.0 / 1024.0,
+        false => 0.0,
+    };
+    TransferProgress {
+        transfer_id: transfer.id.clone(),
+        path: transfer.path.clone(),
+        bytes_transferred: transfer.transferred,
+        total_bytes: transfer.size,
+        progress,
+        speed_mbps: speed,
+        elapsed_seconds: elapsed,
+        estimated_remaining_seconds: if progress > 0.0 { ((elapsed * 100.0 / progress) - elapsed).max(0.0) } else { 0.0 },
+        chunks_received: transfer.transferred.div_ceil(CHUNK_SIZE as u64) as u32,
+        total_chunks: transfer.size.div_ceil(CHUNK_SIZE as u64) as u32,
+        finished,
+    }
 }
 
 pub struct FileTransferManager {
     active_transfers: std::collections::HashMap<String, FileTransferState>,
+    /// Transfers given up for making no progress
+    stalled_transfers: u64,
+    /// Compress chunks, once the peer negotiated compression-zstd
+    compress: bool,
+    /// Send messages as binary frames, once the peer negotiated
+    /// binary-frames
+    binary: bool,
+    queue: TransferQueue,
 }
 
 #[derive(Debug)]
@@ -46,20 +264,65 @@ struct FileTransferState {
     path: PathBuf,
     size: u64,
     chunks_received: u32,
+    /// Which chunks the partial file holds; they can arrive out of order
+    /// once one is sent again
+    received: Vec<bool>,
     total_chunks: u32,
     metadata: FileMetadata,
+    partial_path: PathBuf,
     temp_file: Option<std::fs::File>,
     started_at: Instant,
     last_progress: std::time::Instant,
+    /// When the last chunk arrived
+    last_chunk_at: Instant,
+    /// From the header, empty if the sender sent none
+    chunk_hashes: Vec<String>,
+    /// Rounds of corrupt chunks asked for again
+    repairs: u32,
+    /// Unregisters the transfer from the queue when the state goes
+    handle: TransferHandle,
+}
+
+/// How a finished transfer checked out.
+enum Verification {
+    /// The partial file holds the content the header names
+    Verified(FileTransferState),
+    /// The chunks to ask for again
+    Repair(FileTransferMessage),
+}
+
+impl Default for FileTransferManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FileTransferManager {
     pub fn new() -> Self {
         Self {
             active_transfers: std::collections::HashMap::new(),
+            stalled_transfers: 0,
+            compress: false,
+            binary: false,
+            queue: TransferQueue::global().clone(),
         }
     }
 
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn with_binary_frames(mut self, binary: bool) -> Self {
+        self.binary = binary;
+        self
+    }
+
+    pub fn with_queue(mut self, queue: TransferQueue) -> Self {
+        self.queue = queue;
+        self
+    }
+
     pub fn is_image_file(path: &Path) -> bool {
         path.extension()
             .and_then(|ext| ext.to_str())
@@ -76,89 +339,273 @@ impl FileTransferManager {
             .unwrap_or(false)
     }
 
-    pub async fn send_file(
+    fn encode(&self, message: &FileTransferMessage) -> Result<Vec<u8>, SyncError> {
+        if self.binary {
+            crate::wire::encode_frame(message, false)
+        } else {
+            Ok(serde_json::to_vec(message)?)
+        }
+    }
+
+    /// Answers the peer's `Cancel` with one, the last message of the
+    /// transfer either side sends, so the peer knows nothing else of it is
+    /// still coming.
+    async fn confirm_cancel<S>(&self, stream: &mut S, transfer_id: &str) -> Result<(), SyncError>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let cancel = FileTransferMessage::Cancel { transfer_id: transfer_id.to_string() };
+        stream.write_all(&self.encode(&cancel)?).await?;
+        Ok(())
+    }
+
+    pub async fn send_file<S>(&self, stream: &mut S, file_path: &Path, metadata: FileMetadata) -> Result<(), SyncError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let file = std::fs::File::open(file_path)?;
+        let file_size = file.metadata()?.len();
+        let hashed_path = file_path.to_path_buf();
+        let chunk_hashes = run_blocking(move || hash_chunks(&hashed_path)).await?;
+        let header = header(file_path.to_string_lossy().to_string(), file_size, chunk_hashes, metadata);
+        self.send(stream, &mut FrameReader::default(), file, file_path, header).await
+    }
+
+    /// Sends `content` as `path` to a peer taking it with
+    /// [`receive_content`](Self::receive_content), reading its replies
+    /// through `replies`, the connection's reader. The header names the
+    /// hash of `content` itself, which for an encrypted root isn't
+    /// `metadata`'s.
+    pub async fn send_content<S>(
         &self,
-        stream: &mut tokio::net::TcpStream,
-        file_path: &Path,
+        stream: &mut S,
+        replies: &mut FrameReader,
+        path: &str,
+        content: Vec<u8>,
         metadata: FileMetadata,
-    ) -> Result<(), SyncError> {
-        let transfer_id = uuid::Uuid::new_v4().to_string();
-        let mut file = std::fs::File::open(file_path)?;
-        let file_size = file.metadata()?.len();
-        let total_chunks = (file_size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+    ) -> Result<(), SyncError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (content, hash, chunk_hashes) = run_blocking(move || {
+            let hash = blake3::hash(&content).to_string();
+            let chunk_hashes = content.chunks(CHUNK_SIZE).map(|chunk| blake3::hash(chunk).to_string()).collect();
+            Ok((content, hash, chunk_hashes))
+        })
+        .await?;
+        let header = header(path.to_string(), content.len() as u64, chunk_hashes, FileMetadata { hash, ..metadata });
+        self.send(stream, replies, std::io::Cursor::new(content), Path::new(path), header).await
+    }
 
+    /// Sends the header, then every chunk of `file` the receiver still
+    /// needs, then repairs whatever it finds corrupt.
+    async fn send<S, R>(
+        &self,
+        stream: &mut S,
+        replies: &mut FrameReader,
+        mut file: R,
+        file_path: &Path,
+        header: FileTransferHeader,
+    ) -> Result<(), SyncError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        R: Read + Seek + Send + 'static,
+    {
+        let transfer_id = header.transfer_id.clone();
+        let (file_size, total_chunks) = (header.size, header.chunks);
         println!("Starting file transfer: {} ({} bytes, {} chunks)", 
             file_path.display(), file_size, total_chunks);
 
-        // Send transfer header
-        let header = FileTransferHeader {
-            path: file_path.to_string_lossy().to_string(),
-            size: file_size,
-            chunks: total_chunks as u32,
-            metadata: metadata.clone(),
-            transfer_id: transfer_id.clone(),
-        };
-
+        let handle = self.queue.register(&transfer_id, file_path, file_size);
         let header_msg = FileTransferMessage::StartTransfer(header);
-        let header_data = serde_json::to_vec(&header_msg)?;
-        stream.write_all(&header_data).await?;
+        stream.write_all(&self.encode(&header_msg)?).await?;
+
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(stream).await?;
+        let chunk_index = match reply.ok_or_else(|| SyncError::Network("Connection closed".to_string()))? {
+            FileTransferMessage::ResumeFrom { chunk_index, .. } => chunk_index.min(total_chunks),
+            FileTransferMessage::TransferError { error, .. } => {
+                return Err(SyncError::Network(format!("Transfer error: {}", error)));
+            }
+            FileTransferMessage::Cancel { .. } => return Err(SyncError::Cancelled(file_path.to_path_buf())),
+            _ => return Err(SyncError::Network("Unexpected reply to transfer header".to_string())),
+        };
+        if chunk_index > 0 {
+            println!("Resuming at chunk {} of {}", chunk_index, total_chunks);
+        }
 
         // Send file chunks
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let mut chunk_index = 0;
-        let mut bytes_sent = 0;
+        let bytes_sent = chunk_index as u64 * CHUNK_SIZE as u64;
+        file = self
+            .send_window(stream, replies, file, file_path, &handle, chunk_index..total_chunks, bytes_sent, file_size)
+            .await?;
 
+        // Send completion message, and the chunks the receiver found corrupt
+        // until it verifies the file
+        let mut repairs = 0;
         loop {
-            let bytes_read = file.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+            let complete_msg = FileTransferMessage::CompleteTransfer { transfer_id: transfer_id.clone() };
+            stream.write_all(&self.encode(&complete_msg)?).await?;
+
+            let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(stream).await?;
+            match reply.ok_or_else(|| SyncError::Network("Connection closed".to_string()))? {
+                FileTransferMessage::TransferVerified { .. } => break,
+                FileTransferMessage::RequestChunks { chunks, .. } if repairs < MAX_RETRIES => {
+                    repairs += 1;
+                    let count: u32 = chunks.iter().map(|range| range.len() as u32).sum();
+                    println!("Resending {} corrupt chunks of {}", count, file_path.display());
+                    let chunks = chunks.into_iter().flatten().filter(|index| *index < total_chunks);
+                    file = self.send_window(stream, replies, file, file_path, &handle, chunks, file_size, file_size)
+                        .await?;
+                }
+                FileTransferMessage::RequestChunks { .. } => {
+                    return Err(SyncError::Network(format!(
+                        "{} still corrupt after {} repairs",
+                        file_path.display(),
+                        repairs
+                    )));
+                }
+                FileTransferMessage::TransferError { error, .. } => {
+                    return Err(SyncError::Network(format!("Transfer error: {}", error)));
+                }
+                FileTransferMessage::Cancel { .. } => {
+                    self.confirm_cancel(stream, &transfer_id).await?;
+                    return Err(SyncError::Cancelled(file_path.to_path_buf()));
+                }
+                _ => return Err(SyncError::Network("Unexpected reply to transfer completion".to_string())),
             }
+        }
 
-            let chunk_data = buffer[..bytes_read].to_vec();
-            let checksum = blake3::hash(&chunk_data).to_string();
+        println!("File transfer completed: {}", file_path.display());
+        Ok(())
+    }
 
-            let chunk = FileChunk {
-                transfer_id: transfer_id.clone(),
-                chunk_index,
-                data: chunk_data,
-                checksum,
-            };
+    /// Sends `chunks` of `file`, keeping up to [`SEND_WINDOW`] of them
+    /// unacknowledged at a time instead of waiting for every ack. Chunks the
+    /// receiver reports bad, or all outstanding ones when it goes quiet for
+    /// [`ACK_TIMEOUT`], are sent again, each up to [`MAX_RETRIES`] times.
+    /// `bytes_sent` of `file_size` is what the queue shows as done before
+    /// the first ack.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_window<S, R>(
+        &self,
+        stream: &mut S,
+        replies: &mut FrameReader,
+        mut file: R,
+        file_path: &Path,
+        handle: &TransferHandle,
+        mut chunks: impl Iterator<Item = u32>,
+        mut bytes_sent: u64,
+        file_size: u64,
+    ) -> Result<R, SyncError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        R: Read + Seek + Send + 'static,
+    {
+        let transfer_id = handle.id.clone();
+        // Chunk index to its size and how often it was sent again
+        let mut outstanding: std::collections::BTreeMap<u32, (usize, u32)> = std::collections::BTreeMap::new();
+        let mut resend: std::collections::VecDeque<(u32, u32)> = std::collections::VecDeque::new();
 
-            let chunk_msg = FileTransferMessage::Chunk(chunk);
-            let chunk_data = serde_json::to_vec(&chunk_msg)?;
-            stream.write_all(&chunk_data).await?;
-
-            // Wait for acknowledgment
-            let mut ack_buffer = vec![0u8; 1024];
-            let n = stream.read(&mut ack_buffer).await?;
-            if n > 0 {
-                let ack: FileTransferMessage = serde_json::from_slice(&ack_buffer[..n])?;
-                match ack {
-                    FileTransferMessage::AckChunk { transfer_id: ack_id, chunk_index: ack_index } => {
-                        if ack_id == transfer_id && ack_index == chunk_index {
-                            bytes_sent += bytes_read as u64;
-                            self.print_progress(&transfer_id, bytes_sent, file_size);
-                        }
-                    }
-                    FileTransferMessage::TransferError { transfer_id: _error_id, error } => {
-                        return Err(SyncError::Network(format!("Transfer error: {}", error)));
+        loop {
+            while outstanding.len() < SEND_WINDOW {
+                if handle.is_cancelled() {
+                    let cancel = FileTransferMessage::Cancel { transfer_id: transfer_id.clone() };
+                    stream.write_all(&self.encode(&cancel)?).await?;
+                    skip_to_cancel(stream, replies).await?;
+                    println!("File transfer cancelled: {}", file_path.display());
+                    return Err(SyncError::Cancelled(file_path.to_path_buf()));
+                }
+                let Some((chunk_index, retries)) = resend.pop_front().or_else(|| chunks.next().map(|index| (index, 0)))
+                else {
+                    break;
+                };
+                let (returned_file, frame, bytes_read) = self.read_chunk_frame(file, &transfer_id, chunk_index).await?;
+                file = returned_file;
+                if bytes_read > 0 {
+                    stream.write_all(&frame).await?;
+                    outstanding.insert(chunk_index, (bytes_read, retries));
+                }
+            }
+            if outstanding.is_empty() {
+                return Ok(file);
+            }
+
+            let next = replies.next::<FileTransferMessage, FileTransferMessage, _>(stream);
+            let Ok(reply) = tokio::time::timeout(ACK_TIMEOUT, next).await else {
+                eprintln!("No acks for {}s, sending {} chunks again", ACK_TIMEOUT.as_secs(), outstanding.len());
+                for (chunk_index, (_, retries)) in std::mem::take(&mut outstanding) {
+                    resend.push_back((chunk_index, retries + 1));
+                }
+                if resend.iter().any(|(_, retries)| *retries > MAX_RETRIES) {
+                    return Err(SyncError::Network("Receiver stopped acknowledging chunks".to_string()));
+                }
+                continue;
+            };
+            match reply?.ok_or_else(|| SyncError::Network("Connection closed".to_string()))? {
+                FileTransferMessage::AckChunk { chunk_index, .. } => {
+                    if let Some((bytes_read, _)) = outstanding.remove(&chunk_index) {
+                        bytes_sent = (bytes_sent + bytes_read as u64).min(file_size);
+                        handle.set_transferred(bytes_sent);
                     }
-                    _ => {
-                        eprintln!("Unexpected message during transfer");
+                }
+                FileTransferMessage::NackChunk { chunk_index, .. } => {
+                    if let Some((_, retries)) = outstanding.remove(&chunk_index) {
+                        if retries >= MAX_RETRIES {
+                            return Err(SyncError::Network(format!("Chunk {} kept arriving corrupt", chunk_index)));
+                        }
+                        resend.push_back((chunk_index, retries + 1));
                     }
                 }
+                FileTransferMessage::TransferError { error, .. } => {
+                    return Err(SyncError::Network(format!("Transfer error: {}", error)));
+                }
+                FileTransferMessage::Cancel { .. } => {
+                    self.confirm_cancel(stream, &transfer_id).await?;
+                    println!("File transfer cancelled by the receiver: {}", file_path.display());
+                    return Err(SyncError::Cancelled(file_path.to_path_buf()));
+                }
+                _ => eprintln!("Unexpected message during transfer"),
             }
-
-            chunk_index += 1;
         }
+    }
 
-        // Send completion message
-        let complete_msg = FileTransferMessage::CompleteTransfer { transfer_id: transfer_id.clone() };
-        let complete_data = serde_json::to_vec(&complete_msg)?;
-        stream.write_all(&complete_data).await?;
+    /// Reads chunk `chunk_index` of `file` and encodes it for sending. Hands
+    /// the file back with the encoded message and the chunk's size, 0 past
+    /// the end of the file.
+    async fn read_chunk_frame<R>(
+        &self,
+        file: R,
+        transfer_id: &str,
+        chunk_index: u32,
+    ) -> Result<(R, Vec<u8>, usize), SyncError>
+    where
+        R: Read + Seek + Send + 'static,
+    {
+        // Disk reads, hashing and compression run on the blocking pool so
+        // a slow disk or a large chunk doesn't stall the runtime
+        let compress = self.compress;
+        let (file, data, checksum, compressed) = run_blocking(move || {
+            let mut file = file;
+            let data = read_chunk(&mut file, chunk_index)?;
+            let checksum = blake3::hash(&data).to_string();
+            let compressed = if compress { crate::compression::compress(&data) } else { None };
+            Ok((file, data, checksum, compressed))
+        })
+        .await?;
+        if data.is_empty() {
+            return Ok((file, Vec::new(), 0));
+        }
 
-        println!("File transfer completed: {}", file_path.display());
-        Ok(())
+        let bytes_read = data.len();
+        let chunk = FileChunk {
+            transfer_id: transfer_id.to_string(),
+            chunk_index,
+            compressed: compressed.is_some(),
+            data: compressed.unwrap_or(data),
+            checksum,
+        };
+        let frame = self.encode(&FileTransferMessage::Chunk(chunk))?;
+        Ok((file, frame, bytes_read))
     }
 
     pub async fn receive_file(
@@ -166,30 +613,67 @@ impl FileTransferManager {
         stream: &mut tokio::net::TcpStream,
         base_path: &Path,
     ) -> Result<(), SyncError> {
-        let mut buffer = vec![0u8; CHUNK_SIZE + 1024]; // Extra space for metadata
+        let mut messages = FrameReader::default();
 
         loop {
-            let n = stream.read(&mut buffer).await?;
-            if n == 0 {
+            let next = messages.next::<FileTransferMessage, FileTransferMessage, _>(stream);
+            let message = match tokio::time::timeout(STALLED_TRANSFER_TIMEOUT, next).await {
+                Ok(message) => message?,
+                Err(_) => {
+                    for error in self.expire_stalled(STALLED_TRANSFER_TIMEOUT) {
+                        stream.write_all(&self.encode(&error)?).await?;
+                    }
+                    continue;
+                }
+            };
+            let Some(message) = message else {
                 break;
-            }
-
-            let message: FileTransferMessage = serde_json::from_slice(&buffer[..n])?;
+            };
 
             match message {
                 FileTransferMessage::StartTransfer(header) => {
-                    self.start_transfer(header, base_path).await?;
+                    let transfer_id = header.transfer_id.clone();
+                    if !header.chunk_hashes_valid() {
+                        let reply = FileTransferMessage::TransferError {
+                            transfer_id,
+                            error: "Chunk hashes don't match their Merkle root".to_string(),
+                        };
+                        stream.write_all(&self.encode(&reply)?).await?;
+                        continue;
+                    }
+                    let chunk_index = self.start_transfer(header, base_path).await?;
+                    let reply = FileTransferMessage::ResumeFrom { transfer_id, chunk_index };
+                    stream.write_all(&self.encode(&reply)?).await?;
                 }
                 FileTransferMessage::Chunk(chunk) => {
+                    let cancelled = self.active_transfers.get(&chunk.transfer_id)
+                        .is_some_and(|state| state.handle.is_cancelled());
+                    if cancelled {
+                        // Answered instead of the ack the sender waits for
+                        let reply = FileTransferMessage::Cancel { transfer_id: chunk.transfer_id.clone() };
+                        stream.write_all(&self.encode(&reply)?).await?;
+                        self.cancel_transfer(&chunk.transfer_id)?;
+                        continue;
+                    }
                     self.receive_chunk(chunk, stream).await?;
                 }
                 FileTransferMessage::CompleteTransfer { transfer_id } => {
-                    self.complete_transfer(&transfer_id).await?;
+                    if let Some(reply) = self.complete_transfer(&transfer_id).await? {
+                        stream.write_all(&self.encode(&reply)?).await?;
+                    }
                 }
                 FileTransferMessage::TransferError { transfer_id, error } => {
+                    // The partial file stays, another source can finish it
                     eprintln!("Transfer error for {}: {}", transfer_id, error);
                     self.active_transfers.remove(&transfer_id);
                 }
+                FileTransferMessage::Cancel { transfer_id } => {
+                    // Only a transfer still running is answered, a `Cancel`
+                    // answering this side's own isn't
+                    if self.cancel_transfer(&transfer_id).is_ok() {
+                        self.confirm_cancel(stream, &transfer_id).await?;
+                    }
+                }
                 _ => {
                     eprintln!("Unexpected file transfer message");
                 }
@@ -199,118 +683,267 @@ impl FileTransferManager {
         Ok(())
     }
 
-    async fn start_transfer(&mut self, header: FileTransferHeader, base_path: &Path) -> Result<(), SyncError> {
+    /// Receives one file sent with [`send_content`](Self::send_content),
+    /// reading through `messages`, the connection's reader, and returns its
+    /// content once it verifies. Partial data is kept in `sync_root`'s state,
+    /// so a transfer cut off resumes from where it got to; `destination` is
+    /// what the transfer queue shows.
+    pub async fn receive_content<S>(
+        &mut self,
+        stream: &mut S,
+        messages: &mut FrameReader,
+        sync_root: &Path,
+        destination: &Path,
+    ) -> Result<Vec<u8>, SyncError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let next = messages.next::<FileTransferMessage, FileTransferMessage, _>(stream);
+            let message = tokio::time::timeout(STALLED_TRANSFER_TIMEOUT, next)
+                .await
+                .map_err(|_| SyncError::Network(format!("No progress receiving {}", destination.display())))??
+                .ok_or_else(|| SyncError::Network("Connection closed during a transfer".to_string()))?;
+
+            match message {
+                FileTransferMessage::StartTransfer(header) => {
+                    let transfer_id = header.transfer_id.clone();
+                    // Every chunk's hash comes along, so a file that doesn't
+                    // verify is repaired by sending only its bad chunks again
+                    let started = if header.chunk_hashes.len() != header.chunks as usize || !header.chunk_hashes_valid() {
+                        Err(SyncError::Network("Chunk hashes don't match their Merkle root".to_string()))
+                    } else {
+                        partial_path(sync_root, &header.metadata.hash)
+                            .and_then(|partial| self.begin_transfer(header, destination.to_path_buf(), partial))
+                    };
+                    let reply = match &started {
+                        Ok(chunk_index) => FileTransferMessage::ResumeFrom { transfer_id, chunk_index: *chunk_index },
+                        Err(e) => FileTransferMessage::TransferError { transfer_id, error: e.to_string() },
+                    };
+                    stream.write_all(&self.encode(&reply)?).await?;
+                    started?;
+                }
+                FileTransferMessage::Chunk(chunk) => {
+                    let cancelled = self.active_transfers.get(&chunk.transfer_id)
+                        .is_some_and(|state| state.handle.is_cancelled());
+                    if cancelled {
+                        let cancel = FileTransferMessage::Cancel { transfer_id: chunk.transfer_id.clone() };
+                        stream.write_all(&self.encode(&cancel)?).await?;
+                        self.cancel_transfer(&chunk.transfer_id)?;
+                        skip_to_cancel(stream, messages).await?;
+                        return Err(SyncError::Cancelled(destination.to_path_buf()));
+                    }
+                    self.receive_chunk(chunk, stream).await?;
+                }
+                FileTransferMessage::CompleteTransfer { transfer_id } => match self.verify_transfer(&transfer_id).await? {
+                    Some(Verification::Verified(transfer_state)) => {
+                        let content = std::fs::read(&transfer_state.partial_path)?;
+                        std::fs::remove_file(&transfer_state.partial_path)?;
+                        let verified = FileTransferMessage::TransferVerified { transfer_id };
+                        stream.write_all(&self.encode(&verified)?).await?;
+                        return Ok(content);
+                    }
+                    Some(Verification::Repair(request)) => stream.write_all(&self.encode(&request)?).await?,
+                    None => return Err(SyncError::Network(format!("No transfer {} to complete", transfer_id))),
+                },
+                FileTransferMessage::TransferError { error, .. } => {
+                    return Err(SyncError::Network(format!("Transfer error: {}", error)));
+                }
+                FileTransferMessage::Cancel { transfer_id } => {
+                    self.cancel_transfer(&transfer_id)?;
+                    self.confirm_cancel(stream, &transfer_id).await?;
+                    return Err(SyncError::Cancelled(destination.to_path_buf()));
+                }
+                _ => eprintln!("Unexpected file transfer message"),
+            }
+        }
+    }
+
+    /// Returns the first chunk the sender needs to send.
+    async fn start_transfer(&mut self, header: FileTransferHeader, base_path: &Path) -> Result<u32, SyncError> {
+        // Both come from the sender, so they are checked before anything
+        // is created
+        if !crate::types::is_contained(Path::new(&header.path)) || crate::state::is_state_path(Path::new(&header.path)) {
+            return Err(SyncError::PermissionDenied(format!("{} is outside the sync root", header.path)));
+        }
+        // Partial data is keyed by what it will become, not by who sends
+        // it, so a transfer cut off from one remote continues from another
+        let partial_path = partial_path(base_path, &header.metadata.hash)?;
         let file_path = base_path.join(&header.path);
         
         // Create parent directories if needed
         if let Some(parent) = file_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
+        self.begin_transfer(header, file_path, partial_path)
+    }
 
-        // Create temporary file
-        let temp_path = format!("{}.tmp", file_path.to_string_lossy());
-        let temp_file = std::fs::File::create(&temp_path)?;
+    /// Registers a transfer into `partial_path`, to become `file_path`, and
+    /// returns the first chunk the sender needs to send.
+    fn begin_transfer(&mut self, header: FileTransferHeader, file_path: PathBuf, partial_path: PathBuf) -> Result<u32, SyncError> {
+        let (temp_file, chunks_received) = open_partial(&partial_path, header.chunks)?;
 
+        let handle = self.queue.register(&header.transfer_id, &file_path, header.size);
         let transfer_state = FileTransferState {
             path: file_path.clone(),
             size: header.size,
-            chunks_received: 0,
+            chunks_received,
+            received: (0..header.chunks).map(|index| index < chunks_received).collect(),
             total_chunks: header.chunks,
             metadata: header.metadata,
+            partial_path,
             temp_file: Some(temp_file),
             started_at: Instant::now(),
             last_progress: std::time::Instant::now(),
+            last_chunk_at: Instant::now(),
+            chunk_hashes: header.chunk_hashes,
+            repairs: 0,
+            handle,
         };
 
+        // A new source for the same content replaces any stale transfer
+        // still holding the partial file
+        let hash = &transfer_state.metadata.hash;
+        self.active_transfers.retain(|_, state| &state.metadata.hash != hash);
         self.active_transfers.insert(header.transfer_id.clone(), transfer_state);
-        println!("Started receiving file: {} ({} bytes)", file_path.display(), header.size);
+        if chunks_received > 0 {
+            println!("Resuming file: {} at chunk {} of {}", file_path.display(), chunks_received, header.chunks);
+        } else {
+            println!("Started receiving file: {} ({} bytes)", file_path.display(), header.size);
+        }
 
-        Ok(())
+        Ok(chunks_received)
     }
 
-    async fn receive_chunk(&mut self, chunk: FileChunk, stream: &mut tokio::net::TcpStream) -> Result<(), SyncError> {
+    async fn receive_chunk<S>(&mut self, chunk: FileChunk, stream: &mut S) -> Result<(), SyncError>
+    where
+        S: AsyncWrite + Unpin,
+    {
         let transfer_id = chunk.transfer_id.clone();
-        let bytes_received = if let Some(transfer_state) = self.active_transfers.get_mut(&chunk.transfer_id) {
-            // Verify checksum
+        if !self.active_transfers.contains_key(&transfer_id) {
+            return Ok(());
+        }
+
+        // Decompress and verify the checksum off the runtime
+        let (chunk, checksum_ok) = run_blocking(move || {
+            let mut chunk = chunk;
+            if chunk.compressed {
+                chunk.data = crate::compression::decompress(&chunk.data)?;
+                chunk.compressed = false;
+            }
             let calculated_checksum = blake3::hash(&chunk.data).to_string();
-            if calculated_checksum != chunk.checksum {
+            let checksum_ok = calculated_checksum == chunk.checksum;
+            Ok((chunk, checksum_ok))
+        })
+        .await?;
+
+        let ack = self.encode(&FileTransferMessage::AckChunk {
+            transfer_id: chunk.transfer_id.clone(),
+            chunk_index: chunk.chunk_index,
+        })?;
+        if let Some(transfer_state) = self.active_transfers.get_mut(&chunk.transfer_id) {
+            if chunk.chunk_index >= transfer_state.total_chunks {
                 let error_msg = FileTransferMessage::TransferError {
                     transfer_id: chunk.transfer_id.clone(),
-                    error: format!("Checksum mismatch for chunk {}", chunk.chunk_index),
+                    error: format!("No chunk {} in a file of {}", chunk.chunk_index, transfer_state.total_chunks),
                 };
-                let error_data = serde_json::to_vec(&error_msg)?;
-                stream.write_all(&error_data).await?;
-                return Err(SyncError::Network("Checksum mismatch".to_string()));
+                stream.write_all(&self.encode(&error_msg)?).await?;
+                return Err(SyncError::Network("Chunk out of range".to_string()));
+            }
+            if !checksum_ok {
+                // Only this chunk is sent again, the rest keep coming
+                let nack = FileTransferMessage::NackChunk {
+                    transfer_id: chunk.transfer_id.clone(),
+                    chunk_index: chunk.chunk_index,
+                };
+                stream.write_all(&self.encode(&nack)?).await?;
+                return Ok(());
             }
 
-            // Write chunk to temporary file
+            // Write chunk to temporary file, at its place so chunks sent
+            // again over corrupt ones land where they belong
             if let Some(ref mut temp_file) = transfer_state.temp_file {
+                temp_file.seek(std::io::SeekFrom::Start(chunk.chunk_index as u64 * CHUNK_SIZE as u64))?;
                 temp_file.write_all(&chunk.data)?;
-                transfer_state.chunks_received += 1;
+                if !std::mem::replace(&mut transfer_state.received[chunk.chunk_index as usize], true) {
+                    transfer_state.chunks_received += 1;
+                }
+                transfer_state.last_chunk_at = Instant::now();
+                let received = transfer_state.chunks_received as u64 * CHUNK_SIZE as u64;
+                transfer_state.handle.set_transferred(received.min(transfer_state.size));
 
                 // Send acknowledgment
-                let ack = FileTransferMessage::AckChunk {
-                    transfer_id: chunk.transfer_id.clone(),
-                    chunk_index: chunk.chunk_index,
-                };
-                let ack_data = serde_json::to_vec(&ack)?;
-                stream.write_all(&ack_data).await?;
-
-                // Calculate bytes received for progress
-                transfer_state.chunks_received as u64 * CHUNK_SIZE as u64
-            } else {
-                0
+                stream.write_all(&ack).await?;
             }
-        } else {
-            return Ok(());
-        };
-
-        // Print progress (separate to avoid borrowing conflict)
-        if let Some(transfer_state) = self.active_transfers.get(&transfer_id) {
-            self.print_progress(&transfer_id, bytes_received, transfer_state.size);
         }
 
         Ok(())
     }
 
-    async fn complete_transfer(&mut self, transfer_id: &str) -> Result<(), SyncError> {
-        if let Some(transfer_state) = self.active_transfers.remove(transfer_id) {
-            let temp_path = format!("{}.tmp", transfer_state.path.to_string_lossy());
-            
-            // Verify all chunks received
-            if transfer_state.chunks_received != transfer_state.total_chunks {
-                return Err(SyncError::Network("Incomplete transfer".to_string()));
-            }
+    /// Moves a finished transfer into place once the whole file verifies.
+    /// For senders that sent chunk hashes, returns the answer: the file
+    /// verified, or which chunks came out corrupt and are needed again.
+    async fn complete_transfer(&mut self, transfer_id: &str) -> Result<Option<FileTransferMessage>, SyncError> {
+        let transfer_state = match self.verify_transfer(transfer_id).await? {
+            Some(Verification::Verified(transfer_state)) => transfer_state,
+            Some(Verification::Repair(request)) => return Ok(Some(request)),
+            None => return Ok(None),
+        };
+        let temp_path = transfer_state.partial_path.clone();
 
-            // Rename temporary file to final location
-            std::fs::rename(&temp_path, &transfer_state.path)?;
+        // Rename temporary file to final location
+        std::fs::rename(&temp_path, &transfer_state.path)?;
 
-            // Set file metadata
-            transfer_state.metadata.apply_to_file(&transfer_state.path)?;
+        // Set file metadata
+        transfer_state.metadata.apply_to_file(&transfer_state.path)?;
 
-            let duration = transfer_state.started_at.elapsed();
-            println!("File transfer completed: {} in {:.2}s", 
-                transfer_state.path.display(), duration.as_secs_f64());
-        }
+        let duration = transfer_state.started_at.elapsed();
+        println!("File transfer completed: {} in {:.2}s", 
+            transfer_state.path.display(), duration.as_secs_f64());
 
-        Ok(())
+        let verified = FileTransferMessage::TransferVerified { transfer_id: transfer_id.to_string() };
+        Ok((!transfer_state.chunk_hashes.is_empty()).then_some(verified))
     }
 
-    fn print_progress(&self, transfer_id: &str, bytes_transferred: u64, total_bytes: u64) {
-        if let Some(transfer_state) = self.active_transfers.get(transfer_id) {
-            let now = std::time::Instant::now();
-            if now.duration_since(transfer_state.last_progress).as_secs() >= 1 {
-                let progress = (bytes_transferred as f64 / total_bytes as f64) * 100.0;
-                let speed = bytes_transferred as f64 / transfer_state.started_at.elapsed().as_secs_f64() / 1024.0 / 1024.0;
-                let elapsed = transfer_state.started_at.elapsed().as_secs_f64();
-                let estimated_total = elapsed * 100.0 / progress;
-                let remaining = estimated_total - elapsed;
-                
-                println!("Progress: {:.1}% ({:.1} MB/s) ETA: {:.1}s", progress, speed, remaining);
-                // Note: We can't modify transfer_state.last_progress here due to borrowing
-                // In a real implementation, we'd need a more sophisticated approach
+    /// Checks a finished transfer's partial file against the header. A file
+    /// that doesn't verify but whose chunk hashes tell which chunks are bad
+    /// stays registered until those come again; otherwise it is dropped.
+    /// `None` for transfers that aren't running.
+    async fn verify_transfer(&mut self, transfer_id: &str) -> Result<Option<Verification>, SyncError> {
+        let Some(mut transfer_state) = self.active_transfers.remove(transfer_id) else {
+            return Ok(None);
+        };
+        let temp_path = transfer_state.partial_path.clone();
+
+        // Verify all chunks received
+        if transfer_state.chunks_received != transfer_state.total_chunks {
+            return Err(SyncError::Network("Incomplete transfer".to_string()));
+        }
+
+        // Verify the whole file before it replaces anything
+        let expected_hash = transfer_state.metadata.hash.clone();
+        let hashed_path = temp_path.clone();
+        let actual_hash = run_blocking(move || hash_file(&hashed_path)).await?;
+        if actual_hash != expected_hash {
+            if !transfer_state.chunk_hashes.is_empty() && transfer_state.repairs < MAX_RETRIES {
+                let (checked_path, hashes) = (temp_path.clone(), transfer_state.chunk_hashes.clone());
+                let corrupt = run_blocking(move || corrupt_chunks(&checked_path, &hashes)).await?;
+                if !corrupt.is_empty() {
+                    transfer_state.repairs += 1;
+                    self.active_transfers.insert(transfer_id.to_string(), transfer_state);
+                    return Ok(Some(Verification::Repair(FileTransferMessage::RequestChunks {
+                        transfer_id: transfer_id.to_string(),
+                        chunks: corrupt,
+                    })));
+                }
             }
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(SyncError::Network(format!(
+                "Checksum mismatch for {}",
+                transfer_state.path.display()
+            )));
         }
+        Ok(Some(Verification::Verified(transfer_state)))
     }
 
     pub fn get_transfer_progress(&self, transfer_id: &str) -> Option<TransferProgress> {
@@ -322,6 +955,7 @@ impl FileTransferManager {
             
             TransferProgress {
                 transfer_id: transfer_id.to_string(),
+                path: state.path.clone(),
                 bytes_transferred,
                 total_bytes: state.size,
                 progress,
@@ -334,10 +968,37 @@ impl FileTransferManager {
                 },
                 chunks_received: state.chunks_received,
                 total_chunks: state.total_chunks,
+                finished: false,
             }
         })
     }
 
+    /// Gives up transfers that received no chunk for `max_idle` and returns
+    /// the errors to send their senders. Partial files are kept, so the
+    /// content resumes from any source later.
+    pub fn expire_stalled(&mut self, max_idle: std::time::Duration) -> Vec<FileTransferMessage> {
+        let stalled: Vec<String> = self.active_transfers.iter()
+            .filter(|(_, state)| state.last_chunk_at.elapsed() >= max_idle)
+            .map(|(transfer_id, _)| transfer_id.clone())
+            .collect();
+        self.stalled_transfers += stalled.len() as u64;
+        stalled.into_iter()
+            .filter_map(|transfer_id| {
+                let state = self.active_transfers.remove(&transfer_id)?;
+                eprintln!("Giving up stalled transfer of {}", state.path.display());
+                Some(FileTransferMessage::TransferError {
+                    transfer_id,
+                    error: format!("No progress for {} seconds", max_idle.as_secs()),
+                })
+            })
+            .collect()
+    }
+
+    /// Transfers given up by [`expire_stalled`](Self::expire_stalled) so far.
+    pub fn stalled_transfers(&self) -> u64 {
+        self.stalled_transfers
+    }
+
     pub fn get_all_transfers_progress(&self) -> Vec<TransferProgress> {
         self.active_transfers.keys()
             .filter_map(|id| self.get_transfer_progress(id))
@@ -368,9 +1029,9 @@ impl FileTransferManager {
 
     pub fn cancel_transfer(&mut self, transfer_id: &str) -> Result<(), SyncError> {
         if let Some(state) = self.active_transfers.remove(transfer_id) {
-            // Clean up temporary file
-            let temp_path = format!("{}.tmp", state.path.to_string_lossy());
-            let _ = std::fs::remove_file(&temp_path);
+            // Cancelling is deliberate, unlike an error, so nothing is kept
+            drop(state.temp_file);
+            let _ = std::fs::remove_file(&state.partial_path);
             println!("Transfer {} cancelled", transfer_id);
             Ok(())
         } else {
@@ -395,13 +1056,481 @@ impl FileTransferManager {
 }
 
 impl FileMetadata {
+    /// Gives a received file the permissions it has on the sending device.
     pub fn apply_to_file(&self, file_path: &Path) -> Result<(), SyncError> {
-        // Set file permissions and timestamps
-        if let Ok(metadata) = std::fs::metadata(file_path) {
-            let mut permissions = metadata.permissions();
-            permissions.set_readonly(true);
-            std::fs::set_permissions(file_path, permissions)?;
+        self.permissions.apply(file_path)
+    }
+}
+
+/// A header for a new transfer of `size` bytes whose chunks hash to
+/// `chunk_hashes`.
+fn header(path: String, size: u64, chunk_hashes: Vec<String>, metadata: FileMetadata) -> FileTransferHeader {
+    FileTransferHeader {
+        path,
+        size,
+        chunks: size.div_ceil(CHUNK_SIZE as u64) as u32,
+        metadata,
+        transfer_id: uuid::Uuid::new_v4().to_string(),
+        chunk_root: crate::merkle::chunk_root(&chunk_hashes),
+        chunk_hashes,
+    }
+}
+
+/// After this side sent `Cancel`, skips what the peer sent before it saw
+/// it, up to the `Cancel` it answers with, so the connection can carry on.
+async fn skip_to_cancel<S>(stream: &mut S, messages: &mut FrameReader) -> Result<(), SyncError>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let next = messages.next::<FileTransferMessage, FileTransferMessage, _>(stream);
+        let message = tokio::time::timeout(ACK_TIMEOUT, next)
+            .await
+            .map_err(|_| SyncError::Network("No answer to a cancelled transfer".to_string()))??;
+        match message {
+            Some(FileTransferMessage::Cancel { .. }) => return Ok(()),
+            Some(_) => {}
+            None => return Err(SyncError::Network("Connection closed".to_string())),
         }
-        Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Whether `hash` is a blake3 digest in hex, as content is named by.
+pub fn is_content_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Where partial data for content with `hash` is kept while it arrives.
+/// The hash comes from the remote, so anything but a digest is refused
+/// rather than joined onto the path.
+pub fn partial_path(sync_root: &Path, hash: &str) -> Result<PathBuf, SyncError> {
+    if !is_content_hash(hash) {
+        return Err(SyncError::PermissionDenied(format!("{:?} is not a content hash", hash)));
+    }
+    Ok(crate::state::dir(sync_root).join(PARTIAL_DIR_NAME).join(hash))
+}
+
+/// Opens the partial file for appending and returns how many whole chunks
+/// it already holds. A chunk cut off mid-write is dropped and fetched again.
+fn open_partial(path: &Path, total_chunks: u32) -> Result<(std::fs::File, u32), SyncError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)?;
+    let chunks = (file.metadata()?.len() / CHUNK_SIZE as u64).min(total_chunks as u64);
+    let resume_at = chunks * CHUNK_SIZE as u64;
+    file.set_len(resume_at)?;
+    file.seek(std::io::SeekFrom::Start(resume_at))?;
+    Ok((file, chunks as u32))
+}
+
+/// Reads chunk `index` of a file, empty past its end. Blocking.
+fn read_chunk<R: Read + Seek>(file: &mut R, index: u32) -> Result<Vec<u8>, SyncError> {
+    file.seek(std::io::SeekFrom::Start(index as u64 * CHUNK_SIZE as u64))?;
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    file.take(CHUNK_SIZE as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The blake3 of every chunk of a file, for the transfer header. Blocking.
+pub fn hash_chunks(path: &Path) -> Result<Vec<String>, SyncError> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hashes = Vec::new();
+    loop {
+        let data = read_chunk(&mut file, hashes.len() as u32)?;
+        if data.is_empty() {
+            return Ok(hashes);
+        }
+        hashes.push(blake3::hash(&data).to_string());
+    }
+}
+
+/// The runs of chunks of a file that don't match `expected`, the hashes
+/// from its transfer header. Blocking.
+fn corrupt_chunks(path: &Path, expected: &[String]) -> Result<Vec<std::ops::Range<u32>>, SyncError> {
+    let mut file = std::fs::File::open(path)?;
+    let mut corrupt: Vec<std::ops::Range<u32>> = Vec::new();
+    for (index, hash) in (0u32..).zip(expected) {
+        if blake3::hash(&read_chunk(&mut file, index)?).to_string() == *hash {
+            continue;
+        }
+        match corrupt.last_mut() {
+            Some(run) if run.end == index => run.end += 1,
+            _ => corrupt.push(index..index + 1),
+        }
+    }
+    Ok(corrupt)
+}
+
+/// Streams a file through blake3 without loading it into memory. Blocking,
+/// call it through [`run_blocking`] from async code.
+pub fn hash_file(path: &Path) -> Result<String, SyncError> {
+    let mut hasher = blake3::Hasher::new();
+    let mut file = std::fs::File::open(path)?;
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Runs CPU- or disk-bound work on tokio's blocking pool.
+pub async fn run_blocking<F, T>(work: F) -> Result<T, SyncError>
+where
+    F: FnOnce() -> Result<T, SyncError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| SyncError::Io(std::io::Error::other(e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_resumes_at_whole_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = partial_path(dir.path(), blake3::hash(b"big").to_hex().as_str()).unwrap();
+
+        let (_, chunks) = open_partial(&path, 4).unwrap();
+        assert_eq!(chunks, 0);
+
+        // Two full chunks and half of a third, as left by a dropped connection
+        std::fs::write(&path, vec![7u8; CHUNK_SIZE * 5 / 2]).unwrap();
+        let (mut file, chunks) = open_partial(&path, 4).unwrap();
+        assert_eq!(chunks, 2);
+        file.write_all(b"next").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (CHUNK_SIZE * 2 + 4) as u64);
+
+        // The hash is the remote's word, not a path
+        for hash in ["../../../.bashrc", "", "a".repeat(63).as_str(), "g".repeat(64).as_str()] {
+            assert!(partial_path(dir.path(), hash).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_transfer_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata = FileMetadata::for_test("big.png", &[0; 10]);
+        let hash = metadata.hash.clone();
+        let header = FileTransferHeader {
+            path: "big.png".to_string(),
+            size: 10,
+            chunks: 1,
+            metadata,
+            transfer_id: "t1".to_string(),
+            chunk_hashes: Vec::new(),
+            chunk_root: String::new(),
+        };
+        let mut manager = FileTransferManager::new();
+        manager.start_transfer(header, dir.path()).await.unwrap();
+
+        assert!(manager.expire_stalled(STALLED_TRANSFER_TIMEOUT).is_empty());
+        let errors = manager.expire_stalled(std::time::Duration::ZERO);
+        assert!(matches!(&errors[..], [FileTransferMessage::TransferError { transfer_id, .. }] if transfer_id == "t1"));
+        assert_eq!(manager.stalled_transfers(), 1);
+        assert!(manager.get_transfer_progress("t1").is_none());
+        assert!(partial_path(dir.path(), &hash).unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn test_header_outside_root_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let header = |path: &str, hash: &str| FileTransferHeader {
+            path: path.to_string(),
+            size: 10,
+            chunks: 1,
+            metadata: FileMetadata { hash: hash.to_string(), ..FileMetadata::for_test(path, &[0; 10]) },
+            transfer_id: "t1".to_string(),
+            chunk_hashes: Vec::new(),
+            chunk_root: String::new(),
+        };
+        let hash = blake3::hash(&[0; 10]).to_hex().to_string();
+        let mut manager = FileTransferManager::new();
+        assert!(manager.start_transfer(header("../outside/big.png", &hash), &root).await.is_err());
+        assert!(manager.start_transfer(header(".syncmd/index.db", &hash), &root).await.is_err());
+        assert!(manager.start_transfer(header("notes/big.png", "../../big"), &root).await.is_err());
+        assert!(!dir.path().join("outside").exists() && !root.join("notes").exists());
+        assert!(manager.get_transfer_progress("t1").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_discards_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sender = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+        let queue = TransferQueue::default();
+        let receiving = {
+            let queue = queue.clone();
+            let base = dir.path().to_path_buf();
+            tokio::spawn(async move {
+                FileTransferManager::new().with_queue(queue).with_binary_frames(true).receive_file(&mut receiver, &base).await
+            })
+        };
+
+        let data = vec![1u8; 10];
+        let metadata = FileMetadata::for_test("notes/big.png", &[0; CHUNK_SIZE * 2]);
+        let hash = metadata.hash.clone();
+        let header = FileTransferHeader {
+            path: "notes/big.png".to_string(),
+            size: CHUNK_SIZE as u64 * 2,
+            chunks: 2,
+            metadata,
+            transfer_id: "t1".to_string(),
+            chunk_hashes: Vec::new(),
+            chunk_root: String::new(),
+        };
+        // JSON and binary frames are both understood, replies come as frames
+        let mut replies = FrameReader::default();
+        sender.write_all(&serde_json::to_vec(&FileTransferMessage::StartTransfer(header)).unwrap()).await.unwrap();
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::ResumeFrom { chunk_index: 0, .. })));
+        assert_eq!(queue.list().len(), 1);
+        assert!(partial_path(dir.path(), &hash).unwrap().exists());
+
+        assert!(queue.cancel("other.md").is_empty());
+        assert_eq!(queue.cancel("notes/big.png")[0].id, "t1");
+        let chunk = FileChunk {
+            transfer_id: "t1".to_string(),
+            chunk_index: 0,
+            checksum: blake3::hash(&data).to_string(),
+            data,
+            compressed: false,
+        };
+        let frame = crate::wire::encode_frame(&FileTransferMessage::Chunk(chunk), false).unwrap();
+        sender.write_all(&frame).await.unwrap();
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::Cancel { transfer_id }) if transfer_id == "t1"));
+
+        drop(sender);
+        receiving.await.unwrap().unwrap();
+        assert!(!partial_path(dir.path(), &hash).unwrap().exists());
+        assert!(queue.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_answered_either_way() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut near = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut far, _) = listener.accept().await.unwrap();
+        let data = vec![7u8; CHUNK_SIZE * 3];
+        let metadata = FileMetadata::for_test("notes/big.bin", &data);
+        let mut messages = FrameReader::default();
+        let send = |message: FileTransferMessage| serde_json::to_vec(&message).unwrap();
+
+        // The receiver answers the sender's Cancel and drops the partial data
+        let root = dir.path().to_path_buf();
+        let receiving = tokio::spawn(async move {
+            let mut manager = FileTransferManager::new().with_queue(TransferQueue::default());
+            let content = manager.receive_content(&mut far, &mut FrameReader::default(), &root, Path::new("big.bin")).await;
+            (content, far)
+        });
+        let chunk_hashes = data.chunks(CHUNK_SIZE).map(|chunk| blake3::hash(chunk).to_string()).collect();
+        let start = header("notes/big.bin".to_string(), data.len() as u64, chunk_hashes, metadata.clone());
+        near.write_all(&send(FileTransferMessage::StartTransfer(start))).await.unwrap();
+        let reply = messages.next::<FileTransferMessage, FileTransferMessage, _>(&mut near).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::ResumeFrom { chunk_index: 0, .. })));
+        assert!(partial_path(dir.path(), &metadata.hash).unwrap().exists());
+        let Some(FileTransferMessage::ResumeFrom { transfer_id, .. }) = reply else { unreachable!() };
+        near.write_all(&send(FileTransferMessage::Cancel { transfer_id })).await.unwrap();
+        let reply = messages.next::<FileTransferMessage, FileTransferMessage, _>(&mut near).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::Cancel { .. })));
+        let (content, mut far) = receiving.await.unwrap();
+        assert!(matches!(content, Err(SyncError::Cancelled(_))));
+        assert!(!partial_path(dir.path(), &metadata.hash).unwrap().exists());
+
+        // The sender answers the receiver's Cancel, sent while chunks are in flight
+        let sending = tokio::spawn(async move {
+            let manager = FileTransferManager::new().with_queue(TransferQueue::default());
+            manager.send_content(&mut near, &mut messages, "notes/big.bin", data, metadata).await
+        });
+        let mut replies = FrameReader::default();
+        let Some(FileTransferMessage::StartTransfer(start)) =
+            replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut far).await.unwrap()
+        else {
+            panic!("expected a header")
+        };
+        assert!(start.chunk_hashes_valid() && start.chunks == 3);
+        let transfer_id = start.transfer_id;
+        far.write_all(&send(FileTransferMessage::ResumeFrom { transfer_id: transfer_id.clone(), chunk_index: 0 })).await.unwrap();
+        far.write_all(&send(FileTransferMessage::Cancel { transfer_id })).await.unwrap();
+        loop {
+            match replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut far).await.unwrap() {
+                Some(FileTransferMessage::Chunk(_)) => {}
+                Some(FileTransferMessage::Cancel { .. }) => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(matches!(sending.await.unwrap(), Err(SyncError::Cancelled(_))));
+    }
+
+    #[tokio::test]
+    async fn test_content_needs_chunk_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sender = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+        let root = dir.path().to_path_buf();
+        let receiving = tokio::spawn(async move {
+            let mut manager = FileTransferManager::new().with_queue(TransferQueue::default());
+            manager.receive_content(&mut receiver, &mut FrameReader::default(), &root, Path::new("big.bin")).await
+        });
+
+        let data = vec![3u8; CHUNK_SIZE * 2];
+        let chunk_hashes = data.chunks(CHUNK_SIZE).map(|chunk| blake3::hash(chunk).to_string()).collect();
+        let mut forged = header("big.bin".to_string(), data.len() as u64, chunk_hashes, FileMetadata::for_test("big.bin", &data));
+        forged.chunk_root = "forged".to_string();
+        sender.write_all(&serde_json::to_vec(&FileTransferMessage::StartTransfer(forged)).unwrap()).await.unwrap();
+        let reply = FrameReader::default().next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::TransferError { .. })));
+        assert!(receiving.await.unwrap().is_err());
+        assert!(!crate::state::dir(dir.path()).join(PARTIAL_DIR_NAME).exists());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_chunks_requested_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sender = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+        let base = dir.path().to_path_buf();
+        let receiving = tokio::spawn(async move {
+            FileTransferManager::new().with_queue(TransferQueue::default()).receive_file(&mut receiver, &base).await
+        });
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 3).map(|i| (i / CHUNK_SIZE) as u8 + 1).collect();
+        let chunk_hashes: Vec<String> = data.chunks(CHUNK_SIZE).map(|chunk| blake3::hash(chunk).to_string()).collect();
+        let hash = blake3::hash(&data).to_string();
+        // An earlier transfer left every chunk, but the middle one rotted
+        let mut partial = data.clone();
+        partial[CHUNK_SIZE + 10] ^= 0xff;
+        std::fs::create_dir_all(partial_path(dir.path(), &hash).unwrap().parent().unwrap()).unwrap();
+        std::fs::write(partial_path(dir.path(), &hash).unwrap(), &partial).unwrap();
+
+        let mut header = FileTransferHeader {
+            path: "notes/big.bin".to_string(),
+            size: data.len() as u64,
+            chunks: 3,
+            metadata: FileMetadata::for_test("notes/big.bin", &data),
+            transfer_id: "t1".to_string(),
+            chunk_root: "forged".to_string(),
+            chunk_hashes,
+        };
+        let mut replies = FrameReader::default();
+        let send = |message: FileTransferMessage| serde_json::to_vec(&message).unwrap();
+        sender.write_all(&send(FileTransferMessage::StartTransfer(header.clone()))).await.unwrap();
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::TransferError { .. })));
+
+        header.chunk_root = crate::merkle::chunk_root(&header.chunk_hashes);
+        sender.write_all(&send(FileTransferMessage::StartTransfer(header))).await.unwrap();
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::ResumeFrom { chunk_index: 3, .. })));
+
+        let complete = FileTransferMessage::CompleteTransfer { transfer_id: "t1".to_string() };
+        sender.write_all(&send(complete.clone())).await.unwrap();
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::RequestChunks { chunks, .. }) if chunks.len() == 1 && chunks[0] == (1..2)));
+
+        let chunk = &data[CHUNK_SIZE..CHUNK_SIZE * 2];
+        let chunk = FileChunk {
+            transfer_id: "t1".to_string(),
+            chunk_index: 1,
+            checksum: blake3::hash(chunk).to_string(),
+            data: chunk.to_vec(),
+            compressed: false,
+        };
+        sender.write_all(&send(FileTransferMessage::Chunk(chunk))).await.unwrap();
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::AckChunk { chunk_index: 1, .. })));
+        sender.write_all(&send(complete)).await.unwrap();
+        let reply = replies.next::<FileTransferMessage, FileTransferMessage, _>(&mut sender).await.unwrap();
+        assert!(matches!(reply, Some(FileTransferMessage::TransferVerified { .. })));
+
+        drop(sender);
+        receiving.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(dir.path().join("notes/big.bin")).unwrap(), data);
+        assert!(!partial_path(dir.path(), &hash).unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn test_chunks_pipelined_and_resent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 40).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let metadata = FileMetadata::for_test("big.bin", &data);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+        let queue = TransferQueue::default();
+        let mut progress = queue.subscribe();
+        let sending = tokio::spawn(async move {
+            FileTransferManager::new().with_queue(queue).send_file(&mut stream, &path, metadata).await
+        });
+
+        let mut messages = FrameReader::default();
+        async fn next(messages: &mut FrameReader, receiver: &mut tokio::net::TcpStream) -> FileTransferMessage {
+            let message = messages.next::<FileTransferMessage, FileTransferMessage, _>(receiver);
+            tokio::time::timeout(std::time::Duration::from_secs(5), message).await.unwrap().unwrap().unwrap()
+        }
+        let FileTransferMessage::StartTransfer(header) = next(&mut messages, &mut receiver).await else {
+            panic!("expected a header")
+        };
+        assert!(header.chunk_hashes_valid());
+        let transfer_id = header.transfer_id;
+        let reply = |message: FileTransferMessage| serde_json::to_vec(&message).unwrap();
+        let resume = FileTransferMessage::ResumeFrom { transfer_id: transfer_id.clone(), chunk_index: 0 };
+        receiver.write_all(&reply(resume)).await.unwrap();
+
+        // A whole window arrives before any ack is sent
+        let mut indices = Vec::new();
+        for _ in 0..SEND_WINDOW {
+            let FileTransferMessage::Chunk(chunk) = next(&mut messages, &mut receiver).await else {
+                panic!("expected a chunk")
+            };
+            indices.push(chunk.chunk_index);
+        }
+        assert_eq!(indices, (0..SEND_WINDOW as u32).collect::<Vec<_>>());
+        let mut acks = Vec::new();
+        for index in indices {
+            let transfer_id = transfer_id.clone();
+            acks.extend(if index == 5 {
+                reply(FileTransferMessage::NackChunk { transfer_id, chunk_index: index })
+            } else {
+                reply(FileTransferMessage::AckChunk { transfer_id, chunk_index: index })
+            });
+        }
+        receiver.write_all(&acks).await.unwrap();
+
+        // The bad chunk goes again along with the rest
+        let mut indices = Vec::new();
+        loop {
+            match next(&mut messages, &mut receiver).await {
+                FileTransferMessage::Chunk(chunk) => {
+                    let ack = FileTransferMessage::AckChunk { transfer_id: transfer_id.clone(), chunk_index: chunk.chunk_index };
+                    receiver.write_all(&reply(ack)).await.unwrap();
+                    indices.push(chunk.chunk_index);
+                }
+                FileTransferMessage::CompleteTransfer { .. } => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        indices.sort();
+        assert_eq!(indices, [5].into_iter().chain(SEND_WINDOW as u32..40).collect::<Vec<_>>());
+        receiver.write_all(&reply(FileTransferMessage::TransferVerified { transfer_id })).await.unwrap();
+        sending.await.unwrap().unwrap();
+
+        // Every acked chunk was reported, then the end of the transfer
+        let mut updates = Vec::new();
+        while let Ok(update) = progress.try_recv() {
+            updates.push(update);
+        }
+        assert_eq!(updates.iter().filter(|update| !update.finished).count(), 40);
+        let last = updates.last().unwrap();
+        assert!(last.finished && last.path.ends_with("big.bin"));
+        assert_eq!((last.bytes_transferred, last.chunks_received, last.total_chunks), (data.len() as u64, 40, 40));
+    }
+}
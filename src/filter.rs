@@ -0,0 +1,122 @@
+#![allow(dead_code)]
+
+use crate::types::{FileMetadata, SyncError};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// What an external filter decided for one candidate file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "decision", rename_all = "lowercase")]
+pub enum FilterDecision {
+    Allow,
+    Deny,
+    /// Sync the file, but publish it under a different relative path
+    Transform { path: PathBuf },
+}
+
+#[derive(Debug, Serialize)]
+struct FilterCandidate<'a> {
+    path: &'a PathBuf,
+    size: u64,
+    hash: &'a str,
+    modified: chrono::DateTime<chrono::Utc>,
+}
+
+/// A user-configured program (`filter_cmd` on a sync root) that decides which
+/// files get synced.
+///
+/// The program receives one JSON object per candidate on stdin and must print
+/// one JSON decision per line in the same order, e.g. `{"decision":"deny"}`
+/// or `{"decision":"transform","path":"archive/note.md"}`.
+#[derive(Debug, Clone)]
+pub struct FilterCommand {
+    command: String,
+}
+
+impl FilterCommand {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn evaluate(&self, candidates: &[&FileMetadata]) -> Result<Vec<FilterDecision>, SyncError> {
+        if candidates.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut input = Vec::new();
+        for metadata in candidates {
+            let candidate = FilterCandidate {
+                path: &metadata.path,
+                size: metadata.size,
+                hash: &metadata.hash,
+                modified: metadata.modified.into(),
+            };
+            serde_json::to_writer(&mut input, &candidate)?;
+            input.push(b'\n');
+        }
+
+        let mut child = shell_command(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        // Feed stdin from a separate thread so a filter that streams its
+        // answers can't deadlock on a full pipe
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+        let output = child.wait_with_output()?;
+        writer.join().map_err(|_| SyncError::Filter("Filter input thread panicked".to_string()))??;
+
+        if !output.status.success() {
+            return Err(SyncError::Filter(format!("'{}' exited with {}", self.command, output.status)));
+        }
+
+        let decisions = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(parse_decision)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if decisions.len() != candidates.len() {
+            return Err(SyncError::Filter(format!(
+                "'{}' returned {} decisions for {} candidates",
+                self.command,
+                decisions.len(),
+                candidates.len()
+            )));
+        }
+
+        Ok(decisions)
+    }
+}
+
+fn parse_decision(line: &str) -> Result<FilterDecision, SyncError> {
+    // Bare words are accepted for simple shell filters
+    match line.trim() {
+        "allow" => Ok(FilterDecision::Allow),
+        "deny" => Ok(FilterDecision::Deny),
+        json => serde_json::from_str(json)
+            .map_err(|e| SyncError::Filter(format!("Invalid filter decision '{}': {}", json, e))),
+    }
+}
+
+/// `command` run through the platform's shell.
+pub fn shell_command(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    }
+}
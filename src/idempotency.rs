@@ -0,0 +1,80 @@
+#![allow(dead_code)]
+
+//! Answers to mutating requests, remembered by the key the client sent with
+//! them. A client that retries after an ambiguous failure, such as a
+//! connection that broke before the answer arrived, sends the same key again
+//! and gets the first answer instead of having the request applied twice.
+//! Keys are remembered per device, the latest [`MAX_KEYS_PER_DEVICE`] of
+//! each.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Far more than a client has in flight when its connection breaks
+pub const MAX_KEYS_PER_DEVICE: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyCache<T> {
+    devices: HashMap<String, DeviceKeys<T>>,
+    max_keys: usize,
+}
+
+#[derive(Debug, Clone)]
+struct DeviceKeys<T> {
+    /// Oldest first, for forgetting them in order
+    order: VecDeque<String>,
+    outcomes: HashMap<String, T>,
+}
+
+impl<T: Clone> Default for IdempotencyCache<T> {
+    fn default() -> Self {
+        Self::new(MAX_KEYS_PER_DEVICE)
+    }
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    pub fn new(max_keys: usize) -> Self {
+        Self { devices: HashMap::new(), max_keys }
+    }
+
+    /// The answer to the request `device` sent with `key`, if it was seen.
+    pub fn get(&self, device: &str, key: &str) -> Option<T> {
+        self.devices.get(device)?.outcomes.get(key).cloned()
+    }
+
+    /// Remembers the answer to a request, forgetting the device's oldest
+    /// key once it has too many.
+    pub fn insert(&mut self, device: &str, key: String, outcome: T) {
+        let keys = self.devices.entry(device.to_string()).or_insert_with(|| DeviceKeys {
+            order: VecDeque::new(),
+            outcomes: HashMap::new(),
+        });
+        if keys.outcomes.insert(key.clone(), outcome).is_none() {
+            keys.order.push_back(key);
+        }
+        while keys.order.len() > self.max_keys {
+            if let Some(oldest) = keys.order.pop_front() {
+                keys.outcomes.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_per_device() {
+        let mut cache = IdempotencyCache::new(2);
+        cache.insert("laptop", "a".to_string(), None);
+        cache.insert("laptop", "b".to_string(), Some("infected"));
+        assert_eq!(cache.get("laptop", "b"), Some(Some("infected")));
+        assert_eq!(cache.get("laptop", "a"), Some(None));
+        // Another device's keys are its own
+        assert_eq!(cache.get("phone", "a"), None);
+
+        cache.insert("laptop", "c".to_string(), None);
+        assert_eq!(cache.get("laptop", "a"), None);
+        assert_eq!(cache.get("laptop", "c"), Some(None));
+    }
+}
@@ -0,0 +1,273 @@
+#![allow(dead_code)]
+
+use crate::types::SyncError;
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+pub const IGNORE_FILE_NAME: &str = ".syncignore";
+pub const GITIGNORE_FILE_NAME: &str = ".gitignore";
+/// Files editors keep next to the ones being edited, which change with every
+/// keystroke or focus change but mean nothing on another device
+pub const EDITOR_TEMP_PATTERNS: &[&str] = &[
+    ".obsidian/workspace*",
+    "~$*",
+    "*.swp",
+    "*.swo",
+    "*.swx",
+    ".#*",
+    "*~",
+];
+/// The source of rules from a root's `ignore` setting
+const SETTING_SOURCE: &str = "ignore setting";
+/// The source of [`EDITOR_TEMP_PATTERNS`]
+const EDITOR_TEMP_SOURCE: &str = "editor temp files";
+
+/// A single line of a `.syncignore` file, using gitignore-style semantics.
+#[derive(Debug, Clone)]
+pub struct IgnoreRule {
+    pub pattern: String,
+    pub line: usize,
+    pub negated: bool,
+    pub dir_only: bool,
+    /// File the rule was read from
+    pub source: PathBuf,
+    /// Directory the pattern is relative to
+    base: PathBuf,
+    matcher: GlobMatcher,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<IgnoreRule>,
+    /// Where the sync root is below the directory rules are relative to,
+    /// for roots inside a git repository
+    prefix: PathBuf,
+}
+
+impl IgnoreRules {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    pub fn load(sync_root: &Path) -> Result<Self, SyncError> {
+        let ignore_path = sync_root.join(IGNORE_FILE_NAME);
+        if ignore_path.exists() {
+            let content = std::fs::read_to_string(ignore_path)?;
+            Ok(Self::parse(&content))
+        } else {
+            Ok(Self::empty())
+        }
+    }
+
+    /// Rules from the `.gitignore` files and `.git/info/exclude` of the git
+    /// repository containing `sync_root`, or `None` outside of one. As in
+    /// git, `.gitignore` files inside ignored directories aren't read.
+    pub fn load_git(sync_root: &Path) -> Result<Option<Self>, SyncError> {
+        let sync_root = sync_root.canonicalize()?;
+        let Some(repository) = sync_root.ancestors().find(|dir| dir.join(".git").exists()) else {
+            return Ok(None);
+        };
+        let prefix = sync_root.strip_prefix(repository)?.to_path_buf();
+        let mut rules = Self { rules: Vec::new(), prefix: prefix.clone() };
+        rules.read_file(repository, Path::new(".git/info/exclude"), Path::new(""))?;
+
+        // From the repository root down to the sync root...
+        let mut dir = PathBuf::new();
+        rules.read_file(repository, Path::new(GITIGNORE_FILE_NAME), &dir)?;
+        for component in prefix.components() {
+            dir.push(component);
+            rules.read_file(repository, &dir.join(GITIGNORE_FILE_NAME), &dir)?;
+        }
+
+        // ...and below it. Sorted by name, a directory's .gitignore comes
+        // before its subdirectories
+        let mut walker = WalkDir::new(&sync_root).min_depth(1).sort_by_file_name().into_iter();
+        while let Some(entry) = walker.next() {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let relative = entry.path().strip_prefix(&sync_root)?;
+            if entry.file_type().is_dir() {
+                if crate::indexer::is_vcs_dir(entry.file_name()) || rules.is_ignored(relative, true) {
+                    walker.skip_current_dir();
+                }
+            } else if entry.file_name() == GITIGNORE_FILE_NAME && entry.depth() > 1 {
+                let dir = prefix.join(relative.parent().unwrap_or(Path::new("")));
+                rules.read_file(repository, &dir.join(GITIGNORE_FILE_NAME), &dir)?;
+            }
+        }
+
+        Ok(Some(rules))
+    }
+
+    fn read_file(&mut self, root: &Path, source: &Path, base: &Path) -> Result<(), SyncError> {
+        let path = root.join(source);
+        if path.is_file() {
+            let content = std::fs::read_to_string(path)?;
+            self.rules.extend(Self::parse_rules(&content, source, base));
+        }
+        Ok(())
+    }
+
+    pub fn parse(content: &str) -> Self {
+        Self {
+            rules: Self::parse_rules(content, Path::new(IGNORE_FILE_NAME), Path::new("")),
+            prefix: PathBuf::new(),
+        }
+    }
+
+    /// The rules a root's files are checked against, by the indexer and the
+    /// watcher alike: editor temp files, the root's `ignore` setting and its
+    /// `.syncignore`, in that order so a later `!pattern` can take an earlier
+    /// one back, and with `respect_gitignore` the git repository's.
+    pub fn for_root(sync_root: &Path, patterns: &[String], respect_gitignore: bool) -> Result<Vec<Self>, SyncError> {
+        let mut own = Self::empty();
+        own.rules.extend(Self::parse_rules(&EDITOR_TEMP_PATTERNS.join("\n"), Path::new(EDITOR_TEMP_SOURCE), Path::new("")));
+        own.rules.extend(Self::parse_rules(&patterns.join("\n"), Path::new(SETTING_SOURCE), Path::new("")));
+        own.rules.extend(Self::load(sync_root)?.rules);
+        let mut rules = vec![own];
+        if respect_gitignore {
+            rules.extend(Self::load_git(sync_root)?);
+        }
+        Ok(rules)
+    }
+
+    fn parse_rules(content: &str, source: &Path, base: &Path) -> Vec<IgnoreRule> {
+        let mut rules = Vec::new();
+
+        for (index, raw_line) in content.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, pattern),
+            };
+
+            // Patterns containing a slash are anchored to the root, everything
+            // else matches at any depth like in .gitignore
+            let glob = if let Some(anchored) = pattern.strip_prefix('/') {
+                anchored.to_string()
+            } else if pattern.contains('/') {
+                pattern.to_string()
+            } else {
+                format!("**/{}", pattern)
+            };
+
+            match GlobBuilder::new(&glob).literal_separator(true).build() {
+                Ok(compiled) => rules.push(IgnoreRule {
+                    pattern: line.to_string(),
+                    line: index + 1,
+                    negated,
+                    dir_only,
+                    source: source.to_path_buf(),
+                    base: base.to_path_buf(),
+                    matcher: compiled.compile_matcher(),
+                }),
+                Err(e) => {
+                    eprintln!("Skipping invalid ignore pattern in {} line {}: {} ({})",
+                        source.display(), index + 1, line, e);
+                }
+            }
+        }
+
+        rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[IgnoreRule] {
+        &self.rules
+    }
+
+    /// Returns true if the root-relative path is excluded, either directly or
+    /// because one of its parent directories is.
+    pub fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        self.matching_rule(relative_path, is_dir).is_some()
+    }
+
+    /// Returns the rule responsible for excluding the path, if any.
+    pub fn matching_rule(&self, relative_path: &Path, is_dir: bool) -> Option<&IgnoreRule> {
+        let mut path = self.prefix.clone();
+        let components: Vec<_> = relative_path.components().collect();
+
+        for (index, component) in components.iter().enumerate() {
+            path.push(component);
+            let component_is_dir = is_dir || index + 1 < components.len();
+            if let Some(rule) = self.decide(&path, component_is_dir) {
+                return Some(rule);
+            }
+        }
+
+        None
+    }
+
+    fn decide(&self, path: &Path, is_dir: bool) -> Option<&IgnoreRule> {
+        // Last matching rule wins, so a later `!pattern` re-includes the path
+        let mut decision = None;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            // Patterns from a nested .gitignore only apply below it
+            let Ok(path) = path.strip_prefix(&rule.base) else {
+                continue;
+            };
+            if rule.matcher.is_match(path) {
+                decision = if rule.negated { None } else { Some(rule) };
+            }
+        }
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore_rules() {
+        let rules = IgnoreRules::parse("# comment\n*.tmp\nbuild/\n/drafts/*.md\n!drafts/keep.md\n");
+
+        assert!(rules.is_ignored(Path::new("notes/a.tmp"), false));
+        assert!(rules.is_ignored(Path::new("build/out.md"), false));
+        assert!(!rules.is_ignored(Path::new("build"), false));
+        assert!(rules.is_ignored(Path::new("drafts/idea.md"), false));
+        assert!(!rules.is_ignored(Path::new("drafts/keep.md"), false));
+        assert!(!rules.is_ignored(Path::new("notes/drafts/idea.md"), false));
+        assert_eq!(rules.matching_rule(Path::new("x/y.tmp"), false).map(|r| r.line), Some(2));
+    }
+
+    #[test]
+    fn test_git_rules() {
+        let repository = tempfile::tempdir().unwrap();
+        let root = repository.path().join("docs");
+        std::fs::create_dir_all(repository.path().join(".git/info")).unwrap();
+        std::fs::create_dir_all(root.join("site/node_modules/pkg")).unwrap();
+        std::fs::write(repository.path().join(".git/info/exclude"), "*.local\n").unwrap();
+        std::fs::write(repository.path().join(".gitignore"), "node_modules/\n/docs/out\n").unwrap();
+        std::fs::write(root.join("site/.gitignore"), "/cache\n").unwrap();
+        std::fs::write(root.join("site/node_modules/pkg/.gitignore"), "*.md\n").unwrap();
+
+        let rules = IgnoreRules::load_git(&root).unwrap().unwrap();
+        assert!(rules.is_ignored(Path::new("out/index.md"), false));
+        assert!(rules.is_ignored(Path::new("notes.local"), false));
+        assert!(rules.is_ignored(Path::new("site/node_modules"), true));
+        assert!(rules.is_ignored(Path::new("site/cache/a.md"), false));
+        assert!(!rules.is_ignored(Path::new("cache/a.md"), false));
+        assert!(!rules.is_ignored(Path::new("site/readme.md"), false));
+        let rule = rules.matching_rule(Path::new("site/cache"), true).unwrap();
+        assert_eq!(rule.source, Path::new("docs/site/.gitignore"));
+
+        assert!(IgnoreRules::load_git(tempfile::tempdir().unwrap().path()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,190 @@
+#![allow(dead_code)]
+
+use crate::ignore::IGNORE_FILE_NAME;
+use crate::index_store::IndexStore;
+use crate::indexer::FileIndexer;
+use crate::types::SyncError;
+use std::path::{Path, PathBuf};
+
+pub const STIGNORE_FILE_NAME: &str = ".stignore";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImportSource {
+    Syncthing,
+    Rsync,
+}
+
+impl ImportSource {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ImportSource::Syncthing => "Syncthing",
+            ImportSource::Rsync => "rsync",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ImportReport {
+    pub root: PathBuf,
+    pub ignore_rules_imported: usize,
+    pub ignore_lines_skipped: Vec<String>,
+    pub files_indexed: usize,
+}
+
+/// Converts a Syncthing `.stignore` file into `.syncignore` lines.
+///
+/// Returns the converted lines and the lines that have no equivalent.
+pub fn convert_stignore(content: &str) -> (Vec<String>, Vec<String>) {
+    let mut converted = Vec::new();
+    let mut skipped = Vec::new();
+
+    for raw_line in content.lines() {
+        let line = raw_line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix("//") {
+            converted.push(format!("#{}", comment));
+            continue;
+        }
+        if line.starts_with("#include") {
+            skipped.push(line.to_string());
+            continue;
+        }
+
+        // (?d) and (?i) only change deletion and case handling in Syncthing,
+        // the pattern itself still applies
+        let mut pattern = line;
+        let mut negated = false;
+        loop {
+            if let Some(rest) = pattern.strip_prefix('!') {
+                negated = true;
+                pattern = rest;
+            } else if let Some(rest) = pattern.strip_prefix("(?d)") {
+                pattern = rest;
+            } else if let Some(rest) = pattern.strip_prefix("(?i)") {
+                pattern = rest;
+            } else {
+                break;
+            }
+        }
+
+        if pattern.is_empty() {
+            skipped.push(line.to_string());
+            continue;
+        }
+
+        converted.push(if negated { format!("!{}", pattern) } else { pattern.to_string() });
+    }
+
+    (converted, skipped)
+}
+
+/// Converts an rsync `--exclude-from`/filter file into `.syncignore` lines.
+pub fn convert_rsync_filters(content: &str) -> (Vec<String>, Vec<String>) {
+    let mut converted = Vec::new();
+    let mut skipped = Vec::new();
+
+    for raw_line in content.lines() {
+        let line = raw_line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('#') || line.starts_with(';') {
+            converted.push(format!("#{}", &line[1..]));
+            continue;
+        }
+
+        if let Some(pattern) = line.strip_prefix("- ").or_else(|| line.strip_prefix("exclude ")) {
+            converted.push(pattern.trim().to_string());
+        } else if let Some(pattern) = line.strip_prefix("+ ").or_else(|| line.strip_prefix("include ")) {
+            converted.push(format!("!{}", pattern.trim()));
+        } else if line == "!" || (line.starts_with(['.', ':', 'P', 'R', 'H', 'S']) && line.chars().nth(1) == Some(' ')) {
+            // Merge, protect, risk, hide/show and clear rules have no equivalent
+            skipped.push(line.to_string());
+        } else {
+            converted.push(line.to_string());
+        }
+    }
+
+    (converted, skipped)
+}
+
+/// Adopts a folder previously synced by another tool: converts its ignore
+/// rules and seeds the persistent index with the current file hashes, so the
+/// first sync only exchanges metadata for files the server already has.
+pub fn import_folder(
+    source: ImportSource,
+    root: &Path,
+    device_id: &str,
+    exclude_from: Option<&Path>,
+    convert_ignores: bool,
+) -> Result<ImportReport, SyncError> {
+    if !root.is_dir() {
+        return Err(SyncError::NotFound(root.to_path_buf()));
+    }
+
+    let mut ignore_rules_imported = 0;
+    let mut ignore_lines_skipped = Vec::new();
+
+    if convert_ignores {
+        let source_file = match source {
+            ImportSource::Syncthing => Some(root.join(STIGNORE_FILE_NAME)),
+            ImportSource::Rsync => exclude_from.map(Path::to_path_buf),
+        };
+
+        if let Some(source_file) = source_file.filter(|p| p.exists()) {
+            let content = std::fs::read_to_string(&source_file)?;
+            let (lines, skipped) = match source {
+                ImportSource::Syncthing => convert_stignore(&content),
+                ImportSource::Rsync => convert_rsync_filters(&content),
+            };
+
+            ignore_rules_imported = lines.iter().filter(|l| !l.starts_with('#')).count();
+            ignore_lines_skipped = skipped;
+            append_ignore_rules(root, source, &source_file, &lines)?;
+        }
+    }
+
+    // Index after writing .syncignore so the seeded state already excludes
+    // the imported patterns
+    let indexer = FileIndexer::new(device_id.to_string(), root.to_path_buf());
+    let state = indexer.index_directory()?;
+    let mut store = IndexStore::open(root)?;
+    store.save_state(&state)?;
+
+    Ok(ImportReport {
+        root: root.to_path_buf(),
+        ignore_rules_imported,
+        ignore_lines_skipped,
+        files_indexed: state.local_files.len(),
+    })
+}
+
+fn append_ignore_rules(
+    root: &Path,
+    source: ImportSource,
+    source_file: &Path,
+    lines: &[String],
+) -> Result<(), SyncError> {
+    let ignore_path = root.join(IGNORE_FILE_NAME);
+    let mut content = if ignore_path.exists() {
+        let existing = std::fs::read_to_string(&ignore_path)?;
+        if existing.ends_with('\n') || existing.is_empty() {
+            existing
+        } else {
+            format!("{}\n", existing)
+        }
+    } else {
+        String::new()
+    };
+
+    content.push_str(&format!("# Imported from {} ({})\n", source.label(), source_file.display()));
+    for line in lines {
+        content.push_str(line);
+        content.push('\n');
+    }
+
+    std::fs::write(ignore_path, content)?;
+    Ok(())
+}
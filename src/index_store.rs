@@ -0,0 +1,1182 @@
+#![allow(dead_code)]
+
+use crate::types::{DirectoryStamp, FileMetadata, SyncError, SyncState, Timestamp};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const STATE_DIR_NAME: &str = ".syncmd";
+pub const INDEX_DB_NAME: &str = "index.db";
+
+/// Journal entries younger than this are never compacted away.
+pub const DEFAULT_JOURNAL_RETENTION_DAYS: i64 = 30;
+/// Minimum time between automatic snapshots.
+const SNAPSHOT_INTERVAL_HOURS: i64 = 24;
+/// Larger files don't keep a base copy for `syncmd diff`.
+pub const MAX_BASE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum JournalOp {
+    Add,
+    Update,
+    Delete,
+}
+
+impl JournalOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JournalOp::Add => "add",
+            JournalOp::Update => "update",
+            JournalOp::Delete => "delete",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, SyncError> {
+        match value {
+            "add" => Ok(JournalOp::Add),
+            "update" => Ok(JournalOp::Update),
+            "delete" => Ok(JournalOp::Delete),
+            other => Err(SyncError::Index(format!("Unknown journal operation: {}", other))),
+        }
+    }
+}
+
+/// One change to the index, numbered by a monotonically increasing sequence.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub op: JournalOp,
+    pub path: PathBuf,
+    pub hash: Option<String>,
+    pub device_id: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// How a device read a file from a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum AccessKind {
+    /// The whole file in answer to a `FileRequest`
+    Download,
+    /// The last chunk of a chunked download
+    Chunked,
+    /// Sent along with a sync response
+    Inline,
+    /// Downloaded through a share link, by someone without a device
+    Link,
+    /// Viewed or downloaded in the web file browser
+    Browser,
+}
+
+impl AccessKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessKind::Download => "download",
+            AccessKind::Chunked => "chunked",
+            AccessKind::Inline => "inline",
+            AccessKind::Link => "link",
+            AccessKind::Browser => "browser",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, SyncError> {
+        match value {
+            "download" => Ok(AccessKind::Download),
+            "chunked" => Ok(AccessKind::Chunked),
+            "inline" => Ok(AccessKind::Inline),
+            "link" => Ok(AccessKind::Link),
+            "browser" => Ok(AccessKind::Browser),
+            other => Err(SyncError::Index(format!("Unknown access kind: {}", other))),
+        }
+    }
+}
+
+/// A device reading a file from a share that logs access.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AccessEntry {
+    pub seq: u64,
+    pub path: PathBuf,
+    /// The version that was read
+    pub hash: String,
+    /// Name of the device the token was issued to
+    pub device: String,
+    pub address: String,
+    pub kind: AccessKind,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// A connection that failed to authenticate, for auditing attempts to
+/// guess tokens.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuthFailureEntry {
+    pub seq: u64,
+    pub address: String,
+    /// The device name the connection claimed
+    pub device: String,
+    pub reason: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// A full copy of the index taken at a journal sequence number.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub id: i64,
+    pub seq: u64,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub file_count: usize,
+}
+
+/// A snapshot kept under a name, and through compaction, see `tags.rs`.
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub snapshot: Snapshot,
+}
+
+/// A change of a device's display name.
+#[derive(Debug, Clone)]
+pub struct DeviceRename {
+    pub device_id: String,
+    pub old_name: String,
+    pub new_name: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Content of a file as of the last completed sync.
+#[derive(Debug, Clone)]
+pub struct BaseVersion {
+    pub hash: String,
+    pub content: Vec<u8>,
+    pub synced_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct CompactionReport {
+    pub snapshot_seq: Option<u64>,
+    pub journal_entries_removed: usize,
+    pub snapshots_removed: usize,
+    pub compacted_through: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct PurgeReport {
+    pub tombstones_removed: usize,
+    pub versions_removed: usize,
+}
+
+/// Persistent copy of the last known index of a sync root, stored in
+/// `<root>/.syncmd/index.db` so it survives restarts.
+pub struct IndexStore {
+    conn: Connection,
+}
+
+impl IndexStore {
+    pub fn open(sync_root: &Path) -> Result<Self, SyncError> {
+        let state_dir = crate::state::dir(sync_root);
+        std::fs::create_dir_all(&state_dir)?;
+        Self::open_at(&state_dir.join(INDEX_DB_NAME))
+    }
+
+    pub fn open_at(db_path: &Path) -> Result<Self, SyncError> {
+        let mut conn = Connection::open(db_path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS files (
+                path TEXT PRIMARY KEY,
+                hash TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                metadata TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS journal (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                op TEXT NOT NULL,
+                path TEXT NOT NULL,
+                hash TEXT,
+                device_id TEXT NOT NULL,
+                timestamp TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS snapshots (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                seq INTEGER NOT NULL,
+                timestamp TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS snapshot_files (
+                snapshot_id INTEGER NOT NULL REFERENCES snapshots(id) ON DELETE CASCADE,
+                path TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                metadata TEXT NOT NULL,
+                PRIMARY KEY (snapshot_id, path)
+            );
+            CREATE TABLE IF NOT EXISTS tags (
+                name TEXT PRIMARY KEY,
+                snapshot_id INTEGER NOT NULL REFERENCES snapshots(id)
+            );
+            CREATE TABLE IF NOT EXISTS directories (
+                path TEXT PRIMARY KEY,
+                modified INTEGER NOT NULL,
+                entries INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS shared_directories (
+                path TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS device_renames (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id TEXT NOT NULL,
+                old_name TEXT NOT NULL,
+                new_name TEXT NOT NULL,
+                timestamp TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS bases (
+                path TEXT PRIMARY KEY,
+                hash TEXT NOT NULL,
+                content BLOB NOT NULL,
+                synced_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS access_log (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                device TEXT NOT NULL,
+                address TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                timestamp TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS access_log_path ON access_log (path);
+            CREATE TABLE IF NOT EXISTS auth_failures (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                address TEXT NOT NULL,
+                device TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                timestamp TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS server_journal (
+                seq INTEGER PRIMARY KEY,
+                op TEXT NOT NULL,
+                path TEXT NOT NULL,
+                hash TEXT,
+                device_id TEXT NOT NULL,
+                timestamp TEXT NOT NULL
+            );
+            CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(
+                path UNINDEXED,
+                hash UNINDEXED,
+                terms
+            );
+            PRAGMA foreign_keys = ON;",
+        )?;
+        migrate_timestamps(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    /// Replaces the stored index with the given state, recording the
+    /// differences to the previous index in the journal.
+    pub fn save_state(&mut self, state: &SyncState) -> Result<(), SyncError> {
+        let previous = self.stored_hashes()?;
+        let now = chrono::Utc::now().to_rfc3339();
+
+        let tx = self.conn.transaction()?;
+        {
+            let mut journal = tx.prepare(
+                "INSERT INTO journal (op, path, hash, device_id, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (path, metadata) in &state.local_files {
+                let op = match previous.get(path) {
+                    None => JournalOp::Add,
+                    Some(hash) if *hash != metadata.hash => JournalOp::Update,
+                    Some(_) => continue,
+                };
+                journal.execute(params![
+                    op.as_str(),
+                    path.to_string_lossy(),
+                    metadata.hash,
+                    state.device_id,
+                    now,
+                ])?;
+            }
+            for path in previous.keys().filter(|p| !state.local_files.contains_key(*p)) {
+                journal.execute(params![
+                    JournalOp::Delete.as_str(),
+                    path.to_string_lossy(),
+                    Option::<String>::None,
+                    state.device_id,
+                    now,
+                ])?;
+            }
+        }
+        tx.execute("DELETE FROM files", [])?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO files (path, hash, size, metadata) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (path, metadata) in &state.local_files {
+                stmt.execute(params![
+                    path.to_string_lossy(),
+                    metadata.hash,
+                    metadata.size as i64,
+                    serde_json::to_string(metadata)?,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Drops files from the index without journaling their deletion, as if
+    /// they had never been synced here.
+    pub fn forget(&mut self, paths: &[PathBuf]) -> Result<(), SyncError> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("DELETE FROM files WHERE path = ?1")?;
+            for path in paths {
+                stmt.execute(params![path.to_string_lossy()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load_state(&self, device_id: String, sync_root: PathBuf) -> Result<SyncState, SyncError> {
+        let mut stmt = self.conn.prepare("SELECT path, metadata FROM files")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut local_files = HashMap::new();
+        for row in rows {
+            let (path, metadata) = row?;
+            local_files.insert(PathBuf::from(path), serde_json::from_str::<FileMetadata>(&metadata)?);
+        }
+
+        Ok(SyncState {
+            local_files,
+            directories: Default::default(),
+            device_id,
+            sync_root,
+        })
+    }
+
+    pub fn get(&self, relative_path: &Path) -> Result<Option<FileMetadata>, SyncError> {
+        let metadata: Option<String> = self.conn
+            .query_row(
+                "SELECT metadata FROM files WHERE path = ?1",
+                params![relative_path.to_string_lossy()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match metadata {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn stored_hashes(&self) -> Result<HashMap<PathBuf, String>, SyncError> {
+        let mut stmt = self.conn.prepare("SELECT path, hash FROM files")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((PathBuf::from(row.get::<_, String>(0)?), row.get::<_, String>(1)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Journal entries with a sequence number greater than `since`, oldest first.
+    ///
+    /// Fails if entries after `since` were already compacted away, in which
+    /// case the caller has to fall back to the full index.
+    pub fn journal_since(&self, since: u64) -> Result<Vec<JournalEntry>, SyncError> {
+        let compacted_through = self.compacted_through()?;
+        if since < compacted_through {
+            return Err(SyncError::Index(format!(
+                "Journal was compacted through sequence {}, cursor {} needs a full resync",
+                compacted_through, since
+            )));
+        }
+
+        self.read_journal(
+            "SELECT seq, op, path, hash, device_id, timestamp FROM journal WHERE seq > ?1 ORDER BY seq",
+            params![since as i64],
+        )
+    }
+
+    /// Up to `limit` entries after `since`, for replicating the journal in
+    /// batches. Entries compacted away are skipped rather than an error.
+    pub fn journal_page(&self, since: u64, limit: usize) -> Result<Vec<JournalEntry>, SyncError> {
+        self.read_journal(
+            "SELECT seq, op, path, hash, device_id, timestamp FROM journal WHERE seq > ?1 ORDER BY seq LIMIT ?2",
+            params![since as i64, limit as i64],
+        )
+    }
+
+    /// Records a single change made outside [`IndexStore::save_state`],
+    /// e.g. a file a device uploaded to a server, and returns its sequence
+    /// number.
+    pub fn append_journal(&self, op: JournalOp, path: &Path, hash: Option<&str>, device_id: &str) -> Result<u64, SyncError> {
+        self.conn.execute(
+            "INSERT INTO journal (op, path, hash, device_id, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![op.as_str(), path.to_string_lossy(), hash, device_id, chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(self.conn.last_insert_rowid() as u64)
+    }
+
+    /// Keeps entries replicated from the server's journal, numbered as
+    /// they are there. Entries already kept are skipped.
+    pub fn store_server_journal(&mut self, entries: &[JournalEntry]) -> Result<(), SyncError> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO server_journal (seq, op, path, hash, device_id, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for entry in entries {
+                stmt.execute(params![
+                    entry.seq as i64,
+                    entry.op.as_str(),
+                    entry.path.to_string_lossy(),
+                    entry.hash,
+                    entry.device_id,
+                    entry.timestamp.to_rfc3339(),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Sequence number of the last replicated server entry, 0 if none.
+    pub fn server_journal_cursor(&self) -> Result<u64, SyncError> {
+        let seq: Option<i64> = self.conn.query_row("SELECT MAX(seq) FROM server_journal", [], |row| row.get(0))?;
+        Ok(seq.unwrap_or(0) as u64)
+    }
+
+    /// The last `limit` replicated server entries, newest first.
+    pub fn server_journal(&self, limit: usize) -> Result<Vec<JournalEntry>, SyncError> {
+        self.read_journal(
+            "SELECT seq, op, path, hash, device_id, timestamp FROM server_journal ORDER BY seq DESC LIMIT ?1",
+            params![limit as i64],
+        )
+    }
+
+    /// The last `limit` entries of this root's own journal, newest first.
+    pub fn recent_journal(&self, limit: usize) -> Result<Vec<JournalEntry>, SyncError> {
+        self.read_journal(
+            "SELECT seq, op, path, hash, device_id, timestamp FROM journal ORDER BY seq DESC LIMIT ?1",
+            params![limit as i64],
+        )
+    }
+
+    fn read_journal(&self, query: &str, params: impl rusqlite::Params) -> Result<Vec<JournalEntry>, SyncError> {
+        let mut stmt = self.conn.prepare(query)?;
+        let rows = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (seq, op, path, hash, device_id, timestamp) = row?;
+            entries.push(JournalEntry {
+                seq: seq as u64,
+                op: JournalOp::parse(&op)?,
+                path: PathBuf::from(path),
+                hash,
+                device_id,
+                timestamp: parse_timestamp(&timestamp)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    pub fn latest_seq(&self) -> Result<u64, SyncError> {
+        let seq: Option<i64> = self.conn.query_row("SELECT MAX(seq) FROM journal", [], |row| row.get(0))?;
+        Ok(seq.unwrap_or(0) as u64)
+    }
+
+    pub fn file_count(&self) -> Result<usize, SyncError> {
+        let count: i64 = self.conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Folds a finished transfer into the moving average used to estimate
+    /// how long the next sync will take.
+    pub fn record_throughput(&self, bytes: u64, elapsed: std::time::Duration) -> Result<(), SyncError> {
+        if bytes == 0 || elapsed.is_zero() {
+            return Ok(());
+        }
+        let sample = bytes as f64 / elapsed.as_secs_f64();
+        let average = match self.recent_throughput()? {
+            Some(previous) => previous * 0.7 + sample * 0.3,
+            None => sample,
+        };
+        self.conn.execute(
+            "INSERT INTO meta (key, value) VALUES ('throughput', ?1)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![average.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Recent transfer throughput in bytes per second, if any was recorded.
+    pub fn recent_throughput(&self) -> Result<Option<f64>, SyncError> {
+        let value: Option<String> = self.conn
+            .query_row("SELECT value FROM meta WHERE key = 'throughput'", [], |row| row.get(0))
+            .optional()?;
+        Ok(value.and_then(|v| v.parse().ok()))
+    }
+
+    /// Remembers what was negotiated with the server on the last connection.
+    pub fn record_capabilities(&self, capabilities: &[crate::capabilities::Capability]) -> Result<(), SyncError> {
+        self.conn.execute(
+            "INSERT INTO meta (key, value) VALUES ('capabilities', ?1)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![serde_json::to_string(capabilities)?],
+        )?;
+        Ok(())
+    }
+
+    /// Capabilities from the last connection, `None` if it never connected.
+    pub fn capabilities(&self) -> Result<Option<Vec<crate::capabilities::Capability>>, SyncError> {
+        let value: Option<String> = self.conn
+            .query_row("SELECT value FROM meta WHERE key = 'capabilities'", [], |row| row.get(0))
+            .optional()?;
+        Ok(value.map(|v| serde_json::from_str(&v)).transpose()?)
+    }
+
+    pub fn record_device_rename(&self, device_id: &str, old_name: &str, new_name: &str) -> Result<(), SyncError> {
+        self.conn.execute(
+            "INSERT INTO device_renames (device_id, old_name, new_name, timestamp) VALUES (?1, ?2, ?3, ?4)",
+            params![device_id, old_name, new_name, chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// Every recorded rename, oldest first.
+    pub fn device_renames(&self) -> Result<Vec<DeviceRename>, SyncError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device_id, old_name, new_name, timestamp FROM device_renames ORDER BY seq",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+
+        let mut renames = Vec::new();
+        for row in rows {
+            let (device_id, old_name, new_name, timestamp) = row?;
+            renames.push(DeviceRename {
+                device_id,
+                old_name,
+                new_name,
+                timestamp: parse_timestamp(&timestamp)?,
+            });
+        }
+        Ok(renames)
+    }
+
+    pub fn record_access(
+        &self,
+        path: &Path,
+        hash: &str,
+        device: &str,
+        address: &str,
+        kind: AccessKind,
+    ) -> Result<(), SyncError> {
+        self.conn.execute(
+            "INSERT INTO access_log (path, hash, device, address, kind, timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![path.to_string_lossy(), hash, device, address, kind.as_str(), chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// The last `limit` reads of the file at `path`, or of the files under
+    /// it, or of any file; newest first.
+    pub fn access_log(&self, path: Option<&Path>, limit: usize) -> Result<Vec<AccessEntry>, SyncError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, path, hash, device, address, kind, timestamp FROM access_log
+             WHERE ?1 IS NULL OR path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'
+             ORDER BY seq DESC LIMIT ?2",
+        )?;
+        let path = path.map(|path| path.to_string_lossy().trim_end_matches('/').to_string());
+        let rows = stmt.query_map(params![path, limit as i64], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (seq, path, hash, device, address, kind, timestamp) = row?;
+            entries.push(AccessEntry {
+                seq: seq as u64,
+                path: PathBuf::from(path),
+                hash,
+                device,
+                address,
+                kind: AccessKind::parse(&kind)?,
+                timestamp: parse_timestamp(&timestamp)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    pub fn record_auth_failure(&self, address: &str, device: &str, reason: &str) -> Result<(), SyncError> {
+        self.conn.execute(
+            "INSERT INTO auth_failures (address, device, reason, timestamp) VALUES (?1, ?2, ?3, ?4)",
+            params![address, device, reason, chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// The last `limit` failed authentications, newest first.
+    pub fn auth_failures(&self, limit: usize) -> Result<Vec<AuthFailureEntry>, SyncError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, address, device, reason, timestamp FROM auth_failures ORDER BY seq DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (seq, address, device, reason, timestamp) = row?;
+            entries.push(AuthFailureEntry {
+                seq: seq as u64,
+                address,
+                device,
+                reason,
+                timestamp: parse_timestamp(&timestamp)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Remembers the content of every text file in `state` as its
+    /// last-synced version. `read` returns the content that was synced.
+    pub fn record_bases<F>(&mut self, state: &SyncState, read: F) -> Result<usize, SyncError>
+    where
+        F: Fn(&Path) -> Result<Vec<u8>, SyncError>,
+    {
+        let stored: HashMap<String, String> = {
+            let mut stmt = self.conn.prepare("SELECT path, hash FROM bases")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        let now = chrono::Utc::now().to_rfc3339();
+        let tx = self.conn.transaction()?;
+        let mut recorded = 0;
+
+        for (path, metadata) in &state.local_files {
+            let key = path.to_string_lossy().to_string();
+            if metadata.size > MAX_BASE_SIZE || stored.get(&key) == Some(&metadata.hash) {
+                continue;
+            }
+            let Ok(content) = read(path) else {
+                continue;
+            };
+            if std::str::from_utf8(&content).is_err() {
+                continue;
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO bases (path, hash, content, synced_at) VALUES (?1, ?2, ?3, ?4)",
+                params![key, metadata.hash, content, now],
+            )?;
+            recorded += 1;
+        }
+        for key in stored.keys() {
+            if !state.local_files.contains_key(Path::new(key)) {
+                tx.execute("DELETE FROM bases WHERE path = ?1", params![key])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(recorded)
+    }
+
+    pub fn base(&self, relative_path: &Path) -> Result<Option<BaseVersion>, SyncError> {
+        let row: Option<(String, Vec<u8>, String)> = self.conn
+            .query_row(
+                "SELECT hash, content, synced_at FROM bases WHERE path = ?1",
+                params![relative_path.to_string_lossy()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        row.map(|(hash, content, synced_at)| {
+            Ok(BaseVersion { hash, content, synced_at: parse_timestamp(&synced_at)? })
+        })
+        .transpose()
+    }
+
+    /// Last-synced content with the given hash, whatever path it was at.
+    pub fn base_with_hash(&self, hash: &str) -> Result<Option<Vec<u8>>, SyncError> {
+        Ok(self.conn
+            .query_row("SELECT content FROM bases WHERE hash = ?1 LIMIT 1", params![hash], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Indexed files with the given content hash.
+    pub fn paths_with_hash(&self, hash: &str) -> Result<Vec<PathBuf>, SyncError> {
+        let mut stmt = self.conn.prepare("SELECT path FROM files WHERE hash = ?1 ORDER BY path")?;
+        let rows = stmt.query_map(params![hash], |row| Ok(PathBuf::from(row.get::<_, String>(0)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Hashes of the file versions the search index has words for.
+    pub fn search_hashes(&self) -> Result<HashMap<PathBuf, String>, SyncError> {
+        let mut stmt = self.conn.prepare("SELECT path, hash FROM search_index")?;
+        let rows = stmt.query_map([], |row| Ok((PathBuf::from(row.get::<_, String>(0)?), row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Replaces the search entries for the given paths and drops `removed`.
+    pub fn update_search_index(
+        &mut self,
+        entries: &[crate::search::SearchEntry],
+        removed: &[PathBuf],
+    ) -> Result<(), SyncError> {
+        let tx = self.conn.transaction()?;
+        for path in removed.iter().chain(entries.iter().map(|entry| &entry.path)) {
+            tx.execute("DELETE FROM search_index WHERE path = ?1", params![path.to_string_lossy()])?;
+        }
+        for entry in entries {
+            tx.execute(
+                "INSERT INTO search_index (path, hash, terms) VALUES (?1, ?2, ?3)",
+                params![entry.path.to_string_lossy(), entry.hash, entry.terms.join(" ")],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Paths of files containing every word of `query`, best match first.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<PathBuf>, SyncError> {
+        let Some(query) = crate::search::fts_query(query) else {
+            return Ok(Vec::new());
+        };
+        let mut stmt = self.conn.prepare(
+            "SELECT path FROM search_index WHERE search_index MATCH ?1 ORDER BY rank LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![query, limit as i64], |row| row.get::<_, String>(0))?;
+        Ok(rows.map(|row| row.map(PathBuf::from)).collect::<Result<_, _>>()?)
+    }
+
+    /// The highest sequence number removed by compaction, 0 if none was.
+    pub fn compacted_through(&self) -> Result<u64, SyncError> {
+        let value: Option<String> = self.conn
+            .query_row("SELECT value FROM meta WHERE key = 'compacted_through'", [], |row| row.get(0))
+            .optional()?;
+        Ok(value.and_then(|v| v.parse().ok()).unwrap_or(0))
+    }
+
+    /// Copies the current index into a new snapshot at the latest sequence.
+    pub fn snapshot(&mut self) -> Result<Snapshot, SyncError> {
+        let seq = self.latest_seq()?;
+        let timestamp = chrono::Utc::now();
+
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO snapshots (seq, timestamp) VALUES (?1, ?2)",
+            params![seq as i64, timestamp.to_rfc3339()],
+        )?;
+        let id = tx.last_insert_rowid();
+        let file_count = tx.execute(
+            "INSERT INTO snapshot_files (snapshot_id, path, hash, metadata)
+             SELECT ?1, path, hash, metadata FROM files",
+            params![id],
+        )?;
+        tx.commit()?;
+
+        Ok(Snapshot { id, seq, timestamp, file_count })
+    }
+
+    /// Snapshots, newest first.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>, SyncError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.id, s.seq, s.timestamp, COUNT(f.path) FROM snapshots s
+             LEFT JOIN snapshot_files f ON f.snapshot_id = s.id
+             GROUP BY s.id ORDER BY s.seq DESC, s.id DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+
+        let mut snapshots = Vec::new();
+        for row in rows {
+            let (id, seq, timestamp, file_count) = row?;
+            snapshots.push(Snapshot {
+                id,
+                seq: seq as u64,
+                timestamp: parse_timestamp(&timestamp)?,
+                file_count: file_count as usize,
+            });
+        }
+        Ok(snapshots)
+    }
+
+    /// Snapshots the current index under `name`. Fails if the name is
+    /// taken.
+    pub fn tag(&mut self, name: &str) -> Result<Tag, SyncError> {
+        if self.find_tag(name)?.is_some() {
+            return Err(SyncError::Index(format!("There already is a tag named {}", name)));
+        }
+        let snapshot = self.snapshot()?;
+        self.conn.execute("INSERT INTO tags (name, snapshot_id) VALUES (?1, ?2)", params![name, snapshot.id])?;
+        Ok(Tag { name: name.to_string(), snapshot })
+    }
+
+    /// Tags, newest first.
+    pub fn tags(&self) -> Result<Vec<Tag>, SyncError> {
+        let names: HashMap<i64, String> = {
+            let mut stmt = self.conn.prepare("SELECT snapshot_id, name FROM tags")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        Ok(self.snapshots()?
+            .into_iter()
+            .filter_map(|snapshot| Some(Tag { name: names.get(&snapshot.id)?.clone(), snapshot }))
+            .collect())
+    }
+
+    pub fn find_tag(&self, name: &str) -> Result<Option<Tag>, SyncError> {
+        Ok(self.tags()?.into_iter().find(|tag| tag.name == name))
+    }
+
+    /// Replaces the stamps of the root's directories, see
+    /// [`FileIndexer::index_directory_since`](crate::indexer::FileIndexer::index_directory_since).
+    pub fn save_directories(&mut self, directories: &HashMap<PathBuf, DirectoryStamp>) -> Result<(), SyncError> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM directories", [])?;
+        {
+            let mut stmt = tx.prepare("INSERT INTO directories (path, modified, entries) VALUES (?1, ?2, ?3)")?;
+            for (path, stamp) in directories {
+                stmt.execute(params![path.to_string_lossy(), stamp.modified.as_millis(), stamp.entries as i64])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load_directories(&self) -> Result<HashMap<PathBuf, DirectoryStamp>, SyncError> {
+        let mut stmt = self.conn.prepare("SELECT path, modified, entries FROM directories")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                PathBuf::from(row.get::<_, String>(0)?),
+                DirectoryStamp { modified: Timestamp::from_millis(row.get(1)?), entries: row.get::<_, i64>(2)? as u64 },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Replaces the directories synced with the server: on a device those
+    /// it had after its last sync, on the server the share's.
+    pub fn save_shared_directories(&mut self, directories: &BTreeSet<PathBuf>) -> Result<(), SyncError> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM shared_directories", [])?;
+        {
+            let mut stmt = tx.prepare("INSERT INTO shared_directories (path) VALUES (?1)")?;
+            for path in directories {
+                stmt.execute(params![path.to_string_lossy()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn shared_directories(&self) -> Result<BTreeSet<PathBuf>, SyncError> {
+        let mut stmt = self.conn.prepare("SELECT path FROM shared_directories")?;
+        let rows = stmt.query_map([], |row| Ok(PathBuf::from(row.get::<_, String>(0)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// The index as it was when the snapshot was taken, for restoring old
+    /// versions after the journal entries are gone.
+    pub fn load_snapshot(&self, snapshot_id: i64) -> Result<HashMap<PathBuf, FileMetadata>, SyncError> {
+        let mut stmt = self.conn.prepare("SELECT path, metadata FROM snapshot_files WHERE snapshot_id = ?1")?;
+        let rows = stmt.query_map(params![snapshot_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut files = HashMap::new();
+        for row in rows {
+            let (path, metadata) = row?;
+            files.insert(PathBuf::from(path), serde_json::from_str::<FileMetadata>(&metadata)?);
+        }
+        Ok(files)
+    }
+
+    /// Runs [`IndexStore::compact`] if the last snapshot is older than the
+    /// snapshot interval.
+    pub fn maybe_compact(&mut self) -> Result<Option<CompactionReport>, SyncError> {
+        let due = match self.snapshots()?.first() {
+            Some(latest) => {
+                chrono::Utc::now() - latest.timestamp >= chrono::Duration::hours(SNAPSHOT_INTERVAL_HOURS)
+            }
+            None => self.latest_seq()? > 0,
+        };
+        if !due {
+            return Ok(None);
+        }
+        self.compact(chrono::Duration::days(DEFAULT_JOURNAL_RETENTION_DAYS)).map(Some)
+    }
+
+    /// Snapshots the current index, drops journal entries older than the
+    /// retention window, and thins out old snapshots so that roughly one
+    /// survives per doubling of age (1h, 2h, 4h, ... 1d, 2d, 4d, ...).
+    pub fn compact(&mut self, retention: chrono::Duration) -> Result<CompactionReport, SyncError> {
+        let mut report = CompactionReport::default();
+
+        let latest_seq = self.latest_seq()?;
+        let needs_snapshot = self
+            .snapshots()?
+            .first()
+            .map(|s| s.seq < latest_seq)
+            .unwrap_or(true);
+        if needs_snapshot {
+            report.snapshot_seq = Some(self.snapshot()?.seq);
+        }
+
+        let now = chrono::Utc::now();
+        let cutoff = (now - retention).to_rfc3339();
+        let tx = self.conn.transaction()?;
+        let removed_through: Option<i64> = tx.query_row(
+            "SELECT MAX(seq) FROM journal WHERE timestamp < ?1",
+            params![cutoff],
+            |row| row.get(0),
+        )?;
+        if let Some(removed_through) = removed_through {
+            report.journal_entries_removed =
+                tx.execute("DELETE FROM journal WHERE seq <= ?1", params![removed_through])?;
+            tx.execute(
+                "INSERT INTO meta (key, value) VALUES ('compacted_through', ?1)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                params![removed_through.to_string()],
+            )?;
+        }
+        tx.commit()?;
+        report.compacted_through = self.compacted_through()?;
+
+        // Tagged snapshots stay until their tag is gone
+        let tagged: std::collections::HashSet<i64> = self.tags()?.iter().map(|tag| tag.snapshot.id).collect();
+        let snapshots = self.snapshots()?;
+        let ages: Vec<(i64, chrono::Duration)> = snapshots.iter()
+            .filter(|s| !tagged.contains(&s.id))
+            .map(|s| (s.id, now - s.timestamp))
+            .collect();
+        for id in snapshots_to_prune(&ages) {
+            self.conn.execute("DELETE FROM snapshot_files WHERE snapshot_id = ?1", params![id])?;
+            self.conn.execute("DELETE FROM snapshots WHERE id = ?1", params![id])?;
+            report.snapshots_removed += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// Drops delete entries from the journal and stored versions of deleted
+    /// files once they are older than `retention`. Clients whose journal
+    /// cursor is older than a dropped tombstone fall back to a full resync,
+    /// as after compaction.
+    pub fn purge_deleted(&mut self, retention: chrono::Duration) -> Result<PurgeReport, SyncError> {
+        let mut report = PurgeReport::default();
+        let cutoff = (chrono::Utc::now() - retention).to_rfc3339();
+
+        let tx = self.conn.transaction()?;
+        let purged_through: Option<i64> = tx.query_row(
+            "SELECT MAX(seq) FROM journal WHERE op = ?1 AND timestamp < ?2",
+            params![JournalOp::Delete.as_str(), cutoff],
+            |row| row.get(0),
+        )?;
+        if let Some(purged_through) = purged_through {
+            report.tombstones_removed = tx.execute(
+                "DELETE FROM journal WHERE op = ?1 AND seq <= ?2",
+                params![JournalOp::Delete.as_str(), purged_through],
+            )?;
+            tx.execute(
+                "INSERT INTO meta (key, value) VALUES ('compacted_through', ?1)
+                 ON CONFLICT(key) DO UPDATE SET value = MAX(CAST(value AS INTEGER), excluded.value)",
+                params![purged_through.to_string()],
+            )?;
+        }
+        report.versions_removed = tx.execute(
+            "DELETE FROM bases WHERE synced_at < ?1 AND path NOT IN (SELECT path FROM files)",
+            params![cutoff],
+        )?;
+        tx.commit()?;
+
+        Ok(report)
+    }
+}
+
+/// Given snapshots newest first, keeps the newest one in each exponential age
+/// bucket and returns the ids of the rest. The newest snapshot is always kept.
+fn snapshots_to_prune(snapshots: &[(i64, chrono::Duration)]) -> Vec<i64> {
+    let mut seen_buckets = std::collections::HashSet::new();
+    snapshots
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (id, age))| {
+            let hours = age.num_hours().max(0) as u64;
+            let bucket = u64::BITS - hours.leading_zeros();
+            let keep = seen_buckets.insert(bucket) || i == 0;
+            (!keep).then_some(*id)
+        })
+        .collect()
+}
+
+/// Rewrites stored metadata from serde's `SystemTime` form to UTC
+/// milliseconds, once per database. Both forms load, this only keeps old
+/// entries from carrying precision newer ones don't have.
+fn migrate_timestamps(conn: &mut Connection) -> Result<(), SyncError> {
+    let migrated: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = 'timestamps'", [], |row| row.get(0))
+        .optional()?;
+    if migrated.is_some() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    for table in ["files", "snapshot_files"] {
+        let rows: Vec<(i64, String)> = tx
+            .prepare(&format!("SELECT rowid, metadata FROM {} WHERE metadata LIKE '%secs_since_epoch%'", table))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let mut update = tx.prepare(&format!("UPDATE {} SET metadata = ?1 WHERE rowid = ?2", table))?;
+        for (rowid, metadata) in rows {
+            let metadata: FileMetadata = serde_json::from_str(&metadata)?;
+            update.execute(params![serde_json::to_string(&metadata)?, rowid])?;
+        }
+    }
+    tx.execute("INSERT INTO meta (key, value) VALUES ('timestamps', 'utc-millis')", [])?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn parse_timestamp(value: &str) -> Result<chrono::DateTime<chrono::Utc>, SyncError> {
+    Ok(chrono::DateTime::parse_from_rfc3339(value)
+        .map_err(|e| SyncError::Index(format!("Invalid journal timestamp: {}", e)))?
+        .with_timezone(&chrono::Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_timestamps_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join(INDEX_DB_NAME);
+        drop(IndexStore::open_at(&db_path).unwrap());
+        let legacy = r#"{"path":"a.md","hash":"h","size":1,
+            "modified":{"secs_since_epoch":1714557600,"nanos_since_epoch":123456789},
+            "created":{"secs_since_epoch":1714557600,"nanos_since_epoch":0},
+            "version":1714557600,"device_id":"device"}"#;
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute("INSERT INTO files (path, hash, size, metadata) VALUES ('a.md', 'h', 1, ?1)", [legacy]).unwrap();
+        conn.execute("DELETE FROM meta WHERE key = 'timestamps'", []).unwrap();
+        drop(conn);
+
+        let store = IndexStore::open_at(&db_path).unwrap();
+        let stored: String = store.conn.query_row("SELECT metadata FROM files", [], |row| row.get(0)).unwrap();
+        assert!(stored.contains(r#""modified":1714557600123"#), "{}", stored);
+        assert_eq!(store.get(Path::new("a.md")).unwrap().unwrap().modified.as_millis(), 1_714_557_600_123);
+    }
+
+    #[test]
+    fn test_snapshot_pruning_keeps_one_per_bucket() {
+        let hours = |h: i64| chrono::Duration::hours(h);
+        // Newest first: buckets are 0, 1, [2,4), [2,4), [4,8), [32,64), [32,64)
+        let snapshots = vec![
+            (7, hours(0)),
+            (6, hours(1)),
+            (5, hours(2)),
+            (4, hours(3)),
+            (3, hours(5)),
+            (2, hours(40)),
+            (1, hours(50)),
+        ];
+        assert_eq!(snapshots_to_prune(&snapshots), vec![4, 1]);
+    }
+
+    #[test]
+    fn test_purge_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = IndexStore::open(dir.path()).unwrap();
+        let file = |path: &str| (PathBuf::from(path), FileMetadata::for_test(path, path.as_bytes()));
+        let state = |files: Vec<(PathBuf, FileMetadata)>| SyncState {
+            local_files: files.into_iter().collect(),
+            directories: Default::default(),
+            device_id: "device".to_string(),
+            sync_root: dir.path().to_path_buf(),
+        };
+
+        store.save_state(&state(vec![file("a.md"), file("b.md")])).unwrap();
+        store.save_state(&state(vec![file("a.md")])).unwrap();
+        assert_eq!(store.purge_deleted(chrono::Duration::days(30)).unwrap().tombstones_removed, 0);
+
+        let report = store.purge_deleted(chrono::Duration::seconds(-1)).unwrap();
+        assert_eq!(report.tombstones_removed, 1);
+        let remaining = store.journal_since(store.compacted_through().unwrap()).unwrap();
+        assert!(remaining.iter().all(|entry| entry.op != JournalOp::Delete));
+        assert!(store.journal_since(0).is_err());
+    }
+
+    #[test]
+    fn test_search_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = IndexStore::open(dir.path()).unwrap();
+        let entry = |path: &str, text: &str| {
+            crate::search::SearchEntry::from_content(PathBuf::from(path), format!("hash-{}", path), text.as_bytes()).unwrap()
+        };
+        store.update_search_index(&[
+            entry("a.md", "Weekly planning meeting"),
+            entry("b.md", "Planning the garden"),
+        ], &[]).unwrap();
+        assert_eq!(store.search("plan", 10).unwrap().len(), 2);
+        assert_eq!(store.search("planning meet", 10).unwrap(), vec![PathBuf::from("a.md")]);
+
+        store.update_search_index(&[entry("b.md", "Roses")], &[PathBuf::from("a.md")]).unwrap();
+        assert!(store.search("planning", 10).unwrap().is_empty());
+        assert_eq!(store.search_hashes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_access_log_per_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = IndexStore::open(dir.path()).unwrap();
+        store.record_access(Path::new("notes/a.md"), "h1", "laptop", "10.0.0.2:5000", AccessKind::Download).unwrap();
+        store.record_access(Path::new("notes-old/b.md"), "h2", "phone", "10.0.0.3:5000", AccessKind::Inline).unwrap();
+        store.record_access(Path::new("notes/a.md"), "h3", "phone", "10.0.0.3:5001", AccessKind::Chunked).unwrap();
+
+        let reads = store.access_log(Some(Path::new("notes/a.md")), 10).unwrap();
+        let summary: Vec<(&str, AccessKind)> = reads.iter().map(|e| (e.device.as_str(), e.kind)).collect();
+        assert_eq!(summary, [("phone", AccessKind::Chunked), ("laptop", AccessKind::Download)]);
+        assert_eq!(store.access_log(Some(Path::new("notes/")), 10).unwrap().len(), 2);
+        assert_eq!(store.access_log(None, 10).unwrap().len(), 3);
+        assert_eq!(store.access_log(None, 1).unwrap()[0].hash, "h3");
+    }
+}
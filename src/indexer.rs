@@ -1,9 +1,17 @@
 #![allow(dead_code)]
 
-use crate::types::{FileMetadata, SyncError, SyncState, FileCategory, FileAnalysis, FileChange, DetailedFileChanges};
+use crate::apply_journal::{self, ApplyBatch};
+pub use crate::apply_journal::Recovery;
+use crate::filter::{FilterCommand, FilterDecision};
+use crate::ignore::IgnoreRules;
+use crate::transform::TransformPipeline;
+use crate::types::{DirectoryStamp, FileMetadata, FilePermissions, SyncError, SyncState, FileCategory, FileAnalysis, FileChange, DetailedFileChanges, PathSelection, FileTypeFilter};
 use blake3::hash;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::SystemTime;
 use walkdir::WalkDir;
 
@@ -11,6 +19,127 @@ use walkdir::WalkDir;
 pub struct FileIndexer {
     device_id: String,
     sync_root: PathBuf,
+    filter: Option<FilterCommand>,
+    transforms: TransformPipeline,
+    categories: Vec<FileCategory>, // empty means every category
+    path_overrides: Mutex<HashMap<PathBuf, PathBuf>>, // published path -> local path
+    strict: bool,
+    sync_vcs_dirs: bool,
+    respect_gitignore: bool,
+    /// The root's `ignore` setting, see [`IgnoreRules::for_root`]
+    ignore_patterns: Vec<String>,
+    selection: PathSelection,
+    file_types: FileTypeFilter,
+    trash_retention: std::time::Duration,
+    /// Open while a batch of sync operations is applied, see `apply_journal.rs`
+    batch: Mutex<Option<ApplyBatch>>,
+    /// Versions from other devices being written, see [`FileIndexer::adopt`]
+    adopted: Mutex<HashMap<PathBuf, FileMetadata>>,
+}
+
+/// Roots with this many files skip files in unchanged directories between
+/// deep scans, see [`FileIndexer::index_directory_since`]
+pub const LARGE_ROOT_FILES: usize = 10_000;
+/// How often a large root is walked in full anyway, for edits in place a
+/// watcher missed
+pub const DEEP_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Repository metadata of version control systems. Its files change on every
+/// commit and checkout, so it isn't synced unless a root asks for it.
+pub const VCS_DIR_NAMES: &[&str] = &[".git", ".hg", ".svn"];
+
+/// Whether `name` is a version control directory, or a `.git` file pointing
+/// at one from a worktree or submodule.
+pub fn is_vcs_dir(name: &std::ffi::OsStr) -> bool {
+    VCS_DIR_NAMES.iter().any(|vcs| name == *vcs)
+}
+
+/// Why a file under the root was left out of the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Name starts with a dot
+    Hidden,
+    /// Inside a `.git`, `.hg` or `.svn` directory
+    VersionControl,
+    /// Matched a `.syncignore` pattern, or a `.gitignore` one when the root
+    /// follows the repository's ignore rules
+    Ignored { source: PathBuf, line: usize, pattern: String },
+    /// The name isn't valid UTF-8, so it can't be sent to peers
+    UndecodablePath,
+    /// The file or directory couldn't be read, e.g. permission denied
+    Unreadable(String),
+    /// The extension isn't a synced file type, or its category is excluded
+    UnsupportedType,
+    /// Larger than the root's `max_file_size`
+    TooLarge { size: u64, limit: u64 },
+    /// The root's filter command rejected it
+    Filtered,
+    /// Outside the subfolders this device syncs
+    NotSelected,
+    /// syncmd's own state directory
+    State,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::Hidden => write!(f, "hidden file or directory"),
+            SkipReason::VersionControl => write!(f, "version control directory"),
+            SkipReason::Ignored { source, line, pattern } => write!(f, "{} line {}: {}", source.display(), line, pattern),
+            SkipReason::UndecodablePath => write!(f, "path is not valid UTF-8"),
+            SkipReason::Unreadable(e) => write!(f, "unreadable: {}", e),
+            SkipReason::UnsupportedType => write!(f, "file type is not synced"),
+            SkipReason::TooLarge { size, limit } => write!(
+                f,
+                "{} is over the {} limit",
+                crate::plan::format_size(*size),
+                crate::plan::format_size(*limit)
+            ),
+            SkipReason::Filtered => write!(f, "rejected by filter command"),
+            SkipReason::NotSelected => write!(f, "not in the selected subfolders"),
+            SkipReason::State => write!(f, "syncmd state directory"),
+        }
+    }
+}
+
+impl SkipReason {
+    /// Exclusions the user asked for, as opposed to files that were dropped
+    /// because they couldn't be handled. Strict mode only fails on the latter.
+    pub fn is_deliberate(&self) -> bool {
+        matches!(
+            self,
+            SkipReason::Hidden
+                | SkipReason::VersionControl
+                | SkipReason::Ignored { .. }
+                | SkipReason::NotSelected
+                | SkipReason::TooLarge { .. }
+                | SkipReason::State
+        )
+    }
+}
+
+/// What a walk of the root found.
+struct Walk {
+    /// Files that keep what the previous index has for them
+    unchanged: HashMap<PathBuf, FileMetadata>,
+    /// Files to read and hash, by full and relative path
+    to_hash: Vec<(PathBuf, PathBuf)>,
+    skipped: Vec<SkippedFile>,
+    directories: HashMap<PathBuf, DirectoryStamp>,
+}
+
+/// The stamp of the directory at `path`, `None` if it can't be read.
+fn directory_stamp(path: &Path) -> Option<DirectoryStamp> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(DirectoryStamp { modified: modified.into(), entries: fs::read_dir(path).ok()?.count() as u64 })
+}
+
+/// A file, or a whole directory for hidden and ignored ones, that was left
+/// out of the index.
+#[derive(Debug, Clone)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: SkipReason,
 }
 
 impl FileIndexer {
@@ -18,53 +147,485 @@ impl FileIndexer {
         Self {
             device_id,
             sync_root,
+            filter: None,
+            transforms: TransformPipeline::default(),
+            categories: Vec::new(),
+            path_overrides: Mutex::new(HashMap::new()),
+            strict: false,
+            sync_vcs_dirs: false,
+            respect_gitignore: false,
+            ignore_patterns: Vec::new(),
+            selection: PathSelection::default(),
+            file_types: FileTypeFilter::default(),
+            trash_retention: crate::trash::retention(None),
+            batch: Mutex::new(None),
+            adopted: Mutex::new(HashMap::new()),
         }
     }
 
+    pub fn with_filter(mut self, filter: Option<FilterCommand>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_transforms(mut self, transforms: TransformPipeline) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Restricts syncing to files of the given categories.
+    pub fn with_categories(mut self, categories: Vec<FileCategory>) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    /// Fails indexing when any file is skipped or can't be read, instead of
+    /// leaving it out. Hidden and `.syncignore`d files are still excluded.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Syncs the contents of version control directories like any other
+    /// directory instead of skipping them.
+    pub fn with_vcs_dirs(mut self, sync_vcs_dirs: bool) -> Self {
+        self.sync_vcs_dirs = sync_vcs_dirs;
+        self
+    }
+
+    /// Also excludes files the enclosing git repository ignores.
+    pub fn with_gitignore(mut self, respect_gitignore: bool) -> Self {
+        self.respect_gitignore = respect_gitignore;
+        self
+    }
+
+    /// Also excludes files matching these `.syncignore`-style patterns.
+    pub fn with_ignore_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.ignore_patterns = patterns;
+        self
+    }
+
+    /// Only indexes the selected subfolders of the root.
+    pub fn with_selection(mut self, selection: PathSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Which file types are synced, and up to what size.
+    pub fn with_file_types(mut self, file_types: FileTypeFilter) -> Self {
+        self.file_types = file_types;
+        self
+    }
+
+    /// How long files deleted by a sync stay in the trash.
+    pub fn with_trash_retention(mut self, retention: std::time::Duration) -> Self {
+        self.trash_retention = retention;
+        self
+    }
+
     pub fn sync_root(&self) -> &PathBuf {
         &self.sync_root
     }
 
     pub fn index_directory(&self) -> Result<SyncState, SyncError> {
-        let mut local_files = std::collections::HashMap::new();
+        self.strict_check(self.index_directory_with_skipped()?)
+    }
+
+    /// Like [`index_directory`](Self::index_directory), but files whose
+    /// modification time and size are unchanged since `previous` keep their
+    /// hash instead of being read again. An edit that preserved both, such
+    /// as a copy with `cp -p` of an equal-sized file, goes unnoticed.
+    pub fn index_directory_from(&self, previous: &SyncState) -> Result<SyncState, SyncError> {
+        let (state, skipped, _) = self.index(Some(&previous.local_files), None)?;
+        self.strict_check((state, skipped))
+    }
+
+    /// Like [`index_directory_from`](Self::index_directory_from), but files
+    /// directly in a directory whose [`DirectoryStamp`] matches `directories`
+    /// aren't even looked at, unless they are among the `changed` absolute
+    /// paths a watcher reported. For very large roots between deep scans,
+    /// which pass no `directories`. Returns the stamps to pass next time.
+    pub fn index_directory_since(
+        &self,
+        previous: &SyncState,
+        directories: Option<&HashMap<PathBuf, DirectoryStamp>>,
+        changed: &HashSet<PathBuf>,
+    ) -> Result<(SyncState, HashMap<PathBuf, DirectoryStamp>), SyncError> {
+        let changed: HashSet<PathBuf> = changed
+            .iter()
+            .filter_map(|path| path.strip_prefix(&self.sync_root).ok())
+            .map(Path::to_path_buf)
+            .collect();
+        let (state, skipped, stamps) = self.index(Some(&previous.local_files), directories.map(|known| (known, &changed)))?;
+        Ok((self.strict_check((state, skipped))?, stamps))
+    }
+
+    /// Indexes the root against the index persisted before syncmd stopped
+    /// and returns the new state with the operations for everything that
+    /// changed on disk in the meantime.
+    pub fn reconcile(&self, persisted: &SyncState) -> Result<(SyncState, Vec<crate::types::SyncOperation>), SyncError> {
+        let current = self.index_directory_from(persisted)?;
+        let operations = diff_states(&persisted.local_files, &current.local_files);
+        Ok((current, operations))
+    }
+
+    fn strict_check(&self, (state, mut skipped): (SyncState, Vec<SkippedFile>)) -> Result<SyncState, SyncError> {
+        skipped.retain(|file| !file.reason.is_deliberate());
+        if self.strict && !skipped.is_empty() {
+            let mut message = format!("{} files could not be synced", skipped.len());
+            for file in &skipped {
+                message.push_str(&format!("\n  {}: {}", file.path.display(), file.reason));
+            }
+            return Err(SyncError::Strict(message));
+        }
+        Ok(state)
+    }
+
+    /// Indexes the root and also returns everything that was left out, with
+    /// the reason. The root's own state directory isn't reported.
+    pub fn index_directory_with_skipped(&self) -> Result<(SyncState, Vec<SkippedFile>), SyncError> {
+        let (state, skipped, _) = self.index(None, None)?;
+        Ok((state, skipped))
+    }
+
+    /// The files the root syncs, relative to it, found by the same rules as
+    /// when indexing but without reading them.
+    pub fn list_files(&self) -> Result<Vec<PathBuf>, SyncError> {
+        let walk = self.walk(None, None)?;
+        let mut files: Vec<PathBuf> = walk.to_hash.into_iter().map(|(_, relative)| relative).collect();
+        files.sort();
+        Ok(files)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn index(
+        &self,
+        previous: Option<&HashMap<PathBuf, FileMetadata>>,
+        quiet: Option<(&HashMap<PathBuf, DirectoryStamp>, &HashSet<PathBuf>)>,
+    ) -> Result<(SyncState, Vec<SkippedFile>, HashMap<PathBuf, DirectoryStamp>), SyncError> {
+        let Walk { unchanged: mut local_files, to_hash, mut skipped, directories: stamps } = self.walk(previous, quiet)?;
+
+        // Reading and hashing dominate on large roots, so they run on all
+        // cores once the walk has found what needs it
+        let hashed: Vec<(PathBuf, Result<FileMetadata, SyncError>)> = to_hash
+            .into_par_iter()
+            .map(|(path, relative)| (relative, self.get_file_metadata(&path)))
+            .collect();
+        for (relative, metadata) in hashed {
+            match metadata {
+                Ok(metadata) => {
+                    local_files.insert(relative, metadata);
+                }
+                Err(e) => skipped.push(SkippedFile {
+                    path: relative,
+                    reason: SkipReason::Unreadable(e.to_string()),
+                }),
+            }
+        }
+
+        if let Some(filter) = &self.filter {
+            local_files = self.apply_filter(filter, local_files, &mut skipped)?;
+        }
+        skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        match previous {
+            Some(previous) => self.assign_versions(&mut local_files, previous),
+            None => self.assign_versions(&mut local_files, &self.stored_files()),
+        }
+
+        Ok((
+            SyncState {
+                local_files,
+                // The root itself has an empty path
+                directories: stamps.keys().filter(|path| !path.as_os_str().is_empty()).cloned().collect(),
+                device_id: self.device_id.clone(),
+                sync_root: self.sync_root.clone(),
+            },
+            skipped,
+            stamps,
+        ))
+    }
+
+    /// Gives each file its version vector: what it had when last indexed
+    /// while its content is the same, or what another device's version had
+    /// once that has been written here, and otherwise one more edit by this
+    /// device than it had.
+    fn assign_versions(&self, files: &mut HashMap<PathBuf, FileMetadata>, known: &HashMap<PathBuf, FileMetadata>) {
+        let mut adopted = self.adopted.lock().unwrap();
+        for (path, metadata) in files.iter_mut() {
+            let known = known.get(path);
+            if let Some(theirs) = adopted.get(path).filter(|theirs| theirs.same_version(metadata)) {
+                metadata.versions = theirs.versions.clone();
+                // Their version may have won over edits made here
+                if let Some(known) = known {
+                    metadata.versions.merge(&known.versions);
+                }
+                continue;
+            }
+            metadata.versions = known.map(|known| known.versions.clone()).unwrap_or_default();
+            if known.is_none_or(|known| !known.same_version(metadata)) {
+                metadata.versions.bump(&self.device_id);
+            }
+        }
+        // Once indexed with their content, the files' own state has them
+        adopted.retain(|path, theirs| known.get(path).is_none_or(|known| !known.same_version(theirs)));
+    }
+
+    /// The files as last saved to the root's index, without creating one.
+    fn stored_files(&self) -> HashMap<PathBuf, FileMetadata> {
+        if !crate::state::dir(&self.sync_root).join(crate::index_store::INDEX_DB_NAME).is_file() {
+            return HashMap::new();
+        }
+        crate::index_store::IndexStore::open(&self.sync_root)
+            .and_then(|store| store.load_state(self.device_id.clone(), self.sync_root.clone()))
+            .map(|state| state.local_files)
+            .unwrap_or_default()
+    }
+
+    /// Notes that `metadata`, another device's version, is being written
+    /// here, so once its content is indexed the file carries its version
+    /// vector rather than counting as an edit by this device.
+    pub fn adopt(&self, metadata: &FileMetadata) {
+        self.adopted.lock().unwrap().insert(metadata.path.clone(), metadata.clone());
+    }
+
+    /// Walks the root. With `quiet`, the stamps of the last walk and the
+    /// paths known to have changed since, files in directories that match
+    /// their stamp keep what `previous` has for them without a stat.
+    fn walk(
+        &self,
+        previous: Option<&HashMap<PathBuf, FileMetadata>>,
+        quiet: Option<(&HashMap<PathBuf, DirectoryStamp>, &HashSet<PathBuf>)>,
+    ) -> Result<Walk, SyncError> {
+        let mut local_files = HashMap::new();
+        let mut skipped = Vec::new();
+        let mut to_hash = Vec::new();
+        let mut directories = HashMap::new();
+        let mut quiet_directories = HashSet::new();
+        let ignore_rules = IgnoreRules::for_root(&self.sync_root, &self.ignore_patterns, self.respect_gitignore)?;
         
-        for entry in WalkDir::new(&self.sync_root)
-            .into_iter()
-            .filter_entry(|e| !Self::is_hidden(e.path()))
-            .filter_map(|e| e.ok())
-        {
+        let mut walker = WalkDir::new(&self.sync_root).into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let path = e.path().unwrap_or(&self.sync_root);
+                    skipped.push(SkippedFile {
+                        path: path.strip_prefix(&self.sync_root).unwrap_or(path).to_path_buf(),
+                        reason: SkipReason::Unreadable(e.to_string()),
+                    });
+                    continue;
+                }
+            };
+            if let Some(reason) = self.excluded_by_rule(&ignore_rules, &entry) {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                let relative = entry.path().strip_prefix(&self.sync_root)?.to_path_buf();
+                if reason != SkipReason::State {
+                    skipped.push(SkippedFile { path: relative, reason });
+                }
+                continue;
+            }
             let path = entry.path();
-            if path.is_file() && self.should_sync_file(path) {
-                if let Ok(metadata) = self.get_file_metadata(path) {
-                    local_files.insert(path.strip_prefix(&self.sync_root)?.to_path_buf(), metadata);
+            let relative = path.strip_prefix(&self.sync_root)?.to_path_buf();
+            if entry.file_type().is_dir() {
+                let Some(stamp) = directory_stamp(path) else {
+                    continue;
+                };
+                if quiet.is_some_and(|(known, _)| known.get(&relative) == Some(&stamp)) {
+                    quiet_directories.insert(relative.clone());
                 }
+                directories.insert(relative, stamp);
+                continue;
+            }
+            let known = previous.and_then(|previous| previous.get(&relative));
+            if let (Some(known), Some((_, changed))) = (known, quiet) {
+                let parent = relative.parent().unwrap_or(Path::new(""));
+                if quiet_directories.contains(parent) && !changed.contains(&relative) {
+                    local_files.insert(relative, known.clone());
+                    continue;
+                }
+            }
+            if !path.is_file() {
+                continue;
+            }
+            if relative.to_str().is_none() {
+                skipped.push(SkippedFile { path: relative, reason: SkipReason::UndecodablePath });
+            } else if !self.should_sync_file(path) {
+                skipped.push(SkippedFile { path: relative, reason: SkipReason::UnsupportedType });
+            } else if let Some((size, limit)) = entry.metadata().ok().and_then(|m| self.file_types.over_limit(m.len())) {
+                skipped.push(SkippedFile { path: relative, reason: SkipReason::TooLarge { size, limit } });
+            } else {
+                if let Some(known) = known.filter(|known| self.is_unchanged(path, known)) {
+                    local_files.insert(relative, known.clone());
+                    continue;
+                }
+                to_hash.push((path.to_path_buf(), relative));
             }
         }
+        Ok(Walk { unchanged: local_files, to_hash, skipped, directories })
+    }
 
-        Ok(SyncState {
-            local_files,
-            device_id: self.device_id.clone(),
-            sync_root: self.sync_root.clone(),
-        })
+    fn apply_filter(
+        &self,
+        filter: &FilterCommand,
+        local_files: HashMap<PathBuf, FileMetadata>,
+        skipped: &mut Vec<SkippedFile>,
+    ) -> Result<HashMap<PathBuf, FileMetadata>, SyncError> {
+        let mut candidates: Vec<&FileMetadata> = local_files.values().collect();
+        candidates.sort_by(|a, b| a.path.cmp(&b.path));
+        let decisions = filter.evaluate(&candidates)?;
+
+        let mut filtered = HashMap::new();
+        let mut overrides = HashMap::new();
+        for (metadata, decision) in candidates.into_iter().zip(decisions) {
+            let published = match decision {
+                FilterDecision::Allow => metadata.clone(),
+                FilterDecision::Deny => {
+                    skipped.push(SkippedFile {
+                        path: metadata.path.clone(),
+                        reason: SkipReason::Filtered,
+                    });
+                    continue;
+                }
+                // Published paths are written on other devices as they are
+                FilterDecision::Transform { path } if !crate::types::is_contained(&path) => {
+                    return Err(SyncError::Filter(format!(
+                        "'{}' published {} as {}, which is not a path inside the root",
+                        filter.command(),
+                        metadata.path.display(),
+                        path.display()
+                    )));
+                }
+                FilterDecision::Transform { path } => {
+                    overrides.insert(path.clone(), metadata.path.clone());
+                    FileMetadata { path, ..metadata.clone() }
+                }
+            };
+            if let Some(other) = filtered.insert(published.path.clone(), published) {
+                return Err(SyncError::Filter(format!(
+                    "'{}' published two files as {}",
+                    filter.command(),
+                    other.path.display()
+                )));
+            }
+        }
+
+        *self.path_overrides.lock().unwrap() = overrides;
+        Ok(filtered)
+    }
+
+    /// Where `relative_path` is under the root, refusing paths another
+    /// device could use to reach outside it or into syncmd's own state.
+    fn contained(&self, relative_path: &Path) -> Result<PathBuf, SyncError> {
+        if crate::state::is_state_path(relative_path) {
+            return Err(SyncError::PermissionDenied(format!("{} is syncmd's own state", relative_path.display())));
+        }
+        match crate::types::is_contained(relative_path) {
+            true => Ok(self.sync_root.join(relative_path)),
+            false => Err(SyncError::PermissionDenied(format!("{} is outside the sync root", relative_path.display()))),
+        }
+    }
+
+    /// Maps a published relative path back to the file on disk, undoing any
+    /// path transform applied by the filter command.
+    pub fn local_path(&self, relative_path: &Path) -> PathBuf {
+        let overrides = self.path_overrides.lock().unwrap();
+        let local = overrides.get(relative_path).map(PathBuf::as_path).unwrap_or(relative_path);
+        self.sync_root.join(local)
+    }
+
+    /// Whether a stat of `path` matches what was indexed, to the millisecond
+    /// the index keeps, permissions included since changing them leaves
+    /// the modification time. Sizes are only compared without transforms,
+    /// which change the indexed size.
+    fn is_unchanged(&self, path: &Path, known: &FileMetadata) -> bool {
+        let Ok(metadata) = fs::metadata(path) else {
+            return false;
+        };
+        metadata.modified().is_ok_and(|modified| crate::types::Timestamp::from(modified) == known.modified)
+            && (!self.transforms.is_empty() || metadata.len() == known.size)
+            && FilePermissions::of(&metadata).matches(&known.permissions)
     }
 
     fn get_file_metadata(&self, path: &Path) -> Result<FileMetadata, SyncError> {
         let metadata = fs::metadata(path)?;
-        let content = fs::read(path)?;
-        let file_hash = hash(&content);
         let relative_path = path.strip_prefix(&self.sync_root)?.to_path_buf();
+        let content = self.transform_content(&relative_path, fs::read(path)?);
+        let file_hash = hash(&content);
 
         Ok(FileMetadata {
             path: relative_path,
             hash: file_hash.to_hex().to_string(),
-            size: metadata.len(),
-            modified: metadata.modified()?,
-            created: metadata.created()?,
+            size: content.len() as u64,
+            modified: metadata.modified()?.into(),
+            created: metadata.created()?.into(),
             version: metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH)?.as_secs(),
             device_id: self.device_id.clone(),
+            versions: Default::default(),
+            permissions: FilePermissions::of(&metadata),
         })
     }
 
+    fn excluded_by_rule(&self, ignore_rules: &[IgnoreRules], entry: &walkdir::DirEntry) -> Option<SkipReason> {
+        let relative = entry.path().strip_prefix(&self.sync_root).ok()?;
+        if relative.as_os_str().is_empty() {
+            return None;
+        }
+        if entry.file_type().is_dir() && crate::state::is_state_dir(entry.path()) {
+            return Some(SkipReason::State);
+        }
+        if is_vcs_dir(entry.file_name()) {
+            if !self.sync_vcs_dirs {
+                return Some(SkipReason::VersionControl);
+            }
+        } else if Self::is_hidden(entry.path()) {
+            return Some(SkipReason::Hidden);
+        }
+        let selected = if entry.file_type().is_dir() {
+            self.selection.may_contain(relative)
+        } else {
+            self.selection.includes(relative)
+        };
+        if !selected {
+            return Some(SkipReason::NotSelected);
+        }
+        ignore_rules
+            .iter()
+            .find_map(|rules| rules.matching_rule(relative, entry.file_type().is_dir()))
+            .map(|rule| SkipReason::Ignored {
+                source: rule.source.clone(),
+                line: rule.line,
+                pattern: rule.pattern.clone(),
+            })
+    }
+
+    /// Why a root-relative path is not synced, or `None` if it is. The path
+    /// may also be inside an excluded directory.
+    pub fn explain(&self, relative_path: &Path) -> Result<Option<SkipReason>, SyncError> {
+        let (state, skipped) = self.index_directory_with_skipped()?;
+        if let Some(file) = skipped.iter().find(|file| relative_path.starts_with(&file.path)) {
+            return Ok(Some(file.reason.clone()));
+        }
+        let published = state.local_files.contains_key(relative_path)
+            || self.path_overrides.lock().unwrap().values().any(|local| local == relative_path);
+        if published {
+            Ok(None)
+        } else {
+            Err(SyncError::NotFound(self.sync_root.join(relative_path)))
+        }
+    }
+
+    /// Runs the configured transform pipeline, producing the bytes that are
+    /// hashed and uploaded for this file.
+    fn transform_content(&self, relative_path: &Path, content: Vec<u8>) -> Vec<u8> {
+        if self.transforms.is_empty() {
+            return content;
+        }
+        let category = self.get_file_category(relative_path);
+        self.transforms.apply(relative_path, &category, content)
+    }
+
     fn is_hidden(path: &Path) -> bool {
         path.file_name()
             .and_then(|name| name.to_str())
@@ -73,53 +634,10 @@ impl FileIndexer {
     }
 
     fn should_sync_file(&self, path: &Path) -> bool {
-        if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
-            match ext.to_lowercase().as_str() {
-                // Markdown files
-                "md" | "markdown" | "mdown" | "mkdn" | "mkd" | "mdwn" | "mdtxt" | "mdtext" | "text" => true,
-                // Image files
-                "jpg" | "jpeg" | "png" | "gif" | "svg" | "webp" | "bmp" | "ico" | "tiff" | "tif" => true,
-                // Code files
-                "rs" | "py" | "js" | "ts" | "jsx" | "tsx" | "html" | "css" | "scss" | "json" | "yaml" | "yml" | "toml" | "xml" => true,
-                // Configuration files
-                "ini" | "cfg" | "conf" | "config" | "env" | "env.example" => true,
-                // Documentation files
-                "txt" | "rtf" | "doc" | "docx" | "pdf" => true,
-                // Data files
-                "csv" | "tsv" | "jsonl" => true,
-                _ => false,
-            }
-        } else {
-            // Check if it's a dotfile that should be synced
-            self.should_sync_dotfile(path)
-        }
-    }
-
-    fn should_sync_dotfile(&self, path: &Path) -> bool {
-        let file_name = path.file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("");
-        
-        match file_name {
-            // Common dotfiles to sync
-            ".gitignore" | ".gitattributes" | ".editorconfig" | ".env.example" => true,
-            // Project configuration files
-            ".eslintrc" | ".eslintrc.json" | ".eslintrc.js" | ".eslintrc.yml" => true,
-            ".prettierrc" | ".prettierrc.json" | ".prettierrc.js" | ".prettierrc.yml" => true,
-            ".babelrc" | ".babelrc.json" | ".babelrc.js" => true,
-            ".vscode" | ".vscodeignore" => true, // Directory
-            // Package manager files
-            "package.json" | "package-lock.json" | "yarn.lock" | "pnpm-lock.yaml" => true,
-            // Build configuration
-            "Cargo.toml" | "Cargo.lock" | "go.mod" | "go.sum" | "pom.xml" | "build.gradle" => true,
-            // Python project files
-            "requirements.txt" | "pyproject.toml" | "setup.py" | "Pipfile" | "poetry.lock" => true,
-            // Node.js project files
-            "tsconfig.json" | "webpack.config.js" | "vite.config.js" | "next.config.js" => true,
-            // Other common project files
-            "README" | "README.md" | "LICENSE" | "CHANGELOG.md" | "CONTRIBUTING.md" => true,
-            _ => false,
+        if !self.categories.is_empty() && !self.categories.contains(&self.get_file_category(path)) {
+            return false;
         }
+        self.file_types.accepts_type(path)
     }
 
     fn is_image_file(path: &Path) -> bool {
@@ -135,55 +653,160 @@ impl FileIndexer {
         Ok(hash.to_hex().to_string())
     }
 
+    /// A file's metadata as it is on disk now, with the version vector the
+    /// next index would give it.
+    pub fn file_metadata(&self, relative_path: &Path) -> Result<FileMetadata, SyncError> {
+        self.contained(relative_path)?;
+        let mut metadata = self.get_file_metadata(&self.local_path(relative_path))?;
+        metadata.path = relative_path.to_path_buf();
+        let known = self.stored_files().remove(relative_path);
+        metadata.versions = known.as_ref().map(|known| known.versions.clone()).unwrap_or_default();
+        if known.is_none_or(|known| !known.same_version(&metadata)) {
+            metadata.versions.bump(&self.device_id);
+        }
+        Ok(metadata)
+    }
+
     pub fn read_file_content(&self, relative_path: &Path) -> Result<Vec<u8>, SyncError> {
-        let full_path = self.sync_root.join(relative_path);
-        Ok(fs::read(full_path)?)
+        let full_path = self.local_path(relative_path);
+        let local_relative = full_path.strip_prefix(&self.sync_root)?.to_path_buf();
+        Ok(self.transform_content(&local_relative, fs::read(&full_path)?))
     }
 
+    /// Writes a file in one step, or stages it while a batch is open.
     pub fn write_file_content(&self, relative_path: &Path, content: &[u8]) -> Result<(), SyncError> {
-        let full_path = self.sync_root.join(relative_path);
-        if let Some(parent) = full_path.parent() {
+        self.write(relative_path, content, None)
+    }
+
+    /// Writes another device's version of a file, with the permissions it
+    /// has there.
+    pub fn write_received(&self, metadata: &FileMetadata, content: &[u8]) -> Result<(), SyncError> {
+        self.write(&metadata.path, content, Some(&metadata.permissions))
+    }
+
+    fn write(&self, relative_path: &Path, content: &[u8], permissions: Option<&FilePermissions>) -> Result<(), SyncError> {
+        self.contained(relative_path)?;
+        let local_relative = self.local_path(relative_path).strip_prefix(&self.sync_root)?.to_path_buf();
+        match self.batch.lock().unwrap().as_mut() {
+            Some(batch) => batch.stage_write(&local_relative, content, permissions),
+            None => apply_journal::write_atomic(&self.sync_root, &local_relative, content, permissions),
+        }
+    }
+
+    /// Whether the file at `relative_path` has the content another device
+    /// hashed as `expected`, so it can be moved rather than downloaded.
+    pub fn holds(&self, relative_path: &Path, expected: &str) -> Result<bool, SyncError> {
+        self.contained(relative_path)?;
+        if !self.local_path(relative_path).is_file() {
+            return Ok(false);
+        }
+        let content = self.read_file_content(relative_path)?;
+        Ok(hash(&content).to_hex().as_str() == expected)
+    }
+
+    /// Moves a file within the root, creating the target's directories.
+    pub fn rename_file(&self, from: &Path, to: &Path) -> Result<(), SyncError> {
+        self.contained(from)?;
+        self.contained(to)?;
+        let target = self.local_path(to);
+        if let Some(batch) = self.batch.lock().unwrap().as_mut() {
+            let from = self.local_path(from).strip_prefix(&self.sync_root)?.to_path_buf();
+            batch.stage_rename(&from, target.strip_prefix(&self.sync_root)?);
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
             fs::create_dir_all(parent)?;
         }
-        Ok(fs::write(full_path, content)?)
+        Ok(fs::rename(self.local_path(from), target)?)
+    }
+
+    /// Creates a directory another device has, with its parents, or stages
+    /// it while a batch is open.
+    pub fn create_directory(&self, relative_path: &Path) -> Result<(), SyncError> {
+        let full_path = self.contained(relative_path)?;
+        if let Some(batch) = self.batch.lock().unwrap().as_mut() {
+            batch.stage_mkdir(relative_path);
+            return Ok(());
+        }
+        Ok(fs::create_dir_all(full_path)?)
+    }
+
+    /// Removes a directory another device removed, unless something is
+    /// still in it here. Returns whether it is gone.
+    pub fn remove_directory(&self, relative_path: &Path) -> Result<bool, SyncError> {
+        let full_path = self.contained(relative_path)?;
+        match fs::read_dir(&full_path).map(|mut entries| entries.next().is_none()) {
+            Ok(false) => Ok(false),
+            Ok(true) => fs::remove_dir(&full_path).map(|_| true).map_err(Into::into),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e.into()),
+        }
     }
 
     pub fn delete_file(&self, relative_path: &Path) -> Result<(), SyncError> {
-        let full_path = self.sync_root.join(relative_path);
+        self.contained(relative_path)?;
+        let full_path = self.local_path(relative_path);
         Ok(fs::remove_file(full_path)?)
     }
 
+    /// Moves a file deleted on another device into the root's trash, and
+    /// drops what has been in there longer than the retention.
+    pub fn trash_file(&self, relative_path: &Path) -> Result<(), SyncError> {
+        self.contained(relative_path)?;
+        let full_path = self.local_path(relative_path);
+        if let Some(batch) = self.batch.lock().unwrap().as_mut() {
+            batch.stage_trash(full_path.strip_prefix(&self.sync_root)?);
+            return Ok(());
+        }
+        let trash = crate::trash::Trash::new(&self.sync_root);
+        trash.put(full_path.strip_prefix(&self.sync_root)?)?;
+        trash.expire(self.trash_retention)?;
+        Ok(())
+    }
+
+    /// Starts staging writes, renames, deletions and new directories to
+    /// apply them together with `commit_apply`. A batch a crash interrupted
+    /// is recovered first.
+    pub fn begin_apply(&self) -> Result<Option<Recovery>, SyncError> {
+        let (batch, recovery) = ApplyBatch::begin(&self.sync_root)?;
+        if let Some(previous) = self.batch.lock().unwrap().replace(batch) {
+            previous.abort()?;
+        }
+        Ok(recovery)
+    }
+
+    /// Applies everything staged since `begin_apply`, returning how many
+    /// operations that was.
+    pub fn commit_apply(&self) -> Result<usize, SyncError> {
+        let Some(batch) = self.batch.lock().unwrap().take() else {
+            return Ok(0);
+        };
+        let count = batch.commit()?;
+        crate::trash::Trash::new(&self.sync_root).expire(self.trash_retention)?;
+        Ok(count)
+    }
+
+    /// Drops everything staged since `begin_apply`.
+    pub fn abort_apply(&self) -> Result<(), SyncError> {
+        match self.batch.lock().unwrap().take() {
+            Some(batch) => batch.abort(),
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes or rolls back a batch a crash interrupted.
+    pub fn recover_apply(&self) -> Result<Option<Recovery>, SyncError> {
+        apply_journal::recover(&self.sync_root)
+    }
+
     pub fn get_file_changes(&self, old_state: &SyncState) -> Vec<crate::types::SyncOperation> {
-        let mut operations = Vec::new();
-        
         // Get current state
         let current_state = match self.index_directory() {
             Ok(state) => state,
-            Err(_) => return operations,
+            Err(_) => return Vec::new(),
         };
         
-        // Find new or modified files
-        for (path, current_meta) in &current_state.local_files {
-            match old_state.local_files.get(path) {
-                Some(old_meta) => {
-                    if current_meta.hash != old_meta.hash {
-                        operations.push(crate::types::SyncOperation::Update(current_meta.clone()));
-                    }
-                }
-                None => {
-                    operations.push(crate::types::SyncOperation::Add(current_meta.clone()));
-                }
-            }
-        }
-        
-        // Find deleted files
-        for path in old_state.local_files.keys() {
-            if !current_state.local_files.contains_key(path) {
-                operations.push(crate::types::SyncOperation::Delete(path.clone()));
-            }
-        }
-        
-        operations
+        diff_states(&old_state.local_files, &current_state.local_files)
     }
 
     pub fn is_text_file(&self, path: &Path) -> bool {
@@ -196,7 +819,7 @@ impl FileIndexer {
     }
 
     pub fn get_file_size(&self, relative_path: &Path) -> Result<u64, SyncError> {
-        let full_path = self.sync_root.join(relative_path);
+        let full_path = self.local_path(relative_path);
         Ok(fs::metadata(full_path)?.len())
     }
 
@@ -244,7 +867,7 @@ impl FileIndexer {
         };
 
         // Try to detect if file is binary
-        if content.iter().any(|&b| b == 0) {
+        if content.contains(&0) {
             analysis.is_binary = true;
             return Ok(analysis);
         }
@@ -315,4 +938,247 @@ impl FileIndexer {
         
         changes
     }
-}
\ No newline at end of file
+}
+/// Operations turning `old` into `new`, with delete and add pairs of the
+/// same content folded into renames.
+fn diff_states(
+    old: &HashMap<PathBuf, FileMetadata>,
+    new: &HashMap<PathBuf, FileMetadata>,
+) -> Vec<crate::types::SyncOperation> {
+    use crate::types::SyncOperation;
+
+    let mut operations = Vec::new();
+    for (path, current) in new {
+        match old.get(path) {
+            Some(previous) if !previous.same_version(current) => operations.push(SyncOperation::Update(current.clone())),
+            Some(_) => {}
+            None => operations.push(SyncOperation::Add(current.clone())),
+        }
+    }
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        operations.push(SyncOperation::Delete(path.clone()));
+    }
+    crate::sync::detect_renames(operations, old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SyncOperation;
+
+    #[test]
+    fn test_reconcile_offline_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::write(root.join("kept.md"), "# Kept").unwrap();
+        fs::write(root.join("edited.md"), "# Draft").unwrap();
+        fs::write(root.join("moved.md"), "# Moved").unwrap();
+        let indexer = FileIndexer::new("device".to_string(), root.clone());
+        let persisted = indexer.index_directory().unwrap();
+
+        // While syncmd wasn't running
+        fs::write(root.join("edited.md"), "# Final version").unwrap();
+        fs::rename(root.join("moved.md"), root.join("renamed.md")).unwrap();
+        fs::write(root.join("new.md"), "# New").unwrap();
+
+        let (current, mut operations) = indexer.reconcile(&persisted).unwrap();
+        assert_eq!(current.local_files.len(), 4);
+        operations.sort_by_key(|operation| format!("{:?}", operation));
+        assert!(matches!(&operations[..], [
+            SyncOperation::Add(added),
+            SyncOperation::Rename { from, to },
+            SyncOperation::Update(updated),
+        ] if added.path == Path::new("new.md")
+            && from == Path::new("moved.md")
+            && to.path == Path::new("renamed.md")
+            && updated.path == Path::new("edited.md")));
+
+        // Unchanged files keep the indexed metadata without being read
+        let mut stale = persisted.clone();
+        stale.local_files.get_mut(Path::new("kept.md")).unwrap().hash = "stale".to_string();
+        let current = indexer.index_directory_from(&stale).unwrap();
+        assert_eq!(current.local_files[Path::new("kept.md")].hash, "stale");
+    }
+
+    #[test]
+    fn test_version_vectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::write(root.join("a.md"), "# One").unwrap();
+        let indexer = FileIndexer::new("laptop".to_string(), root.clone());
+        let versions = |state: &SyncState| state.local_files[Path::new("a.md")].versions.clone();
+        let first = indexer.index_directory().unwrap();
+        assert_eq!(versions(&first).get("laptop"), 1);
+        crate::index_store::IndexStore::open(&root).unwrap().save_state(&first).unwrap();
+
+        // An edit counts once however often it's indexed before it's saved
+        fs::write(root.join("a.md"), "# Two").unwrap();
+        assert_eq!(versions(&indexer.index_directory().unwrap()).get("laptop"), 2);
+        let edited = indexer.index_directory().unwrap();
+        assert_eq!(versions(&edited).get("laptop"), 2);
+
+        // Another device's version written here keeps its vector
+        let mut theirs = edited.local_files[Path::new("a.md")].clone();
+        theirs.hash = hash(b"# Three").to_hex().to_string();
+        theirs.versions.bump("phone");
+        indexer.adopt(&theirs);
+        fs::write(root.join("a.md"), "# Three").unwrap();
+        let adopted = indexer.index_directory_from(&edited).unwrap();
+        assert_eq!(versions(&adopted), theirs.versions);
+        assert_eq!(versions(&indexer.index_directory_from(&adopted).unwrap()), theirs.versions);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permission_changes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::write(root.join("run.md"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("run.md"), fs::Permissions::from_mode(0o644)).unwrap();
+        let indexer = FileIndexer::new("laptop".to_string(), root.clone());
+        let first = indexer.index_directory().unwrap();
+        let mut store = crate::index_store::IndexStore::open(&root).unwrap();
+        store.save_state(&first).unwrap();
+        let persisted = store.load_state("laptop".to_string(), root.clone()).unwrap();
+        assert_eq!(persisted.local_files[Path::new("run.md")].permissions.mode, Some(0o644));
+
+        // A chmod alone is an edit, though the modification time stays
+        fs::set_permissions(root.join("run.md"), fs::Permissions::from_mode(0o755)).unwrap();
+        let (current, operations) = indexer.reconcile(&persisted).unwrap();
+        assert!(matches!(&operations[..], [SyncOperation::Update(updated)] if updated.permissions.mode == Some(0o755)));
+        assert_eq!(current.local_files[Path::new("run.md")].versions.get("laptop"), 2);
+
+        // Setuid and the like aren't taken from another device
+        let mut theirs = current.local_files[Path::new("run.md")].clone();
+        theirs.permissions.mode = Some(0o4755);
+        indexer.write_received(&theirs, b"#!/bin/sh\n").unwrap();
+        assert_eq!(fs::metadata(root.join("run.md")).unwrap().permissions().mode() & 0o7777, 0o755);
+    }
+
+    #[test]
+    fn test_quiet_directories_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir_all(root.join("archive")).unwrap();
+        fs::write(root.join("today.md"), "# Today").unwrap();
+        fs::write(root.join("archive/old.md"), "# Old").unwrap();
+        let indexer = FileIndexer::new("device".to_string(), root.clone());
+        let none = HashSet::new();
+        let (state, stamps) = indexer.index_directory_since(&indexer.index_directory().unwrap(), None, &none).unwrap();
+        assert_eq!(stamps[Path::new("archive")].entries, 1);
+
+        // Edited in place, which leaves the directory's stamp alone
+        fs::write(root.join("archive/old.md"), "# Old, edited").unwrap();
+        let (quick, stamps) = indexer.index_directory_since(&state, Some(&stamps), &none).unwrap();
+        assert_eq!(quick.local_files[Path::new("archive/old.md")].hash, state.local_files[Path::new("archive/old.md")].hash);
+        // ...until a watcher reports it
+        let reported = HashSet::from([root.join("archive/old.md")]);
+        let (watched, stamps) = indexer.index_directory_since(&state, Some(&stamps), &reported).unwrap();
+        assert_ne!(watched.local_files[Path::new("archive/old.md")].hash, state.local_files[Path::new("archive/old.md")].hash);
+
+        // A new file changes the directory
+        fs::write(root.join("archive/new.md"), "# New").unwrap();
+        let (quick, _) = indexer.index_directory_since(&watched, Some(&stamps), &none).unwrap();
+        assert_eq!(quick.local_files.len(), 3);
+
+        let mut store = crate::index_store::IndexStore::open(&root).unwrap();
+        store.save_directories(&stamps).unwrap();
+        assert_eq!(store.load_directories().unwrap(), stamps);
+    }
+
+    #[test]
+    fn test_list_nested_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir_all(root.join("projects/2024")).unwrap();
+        fs::create_dir_all(root.join(crate::index_store::STATE_DIR_NAME).join("quarantine")).unwrap();
+        fs::write(root.join("top.md"), "# Top").unwrap();
+        fs::write(root.join("projects/2024/plan.md"), "# Plan").unwrap();
+        fs::write(root.join(crate::index_store::STATE_DIR_NAME).join("quarantine/bad.md"), "x").unwrap();
+        let indexer = FileIndexer::new("device".to_string(), root);
+        assert_eq!(indexer.list_files().unwrap(), vec![PathBuf::from("projects/2024/plan.md"), PathBuf::from("top.md")]);
+    }
+
+    #[test]
+    fn test_paths_outside_root_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(&root).unwrap();
+        let indexer = FileIndexer::new("device".to_string(), root.clone());
+        assert!(indexer.create_directory(Path::new("../outside")).is_err());
+        assert!(indexer.write_file_content(Path::new("notes/../../.bashrc"), b"x").is_err());
+        assert!(indexer.remove_directory(&dir.path().join("root")).is_err());
+        fs::write(dir.path().join("secret"), "key").unwrap();
+        assert!(indexer.rename_file(Path::new("../secret"), Path::new("stolen")).is_err());
+        assert!(indexer.holds(Path::new("../secret"), "").is_err());
+        assert!(indexer.trash_file(Path::new("../secret")).is_err());
+        assert!(indexer.delete_file(Path::new("../secret")).is_err());
+        // A remote's file can't land in the root's own state
+        let state_file = crate::types::FileMetadata::for_test(".syncmd/state.db", b"x");
+        assert!(indexer.write_received(&state_file, b"x").is_err());
+        assert!(!root.join(".syncmd/state.db").exists());
+        assert!(dir.path().join("secret").exists() && !root.join("stolen").exists());
+        assert!(!dir.path().join("outside").exists() && !dir.path().join(".bashrc").exists());
+        indexer.create_directory(Path::new("notes/old")).unwrap();
+        assert!(root.join("notes/old").is_dir());
+    }
+
+    #[test]
+    fn test_file_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::write(root.join("note.md"), "# Note").unwrap();
+        fs::write(root.join("memo.m4a"), [0u8; 64]).unwrap();
+        fs::write(root.join("scan.pdf"), [0u8; 2048]).unwrap();
+
+        let (state, skipped) = FileIndexer::new("device".to_string(), root.clone()).index_directory_with_skipped().unwrap();
+        assert_eq!(state.local_files.len(), 2);
+        assert_eq!(skipped[0].reason, SkipReason::UnsupportedType);
+
+        let indexer = FileIndexer::new("device".to_string(), root).with_file_types(FileTypeFilter {
+            sync_all_files: true,
+            max_file_size: Some(1024),
+        });
+        let (state, skipped) = indexer.index_directory_with_skipped().unwrap();
+        let mut synced: Vec<&Path> = state.local_files.keys().map(|path| path.as_path()).collect();
+        synced.sort();
+        assert_eq!(synced, [Path::new("memo.m4a"), Path::new("note.md")]);
+        assert_eq!(skipped[0].path, Path::new("scan.pdf"));
+        assert_eq!(skipped[0].reason, SkipReason::TooLarge { size: 2048, limit: 1024 });
+        assert!(skipped[0].reason.is_deliberate());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_filter_decisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        for name in ["a.md", "b.md", "c.md"] {
+            fs::write(root.join(name), format!("# {}", name)).unwrap();
+        }
+        // Candidates come sorted by path, one decision each
+        let filtered = |decisions: &[&str]| {
+            let command = format!("cat >/dev/null; printf '%s\\n' {}", decisions.iter().map(|d| format!("'{}'", d)).collect::<Vec<_>>().join(" "));
+            let indexer = FileIndexer::new("device".to_string(), root.clone()).with_filter(Some(FilterCommand::new(command)));
+            indexer.index_directory_with_skipped().map(|(state, skipped)| (indexer, state, skipped))
+        };
+        let transform = |path: &str| format!(r#"{{"decision":"transform","path":"{}"}}"#, path);
+
+        let archived = transform("archive/c.md");
+        let (indexer, state, skipped) = filtered(&["allow", "deny", &archived]).unwrap();
+        let mut published: Vec<&Path> = state.local_files.keys().map(PathBuf::as_path).collect();
+        published.sort();
+        assert_eq!(published, [Path::new("a.md"), Path::new("archive/c.md")]);
+        assert_eq!(state.local_files[Path::new("archive/c.md")].hash, hash(b"# c.md").to_hex().to_string());
+        assert_eq!(indexer.local_path(Path::new("archive/c.md")), root.join("c.md"));
+        assert!(matches!(&skipped[..], [SkippedFile { path, reason: SkipReason::Filtered }] if path == Path::new("b.md")));
+
+        // Paths that leave the root, and two files published as one, fail
+        for invalid in [transform("../outside.md"), transform("/etc/c.md"), transform("a.md")] {
+            let error = filtered(&["allow", "deny", &invalid]).err().expect(&invalid);
+            assert!(matches!(error, SyncError::Filter(_)), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+#![allow(dead_code)]
+
+//! Replication of the server's change journal to clients, for history
+//! across all devices. Entries go out in batches that only carry what
+//! changes from one entry to the next: sequence gaps, milliseconds since the
+//! previous entry, the part of the path that differs from the previous path,
+//! and devices as indexes into a table sent once per batch. No file content
+//! is involved.
+
+use crate::index_store::{JournalEntry, JournalOp};
+use crate::types::SyncError;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Most entries the server sends in one batch
+pub const MAX_BATCH: usize = 1000;
+
+fn is_one(value: &u64) -> bool {
+    *value == 1
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn one() -> u64 {
+    1
+}
+
+/// One journal entry relative to the one before it in the batch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeltaEntry {
+    /// Distance to the previous sequence number
+    #[serde(rename = "s", default = "one", skip_serializing_if = "is_one")]
+    pub seq_gap: u64,
+    #[serde(rename = "o")]
+    pub op: JournalOp,
+    /// Leading bytes shared with the previous path
+    #[serde(rename = "k", default, skip_serializing_if = "is_zero")]
+    pub kept: usize,
+    /// The rest of the path
+    #[serde(rename = "p")]
+    pub suffix: String,
+    #[serde(rename = "h", default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Index into the batch's devices
+    #[serde(rename = "d", default, skip_serializing_if = "is_zero")]
+    pub device: usize,
+    /// Milliseconds after the previous entry
+    #[serde(rename = "t", default)]
+    pub elapsed: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalBatch {
+    /// Sequence number the first entry's gap counts from. It is past the
+    /// requested one when older entries were compacted away on the server.
+    pub after: u64,
+    pub devices: Vec<String>,
+    /// Time the first entry's `elapsed` counts from
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub entries: Vec<DeltaEntry>,
+    /// Last sequence number the batch covers, to ask for the next batch
+    /// after. Entries outside the client's subscriptions are left out, so
+    /// it can be past the last entry.
+    pub through: u64,
+    /// Whether the server has entries past this batch
+    pub more: bool,
+}
+
+impl JournalBatch {
+    /// Encodes `entries`, in sequence order, as following on from `after`
+    /// and covering the journal up to `through`.
+    pub fn encode(after: u64, through: u64, entries: &[JournalEntry], more: bool) -> Self {
+        let start = entries.first().map(|entry| entry.timestamp).unwrap_or_else(chrono::Utc::now);
+        let mut devices: Vec<String> = Vec::new();
+        let mut previous_seq = after;
+        let mut previous_time = start;
+        let mut previous_path = String::new();
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                let device = match devices.iter().position(|device| *device == entry.device_id) {
+                    Some(index) => index,
+                    None => {
+                        devices.push(entry.device_id.clone());
+                        devices.len() - 1
+                    }
+                };
+                let path = entry.path.to_string_lossy().to_string();
+                let kept = shared_prefix(&previous_path, &path);
+                let delta = DeltaEntry {
+                    seq_gap: entry.seq - previous_seq,
+                    op: entry.op,
+                    kept,
+                    suffix: path[kept..].to_string(),
+                    hash: entry.hash.clone(),
+                    device,
+                    elapsed: (entry.timestamp - previous_time).num_milliseconds(),
+                };
+                previous_seq = entry.seq;
+                // From the time as decoded, so rounding doesn't add up
+                previous_time += chrono::Duration::milliseconds(delta.elapsed);
+                previous_path = path;
+                delta
+            })
+            .collect();
+        Self { after, devices, start, entries, through, more }
+    }
+
+    pub fn decode(&self) -> Result<Vec<JournalEntry>, SyncError> {
+        let invalid = |reason: &str| SyncError::Network(format!("Invalid journal batch: {}", reason));
+        let mut seq = self.after;
+        let mut timestamp = self.start;
+        let mut path = String::new();
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for delta in &self.entries {
+            if delta.seq_gap == 0 {
+                return Err(invalid("sequence numbers must increase"));
+            }
+            seq += delta.seq_gap;
+            timestamp += chrono::Duration::milliseconds(delta.elapsed);
+            let kept = path.get(..delta.kept).ok_or_else(|| invalid("path prefix out of range"))?;
+            path = format!("{}{}", kept, delta.suffix);
+            let device_id = self.devices.get(delta.device).ok_or_else(|| invalid("unknown device"))?;
+            entries.push(JournalEntry {
+                seq,
+                op: delta.op,
+                path: PathBuf::from(&path),
+                hash: delta.hash.clone(),
+                device_id: device_id.clone(),
+                timestamp,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Length in bytes of the common start of `a` and `b`, on a character
+/// boundary.
+fn shared_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map(|((index, _), _)| index)
+        .unwrap_or_else(|| a.len().min(b.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_round_trip() {
+        let start = chrono::Utc::now();
+        let entry = |seq, op, path: &str, device: &str, seconds| JournalEntry {
+            seq,
+            op,
+            path: PathBuf::from(path),
+            hash: (op != JournalOp::Delete).then(|| format!("hash{}", seq)),
+            device_id: device.to_string(),
+            timestamp: start + chrono::Duration::seconds(seconds),
+        };
+        let entries = vec![
+            entry(5, JournalOp::Add, "notes/2024/jan.md", "laptop", 0),
+            entry(6, JournalOp::Update, "notes/2024/feb.md", "laptop", 3),
+            entry(9, JournalOp::Delete, "notes/ümlaut.md", "phone", 60),
+            entry(10, JournalOp::Update, "notes/übung.md", "laptop", 61),
+        ];
+        let batch = JournalBatch::encode(4, 10, &entries, false);
+        assert_eq!(batch.devices, vec!["laptop", "phone"]);
+        assert_eq!(batch.entries[1].kept, "notes/2024/".len());
+        assert_eq!(batch.entries[1].suffix, "feb.md");
+        assert_eq!(batch.entries[3].kept, "notes/".len() + 'ü'.len_utf8());
+
+        let decoded = batch.decode().unwrap();
+        for (decoded, entry) in decoded.iter().zip(&entries) {
+            assert_eq!(decoded.seq, entry.seq);
+            assert_eq!(decoded.op, entry.op);
+            assert_eq!(decoded.path, entry.path);
+            assert_eq!(decoded.hash, entry.hash);
+            assert_eq!(decoded.device_id, entry.device_id);
+            assert_eq!(decoded.timestamp.timestamp_millis(), entry.timestamp.timestamp_millis());
+        }
+
+        // Repeated defaults are left out on the wire
+        let json = serde_json::to_string(&batch.entries[1]).unwrap();
+        assert!(!json.contains("\"s\"") && !json.contains("\"d\""), "{}", json);
+
+        let mut broken = batch.clone();
+        broken.entries[1].kept = 100;
+        assert!(broken.decode().is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! The sync engine behind the `syncmd` binaries, for embedding folder sync
+//! in other applications. [`SyncSession`] syncs a folder with a server or
+//! peer in a few calls, once or on every change a [`ChangeSource`] reports;
+//! the modules below it are the pieces the binaries are built from:
+//! [`FileIndexer`] scans a root, [`SyncEngine`] decides what to apply,
+//! [`FileWatcher`] watches it for changes, and [`FileTransferManager`] moves
+//! large files in chunks.
+
+pub mod types;
+pub mod indexer;
+pub mod apply_journal;
+pub mod sync;
+pub mod network;
+pub mod cli;
+pub mod server_config;
+pub mod config_edit;
+pub mod file_transfer;
+pub mod security;
+pub mod secrets;
+pub mod ignore;
+pub mod filter;
+pub mod transform;
+pub mod index_store;
+pub mod import;
+pub mod export;
+pub mod remote;
+pub mod plan;
+pub mod discovery;
+pub mod peer_registry;
+pub mod overlay;
+pub mod templates;
+pub mod style;
+pub mod text_diff;
+pub mod sections;
+pub mod merge_drivers;
+pub mod share_links;
+pub mod bandwidth;
+pub mod chaos;
+pub mod websocket;
+pub mod protocol_trace;
+pub mod journal_replication;
+pub mod wire;
+pub mod state;
+pub mod capabilities;
+pub mod search;
+pub mod encryption;
+pub mod maintenance;
+pub mod trash;
+pub mod conflicts;
+pub mod exit_codes;
+pub mod merkle;
+pub mod compression;
+pub mod telemetry;
+pub mod schedule;
+pub mod migrate;
+pub mod session;
+pub mod watcher;
+pub mod watch_limits;
+pub mod open_files;
+
+pub use file_transfer::FileTransferManager;
+pub use indexer::FileIndexer;
+pub use session::{SyncReport, SyncSession};
+pub use sync::SyncEngine;
+pub use watcher::{ChangeSource, FileWatcher};
+pub use types::SyncError;
@@ -0,0 +1,126 @@
+#![allow(dead_code)]
+
+//! Content a download would bring that this device already has: in another
+//! file of the root, moved in a way the index missed, among the last-synced
+//! versions kept for `syncmd diff`, or in the trash. Such downloads are
+//! copied locally instead of going over the network. Whatever is found is
+//! hashed again before it is used, so a stale index only costs a download.
+
+use crate::index_store::IndexStore;
+use crate::indexer::FileIndexer;
+use crate::trash::Trash;
+use crate::types::SyncError;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Where a local copy came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Another file in the root
+    Root(PathBuf),
+    /// The last-synced version of a file
+    Base,
+    /// A file in the trash, at its path before it was deleted
+    Trash(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Root(path) => write!(f, "{:?}", path),
+            Source::Base => write!(f, "a synced version"),
+            Source::Trash(path) => write!(f, "{:?} in the trash", path),
+        }
+    }
+}
+
+pub struct LocalCopies<'a> {
+    indexer: &'a FileIndexer,
+    store: IndexStore,
+    /// Trashed files by hash, once one was needed: `(path, location)`
+    trash: Option<HashMap<String, (PathBuf, PathBuf)>>,
+}
+
+impl<'a> LocalCopies<'a> {
+    pub fn new(indexer: &'a FileIndexer) -> Result<Self, SyncError> {
+        Ok(Self {
+            indexer,
+            store: IndexStore::open(indexer.sync_root())?,
+            trash: None,
+        })
+    }
+
+    /// Content with the given hash from this device, and where it was found.
+    pub fn find(&mut self, hash: &str) -> Result<Option<(Source, Vec<u8>)>, SyncError> {
+        for path in self.store.paths_with_hash(hash)? {
+            if let Ok(content) = self.indexer.read_file_content(&path) {
+                if matches(&content, hash) {
+                    return Ok(Some((Source::Root(path), content)));
+                }
+            }
+        }
+        if let Some(content) = self.store.base_with_hash(hash)?.filter(|content| matches(content, hash)) {
+            return Ok(Some((Source::Base, content)));
+        }
+        let trash = match &mut self.trash {
+            Some(trash) => trash,
+            None => self.trash.insert(index_trash(self.indexer)?),
+        };
+        if let Some((path, location)) = trash.get(hash) {
+            if let Ok(content) = std::fs::read(location) {
+                if matches(&content, hash) {
+                    return Ok(Some((Source::Trash(path.clone()), content)));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn matches(content: &[u8], hash: &str) -> bool {
+    blake3::hash(content).to_hex().as_str() == hash
+}
+
+/// Hashes everything in the root's trash, keeping the most recently deleted
+/// file for each hash.
+fn index_trash(indexer: &FileIndexer) -> Result<HashMap<String, (PathBuf, PathBuf)>, SyncError> {
+    let mut trash = HashMap::new();
+    for entry in Trash::new(indexer.sync_root()).list()? {
+        if let Ok(hash) = crate::file_transfer::hash_file(entry.location()) {
+            trash.entry(hash).or_insert_with(|| (entry.path.clone(), entry.location().to_path_buf()));
+        }
+    }
+    Ok(trash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_finds_copies_in_root_and_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("notes")).unwrap();
+        std::fs::write(root.join("notes/a.md"), "# A").unwrap();
+        std::fs::write(root.join("notes/b.md"), "# B").unwrap();
+        let indexer = FileIndexer::new("laptop".to_string(), root.to_path_buf());
+        let state = indexer.index_directory().unwrap();
+        IndexStore::open(root).unwrap().save_state(&state).unwrap();
+        let hash = |content: &str| blake3::hash(content.as_bytes()).to_hex().to_string();
+
+        Trash::new(root).put(Path::new("notes/b.md")).unwrap();
+        let mut copies = LocalCopies::new(&indexer).unwrap();
+        let (source, content) = copies.find(&hash("# A")).unwrap().unwrap();
+        assert_eq!(source, Source::Root(PathBuf::from("notes/a.md")));
+        assert_eq!(content, b"# A");
+        let (source, _) = copies.find(&hash("# B")).unwrap().unwrap();
+        assert_eq!(source, Source::Trash(PathBuf::from("notes/b.md")));
+
+        // The index is only a hint, changed files aren't used
+        std::fs::write(root.join("notes/a.md"), "# A, edited").unwrap();
+        assert!(copies.find(&hash("# A")).unwrap().is_none());
+    }
+}
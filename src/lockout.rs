@@ -0,0 +1,115 @@
+#![allow(dead_code)]
+
+//! Failed authentications per source address. Once an address has failed
+//! `max_attempts` times in a row it is locked out for [`BASE_LOCKOUT`],
+//! and each further failure after a lockout doubles it, up to
+//! [`MAX_LOCKOUT`], so guessing tokens on an exposed port gets nowhere. A
+//! successful authentication forgets the address.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Failures an address may have before it is locked out
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+/// The first lockout of an address
+pub const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+/// Lockouts stop doubling here
+pub const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct AuthLockout {
+    max_attempts: u32,
+    sources: HashMap<IpAddr, Source>,
+}
+
+#[derive(Debug, Clone)]
+struct Source {
+    /// Failures since the last success
+    failures: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+impl Default for AuthLockout {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ATTEMPTS)
+    }
+}
+
+impl AuthLockout {
+    pub fn new(max_attempts: u32) -> Self {
+        Self { max_attempts: max_attempts.max(1), sources: HashMap::new() }
+    }
+
+    /// How much longer `address` is locked out, `None` if it may try.
+    pub fn locked(&self, address: IpAddr, now: Instant) -> Option<Duration> {
+        let until = self.sources.get(&address)?.locked_until?;
+        (until > now).then(|| until - now)
+    }
+
+    /// Counts a failure from `address`, returning the lockout it earned if
+    /// it has run out of attempts.
+    pub fn record_failure(&mut self, address: IpAddr, now: Instant) -> Option<Duration> {
+        let source = self.sources.entry(address).or_insert(Source {
+            failures: 0,
+            locked_until: None,
+            last_failure: now,
+        });
+        source.failures += 1;
+        source.last_failure = now;
+        let beyond = source.failures.checked_sub(self.max_attempts)?;
+        let lockout = BASE_LOCKOUT.saturating_mul(2u32.saturating_pow(beyond)).min(MAX_LOCKOUT);
+        source.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    pub fn record_success(&mut self, address: IpAddr) {
+        self.sources.remove(&address);
+    }
+
+    /// Forgets addresses that haven't failed for the longest lockout, so
+    /// the table doesn't grow with every address that ever mistyped.
+    pub fn forget_idle(&mut self, now: Instant) -> usize {
+        let before = self.sources.len();
+        self.sources.retain(|_, source| now.duration_since(source.last_failure) < MAX_LOCKOUT);
+        before - self.sources.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_lockout() {
+        let mut lockout = AuthLockout::new(3);
+        let attacker: IpAddr = "203.0.113.7".parse().unwrap();
+        let laptop: IpAddr = "198.51.100.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(lockout.record_failure(attacker, now), None);
+        assert_eq!(lockout.record_failure(attacker, now), None);
+        assert_eq!(lockout.locked(attacker, now), None);
+        assert_eq!(lockout.record_failure(attacker, now), Some(BASE_LOCKOUT));
+        assert_eq!(lockout.locked(attacker, now + Duration::from_secs(10)), Some(BASE_LOCKOUT - Duration::from_secs(10)));
+        assert_eq!(lockout.locked(laptop, now), None);
+
+        // Each failure after the lockout ends doubles the next
+        let later = now + BASE_LOCKOUT;
+        assert_eq!(lockout.locked(attacker, later), None);
+        assert_eq!(lockout.record_failure(attacker, later), Some(BASE_LOCKOUT * 2));
+        assert_eq!(lockout.record_failure(attacker, later), Some(BASE_LOCKOUT * 4));
+        for _ in 0..20 {
+            lockout.record_failure(attacker, later);
+        }
+        assert_eq!(lockout.locked(attacker, later), Some(MAX_LOCKOUT));
+
+        lockout.record_failure(laptop, now);
+        lockout.record_success(laptop);
+        assert_eq!(lockout.record_failure(laptop, now), None);
+
+        assert_eq!(lockout.forget_idle(later + MAX_LOCKOUT), 2);
+        assert_eq!(lockout.locked(attacker, later + MAX_LOCKOUT), None);
+    }
+}
@@ -1,41 +1,186 @@
-mod types;
-mod indexer;
-mod sync;
-mod network;
-mod cli;
-mod watcher;
-mod file_transfer;
-mod security;
+mod backoff;
+mod events;
+mod local_copies;
+mod ui;
+mod markdown_preview;
+mod progress;
+mod tags;
+mod swarm;
+mod simulate;
+mod pairing;
+mod mirror;
+mod manifest;
+mod daemon;
+mod mdns;
 
+use syncmd_core::session::{self, open_download, remote_path, request_operations, root_engine, root_indexer};
+use syncmd_core::{
+    bandwidth, capabilities, chaos, cli, config_edit, conflicts, discovery, encryption, exit_codes,
+    export, file_transfer, ignore, import, index_store, indexer, maintenance,
+    migrate, network, open_files, overlay, peer_registry, plan, protocol_trace, remote, schedule,
+    search, secrets, sections, state, style, sync, telemetry, templates, text_diff, trash, types,
+    watcher,
+};
 use clap::Parser;
-use cli::{Cli, Commands, Config};
+use cli::{Cli, Commands, Config, ConfigAction, DeviceAction, FiltersAction, ManifestAction, MigrateAction, PairAction, PeersAction, QueueAction, RemoteAction, SnapshotAction, StateAction, TelemetryAction, TraceAction, TrashAction};
 use indexer::FileIndexer;
 use network::{ClientManager, NetworkManager, NetworkMessage};
 use sync::SyncEngine;
 use std::sync::Arc;
 use tokio::signal;
-use watcher::{FileWatcher, WatchEvent};
-use file_transfer::FileTransferManager;
+use watcher::FileWatcher;
+use index_store::IndexStore;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> std::process::ExitCode {
     tracing_subscriber::fmt::init();
     
     let cli = Cli::parse();
-    
-        
+    style::init(cli.color);
+    if cli.no_compress {
+        capabilities::disable_compression();
+    }
+    if let Some(spec) = cli.chaos.clone().or_else(|| std::env::var(chaos::ENV_VAR).ok()) {
+        match chaos::ChaosConfig::parse(&spec) {
+            Ok(config) => {
+                eprintln!("{}", style::conflict(format!("Chaos mode, connections get {}", config)));
+                chaos::enable(config);
+            }
+            Err(e) => return exit_codes::report(&types::SyncError::Config(format!("Invalid chaos spec: {}", e)), cli.errors),
+        }
+    }
+    if let Some(path) = &cli.trace_protocol {
+        if let Err(e) = protocol_trace::enable(path) {
+            return exit_codes::report(&e, cli.errors);
+        }
+    }
+    let errors = cli.errors;
+    match run(cli).await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => exit_codes::report(e.as_ref(), errors),
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
     match cli.command {
-        Commands::Sync { path, connect, server, port } => {
-            sync_folder(path, connect, server, port).await?;
+        Commands::Sync { path, connect, server, port, confirm_over, strict, discover, json } => {
+            let connect = if discover {
+                match pick_discovered_peer().await? {
+                    Some(address) => Some(address),
+                    None => return Ok(()),
+                }
+            } else {
+                connect
+            };
+            let output = if json { progress::ProgressOutput::Json } else { progress::ProgressOutput::Bars };
+            progress::spawn(file_transfer::TransferQueue::global(), output);
+            sync_folder(path, connect, server, port, confirm_over, strict).await?;
         }
         Commands::ListClients => {
             list_clients().await?;
         }
-        Commands::Status => {
-            show_status().await?;
+        Commands::Status { ignored } => {
+            show_status(ignored, cli.verbose).await?;
+        }
+        Commands::Ui => {
+            ui::run().await?;
+        }
+        Commands::Init { path, name, auth_token, template } => {
+            init_config(path, name, auth_token, template).await?;
+        }
+        Commands::Import { from, path, exclude_from, no_ignores } => {
+            import_folder(from, path, exclude_from, no_ignores).await?;
+        }
+        Commands::ExportBatch { path, since, format, out } => {
+            export_batch(path, since, format, out).await?;
+        }
+        Commands::Manifest { action } => {
+            manage_manifest(action)?;
+        }
+        Commands::Compact { path, retention_days } => {
+            compact_journal(path, retention_days).await?;
+        }
+        Commands::Snapshot { action } => {
+            manage_snapshots(action)?;
+        }
+        Commands::Config { action } => {
+            manage_config(action).await?;
+        }
+        Commands::Diff { path, remote } => {
+            diff_file(path, remote).await?;
+        }
+        Commands::Device { action } => {
+            manage_device(action).await?;
+        }
+        Commands::Filters { action } => {
+            manage_filters(action).await?;
+        }
+        Commands::Encrypt { path, salt } => {
+            encrypt_folder(path, salt).await?;
+        }
+        Commands::Select { path, include, exclude, remove, clear } => {
+            select_subfolders(path, include, exclude, remove, clear)?;
+        }
+        Commands::Conflicts { path, preview } => {
+            list_conflicts(path, preview)?;
+        }
+        Commands::Trash { action } => {
+            manage_trash(action)?;
+        }
+        Commands::State { action } => {
+            manage_state(action)?;
+        }
+        Commands::Remote { action } => {
+            manage_remotes(action).await?;
+        }
+        Commands::Peers { action, listen } => {
+            manage_peers(action, listen).await?;
+        }
+        Commands::Mirror { path, to, remote, delete, verify, report, every } => {
+            mirror_folder(path, to, remote, mirror::MirrorOptions { delete, verify }, report, every).await?;
+        }
+        Commands::Pair { action } => {
+            pair(action).await?;
         }
-        Commands::Init { path, name, auth_token } => {
-            init_config(path, name, auth_token).await?;
+        Commands::Daemon { connect, port } => {
+            run_daemon(connect, port, Arc::new(root_change_source)).await?;
+        }
+        Commands::Pause { path } => {
+            control_daemon(daemon::ControlRequest::Pause { path: configured_root(path)? }).await?;
+        }
+        Commands::Resume { path } => {
+            control_daemon(daemon::ControlRequest::Resume { path: configured_root(path)? }).await?;
+        }
+        Commands::Flush => {
+            control_daemon(daemon::ControlRequest::Flush).await?;
+        }
+        Commands::Focus { path, closed } => {
+            let path = std::path::absolute(path)?;
+            control_daemon(daemon::ControlRequest::Focus { path, open: !closed }).await?;
+        }
+        Commands::Telemetry { action } => {
+            manage_telemetry(action)?;
+        }
+        Commands::Queue { action } => {
+            manage_queue(action).await?;
+        }
+        Commands::Search { query, path, connect, limit } => {
+            search_notes(query, path, connect, limit).await?;
+        }
+        Commands::History { path, all_devices, connect, limit } => {
+            show_history(path, all_devices, connect, limit).await?;
+        }
+        Commands::ShareLink { path, connect, expires } => {
+            share_link(path, connect, expires).await?;
+        }
+        Commands::Simulate { devices, script, keep } => {
+            simulate(devices, script, keep).await?;
+        }
+        Commands::Migrate { action } => {
+            migrate_device(action)?;
+        }
+        Commands::Trace { action } => {
+            analyze_trace(action)?;
         }
     }
     
@@ -47,20 +192,42 @@ async fn sync_folder(
     connect: Option<String>,
     server_mode: bool,
     port: u16,
+    confirm_over: Option<u64>,
+    strict: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let config = Config::load()?;
+    let mut config = Config::load()?;
     let client_manager = Arc::new(ClientManager::new());
     
     println!("Starting sync for folder: {:?}", path);
     println!("Server ID: {}", client_manager.server_id());
     println!("Client Name: {}", config.device_name);
     
-    let indexer = FileIndexer::new(client_manager.server_id().to_string(), path.clone());
-    let sync_engine = SyncEngine::new(client_manager.server_id().to_string());
+    let indexer = root_indexer(&config, client_manager.server_id().to_string(), &path).with_strict(strict);
+    report_recovery(&path, indexer.recover_apply()?);
+    let folder_key = if connect.is_some() { folder_key(&config, &path)? } else { None };
+    let sync_engine = root_engine(&config, client_manager.server_id().to_string(), &path);
     
     // Initial indexing
-    let sync_state = indexer.index_directory()?;
+    let sync_state = match indexer.index_directory() {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err("initial indexing failed".into());
+        }
+    };
     println!("Indexed {} files", sync_state.local_files.len());
+    let mut index_store = IndexStore::open(&path)?;
+    let previous_files = index_store
+        .load_state(sync_state.device_id.clone(), path.clone())?
+        .local_files;
+    index_store.save_state(&sync_state)?;
+    if let Some(report) = index_store.maybe_compact()? {
+        tracing::info!(
+            "Compacted journal: {} entries and {} snapshots removed",
+            report.journal_entries_removed,
+            report.snapshots_removed
+        );
+    }
     
     let network_manager = NetworkManager::new(
         client_manager.clone(),
@@ -69,6 +236,32 @@ async fn sync_folder(
     
     if server_mode {
         println!("Starting server on port {}", port);
+        let identity = discovery::DeviceIdentity::load_or_create()?;
+        let shares = config.sync_roots.iter()
+            .filter(|root| root.enabled)
+            .filter_map(|root| root.path.file_name().map(|n| n.to_string_lossy().to_string()))
+            .collect();
+        let service = mdns::ServiceInfo {
+            device_id: config.device_id.clone(),
+            name: config.device_name.clone(),
+            shares,
+            port,
+        };
+        let beacon = discovery::broadcast_beacons(
+            identity, config.device_id.clone(), config.device_name.clone(), service.shares.clone(), port,
+        );
+        tokio::spawn(async move {
+            if let Err(e) = beacon.await {
+                eprintln!("Discovery beacon error: {}", e);
+            }
+        });
+        tokio::spawn(async move {
+            // Another responder may hold the port exclusively; beacons
+            // still work then
+            if let Err(e) = mdns::advertise(service).await {
+                tracing::warn!("mDNS advertisement unavailable: {}", e);
+            }
+        });
         tokio::spawn(async move {
             if let Err(e) = network_manager.start_server().await {
                 eprintln!("Server error: {}", e);
@@ -81,77 +274,150 @@ async fn sync_folder(
     } else if let Some(server_addr) = connect {
         println!("Connecting to server: {}", server_addr);
         
-        let mut stream = network_manager.connect_to_server(&server_addr).await?;
-        
         // Calculate root hash for handshake
         let _root_hash = calculate_root_hash(&sync_state)?;
         
-        // Send authentication
-        let auth_token = config.auth_token.clone()
-            .ok_or("Authentication token required. Please run 'syncmd init' with --auth-token.")?;
-        network_manager.send_authentication(&mut stream, auth_token, config.device_name.clone()).await?;
+        let client_name = config.device_name.clone();
+        let (mut stream, negotiated) = loop {
+            let (mut stream, remote) = network_manager.connect_supervised(&config, &server_addr).await?;
+            println!("Connected to {} at {}", remote.name, remote.address);
+            
+            match authenticate(&mut config, &network_manager, &mut stream, client_name.clone()).await {
+                Ok(negotiated) => break (stream, negotiated),
+                Err(e) if is_token_rejection(e.as_ref()) => {
+                    // Nothing has been applied yet, local edits stay on disk
+                    // and in the journal until the next successful sync
+                    eprintln!("{}", style::conflict(format!("Authentication failed: {}", e)));
+                    eprintln!("Pair this device again and save the new token with");
+                    eprintln!("  syncmd config set auth_token <token>");
+                    eprintln!("Local changes are kept and will sync once authenticated. Waiting for a new token...");
+                    wait_for_new_token(&mut config).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        };
         println!("Connected to server successfully");
+        tracing::debug!("Negotiated capabilities: {}", capabilities::describe(&negotiated));
+        index_store.record_capabilities(&negotiated)?;
+        
+        let subscriptions = send_subscriptions(&config, &path, &mut stream, folder_key.as_ref()).await?;
+        if !subscriptions.is_empty() {
+            println!("Subscribed to {}", subscriptions.join(", "));
+        }
+        
+        // Initial sync, with a plan summary so large transfers don't start unannounced
+        let (operations, inline) = request_operations(&sync_state, &mut stream, folder_key.as_ref(), &negotiated).await?;
+        let operations = sync_engine.incoming_operations(operations);
+        let plan = plan::TransferPlan::new(&operations, &sync_state.local_files, &previous_files);
+        if !plan.is_empty() {
+            plan.print_summary(index_store.recent_throughput()?);
+        }
+        if let Some(threshold) = confirm_over {
+            if !plan::confirm(&plan, threshold)? {
+                println!("Sync cancelled");
+                return Ok(());
+            }
+        }
+        let result = apply_operations(&indexer, &sync_engine, &mut stream, operations, inline, folder_key.as_ref()).await;
+        record_round(&result);
+        result?;
+        // Word lists of encrypted files would be as opaque as their names
+        if folder_key.is_none() && config.find_sync_root(&path).is_some_and(|root| root.offline_search) {
+            if let Some(updated) = refresh_search_index(&mut index_store, &mut stream, &negotiated).await? {
+                println!("Updated {} search index entries", updated);
+            }
+        }
+        replicate_server_journal(&mut index_store, &mut stream, &negotiated, folder_key.as_ref()).await?;
+        
+        let scheduler = Arc::new(schedule::Scheduler::new(
+            config.find_sync_root(&path).and_then(|root| root.schedule.as_ref()),
+        )?);
         
         // Start file watcher for real-time sync
-        let mut file_watcher = FileWatcher::new(path.clone())?;
+        let mut file_watcher = root_watcher(&config, &path)?;
         println!("Started file watcher for: {:?}", path);
         
         // Start periodic sync
-        let sync_stream = Arc::new(tokio::sync::Mutex::new(stream));
+        let sync_connection = Arc::new(tokio::sync::Mutex::new(SyncConnection {
+            config: config.clone(),
+            network_manager,
+            target: server_addr.clone(),
+            path: path.clone(),
+            folder_key: folder_key.clone(),
+            stream: Some(stream),
+            negotiated: negotiated.clone(),
+            backoff: backoff::Backoff::reconnect(),
+        }));
         let sync_indexer = Arc::new(indexer);
         let sync_engine_clone = Arc::new(sync_engine);
         
         // File watching task
-        let watcher_sync_stream = sync_stream.clone();
+        let watcher_connection = sync_connection.clone();
         let watcher_indexer = sync_indexer.clone();
         let watcher_engine = sync_engine_clone.clone();
         let watcher_path = path.clone();
+        let watcher_scheduler = scheduler.clone();
         
-        tokio::spawn(async move {
-            let mut last_sync = std::time::Instant::now();
-            
+        let flush = Arc::new(tokio::sync::Notify::new());
+        let watcher_flush = flush.clone();
+        let server_changed = Arc::new(tokio::sync::Notify::new());
+        let watcher_server_changed = server_changed.clone();
+        if negotiated.contains(&capabilities::Capability::Notifications) {
+            tokio::spawn(watch_server_changes(
+                config.clone(),
+                path.clone(),
+                server_addr.clone(),
+                folder_key.clone(),
+                server_changed,
+            ));
+        }
+        
+        let watcher_task = tokio::spawn(async move {
             loop {
-                if let Some(event) = file_watcher.next_event_debounced().await {
-                    // Check if this event should trigger a sync
-                    if file_watcher.should_sync_event(&event) {
-                        println!("File event: {:?}", event);
-                        
-                        // Get relative path for logging
-                        if let Some(relative_path) = file_watcher.get_relative_path(
-                            match &event {
-                                WatchEvent::Created(p) | WatchEvent::Modified(p) | WatchEvent::Deleted(p) => p,
-                                WatchEvent::Renamed(_, new) => new,
-                            },
-                            &watcher_path
-                        ) {
-                            println!("Relative path: {:?}", relative_path);
-                        }
-                        
-                        // Debounce rapid changes
-                        if last_sync.elapsed() > std::time::Duration::from_secs(1) {
-                            if let Ok(mut stream) = watcher_sync_stream.try_lock() {
-                                if let Err(e) = perform_sync(&watcher_indexer, &watcher_engine, &mut stream).await {
-                                    eprintln!("Real-time sync error: {}", e);
-                                }
-                                last_sync = std::time::Instant::now();
-                            }
-                        }
+                // Files are reported once they stop changing; on shutdown
+                // whatever is still held back goes out immediately
+                let (changes, remote, last) = tokio::select! {
+                    changes = file_watcher.next_changes() => match changes {
+                        Some(changes) => (changes, false, false),
+                        None => break,
+                    },
+                    _ = watcher_server_changed.notified() => (Vec::new(), true, false),
+                    _ = watcher_flush.notified() => (file_watcher.take_pending(), false, true),
+                };
+                for changed in &changes {
+                    if let Some(relative_path) = file_watcher.get_relative_path(changed, &watcher_path) {
+                        println!("Changed: {:?}", relative_path);
+                    }
+                }
+                
+                // Quiet hours hold changes back for the next periodic
+                // round, which indexes the whole root
+                if !last && watcher_scheduler.is_quiet_now() {
+                    continue;
+                }
+                if remote || !changes.is_empty() {
+                    // Wait for a periodic sync in progress rather than drop the final state
+                    let mut connection = watcher_connection.lock().await;
+                    if let Err(e) = connection.sync(&watcher_indexer, &watcher_engine).await {
+                        eprintln!("Real-time sync error: {}", e);
                     }
                 }
+                if last {
+                    break;
+                }
             }
         });
         
         // Periodic sync task
-        let periodic_sync_stream = sync_stream.clone();
+        let periodic_connection = sync_connection.clone();
         let periodic_indexer = sync_indexer.clone();
         let periodic_engine = sync_engine_clone.clone();
         
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
-            loop {
-                interval.tick().await;
-                if let Ok(mut stream) = periodic_sync_stream.try_lock() {
-                    if let Err(e) = perform_sync(&periodic_indexer, &periodic_engine, &mut stream).await {
+            while let Some(delay) = scheduler.next_delay() {
+                tokio::time::sleep(delay).await;
+                if let Ok(mut connection) = periodic_connection.try_lock() {
+                    if let Err(e) = connection.sync(&periodic_indexer, &periodic_engine).await {
                         eprintln!("Periodic sync error: {}", e);
                     }
                 }
@@ -161,6 +427,10 @@ async fn sync_folder(
         // Wait for Ctrl+C
         signal::ctrl_c().await?;
         println!("Shutting down client...");
+        flush.notify_one();
+        if tokio::time::timeout(std::time::Duration::from_secs(30), watcher_task).await.is_err() {
+            eprintln!("Gave up waiting for the last changes to sync");
+        }
     } else {
         println!("Either --connect or --server must be specified");
     }
@@ -168,120 +438,800 @@ async fn sync_folder(
     Ok(())
 }
 
+/// Browses the LAN over mDNS and asks which server to sync with. Returns
+/// `None` when nothing was found or nothing was picked.
+async fn pick_discovered_peer() -> Result<Option<String>, Box<dyn std::error::Error>> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    let config = Config::load()?;
+    println!("Looking for syncmd servers on the local network...");
+    let peers = mdns::browse(std::time::Duration::from_secs(3), &config.device_id).await?;
+    if peers.is_empty() {
+        println!("No servers found");
+        return Ok(None);
+    }
+
+    for (i, peer) in peers.iter().enumerate() {
+        let known = config.known_peers.iter().any(|known| known.device_id == peer.info.device_id);
+        println!("{}) {} [{}] at {}{}", i + 1, peer.info.name, types::device_slug(&peer.info.name),
+            peer.address, if known { " - known peer" } else { "" });
+        if !peer.info.shares.is_empty() {
+            println!("   shares: {}", peer.info.shares.join(", "));
+        }
+    }
+    if !std::io::stdin().is_terminal() {
+        eprintln!("No terminal to pick a server from, use --connect with one of the addresses above");
+        return Ok(None);
+    }
+
+    print!("Connect to [1-{}]: ", peers.len());
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    let choice = answer.trim().parse::<usize>().ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| peers.get(i));
+    match choice {
+        Some(peer) => Ok(Some(peer.address.to_string())),
+        None => {
+            println!("No server picked");
+            Ok(None)
+        }
+    }
+}
+
 async fn perform_sync(
     indexer: &FileIndexer,
-    _sync_engine: &SyncEngine,
-    stream: &mut tokio::net::TcpStream,
+    sync_engine: &SyncEngine,
+    stream: &mut network::Connection,
+    folder_key: Option<&encryption::FolderKey>,
+    negotiated: &[capabilities::Capability],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    
-    // Get current state
-    let sync_state = indexer.index_directory()?;
-    
-    // Send sync request
-    let sync_request = NetworkMessage::SyncRequest {
-        client_id: sync_state.device_id.clone(),
-        files: sync_state.local_files.values().cloned().collect(),
+    // Get current state; hashing every file is blocking work, so keep it
+    // off the worker thread's task queue
+    let round = async {
+        let sync_state = tokio::task::block_in_place(|| indexer.index_directory())?;
+        let (operations, inline) = request_operations(&sync_state, stream, folder_key, negotiated).await?;
+        let operations = sync_engine.incoming_operations(operations);
+        apply_operations(indexer, sync_engine, stream, operations, inline, folder_key).await
     };
+    let result = round.await;
+    record_round(&result);
+    result
+}
+
+/// The connection `sync --connect` runs its rounds over. When a round fails
+/// because the connection broke, e.g. the server restarted, it connects,
+/// authenticates and subscribes again, backing off between failed attempts,
+/// and then runs the round once more.
+struct SyncConnection {
+    config: Config,
+    network_manager: NetworkManager,
+    target: String,
+    path: std::path::PathBuf,
+    folder_key: Option<encryption::FolderKey>,
+    stream: Option<network::Connection>,
+    negotiated: Vec<capabilities::Capability>,
+    backoff: backoff::Backoff,
+}
+
+impl SyncConnection {
+    async fn sync(&mut self, indexer: &FileIndexer, sync_engine: &SyncEngine) -> Result<(), Box<dyn std::error::Error>> {
+        if self.stream.is_none() {
+            self.reconnect().await?;
+        }
+        let stream = self.stream.as_mut().expect("connected above");
+        let lost = match perform_sync(indexer, sync_engine, stream, self.folder_key.as_ref(), &self.negotiated).await {
+            Err(e) if is_connection_error(e.as_ref()) => e.to_string(),
+            result => return result,
+        };
+        eprintln!("{}", style::conflict(format!("Lost the connection to {}: {}", self.target, lost)));
+        self.stream = None;
+        self.reconnect().await?;
+        let stream = self.stream.as_mut().expect("reconnected above");
+        perform_sync(indexer, sync_engine, stream, self.folder_key.as_ref(), &self.negotiated).await
+    }
+
+    /// Tries until connected again. Fails only on errors retrying can't fix.
+    async fn reconnect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            // Boxed errors aren't Send, so only their messages are kept
+            // across the awaits below
+            let (message, rejected) = match self.connect().await {
+                Ok(()) => {
+                    println!("Reconnected to {}", self.target);
+                    self.backoff.reset();
+                    return Ok(());
+                }
+                Err(e) if is_token_rejection(e.as_ref()) => (e.to_string(), true),
+                Err(e) if is_connection_error(e.as_ref()) => (e.to_string(), false),
+                Err(e) => return Err(e),
+            };
+            if rejected {
+                eprintln!("{}", style::conflict(format!("Authentication failed: {}", message)));
+                eprintln!("Local changes are kept and will sync once authenticated. Waiting for a new token...");
+                wait_for_new_token(&mut self.config).await?;
+            } else {
+                let delay = self.backoff.next_delay();
+                eprintln!("Reconnecting to {} failed: {}, retrying in {:.1}s", self.target, message, delay.as_secs_f64());
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    async fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut stream, _) = self.network_manager.connect_supervised(&self.config, &self.target).await?;
+        let client_name = self.config.device_name.clone();
+        let negotiated = authenticate(&mut self.config, &self.network_manager, &mut stream, client_name).await?;
+        IndexStore::open(&self.path)?.record_capabilities(&negotiated)?;
+        send_subscriptions(&self.config, &self.path, &mut stream, self.folder_key.as_ref()).await?;
+        self.stream = Some(stream);
+        self.negotiated = negotiated;
+        Ok(())
+    }
+}
+
+/// Whether an error means the connection itself is gone, so the stream
+/// can't be used any more and connecting again may help.
+fn is_connection_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.is::<std::io::Error>()
+        || matches!(
+            error.downcast_ref::<types::SyncError>(),
+            Some(types::SyncError::Io(_) | types::SyncError::Network(_))
+        )
+}
+
+/// Counts a finished sync round, when telemetry is on.
+fn record_round<T, E>(result: &Result<T, E>) {
+    telemetry::record(|counters| match result {
+        Ok(_) => counters.syncs_succeeded += 1,
+        Err(_) => counters.syncs_failed += 1,
+    });
+}
+
+async fn apply_operations(
+    indexer: &FileIndexer,
+    sync_engine: &SyncEngine,
+    stream: &mut network::Connection,
+    operations: Vec<types::SyncOperation>,
+    mut inline: types::InlineContent,
+    folder_key: Option<&encryption::FolderKey>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started = std::time::Instant::now();
+    let mut transferred_bytes = 0;
+    let mut downloads = Vec::new();
+    let root = indexer.sync_root().as_path();
+    let changes = operations.len();
+    let conflicts: Vec<&std::path::Path> = operations.iter()
+        .filter_map(|operation| match operation {
+            types::SyncOperation::Add(metadata) if conflicts::is_copy(&metadata.path) => Some(metadata.path.as_path()),
+            _ => None,
+        })
+        .collect();
+    if !conflicts.is_empty() {
+        let count = conflicts.len() as u64;
+        telemetry::record(|counters| counters.conflicts += count);
+    }
+    for path in conflicts {
+        events::record(Some(root), events::EventKind::Conflict, path.display().to_string());
+    }
     
-    let request_data = serde_json::to_vec(&sync_request)?;
-    stream.write_all(&request_data).await?;
-    
-    // Read response
-    let mut response_buffer = vec![0u8; 8192];
-    let n = stream.read(&mut response_buffer).await?;
-    
-    if n > 0 {
-        let response: NetworkMessage = serde_json::from_slice(&response_buffer[..n])?;
-        
-        if let NetworkMessage::SyncResponse { operations } = response {
-            println!("Received {} sync operations", operations.len());
-            
-            // Apply operations
-            for operation in operations {
-                match operation {
-                    crate::types::SyncOperation::Add(metadata) => {
-                        println!("Add operation for: {:?}", metadata.path);
-                        
-                        // Use new file transfer system
-                        let mut transfer_manager = FileTransferManager::new();
-                        if let Err(e) = transfer_manager.receive_file(stream, indexer.sync_root()).await {
-                            eprintln!("File transfer error: {}", e);
-                        }
-                    }
-                    crate::types::SyncOperation::Update(metadata) => {
-                        println!("Update operation for: {:?}", metadata.path);
-                        
-                        // Use new file transfer system for updates too
-                        let mut transfer_manager = FileTransferManager::new();
-                        if let Err(e) = transfer_manager.receive_file(stream, indexer.sync_root()).await {
-                            eprintln!("File transfer error: {}", e);
+    // Everything is staged and lands on disk together once it's all here
+    report_recovery(indexer.sync_root(), indexer.begin_apply()?);
+    // Removed once the files leaving them are gone
+    let mut removed_directories = Vec::new();
+    let staged = async {
+        // Hashes of encrypted roots are of ciphertext, which is never local
+        let mut local_copies = match folder_key {
+            Some(_) => None,
+            None => Some(local_copies::LocalCopies::new(indexer)?),
+        };
+        for operation in operations {
+            match operation {
+                crate::types::SyncOperation::Add(metadata) | crate::types::SyncOperation::Update(metadata) => {
+                    indexer.adopt(&metadata);
+                    // Small files arrived with the response and need no transfer
+                    match inline.take(&metadata) {
+                        Some(content) => {
+                            session::write_incoming(indexer, sync_engine, &metadata, &content)?;
+                            transferred_bytes += content.len() as u64;
+                            events::record(Some(root), events::EventKind::Downloaded, metadata.path.display().to_string());
                         }
+                        None if copy_locally(local_copies.as_mut(), indexer, sync_engine, &metadata)? => {}
+                        None => downloads.push(metadata),
                     }
-                    crate::types::SyncOperation::Delete(path) => {
-                        println!("Delete operation for: {:?}", path);
-                        indexer.delete_file(&path)?;
+                }
+                crate::types::SyncOperation::Delete(path) => {
+                    println!("{}", style::deleted(format!("Moved {:?} to the trash", path)));
+                    indexer.trash_file(&path)?;
+                    events::record(Some(root), events::EventKind::Deleted, path.display().to_string());
+                }
+                crate::types::SyncOperation::Rename { from, to } => {
+                    indexer.adopt(&to);
+                    // Without the old file as it was sent there's nothing to
+                    // move, fetch it instead and leave a local edit in place
+                    if indexer.holds(&from, &to.hash)? {
+                        println!("Moved {:?} to {:?}", from, to.path);
+                        indexer.rename_file(&from, &to.path)?;
+                    } else if !copy_locally(local_copies.as_mut(), indexer, sync_engine, &to)? {
+                        downloads.push(to);
                     }
                 }
+                crate::types::SyncOperation::MkDir(path) => {
+                    indexer.create_directory(&path)?;
+                    println!("{}", style::added(format!("Created directory {:?}", path)));
+                }
+                crate::types::SyncOperation::RmDir(path) => removed_directories.push(path),
             }
         }
+        
+        // Open files first, bulk imports last, within the small files and
+        // within the large ones
+        open_files::OpenFiles::global().prioritize(root, &mut downloads);
+        let (large, downloads): (Vec<_>, Vec<_>) = downloads.into_iter()
+            .partition(|metadata| metadata.size >= swarm::MIN_FILE_SIZE);
+        transferred_bytes += download_files(indexer, sync_engine, stream, &downloads, folder_key).await?;
+        transferred_bytes += swarm_download_files(indexer, sync_engine, stream, &large, folder_key).await?;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    };
+    if let Err(e) = staged.await {
+        indexer.abort_apply()?;
+        return Err(e);
+    }
+    indexer.commit_apply()?;
+    for path in removed_directories {
+        match indexer.remove_directory(&path)? {
+            true => println!("{}", style::deleted(format!("Removed directory {:?}", path))),
+            false => println!("Kept directory {:?}, removed elsewhere but files are left in it", path),
+        }
+    }
+    if changes > 0 {
+        events::record(Some(root), events::EventKind::Synced, format!("{} change(s) applied", changes));
     }
     
+    let mut store = IndexStore::open(indexer.sync_root())?;
+    store.record_throughput(transferred_bytes, started.elapsed())?;
+    
+    // What's on disk now is the last-synced version for `syncmd diff`
+    let synced_state = tokio::task::block_in_place(|| indexer.index_directory())?;
+    store.record_bases(&synced_state, |path| indexer.read_file_content(path))?;
+    session::record_shared_directories(&mut store, &synced_state)?;
+    
     Ok(())
 }
 
+/// Writes a file from content this device already has instead of
+/// downloading it. Returns whether there was such content.
+fn copy_locally(
+    copies: Option<&mut local_copies::LocalCopies>,
+    indexer: &FileIndexer,
+    sync_engine: &SyncEngine,
+    metadata: &types::FileMetadata,
+) -> Result<bool, types::SyncError> {
+    let Some((source, content)) = copies.map(|copies| copies.find(&metadata.hash)).transpose()?.flatten() else {
+        return Ok(false);
+    };
+    println!("{}", style::added(format!("Copied {:?} from {}", metadata.path, source)));
+    events::record(
+        Some(indexer.sync_root()),
+        events::EventKind::Downloaded,
+        format!("{} (copied from {})", metadata.path.display(), source),
+    );
+    session::write_incoming(indexer, sync_engine, metadata, &content)?;
+    Ok(true)
+}
+
+/// Tells what became of operations a crash interrupted while they were
+/// applied.
+fn report_recovery(root: &std::path::Path, recovery: Option<indexer::Recovery>) {
+    match recovery {
+        Some(indexer::Recovery::RolledBack) => println!(
+            "{}: the last sync was interrupted before its changes were applied, they were dropped",
+            root.display()
+        ),
+        Some(indexer::Recovery::Replayed(steps)) => println!(
+            "{}: finished applying {} change(s) of the last sync, which was interrupted",
+            root.display(),
+            steps
+        ),
+        None => {}
+    }
+}
+
+/// Requests every download up front and writes files as the responses come
+/// back, so the server never waits on a round trip between files. Returns
+/// the bytes received.
+async fn download_files(
+    indexer: &FileIndexer,
+    sync_engine: &SyncEngine,
+    stream: &mut network::Connection,
+    downloads: &[types::FileMetadata],
+    folder_key: Option<&encryption::FolderKey>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    use tokio::io::AsyncWriteExt;
+
+    if downloads.is_empty() {
+        return Ok(0);
+    }
+    // Large files go one at a time as windowed transfers, which resume
+    // where an interrupted download stopped
+    let negotiated = IndexStore::open(indexer.sync_root())?.capabilities()?.unwrap_or_default();
+    let (windowed, downloads): (Vec<&types::FileMetadata>, Vec<&types::FileMetadata>) = downloads
+        .iter()
+        .partition(|metadata| {
+            negotiated.contains(&capabilities::Capability::WindowedTransfer)
+                && metadata.size >= file_transfer::WINDOWED_TRANSFER_SIZE
+        });
+    let limiter = bandwidth::limiter(stream);
+    let (tcp, reader) = stream.parts();
+    let (mut read_half, mut write_half) = tcp.split();
+
+    let requests = async {
+        for metadata in &downloads {
+            let request = NetworkMessage::FileRequest {
+                path: remote_path(metadata, folder_key),
+            };
+            write_half.write_all(&serde_json::to_vec(&request)?).await?;
+        }
+        Ok::<_, types::SyncError>(())
+    };
+
+    let responses = async {
+        // Registered so `syncmd queue cancel` can drop them before they're written
+        let queue = file_transfer::TransferQueue::global();
+        let mut pending: std::collections::HashMap<String, (&types::FileMetadata, file_transfer::TransferHandle)> = downloads
+            .iter()
+            .map(|&metadata| {
+                let id = uuid::Uuid::new_v4().to_string();
+                let handle = queue.register(&id, &indexer.sync_root().join(&metadata.path), metadata.size);
+                (remote_path(metadata, folder_key), (metadata, handle))
+            })
+            .collect();
+        let mut received = 0;
+
+        while !pending.is_empty() {
+            let message = reader.next(&mut read_half).await?
+                .ok_or_else(|| types::SyncError::Network("Server closed the connection during downloads".to_string()))?;
+            let NetworkMessage::FileResponse { path, found, content, .. } = message else {
+                eprintln!("Unexpected message while downloading");
+                continue;
+            };
+            let Some((metadata, handle)) = pending.remove(&path) else {
+                continue;
+            };
+            if handle.is_cancelled() {
+                println!("Cancelled download of {:?}", metadata.path);
+                continue;
+            }
+            let Some(content) = content.filter(|_| found) else {
+                eprintln!("Server no longer has {:?}", metadata.path);
+                continue;
+            };
+            if let Some(limiter) = &limiter {
+                limiter.throttle(content.len()).await;
+            }
+            // Whole files arrive at once, so their progress jumps to the end
+            handle.set_transferred(metadata.size);
+            received += write_download(indexer, sync_engine, metadata, content, folder_key)?;
+        }
+        Ok::<_, types::SyncError>(received)
+    };
+
+    let (sent, received) = tokio::join!(requests, responses);
+    sent?;
+    let mut received = received?;
+
+    for metadata in windowed {
+        let path = remote_path(metadata, folder_key);
+        let destination = indexer.sync_root().join(&metadata.path);
+        let downloaded = network::download_windowed(stream, &path, indexer.sync_root(), &destination, &negotiated).await;
+        let content = match downloaded {
+            Ok(Some(content)) => content,
+            Ok(None) => {
+                eprintln!("Server no longer has {:?}", metadata.path);
+                continue;
+            }
+            Err(types::SyncError::Cancelled(_)) => {
+                println!("Cancelled download of {:?}", metadata.path);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(limiter) = &limiter {
+            limiter.throttle(content.len()).await;
+        }
+        received += write_download(indexer, sync_engine, metadata, content, folder_key)?;
+    }
+    Ok(received)
+}
+
+/// Decrypts a downloaded file if the root is encrypted and writes it,
+/// returning the bytes written. A file that doesn't decrypt is skipped.
+fn write_download(
+    indexer: &FileIndexer,
+    sync_engine: &SyncEngine,
+    metadata: &types::FileMetadata,
+    content: Vec<u8>,
+    folder_key: Option<&encryption::FolderKey>,
+) -> Result<u64, types::SyncError> {
+    match open_download(metadata, content, folder_key) {
+        Ok(content) => {
+            println!("{}", style::added(format!("Downloaded {:?}", metadata.path)));
+            events::record(Some(indexer.sync_root()), events::EventKind::Downloaded, metadata.path.display().to_string());
+            session::write_incoming(indexer, sync_engine, metadata, &content)?;
+            Ok(content.len() as u64)
+        }
+        Err(e) => {
+            eprintln!("{}", style::conflict(format!("{}, skipping", e)));
+            Ok(0)
+        }
+    }
+}
+
+/// Downloads large files from the connected server and the root's
+/// `download_sources` at once. Without other sources, or if none of them
+/// serves chunks, this is a plain download; a file whose swarm download
+/// fails is fetched the plain way too.
+async fn swarm_download_files(
+    indexer: &FileIndexer,
+    sync_engine: &SyncEngine,
+    stream: &mut network::Connection,
+    downloads: &[types::FileMetadata],
+    folder_key: Option<&encryption::FolderKey>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let targets = config.find_sync_root(indexer.sync_root())
+        .map(|root| root.download_sources.clone())
+        .unwrap_or_default();
+    if downloads.is_empty() || targets.is_empty() {
+        return download_files(indexer, sync_engine, stream, downloads, folder_key).await;
+    }
+
+    let network_manager = NetworkManager::new(Arc::new(ClientManager::new()), String::new());
+    let mut extra_sources = Vec::new();
+    for target in &targets {
+        let connected = async {
+            let (mut source, remote) = network_manager.connect_supervised(&config, target).await?;
+            let client_name = config.device_name.clone();
+            let negotiated = authenticate(&mut config, &network_manager, &mut source, client_name).await?;
+            Ok::<_, Box<dyn std::error::Error>>((source, remote.name, negotiated))
+        };
+        match connected.await {
+            Ok((source, name, negotiated)) if negotiated.contains(&capabilities::Capability::ChunkedDownload) => {
+                extra_sources.push((source, name));
+            }
+            Ok((_, name, _)) => eprintln!("{} can't serve chunks, not downloading from it", name),
+            Err(e) => eprintln!("Download source {} unavailable: {}", target, e),
+        }
+    }
+    let primary_chunks = IndexStore::open(indexer.sync_root())?
+        .capabilities()?
+        .is_some_and(|negotiated| negotiated.contains(&capabilities::Capability::ChunkedDownload));
+    let primary_name = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+
+    let mut received = 0;
+    let mut fallback = Vec::new();
+    for metadata in downloads {
+        let mut sources: Vec<swarm::Source> = extra_sources.iter_mut()
+            .map(|(stream, name)| swarm::Source { name: name.clone(), stream })
+            .collect();
+        if primary_chunks {
+            sources.push(swarm::Source { name: primary_name.clone(), stream: &mut *stream });
+        }
+        if sources.len() < 2 {
+            fallback.push(metadata.clone());
+            continue;
+        }
+
+        println!("Downloading {:?} from {} sources", metadata.path, sources.len());
+        let id = uuid::Uuid::new_v4().to_string();
+        let handle = file_transfer::TransferQueue::global()
+            .register(&id, &indexer.sync_root().join(&metadata.path), metadata.size);
+        let partial = file_transfer::partial_path(indexer.sync_root(), &metadata.hash)?;
+        let remote = remote_path(metadata, folder_key);
+        let downloaded = swarm::download(sources, &remote, &metadata.hash, metadata.size, &partial, &handle).await;
+        let (content, stats) = match downloaded {
+            Ok(downloaded) => downloaded,
+            Err(types::SyncError::Cancelled(_)) => {
+                println!("Cancelled download of {:?}", metadata.path);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}", style::conflict(format!("Parallel download of {:?} failed: {}", metadata.path, e)));
+                fallback.push(metadata.clone());
+                continue;
+            }
+        };
+        for source in &stats {
+            match &source.dropped {
+                Some(reason) => println!("  {}: {} chunks, dropped: {}", source.name, source.chunks, reason),
+                None => println!("  {}: {} chunks at {}/s", source.name, source.chunks,
+                    plan::format_size(source.throughput() as u64)),
+            }
+        }
+        received += write_download(indexer, sync_engine, metadata, content, folder_key)?;
+    }
+    Ok(received + download_files(indexer, sync_engine, stream, &fallback, folder_key).await?)
+}
+
 async fn list_clients() -> Result<(), Box<dyn std::error::Error>> {
     let _config = Config::load()?;
-    let client_manager = ClientManager::new();
-    
-    let clients = client_manager.list_clients().await;
+    // Only a running daemon has clients to report
+    let clients = match daemon::query(&daemon::socket_path()?, &daemon::ControlRequest::ListClients).await? {
+        Some(daemon::ControlResponse::Clients { clients }) => clients,
+        _ => Vec::new(),
+    };
     
     if clients.is_empty() {
         println!("No connected clients");
     } else {
         println!("Connected clients:");
         for client in clients {
-            println!("  - {} ({}) at {}", client.name, client.id, client.address);
+            println!("  - {} [{}] ({}) at {}", client.name, types::device_slug(&client.name), client.id, client.address);
         }
     }
     
     Ok(())
 }
 
-async fn show_status() -> Result<(), Box<dyn std::error::Error>> {
+async fn show_status(ignored: bool, verbose: bool) -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::load()?;
     
     println!("Device ID: {}", config.device_id);
-    println!("Device Name: {}", config.device_name);
+    println!("Device Name: {} [{}]", config.device_name, types::device_slug(&config.device_name));
+    let live: std::collections::HashMap<_, _> =
+        match daemon::query(&daemon::socket_path()?, &daemon::ControlRequest::Status).await? {
+            Some(daemon::ControlResponse::Status { pid, started, roots, cleanup }) => {
+                println!("Daemon: running (pid {}) since {}", pid, started.to_rfc2822());
+                if let Some(last_run) = cleanup.last_run {
+                    println!("  cleaned up {} in {} run(s), last at {}", cleanup, cleanup.runs, last_run.to_rfc2822());
+                }
+                roots.into_iter().map(|root| (root.path.clone(), root)).collect()
+            }
+            _ => Default::default(),
+        };
     println!("Sync Roots:");
     
     for root in &config.sync_roots {
-        let status = if root.enabled { "enabled" } else { "disabled" };
-        let last_sync = root.last_sync
-            .map(|t| t.to_rfc2822())
-            .unwrap_or_else(|| "never".to_string());
-        println!("  - {:?} ({}) - last sync: {}", root.path, status, last_sync);
+        if let Some(live) = live.get(&root.path) {
+            let status = if live.missing {
+                style::conflict("missing, waiting for it to return")
+            } else if live.paused {
+                style::dim("paused")
+            } else if let Some(remote) = &live.connected_to {
+                style::added(format!("syncing with {}", remote))
+            } else {
+                style::added("watching")
+            };
+            let last_sync = live.last_sync
+                .map(|t| t.to_rfc2822())
+                .unwrap_or_else(|| "never".to_string());
+            println!("  - {:?} ({}) - {} files - last sync: {}", root.path, status, live.files, last_sync);
+            if let Some(error) = &live.last_error {
+                println!("      last error: {}", style::conflict(error));
+            }
+        } else {
+            let status = if root.enabled { style::added("enabled") } else { style::dim("disabled") };
+            let last_sync = root.last_sync
+                .map(|t| t.to_rfc2822())
+                .unwrap_or_else(|| "never".to_string());
+            println!("  - {:?} ({}) - last sync: {}", root.path, status, last_sync);
+        }
+        if let Some(direction) = root.direction.filter(|direction| *direction != sync::SyncDirection::Both) {
+            println!("      direction: {}", direction.as_str());
+        }
+
+        if verbose && state::dir(&root.path).exists() {
+            let negotiated = IndexStore::open(&root.path)?.capabilities()?;
+            let negotiated = negotiated.as_deref().map(capabilities::describe)
+                .unwrap_or_else(|| "not connected yet".to_string());
+            println!("      capabilities: {}", style::dim(negotiated));
+        }
+
+        if ignored {
+            let indexer = root_indexer(&config, config.device_id.clone(), &root.path);
+            let (_, skipped) = indexer.index_directory_with_skipped()?;
+            if skipped.is_empty() {
+                println!("      nothing excluded");
+            }
+            for file in skipped {
+                println!("      {}: {}", file.path.display(), style::dim(&file.reason));
+            }
+        }
     }
     
     Ok(())
 }
 
+async fn diff_file(
+    path: std::path::PathBuf,
+    remote: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = std::path::absolute(&path)?;
+    let root = config.root_containing(&path)
+        .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?;
+    let root_path = root.path.canonicalize()?;
+    let relative = path.canonicalize().unwrap_or(path.clone()).strip_prefix(&root_path)?.to_path_buf();
+    let indexer = root_indexer(&config, config.device_id.clone(), &root_path);
+
+    let local = match indexer.read_file_content(&relative) {
+        Ok(content) => String::from_utf8(content).map_err(|_| format!("{} is not a text file", relative.display()))?,
+        Err(types::SyncError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let label = relative.display().to_string();
+
+    let (other, other_label) = match remote {
+        Some(target) => {
+            let client_name = config.device_name.clone();
+            let (mut stream, remote, _) = connect_authenticated(&mut config, &target, client_name).await?;
+
+            use tokio::io::AsyncWriteExt;
+            let request = NetworkMessage::FileRequest { path: relative.to_string_lossy().to_string() };
+            stream.write_all(&serde_json::to_vec(&request)?).await?;
+            let content = match stream.read_message().await? {
+                NetworkMessage::FileResponse { found: true, content: Some(content), .. } => content,
+                NetworkMessage::FileResponse { .. } => Vec::new(),
+                _ => return Err("Unexpected response from server".into()),
+            };
+            (String::from_utf8_lossy(&content).to_string(), format!("{} ({})", label, remote.name))
+        }
+        None => {
+            let store = IndexStore::open(&root_path)?;
+            let base = store.base(&relative)?
+                .ok_or_else(|| format!("No last-synced version of {} is recorded", relative.display()))?;
+            let synced_at = base.synced_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+            (String::from_utf8_lossy(&base.content).to_string(), format!("{} (synced {})", label, synced_at))
+        }
+    };
+
+    let diff = text_diff::unified_diff(&other, &local, &other_label, &format!("{} (local)", label), text_diff::DEFAULT_CONTEXT);
+    if diff.is_empty() {
+        println!("No differences");
+    } else {
+        for line in diff.lines() {
+            let line = if line.starts_with("+++") || line.starts_with("---") {
+                style::heading(line)
+            } else if line.starts_with('+') {
+                style::added(line)
+            } else if line.starts_with('-') {
+                style::deleted(line)
+            } else if line.starts_with("@@") {
+                style::dim(line)
+            } else {
+                line.to_string()
+            };
+            println!("{}", line);
+        }
+    }
+
+    Ok(())
+}
+
+async fn manage_device(action: DeviceAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+
+    match action {
+        DeviceAction::Show => {
+            println!("Device ID:   {}", config.device_id);
+            println!("Device Name: {}", config.device_name);
+            println!("Slug:        {}", types::device_slug(&config.device_name));
+        }
+        DeviceAction::Rename { name, connect } => {
+            let old_name = std::mem::replace(&mut config.device_name, name.clone());
+            if old_name == name {
+                println!("Device is already called {}", name);
+                return Ok(());
+            }
+            config.save()?;
+            for root in &config.sync_roots {
+                IndexStore::open(&root.path)?.record_device_rename(&config.device_id, &old_name, &name)?;
+            }
+            println!("Renamed {} to {} [{}]", old_name, name, types::device_slug(&name));
+
+            if let Some(target) = connect {
+                use tokio::io::AsyncWriteExt;
+
+                let (mut stream, remote, _) = connect_authenticated(&mut config, &target, old_name.clone()).await?;
+                let message = NetworkMessage::RenameDevice { old_name, new_name: name };
+                stream.write_all(&serde_json::to_vec(&message)?).await?;
+                println!("Told {} about the new name", remote.name);
+            }
+        }
+        DeviceAction::History => {
+            let mut renames = Vec::new();
+            for root in &config.sync_roots {
+                renames.extend(IndexStore::open(&root.path)?.device_renames()?);
+            }
+            // Every root records the same rename
+            renames.sort_by_key(|r| r.timestamp);
+            renames.dedup_by(|a, b| {
+                a.old_name == b.old_name && a.new_name == b.new_name && (a.timestamp - b.timestamp).num_seconds().abs() < 5
+            });
+            if renames.is_empty() {
+                println!("No renames recorded");
+            }
+            for rename in renames {
+                println!("{}  {} -> {} [{}]",
+                    style::dim(rename.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M")),
+                    rename.old_name, rename.new_name, types::device_slug(&rename.new_name));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn manage_filters(action: FiltersAction) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+
+    match action {
+        FiltersAction::Explain { path } => {
+            let path = match path {
+                Some(path) => path,
+                None => std::env::current_dir()?,
+            };
+            let path = path.canonicalize()?;
+            let root = config.root_containing(&path)
+                .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?;
+            let root_path = root.path.canonicalize()?;
+            let relative = path.strip_prefix(&root_path)?;
+            let indexer = root_indexer(&config, config.device_id.clone(), &root_path);
+
+            if path.is_dir() {
+                let (_, skipped) = indexer.index_directory_with_skipped()?;
+                let mut excluded = 0;
+                for file in skipped.iter().filter(|f| f.path.starts_with(relative) || relative.starts_with(&f.path)) {
+                    println!("{}: {}", file.path.display(), style::dim(&file.reason));
+                    excluded += 1;
+                }
+                if excluded == 0 {
+                    println!("Nothing under {} is excluded", path.display());
+                }
+            } else {
+                match indexer.explain(relative)? {
+                    None => println!("{} is {}", relative.display(), style::added("synced")),
+                    Some(reason) => println!("{} is {}: {}", relative.display(), style::conflict("excluded"), reason),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
 
 async fn init_config(
     path: std::path::PathBuf,
     name: String,
     auth_token: Option<String>,
+    template: Option<templates::RootTemplate>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut config = Config::load()?;
     config.device_name = name;
     
     if let Some(token) = auth_token {
         println!("Setting up authentication...");
+        config.unlock_secrets()?;
         config.auth_token = Some(token);
         println!("Authentication token configured");
     }
     
+    if let Some(template) = template {
+        let created = template.apply(&path)?;
+        println!("Applied {} template ({} items created)", template.label(), created.len());
+        for item in &created {
+            println!("  + {}", item.display());
+        }
+    }
+    
     config.add_sync_root(path);
+    if let (Some(template), Some(root)) = (template, config.sync_roots.last_mut()) {
+        let policies = template.policies();
+        root.template = Some(template);
+        root.conflict_strategy = Some(policies.conflict_strategy);
+        root.categories = policies.categories;
+    }
     config.save()?;
     
     println!("Configuration initialized successfully");
@@ -290,6 +1240,1690 @@ async fn init_config(
     Ok(())
 }
 
+async fn import_folder(
+    from: import::ImportSource,
+    path: std::path::PathBuf,
+    exclude_from: Option<std::path::PathBuf>,
+    no_ignores: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = path.canonicalize()?;
+
+    println!("Importing {} folder: {:?}", from.label(), path);
+    let report = import::import_folder(from, &path, &config.device_id, exclude_from.as_deref(), !no_ignores)?;
+
+    if report.ignore_rules_imported > 0 {
+        println!("Converted {} ignore rules into {}", report.ignore_rules_imported, ignore::IGNORE_FILE_NAME);
+    }
+    for line in &report.ignore_lines_skipped {
+        println!("  Skipped unsupported ignore rule: {}", line);
+    }
+    println!("Seeded index with {} files", report.files_indexed);
+    println!("Files already identical on the server are matched by hash and won't be re-transferred");
+
+    if config.get_sync_root(&path).is_none() {
+        config.add_sync_root(path);
+        config.save()?;
+        println!("Registered sync root in configuration");
+    } else {
+        println!("Sync root already registered");
+    }
+
+    Ok(())
+}
+
+async fn export_batch(
+    path: std::path::PathBuf,
+    since: u64,
+    format: export::ExportFormat,
+    out: std::path::PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let indexer = root_indexer(&config, config.device_id.clone(), &path);
+    let report = export::export_batch(&indexer, since, format, &out)?;
+
+    println!("Exported changes {}..{} to {:?}", report.since, report.until, report.out_dir);
+    if report.full {
+        println!("  Journal was compacted past --since {}, exported all files", since);
+    }
+    println!("  {} changed files ({})", report.changed.len(), export::FILE_LIST_NAME);
+    println!("  {} deleted files ({})", report.deleted.len(), export::DELETED_LIST_NAME);
+    match format {
+        export::ExportFormat::FilesFrom => {
+            println!("Mirror with: rsync -a --files-from={} {:?} <target>",
+                out.join(export::FILE_LIST_NAME).display(), path);
+        }
+        export::ExportFormat::Tar => {
+            println!("Archive: {}", out.join(export::ARCHIVE_NAME).display());
+        }
+    }
+    println!("Next incremental export: --since {}", report.until);
+
+    Ok(())
+}
+
+fn manage_manifest(action: ManifestAction) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        ManifestAction::Export { path, out } => {
+            let config = Config::load()?;
+            let indexer = root_indexer(&config, config.device_id.clone(), &path);
+            // Describes the files as they are now, not as last synced
+            let state = indexer.index_directory()?;
+            IndexStore::open(&path)?.save_state(&state)?;
+
+            let text = manifest::Manifest::from_files(state.local_files.values()).to_text();
+            let identity = discovery::DeviceIdentity::load_or_create()?;
+            let signature = manifest::sign(&text, &identity, &config.device_id, &config.device_name);
+            std::fs::write(&out, &text)?;
+            let signature_path = manifest::signature_path(&out);
+            std::fs::write(&signature_path, serde_json::to_string_pretty(&signature)?)?;
+            println!("Wrote the hashes of {} file(s) to {}", state.local_files.len(), out.display());
+            println!("Signature: {}", signature_path.display());
+        }
+        ManifestAction::Verify { path, manifest: manifest_path } => {
+            let text = std::fs::read_to_string(&manifest_path)?;
+            let manifest = manifest::Manifest::parse(&text)?;
+            match std::fs::read(manifest::signature_path(&manifest_path)) {
+                Ok(signature) => {
+                    let signature: manifest::ManifestSignature = serde_json::from_slice(&signature)?;
+                    manifest::verify_signature(&text, &signature)
+                        .map_err(|_| types::SyncError::Auth("The manifest doesn't match its signature".to_string()))?;
+                    let created = signature.created.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+                    println!("Signed by {} ({}) on {}", signature.device_name, signature.device_id, created);
+                    println!("  {}", style::dim(format!("key {}", signature.public_key)));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    eprintln!("{}", style::conflict("The manifest isn't signed, checking the hashes only"));
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            let report = manifest.verify(&path)?;
+            for missing in &report.missing {
+                println!("{} {}", style::deleted("missing"), missing.display());
+            }
+            for changed in &report.changed {
+                println!("{} {}", style::conflict("changed"), changed.display());
+            }
+            println!("{} of {} file(s) match", report.matched, manifest.entries.len());
+            if !report.is_ok() {
+                return Err(types::SyncError::Partial(format!(
+                    "{} missing and {} changed file(s) in {}",
+                    report.missing.len(),
+                    report.changed.len(),
+                    path.display()
+                )).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn compact_journal(
+    path: std::path::PathBuf,
+    retention_days: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = IndexStore::open(&path)?;
+    let report = store.compact(chrono::Duration::days(retention_days))?;
+
+    match report.snapshot_seq {
+        Some(seq) => println!("Snapshot taken at sequence {}", seq),
+        None => println!("Latest snapshot is up to date"),
+    }
+    println!("Removed {} journal entries older than {} days", report.journal_entries_removed, retention_days);
+    println!("Removed {} old snapshots", report.snapshots_removed);
+    if report.compacted_through > 0 {
+        println!("Incremental cursors must be at least {}", report.compacted_through);
+    }
+    for snapshot in store.snapshots()? {
+        println!("  snapshot {} at seq {} ({}, {} files)",
+            snapshot.id, snapshot.seq, snapshot.timestamp.format("%Y-%m-%d %H:%M"), snapshot.file_count);
+    }
+
+    Ok(())
+}
+
+/// Tries of a mirror run to a server before a lost connection is an error
+const MIRROR_ATTEMPTS: u32 = 3;
+
+async fn mirror_folder(
+    path: std::path::PathBuf,
+    to: Option<std::path::PathBuf>,
+    remote: Option<String>,
+    options: mirror::MirrorOptions,
+    report_path: Option<std::path::PathBuf>,
+    every: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = path.canonicalize()?;
+    if let Some(to) = &to {
+        let to = std::path::absolute(to)?;
+        if to.starts_with(&path) || path.starts_with(&to) {
+            return Err("The backup target can't be inside the folder or contain it".into());
+        }
+    }
+    let indexer = root_indexer(&config, config.device_id.clone(), &path);
+    // Backups to a directory stay plaintext, they don't leave the device's
+    // control the way uploads do
+    let folder_key = if remote.is_some() { folder_key(&config, &path)? } else { None };
+
+    loop {
+        let report = match (&to, &remote) {
+            (Some(to), _) => tokio::task::block_in_place(|| mirror::mirror_to_directory(&indexer, to, options))?,
+            (None, Some(target)) => {
+                // Lost connections are retried, with the same upload keys
+                // so nothing the server got before is stored twice
+                let mut keys = mirror::UploadKeys::default();
+                let mut backoff = backoff::Backoff::reconnect();
+                loop {
+                    let attempt = async {
+                        let (mut stream, remote, negotiated) = connect_authenticated(&mut config, target, "syncmd-mirror".to_string()).await?;
+                        let report = mirror::mirror_to_remote(&indexer, &mut stream, &remote.name, options, folder_key.as_ref(), &negotiated, &mut keys).await?;
+                        Ok::<_, Box<dyn std::error::Error>>(report)
+                    };
+                    match attempt.await {
+                        Err(e) if backoff.failures() < MIRROR_ATTEMPTS - 1
+                            && exit_codes::FailureKind::of(e.as_ref()) == exit_codes::FailureKind::Network => {
+                            let delay = backoff.next_delay();
+                            eprintln!("Mirroring to {} failed: {}; retrying in {}s", target, e, delay.as_secs());
+                            tokio::time::sleep(delay).await;
+                        }
+                        result => break result?,
+                    }
+                }
+            }
+            (None, None) => return Err("Either --to or --remote is required".into()),
+        };
+
+        println!("{}", style::heading(format!("Mirrored {} to {}", path.display(), report.target)));
+        for file in &report.copied {
+            println!("  {}", style::added(format!("copied   {}", file.display())));
+        }
+        for file in &report.updated {
+            println!("  {}", style::added(format!("updated  {}", file.display())));
+        }
+        for file in &report.deleted {
+            println!("  {}", style::deleted(format!("deleted  {}", file.display())));
+        }
+        for file in &report.corrupt {
+            println!("  {}", style::conflict(format!("corrupt  {}", file.display())));
+        }
+        for (file, error) in &report.failed {
+            println!("  {}", style::conflict(format!("failed   {}: {}", file.display(), error)));
+        }
+        println!("{} copied, {} updated, {} deleted, {} unchanged, {} bytes written",
+            report.copied.len(), report.updated.len(), report.deleted.len(), report.unchanged, report.bytes);
+        if let Some(report_path) = &report_path {
+            std::fs::write(report_path, serde_json::to_vec_pretty(&report)?)?;
+        }
+
+        match every {
+            // A scheduled mirror reports problems and tries again next time
+            Some(minutes) => {
+                if !report.is_clean() {
+                    eprintln!("{}", style::conflict("Mirror finished with errors"));
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(minutes * 60)).await;
+            }
+            None if report.is_clean() => return Ok(()),
+            None => return Err(types::SyncError::Partial(format!(
+                "{} file(s) failed, {} corrupt", report.failed.len(), report.corrupt.len()
+            )).into()),
+        }
+    }
+}
+
+async fn pair(action: PairAction) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let passphrase = pairing::read_passphrase()?;
+    let (stream, role, path) = match action {
+        PairAction::Listen { path, port } => {
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+            println!("Waiting for a device on port {}...", port);
+            let (stream, address) = listener.accept().await?;
+            println!("Connection from {}", address);
+            (stream, pairing::PairRole::Responder, path)
+        }
+        PairAction::Connect { address, path } => {
+            // The pairing channel is sealed with the session key, TLS would
+            // add nothing
+            let remote = remote::Remote {
+                tls: remote::TlsMode::Disabled,
+                ..remote::Remote::from_address(&address)
+            };
+            let stream = remote.connect().await?;
+            (stream, pairing::PairRole::Initiator, path)
+        }
+    };
+    if !path.is_dir() {
+        return Err(format!("{} is not a directory", path.display()).into());
+    }
+    let indexer = root_indexer(&config, config.device_id.clone(), &path);
+
+    let session = pairing::PairSession::establish(stream, role, &passphrase, &config.device_name).await?;
+    println!("Paired with {}", style::heading(&session.peer_name));
+
+    let summary = session.sync(&indexer).await?;
+    println!("{}", style::added(format!(
+        "Received {} files ({} bytes), sent {} files",
+        summary.received_files, summary.received_bytes, summary.sent_files
+    )));
+    Ok(())
+}
+
+/// Runs the daemon, watching each root with what `watchers` builds for it.
+async fn run_daemon(
+    connect: Option<String>,
+    port: Option<u16>,
+    watchers: watcher::WatcherFactory,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    if connect.is_some() {
+        config.unlock_secrets()?;
+    }
+    let roots: Vec<std::path::PathBuf> = config.sync_roots.iter()
+        .filter(|root| root.enabled)
+        .map(|root| root.path.clone())
+        .collect();
+    if roots.is_empty() {
+        return Err(types::SyncError::Config("No enabled sync roots, add one with `syncmd init`".to_string()).into());
+    }
+
+    let client_manager = Arc::new(ClientManager::new());
+    let state = daemon::DaemonState::new(client_manager.clone());
+    let socket = daemon::socket_path()?;
+    let listener = daemon::bind(&socket).await?;
+    println!("Daemon started, control socket at {}", socket.display());
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            if let Err(e) = daemon::serve(state, listener).await {
+                eprintln!("Control socket error: {}", e);
+            }
+        }
+    });
+
+    if let Some(port) = port {
+        let network_manager = NetworkManager::new(client_manager, format!("0.0.0.0:{}", port));
+        tokio::spawn(async move {
+            if let Err(e) = network_manager.start_server().await {
+                eprintln!("Server error: {}", e);
+            }
+        });
+    }
+
+    for path in roots {
+        println!("Syncing {}", path.display());
+        // Asked for up front, the sync tasks can't share the terminal
+        let folder_key = if connect.is_some() { folder_key(&config, &path)? } else { None };
+        state.add_root(path.clone());
+        let config = config.clone();
+        let connect = connect.clone();
+        let state = state.clone();
+        let watchers = watchers.clone();
+        tokio::spawn(async move {
+            if let Err(e) = daemon_sync_root(config, path.clone(), connect, folder_key, state.clone(), watchers).await {
+                let message = e.to_string();
+                eprintln!("Stopped syncing {}: {}", path.display(), message);
+                state.update(&path, |status| {
+                    status.last_error = Some(message);
+                    status.stopped = true;
+                });
+            }
+        });
+    }
+    tokio::spawn(daemon_cleanup(state.clone()));
+    tokio::spawn(daemon_telemetry());
+    tokio::spawn(daemon_open_files(state.root_paths()));
+
+    tokio::select! {
+        result = signal::ctrl_c() => result?,
+        _ = state.shutdown_requested() => {}
+    }
+    println!("Syncing pending changes before stopping...");
+    let generation = state.request_flush();
+    if !state.wait_flushed(generation, std::time::Duration::from_secs(30)).await {
+        eprintln!("Gave up waiting for the last changes to sync");
+    }
+    let _ = std::fs::remove_file(&socket);
+    println!("Daemon stopped");
+    Ok(())
+}
+
+/// Removes partial downloads nobody resumed from the daemon's roots and
+/// expired sessions and idle clients from its server, every
+/// [`maintenance::CLEANUP_INTERVAL`]. The totals show up in `syncmd status`.
+async fn daemon_cleanup(state: daemon::DaemonState) {
+    let mut interval = tokio::time::interval(maintenance::CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let run = maintenance::run(state.clients(), &state.root_paths()).await;
+        if run.removed() > 0 {
+            tracing::info!("Cleanup removed {}", run);
+        }
+        state.record_cleanup(&run);
+    }
+}
+
+/// Looks for files held open under the daemon's roots every
+/// [`open_files::SCAN_INTERVAL`], so they sync first.
+async fn daemon_open_files(roots: Vec<std::path::PathBuf>) {
+    let mut interval = tokio::time::interval(open_files::SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        let roots = roots.clone();
+        if let Ok(open) = tokio::task::spawn_blocking(move || open_files::scan(&roots)).await {
+            open_files::OpenFiles::global().set_scanned(open);
+        }
+    }
+}
+
+/// Sends the telemetry report once it's due, if the user turned telemetry
+/// on. The config is read every time, so enabling it takes effect without a
+/// restart.
+async fn daemon_telemetry() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        let Some(settings) = Config::load().ok().and_then(|config| config.telemetry) else {
+            continue;
+        };
+        match telemetry::send_if_due(&settings).await {
+            Ok(true) => tracing::info!("Sent telemetry report to {}", settings.endpoint),
+            Ok(false) => {}
+            Err(e) => tracing::warn!("Could not send telemetry report: {}", e),
+        }
+    }
+}
+
+/// Keeps one root in sync for the daemon: once changed files have been quiet
+/// for the coalescing window, when the server pushes changes from other
+/// devices, on the root's schedule and when a flush is requested,
+/// unless paused, the index is refreshed and, with a remote, synced.
+/// During the schedule's quiet hours only flushes run.
+/// A failed round drops the connection, and rounds keep coming with
+/// exponential backoff until one connects again.
+///
+/// Before the watcher starts, the persisted index is compared with the disk
+/// so edits made while the daemon wasn't running go out in the first round.
+///
+/// A paused root isn't watched. A root that goes away, e.g. on an unmounted
+/// drive, is shown as missing and skipped until it returns. Either way it is
+/// watched anew afterwards and the next round catches up with what changed
+/// meanwhile.
+async fn daemon_sync_root(
+    mut config: Config,
+    path: std::path::PathBuf,
+    connect: Option<String>,
+    folder_key: Option<encryption::FolderKey>,
+    state: daemon::DaemonState,
+    watchers: watcher::WatcherFactory,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Nothing is touched before the root is there, not even its state
+    wait_for_root(&path, 0, &state).await;
+    let indexer = root_indexer(&config, config.device_id.clone(), &path);
+    report_recovery(&path, indexer.recover_apply()?);
+    let sync_engine = root_engine(&config, config.device_id.clone(), &path);
+    let network_manager = NetworkManager::new(Arc::new(ClientManager::new()), String::new());
+    let persisted = IndexStore::open(&path)?.load_state(config.device_id.clone(), path.clone())?;
+    // A central state directory outlives an unmounted root
+    wait_for_root(&path, persisted.local_files.len(), &state).await;
+    let (mut known_state, offline_changes) = tokio::task::block_in_place(|| indexer.reconcile(&persisted))?;
+    // Files that left the selection weren't deleted
+    let offline_changes = sync_engine.select_operations(offline_changes);
+    if !persisted.local_files.is_empty() && !offline_changes.is_empty() {
+        println!("{}: {} change(s) made while syncmd wasn't running", path.display(), offline_changes.len());
+        for operation in &offline_changes {
+            tracing::debug!("Offline change: {:?}", operation);
+        }
+    }
+    IndexStore::open(&path)?.save_state(&known_state)?;
+    state.update(&path, |status| status.files = known_state.local_files.len());
+    let mut file_watcher = Some(watchers(&config, &path)?);
+    let scheduler = schedule::Scheduler::new(config.find_sync_root(&path).and_then(|root| root.schedule.as_ref()))?;
+    // The first round runs right away
+    let mut round_at = Some(tokio::time::Instant::now());
+    let mut held_back = false;
+    let mut root_check = tokio::time::interval(watcher::ROOT_CHECK_INTERVAL);
+    let offline_search = folder_key.is_none() && config.find_sync_root(&path).is_some_and(|root| root.offline_search);
+    let mut connection: Option<(network::Connection, String, Vec<capabilities::Capability>)> = None;
+    let mut backoff = backoff::Backoff::reconnect();
+    let mut retry_at: Option<tokio::time::Instant> = None;
+    let mut flush_requests = state.flush_requests();
+    let mut pause_changes = state.pause_changes();
+    let mut root_missing = false;
+    // Large roots skip files in unchanged directories between deep scans,
+    // relying on the watcher for edits in place. Reconciling just walked
+    // the root in full, so the next deep scan is an interval away
+    let mut directories = Some(IndexStore::open(&path)?.load_directories()?);
+    let mut changed = std::collections::HashSet::new();
+    let mut deep_scan_at = tokio::time::Instant::now() + indexer::DEEP_SCAN_INTERVAL;
+    let server_changed = Arc::new(tokio::sync::Notify::new());
+    // Ends with this function if the root stops syncing
+    let _watch = connect.clone().map(|target| {
+        AbortOnDrop(tokio::spawn(watch_server_changes(
+            config.clone(),
+            path.clone(),
+            target,
+            folder_key.clone(),
+            server_changed.clone(),
+        )))
+    });
+
+    loop {
+        let mut flush = None;
+        tokio::select! {
+            _ = tokio::time::sleep_until(round_at.unwrap_or_else(tokio::time::Instant::now)), if round_at.is_some() => {
+                round_at = scheduler.next_delay().map(|delay| tokio::time::Instant::now() + delay);
+            }
+            Some(changes) = next_changes(&mut file_watcher) => changed.extend(changes),
+            _ = root_check.tick() => {
+                // Only the root going away or coming back is worth a round
+                if watcher::root_present(&path, known_state.local_files.len()) != root_missing {
+                    continue;
+                }
+            }
+            Ok(()) = pause_changes.changed() => {
+                pause_changes.borrow_and_update();
+            }
+            _ = server_changed.notified() => {}
+            _ = tokio::time::sleep_until(retry_at.unwrap_or_else(tokio::time::Instant::now)), if retry_at.is_some() => {
+                retry_at = None;
+            }
+            Ok(()) = flush_requests.changed() => {
+                flush = Some(*flush_requests.borrow_and_update());
+            }
+        }
+        if state.is_paused(&path) {
+            // Nothing is watched while paused, so a large reorganization
+            // doesn't pile up events; resuming indexes the whole root
+            if file_watcher.take().is_some() {
+                println!("Paused {}", path.display());
+                events::record(Some(&path), events::EventKind::Paused, "Paused, changes are picked up on resume");
+            }
+            continue;
+        }
+        if !watcher::root_present(&path, known_state.local_files.len()) {
+            file_watcher = None;
+            if !root_missing {
+                root_missing = true;
+                set_root_missing(&path, &state, true);
+            }
+            // The status says why nothing was synced
+            if let Some(generation) = flush {
+                state.update(&path, |status| status.flushed = generation);
+            }
+            continue;
+        }
+        if file_watcher.is_none() {
+            // The old watch ended with the root or the pause, and this
+            // round's indexing picks up what changed meanwhile
+            file_watcher = Some(watchers(&config, &path)?);
+            deep_scan_at = tokio::time::Instant::now();
+            if root_missing {
+                root_missing = false;
+                set_root_missing(&path, &state, false);
+            } else {
+                println!("Resumed {}, catching up with changes", path.display());
+                events::record(Some(&path), events::EventKind::Resumed, "Resumed");
+            }
+        }
+        if flush.is_none() && scheduler.is_quiet_now() {
+            if !held_back {
+                held_back = true;
+                println!("Quiet hours for {}, changes wait for the next scheduled round", path.display());
+            }
+            continue;
+        }
+        held_back = false;
+        // The round indexes the whole root, held back files included
+        if let Some(file_watcher) = file_watcher.as_mut() {
+            changed.extend(file_watcher.take_pending());
+        }
+
+        let deep = known_state.local_files.len() < indexer::LARGE_ROOT_FILES || tokio::time::Instant::now() >= deep_scan_at;
+        if deep {
+            deep_scan_at = tokio::time::Instant::now() + indexer::DEEP_SCAN_INTERVAL;
+        }
+        let (sync_state, stamps) = tokio::task::block_in_place(|| {
+            indexer.index_directory_since(&known_state, directories.as_ref().filter(|_| !deep), &changed)
+        })?;
+        changed.clear();
+        let mut store = IndexStore::open(&path)?;
+        store.save_state(&sync_state)?;
+        store.save_directories(&stamps)?;
+        directories = Some(stamps);
+        state.update(&path, |status| status.files = sync_state.local_files.len());
+        known_state = sync_state.clone();
+        let Some(target) = &connect else {
+            if let Some(generation) = flush {
+                state.update(&path, |status| status.flushed = generation);
+            }
+            continue;
+        };
+
+        let round = async {
+            if connection.is_none() {
+                let (mut stream, remote) = network_manager.connect_supervised(&config, target).await?;
+                let client_name = config.device_name.clone();
+                let negotiated = authenticate(&mut config, &network_manager, &mut stream, client_name).await?;
+                IndexStore::open(&path)?.record_capabilities(&negotiated)?;
+                send_subscriptions(&config, &path, &mut stream, folder_key.as_ref()).await?;
+                events::record(Some(&path), events::EventKind::Connected, format!("Connected to {}", remote.name));
+                connection = Some((stream, remote.name, negotiated));
+            }
+            let (stream, _, negotiated) = connection.as_mut().expect("connected above");
+            let (operations, inline) = request_operations(&sync_state, stream, folder_key.as_ref(), negotiated).await?;
+            let operations = sync_engine.incoming_operations(operations);
+            apply_operations(&indexer, &sync_engine, stream, operations, inline, folder_key.as_ref()).await?;
+            if offline_search {
+                refresh_search_index(&mut IndexStore::open(&path)?, stream, negotiated).await?;
+            }
+            replicate_server_journal(&mut IndexStore::open(&path)?, stream, negotiated, folder_key.as_ref()).await?;
+            Ok::<_, Box<dyn std::error::Error>>(())
+        };
+        let result = round.await.map_err(|e| e.to_string());
+        record_round(&result);
+        if let Err(message) = &result {
+            if let Some((_, remote, _)) = connection.take() {
+                events::record(Some(&path), events::EventKind::Disconnected, format!("Lost the connection to {}", remote));
+            }
+            events::record(Some(&path), events::EventKind::Error, message.clone());
+            retry_at = Some(tokio::time::Instant::now() + backoff.next_delay());
+        } else {
+            backoff.reset();
+            retry_at = None;
+        }
+        let connected_to = connection.as_ref().map(|(_, name, _)| name.clone());
+        state.update(&path, |status| {
+            match result {
+                Ok(()) => {
+                    status.connected_to = connected_to;
+                    status.last_sync = Some(chrono::Utc::now());
+                    status.last_error = None;
+                }
+                Err(message) => {
+                    status.connected_to = None;
+                    status.last_error = Some(message);
+                }
+            }
+            // A failed round still answers the flush, the error is in the status
+            if let Some(generation) = flush {
+                status.flushed = generation;
+            }
+        });
+    }
+}
+
+/// Sends a request answered with a message to the running daemon.
+async fn control_daemon(request: daemon::ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    match daemon::query(&daemon::socket_path()?, &request).await? {
+        Some(daemon::ControlResponse::Done { message }) => {
+            println!("{}", message);
+            Ok(())
+        }
+        Some(daemon::ControlResponse::Error { message }) => Err(message.into()),
+        Some(_) => Err("Unexpected response from the daemon".into()),
+        None => Err("The daemon isn't running, start it with `syncmd daemon`".into()),
+    }
+}
+
+fn manage_telemetry(action: TelemetryAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    match action {
+        TelemetryAction::Status => {
+            match &config.telemetry {
+                Some(settings) if settings.enabled => println!("Telemetry is on, reports go to {}", settings.endpoint),
+                _ => println!("Telemetry is off, nothing is collected or sent"),
+            }
+            if let Some(collected) = telemetry::load()? {
+                if let Some(last_sent) = collected.last_sent {
+                    println!("Last report sent {}", last_sent.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"));
+                }
+                println!("The next report, sent by the daemon once a day:");
+                println!("{}", serde_json::to_string_pretty(&collected.report())?);
+            }
+        }
+        TelemetryAction::Enable { endpoint } => {
+            let endpoint = match (endpoint, &config.telemetry) {
+                (Some(endpoint), _) => endpoint,
+                (None, Some(settings)) => settings.endpoint.clone(),
+                (None, None) => {
+                    return Err(types::SyncError::Config("No telemetry endpoint yet, pass one with --endpoint".to_string()).into());
+                }
+            };
+            telemetry::parse_endpoint(&endpoint)?;
+            config.telemetry = Some(telemetry::TelemetrySettings { enabled: true, endpoint: endpoint.clone() });
+            config.save()?;
+            telemetry::start()?;
+            println!("Telemetry enabled, the daemon sends counts of sync rounds and conflicts to {} once a day", endpoint);
+            println!("See what is sent with `syncmd telemetry status`");
+        }
+        TelemetryAction::Disable => {
+            if let Some(settings) = &mut config.telemetry {
+                settings.enabled = false;
+                config.save()?;
+            }
+            telemetry::stop()?;
+            println!("Telemetry disabled, collected counts were deleted");
+        }
+    }
+    Ok(())
+}
+
+async fn manage_queue(action: QueueAction) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        QueueAction::List => match daemon::query(&daemon::socket_path()?, &daemon::ControlRequest::Transfers).await? {
+            Some(daemon::ControlResponse::Transfers { transfers }) => {
+                if transfers.is_empty() {
+                    println!("No transfers in progress");
+                }
+                for transfer in &transfers {
+                    let started = transfer.started.with_timezone(&chrono::Local).format("%H:%M:%S");
+                    println!("{}  {} {}", transfer.id, transfer.path.display(),
+                        style::dim(format!("({}, started {})", plan::format_size(transfer.size), started)));
+                }
+                Ok(())
+            }
+            Some(daemon::ControlResponse::Error { message }) => Err(message.into()),
+            Some(_) => Err("Unexpected response from the daemon".into()),
+            None => Err("The daemon isn't running, start it with `syncmd daemon`".into()),
+        },
+        QueueAction::Cancel { target } => control_daemon(daemon::ControlRequest::CancelTransfer { target }).await,
+    }
+}
+
+/// Maps a folder given on the command line to the sync root it names.
+fn configured_root(path: Option<std::path::PathBuf>) -> Result<Option<std::path::PathBuf>, Box<dyn std::error::Error>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let config = Config::load()?;
+    let root = config.find_sync_root(&path)
+        .ok_or_else(|| format!("{} is not a configured sync root", path.display()))?;
+    Ok(Some(root.path.clone()))
+}
+
+async fn search_notes(
+    query: String,
+    path: Option<std::path::PathBuf>,
+    connect: Option<String>,
+    limit: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = std::path::absolute(path.unwrap_or(std::env::current_dir()?))?;
+    let root = config.root_containing(&path)
+        .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?
+        .path.clone();
+    let mut store = IndexStore::open(&root)?;
+
+    if connect.is_some() && config.find_sync_root(&root).is_some_and(|root| root.encryption.is_some()) {
+        eprintln!("{} is encrypted, so the server has no search index for it; searching local files only", root.display());
+    } else if let Some(target) = connect {
+        let network_manager = NetworkManager::new(Arc::new(ClientManager::new()), String::new());
+        let (mut stream, remote) = network_manager.connect_supervised(&config, &target).await?;
+        let client_name = config.device_name.clone();
+        let negotiated = authenticate(&mut config, &network_manager, &mut stream, client_name).await?;
+        send_subscriptions(&config, &root, &mut stream, None).await?;
+        match refresh_search_index(&mut store, &mut stream, &negotiated).await? {
+            Some(updated) => println!("Updated {} search index entries from {}", updated, remote.name),
+            None => eprintln!("{} doesn't offer a search index, searching local files only", remote.name),
+        }
+    }
+
+    // Files on this device are indexed from disk, so edits not yet synced
+    // are found too
+    let indexer = root_indexer(&config, config.device_id.clone(), &root);
+    let local_files = tokio::task::block_in_place(|| indexer.index_directory())?.local_files;
+    let known = store.search_hashes()?;
+    let entries: Vec<search::SearchEntry> = local_files.values()
+        .filter(|metadata| known.get(&metadata.path) != Some(&metadata.hash))
+        .filter_map(|metadata| {
+            let content = indexer.read_file_content(&metadata.path).ok()?;
+            search::SearchEntry::from_content(metadata.path.clone(), metadata.hash.clone(), &content)
+        })
+        .collect();
+    store.update_search_index(&entries, &[])?;
+
+    let results = store.search(&query, limit)?;
+    if results.is_empty() {
+        println!("No matches for {:?}", query);
+    }
+    for result in results {
+        if local_files.contains_key(&result) {
+            println!("{}", result.display());
+        } else {
+            println!("{} {}", result.display(), style::dim("(not on this device)"));
+        }
+    }
+    Ok(())
+}
+
+/// Asks the server for a download link to a file or folder of a root. The
+/// link is for the server's version, so changes not yet synced aren't in it.
+async fn share_link(
+    path: std::path::PathBuf,
+    connect: String,
+    expires: std::time::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = std::path::absolute(path)?;
+    let root = config.root_containing(&path)
+        .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?;
+    if root.encryption.is_some() {
+        return Err(format!("{} is encrypted, so the server can't serve its files", root.path.display()).into());
+    }
+    let relative = path.strip_prefix(&root.path)?.to_string_lossy().to_string();
+
+    let client_name = config.device_name.clone();
+    let (mut stream, remote, negotiated) = connect_authenticated(&mut config, &connect, client_name).await?;
+    if !negotiated.contains(&capabilities::Capability::ShareLinks) {
+        return Err(format!("{} doesn't issue share links", remote.name).into());
+    }
+    let link = network::request_share_link(&mut stream, relative, expires).await?;
+    let host = remote.address.rsplit_once(':').map_or(remote.address.as_str(), |(host, _)| host);
+    println!("http://{}:{}{}", host, link.port, link.target);
+    println!("{}", style::dim(format!("Works until {}", link.expires.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"))));
+    Ok(())
+}
+
+/// Downloads word lists for files the local search index lacks or has an
+/// older version of. Returns how many entries changed, or `None` if the
+/// server doesn't offer a search index.
+async fn refresh_search_index(
+    store: &mut IndexStore,
+    stream: &mut network::Connection,
+    negotiated: &[capabilities::Capability],
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    if !negotiated.contains(&capabilities::Capability::SearchIndex) {
+        return Ok(None);
+    }
+    let (entries, removed) = network::fetch_search_index(stream, store.search_hashes()?).await?;
+    store.update_search_index(&entries, &removed)?;
+    Ok(Some(entries.len() + removed.len()))
+}
+
+/// Replicates the server's journal entries this root doesn't have yet.
+/// Returns how many arrived, or `None` if the server doesn't offer its
+/// journal.
+async fn replicate_server_journal(
+    store: &mut IndexStore,
+    stream: &mut network::Connection,
+    negotiated: &[capabilities::Capability],
+    folder_key: Option<&encryption::FolderKey>,
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    if !negotiated.contains(&capabilities::Capability::JournalReplication) {
+        return Ok(None);
+    }
+    let cursor = store.server_journal_cursor()?;
+    let received = network::replicate_journal(stream, cursor, |mut entries| {
+        if let Some(key) = folder_key {
+            for entry in &mut entries {
+                entry.path = key.decrypt_path(&entry.path).unwrap_or_else(|_| entry.path.clone());
+            }
+        }
+        store.store_server_journal(&entries)
+    }).await?;
+    Ok(Some(received))
+}
+
+/// Lists a root's journal, or the server's journal replicated into it with
+/// `all_devices`, naming devices this one knows.
+async fn show_history(
+    path: Option<std::path::PathBuf>,
+    all_devices: bool,
+    connect: Option<String>,
+    limit: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = std::path::absolute(path.unwrap_or(std::env::current_dir()?))?;
+    let root = config.root_containing(&path)
+        .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?
+        .path.clone();
+    let mut store = IndexStore::open(&root)?;
+
+    if let Some(target) = connect {
+        let folder_key = folder_key(&config, &root)?;
+        let client_name = config.device_name.clone();
+        let (mut stream, remote, negotiated) = connect_authenticated(&mut config, &target, client_name).await?;
+        send_subscriptions(&config, &root, &mut stream, folder_key.as_ref()).await?;
+        match replicate_server_journal(&mut store, &mut stream, &negotiated, folder_key.as_ref()).await? {
+            Some(received) => println!("Replicated {} journal entries from {}", received, remote.name),
+            None => eprintln!("{} doesn't share its journal", remote.name),
+        }
+    }
+
+    let entries = match all_devices {
+        true => store.server_journal(limit)?,
+        false => store.recent_journal(limit)?,
+    };
+    if entries.is_empty() {
+        println!("No changes recorded");
+    }
+    let device_name = |device_id: &str| {
+        if device_id == config.device_id {
+            return config.device_name.clone();
+        }
+        config.known_peers.iter()
+            .find(|peer| peer.device_id == device_id)
+            .map(|peer| peer.name.clone())
+            .unwrap_or_else(|| device_id.chars().take(8).collect())
+    };
+    for entry in entries {
+        let op = match entry.op {
+            index_store::JournalOp::Add => style::added("add   "),
+            index_store::JournalOp::Update => "update".to_string(),
+            index_store::JournalOp::Delete => style::deleted("delete"),
+        };
+        let line = format!("{}  {}  {}", style::dim(entry.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M")), op, entry.path.display());
+        match all_devices {
+            true => println!("{}  {}", line, style::dim(device_name(&entry.device_id))),
+            false => println!("{}", line),
+        }
+    }
+    Ok(())
+}
+
+async fn simulate(
+    devices: usize,
+    script: std::path::PathBuf,
+    keep: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scenario = simulate::Scenario::load(&script)?;
+    let simulation = simulate::Simulation::new(devices, &scenario)?;
+    let base_dir = simulation.base_dir().to_path_buf();
+    let result = simulation.run(&scenario);
+    if keep {
+        println!("Device directories kept in {}", base_dir.display());
+    } else {
+        simulate::Simulation::cleanup(&base_dir);
+    }
+    let report = result?;
+
+    for line in &report.log {
+        println!("{}", line);
+    }
+    println!();
+    if report.conflicts.is_empty() {
+        println!("No conflicts");
+    } else {
+        println!("{}", style::conflict(format!("{} conflicts:", report.conflicts.len())));
+        for conflict in &report.conflicts {
+            println!("  [{}] device {} {}: {}",
+                conflict.step, conflict.device, style::conflict(conflict.path.display()), conflict.resolution);
+        }
+    }
+
+    if report.converged() {
+        println!("{}", style::added(format!("Converged: {} devices agree on {} files", devices, report.file_count)));
+        Ok(())
+    } else {
+        println!("{}", style::deleted(format!("Diverged on {} of {} files:", report.divergent.len(), report.file_count)));
+        for path in &report.divergent {
+            println!("  {}", style::deleted(path.display()));
+        }
+        Err("devices did not converge".into())
+    }
+}
+
+async fn encrypt_folder(path: std::path::PathBuf, salt: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = path.canonicalize()?;
+    let root = config.sync_roots.iter_mut()
+        .find(|root| root.path == path)
+        .ok_or_else(|| format!("{} is not a configured sync root", path.display()))?;
+    if root.encryption.is_some() {
+        println!("{} is already encrypted", path.display());
+        return Ok(());
+    }
+
+    let passphrase = encryption::read_passphrase("Folder passphrase: ")?;
+    // Joining an encrypted folder only needs the passphrase to be right
+    // once it's used, a new one is worth typing twice
+    if salt.is_none()
+        && encryption::passphrase_from_env().is_none()
+        && encryption::read_passphrase("Repeat passphrase: ")? != passphrase
+    {
+        return Err("Passphrases don't match".into());
+    }
+    let (settings, _) = encryption::FolderEncryption::new(&passphrase, salt.as_deref())?;
+    let shared_salt = settings.salt.clone();
+    root.encryption = Some(settings);
+    config.save()?;
+
+    println!("{} is now encrypted before it is synced", path.display());
+    if salt.is_none() {
+        println!("On the folder's other devices run");
+        println!("  syncmd encrypt -p <folder> --salt {}", shared_salt);
+        println!("with the same passphrase. Files already on the server stay unencrypted,");
+        println!("mirror the folder to upload encrypted copies.");
+    }
+    println!("Set {} to unlock it non-interactively", encryption::PASSPHRASE_ENV);
+    Ok(())
+}
+
+/// Lists conflict copies in both layouts, whichever the root uses now.
+fn list_conflicts(path: Option<std::path::PathBuf>, preview: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let path = match path {
+        Some(path) => path,
+        None => std::env::current_dir()?,
+    };
+    let root = config.root_containing(&path)
+        .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?;
+    let copies = conflicts::find(&root.path)?;
+    if copies.is_empty() {
+        println!("No conflict copies in {}", root.path.display());
+        return Ok(());
+    }
+    for copy in &copies {
+        let location = match copy.layout {
+            conflicts::ConflictLayout::Sibling => "",
+            conflicts::ConflictLayout::Directory => " (not synced)",
+        };
+        println!("{}: {}{}", copy.original.display(), style::conflict(copy.copy.display()), style::dim(location));
+        if preview {
+            preview_conflict(&root.path, copy);
+        }
+    }
+    println!("{} conflict cop{}", copies.len(), if copies.len() == 1 { "y" } else { "ies" });
+    Ok(())
+}
+
+/// Prints the file and its conflict copy rendered side by side, as wide as
+/// the terminal.
+fn preview_conflict(root: &std::path::Path, copy: &conflicts::ConflictCopy) {
+    let total = ratatui::crossterm::terminal::size().map(|(columns, _)| columns as usize).unwrap_or(100);
+    let width = total.saturating_sub(3) / 2;
+    let read = |path: &std::path::Path| match std::fs::read_to_string(root.join(path)) {
+        Ok(text) => markdown_preview::render(&text, width),
+        Err(e) => vec![ratatui::text::Line::from(format!("({})", e))],
+    };
+    let title = |path: &std::path::Path| {
+        let bold = ratatui::style::Style::new().add_modifier(ratatui::style::Modifier::BOLD);
+        vec![ratatui::text::Line::styled(path.display().to_string(), bold)]
+    };
+    let header = markdown_preview::side_by_side(title(&copy.original), title(&copy.copy), width);
+    println!();
+    for line in header.iter().chain(&markdown_preview::side_by_side(read(&copy.original), read(&copy.copy), width)) {
+        println!("{}", markdown_preview::to_ansi(line));
+    }
+    println!();
+}
+
+fn manage_snapshots(action: SnapshotAction) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let path = match &action {
+        SnapshotAction::Tag { path, .. }
+        | SnapshotAction::List { path }
+        | SnapshotAction::Diff { path, .. }
+        | SnapshotAction::Export { path, .. }
+        | SnapshotAction::Restore { path, .. } => path.clone(),
+    };
+    let path = std::path::absolute(path.unwrap_or(std::env::current_dir()?))?;
+    let root = config.root_containing(&path)
+        .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?
+        .path.clone();
+    let indexer = root_indexer(&config, config.device_id.clone(), &root);
+    match action {
+        SnapshotAction::Tag { name, .. } => {
+            let (tag, copied) = tags::create(&indexer, &name)?;
+            println!("Tagged {} files at sequence {} as {} ({} new copies kept)",
+                tag.snapshot.file_count, tag.snapshot.seq, tag.name, copied);
+        }
+        SnapshotAction::List { .. } => {
+            let tags = IndexStore::open(&root)?.tags()?;
+            if tags.is_empty() {
+                println!("No tags yet, add one with `syncmd snapshot tag <name>`");
+            }
+            for tag in tags {
+                let taken = tag.snapshot.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+                println!("{}  {} {}", style::dim(taken), tag.name,
+                    style::dim(format!("({} files, sequence {})", tag.snapshot.file_count, tag.snapshot.seq)));
+            }
+        }
+        SnapshotAction::Diff { name, .. } => {
+            let mut store = IndexStore::open(&root)?;
+            store.save_state(&indexer.index_directory()?)?;
+            let current = store.load_state(config.device_id.clone(), root.clone())?.local_files;
+            let changes = tags::diff(&tags::files(&store, &name)?, &current);
+            if changes.is_empty() {
+                println!("Nothing changed since {}", name);
+            }
+            for (path, change) in changes {
+                match change {
+                    tags::Change::Added => println!("{}", style::added(format!("added    {}", path.display()))),
+                    tags::Change::Removed => println!("{}", style::deleted(format!("removed  {}", path.display()))),
+                    tags::Change::Changed => println!("changed  {}", path.display()),
+                }
+            }
+        }
+        SnapshotAction::Export { name, output, .. } => {
+            let exported = tags::export(&indexer, &name, &output)?;
+            println!("Exported {} files of {} to {}", exported, name, output.display());
+        }
+        SnapshotAction::Restore { name, target, .. } => {
+            let report = tags::restore(&indexer, &name, &target)?;
+            println!("Restored {} files of {} into {}", report.restored, name, target.display());
+            for path in &report.missing {
+                eprintln!("{}", style::conflict(format!("No content kept for {}", path.display())));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn manage_trash(action: TrashAction) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    match action {
+        TrashAction::List { path } => {
+            let path = match path {
+                Some(path) => path,
+                None => std::env::current_dir()?,
+            };
+            let root = config.root_containing(&path)
+                .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?;
+            let entries = trash::Trash::new(&root.path).list()?;
+            if entries.is_empty() {
+                println!("The trash of {} is empty", root.path.display());
+            }
+            for entry in &entries {
+                let deleted = entry.deleted.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+                println!("{}  {} {}", style::dim(deleted), entry.path.display(), style::dim(format!("({} bytes)", entry.size)));
+            }
+            let retention = root.trash_retention_days.unwrap_or(trash::DEFAULT_RETENTION_DAYS);
+            println!("{} file(s), kept for {} days after deletion", entries.len(), retention);
+        }
+        TrashAction::Restore { path } => {
+            // The file is gone, so symlinks can only be resolved through its directory
+            let path = std::path::absolute(&path)?;
+            let path = match (path.parent().and_then(|parent| parent.canonicalize().ok()), path.file_name()) {
+                (Some(parent), Some(name)) => parent.join(name),
+                _ => path,
+            };
+            let root = config.root_containing(&path)
+                .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?;
+            let root_path = root.path.canonicalize()?;
+            let relative = path.strip_prefix(&root_path)?;
+            let entry = trash::Trash::new(&root_path).restore(relative)?;
+            let deleted = entry.deleted.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+            println!("{} {} (deleted {})", style::added("Restored"), relative.display(), deleted);
+        }
+    }
+    Ok(())
+}
+
+fn manage_state(action: StateAction) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let containing_root = |path: Option<std::path::PathBuf>| -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        let path = match path {
+            Some(path) => path,
+            None => std::env::current_dir()?,
+        };
+        let root = config.root_containing(&path)
+            .ok_or_else(|| format!("{} is not inside a configured sync root", path.display()))?;
+        Ok(root.path.clone())
+    };
+    match action {
+        StateAction::Path { path } => {
+            let root = containing_root(path)?;
+            println!("{}", state::dir(&root).display());
+            let in_root = root.join(index_store::STATE_DIR_NAME).join(index_store::INDEX_DB_NAME);
+            if state::central().is_some() && in_root.exists() {
+                eprintln!("{}", style::dim(format!("{} is left from before state_dir was set and no longer used", in_root.display())));
+            }
+        }
+        StateAction::Clean { path, trash } => {
+            let root = containing_root(path)?;
+            let report = state::clean(&root, trash)?;
+            println!("Removed {} from {}", report, state::dir(&root).display());
+        }
+    }
+    Ok(())
+}
+
+fn analyze_trace(action: TraceAction) -> Result<(), Box<dyn std::error::Error>> {
+    let TraceAction::Analyze { file, stall_after } = action;
+    let records = protocol_trace::read(&file)?;
+    if records.is_empty() {
+        println!("No messages in {}", file.display());
+        return Ok(());
+    }
+    let stall_after = std::time::Duration::try_from_secs_f64(stall_after).unwrap_or(protocol_trace::DEFAULT_STALL);
+    print!("{}", protocol_trace::analyze(&records, stall_after));
+    Ok(())
+}
+
+fn migrate_device(action: MigrateAction) -> Result<(), Box<dyn std::error::Error>> {
+    let config_dir = migrate::config_dir()?;
+    match action {
+        MigrateAction::Export { output } => {
+            let report = migrate::export(&config_dir, &output)?;
+            println!(
+                "Exported {} with {} root(s), {} indexed, to {}",
+                report.device_name, report.roots, report.indexes, output.display()
+            );
+            println!("{}", style::dim("The archive holds this device's identity and secrets, keep it safe"));
+        }
+        MigrateAction::Import { archive, remap, force } => {
+            let report = migrate::import(&archive, &config_dir, &remap, force)?;
+            println!("This machine is now {} ({})", report.device_name, report.device_id);
+            for root in &report.roots {
+                match root.outcome {
+                    migrate::RootOutcome::Resumed { files, missing: 0 } => {
+                        println!("  {}: {} file(s) indexed", root.path.display(), files);
+                    }
+                    migrate::RootOutcome::Resumed { files, missing } => {
+                        println!("  {}: {} file(s) indexed, {} not copied over will be downloaded", root.path.display(), files, missing);
+                    }
+                    migrate::RootOutcome::Missing => {
+                        println!("  {}: {}", root.path.display(), style::conflict("missing, copy it here or it syncs from scratch"));
+                    }
+                    migrate::RootOutcome::NotIndexed => {
+                        println!("  {}: not indexed yet", root.path.display());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Updates the subfolders a root syncs on this device. Files that leave the
+/// selection stay on disk and on the server, they just stop syncing.
+fn select_subfolders(
+    path: std::path::PathBuf,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    remove: Vec<String>,
+    clear: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let path = path.canonicalize()?;
+    let root = config.sync_roots.iter_mut()
+        .find(|root| root.path == path)
+        .ok_or_else(|| format!("{} is not a configured sync root", path.display()))?;
+
+    let normalize = |subfolder: &String| -> Result<String, Box<dyn std::error::Error>> {
+        let relative = std::path::Path::new(subfolder.trim_matches('/'));
+        if !types::is_contained(relative) {
+            return Err(format!("{} is not a subfolder of the root", subfolder).into());
+        }
+        Ok(relative.to_string_lossy().to_string())
+    };
+    let changed = clear || !include.is_empty() || !exclude.is_empty() || !remove.is_empty();
+    if clear {
+        root.include.clear();
+        root.exclude.clear();
+    }
+    for subfolder in &remove {
+        let subfolder = normalize(subfolder)?;
+        root.include.retain(|path| *path != subfolder);
+        root.exclude.retain(|path| *path != subfolder);
+    }
+    for (additions, list) in [(&include, &mut root.include), (&exclude, &mut root.exclude)] {
+        for subfolder in additions {
+            let subfolder = normalize(subfolder)?;
+            if !list.contains(&subfolder) {
+                list.push(subfolder);
+            }
+        }
+    }
+
+    if root.include.is_empty() && root.exclude.is_empty() {
+        println!("{} syncs the whole folder", path.display());
+    } else {
+        if root.include.is_empty() {
+            println!("{} syncs everything except:", path.display());
+        } else {
+            println!("{} syncs:", path.display());
+            for subfolder in &root.include {
+                println!("  {}", style::added(format!("{}/", subfolder)));
+            }
+            if !root.exclude.is_empty() {
+                println!("except:");
+            }
+        }
+        for subfolder in &root.exclude {
+            println!("  {}", style::deleted(format!("{}/", subfolder)));
+        }
+    }
+    if changed {
+        config.save()?;
+        println!("Unselected files are kept on disk; newly selected ones download on the next sync");
+    }
+    Ok(())
+}
+
+async fn manage_config(action: ConfigAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+
+    match action {
+        ConfigAction::Encrypt => {
+            if config.secrets_encrypted() {
+                println!("Config secrets are already encrypted");
+                return Ok(());
+            }
+            let passphrase = secrets::read_passphrase("New config passphrase: ")?;
+            if secrets::passphrase_from_env().is_none()
+                && secrets::read_passphrase("Repeat passphrase: ")? != passphrase
+            {
+                return Err("Passphrases don't match".into());
+            }
+            config.encrypt_secrets(&passphrase)?;
+            config.save()?;
+            println!("Config secrets encrypted");
+            println!("Set {} to unlock them non-interactively", secrets::PASSPHRASE_ENV);
+        }
+        ConfigAction::Decrypt => {
+            if !config.secrets_encrypted() {
+                println!("Config secrets are not encrypted");
+                return Ok(());
+            }
+            config.decrypt_secrets()?;
+            config.save()?;
+            println!("Config secrets stored in plaintext");
+        }
+        ConfigAction::Get { key } => {
+            if config_edit::is_secret_key(&key) {
+                config.unlock_secrets()?;
+            }
+            match config_edit::get(&config, &key)? {
+                serde_json::Value::String(value) => println!("{}", value),
+                value => println!("{}", serde_json::to_string_pretty(&value)?),
+            }
+        }
+        ConfigAction::Set { key, value } => {
+            if config_edit::is_secret_key(&key) {
+                config.unlock_secrets()?;
+            }
+            config_edit::set(&mut config, &key, &value)?;
+            config.save()?;
+            println!("Set {}", key);
+        }
+        ConfigAction::Unset { key } => {
+            if config_edit::is_secret_key(&key) {
+                config.unlock_secrets()?;
+            }
+            config_edit::unset(&mut config, &key)?;
+            config.save()?;
+            println!("Unset {}", key);
+        }
+        ConfigAction::Edit => {
+            config.unlock_secrets()?;
+            if config_edit::edit(&mut config)? {
+                config.save()?;
+                println!("Configuration saved");
+            } else {
+                println!("Configuration left unchanged");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn manage_remotes(action: RemoteAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+
+    match action {
+        RemoteAction::Add { name, address, tls, fingerprint, transport, proxy, compression, bandwidth } => {
+            let new_remote = remote::Remote {
+                name: name.clone(),
+                address,
+                tls,
+                fingerprint,
+                transport,
+                proxy,
+                compression,
+                bandwidth,
+            };
+            if let Err(e) = new_remote.check_supported() {
+                println!("Warning: {}", e);
+            }
+            config.remotes.retain(|r| r.name != name);
+            config.remotes.push(new_remote);
+            config.save()?;
+            println!("Added remote {}", name);
+        }
+        RemoteAction::Remove { name } => {
+            let before = config.remotes.len();
+            config.remotes.retain(|r| r.name != name);
+            if config.remotes.len() == before {
+                return Err(types::SyncError::Config(format!("No remote named {}", name)).into());
+            }
+            config.save()?;
+            println!("Removed remote {}", name);
+        }
+        RemoteAction::Files { name, prefix } => {
+            let client_name = config.device_name.clone();
+            let (mut stream, _, _) = connect_authenticated(&mut config, &name, client_name).await?;
+            let total = network::list_remote_files(&mut stream, prefix, |page| {
+                for file in page {
+                    println!("{:>10}  {}", plan::format_size(file.size), file.path.display());
+                }
+            }).await?;
+            println!("{} files", total);
+        }
+        RemoteAction::List { verbose } => {
+            if config.remotes.is_empty() {
+                println!("No remotes configured");
+            }
+            for r in &config.remotes {
+                println!("{}\t{}", r.name, r.address);
+                if verbose {
+                    println!("  tls:         {}", remote::setting_name(&r.tls));
+                    println!("  fingerprint: {}", r.fingerprint.as_deref().unwrap_or("-"));
+                    println!("  transport:   {}", remote::setting_name(&r.transport));
+                    println!("  proxy:       {}", r.proxy.as_deref().unwrap_or("-"));
+                    println!("  compression: {}", remote::setting_name(&r.compression));
+                    let bandwidth: Vec<String> = r.bandwidth.iter().map(|profile| profile.to_string()).collect();
+                    println!("  bandwidth:   {}", if bandwidth.is_empty() { "unlimited".to_string() } else { bandwidth.join(", ") });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn manage_peers(action: Option<PeersAction>, listen: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+
+    match action {
+        None => {
+            println!("Listening for {}s on UDP port {}...", listen, discovery::DISCOVERY_PORT);
+            let peers = discovery::listen(std::time::Duration::from_secs(listen), &config.known_peers).await?;
+            let registry = peer_registry::PeerRegistry::open()?;
+            if peers.is_empty() {
+                println!("No peers visible");
+            }
+            for peer in peers {
+                let trust = match peer.trust {
+                    discovery::PeerTrust::Verified => "verified",
+                    discovery::PeerTrust::Unknown => "unknown key",
+                };
+                let address = std::net::SocketAddr::new(peer.address.ip(), peer.announcement.port);
+                // Only verified beacons are worth remembering, anyone can
+                // claim an unknown device id
+                if peer.trust == discovery::PeerTrust::Verified {
+                    registry.record_seen(&peer.announcement.device_id, address)?;
+                }
+                println!("{} [{}] ({}) at {} - {}", peer.announcement.name, types::device_slug(&peer.announcement.name),
+                    peer.announcement.device_id, address, trust);
+                if !peer.announcement.shares.is_empty() {
+                    println!("  shares: {}", peer.announcement.shares.join(", "));
+                }
+            }
+        }
+        Some(PeersAction::Known) => {
+            if config.known_peers.is_empty() {
+                println!("No known peers");
+            }
+            let registry = peer_registry::PeerRegistry::open()?;
+            let overlays = overlay::OverlayNetworks::detect(&config.overlay_networks);
+            for peer in &config.known_peers {
+                println!("{} [{}] ({})", peer.name, types::device_slug(&peer.name), peer.device_id);
+                for candidate in registry.candidates(&peer.device_id, &overlays)? {
+                    let last_success = candidate.last_success
+                        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or_else(|| "never".to_string());
+                    println!("  {} ({})  seen {}  connected {}  failures {}",
+                        candidate.address, candidate.kind.label(), candidate.last_seen.format("%Y-%m-%d %H:%M"), last_success, candidate.failures);
+                }
+            }
+        }
+        Some(PeersAction::Identity) => {
+            let identity = discovery::DeviceIdentity::load_or_create()?;
+            println!("Device ID:  {}", config.device_id);
+            println!("Public key: {}", identity.public_key());
+            println!("Peers trust this device with:");
+            println!("  syncmd peers trust {} {}", config.device_id, identity.public_key());
+        }
+        Some(PeersAction::Trust { device_id, public_key, name }) => {
+            discovery::decode_public_key(&public_key)?;
+            config.known_peers.retain(|p| p.device_id != device_id);
+            config.known_peers.push(discovery::KnownPeer {
+                name: name.unwrap_or_else(|| device_id.clone()),
+                device_id: device_id.clone(),
+                public_key,
+            });
+            config.save()?;
+            println!("Trusting beacons from {}", device_id);
+        }
+        Some(PeersAction::Forget { device_id }) => {
+            let before = config.known_peers.len();
+            config.known_peers.retain(|p| p.device_id != device_id);
+            if config.known_peers.len() == before {
+                return Err(types::SyncError::Config(format!("No known peer {}", device_id)).into());
+            }
+            config.save()?;
+            peer_registry::PeerRegistry::open()?.forget(&device_id)?;
+            println!("Forgot peer {}", device_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Authenticates with the configured token and returns the negotiated
+/// capabilities. An expired token is exchanged for a new one, which is
+/// saved, when the server allows it.
+async fn authenticate(
+    config: &mut Config,
+    network_manager: &NetworkManager,
+    stream: &mut network::Connection,
+    client_name: String,
+) -> Result<Vec<capabilities::Capability>, Box<dyn std::error::Error>> {
+    config.unlock_secrets()?;
+    let auth_token = config.auth_token.clone()
+        .ok_or_else(|| types::SyncError::Auth("Authentication token required. Please run 'syncmd init' with --auth-token.".to_string()))?;
+    match network_manager.send_authentication(stream, auth_token.clone(), client_name.clone()).await {
+        Err(types::SyncError::TokenExpired) => {
+            println!("Token expired, requesting a new one");
+            let token = network_manager.refresh_token(stream, auth_token).await?;
+            config.auth_token = Some(token.clone());
+            config.save()?;
+            let negotiated = network_manager.send_authentication(stream, token, client_name).await?;
+            println!("Token refreshed");
+            Ok(negotiated)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Whether the server turned the token down, as opposed to any other
+/// failure, so the user needs to pair again.
+fn is_token_rejection(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<types::SyncError>(),
+        Some(types::SyncError::TokenExpired | types::SyncError::TokenRevoked | types::SyncError::InvalidToken)
+    )
+}
+
+/// Polls the config file until its token changes, then reloads it.
+async fn wait_for_new_token(config: &mut Config) -> Result<(), Box<dyn std::error::Error>> {
+    let rejected = Config::load()?.secrets_fingerprint()?;
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        let reloaded = Config::load()?;
+        if reloaded.secrets_fingerprint()? != rejected {
+            *config = reloaded;
+            return Ok(());
+        }
+    }
+}
+
+/// Connects to a peer, remote or address for a one-off request and
+/// authenticates as `client_name`.
+async fn connect_authenticated(
+    config: &mut Config,
+    target: &str,
+    client_name: String,
+) -> Result<(network::Connection, remote::Remote, Vec<capabilities::Capability>), Box<dyn std::error::Error>> {
+    let network_manager = NetworkManager::new(Arc::new(ClientManager::new()), String::new());
+    let (mut stream, remote) = network_manager.connect_supervised(config, target).await?;
+    let negotiated = authenticate(config, &network_manager, &mut stream, client_name).await?;
+    Ok((stream, remote, negotiated))
+}
+
+/// Sends the root's subscriptions, if it has any, and returns them.
+async fn send_subscriptions(
+    config: &Config,
+    path: &std::path::Path,
+    stream: &mut network::Connection,
+    folder_key: Option<&encryption::FolderKey>,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    use tokio::io::AsyncWriteExt;
+
+    let subscriptions = config.find_sync_root(path)
+        .map(|root| root.subscriptions.clone())
+        .unwrap_or_default();
+    if !subscriptions.is_empty() {
+        // Names are encrypted component by component, so prefixes still
+        // match once encrypted the same way
+        let prefixes = subscriptions.iter()
+            .map(|prefix| match folder_key {
+                Some(key) => key.encrypt_path(std::path::Path::new(prefix)).to_string_lossy().to_string(),
+                None => prefix.clone(),
+            })
+            .collect();
+        let subscribe = NetworkMessage::Subscribe { prefixes };
+        stream.write_all(&serde_json::to_vec(&subscribe)?).await?;
+    }
+    Ok(subscriptions)
+}
+
+/// Aborts a spawned task when dropped.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Keeps a second connection to `target` open on which the server pushes
+/// changes other devices made, and wakes `changed` for each batch. The sync
+/// connection is strictly request and response, so a pushed message can't
+/// get in the way of an answer there. A dropped connection is reopened with
+/// exponential backoff, and `changed` is woken once it is back, since
+/// notifications sent meanwhile were missed. Gives up, leaving the periodic
+/// sync, if the server doesn't support notifications.
+async fn watch_server_changes(
+    mut config: Config,
+    path: std::path::PathBuf,
+    target: String,
+    folder_key: Option<encryption::FolderKey>,
+    changed: Arc<tokio::sync::Notify>,
+) {
+    let network_manager = NetworkManager::new(Arc::new(ClientManager::new()), String::new());
+    let mut backoff = backoff::Backoff::reconnect();
+    let mut watching = false;
+    loop {
+        let watched = async {
+            let (mut stream, _) = network_manager.connect_supervised(&config, &target).await?;
+            let client_name = config.device_name.clone();
+            let negotiated = authenticate(&mut config, &network_manager, &mut stream, client_name).await?;
+            if !negotiated.contains(&capabilities::Capability::Notifications) {
+                return Ok(false);
+            }
+            send_subscriptions(&config, &path, &mut stream, folder_key.as_ref()).await?;
+            network::watch_changes(&mut stream, &config.device_id).await?;
+            if watching {
+                changed.notify_one();
+            }
+            watching = true;
+            backoff.reset();
+            
+            while let Some(message) = stream.next().await? {
+                let NetworkMessage::ChangeNotification { paths } = message else {
+                    continue;
+                };
+                let names: Vec<String> = paths.iter()
+                    .map(|changed| match &folder_key {
+                        Some(key) => key.decrypt_path(changed).unwrap_or_else(|_| changed.clone()),
+                        None => changed.clone(),
+                    })
+                    .map(|changed| changed.display().to_string())
+                    .collect();
+                if names.is_empty() {
+                    println!("Files changed on the server");
+                } else {
+                    println!("Changed on the server: {}", names.join(", "));
+                }
+                changed.notify_one();
+            }
+            Ok::<_, Box<dyn std::error::Error>>(true)
+        }.await.map_err(|e| e.to_string());
+        
+        match watched {
+            Ok(false) => {
+                println!("The server doesn't push changes, they arrive with the periodic sync");
+                return;
+            }
+            Ok(true) => tracing::debug!("Change notification connection closed"),
+            Err(e) => tracing::debug!("Change notification connection failed: {}", e),
+        }
+        tokio::time::sleep(backoff.next_delay()).await;
+    }
+}
+
+/// Unlocks the root's encryption key, if the root is encrypted.
+fn folder_key(config: &Config, path: &std::path::Path) -> Result<Option<encryption::FolderKey>, Box<dyn std::error::Error>> {
+    let Some(settings) = config.find_sync_root(path).and_then(|root| root.encryption.as_ref()) else {
+        return Ok(None);
+    };
+    let passphrase = encryption::read_passphrase(&format!("Passphrase for {}: ", path.display()))?;
+    Ok(Some(settings.unlock(&passphrase)?))
+}
+
+/// Waits while the root isn't there, see [`watcher::root_present`], showing
+/// it as missing meanwhile.
+async fn wait_for_root(path: &std::path::Path, indexed: usize, state: &daemon::DaemonState) {
+    if watcher::root_present(path, indexed) {
+        return;
+    }
+    set_root_missing(path, state, true);
+    while !watcher::root_present(path, indexed) {
+        tokio::time::sleep(watcher::ROOT_CHECK_INTERVAL).await;
+    }
+    set_root_missing(path, state, false);
+}
+
+fn set_root_missing(path: &std::path::Path, state: &daemon::DaemonState, missing: bool) {
+    state.update(path, |status| status.missing = missing);
+    if missing {
+        println!("{} is missing, syncing it is paused until it returns", path.display());
+        events::record(Some(path), events::EventKind::RootMissing, "The root is missing, syncing waits for it to return");
+    } else {
+        println!("{} is back, catching up with changes", path.display());
+        events::record(Some(path), events::EventKind::RootReturned, "The root is back");
+    }
+}
+
+/// The watcher's next changes, never for a root without one.
+async fn next_changes(file_watcher: &mut Option<Box<dyn watcher::ChangeSource>>) -> Option<Vec<std::path::PathBuf>> {
+    match file_watcher {
+        Some(file_watcher) => file_watcher.next_changes().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The daemon's own change source, a [`root_watcher`].
+fn root_change_source(config: &Config, path: &std::path::Path) -> Result<Box<dyn watcher::ChangeSource>, types::SyncError> {
+    Ok(Box::new(root_watcher(config, path)?))
+}
+
+/// Watches a folder, honouring the root's settings for which events matter.
+fn root_watcher(config: &Config, path: &std::path::Path) -> Result<FileWatcher, types::SyncError> {
+    let watcher = FileWatcher::for_root(config, path)?;
+    if let Some(report) = watcher.limit_report() {
+        eprintln!("{}", style::conflict(report));
+    }
+    Ok(watcher)
+}
+
 fn calculate_root_hash(sync_state: &types::SyncState) -> Result<String, Box<dyn std::error::Error>> {
     use md5::Digest;
     
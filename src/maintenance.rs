@@ -0,0 +1,133 @@
+#![allow(dead_code)]
+
+//! Periodic cleanup for the long-running processes: partial downloads that
+//! nobody resumed, timed out sessions and clients that stopped connecting.
+//! Transfers that stall mid-file are dropped by the connection receiving
+//! them, see `FileTransferManager::expire_stalled`.
+
+use crate::file_transfer::PARTIAL_DIR_NAME;
+use crate::index_store::{IndexStore, PurgeReport};
+use crate::network::ClientManager;
+use crate::server_config::ServerConfig;
+use crate::types::SyncError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How often servers collect tombstones and deleted versions past retention
+pub const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Partial files are kept this long for another source to resume them
+pub const PARTIAL_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Registered clients that haven't authenticated for this long are forgotten
+pub const CLIENT_MAX_IDLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What cleanup removed, for one run or added up since the process started.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CleanupStats {
+    pub runs: u64,
+    pub partial_files: u64,
+    pub expired_sessions: u64,
+    pub stale_clients: u64,
+    pub last_run: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl CleanupStats {
+    /// Adds one run's counts to the totals.
+    pub fn record(&mut self, run: &CleanupStats) {
+        self.runs += 1;
+        self.partial_files += run.partial_files;
+        self.expired_sessions += run.expired_sessions;
+        self.stale_clients += run.stale_clients;
+        self.last_run = Some(chrono::Utc::now());
+    }
+
+    pub fn removed(&self) -> u64 {
+        self.partial_files + self.expired_sessions + self.stale_clients
+    }
+}
+
+impl fmt::Display for CleanupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} partial file(s), {} expired session(s), {} stale client(s)",
+            self.partial_files, self.expired_sessions, self.stale_clients
+        )
+    }
+}
+
+/// Drops the tombstones and deleted versions in a served share's `store`
+/// older than the retention `server.json` sets for `share`. The setting is
+/// read on every pass, so a change applies without a restart.
+pub fn purge_deleted(store: &mut IndexStore, share: &Path) -> Result<PurgeReport, SyncError> {
+    let retention_days = ServerConfig::load()?.deleted_retention_days(share);
+    store.purge_deleted(chrono::Duration::days(retention_days))
+}
+
+/// Removes partial downloads under `sync_root` that haven't been written to
+/// for `max_age`, along with their chunk bitmaps. Returns how many files
+/// were removed.
+pub fn remove_orphaned_partials(sync_root: &Path, max_age: Duration) -> Result<u64, SyncError> {
+    let dir = crate::state::dir(sync_root).join(PARTIAL_DIR_NAME);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let modified = entry.metadata()?.modified()?;
+        let age = now.duration_since(modified).unwrap_or_default();
+        if age >= max_age && std::fs::remove_file(entry.path()).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// One cleanup run: ends timed out sessions, forgets idle clients and
+/// removes orphaned partial files from each of `roots`.
+pub async fn run(clients: &ClientManager, roots: &[PathBuf]) -> CleanupStats {
+    let mut run = CleanupStats {
+        expired_sessions: clients.expire_sessions() as u64,
+        stale_clients: clients.remove_idle_clients(CLIENT_MAX_IDLE).await as u64,
+        ..Default::default()
+    };
+    for root in roots {
+        match remove_orphaned_partials(root, PARTIAL_MAX_AGE) {
+            Ok(removed) => run.partial_files += removed,
+            Err(e) => tracing::warn!("Cleaning up partial files in {} failed: {}", root.display(), e),
+        }
+    }
+    run
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_orphaned_partials() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(remove_orphaned_partials(dir.path(), PARTIAL_MAX_AGE).unwrap(), 0);
+
+        let partials = crate::state::dir(dir.path()).join(PARTIAL_DIR_NAME);
+        std::fs::create_dir_all(&partials).unwrap();
+        std::fs::write(partials.join("abc123"), b"half a file").unwrap();
+        std::fs::write(partials.join("abc123.chunks"), [0b101]).unwrap();
+        assert_eq!(remove_orphaned_partials(dir.path(), PARTIAL_MAX_AGE).unwrap(), 0);
+        assert_eq!(remove_orphaned_partials(dir.path(), Duration::ZERO).unwrap(), 2);
+        assert_eq!(std::fs::read_dir(&partials).unwrap().count(), 0);
+
+        let mut totals = CleanupStats::default();
+        totals.record(&CleanupStats { partial_files: 2, stale_clients: 1, ..Default::default() });
+        totals.record(&CleanupStats { expired_sessions: 3, ..Default::default() });
+        assert_eq!(totals.runs, 2);
+        assert_eq!(totals.removed(), 6);
+        assert_eq!(totals.to_string(), "2 partial file(s), 3 expired session(s), 1 stale client(s)");
+    }
+}
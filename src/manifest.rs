@@ -0,0 +1,140 @@
+#![allow(dead_code)]
+
+//! Checksum manifests of a root, to check a copy made outside syncmd (a USB
+//! backup, another sync tool) against what syncmd has indexed. The manifest
+//! is in the format of `b3sum`, so `b3sum --check` reads it too; the
+//! signature goes next to it in `<manifest>.sig`, made with this device's
+//! identity key.
+
+use crate::discovery::DeviceIdentity;
+use crate::types::{FileMetadata, SyncError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Relative path -> blake3 hash of the content as indexed. Roots with
+/// transforms index the transformed content.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: BTreeMap<PathBuf, String>,
+}
+
+/// Who signed a manifest, and the ed25519 signature over its exact bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestSignature {
+    pub device_id: String,
+    pub device_name: String,
+    pub public_key: String,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub signature: String,
+}
+
+/// How a copy differs from its manifest.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub matched: usize,
+    pub missing: Vec<PathBuf>,
+    pub changed: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Manifest {
+    pub fn from_files<'a>(files: impl IntoIterator<Item = &'a FileMetadata>) -> Self {
+        Self {
+            entries: files.into_iter().map(|file| (file.path.clone(), file.hash.clone())).collect(),
+        }
+    }
+
+    /// One `<hash>  <path>` line per file, paths with `/` on every platform.
+    pub fn to_text(&self) -> String {
+        self.entries.iter()
+            .map(|(path, hash)| format!("{}  {}\n", hash, path.to_string_lossy().replace('\\', "/")))
+            .collect()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SyncError> {
+        let mut entries = BTreeMap::new();
+        for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+            let (hash, path) = line.split_once("  ")
+                .filter(|(hash, _)| crate::file_transfer::is_content_hash(hash))
+                .ok_or_else(|| SyncError::Config(format!("Line {} of the manifest isn't `<hash>  <path>`", number + 1)))?;
+            entries.insert(PathBuf::from(path), hash.to_string());
+        }
+        Ok(Self { entries })
+    }
+
+    /// Hashes the files of the copy at `dir` and compares them with the
+    /// manifest. Files the manifest doesn't list are not looked at.
+    pub fn verify(&self, dir: &Path) -> Result<VerifyReport, SyncError> {
+        let mut report = VerifyReport::default();
+        for (path, hash) in &self.entries {
+            match crate::file_transfer::hash_file(&dir.join(path)) {
+                Ok(actual) if actual == *hash => report.matched += 1,
+                Ok(_) => report.changed.push(path.clone()),
+                Err(SyncError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => report.missing.push(path.clone()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Signs the manifest's `text` as this device.
+pub fn sign(text: &str, identity: &DeviceIdentity, device_id: &str, device_name: &str) -> ManifestSignature {
+    ManifestSignature {
+        device_id: device_id.to_string(),
+        device_name: device_name.to_string(),
+        public_key: identity.public_key(),
+        created: chrono::Utc::now(),
+        signature: identity.sign_bytes(text.as_bytes()),
+    }
+}
+
+/// Checks that `signature` was made over exactly `text` with the key it names.
+pub fn verify_signature(text: &str, signature: &ManifestSignature) -> Result<(), SyncError> {
+    crate::discovery::verify_bytes(&signature.public_key, text.as_bytes(), &signature.signature)
+}
+
+/// Where the signature of the manifest at `path` is kept.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(SIGNATURE_EXTENSION);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_roundtrip_and_verify() {
+        let copy = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(copy.path().join("notes")).unwrap();
+        std::fs::write(copy.path().join("notes/a.md"), "# A").unwrap();
+        std::fs::write(copy.path().join("b.md"), "# B").unwrap();
+        std::fs::write(copy.path().join("c.md"), "# C").unwrap();
+
+        let indexer = crate::indexer::FileIndexer::new("laptop".to_string(), copy.path().to_path_buf());
+        let manifest = Manifest::from_files(indexer.index_directory().unwrap().local_files.values());
+        let text = manifest.to_text();
+        assert!(text.starts_with(&format!("{}  b.md\n", blake3::hash(b"# B").to_hex())));
+        assert_eq!(Manifest::parse(&text).unwrap(), manifest);
+        assert!(Manifest::parse("not a manifest").is_err());
+
+        std::fs::write(copy.path().join("b.md"), "# B, edited").unwrap();
+        std::fs::remove_file(copy.path().join("c.md")).unwrap();
+        let report = manifest.verify(copy.path()).unwrap();
+        assert_eq!(report.matched, 1);
+        assert_eq!(report.changed, [PathBuf::from("b.md")]);
+        assert_eq!(report.missing, [PathBuf::from("c.md")]);
+        assert_eq!(signature_path(Path::new("out/manifest.blake3")), Path::new("out/manifest.blake3.sig"));
+    }
+}
@@ -0,0 +1,412 @@
+#![allow(dead_code)]
+
+//! Markdown rendered for the terminal, so both sides of a conflict can be
+//! read the way they will look rather than as raw text: headings, lists,
+//! quotes, emphasis, code and links are styled and paragraphs wrapped to a
+//! column. `syncmd conflicts --preview` prints the sides next to each other
+//! and `syncmd ui` shows them in its conflict preview.
+
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+
+/// Between the columns of a side-by-side preview
+const SEPARATOR: &str = " │ ";
+
+/// `markdown` as styled lines at most `width` columns wide. Words longer
+/// than a line are split.
+pub fn render(markdown: &str, width: usize) -> Vec<Line<'static>> {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS;
+    let mut renderer = Renderer::new(width.max(8));
+    for event in Parser::new_ext(markdown, options) {
+        renderer.event(event);
+    }
+    renderer.finish()
+}
+
+/// Two columns of lines next to each other, each `width` columns wide.
+pub fn side_by_side(left: Vec<Line<'static>>, right: Vec<Line<'static>>, width: usize) -> Vec<Line<'static>> {
+    let rows = left.len().max(right.len());
+    let mut left = left.into_iter();
+    let mut right = right.into_iter();
+    (0..rows)
+        .map(|_| {
+            let mut line = left.next().unwrap_or_default();
+            let padding = width.saturating_sub(line.width());
+            line.spans.push(Span::raw(" ".repeat(padding)));
+            line.spans.push(Span::styled(SEPARATOR, Style::new().fg(Color::DarkGray)));
+            line.spans.extend(right.next().unwrap_or_default().spans);
+            line
+        })
+        .collect()
+}
+
+/// A line with its styles as ANSI escapes, when output is colored.
+pub fn to_ansi(line: &Line) -> String {
+    let mut text = String::new();
+    for span in &line.spans {
+        let codes = ansi_codes(span.style);
+        if codes.is_empty() || !crate::style::enabled() {
+            text.push_str(&span.content);
+        } else {
+            text.push_str(&format!("\x1b[{}m{}\x1b[0m", codes.join(";"), span.content));
+        }
+    }
+    text.trim_end().to_string()
+}
+
+fn ansi_codes(style: Style) -> Vec<&'static str> {
+    let mut codes = Vec::new();
+    for (modifier, code) in [
+        (Modifier::BOLD, "1"),
+        (Modifier::DIM, "2"),
+        (Modifier::ITALIC, "3"),
+        (Modifier::UNDERLINED, "4"),
+        (Modifier::CROSSED_OUT, "9"),
+    ] {
+        if style.add_modifier.contains(modifier) {
+            codes.push(code);
+        }
+    }
+    codes.extend(match style.fg {
+        Some(Color::Red) => Some("31"),
+        Some(Color::Green) => Some("32"),
+        Some(Color::Yellow) => Some("33"),
+        Some(Color::Blue) => Some("34"),
+        Some(Color::Magenta) => Some("35"),
+        Some(Color::Cyan) => Some("36"),
+        Some(Color::DarkGray) => Some("90"),
+        _ => None,
+    });
+    codes
+}
+
+struct Renderer {
+    width: usize,
+    lines: Vec<Line<'static>>,
+    current: Vec<Span<'static>>,
+    current_width: usize,
+    /// Whether `current` holds more than its prefix
+    started: bool,
+    styles: Vec<Style>,
+    /// What starts each line inside quotes and list items, outermost first
+    indents: Vec<Span<'static>>,
+    /// The marker of a list item whose first line is still to come, shown
+    /// in place of the innermost indent
+    bullet: Option<Span<'static>>,
+    /// The next number of each ordered list, `None` for bullet lists
+    lists: Vec<Option<u64>>,
+    in_code_block: bool,
+    in_metadata: bool,
+    /// Whether a cell was already written in the current table row
+    in_row: bool,
+}
+
+impl Renderer {
+    fn new(width: usize) -> Self {
+        Self {
+            width,
+            lines: Vec::new(),
+            current: Vec::new(),
+            current_width: 0,
+            started: false,
+            styles: vec![Style::new()],
+            indents: Vec::new(),
+            bullet: None,
+            lists: Vec::new(),
+            in_code_block: false,
+            in_metadata: false,
+            in_row: false,
+        }
+    }
+
+    fn style(&self) -> Style {
+        *self.styles.last().unwrap_or(&Style::new())
+    }
+
+    fn push_style(&mut self, change: impl FnOnce(Style) -> Style) {
+        self.styles.push(change(self.style()));
+    }
+
+    fn pop_style(&mut self) {
+        if self.styles.len() > 1 {
+            self.styles.pop();
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        if self.in_metadata {
+            // Frontmatter isn't part of the note as it reads
+            self.in_metadata = !matches!(event, Event::End(TagEnd::MetadataBlock(_)));
+            return;
+        }
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) if self.in_code_block => {
+                for line in text.lines() {
+                    self.write(line, self.style());
+                    self.break_line();
+                }
+            }
+            Event::Text(text) => self.text(&text, self.style()),
+            Event::Code(code) => self.text(&code, self.style().fg(Color::Yellow)),
+            Event::InlineMath(math) | Event::DisplayMath(math) => self.text(&math, self.style().fg(Color::Yellow)),
+            Event::Html(html) | Event::InlineHtml(html) => self.text(html.trim_end(), self.style().fg(Color::DarkGray)),
+            Event::FootnoteReference(name) => self.text(&format!("[^{}]", name), self.style().fg(Color::DarkGray)),
+            Event::SoftBreak => self.text(" ", self.style()),
+            Event::HardBreak => self.break_line(),
+            Event::Rule => {
+                self.break_line();
+                let rule = "─".repeat(self.width.saturating_sub(self.indent_width()));
+                self.write(&rule, Style::new().fg(Color::DarkGray));
+                self.break_line();
+                self.blank_line();
+            }
+            Event::TaskListMarker(done) => {
+                let marker = if done { "[x] " } else { "[ ] " };
+                self.write(marker, Style::new().fg(Color::DarkGray));
+            }
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Heading { level, .. } => {
+                self.break_line();
+                let color = if level as usize <= 2 { Color::Cyan } else { Color::Blue };
+                self.push_style(|style| style.fg(color).add_modifier(Modifier::BOLD));
+                self.write(&format!("{} ", "#".repeat(level as usize)), self.style());
+            }
+            Tag::Paragraph => self.break_line(),
+            Tag::BlockQuote(_) => {
+                self.break_line();
+                self.indents.push(Span::styled("│ ", Style::new().fg(Color::DarkGray)));
+                self.push_style(|style| style.add_modifier(Modifier::ITALIC));
+            }
+            Tag::CodeBlock(kind) => {
+                self.break_line();
+                if let CodeBlockKind::Fenced(language) = kind {
+                    if !language.is_empty() {
+                        self.write(&language, Style::new().fg(Color::DarkGray));
+                        self.break_line();
+                    }
+                }
+                self.indents.push(Span::raw("  "));
+                self.push_style(|style| style.fg(Color::Yellow));
+                self.in_code_block = true;
+            }
+            Tag::List(start) => {
+                self.break_line();
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.break_line();
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.indents.push(Span::raw(" ".repeat(marker.chars().count())));
+                self.bullet = Some(Span::styled(marker, Style::new().fg(Color::Magenta)));
+            }
+            Tag::Emphasis => self.push_style(|style| style.add_modifier(Modifier::ITALIC)),
+            Tag::Strong => self.push_style(|style| style.add_modifier(Modifier::BOLD)),
+            Tag::Strikethrough => self.push_style(|style| style.add_modifier(Modifier::CROSSED_OUT)),
+            Tag::Link { .. } | Tag::Image { .. } => {
+                self.push_style(|style| style.fg(Color::Blue).add_modifier(Modifier::UNDERLINED))
+            }
+            Tag::Table(_) => self.break_line(),
+            Tag::TableHead => {
+                self.in_row = false;
+                self.push_style(|style| style.add_modifier(Modifier::BOLD));
+            }
+            Tag::TableRow => self.in_row = false,
+            Tag::TableCell => {
+                if self.in_row {
+                    self.write(" │ ", Style::new().fg(Color::DarkGray));
+                }
+                self.in_row = true;
+            }
+            Tag::MetadataBlock(_) => self.in_metadata = true,
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Heading(_) => {
+                self.break_line();
+                self.pop_style();
+                self.blank_line();
+            }
+            TagEnd::Paragraph => {
+                self.break_line();
+                if self.lists.is_empty() {
+                    self.blank_line();
+                }
+            }
+            TagEnd::BlockQuote(_) => {
+                self.break_line();
+                self.indents.pop();
+                self.pop_style();
+                self.blank_line();
+            }
+            TagEnd::CodeBlock => {
+                self.break_line();
+                self.indents.pop();
+                self.pop_style();
+                self.in_code_block = false;
+                self.blank_line();
+            }
+            TagEnd::List(_) => {
+                self.break_line();
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.blank_line();
+                }
+            }
+            TagEnd::Item => {
+                self.break_line();
+                self.indents.pop();
+                self.bullet = None;
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Link | TagEnd::Image => self.pop_style(),
+            TagEnd::TableHead => {
+                self.break_line();
+                self.pop_style();
+            }
+            TagEnd::TableRow => self.break_line(),
+            TagEnd::Table => {
+                self.break_line();
+                self.blank_line();
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes `text` word by word, wrapping at the width.
+    fn text(&mut self, text: &str, style: Style) {
+        for word in text.split_inclusive(' ') {
+            let width = word.trim_end().chars().count();
+            if self.started && self.current_width + width > self.width {
+                self.break_line();
+                let word = word.trim_start();
+                if word.is_empty() {
+                    continue;
+                }
+                self.write(word, style);
+            } else {
+                self.write(word, style);
+            }
+        }
+    }
+
+    /// Writes `text` on the current line, splitting it only where it can't
+    /// fit on a line of its own.
+    fn write(&mut self, text: &str, style: Style) {
+        let mut rest = text;
+        loop {
+            self.start_line();
+            let room = self.width.saturating_sub(self.current_width).max(1);
+            let split = rest.char_indices().nth(room).map(|(at, _)| at);
+            match split {
+                Some(at) if !rest[at..].trim().is_empty() => {
+                    self.current.push(Span::styled(rest[..at].to_string(), style));
+                    self.current_width += room;
+                    self.started = true;
+                    self.break_line();
+                    rest = &rest[at..];
+                }
+                _ => {
+                    self.current.push(Span::styled(rest.to_string(), style));
+                    self.current_width += rest.chars().count();
+                    self.started = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Starts a line with the prefixes of the quotes and list items around it.
+    fn start_line(&mut self) {
+        if !self.current.is_empty() {
+            return;
+        }
+        let innermost = self.indents.len().saturating_sub(1);
+        for (i, indent) in self.indents.iter().enumerate() {
+            let span = match (&self.bullet, i == innermost) {
+                (Some(bullet), true) => bullet.clone(),
+                _ => indent.clone(),
+            };
+            self.current_width += span.width();
+            self.current.push(span);
+        }
+        self.bullet = None;
+    }
+
+    fn break_line(&mut self) {
+        if self.started {
+            let line = std::mem::take(&mut self.current);
+            self.lines.push(Line::from(line));
+        }
+        self.current.clear();
+        self.current_width = 0;
+        self.started = false;
+    }
+
+    fn blank_line(&mut self) {
+        if self.lines.last().is_some_and(|line| line.width() > 0) {
+            self.lines.push(Line::default());
+        }
+    }
+
+    fn indent_width(&self) -> usize {
+        self.indents.iter().map(Span::width).sum()
+    }
+
+    fn finish(mut self) -> Vec<Line<'static>> {
+        self.break_line();
+        while self.lines.last().is_some_and(|line| line.width() == 0) {
+            self.lines.pop();
+        }
+        self.lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(lines: &[Line]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string().trim_end().to_string()).collect()
+    }
+
+    #[test]
+    fn test_markdown_preview() {
+        let note = "---\ntags: x\n---\n# Plan\n\nSome *words* that need to wrap here.\n\n- one\n- two\n  1. nested\n\n> quoted\n";
+        let lines = render(note, 20);
+        assert_eq!(plain(&lines), [
+            "# Plan",
+            "",
+            "Some words that need",
+            "to wrap here.",
+            "",
+            "• one",
+            "• two",
+            "  1. nested",
+            "",
+            "│ quoted",
+        ]);
+        assert!(lines[0].spans.iter().all(|span| span.style.add_modifier.contains(Modifier::BOLD)));
+        assert!(lines[2].spans.iter().any(|span| span.content == "words" && span.style.add_modifier.contains(Modifier::ITALIC)));
+
+        let columns = side_by_side(render("# Mine", 10), render("# Theirs\n\nmore", 10), 10);
+        assert_eq!(plain(&columns), ["# Mine     │ # Theirs", "           │", "           │ more"]);
+    }
+}
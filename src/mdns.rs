@@ -0,0 +1,395 @@
+#![allow(dead_code)]
+
+//! Minimal mDNS / DNS-SD (RFC 6762/6763) for finding syncmd servers on the
+//! LAN by name. Servers answer queries for `_syncmd._tcp.local` with PTR,
+//! SRV and TXT records; browsers ask with the unicast-response bit set so
+//! they don't need the mDNS port themselves.
+//!
+//! Unlike the signed beacons in `discovery.rs`, nothing here is
+//! authenticated: a found peer is only a convenience for picking an address.
+
+use crate::types::SyncError;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+pub const SERVICE_TYPE: &str = "_syncmd._tcp.local";
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const RECORD_TTL: u32 = 120;
+const MAX_PACKET_SIZE: usize = 9000;
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// In a question, asks for a unicast reply; in a record, flushes caches
+const CLASS_TOP_BIT: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+/// What a server publishes about itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceInfo {
+    pub device_id: String,
+    pub name: String,
+    pub shares: Vec<String>,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct MdnsPeer {
+    pub info: ServiceInfo,
+    pub address: SocketAddr,
+}
+
+/// Answers queries for the syncmd service until the task is dropped. An
+/// unsolicited announcement goes out first so browsers already waiting
+/// see the server straight away.
+pub async fn advertise(info: ServiceInfo) -> Result<(), SyncError> {
+    let socket = bind_mdns_port()?;
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+    let announcement = encode_response(0, &info);
+    socket.send_to(&announcement, group).await?;
+
+    let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let (n, source) = socket.recv_from(&mut buffer).await?;
+        let Some(query) = parse_query(&buffer[..n]) else {
+            continue;
+        };
+        if !query.asks_for_service {
+            continue;
+        }
+        let response = encode_response(query.id, &info);
+        // Browsers on another port, or asking for it, get a direct reply
+        let target = if query.unicast || source.port() != MDNS_PORT { source } else { group };
+        if let Err(e) = socket.send_to(&response, target).await {
+            tracing::debug!("Failed to answer mDNS query from {}: {}", source, e);
+        }
+    }
+}
+
+/// Asks the LAN for syncmd servers for the given time and returns one entry
+/// per device, other than `own_device_id`, sorted by name.
+pub async fn browse(duration: Duration, own_device_id: &str) -> Result<Vec<MdnsPeer>, SyncError> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+    let query = encode_query();
+    let mut found: HashMap<String, MdnsPeer> = HashMap::new();
+    let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+    let mut resend = tokio::time::interval(Duration::from_secs(1));
+
+    let deadline = tokio::time::Instant::now() + duration;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            _ = resend.tick() => {
+                socket.send_to(&query, group).await?;
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (n, source) = received?;
+                for info in parse_response(&buffer[..n]) {
+                    if info.device_id != own_device_id {
+                        let address = SocketAddr::new(source.ip(), info.port);
+                        found.insert(info.device_id.clone(), MdnsPeer { info, address });
+                    }
+                }
+            }
+        }
+    }
+
+    let mut peers: Vec<MdnsPeer> = found.into_values().collect();
+    peers.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+    Ok(peers)
+}
+
+/// Shares the mDNS port with any system responder that is already running.
+fn bind_mdns_port() -> Result<UdpSocket, SyncError> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn instance_name(info: &ServiceInfo) -> String {
+    format!("{}.{}", info.device_id, SERVICE_TYPE)
+}
+
+fn encode_query() -> Vec<u8> {
+    let mut packet = header(0, 0, 1, 0);
+    write_name(&mut packet, SERVICE_TYPE);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&(CLASS_IN | CLASS_TOP_BIT).to_be_bytes());
+    packet
+}
+
+fn encode_response(id: u16, info: &ServiceInfo) -> Vec<u8> {
+    let instance = instance_name(info);
+    let mut packet = header(id, FLAG_RESPONSE | FLAG_AUTHORITATIVE, 0, 3);
+
+    let mut ptr = Vec::new();
+    write_name(&mut ptr, &instance);
+    write_record(&mut packet, SERVICE_TYPE, TYPE_PTR, CLASS_IN, &ptr);
+
+    let mut srv = Vec::new();
+    srv.extend_from_slice(&0u16.to_be_bytes()); // priority
+    srv.extend_from_slice(&0u16.to_be_bytes()); // weight
+    srv.extend_from_slice(&info.port.to_be_bytes());
+    write_name(&mut srv, &format!("{}.local", info.device_id));
+    write_record(&mut packet, &instance, TYPE_SRV, CLASS_IN | CLASS_TOP_BIT, &srv);
+
+    let mut txt = Vec::new();
+    let entries = [
+        format!("id={}", info.device_id),
+        format!("name={}", info.name),
+        format!("shares={}", info.shares.join(",")),
+    ];
+    for entry in entries {
+        let bytes = &entry.as_bytes()[..entry.len().min(255)];
+        txt.push(bytes.len() as u8);
+        txt.extend_from_slice(bytes);
+    }
+    write_record(&mut packet, &instance, TYPE_TXT, CLASS_IN | CLASS_TOP_BIT, &txt);
+    packet
+}
+
+fn header(id: u16, flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    [id, flags, questions, answers, 0, 0]
+        .iter()
+        .flat_map(|field| field.to_be_bytes())
+        .collect()
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    // The instance label is a device id, the rest are fixed, so no label
+    // comes near the 63 byte limit
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+fn write_record(packet: &mut Vec<u8>, name: &str, record_type: u16, class: u16, data: &[u8]) {
+    write_name(packet, name);
+    packet.extend_from_slice(&record_type.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+struct Query {
+    id: u16,
+    asks_for_service: bool,
+    unicast: bool,
+}
+
+struct Record {
+    name: String,
+    record_type: u16,
+    /// Offset of the record data in the packet, for names compressed
+    /// against earlier parts of it
+    data_offset: usize,
+    data_len: usize,
+}
+
+fn parse_query(packet: &[u8]) -> Option<Query> {
+    let mut reader = Reader::new(packet);
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    if flags & FLAG_RESPONSE != 0 {
+        return None;
+    }
+    let questions = reader.u16()?;
+    reader.skip(6)?;
+
+    let mut query = Query { id, asks_for_service: false, unicast: false };
+    for _ in 0..questions {
+        let name = reader.name()?;
+        let record_type = reader.u16()?;
+        let class = reader.u16()?;
+        if name.eq_ignore_ascii_case(SERVICE_TYPE) && matches!(record_type, TYPE_PTR | TYPE_ANY) {
+            query.asks_for_service = true;
+            query.unicast |= class & CLASS_TOP_BIT != 0;
+        }
+    }
+    Some(query)
+}
+
+/// The services described by a response. Records may be spread over the
+/// answer and additional sections in any order.
+fn parse_response(packet: &[u8]) -> Vec<ServiceInfo> {
+    let Some(records) = parse_records(packet) else {
+        return Vec::new();
+    };
+    let instances = records.iter().filter_map(|record| {
+        (record.record_type == TYPE_PTR && record.name.eq_ignore_ascii_case(SERVICE_TYPE))
+            .then(|| Reader::at(packet, record.data_offset).name())
+            .flatten()
+    });
+
+    let mut services = Vec::new();
+    for instance in instances {
+        let for_instance = |record_type| {
+            records.iter().find(|r| r.record_type == record_type && r.name.eq_ignore_ascii_case(&instance))
+        };
+        let (Some(srv), Some(txt)) = (for_instance(TYPE_SRV), for_instance(TYPE_TXT)) else {
+            continue;
+        };
+        let Some(port) = Reader::at(packet, srv.data_offset + 4).u16() else {
+            continue;
+        };
+        let fields = parse_txt(&packet[txt.data_offset..txt.data_offset + txt.data_len]);
+        let Some(device_id) = fields.get("id").cloned() else {
+            continue;
+        };
+        services.push(ServiceInfo {
+            name: fields.get("name").cloned().unwrap_or_else(|| device_id.clone()),
+            shares: fields.get("shares")
+                .map(|shares| shares.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+            device_id,
+            port,
+        });
+    }
+    services
+}
+
+fn parse_records(packet: &[u8]) -> Option<Vec<Record>> {
+    let mut reader = Reader::new(packet);
+    reader.skip(2)?;
+    let flags = reader.u16()?;
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let questions = reader.u16()?;
+    let records = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+    for _ in 0..questions {
+        reader.name()?;
+        reader.skip(4)?;
+    }
+
+    let mut parsed = Vec::with_capacity(records);
+    for _ in 0..records {
+        let name = reader.name()?;
+        let record_type = reader.u16()?;
+        reader.skip(6)?; // class and TTL
+        let data_len = reader.u16()? as usize;
+        let data_offset = reader.position;
+        reader.skip(data_len)?;
+        parsed.push(Record { name, record_type, data_offset, data_len });
+    }
+    Some(parsed)
+}
+
+fn parse_txt(data: &[u8]) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut rest = data;
+    while let Some((&len, tail)) = rest.split_first() {
+        let Some(entry) = tail.get(..len as usize) else {
+            break;
+        };
+        if let Some((key, value)) = String::from_utf8_lossy(entry).split_once('=') {
+            fields.insert(key.to_string(), value.to_string());
+        }
+        rest = &tail[len as usize..];
+    }
+    fields
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(packet: &'a [u8]) -> Self {
+        Self::at(packet, 0)
+    }
+
+    fn at(packet: &'a [u8], position: usize) -> Self {
+        Self { packet, position }
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        let end = self.position.checked_add(count)?;
+        (end <= self.packet.len()).then(|| self.position = end)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.packet.get(self.position..self.position + 2)?;
+        self.position += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Reads a possibly compressed name, leaving the reader after it.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut position = self.position;
+        let mut resume = None;
+        // Pointers must go backwards, but a malicious packet could still
+        // chain them, so cap the number followed
+        for _ in 0..128 {
+            let len = *self.packet.get(position)? as usize;
+            match len {
+                0 => {
+                    self.position = resume.unwrap_or(position + 1);
+                    return Some(labels.join("."));
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let low = *self.packet.get(position + 1)? as usize;
+                    resume.get_or_insert(position + 2);
+                    position = ((len & 0x3F) << 8) | low;
+                }
+                len => {
+                    let label = self.packet.get(position + 1..position + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).to_string());
+                    position += 1 + len;
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_and_response_round_trip() {
+        let query = parse_query(&encode_query()).unwrap();
+        assert!(query.asks_for_service && query.unicast);
+
+        let info = ServiceInfo {
+            device_id: "4f1c2b7e-8d3a-4c55-9e61-0b2a7d9f3c10".to_string(),
+            name: "Work laptop".to_string(),
+            shares: vec!["notes".to_string(), "journal".to_string()],
+            port: 8080,
+        };
+        let response = encode_response(7, &info);
+        assert!(parse_query(&response).is_none());
+        assert_eq!(parse_response(&response), vec![info]);
+
+        // The instance name points back at the record's own name, which
+        // starts right after the header
+        let mut compressed = header(0, FLAG_RESPONSE, 0, 1);
+        write_record(&mut compressed, SERVICE_TYPE, TYPE_PTR, CLASS_IN, &[1, b'x', 0xC0, 12]);
+        let records = parse_records(&compressed).unwrap();
+        let name = Reader::at(&compressed, records[0].data_offset).name().unwrap();
+        assert_eq!(name, "x._syncmd._tcp.local");
+
+        let looping = [0, 0, 0x84, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xC0, 12];
+        assert!(parse_records(&looping).is_none());
+    }
+}
@@ -0,0 +1,340 @@
+#![allow(dead_code)]
+
+//! Merge drivers: how a file changed on both sides since the last sync is
+//! merged, chosen by file type. Markdown is merged line by line (or section
+//! by section, see `sections.rs`), JSON key by key and CSV row by row. When
+//! no driver fits, or the driver finds both sides changed the same thing,
+//! the conflict is left to a conflict copy.
+//!
+//! Drivers implement `MergeDriver`; ones registered with
+//! `SyncEngine::with_merge_driver` are tried before the built-in ones.
+
+use crate::sections::SectionSplit;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    Merged(Vec<u8>),
+    /// Both sides changed the same thing, or nothing could merge the file
+    Conflict,
+}
+
+pub trait MergeDriver: Send + Sync {
+    /// Shown when reporting how a conflict was resolved
+    fn name(&self) -> &str;
+
+    fn applies_to(&self, path: &Path) -> bool;
+
+    /// Merges `local` and `remote`, both changed from `base`.
+    fn merge(&self, path: &Path, base: &[u8], local: &[u8], remote: &[u8]) -> MergeOutcome;
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+fn text(content: &[u8]) -> Option<&str> {
+    std::str::from_utf8(content).ok()
+}
+
+/// Markdown, with frontmatter kept apart and conflicting regions marked
+/// in the file.
+pub struct MarkdownDriver {
+    section_split: SectionSplit,
+}
+
+impl MarkdownDriver {
+    pub fn new(section_split: SectionSplit) -> Self {
+        Self { section_split }
+    }
+}
+
+impl MergeDriver for MarkdownDriver {
+    fn name(&self) -> &str {
+        "markdown"
+    }
+
+    fn applies_to(&self, path: &Path) -> bool {
+        crate::file_transfer::FileTransferManager::is_markdown_file(path)
+    }
+
+    fn merge(&self, path: &Path, base: &[u8], local: &[u8], remote: &[u8]) -> MergeOutcome {
+        let (Some(base), Some(local), Some(remote)) = (text(base), text(local), text(remote)) else {
+            return MergeOutcome::Conflict;
+        };
+        let merged = if self.section_split.applies_to(path) {
+            crate::sync::SyncEngine::merge_markdown_by_section(local, remote, base)
+        } else {
+            crate::sync::SyncEngine::merge_markdown_content(local, remote, base)
+        };
+        match merged {
+            Ok(merged) => MergeOutcome::Merged(merged.into_bytes()),
+            Err(_) => MergeOutcome::Conflict,
+        }
+    }
+}
+
+/// JSON, merged key by key through nested objects. Written back
+/// pretty-printed, with keys sorted.
+pub struct JsonDriver;
+
+/// Three-way merge of one value, `None` standing for a missing key. The
+/// outer `None` is a conflict.
+fn merge_value(base: Option<&Value>, local: Option<&Value>, remote: Option<&Value>) -> Option<Option<Value>> {
+    if local == remote || remote == base {
+        return Some(local.cloned());
+    }
+    if local == base {
+        return Some(remote.cloned());
+    }
+    let (Some(Value::Object(local)), Some(Value::Object(remote))) = (local, remote) else {
+        return None;
+    };
+    let empty = serde_json::Map::new();
+    let base = match base {
+        Some(Value::Object(base)) => base,
+        _ => &empty,
+    };
+    let mut merged = serde_json::Map::new();
+    for key in local.keys().chain(remote.keys()).chain(base.keys()) {
+        if merged.contains_key(key) {
+            continue;
+        }
+        if let Some(value) = merge_value(base.get(key), local.get(key), remote.get(key))? {
+            merged.insert(key.clone(), value);
+        }
+    }
+    Some(Some(Value::Object(merged)))
+}
+
+impl MergeDriver for JsonDriver {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn applies_to(&self, path: &Path) -> bool {
+        has_extension(path, &["json"])
+    }
+
+    fn merge(&self, _path: &Path, base: &[u8], local: &[u8], remote: &[u8]) -> MergeOutcome {
+        let parse = |content: &[u8]| serde_json::from_slice::<Value>(content).ok();
+        let (Some(base), Some(local_value), Some(remote)) = (parse(base), parse(local), parse(remote)) else {
+            return MergeOutcome::Conflict;
+        };
+        match merge_value(Some(&base), Some(&local_value), Some(&remote)) {
+            Some(Some(merged)) => {
+                let mut content = serde_json::to_vec_pretty(&merged).unwrap_or_default();
+                if local.ends_with(b"\n") {
+                    content.push(b'\n');
+                }
+                MergeOutcome::Merged(content)
+            }
+            _ => MergeOutcome::Conflict,
+        }
+    }
+}
+
+/// CSV with a header line, merged row by row. Rows are matched by their
+/// first column when it is unique, so both sides can edit different rows;
+/// otherwise by the whole line, an edit counting as a removed and an added
+/// row. An edit to a row wins over its removal. Quoted fields spanning lines
+/// aren't supported.
+pub struct CsvDriver;
+
+/// Row key -> row, in file order. `None` if the keys aren't unique.
+fn keyed_rows<'a>(rows: &[&'a str], key: impl Fn(&'a str) -> &'a str) -> Option<Vec<(&'a str, &'a str)>> {
+    let mut seen = HashSet::new();
+    let mut keyed = Vec::new();
+    for row in rows {
+        if !seen.insert(key(row)) {
+            return None;
+        }
+        keyed.push((key(row), *row));
+    }
+    Some(keyed)
+}
+
+fn merge_rows<'a>(base: &[&'a str], local: &[&'a str], remote: &[&'a str]) -> Option<Vec<&'a str>> {
+    let first_column = |row: &'a str| row.split(',').next().unwrap_or_default();
+    let (base, local, remote) = match (
+        keyed_rows(base, first_column),
+        keyed_rows(local, first_column),
+        keyed_rows(remote, first_column),
+    ) {
+        (Some(base), Some(local), Some(remote)) => (base, local, remote),
+        _ => (
+            keyed_rows(base, |row| row)?,
+            keyed_rows(local, |row| row)?,
+            keyed_rows(remote, |row| row)?,
+        ),
+    };
+    let find = |rows: &[(&'a str, &'a str)], key: &str| rows.iter().find(|(k, _)| *k == key).map(|(_, row)| *row);
+
+    let mut merged = Vec::new();
+    for (key, row) in &local {
+        let (base_row, remote_row) = (find(&base, key), find(&remote, key));
+        match (base_row, remote_row) {
+            (_, Some(remote_row)) if remote_row == *row => merged.push(*row),
+            (Some(base_row), Some(remote_row)) if base_row == *row => merged.push(remote_row),
+            (Some(base_row), Some(remote_row)) if base_row == remote_row => merged.push(*row),
+            (Some(_), Some(_)) => return None,
+            // Removed on the other side, unchanged here
+            (Some(base_row), None) if base_row == *row => {}
+            (None, Some(_)) => return None,
+            _ => merged.push(*row),
+        }
+    }
+    for (key, row) in &remote {
+        if find(&local, key).is_some() {
+            continue;
+        }
+        match find(&base, key) {
+            None => merged.push(*row),
+            // Removed here, but edited on the other side
+            Some(base_row) if base_row != *row => merged.push(*row),
+            Some(_) => {}
+        }
+    }
+    Some(merged)
+}
+
+/// The header line and the rows after it, blank lines skipped.
+fn split_csv(content: &str) -> (Option<&str>, Vec<&str>) {
+    let mut lines = content.lines().filter(|line| !line.is_empty());
+    (lines.next(), lines.collect())
+}
+
+impl MergeDriver for CsvDriver {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    fn applies_to(&self, path: &Path) -> bool {
+        has_extension(path, &["csv"])
+    }
+
+    fn merge(&self, _path: &Path, base: &[u8], local: &[u8], remote: &[u8]) -> MergeOutcome {
+        let (Some(base), Some(local), Some(remote)) = (text(base), text(local), text(remote)) else {
+            return MergeOutcome::Conflict;
+        };
+        let (base_header, base_rows) = split_csv(base);
+        let (local_header, local_rows) = split_csv(local);
+        let (remote_header, remote_rows) = split_csv(remote);
+        let multiline = [base, local, remote].iter().any(|content| content.lines().any(|line| line.matches('"').count() % 2 == 1));
+        if multiline || local_header != remote_header || local_header.is_none() {
+            return MergeOutcome::Conflict;
+        }
+        let base_rows = if base_header == local_header { base_rows } else { Vec::new() };
+        match merge_rows(&base_rows, &local_rows, &remote_rows) {
+            Some(rows) => {
+                let mut content = String::new();
+                for line in local_header.into_iter().chain(rows) {
+                    content.push_str(line);
+                    content.push('\n');
+                }
+                MergeOutcome::Merged(content.into_bytes())
+            }
+            None => MergeOutcome::Conflict,
+        }
+    }
+}
+
+/// The drivers tried for a file, in order.
+#[derive(Clone)]
+pub struct MergeDrivers {
+    drivers: Vec<Arc<dyn MergeDriver>>,
+}
+
+impl MergeDrivers {
+    /// Markdown, JSON and CSV.
+    pub fn builtin(section_split: SectionSplit) -> Self {
+        Self {
+            drivers: vec![
+                Arc::new(MarkdownDriver::new(section_split)),
+                Arc::new(JsonDriver),
+                Arc::new(CsvDriver),
+            ],
+        }
+    }
+
+    /// Tries `driver` before the ones already registered.
+    pub fn register(&mut self, driver: Arc<dyn MergeDriver>) {
+        self.drivers.insert(0, driver);
+    }
+
+    pub fn driver_for(&self, path: &Path) -> Option<&dyn MergeDriver> {
+        self.drivers.iter().find(|driver| driver.applies_to(path)).map(|driver| driver.as_ref())
+    }
+
+    /// Merges with the first driver for the file, a conflict if there is none.
+    pub fn merge(&self, path: &Path, base: &[u8], local: &[u8], remote: &[u8]) -> MergeOutcome {
+        match self.driver_for(path) {
+            Some(driver) => driver.merge(path, base, local, remote),
+            None => MergeOutcome::Conflict,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(outcome: MergeOutcome) -> String {
+        match outcome {
+            MergeOutcome::Merged(content) => String::from_utf8(content).unwrap(),
+            MergeOutcome::Conflict => panic!("unexpected conflict"),
+        }
+    }
+
+    #[test]
+    fn test_builtin_drivers() {
+        let drivers = MergeDrivers::builtin(SectionSplit::default());
+        let merge = |path: &str, base: &str, local: &str, remote: &str| {
+            drivers.merge(Path::new(path), base.as_bytes(), local.as_bytes(), remote.as_bytes())
+        };
+
+        let base = r#"{"theme": "dark", "editor": {"font": 12, "wrap": true}}"#;
+        let local = r#"{"theme": "dark", "editor": {"font": 14, "wrap": true}}"#;
+        let remote = r#"{"theme": "dark", "editor": {"font": 12}, "plugins": ["git"]}"#;
+        let json: Value = serde_json::from_str(&merged(merge("settings.json", base, local, remote))).unwrap();
+        assert_eq!(json, serde_json::json!({"theme": "dark", "editor": {"font": 14}, "plugins": ["git"]}));
+        let remote = r#"{"theme": "dark", "editor": {"font": 16, "wrap": true}}"#;
+        assert_eq!(merge("settings.json", base, local, remote), MergeOutcome::Conflict);
+
+        let base = "id,task,done\n1,write,no\n2,review,no\n";
+        let local = "id,task,done\n1,write,yes\n2,review,no\n3,ship,no\n";
+        let remote = "id,task,done\n2,review,yes\n4,celebrate,no\n";
+        assert_eq!(merged(merge("tasks.csv", base, local, remote)), "id,task,done\n1,write,yes\n2,review,yes\n3,ship,no\n4,celebrate,no\n");
+        let remote = "id,task,done\n1,write,later\n2,review,no\n";
+        assert_eq!(merge("tasks.csv", base, local, remote), MergeOutcome::Conflict);
+
+        assert_eq!(merge("photo.png", "a", "b", "c"), MergeOutcome::Conflict);
+    }
+
+    #[test]
+    fn test_registered_driver_goes_first() {
+        struct Union;
+        impl MergeDriver for Union {
+            fn name(&self) -> &str {
+                "union"
+            }
+            fn applies_to(&self, path: &Path) -> bool {
+                has_extension(path, &["csv", "txt"])
+            }
+            fn merge(&self, _path: &Path, _base: &[u8], local: &[u8], remote: &[u8]) -> MergeOutcome {
+                MergeOutcome::Merged([local, remote].concat())
+            }
+        }
+
+        let mut drivers = MergeDrivers::builtin(SectionSplit::default());
+        drivers.register(Arc::new(Union));
+        assert_eq!(drivers.driver_for(Path::new("tasks.csv")).unwrap().name(), "union");
+        assert_eq!(drivers.driver_for(Path::new("notes.md")).unwrap().name(), "markdown");
+        assert_eq!(merged(drivers.merge(Path::new("a.txt"), b"", b"x\n", b"y\n")), "x\ny\n");
+    }
+}
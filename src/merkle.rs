@@ -0,0 +1,219 @@
+#![allow(dead_code)]
+
+//! Directory-level Merkle tree over a root's file hashes, so client and
+//! server can find what differs without sending the whole index. A
+//! directory's hash covers the names and hashes of everything below it:
+//! when two root hashes match the sides hold the same files, and otherwise
+//! only directories whose hashes differ are opened up further.
+
+use crate::types::FileMetadata;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// A file or directory directly inside a directory of the tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeEntry {
+    pub name: String,
+    pub dir: bool,
+    /// Content hash and permissions of a file, tree hash of a directory
+    pub hash: String,
+}
+
+/// One directory with its children, as exchanged during negotiation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeNode {
+    /// Relative to the root, empty for the root itself
+    pub path: PathBuf,
+    pub hash: String,
+    pub children: Vec<TreeEntry>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MerkleTree {
+    /// Directory -> children sorted by name
+    children: HashMap<PathBuf, Vec<TreeEntry>>,
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl MerkleTree {
+    pub fn build<'a>(files: impl IntoIterator<Item = &'a FileMetadata>) -> Self {
+        let mut entries: HashMap<PathBuf, BTreeMap<String, TreeEntry>> = HashMap::new();
+        entries.insert(PathBuf::new(), BTreeMap::new());
+        for file in files {
+            let (Some(parent), Some(name)) = (file.path.parent(), file.path.file_name()) else {
+                continue;
+            };
+            let name = name.to_string_lossy().to_string();
+            entries.entry(parent.to_path_buf()).or_default()
+                .insert(name.clone(), TreeEntry { name, dir: false, hash: file_hash(file) });
+            // Every ancestor needs a node, even without files of its own
+            for ancestor in parent.ancestors().skip(1) {
+                entries.entry(ancestor.to_path_buf()).or_default();
+            }
+        }
+
+        // Deepest first, so a directory's subdirectories are hashed before it
+        let mut dirs: Vec<PathBuf> = entries.keys().cloned().collect();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        let mut tree = MerkleTree::default();
+        for dir in dirs {
+            let mut children = entries.remove(&dir).unwrap_or_default();
+            for (name, entry) in children.iter_mut() {
+                if let Some(hash) = tree.hashes.get(&dir.join(name)) {
+                    entry.dir = true;
+                    entry.hash = hash.clone();
+                }
+            }
+            let children: Vec<TreeEntry> = children.into_values().collect();
+            let hash = hash_children(&children);
+            if let Some((parent, name)) = dir.parent().zip(dir.file_name()) {
+                let name = name.to_string_lossy().to_string();
+                entries.entry(parent.to_path_buf()).or_default()
+                    .entry(name.clone())
+                    .or_insert(TreeEntry { name, dir: true, hash: String::new() });
+            }
+            tree.hashes.insert(dir.clone(), hash);
+            tree.children.insert(dir, children);
+        }
+        tree
+    }
+
+    pub fn root_hash(&self) -> &str {
+        self.hashes.get(Path::new("")).map(String::as_str).unwrap_or_default()
+    }
+
+    pub fn hash(&self, dir: &Path) -> Option<&str> {
+        self.hashes.get(dir).map(String::as_str)
+    }
+
+    pub fn children(&self, dir: &Path) -> &[TreeEntry] {
+        self.children.get(dir).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// The directory at `dir`, or an empty node if there is none.
+    pub fn node(&self, dir: &Path) -> TreeNode {
+        TreeNode {
+            path: dir.to_path_buf(),
+            hash: self.hash(dir).unwrap_or_default().to_string(),
+            children: self.children(dir).to_vec(),
+        }
+    }
+
+    /// Compares a directory with the other side's version of it. Returns the
+    /// subdirectories whose hashes differ, to be compared next, and the
+    /// files and directories that are different or only on one side.
+    pub fn diff(&self, remote: &TreeNode) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let local: HashMap<&str, &TreeEntry> =
+            self.children(&remote.path).iter().map(|entry| (entry.name.as_str(), entry)).collect();
+        let mut descend = Vec::new();
+        let mut differing = Vec::new();
+        for entry in &remote.children {
+            match local.get(entry.name.as_str()) {
+                Some(ours) if ours.hash == entry.hash && ours.dir == entry.dir => {}
+                Some(ours) if ours.dir && entry.dir => descend.push(remote.path.join(&entry.name)),
+                _ => differing.push(remote.path.join(&entry.name)),
+            }
+        }
+        let theirs: std::collections::HashSet<&str> = remote.children.iter().map(|entry| entry.name.as_str()).collect();
+        differing.extend(
+            local.keys()
+                .filter(|name| !theirs.contains(*name))
+                .map(|name| remote.path.join(name)),
+        );
+        (descend, differing)
+    }
+}
+
+/// A file's entry hash: its content hash, with its permissions where they
+/// say more than the default, so a change of mode alone shows in the tree.
+/// Sides that disagree on whether there are mode bits at all, Windows and
+/// the rest, descend to the files and let the sync engine compare them.
+fn file_hash(file: &FileMetadata) -> String {
+    match file.permissions.mode {
+        Some(mode) => format!("{}:{:o}", file.hash, mode & crate::types::PERMISSION_BITS),
+        None if file.permissions.readonly => format!("{}:ro", file.hash),
+        None => file.hash.clone(),
+    }
+}
+
+fn hash_children(children: &[TreeEntry]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for child in children {
+        hasher.update(if child.dir { b"d" } else { b"f" });
+        hasher.update(child.name.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(child.hash.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Merkle root over the hashes of a file's chunks, in order. Neighbours are
+/// hashed together level by level; an odd one out moves up as it is.
+pub fn chunk_root(chunk_hashes: &[String]) -> String {
+    let mut level: Vec<String> = chunk_hashes.to_vec();
+    if level.is_empty() {
+        return blake3::hash(b"").to_hex().to_string();
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = blake3::Hasher::new();
+                    hasher.update(left.as_bytes());
+                    hasher.update(&[0]);
+                    hasher.update(right.as_bytes());
+                    hasher.finalize().to_hex().to_string()
+                }
+                [single] => single.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level.remove(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, content: &str) -> FileMetadata {
+        FileMetadata::for_test(path, content.as_bytes())
+    }
+
+    #[test]
+    fn test_merkle_diff() {
+        let local = [file("a.md", "1"), file("notes/b.md", "2"), file("notes/deep/c.md", "3"), file("work/d.md", "4")];
+        let mut remote = local.to_vec();
+        let same = MerkleTree::build(&remote);
+        assert_eq!(MerkleTree::build(&local).root_hash(), same.root_hash());
+
+        remote[2].hash = "changed".to_string();
+        remote.push(file("photos/e.png", "5"));
+        remote.retain(|file| file.path != Path::new("work/d.md"));
+        let (local, remote) = (MerkleTree::build(&local), MerkleTree::build(&remote));
+        assert_ne!(local.root_hash(), remote.root_hash());
+
+        let (descend, differing) = local.diff(&remote.node(Path::new("")));
+        assert_eq!(descend, vec![PathBuf::from("notes")]);
+        let mut differing = differing;
+        differing.sort();
+        assert_eq!(differing, vec![PathBuf::from("photos"), PathBuf::from("work")]);
+
+        let (descend, differing) = local.diff(&remote.node(Path::new("notes")));
+        assert_eq!(descend, vec![PathBuf::from("notes/deep")]);
+        assert!(differing.is_empty());
+        let (descend, differing) = local.diff(&remote.node(Path::new("notes/deep")));
+        assert!(descend.is_empty());
+        assert_eq!(differing, vec![PathBuf::from("notes/deep/c.md")]);
+
+        // A change of mode alone is a change
+        let mut executable = file("a.md", "1");
+        executable.permissions.mode = Some(0o755);
+        let plain = MerkleTree::build(&[file("a.md", "1")]);
+        let (_, differing) = plain.diff(&MerkleTree::build(&[executable]).node(Path::new("")));
+        assert_eq!(differing, vec![PathBuf::from("a.md")]);
+    }
+}
@@ -0,0 +1,363 @@
+#![allow(dead_code)]
+
+//! Moving a device to a new machine. `syncmd migrate export` packs what
+//! makes the device itself into a tar archive: the config with its roots,
+//! remotes and secrets, the identity key peers trust, the registry of peer
+//! addresses and the index of every root. `syncmd migrate import` unpacks
+//! it on the new machine, which then syncs as the same device without
+//! pairing again. Files copied over with the roots are only hashed and
+//! found unchanged, nothing is downloaded again.
+//!
+//! Secrets stay in the archive as they are in the config, encrypted only if
+//! the config's are, so the archive needs the same care as the config.
+
+use crate::cli::{Config, CONFIG_FILE_NAME};
+use crate::discovery::IDENTITY_FILE_NAME;
+use crate::index_store::{IndexStore, INDEX_DB_NAME};
+use crate::peer_registry::PEERS_DB_NAME;
+use crate::types::SyncError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_NAME: &str = "migration.json";
+const FORMAT_VERSION: u32 = 1;
+
+/// What an archive holds besides the files themselves.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    device_id: String,
+    device_name: String,
+    exported_at: chrono::DateTime<chrono::Utc>,
+    roots: Vec<ArchivedRoot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedRoot {
+    path: PathBuf,
+    /// Archive entry holding the root's index, if it had one
+    index: Option<String>,
+}
+
+/// `OLD=NEW`: a root at or below `OLD` on the old machine is at the same
+/// place below `NEW` on this one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootRemap {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl RootRemap {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.split_once('=') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(Self {
+                from: PathBuf::from(from),
+                to: PathBuf::from(to),
+            }),
+            _ => Err(format!("Expected OLD=NEW, got {}", value)),
+        }
+    }
+
+    /// Where `path` is with the most specific of `remaps` applied.
+    fn apply(remaps: &[RootRemap], path: &Path) -> PathBuf {
+        remaps
+            .iter()
+            .filter_map(|remap| path.strip_prefix(&remap.from).ok().map(|rest| (remap, rest)))
+            .max_by_key(|(remap, _)| remap.from.components().count())
+            .map(|(remap, rest)| remap.to.join(rest))
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    pub device_name: String,
+    pub roots: usize,
+    pub indexes: usize,
+}
+
+/// How a root was restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootOutcome {
+    /// The index is back; `missing` files it listed aren't in the folder
+    /// and will be downloaded, not deleted from the server
+    Resumed { files: usize, missing: usize },
+    /// The folder isn't there, so it's synced from scratch once it is
+    Missing,
+    /// The old machine hadn't indexed it
+    NotIndexed,
+}
+
+#[derive(Debug)]
+pub struct RestoredRoot {
+    pub path: PathBuf,
+    pub outcome: RootOutcome,
+}
+
+#[derive(Debug)]
+pub struct ImportReport {
+    pub device_id: String,
+    pub device_name: String,
+    pub roots: Vec<RestoredRoot>,
+}
+
+/// The directory the config, identity key and peer registry are kept in.
+pub fn config_dir() -> Result<PathBuf, SyncError> {
+    dirs::config_dir()
+        .map(|dir| dir.join("syncmd"))
+        .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))
+}
+
+/// Writes the device kept in `config_dir` into a tar archive at `output`.
+/// Databases are copied consistently even while the daemon writes to them.
+pub fn export(config_dir: &Path, output: &Path) -> Result<ExportReport, SyncError> {
+    let config_bytes = match std::fs::read(config_dir.join(CONFIG_FILE_NAME)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(SyncError::Config("This device isn't set up, there is nothing to export".to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let config: Config = serde_json::from_slice(&config_bytes)?;
+    // Database copies are made next to the archive and removed once packed
+    let staging = output.with_extension("staging");
+    std::fs::create_dir_all(&staging)?;
+    let result = write_archive(config_dir, &config, &config_bytes, &staging, output);
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+fn write_archive(
+    config_dir: &Path,
+    config: &Config,
+    config_bytes: &[u8],
+    staging: &Path,
+    output: &Path,
+) -> Result<ExportReport, SyncError> {
+    let mut builder = tar::Builder::new(std::fs::File::create(output)?);
+    let mut report = ExportReport { device_name: config.device_name.clone(), ..Default::default() };
+
+    append_bytes(&mut builder, CONFIG_FILE_NAME, config_bytes)?;
+    let identity = config_dir.join(IDENTITY_FILE_NAME);
+    if identity.is_file() {
+        builder.append_path_with_name(&identity, IDENTITY_FILE_NAME)?;
+    }
+    let peers = config_dir.join(PEERS_DB_NAME);
+    if peers.is_file() {
+        let copy = staging.join(PEERS_DB_NAME);
+        copy_database(&peers, &copy)?;
+        builder.append_path_with_name(&copy, PEERS_DB_NAME)?;
+    }
+
+    let mut roots = Vec::new();
+    for (i, root) in config.sync_roots.iter().enumerate() {
+        let index = crate::state::dir(&root.path).join(INDEX_DB_NAME);
+        let entry = if index.is_file() {
+            let name = format!("roots/{}/{}", i, INDEX_DB_NAME);
+            let copy = staging.join(format!("{}-{}", i, INDEX_DB_NAME));
+            copy_database(&index, &copy)?;
+            builder.append_path_with_name(&copy, &name)?;
+            report.indexes += 1;
+            Some(name)
+        } else {
+            None
+        };
+        roots.push(ArchivedRoot { path: root.path.clone(), index: entry });
+    }
+    report.roots = roots.len();
+
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        device_id: config.device_id.clone(),
+        device_name: config.device_name.clone(),
+        exported_at: chrono::Utc::now(),
+        roots,
+    };
+    append_bytes(&mut builder, MANIFEST_NAME, &serde_json::to_vec_pretty(&manifest)?)?;
+    builder.finish()?;
+    Ok(report)
+}
+
+/// Restores the device in `archive` into `config_dir`, moving roots by
+/// `remaps`. An existing config of another device is only replaced with
+/// `force`.
+pub fn import(archive: &Path, config_dir: &Path, remaps: &[RootRemap], force: bool) -> Result<ImportReport, SyncError> {
+    let mut entries = read_archive(archive)?;
+    let manifest: Manifest = match entries.remove(MANIFEST_NAME) {
+        Some(bytes) => serde_json::from_slice(&bytes)?,
+        None => return Err(SyncError::Config(format!("{} is not a migration archive", archive.display()))),
+    };
+    if manifest.version > FORMAT_VERSION {
+        return Err(SyncError::Config(format!(
+            "{} was written by a newer syncmd (format {}), upgrade to import it",
+            archive.display(),
+            manifest.version
+        )));
+    }
+    let mut config: Config = match entries.remove(CONFIG_FILE_NAME) {
+        Some(bytes) => serde_json::from_slice(&bytes)?,
+        None => return Err(SyncError::Config(format!("{} holds no config", archive.display()))),
+    };
+
+    let config_path = config_dir.join(CONFIG_FILE_NAME);
+    if let Ok(existing) = std::fs::read(&config_path) {
+        let existing: Config = serde_json::from_slice(&existing)?;
+        if existing.device_id != config.device_id && !force {
+            return Err(SyncError::Conflict(format!(
+                "this machine is already set up as {} ({}), pass --force to replace it",
+                existing.device_name, existing.device_id
+            )));
+        }
+    }
+
+    for root in &mut config.sync_roots {
+        root.path = RootRemap::apply(remaps, &root.path);
+    }
+    config.state_dir = config.state_dir.map(|dir| RootRemap::apply(remaps, &dir));
+
+    std::fs::create_dir_all(config_dir)?;
+    std::fs::write(&config_path, serde_json::to_string_pretty(&config)?)?;
+    if let Some(identity) = entries.remove(IDENTITY_FILE_NAME) {
+        let path = config_dir.join(IDENTITY_FILE_NAME);
+        std::fs::write(&path, identity)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    if let Some(peers) = entries.remove(PEERS_DB_NAME) {
+        std::fs::write(config_dir.join(PEERS_DB_NAME), peers)?;
+    }
+
+    crate::state::set_central(config.state_dir.clone());
+    let mut roots = Vec::new();
+    for (archived, root) in manifest.roots.iter().zip(&config.sync_roots) {
+        let index = archived.index.as_ref().and_then(|entry| entries.remove(entry));
+        let outcome = match index {
+            None => RootOutcome::NotIndexed,
+            Some(_) if !root.path.is_dir() => RootOutcome::Missing,
+            Some(index) => restore_index(&root.path, &index)?,
+        };
+        roots.push(RestoredRoot { path: root.path.clone(), outcome });
+    }
+
+    Ok(ImportReport {
+        device_id: config.device_id,
+        device_name: config.device_name,
+        roots,
+    })
+}
+
+/// Puts a root's archived index in place. Files it lists that weren't
+/// copied over are forgotten, so the next sync downloads them instead of
+/// taking them for deleted here. Directory stamps are dropped as they
+/// can't match a copy.
+fn restore_index(sync_root: &Path, index: &[u8]) -> Result<RootOutcome, SyncError> {
+    let state_dir = crate::state::dir(sync_root);
+    std::fs::create_dir_all(&state_dir)?;
+    let db_path = state_dir.join(INDEX_DB_NAME);
+    std::fs::write(&db_path, index)?;
+
+    let mut store = IndexStore::open_at(&db_path)?;
+    let state = store.load_state(String::new(), sync_root.to_path_buf())?;
+    let missing: Vec<PathBuf> = state
+        .local_files
+        .keys()
+        .filter(|path| !sync_root.join(path).is_file())
+        .cloned()
+        .collect();
+    store.forget(&missing)?;
+    store.save_directories(&HashMap::new())?;
+    Ok(RootOutcome::Resumed {
+        files: state.local_files.len() - missing.len(),
+        missing: missing.len(),
+    })
+}
+
+/// Copies a SQLite database as of one moment, whatever else has it open.
+fn copy_database(source: &Path, destination: &Path) -> Result<(), SyncError> {
+    let conn = rusqlite::Connection::open(source)?;
+    conn.execute("VACUUM INTO ?1", [destination.to_string_lossy()])?;
+    Ok(())
+}
+
+fn append_bytes<W: std::io::Write>(builder: &mut tar::Builder<W>, name: &str, bytes: &[u8]) -> Result<(), SyncError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, name, bytes)?;
+    Ok(())
+}
+
+fn read_archive(archive: &Path) -> Result<HashMap<String, Vec<u8>>, SyncError> {
+    let mut entries = HashMap::new();
+    for entry in tar::Archive::new(std::fs::File::open(archive)?).entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes)?;
+        entries.insert(name, bytes);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::FileIndexer;
+
+    #[test]
+    fn test_migrate_device() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let root = old.path().join("notes");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("kept.md"), "kept").unwrap();
+        std::fs::write(root.join("lost.md"), "lost").unwrap();
+        let mut store = IndexStore::open(&root).unwrap();
+        store.save_state(&FileIndexer::new("laptop".to_string(), root.clone()).index_directory().unwrap()).unwrap();
+
+        let old_config = old.path().join("config");
+        std::fs::create_dir_all(&old_config).unwrap();
+        let config = serde_json::json!({
+            "device_id": "device-1",
+            "device_name": "laptop",
+            "sync_roots": [{ "path": root, "enabled": true, "last_sync": null }],
+            "auth_token": "secret",
+        });
+        std::fs::write(old_config.join(CONFIG_FILE_NAME), config.to_string()).unwrap();
+        std::fs::write(old_config.join(IDENTITY_FILE_NAME), [7u8; 32]).unwrap();
+        let archive = old.path().join("laptop.tar");
+        let report = export(&old_config, &archive).unwrap();
+        assert_eq!((report.roots, report.indexes), (1, 1));
+
+        // Only one file was copied to the new machine
+        let new_root = new.path().join("notes");
+        std::fs::create_dir_all(&new_root).unwrap();
+        std::fs::write(new_root.join("kept.md"), "kept").unwrap();
+        let new_config = new.path().join("config");
+        let remap = RootRemap::parse(&format!("{}={}", old.path().display(), new.path().display())).unwrap();
+        let report = import(&archive, &new_config, &[remap], false).unwrap();
+        assert_eq!(report.device_id, "device-1");
+        assert_eq!(report.roots[0].path, new_root);
+        assert_eq!(report.roots[0].outcome, RootOutcome::Resumed { files: 1, missing: 1 });
+        assert_eq!(std::fs::read(new_config.join(IDENTITY_FILE_NAME)).unwrap(), [7u8; 32]);
+        let restored: Config = serde_json::from_slice(&std::fs::read(new_config.join(CONFIG_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!((restored.auth_token.as_deref(), &restored.sync_roots[0].path), (Some("secret"), &new_root));
+        let store = IndexStore::open(&new_root).unwrap();
+        assert!(store.get(Path::new("kept.md")).unwrap().is_some());
+        assert!(store.get(Path::new("lost.md")).unwrap().is_none());
+
+        // Another device set up here is only replaced on request
+        let mut other = config.clone();
+        other["device_id"] = "device-2".into();
+        std::fs::write(new_config.join(CONFIG_FILE_NAME), other.to_string()).unwrap();
+        assert!(import(&archive, &new_config, &[], false).is_err());
+        assert!(import(&archive, &new_config, &[], true).is_ok());
+    }
+}
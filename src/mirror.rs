@@ -0,0 +1,332 @@
+#![allow(dead_code)]
+
+//! One-way replication of a sync root to a backup target. Nothing is ever
+//! read back from the target into the root.
+
+use crate::capabilities::Capability;
+use crate::encryption::FolderKey;
+use crate::index_store::STATE_DIR_NAME;
+use crate::indexer::FileIndexer;
+use crate::network::{self, NetworkMessage};
+use crate::types::{FileMetadata, SyncError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MirrorOptions {
+    /// Remove files from the target that are gone from the source
+    pub delete: bool,
+    /// Re-read every written file and check its hash
+    pub verify: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MirrorReport {
+    pub target: String,
+    pub copied: Vec<PathBuf>,
+    pub updated: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    pub unchanged: usize,
+    pub bytes: u64,
+    /// Files whose copy on the target doesn't match the source after writing
+    pub corrupt: Vec<PathBuf>,
+    /// Files that couldn't be read from the source or written to the target
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// The idempotency key of each upload, kept across attempts so an upload
+/// retried after a lost connection is stored once.
+#[derive(Debug, Default)]
+pub struct UploadKeys {
+    keys: HashMap<(PathBuf, String), String>,
+}
+
+impl UploadKeys {
+    /// The key for uploading this version of `path`, the same every time.
+    fn key(&mut self, path: &Path, hash: &str) -> String {
+        self.keys
+            .entry((path.to_path_buf(), hash.to_string()))
+            .or_insert_with(|| uuid::Uuid::new_v4().to_string())
+            .clone()
+    }
+}
+
+impl MirrorReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.failed.is_empty()
+    }
+}
+
+/// Replicates the root into a local directory, such as a mounted backup
+/// drive. Files are compared by content hash, so the target's timestamps
+/// don't matter.
+pub fn mirror_to_directory(
+    indexer: &FileIndexer,
+    target: &Path,
+    options: MirrorOptions,
+) -> Result<MirrorReport, SyncError> {
+    let source = indexer.index_directory()?.local_files;
+    fs::create_dir_all(target)?;
+    let existing = hash_directory(target)?;
+    let mut report = MirrorReport {
+        target: target.display().to_string(),
+        ..Default::default()
+    };
+
+    let mut paths: Vec<&PathBuf> = source.keys().collect();
+    paths.sort();
+    for path in paths {
+        let metadata = &source[path];
+        let previous = existing.get(path);
+        if previous == Some(&metadata.hash) {
+            report.unchanged += 1;
+            continue;
+        }
+
+        let content = match indexer.read_file_content(path) {
+            Ok(content) => content,
+            Err(e) => {
+                report.failed.push((path.clone(), e.to_string()));
+                continue;
+            }
+        };
+        let destination = target.join(path);
+        if let Err(e) = write_atomically(&destination, &content) {
+            report.failed.push((path.clone(), e.to_string()));
+            continue;
+        }
+        if options.verify && !matches_hash(&destination, &metadata.hash) {
+            report.corrupt.push(path.clone());
+        }
+        report.bytes += content.len() as u64;
+        match previous {
+            Some(_) => report.updated.push(path.clone()),
+            None => report.copied.push(path.clone()),
+        }
+    }
+
+    if options.delete {
+        let mut extra: Vec<&PathBuf> = existing.keys().filter(|path| !source.contains_key(*path)).collect();
+        extra.sort();
+        for path in extra {
+            match fs::remove_file(target.join(path)) {
+                Ok(()) => {
+                    remove_empty_parents(target, path);
+                    report.deleted.push(path.clone());
+                }
+                Err(e) => report.failed.push((path.clone(), e.to_string())),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Replicates the root to a server by uploading whatever the server lacks
+/// or has a different version of. The server protocol has no deletes, so
+/// `delete` is refused rather than silently ignored. Uploads are compressed
+/// if the server `negotiated` compression, and files it refuses, when it
+/// says so, count as failed. Uploads carry their key from `keys`, so a run
+/// retried with the same keys doesn't store anything twice.
+pub async fn mirror_to_remote(
+    indexer: &FileIndexer,
+    stream: &mut network::Connection,
+    target: &str,
+    options: MirrorOptions,
+    folder_key: Option<&FolderKey>,
+    negotiated: &[Capability],
+    keys: &mut UploadKeys,
+) -> Result<MirrorReport, SyncError> {
+    let encoding = crate::network::Encoding::negotiated(negotiated);
+    let results = negotiated.contains(&Capability::UploadResults);
+    let keyed = negotiated.contains(&Capability::IdempotencyKeys);
+    let windowed = negotiated.contains(&Capability::WindowedTransfer);
+    if options.delete {
+        return Err(SyncError::Network(format!("{} doesn't support deletion mirroring", target)));
+    }
+    let source = tokio::task::block_in_place(|| indexer.index_directory())?.local_files;
+    let existing = remote_hashes(stream).await?;
+    let mut report = MirrorReport {
+        target: target.to_string(),
+        ..Default::default()
+    };
+
+    let mut files: Vec<&FileMetadata> = source.values().collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut uploaded = Vec::new();
+    for metadata in files {
+        // The server only ever sees the encrypted form of an encrypted root
+        let remote = match folder_key {
+            Some(key) => key.encrypt_metadata(metadata),
+            None => metadata.clone(),
+        };
+        let previous = existing.get(&remote.path);
+        if previous == Some(&remote.hash) {
+            report.unchanged += 1;
+            continue;
+        }
+        let content = match indexer.read_file_content(&metadata.path) {
+            Ok(content) => content,
+            Err(e) => {
+                report.failed.push((metadata.path.clone(), e.to_string()));
+                continue;
+            }
+        };
+        let content = match folder_key {
+            Some(key) => key.encrypt_content(&metadata.path, &content),
+            None => content,
+        };
+        report.bytes += content.len() as u64;
+        let idempotency_key = keyed.then(|| keys.key(&remote.path, &remote.hash));
+        // Large files go as windowed transfers, which resume where an
+        // interrupted upload stopped
+        let rejection = if windowed && content.len() as u64 >= crate::file_transfer::WINDOWED_TRANSFER_SIZE {
+            crate::bandwidth::throttle(stream, content.len()).await;
+            match network::upload_windowed(stream, &remote, content, idempotency_key, negotiated).await {
+                Err(SyncError::Cancelled(_)) => {
+                    report.failed.push((metadata.path.clone(), "cancelled".to_string()));
+                    continue;
+                }
+                uploaded => uploaded?,
+            }
+        } else {
+            let message = NetworkMessage::FileTransfer {
+                path: remote.path.to_string_lossy().to_string(),
+                content,
+                metadata: remote.clone(),
+                idempotency_key,
+            };
+            let encoded = crate::network::encode_message(&message, encoding)?;
+            crate::bandwidth::throttle(stream, encoded.len()).await;
+            stream.write_all(&encoded).await?;
+            if results { upload_result(stream).await? } else { None }
+        };
+        if let Some(rejection) = rejection {
+            report.failed.push((metadata.path.clone(), format!("refused by the server, {}", rejection)));
+            continue;
+        }
+        match previous {
+            Some(_) => report.updated.push(metadata.path.clone()),
+            None => report.copied.push(metadata.path.clone()),
+        }
+        uploaded.push((metadata, remote));
+    }
+
+    if options.verify && !uploaded.is_empty() {
+        // Uploads aren't acknowledged, so check what the server now lists
+        let stored = remote_hashes(stream).await?;
+        for (metadata, remote) in uploaded {
+            if stored.get(&remote.path) != Some(&remote.hash) {
+                report.corrupt.push(metadata.path.clone());
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Waits for the server's answer to an upload.
+async fn upload_result(stream: &mut network::Connection) -> Result<Option<network::UploadRejection>, SyncError> {
+    match stream.read_message().await? {
+        NetworkMessage::UploadResult { rejection, .. } => Ok(rejection),
+        _ => Err(SyncError::Network("Unexpected reply to an upload".to_string())),
+    }
+}
+
+async fn remote_hashes(stream: &mut network::Connection) -> Result<HashMap<PathBuf, String>, SyncError> {
+    let mut hashes = HashMap::new();
+    network::list_remote_files(stream, None, |files| {
+        hashes.extend(files.into_iter().map(|file| (file.path, file.hash)));
+    })
+    .await?;
+    Ok(hashes)
+}
+
+/// Content hashes of every file under `root`, keyed by relative path.
+fn hash_directory(root: &Path) -> Result<HashMap<PathBuf, String>, SyncError> {
+    let mut hashes = HashMap::new();
+    // A target that is itself a sync root keeps its own state
+    let entries = WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != STATE_DIR_NAME);
+    for entry in entries {
+        let entry = entry.map_err(|e| SyncError::Io(e.into()))?;
+        if !entry.file_type().is_file() || is_partial(entry.path()) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(root)?.to_path_buf();
+        hashes.insert(relative, FileIndexer::calculate_file_hash(entry.path())?);
+    }
+    Ok(hashes)
+}
+
+fn matches_hash(path: &Path, hash: &str) -> bool {
+    FileIndexer::calculate_file_hash(path).is_ok_and(|actual| actual == hash)
+}
+
+const PARTIAL_SUFFIX: &str = ".syncmd-partial";
+
+fn is_partial(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(PARTIAL_SUFFIX)
+}
+
+/// Writes next to the destination and renames, so an interrupted backup
+/// never leaves a truncated file under the real name.
+fn write_atomically(destination: &Path, content: &[u8]) -> Result<(), SyncError> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut partial = destination.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    let partial = PathBuf::from(partial);
+    fs::write(&partial, content)?;
+    fs::rename(&partial, destination)?;
+    Ok(())
+}
+
+fn remove_empty_parents(root: &Path, relative: &Path) {
+    let mut parent = relative.parent();
+    while let Some(dir) = parent.filter(|dir| !dir.as_os_str().is_empty()) {
+        // Fails, and stops, at the first directory that still has files
+        if fs::remove_dir(root.join(dir)).is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirror_to_directory() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("a.md"), "one").unwrap();
+        fs::create_dir(source.path().join("notes")).unwrap();
+        fs::write(source.path().join("notes/b.md"), "two").unwrap();
+        fs::create_dir(target.path().join("old")).unwrap();
+        fs::write(target.path().join("old/stale.md"), "gone").unwrap();
+        fs::write(target.path().join("a.md"), "outdated").unwrap();
+
+        let indexer = FileIndexer::new("device".to_string(), source.path().to_path_buf());
+        let options = MirrorOptions { delete: false, verify: true };
+        let report = mirror_to_directory(&indexer, target.path(), options).unwrap();
+        assert_eq!(report.copied, vec![PathBuf::from("notes/b.md")]);
+        assert_eq!(report.updated, vec![PathBuf::from("a.md")]);
+        assert!(report.is_clean());
+        assert!(target.path().join("old/stale.md").exists());
+
+        let options = MirrorOptions { delete: true, verify: true };
+        let report = mirror_to_directory(&indexer, target.path(), options).unwrap();
+        assert_eq!(report.unchanged, 2);
+        assert_eq!(report.deleted, vec![PathBuf::from("old/stale.md")]);
+        assert!(!target.path().join("old").exists());
+        assert_eq!(fs::read_to_string(target.path().join("a.md")).unwrap(), "one");
+    }
+}
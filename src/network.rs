@@ -1,15 +1,33 @@
 #![allow(dead_code)]
 
+use crate::capabilities::{self, Capability};
+use crate::security::AuthManager;
 use crate::types::{ClientInfo, SyncError};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
+/// Files per message when metadata listings are split into pages.
+pub const METADATA_PAGE_SIZE: usize = 1000;
+/// Largest chunk a server sends for one `ChunkRequest`.
+pub const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How long each candidate address gets before the next one is tried.
+const CANDIDATE_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+/// How long a loopback relay waits for its own connection to arrive
+const LOOPBACK_ACCEPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 pub struct ClientManager {
     clients: Arc<RwLock<HashMap<String, ClientInfo>>>,
     server_id: String,
-    auth_tokens: HashMap<String, String>, // token -> client_id
+    auth: std::sync::Mutex<AuthManager>,
+}
+
+impl Default for ClientManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientManager {
@@ -18,22 +36,47 @@ impl ClientManager {
         Self {
             clients: Arc::new(RwLock::new(HashMap::new())),
             server_id,
-            auth_tokens: HashMap::new(),
+            auth: std::sync::Mutex::new(AuthManager::new()),
         }
     }
 
+    /// A manager checking tokens with `auth`, e.g. one issuing long-lived
+    /// tokens.
+    pub fn with_auth(auth: AuthManager) -> Self {
+        Self { auth: std::sync::Mutex::new(auth), ..Self::new() }
+    }
+
     pub fn server_id(&self) -> &str {
         &self.server_id
     }
 
-    pub fn generate_auth_token(&mut self, client_id: String, _client_name: String) -> String {
-        let token = format!("syncmd_{}", Uuid::new_v4().to_string());
-        self.auth_tokens.insert(token.clone(), client_id.clone());
-        token
+    pub fn generate_auth_token(&self, client_id: String, client_name: String) -> Result<String, SyncError> {
+        self.auth.lock().unwrap().generate_token(client_id, client_name)
     }
 
-    pub fn validate_token(&self, token: &str) -> Option<String> {
-        self.auth_tokens.get(token).cloned()
+    /// Returns the client id the token belongs to, or why it was refused.
+    pub fn validate_token(&self, token: &str) -> Result<String, SyncError> {
+        self.auth.lock().unwrap().check_token(token).map(|t| t.client_id.clone())
+    }
+
+    /// The token, for the device it was issued to and what it may access,
+    /// or why it was refused.
+    pub fn check_token(&self, token: &str) -> Result<crate::security::AuthToken, SyncError> {
+        self.auth.lock().unwrap().check_token(token).cloned()
+    }
+
+    pub fn refresh_token(&self, token: &str) -> Result<String, SyncError> {
+        self.auth.lock().unwrap().refresh_token(token)
+    }
+
+    /// Reloads the tokens saved at `path`, picking up ones issued or revoked
+    /// since.
+    pub fn load_tokens(&self, path: &std::path::Path) -> Result<(), SyncError> {
+        self.auth.lock().unwrap().load_tokens(path)
+    }
+
+    pub fn save_tokens(&self, path: &std::path::Path) -> Result<(), SyncError> {
+        self.auth.lock().unwrap().save_tokens(path)
     }
 
     pub async fn register_client(&self, client_info: ClientInfo) -> Result<(), SyncError> {
@@ -52,11 +95,41 @@ impl ClientManager {
         clients.values().cloned().collect()
     }
 
+    /// Renames every registered client called `old_name`, returning how many
+    /// there were.
+    pub async fn rename_client(&self, old_name: &str, new_name: &str) -> usize {
+        let mut clients = self.clients.write().await;
+        let mut renamed = 0;
+        for client in clients.values_mut().filter(|c| c.name == old_name) {
+            client.name = new_name.to_string();
+            renamed += 1;
+        }
+        renamed
+    }
+
     pub async fn remove_client(&self, client_id: &str) -> Result<(), SyncError> {
         let mut clients = self.clients.write().await;
         clients.remove(client_id);
         Ok(())
     }
+
+    /// Forgets clients that haven't authenticated for `max_idle`, returning
+    /// how many there were.
+    pub async fn remove_idle_clients(&self, max_idle: std::time::Duration) -> usize {
+        let cutoff = chrono::Utc::now() - chrono::Duration::from_std(max_idle).unwrap_or(chrono::Duration::MAX);
+        let mut clients = self.clients.write().await;
+        let before = clients.len();
+        clients.retain(|_, client| client.last_seen > cutoff);
+        before - clients.len()
+    }
+
+    /// Drops timed out sessions and tokens past their refresh grace period,
+    /// returning how many sessions ended.
+    pub fn expire_sessions(&self) -> usize {
+        let mut auth = self.auth.lock().unwrap();
+        auth.cleanup_expired_tokens();
+        auth.cleanup_inactive_sessions()
+    }
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -64,27 +137,181 @@ pub enum NetworkMessage {
     Authenticate {
         token: String,
         client_name: String,
+        /// Optional features the client supports
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<Capability>,
     },
     AuthResponse {
         success: bool,
         client_id: Option<String>,
         message: String,
+        /// Why authentication failed, for clients that can recover
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        failure: Option<AuthFailure>,
+        /// Features both sides support, which the rest of the session uses
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<Capability>,
     },
+    /// Asks for a new token in exchange for an expired one. Answered with
+    /// `TokenRefreshed`, or an `AuthResponse` failure if it isn't allowed.
+    RefreshToken {
+        token: String,
+    },
+    TokenRefreshed {
+        token: String,
+    },
+    /// Sent instead of an answer to a request the connection isn't
+    /// authenticated for
+    AuthError {
+        failure: AuthFailure,
+        message: String,
+    },
+    /// The client's index, split over several messages for large roots:
+    /// every page but the last has `more` set
     SyncRequest {
         client_id: String,
         files: Vec<crate::types::FileMetadata>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        more: bool,
+        /// Only these files and directories are being synced, as found by
+        /// comparing trees; everything else is known to match
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scope: Option<Vec<std::path::PathBuf>>,
+        /// On the last page, when `Directories` was negotiated
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        directories: Option<DirectoryListing>,
     },
     SyncResponse {
         operations: Vec<crate::types::SyncOperation>,
+        #[serde(default, skip_serializing_if = "crate::types::InlineContent::is_empty")]
+        inline: crate::types::InlineContent,
     },
     FileTransfer {
         path: String,
         content: Vec<u8>,
         metadata: crate::types::FileMetadata,
+        /// Made up by the client and sent again with every retry of the
+        /// upload, with idempotency-keys, so the server stores it once
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        idempotency_key: Option<String>,
+    },
+    /// The server's answer to a `FileTransfer`, with upload-results
+    UploadResult {
+        path: String,
+        /// Why the file wasn't stored, `None` when it was
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rejection: Option<UploadRejection>,
     },
     FileRequest {
         path: String,
     },
+    /// Asks for a file as a windowed transfer: answered with a
+    /// `FileResponse` without content, followed by the transfer's messages
+    /// when the file was found. Needs windowed-transfer.
+    WindowedFileRequest {
+        path: String,
+    },
+    /// Announces an upload sent as a windowed transfer. Answered with
+    /// `UploadReady`, after which the transfer's messages follow and an
+    /// `UploadResult` ends it, or straight away with an `UploadResult` when
+    /// the server won't take it. Needs windowed-transfer.
+    WindowedUpload {
+        path: String,
+        metadata: crate::types::FileMetadata,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        idempotency_key: Option<String>,
+    },
+    /// The server takes the `WindowedUpload` of `path`
+    UploadReady {
+        path: String,
+    },
+    /// Asks for bytes `index * chunk_size` up to `chunk_size` more of the
+    /// file, as long as the server still has the version with `hash`
+    ChunkRequest {
+        path: String,
+        hash: String,
+        index: u64,
+        chunk_size: u64,
+    },
+    /// `data` is `None` when the server doesn't have that version or range
+    ChunkResponse {
+        path: String,
+        index: u64,
+        data: Option<Vec<u8>>,
+    },
+    /// Asks for one page of the server's file metadata, optionally only
+    /// under `prefix`. `continuation` comes from the previous `FileList`.
+    ListFiles {
+        prefix: Option<String>,
+        continuation: Option<String>,
+        limit: usize,
+    },
+    /// A page of file metadata, sorted by path; `continuation` is set when
+    /// more pages follow
+    FileList {
+        files: Vec<crate::types::FileMetadata>,
+        continuation: Option<String>,
+    },
+    /// Limits the operations and notifications the server sends this
+    /// connection to paths under `prefixes`; empty subscribes to everything
+    Subscribe {
+        prefixes: Vec<String>,
+    },
+    /// Asks for the search index of the subscribed files, given the file
+    /// versions the client already has entries for
+    SearchIndexRequest {
+        known: Vec<(std::path::PathBuf, String)>,
+    },
+    /// Entries for files the client's index lacks or has an older version
+    /// of, and the known paths the server no longer has
+    SearchIndexUpdate {
+        entries: Vec<crate::search::SearchEntry>,
+        removed: Vec<std::path::PathBuf>,
+    },
+    /// Asks for the server's Merkle tree nodes of these directories, the
+    /// root being the empty path. Needs the merkle-sync capability.
+    TreeRequest {
+        paths: Vec<std::path::PathBuf>,
+    },
+    /// The requested directories, empty nodes for ones the server lacks
+    TreeResponse {
+        nodes: Vec<crate::merkle::TreeNode>,
+    },
+    /// Turns the connection into one the server only pushes
+    /// `ChangeNotification`s on, for changes made by devices other than
+    /// `device_id`. Needs the notifications capability.
+    WatchChanges {
+        device_id: String,
+    },
+    /// Subscribed files changed on the server. Empty when more changed than
+    /// the server kept track of, so the client should just sync.
+    ChangeNotification {
+        paths: Vec<std::path::PathBuf>,
+    },
+    /// Asks for a link anyone can download `path` with, a file or a
+    /// directory, for `expires_in` seconds. Needs the share-links capability.
+    ShareLinkRequest {
+        path: String,
+        expires_in: u64,
+    },
+    /// The link, or why the server won't issue one
+    ShareLinkResponse {
+        link: Option<crate::share_links::ShareLink>,
+        error: Option<String>,
+    },
+    /// Asks for the server's journal entries after sequence `after`. Needs
+    /// the journal-replication capability.
+    JournalRequest {
+        after: u64,
+    },
+    JournalResponse {
+        batch: crate::journal_replication::JournalBatch,
+    },
+    /// The sending device changed its display name
+    RenameDevice {
+        old_name: String,
+        new_name: String,
+    },
     FileResponse {
         path: String,
         found: bool,
@@ -92,6 +319,96 @@ pub enum NetworkMessage {
         metadata: Option<crate::types::FileMetadata>,
     },
     Heartbeat,
+    /// Another message as zstd-compressed JSON in base64, sent instead of
+    /// large messages once compression-zstd is negotiated
+    Compressed {
+        data: String,
+    },
+}
+
+impl NetworkMessage {
+    /// Whether a server only answers this once the connection has
+    /// authenticated.
+    pub fn requires_auth(&self) -> bool {
+        !matches!(
+            self,
+            NetworkMessage::Authenticate { .. } | NetworkMessage::RefreshToken { .. } | NetworkMessage::Heartbeat
+        )
+    }
+}
+
+/// A device's directories in a sync request, see
+/// [`crate::sync::sync_directories`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DirectoryListing {
+    /// The directories it has now
+    pub present: std::collections::BTreeSet<std::path::PathBuf>,
+    /// The ones it had after its last sync
+    pub known: std::collections::BTreeSet<std::path::PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFailure {
+    Expired,
+    Revoked,
+    Invalid,
+    /// The connection never presented a token
+    Unauthenticated,
+    /// Too many failed attempts from the client's address
+    LockedOut,
+}
+
+impl AuthFailure {
+    pub fn from_error(error: &SyncError) -> Self {
+        match error {
+            SyncError::TokenExpired => AuthFailure::Expired,
+            SyncError::TokenRevoked => AuthFailure::Revoked,
+            _ => AuthFailure::Invalid,
+        }
+    }
+
+    pub fn into_error(self) -> SyncError {
+        match self {
+            AuthFailure::Expired => SyncError::TokenExpired,
+            AuthFailure::Revoked => SyncError::TokenRevoked,
+            AuthFailure::Invalid => SyncError::InvalidToken,
+            AuthFailure::Unauthenticated => SyncError::Auth("Not authenticated".to_string()),
+            AuthFailure::LockedOut => SyncError::Auth("Too many failed attempts from this address, try again later".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    /// The server's scanner found malware
+    Infected,
+    /// The scanner couldn't check the file
+    ScanFailed,
+    /// The client's token doesn't allow writing there
+    Forbidden,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UploadRejection {
+    pub reason: RejectionReason,
+    /// What the scanner said
+    pub detail: String,
+}
+
+impl std::fmt::Display for UploadRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.reason {
+            RejectionReason::Infected => "infected",
+            RejectionReason::ScanFailed => "scan failed",
+            RejectionReason::Forbidden => "not permitted",
+        };
+        match self.detail.as_str() {
+            "" => write!(f, "{}", reason),
+            detail => write!(f, "{}: {}", reason, detail),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -120,7 +437,19 @@ impl NetworkManager {
                 Ok((stream, addr)) => {
                     let client_manager = self.client_manager.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, client_manager, addr.to_string()).await {
+                        let stream = match crate::websocket::is_upgrade(&stream).await {
+                            true => crate::websocket::accept(stream).await,
+                            false => Ok(stream),
+                        };
+                        let stream = match stream {
+                            Ok(stream) => crate::protocol_trace::wrap(stream, &addr.to_string()).await,
+                            Err(e) => Err(e),
+                        };
+                        let result = match stream {
+                            Ok(stream) => Self::handle_connection(stream, client_manager, addr.to_string()).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = result {
                             eprintln!("Connection error: {}", e);
                         }
                     });
@@ -147,50 +476,71 @@ impl NetworkManager {
         let message: NetworkMessage = serde_json::from_slice(&buffer[..n])?;
         
         match message {
-            NetworkMessage::Authenticate { token, client_name } => {
+            NetworkMessage::Authenticate { token, client_name, capabilities: client_capabilities } => {
                 println!("Authentication request from: {}", client_name);
                 
-                if let Some(client_id) = client_manager.validate_token(&token) {
-                    println!("Authentication successful for client: {}", client_id);
-                    
-                    let client_info = ClientInfo {
-                        id: client_id.clone(),
-                        name: client_name,
-                        address: client_addr,
-                        last_seen: chrono::Utc::now(),
-                        auth_token: token,
-                    };
-                    
-                    client_manager.register_client(client_info).await?;
-                    
-                    let response = NetworkMessage::AuthResponse {
-                        success: true,
-                        client_id: Some(client_id),
-                        message: "Authentication successful".to_string(),
-                    };
-                    
-                    let response_data = serde_json::to_vec(&response)?;
-                    stream.write_all(&response_data).await?;
-                } else {
-                    println!("Authentication failed for client: {}", client_name);
-                    
-                    let response = NetworkMessage::AuthResponse {
+                match client_manager.validate_token(&token) {
+                    Ok(client_id) => {
+                        println!("Authentication successful for client: {}", client_id);
+                        
+                        let client_info = ClientInfo {
+                            id: client_id.clone(),
+                            name: client_name,
+                            address: client_addr,
+                            last_seen: chrono::Utc::now(),
+                            auth_token: token,
+                        };
+                        
+                        client_manager.register_client(client_info).await?;
+                        
+                        let response = NetworkMessage::AuthResponse {
+                            success: true,
+                            client_id: Some(client_id),
+                            message: "Authentication successful".to_string(),
+                            failure: None,
+                            capabilities: capabilities::negotiate(&server_capabilities(), &client_capabilities),
+                        };
+                        
+                        let response_data = serde_json::to_vec(&response)?;
+                        stream.write_all(&response_data).await?;
+                    }
+                    Err(e) => {
+                        println!("Authentication failed for client {}: {}", client_name, e);
+                        
+                        let response = NetworkMessage::AuthResponse {
+                            success: false,
+                            client_id: None,
+                            message: e.to_string(),
+                            failure: Some(AuthFailure::from_error(&e)),
+                            capabilities: Vec::new(),
+                        };
+                        
+                        let response_data = serde_json::to_vec(&response)?;
+                        stream.write_all(&response_data).await?;
+                    }
+                }
+            }
+            NetworkMessage::RefreshToken { token } => {
+                let response = match client_manager.refresh_token(&token) {
+                    Ok(token) => NetworkMessage::TokenRefreshed { token },
+                    Err(e) => NetworkMessage::AuthResponse {
                         success: false,
                         client_id: None,
-                        message: "Invalid authentication token".to_string(),
-                    };
-                    
-                    let response_data = serde_json::to_vec(&response)?;
-                    stream.write_all(&response_data).await?;
-                }
+                        message: e.to_string(),
+                        failure: Some(AuthFailure::from_error(&e)),
+                        capabilities: Vec::new(),
+                    },
+                };
+                stream.write_all(&serde_json::to_vec(&response)?).await?;
             }
-            NetworkMessage::SyncRequest { client_id, files } => {
+            NetworkMessage::SyncRequest { client_id, files, .. } => {
                 println!("Sync request from {} with {} files", client_id, files.len());
                 
                 // Get server's current file state
                 // For now, we'll just acknowledge the sync request
                 let response = NetworkMessage::SyncResponse {
                     operations: vec![],
+                    inline: Default::default(),
                 };
                 
                 let response_data = serde_json::to_vec(&response)?;
@@ -209,10 +559,28 @@ impl NetworkManager {
                 let response_data = serde_json::to_vec(&response)?;
                 stream.write_all(&response_data).await?;
             }
-            NetworkMessage::FileTransfer { path, content, metadata: _ } => {
+            NetworkMessage::ChunkRequest { path, index, .. } => {
+                // No stored files here either
+                let response = NetworkMessage::ChunkResponse { path, index, data: None };
+                stream.write_all(&serde_json::to_vec(&response)?).await?;
+            }
+            NetworkMessage::FileTransfer { path, content, .. } => {
                 println!("File transfer: {} ({} bytes)", path, content.len());
                 // Handle incoming file transfer (client to server)
             }
+            NetworkMessage::ListFiles { .. } => {
+                // This server keeps no file state of its own
+                let response = NetworkMessage::FileList { files: Vec::new(), continuation: None };
+                stream.write_all(&serde_json::to_vec(&response)?).await?;
+            }
+            NetworkMessage::Subscribe { .. } => {
+                // Nothing to filter, this server sends no operations
+            }
+            NetworkMessage::RenameDevice { old_name, new_name } => {
+                let renamed = client_manager.rename_client(&old_name, &new_name).await;
+                println!("Device {} renamed to {} [{}] ({} clients updated)",
+                    old_name, new_name, crate::types::device_slug(&new_name), renamed);
+            }
             NetworkMessage::Heartbeat => {
                 // Handle heartbeat
             }
@@ -230,39 +598,781 @@ impl NetworkManager {
     ) -> Result<tokio::net::TcpStream, SyncError> {
         let stream = tokio::net::TcpStream::connect(server_addr).await
             .map_err(|e| SyncError::Network(e.to_string()))?;
-        Ok(stream)
+        let stream = crate::chaos::wrap(stream).await?;
+        crate::protocol_trace::wrap(stream, server_addr).await
+    }
+
+    /// Connects honouring the remote's proxy, TLS and transport settings.
+    pub async fn connect_to_remote(
+        &self,
+        remote: &crate::remote::Remote,
+    ) -> Result<tokio::net::TcpStream, SyncError> {
+        remote.connect().await
     }
 
+    /// Connects to `target`, which is a known peer, a named remote or a plain
+    /// address. For known peers every address they were last reachable at is
+    /// tried, best first, and the outcome is remembered for next time.
+    pub async fn connect_supervised(
+        &self,
+        config: &crate::cli::Config,
+        target: &str,
+    ) -> Result<(Connection, crate::remote::Remote), SyncError> {
+        let Some(peer) = config
+            .known_peers
+            .iter()
+            .find(|p| p.device_id == target || p.name == target)
+        else {
+            let remote = config.resolve_remote(target);
+            let stream = self.connect_to_remote(&remote).await?;
+            return Ok((Connection::new(stream), remote));
+        };
+
+        // A remote with the peer's name supplies connection settings and a
+        // fallback address
+        let template = config
+            .remotes
+            .iter()
+            .find(|r| r.name == peer.name || r.name == peer.device_id)
+            .cloned();
+        let registry = crate::peer_registry::PeerRegistry::open()?;
+        let overlays = crate::overlay::OverlayNetworks::detect(&config.overlay_networks);
+        let mut addresses: Vec<String> = registry
+            .candidates(&peer.device_id, &overlays)?
+            .into_iter()
+            .map(|candidate| candidate.address.to_string())
+            .collect();
+        if let Some(template) = &template {
+            if !addresses.contains(&template.address) {
+                addresses.push(template.address.clone());
+            }
+        }
+        // The configured address is usually the public relay, but it may
+        // itself be an overlay address
+        let is_overlay = |address: &String| {
+            address
+                .parse::<std::net::SocketAddr>()
+                .map(|a| overlays.classify(a.ip()) == crate::overlay::EndpointKind::Overlay)
+                .unwrap_or(false)
+        };
+        addresses.sort_by_key(|address| !is_overlay(address));
+        if addresses.is_empty() {
+            return Err(SyncError::Network(format!(
+                "No known address for peer {}, run 'syncmd peers' on the same network first",
+                peer.name
+            )));
+        }
+
+        let mut last_error = None;
+        for address in addresses {
+            let mut remote = template
+                .clone()
+                .unwrap_or_else(|| crate::remote::Remote::from_address(&address));
+            remote.name = peer.name.clone();
+            remote.address = address.clone();
+            println!("Trying {} at {}", peer.name, address);
+
+            let attempt = tokio::time::timeout(CANDIDATE_CONNECT_TIMEOUT, self.connect_to_remote(&remote)).await;
+            let socket_address = address.parse().ok();
+            match attempt {
+                Ok(Ok(stream)) => {
+                    let reached = socket_address.or_else(|| stream.peer_addr().ok());
+                    if let Some(reached) = reached {
+                        registry.record_success(&peer.device_id, reached)?;
+                    }
+                    return Ok((Connection::new(stream), remote));
+                }
+                Ok(Err(e)) => last_error = Some(e),
+                Err(_) => last_error = Some(SyncError::Network(format!("Timed out connecting to {}", address))),
+            }
+            if let Some(socket_address) = socket_address {
+                registry.record_failure(&peer.device_id, socket_address)?;
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| SyncError::Network(format!("Peer {} is unreachable", peer.name))))
+    }
+
+    /// Authenticates and returns the capabilities negotiated with the
+    /// server, empty for servers that predate negotiation.
     pub async fn send_authentication(
         &self,
-        stream: &mut tokio::net::TcpStream,
+        stream: &mut Connection,
         auth_token: String,
         client_name: String,
-    ) -> Result<(), SyncError> {
-        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    ) -> Result<Vec<Capability>, SyncError> {
+        use tokio::io::AsyncWriteExt;
 
         let auth_request = NetworkMessage::Authenticate {
             token: auth_token,
             client_name,
+            capabilities: capabilities::offered(),
         };
 
         let data = serde_json::to_vec(&auth_request)?;
         stream.write_all(&data).await?;
 
-        let mut response_buffer = vec![0u8; 1024];
-        let n = stream.read(&mut response_buffer).await?;
+        let response = stream.read_message().await?;
         
-        let response: NetworkMessage = serde_json::from_slice(&response_buffer[..n])?;
-        
-        if let NetworkMessage::AuthResponse { success, client_id: _, message } = response {
+        if let NetworkMessage::AuthResponse { success, client_id: _, message, failure, capabilities } = response {
             if success {
                 println!("{}", message);
-                Ok(())
+                Ok(capabilities)
             } else {
-                Err(SyncError::Network(message))
+                Err(failure.map(AuthFailure::into_error).unwrap_or(SyncError::Network(message)))
             }
         } else {
             Err(SyncError::Network("Invalid authentication response".to_string()))
         }
     }
-}
\ No newline at end of file
+
+    /// Exchanges an expired token for a new one, if the server allows it.
+    pub async fn refresh_token(
+        &self,
+        stream: &mut Connection,
+        auth_token: String,
+    ) -> Result<String, SyncError> {
+        use tokio::io::AsyncWriteExt;
+
+        let request = NetworkMessage::RefreshToken { token: auth_token };
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+
+        match stream.read_message().await? {
+            NetworkMessage::TokenRefreshed { token } => Ok(token),
+            NetworkMessage::AuthResponse { failure, message, .. } => {
+                Err(failure.map(AuthFailure::into_error).unwrap_or(SyncError::Network(message)))
+            }
+            _ => Err(SyncError::Network("Invalid token refresh response".to_string())),
+        }
+    }
+}
+
+/// Splits a stream of back-to-back messages, which may arrive several to a
+/// read or spread across reads.
+#[derive(Default)]
+pub struct MessageReader {
+    frames: crate::wire::FrameReader,
+}
+
+impl MessageReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses messages larger than `limit`, compressed ones included, or
+    /// with `None` goes back to the usual limits. See
+    /// [`FrameReader::set_limit`](crate::wire::FrameReader::set_limit).
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.frames.set_limit(limit);
+    }
+
+    /// The frames underneath, for a file transfer running on the same
+    /// connection.
+    pub fn frames(&mut self) -> &mut crate::wire::FrameReader {
+        &mut self.frames
+    }
+
+    /// The next message, or `None` once the peer has closed the connection.
+    pub async fn next<R>(&mut self, stream: &mut R) -> Result<Option<NetworkMessage>, SyncError>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        match self.frames.next::<NetworkMessage, BinaryMessage, R>(stream).await? {
+            Some(NetworkMessage::Compressed { .. }) if self.frames.limit().is_some() => {
+                Err(SyncError::Network("Refused a compressed message".to_string()))
+            }
+            Some(message) => decompress_message(message).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A connection to a server, with the reader for what it sends. Bytes read
+/// past one message stay in the reader for the next, so messages are read
+/// through the connection rather than the stream; writes go to the stream.
+pub struct Connection {
+    stream: tokio::net::TcpStream,
+    reader: MessageReader,
+}
+
+impl Connection {
+    pub fn new(stream: tokio::net::TcpStream) -> Self {
+        Self { stream, reader: MessageReader::new() }
+    }
+
+    /// The next message, or `None` once the server has closed the connection.
+    pub async fn next(&mut self) -> Result<Option<NetworkMessage>, SyncError> {
+        self.reader.next(&mut self.stream).await
+    }
+
+    /// Reads a single message, for request/response exchanges. A request the
+    /// server refused for lack of authentication comes back as the error.
+    pub async fn read_message(&mut self) -> Result<NetworkMessage, SyncError> {
+        match self.next().await? {
+            Some(NetworkMessage::AuthError { failure, message }) => {
+                tracing::debug!("Request refused: {}", message);
+                Err(failure.into_error())
+            }
+            Some(message) => Ok(message),
+            None => Err(SyncError::Network("Connection closed".to_string())),
+        }
+    }
+
+    /// The stream and its reader, to write while reading, e.g. through
+    /// `TcpStream::split`.
+    pub fn parts(&mut self) -> (&mut tokio::net::TcpStream, &mut MessageReader) {
+        (&mut self.stream, &mut self.reader)
+    }
+}
+
+impl std::ops::Deref for Connection {
+    type Target = tokio::net::TcpStream;
+
+    fn deref(&self) -> &Self::Target {
+        &self.stream
+    }
+}
+
+impl std::ops::DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.stream
+    }
+}
+
+/// How to encode messages for a peer, following what was negotiated.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoding {
+    /// Compress messages when that makes them smaller
+    pub compress: bool,
+    /// Send messages carrying file content as binary frames
+    pub binary: bool,
+}
+
+impl Encoding {
+    pub fn negotiated(negotiated: &[Capability]) -> Self {
+        Self {
+            compress: negotiated.contains(&Capability::CompressionZstd),
+            binary: negotiated.contains(&Capability::BinaryFrames),
+        }
+    }
+}
+
+/// The messages carrying file content, in the form sent as binary frames.
+/// Everything else is small and stays JSON. [`BinaryMessageRef`] writes the
+/// same layout without copying the content.
+#[derive(serde::Deserialize)]
+enum BinaryMessage {
+    FileTransfer {
+        path: String,
+        #[serde(with = "serde_bytes")]
+        content: Vec<u8>,
+        metadata: crate::types::FileMetadata,
+    },
+    ChunkResponse {
+        path: String,
+        index: u64,
+        #[serde(with = "serde_bytes")]
+        data: Option<Vec<u8>>,
+    },
+    FileResponse {
+        path: String,
+        found: bool,
+        #[serde(with = "serde_bytes")]
+        content: Option<Vec<u8>>,
+        metadata: Option<crate::types::FileMetadata>,
+    },
+    /// A `FileTransfer` with an idempotency key. Frames are bincode, which
+    /// has no optional fields, and older peers know the variants above.
+    KeyedFileTransfer {
+        path: String,
+        #[serde(with = "serde_bytes")]
+        content: Vec<u8>,
+        metadata: crate::types::FileMetadata,
+        idempotency_key: String,
+    },
+}
+
+#[derive(serde::Serialize)]
+enum BinaryMessageRef<'a> {
+    FileTransfer {
+        path: &'a str,
+        #[serde(with = "serde_bytes")]
+        content: &'a [u8],
+        metadata: &'a crate::types::FileMetadata,
+    },
+    ChunkResponse {
+        path: &'a str,
+        index: u64,
+        #[serde(with = "serde_bytes")]
+        data: Option<&'a [u8]>,
+    },
+    FileResponse {
+        path: &'a str,
+        found: bool,
+        #[serde(with = "serde_bytes")]
+        content: Option<&'a [u8]>,
+        metadata: Option<&'a crate::types::FileMetadata>,
+    },
+    KeyedFileTransfer {
+        path: &'a str,
+        #[serde(with = "serde_bytes")]
+        content: &'a [u8],
+        metadata: &'a crate::types::FileMetadata,
+        idempotency_key: &'a str,
+    },
+}
+
+impl<'a> BinaryMessageRef<'a> {
+    fn borrow(message: &'a NetworkMessage) -> Option<Self> {
+        Some(match message {
+            NetworkMessage::FileTransfer { path, content, metadata, idempotency_key: None } => {
+                BinaryMessageRef::FileTransfer { path, content, metadata }
+            }
+            NetworkMessage::FileTransfer { path, content, metadata, idempotency_key: Some(idempotency_key) } => {
+                BinaryMessageRef::KeyedFileTransfer { path, content, metadata, idempotency_key }
+            }
+            NetworkMessage::ChunkResponse { path, index, data } => {
+                BinaryMessageRef::ChunkResponse { path, index: *index, data: data.as_deref() }
+            }
+            NetworkMessage::FileResponse { path, found, content, metadata } => BinaryMessageRef::FileResponse {
+                path,
+                found: *found,
+                content: content.as_deref(),
+                metadata: metadata.as_ref(),
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl From<BinaryMessage> for NetworkMessage {
+    fn from(message: BinaryMessage) -> Self {
+        match message {
+            BinaryMessage::FileTransfer { path, content, metadata } => {
+                NetworkMessage::FileTransfer { path, content, metadata, idempotency_key: None }
+            }
+            BinaryMessage::KeyedFileTransfer { path, content, metadata, idempotency_key } => {
+                NetworkMessage::FileTransfer { path, content, metadata, idempotency_key: Some(idempotency_key) }
+            }
+            BinaryMessage::ChunkResponse { path, index, data } => NetworkMessage::ChunkResponse { path, index, data },
+            BinaryMessage::FileResponse { path, found, content, metadata } => {
+                NetworkMessage::FileResponse { path, found, content, metadata }
+            }
+        }
+    }
+}
+
+/// The network message in a frame's payload, if it holds one, with its
+/// file content left out, for protocol traces.
+pub fn frame_message(payload: &[u8]) -> Option<NetworkMessage> {
+    let message: NetworkMessage = crate::wire::decode_exact::<BinaryMessage>(payload)?.into();
+    Some(match message {
+        NetworkMessage::FileTransfer { path, metadata, idempotency_key, .. } => {
+            NetworkMessage::FileTransfer { path, content: Vec::new(), metadata, idempotency_key }
+        }
+        NetworkMessage::ChunkResponse { path, index, data } => {
+            NetworkMessage::ChunkResponse { path, index, data: data.map(|_| Vec::new()) }
+        }
+        NetworkMessage::FileResponse { path, found, content, metadata } => {
+            NetworkMessage::FileResponse { path, found, content: content.map(|_| Vec::new()), metadata }
+        }
+        message => message,
+    })
+}
+
+/// Serializes a message for sending: as a binary frame when it carries file
+/// content and the peer takes those, otherwise as JSON, compressed when
+/// that's on and makes it smaller.
+pub fn encode_message(message: &NetworkMessage, encoding: Encoding) -> Result<Vec<u8>, SyncError> {
+    use base64::Engine;
+
+    if encoding.binary {
+        if let Some(binary) = BinaryMessageRef::borrow(message) {
+            return crate::wire::encode_frame(&binary, encoding.compress);
+        }
+    }
+    let json = serde_json::to_vec(message)?;
+    if encoding.compress {
+        if let Some(compressed) = crate::compression::compress(&json) {
+            let data = base64::engine::general_purpose::STANDARD.encode(compressed);
+            if data.len() < json.len() {
+                return Ok(serde_json::to_vec(&NetworkMessage::Compressed { data })?);
+            }
+        }
+    }
+    Ok(json)
+}
+
+/// The message inside a `Compressed` one, any other message as it is.
+pub fn decompress_message(message: NetworkMessage) -> Result<NetworkMessage, SyncError> {
+    use base64::Engine;
+
+    let NetworkMessage::Compressed { data } = message else {
+        return Ok(message);
+    };
+    let compressed = base64::engine::general_purpose::STANDARD.decode(data)
+        .map_err(|e| SyncError::Network(format!("Malformed compressed message: {}", e)))?;
+    match serde_json::from_slice(&crate::compression::decompress(&compressed)?)? {
+        NetworkMessage::Compressed { .. } => Err(SyncError::Network("Nested compressed message".to_string())),
+        message => Ok(message),
+    }
+}
+
+/// The two ends of a connection over the loopback interface, for relays
+/// that give the protocol code an ordinary stream.
+pub async fn loopback_pair() -> Result<(tokio::net::TcpStream, tokio::net::TcpStream), SyncError> {
+    loopback_pair_on(tokio::net::TcpListener::bind("127.0.0.1:0").await?).await
+}
+
+/// Any local user can connect to the listener too, and would be handed an
+/// authenticated session if taken for the relay's own end; only the
+/// connection coming from `near` is accepted.
+async fn loopback_pair_on(
+    listener: tokio::net::TcpListener,
+) -> Result<(tokio::net::TcpStream, tokio::net::TcpStream), SyncError> {
+    let near = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+    let expected = near.local_addr()?;
+    let far = tokio::time::timeout(LOOPBACK_ACCEPT_TIMEOUT, async {
+        loop {
+            let (far, peer) = listener.accept().await?;
+            if peer == expected && far.peer_addr()? == expected {
+                return Ok::<_, std::io::Error>(far);
+            }
+            tracing::warn!("Dropped a connection from {} to a loopback relay", peer);
+        }
+    })
+    .await
+    .map_err(|_| SyncError::Network("The loopback relay's own connection never arrived".to_string()))??;
+    Ok((near, far))
+}
+
+
+/// Sends the local index as `SyncRequest` pages of `METADATA_PAGE_SIZE`.
+/// With a `scope` the index only has to cover the paths under it.
+pub async fn send_sync_request(
+    stream: &mut Connection,
+    client_id: &str,
+    files: &[crate::types::FileMetadata],
+    scope: Option<Vec<std::path::PathBuf>>,
+    mut directories: Option<DirectoryListing>,
+    encoding: Encoding,
+) -> Result<(), SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut pages = files.chunks(METADATA_PAGE_SIZE).peekable();
+    if pages.peek().is_none() {
+        let request = NetworkMessage::SyncRequest {
+            client_id: client_id.to_string(),
+            files: Vec::new(),
+            more: false,
+            scope,
+            directories,
+        };
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+        return Ok(());
+    }
+    while let Some(page) = pages.next() {
+        let more = pages.peek().is_some();
+        let request = NetworkMessage::SyncRequest {
+            client_id: client_id.to_string(),
+            files: page.to_vec(),
+            more,
+            scope: scope.clone(),
+            directories: if more { None } else { directories.take() },
+        };
+        stream.write_all(&encode_message(&request, encoding)?).await?;
+    }
+    Ok(())
+}
+
+/// Walks the server's Merkle tree down from the root, one level per round
+/// trip, and returns the files and directories that differ from `local`.
+/// Empty when the trees match.
+pub async fn differing_paths(
+    stream: &mut Connection,
+    local: &crate::merkle::MerkleTree,
+) -> Result<Vec<std::path::PathBuf>, SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut level = vec![std::path::PathBuf::new()];
+    let mut differing = Vec::new();
+    while !level.is_empty() {
+        let request = NetworkMessage::TreeRequest { paths: std::mem::take(&mut level) };
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+        let NetworkMessage::TreeResponse { nodes } = stream.read_message().await? else {
+            return Err(SyncError::Network("Expected a tree response".to_string()));
+        };
+        for node in nodes {
+            if local.hash(&node.path) == Some(node.hash.as_str()) {
+                continue;
+            }
+            let (descend, changed) = local.diff(&node);
+            level.extend(descend);
+            differing.extend(changed);
+        }
+    }
+    Ok(differing)
+}
+
+/// Fetches the server's file metadata one page at a time, handing each page
+/// to `on_page` so the full listing never has to be held at once. Returns
+/// the number of files listed.
+pub async fn list_remote_files<F>(
+    stream: &mut Connection,
+    prefix: Option<String>,
+    mut on_page: F,
+) -> Result<usize, SyncError>
+where
+    F: FnMut(Vec<crate::types::FileMetadata>),
+{
+    use tokio::io::AsyncWriteExt;
+
+    let mut continuation = None;
+    let mut total = 0;
+    loop {
+        let request = NetworkMessage::ListFiles {
+            prefix: prefix.clone(),
+            continuation: continuation.take(),
+            limit: METADATA_PAGE_SIZE,
+        };
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+
+        match stream.read_message().await? {
+            NetworkMessage::FileList { files, continuation: next } => {
+                total += files.len();
+                on_page(files);
+                match next {
+                    Some(token) => continuation = Some(token),
+                    None => return Ok(total),
+                }
+            }
+            _ => return Err(SyncError::Network("Unexpected response to file listing".to_string())),
+        }
+    }
+}
+
+/// Brings a client's search index up to date with the server, sending only
+/// the hashes it already has so unchanged entries aren't transferred again.
+pub async fn fetch_search_index(
+    stream: &mut Connection,
+    known: std::collections::HashMap<std::path::PathBuf, String>,
+) -> Result<(Vec<crate::search::SearchEntry>, Vec<std::path::PathBuf>), SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let request = NetworkMessage::SearchIndexRequest { known: known.into_iter().collect() };
+    stream.write_all(&serde_json::to_vec(&request)?).await?;
+    match stream.read_message().await? {
+        NetworkMessage::SearchIndexUpdate { entries, removed } => Ok((entries, removed)),
+        _ => Err(SyncError::Network("Unexpected response to search index request".to_string())),
+    }
+}
+
+/// Asks the server for a download link to `path`, relative to the share.
+pub async fn request_share_link(
+    stream: &mut Connection,
+    path: String,
+    expires_in: std::time::Duration,
+) -> Result<crate::share_links::ShareLink, SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let request = NetworkMessage::ShareLinkRequest { path, expires_in: expires_in.as_secs() };
+    stream.write_all(&serde_json::to_vec(&request)?).await?;
+    match stream.read_message().await? {
+        NetworkMessage::ShareLinkResponse { link: Some(link), .. } => Ok(link),
+        NetworkMessage::ShareLinkResponse { error, .. } => Err(SyncError::Network(
+            error.unwrap_or_else(|| "The server didn't issue a link".to_string()),
+        )),
+        _ => Err(SyncError::Network("Unexpected response to share link request".to_string())),
+    }
+}
+
+/// Fetches the server's journal entries after `after`, batch by batch,
+/// handing each batch's entries to `store`. Returns how many there were.
+pub async fn replicate_journal<F>(
+    stream: &mut Connection,
+    mut after: u64,
+    mut store: F,
+) -> Result<usize, SyncError>
+where
+    F: FnMut(Vec<crate::index_store::JournalEntry>) -> Result<(), SyncError>,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let mut total = 0;
+    loop {
+        let request = NetworkMessage::JournalRequest { after };
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+        let NetworkMessage::JournalResponse { batch } = stream.read_message().await? else {
+            return Err(SyncError::Network("Unexpected response to journal request".to_string()));
+        };
+        let entries = batch.decode()?;
+        total += entries.len();
+        let next = batch.through;
+        store(entries)?;
+        // A batch that doesn't move on would be asked for forever
+        if !batch.more || next <= after {
+            return Ok(total);
+        }
+        after = next;
+    }
+}
+
+/// Asks the server to push change notifications on this connection from now
+/// on. Nothing else should be sent on it afterwards except heartbeats.
+pub async fn watch_changes(stream: &mut Connection, device_id: &str) -> Result<(), SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let request = NetworkMessage::WatchChanges { device_id: device_id.to_string() };
+    stream.write_all(&serde_json::to_vec(&request)?).await?;
+    Ok(())
+}
+
+/// What the built-in server negotiates. It stores no files, so there are
+/// no changes to notify anyone about.
+fn server_capabilities() -> Vec<Capability> {
+    capabilities::SUPPORTED
+        .iter()
+        .copied()
+        .filter(|capability| {
+            !matches!(
+                capability,
+                Capability::Notifications
+                    | Capability::MerkleSync
+                    | Capability::CompressionZstd
+                    | Capability::UploadResults
+                    | Capability::ShareLinks
+                    | Capability::JournalReplication
+                    | Capability::BinaryFrames
+                    | Capability::WindowedTransfer
+            )
+        })
+        .collect()
+}
+
+/// Fetches one chunk of a file. `None` if the server doesn't have the file
+/// at `hash`, or not that far into it.
+pub async fn fetch_chunk(
+    stream: &mut Connection,
+    path: &str,
+    hash: &str,
+    index: u64,
+    chunk_size: u64,
+) -> Result<Option<Vec<u8>>, SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let request = NetworkMessage::ChunkRequest {
+        path: path.to_string(),
+        hash: hash.to_string(),
+        index,
+        chunk_size,
+    };
+    stream.write_all(&serde_json::to_vec(&request)?).await?;
+    match stream.read_message().await? {
+        NetworkMessage::ChunkResponse { index: received, data, .. } if received == index => {
+            crate::bandwidth::throttle(stream, data.as_ref().map_or(0, Vec::len)).await;
+            Ok(data)
+        }
+        _ => Err(SyncError::Network("Unexpected response to chunk request".to_string())),
+    }
+}
+
+/// Transfers over a connection, using what it `negotiated`.
+pub fn transfer_manager(negotiated: &[Capability]) -> crate::file_transfer::FileTransferManager {
+    crate::file_transfer::FileTransferManager::new()
+        .with_compression(negotiated.contains(&Capability::CompressionZstd))
+        .with_binary_frames(negotiated.contains(&Capability::BinaryFrames))
+}
+
+/// Downloads `path` as a windowed transfer, `None` if the server doesn't
+/// have it. A download cut off resumes from the partial data kept in
+/// `sync_root`'s state; `destination` is what the transfer queue shows.
+pub async fn download_windowed(
+    stream: &mut Connection,
+    path: &str,
+    sync_root: &std::path::Path,
+    destination: &std::path::Path,
+    negotiated: &[Capability],
+) -> Result<Option<Vec<u8>>, SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let request = NetworkMessage::WindowedFileRequest { path: path.to_string() };
+    stream.write_all(&serde_json::to_vec(&request)?).await?;
+    match stream.read_message().await? {
+        NetworkMessage::FileResponse { found: true, .. } => {}
+        NetworkMessage::FileResponse { .. } => return Ok(None),
+        _ => return Err(SyncError::Network("Unexpected response to windowed file request".to_string())),
+    }
+    let (stream, reader) = stream.parts();
+    let content = transfer_manager(negotiated)
+        .receive_content(stream, reader.frames(), sync_root, destination)
+        .await?;
+    Ok(Some(content))
+}
+
+/// Uploads `content` as `metadata`'s file as a windowed transfer. Returns
+/// why the server refused it, `None` once it is stored.
+pub async fn upload_windowed(
+    stream: &mut Connection,
+    metadata: &crate::types::FileMetadata,
+    content: Vec<u8>,
+    idempotency_key: Option<String>,
+    negotiated: &[Capability],
+) -> Result<Option<UploadRejection>, SyncError> {
+    use tokio::io::AsyncWriteExt;
+
+    let path = metadata.path.to_string_lossy().to_string();
+    let request = NetworkMessage::WindowedUpload { path: path.clone(), metadata: metadata.clone(), idempotency_key };
+    stream.write_all(&serde_json::to_vec(&request)?).await?;
+    match stream.read_message().await? {
+        NetworkMessage::UploadReady { .. } => {}
+        NetworkMessage::UploadResult { rejection, .. } => return Ok(rejection),
+        _ => return Err(SyncError::Network("Unexpected response to windowed upload".to_string())),
+    }
+    let (tcp, reader) = stream.parts();
+    transfer_manager(negotiated)
+        .send_content(tcp, reader.frames(), &path, content, metadata.clone())
+        .await?;
+    match stream.read_message().await? {
+        NetworkMessage::UploadResult { rejection, .. } => Ok(rejection),
+        _ => Err(SyncError::Network("Unexpected response to windowed upload".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_loopback_pair_refuses_strangers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // Another process got there first
+        let mut stranger = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut near, mut far) = loopback_pair_on(listener).await.unwrap();
+        assert_eq!(far.peer_addr().unwrap(), near.local_addr().unwrap());
+
+        near.write_all(b"ours").await.unwrap();
+        let mut received = [0; 4];
+        far.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ours");
+        // Its connection was closed unanswered
+        assert_eq!(stranger.read(&mut received).await.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_keeps_messages_read_ahead() {
+        let (near, mut far) = loopback_pair().await.unwrap();
+        let mut connection = Connection::new(near);
+        // Both responses arrive in one read
+        let mut responses = serde_json::to_vec(&NetworkMessage::TokenRefreshed { token: "a".to_string() }).unwrap();
+        responses.extend(serde_json::to_vec(&NetworkMessage::TokenRefreshed { token: "b".to_string() }).unwrap());
+        far.write_all(&responses).await.unwrap();
+
+        for expected in ["a", "b"] {
+            match connection.read_message().await.unwrap() {
+                NetworkMessage::TokenRefreshed { token } => assert_eq!(token, expected),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+#![allow(dead_code)]
+
+//! Files the user has open right now, which sync ahead of everything else.
+//! Editor plugins report them through the daemon's control socket with
+//! `syncmd focus`. Without a plugin they are guessed: files other processes
+//! hold open under a root, found the way `lsof` finds them, and files next
+//! to an editor's lock or swap file.
+//!
+//! An open file that changes is synced after [`PRIORITY_WINDOW`] instead of
+//! the coalescing window, and downloads are queued open files first and
+//! bulk files, such as a folder of imported attachments, last.
+
+use crate::types::FileMetadata;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long an open file's changes wait for more saves
+pub const PRIORITY_WINDOW: Duration = Duration::from_millis(500);
+/// Plugins renew open files; ones they stop reporting are forgotten
+pub const REPORT_TTL: Duration = Duration::from_secs(10 * 60);
+/// How often the daemon looks for files held open
+pub const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+/// Non-note files from this size on are bulk, queued behind the rest
+pub const BULK_FILE_SIZE: u64 = 1024 * 1024;
+
+/// The order transfers are queued in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Open,
+    Normal,
+    Bulk,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OpenFiles {
+    inner: Arc<Mutex<Known>>,
+}
+
+#[derive(Debug, Default)]
+struct Known {
+    /// From plugins and editor lock files, with when they were reported
+    reported: HashMap<PathBuf, Instant>,
+    /// Held open by a process at the last scan
+    scanned: HashSet<PathBuf>,
+}
+
+impl OpenFiles {
+    /// The open files of this process, the ones its watchers and transfers
+    /// see.
+    pub fn global() -> &'static OpenFiles {
+        static OPEN: OnceLock<OpenFiles> = OnceLock::new();
+        OPEN.get_or_init(OpenFiles::default)
+    }
+
+    /// Records that the file at `path`, an absolute path, was opened or
+    /// closed.
+    pub fn report(&self, path: &Path, open: bool) {
+        let mut known = self.inner.lock().unwrap();
+        if open {
+            known.reported.insert(path.to_path_buf(), Instant::now());
+        } else {
+            known.reported.remove(path);
+        }
+    }
+
+    /// Replaces the files found held open by the last scan.
+    pub fn set_scanned(&self, paths: HashSet<PathBuf>) {
+        self.inner.lock().unwrap().scanned = paths;
+    }
+
+    pub fn is_open(&self, path: &Path) -> bool {
+        let known = self.inner.lock().unwrap();
+        known.scanned.contains(path)
+            || known.reported.get(path).is_some_and(|at| at.elapsed() < REPORT_TTL)
+    }
+
+    /// Where a file of `root` goes in the transfer queue.
+    pub fn priority(&self, root: &Path, metadata: &FileMetadata) -> Priority {
+        if self.is_open(&root.join(&metadata.path)) {
+            Priority::Open
+        } else if metadata.size >= BULK_FILE_SIZE && !crate::file_transfer::FileTransferManager::is_markdown_file(&metadata.path) {
+            Priority::Bulk
+        } else {
+            Priority::Normal
+        }
+    }
+
+    /// Orders files of `root` for transfer, keeping the order within each
+    /// priority.
+    pub fn prioritize(&self, root: &Path, files: &mut [FileMetadata]) {
+        files.sort_by_key(|metadata| self.priority(root, metadata));
+    }
+}
+
+/// The file an editor's lock or swap file stands for: `.notes.md.swp` (vim),
+/// `.#notes.md` (emacs) or `~$notes.docx` (office).
+pub fn lock_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let target = if let Some(target) = name.strip_prefix(".#").or_else(|| name.strip_prefix("~$")) {
+        target
+    } else {
+        let hidden = name.strip_prefix('.')?;
+        [".swp", ".swo", ".swx"].iter().find_map(|suffix| hidden.strip_suffix(suffix))?
+    };
+    (!target.is_empty()).then(|| path.with_file_name(target))
+}
+
+/// Files under `roots` other processes hold open. Blocking.
+#[cfg(target_os = "linux")]
+pub fn scan(roots: &[PathBuf]) -> HashSet<PathBuf> {
+    let own = std::process::id().to_string();
+    let Ok(processes) = std::fs::read_dir("/proc") else {
+        return HashSet::new();
+    };
+    processes
+        .flatten()
+        .filter(|process| process.file_name() != own.as_str())
+        // Processes of other users can't be looked into
+        .filter_map(|process| std::fs::read_dir(process.path().join("fd")).ok())
+        .flat_map(|descriptors| descriptors.flatten())
+        .filter_map(|descriptor| std::fs::read_link(descriptor.path()).ok())
+        .filter(|path| roots.iter().any(|root| path.starts_with(root)) && path.is_file())
+        .collect()
+}
+
+/// Files under `roots` other processes hold open, as `lsof` lists them.
+/// Blocking; nothing is found without `lsof`.
+#[cfg(not(target_os = "linux"))]
+pub fn scan(roots: &[PathBuf]) -> HashSet<PathBuf> {
+    let Ok(output) = std::process::Command::new("lsof").args(["-F", "n", "-w"]).output() else {
+        return HashSet::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix('n'))
+        .map(PathBuf::from)
+        .filter(|path| roots.iter().any(|root| path.starts_with(root)) && path.is_file())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_files_first() {
+        let file = |path: &str, size: u64| FileMetadata { size, ..FileMetadata::for_test(path, b"") };
+        let root = Path::new("/notes");
+        let open = OpenFiles::default();
+        open.report(Path::new("/notes/today.md"), true);
+        open.set_scanned(HashSet::from([PathBuf::from("/notes/big.pdf")]));
+
+        let mut files = vec![
+            file("scan.png", 5 * BULK_FILE_SIZE),
+            file("a.md", 10),
+            file("today.md", 10),
+            file("long.md", 5 * BULK_FILE_SIZE),
+            file("big.pdf", 5 * BULK_FILE_SIZE),
+        ];
+        open.prioritize(root, &mut files);
+        let order: Vec<&str> = files.iter().map(|file| file.path.to_str().unwrap()).collect();
+        assert_eq!(order, ["today.md", "big.pdf", "a.md", "long.md", "scan.png"]);
+
+        open.report(Path::new("/notes/today.md"), false);
+        assert!(!open.is_open(Path::new("/notes/today.md")));
+
+        assert_eq!(lock_target(Path::new("/notes/.today.md.swp")), Some(PathBuf::from("/notes/today.md")));
+        assert_eq!(lock_target(Path::new("/notes/.#today.md")), Some(PathBuf::from("/notes/today.md")));
+        assert_eq!(lock_target(Path::new("/notes/.hidden.md")), None);
+        assert_eq!(lock_target(Path::new("/notes/today.md")), None);
+    }
+}
@@ -0,0 +1,143 @@
+#![allow(dead_code)]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Tailscale hands out addresses from the CGNAT range and this ULA prefix.
+const TAILSCALE_RANGES: &[&str] = &["100.64.0.0/10", "fd7a:115c:a1e0::/48"];
+/// Tailscale's resolver, routed through the tailnet interface when it's up.
+const TAILSCALE_PROBE: &str = "100.100.100.100:53";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EndpointKind {
+    /// Private overlay such as Tailscale or WireGuard
+    Overlay,
+    /// Private LAN address
+    Lan,
+    /// Anything else, typically the public relay
+    Public,
+}
+
+impl EndpointKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            EndpointKind::Overlay => "overlay",
+            EndpointKind::Lan => "lan",
+            EndpointKind::Public => "public",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network: IpAddr = address.parse().map_err(|_| format!("Invalid network: {}", value))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max_prefix
+        } else {
+            prefix.parse().map_err(|_| format!("Invalid prefix length: {}", value))?
+        };
+        if prefix > max_prefix {
+            return Err(format!("Invalid prefix length: {}", value));
+        }
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The overlay networks this machine is part of: Tailscale when its
+/// interface is up, plus any WireGuard ranges from the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct OverlayNetworks {
+    ranges: Vec<Cidr>,
+}
+
+impl OverlayNetworks {
+    pub fn detect(configured: &[String]) -> Self {
+        let mut ranges: Vec<Cidr> = configured
+            .iter()
+            .filter_map(|range| match Cidr::parse(range) {
+                Ok(cidr) => Some(cidr),
+                Err(e) => {
+                    eprintln!("Ignoring overlay network: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        let tailscale: Vec<Cidr> = TAILSCALE_RANGES.iter().filter_map(|r| Cidr::parse(r).ok()).collect();
+        if tailscale_ip().map(|ip| tailscale.iter().any(|c| c.contains(ip))).unwrap_or(false) {
+            ranges.extend(tailscale);
+        }
+        Self { ranges }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn classify(&self, ip: IpAddr) -> EndpointKind {
+        if self.ranges.iter().any(|range| range.contains(ip)) {
+            EndpointKind::Overlay
+        } else if is_private(ip) {
+            EndpointKind::Lan
+        } else {
+            EndpointKind::Public
+        }
+    }
+}
+
+/// The local address used to reach the Tailscale resolver, which is only a
+/// tailnet address while Tailscale is connected. Nothing is sent.
+fn tailscale_ip() -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(TAILSCALE_PROBE).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip == Ipv4Addr::UNSPECIFIED,
+        IpAddr::V6(ip) => {
+            ip.is_loopback() || ip == Ipv6Addr::UNSPECIFIED || (ip.segments()[0] & 0xfe00) == 0xfc00
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_endpoints() {
+        let overlays = OverlayNetworks {
+            ranges: vec![Cidr::parse("100.64.0.0/10").unwrap(), Cidr::parse("10.8.0.0/24").unwrap()],
+        };
+        let kind = |ip: &str| overlays.classify(ip.parse().unwrap());
+        assert_eq!(kind("100.101.102.103"), EndpointKind::Overlay);
+        assert_eq!(kind("10.8.0.7"), EndpointKind::Overlay);
+        assert_eq!(kind("10.9.0.7"), EndpointKind::Lan);
+        assert_eq!(kind("192.168.1.20"), EndpointKind::Lan);
+        assert_eq!(kind("203.0.113.9"), EndpointKind::Public);
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+    }
+}
@@ -0,0 +1,494 @@
+#![allow(dead_code)]
+
+//! Direct device-to-device sync authenticated by a shared passphrase, for
+//! when there is no server to hold tokens. The devices run a SPAKE2 exchange
+//! over Ristretto, so an eavesdropper learns nothing that helps guess the
+//! passphrase, then send everything else sealed with the session key.
+
+use crate::indexer::FileIndexer;
+use crate::types::{FileMetadata, InlineContent, SyncError, SyncOperation};
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Environment variable holding the passphrase, for scripted pairing
+pub const PASSPHRASE_ENV: &str = "SYNCMD_PAIR_PASSPHRASE";
+const PROTOCOL_VERSION: u32 = 1;
+/// Largest sealed frame accepted once the peer proved it knows the
+/// passphrase, which bounds single files too
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+/// Largest frame accepted during the handshake, from a peer that may be
+/// anyone
+const HANDSHAKE_FRAME_SIZE: usize = 4 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairRole {
+    /// The device that connected
+    Initiator,
+    /// The device that was listening
+    Responder,
+}
+
+/// One side of a SPAKE2 exchange: each side blinds a Diffie-Hellman share
+/// with the passphrase, so only someone who knows it can unblind the other's.
+pub struct Spake2 {
+    role: PairRole,
+    password: Scalar,
+    secret: Scalar,
+    message: [u8; 32],
+}
+
+/// Keys both sides derive from a completed exchange.
+pub struct SessionKeys {
+    initiator_confirm: [u8; 32],
+    responder_confirm: [u8; 32],
+    initiator_to_responder: [u8; 32],
+    responder_to_initiator: [u8; 32],
+}
+
+impl Spake2 {
+    pub fn start(role: PairRole, passphrase: &str) -> Self {
+        let password = Scalar::hash_from_bytes::<Sha512>(
+            [b"syncmd pair password:".as_slice(), passphrase.as_bytes()].concat().as_slice(),
+        );
+        let mut bytes = [0u8; 64];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = Scalar::from_bytes_mod_order_wide(&bytes);
+
+        let blind = match role {
+            PairRole::Initiator => point_m(),
+            PairRole::Responder => point_n(),
+        };
+        let element = RISTRETTO_BASEPOINT_POINT * secret + blind * password;
+        Self {
+            role,
+            password,
+            secret,
+            message: element.compress().to_bytes(),
+        }
+    }
+
+    /// The element to send to the peer.
+    pub fn message(&self) -> [u8; 32] {
+        self.message
+    }
+
+    /// Combines the peer's element with ours. A wrong passphrase on either
+    /// side still produces keys, they just differ, which key confirmation
+    /// then catches.
+    pub fn finish(self, peer_message: &[u8]) -> Result<SessionKeys, SyncError> {
+        let peer = CompressedRistretto::from_slice(peer_message)
+            .decompress()
+            .ok_or_else(|| SyncError::Auth("Peer sent an invalid pairing element".to_string()))?;
+        let peer_blind = match self.role {
+            PairRole::Initiator => point_n(),
+            PairRole::Responder => point_m(),
+        };
+        let shared = (peer - peer_blind * self.password) * self.secret;
+
+        let (initiator_message, responder_message) = match self.role {
+            PairRole::Initiator => (self.message.as_slice(), peer_message),
+            PairRole::Responder => (peer_message, self.message.as_slice()),
+        };
+        let mut transcript = Sha256::new();
+        transcript.update(b"syncmd pair v1");
+        transcript.update(initiator_message);
+        transcript.update(responder_message);
+        transcript.update(shared.compress().as_bytes());
+        transcript.update(self.password.as_bytes());
+        let key = transcript.finalize();
+
+        Ok(SessionKeys {
+            initiator_confirm: derive_key(&key, b"confirm initiator"),
+            responder_confirm: derive_key(&key, b"confirm responder"),
+            initiator_to_responder: derive_key(&key, b"initiator to responder"),
+            responder_to_initiator: derive_key(&key, b"responder to initiator"),
+        })
+    }
+}
+
+/// Fixed points with no known discrete log, so neither side can pick its
+/// element to test several passphrases at once.
+fn point_m() -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(b"syncmd pair M")
+}
+
+fn point_n() -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(b"syncmd pair N")
+}
+
+fn derive_key(key: &[u8], label: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum PairMessage {
+    Hello {
+        version: u32,
+        device_name: String,
+        element: String,
+    },
+    Confirm {
+        mac: String,
+    },
+    /// The sender's index; the receiver answers with what it has newer
+    Index {
+        files: Vec<FileMetadata>,
+    },
+    Offer {
+        operations: Vec<SyncOperation>,
+        inline: InlineContent,
+    },
+    FileRequest {
+        path: PathBuf,
+    },
+    FileResponse {
+        path: PathBuf,
+        content: Option<Vec<u8>>,
+    },
+    /// The sender has finished pulling and hands the turn over
+    Done,
+}
+
+/// A connection whose frames are sealed with AES-256-GCM, with a separate
+/// key and message counter per direction.
+struct SecureChannel {
+    stream: TcpStream,
+    send: Aes256Gcm,
+    receive: Aes256Gcm,
+    sent: u64,
+    received: u64,
+}
+
+impl SecureChannel {
+    fn new(stream: TcpStream, keys: &SessionKeys, role: PairRole) -> Self {
+        let (send, receive) = match role {
+            PairRole::Initiator => (&keys.initiator_to_responder, &keys.responder_to_initiator),
+            PairRole::Responder => (&keys.responder_to_initiator, &keys.initiator_to_responder),
+        };
+        Self {
+            stream,
+            send: Aes256Gcm::new(Key::from_slice(send)),
+            receive: Aes256Gcm::new(Key::from_slice(receive)),
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    async fn send(&mut self, message: &PairMessage) -> Result<(), SyncError> {
+        let plaintext = serde_json::to_vec(message)?;
+        let nonce = counter_nonce(self.sent);
+        self.sent += 1;
+        let sealed = self
+            .send
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| SyncError::Network("Failed to seal pairing message".to_string()))?;
+        write_frame(&mut self.stream, &sealed).await
+    }
+
+    async fn receive(&mut self) -> Result<PairMessage, SyncError> {
+        let sealed = read_frame(&mut self.stream, MAX_FRAME_SIZE).await?;
+        let nonce = counter_nonce(self.received);
+        self.received += 1;
+        let plaintext = self
+            .receive
+            .decrypt(Nonce::from_slice(&nonce), sealed.as_slice())
+            .map_err(|_| SyncError::Network("Pairing message failed authentication".to_string()))?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+async fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> Result<(), SyncError> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    stream.write_all(payload).await?;
+    Ok(())
+}
+
+/// Reads one frame, refusing it before reading any further if it is over
+/// `limit` bytes.
+async fn read_frame(stream: &mut TcpStream, limit: usize) -> Result<Vec<u8>, SyncError> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > limit {
+        return Err(SyncError::Network(format!("Peer sent a {} byte frame", length)));
+    }
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+async fn send_plain(stream: &mut TcpStream, message: &PairMessage) -> Result<(), SyncError> {
+    write_frame(stream, &serde_json::to_vec(message)?).await
+}
+
+/// Reads a handshake message, which is small and not yet authenticated.
+async fn receive_plain(stream: &mut TcpStream) -> Result<PairMessage, SyncError> {
+    Ok(serde_json::from_slice(&read_frame(stream, HANDSHAKE_FRAME_SIZE).await?)?)
+}
+
+/// A paired connection, ready to sync.
+pub struct PairSession {
+    channel: SecureChannel,
+    role: PairRole,
+    pub peer_name: String,
+}
+
+/// Totals from a pairing sync.
+#[derive(Debug, Default)]
+pub struct PairSummary {
+    pub received_files: usize,
+    pub received_bytes: u64,
+    pub sent_files: usize,
+}
+
+impl PairSession {
+    /// Runs the handshake on a fresh connection. Fails with an
+    /// authentication error if the peer used a different passphrase.
+    pub async fn establish(
+        mut stream: TcpStream,
+        role: PairRole,
+        passphrase: &str,
+        device_name: &str,
+    ) -> Result<Self, SyncError> {
+        let spake = Spake2::start(role, passphrase);
+        send_plain(&mut stream, &PairMessage::Hello {
+            version: PROTOCOL_VERSION,
+            device_name: device_name.to_string(),
+            element: BASE64.encode(spake.message()),
+        })
+        .await?;
+
+        let PairMessage::Hello { version, device_name: peer_name, element } = receive_plain(&mut stream).await? else {
+            return Err(SyncError::Auth("Peer didn't start a pairing handshake".to_string()));
+        };
+        if version != PROTOCOL_VERSION {
+            return Err(SyncError::Auth(format!("Peer speaks pairing protocol v{}, expected v{}", version, PROTOCOL_VERSION)));
+        }
+        let element = BASE64
+            .decode(element)
+            .map_err(|_| SyncError::Auth("Peer sent an invalid pairing element".to_string()))?;
+        let keys = spake.finish(&element)?;
+
+        // Prove knowledge of the key before anything is sealed with it. The
+        // initiator goes first, so a responder never confirms to a guesser.
+        let (ours, theirs) = match role {
+            PairRole::Initiator => (keys.initiator_confirm, keys.responder_confirm),
+            PairRole::Responder => (keys.responder_confirm, keys.initiator_confirm),
+        };
+        if role == PairRole::Initiator {
+            send_plain(&mut stream, &PairMessage::Confirm { mac: BASE64.encode(ours) }).await?;
+        }
+        let confirmed = match receive_plain(&mut stream).await {
+            Ok(PairMessage::Confirm { mac }) => BASE64
+                .decode(mac)
+                .map(|mac| constant_time_eq(&mac, &theirs))
+                .unwrap_or(false),
+            _ => false,
+        };
+        if !confirmed {
+            return Err(SyncError::Auth("Pairing failed: the passphrases don't match".to_string()));
+        }
+        if role == PairRole::Responder {
+            send_plain(&mut stream, &PairMessage::Confirm { mac: BASE64.encode(ours) }).await?;
+        }
+
+        Ok(Self {
+            channel: SecureChannel::new(stream, &keys, role),
+            role,
+            peer_name,
+        })
+    }
+
+    /// Syncs both ways: the initiator pulls what the responder has newer,
+    /// then the responder pulls from the initiator. Deletions aren't
+    /// propagated, as the devices share no history to tell a deleted file
+    /// from one the other side never had.
+    pub async fn sync(mut self, indexer: &FileIndexer) -> Result<PairSummary, SyncError> {
+        let mut summary = PairSummary::default();
+        match self.role {
+            PairRole::Initiator => {
+                self.pull(indexer, &mut summary).await?;
+                self.serve(indexer, &mut summary).await?;
+            }
+            PairRole::Responder => {
+                self.serve(indexer, &mut summary).await?;
+                self.pull(indexer, &mut summary).await?;
+            }
+        }
+        Ok(summary)
+    }
+
+    async fn pull(&mut self, indexer: &FileIndexer, summary: &mut PairSummary) -> Result<(), SyncError> {
+        let state = tokio::task::block_in_place(|| indexer.index_directory())?;
+        self.channel
+            .send(&PairMessage::Index {
+                files: state.local_files.into_values().collect(),
+            })
+            .await?;
+
+        let PairMessage::Offer { operations, mut inline } = self.channel.receive().await? else {
+            return Err(SyncError::Network("Peer didn't answer with an offer".to_string()));
+        };
+        for operation in operations {
+            let (SyncOperation::Add(metadata) | SyncOperation::Update(metadata)) = operation else {
+                continue;
+            };
+            let content = match inline.take(&metadata) {
+                Some(content) => content,
+                None => {
+                    self.channel.send(&PairMessage::FileRequest { path: metadata.path.clone() }).await?;
+                    match self.channel.receive().await? {
+                        PairMessage::FileResponse { content: Some(content), .. }
+                            if blake3::hash(&content).to_hex().as_str() == metadata.hash =>
+                        {
+                            content
+                        }
+                        _ => {
+                            eprintln!("Skipping {:?}, the peer couldn't send a matching copy", metadata.path);
+                            continue;
+                        }
+                    }
+                }
+            };
+            indexer.write_received(&metadata, &content)?;
+            summary.received_files += 1;
+            summary.received_bytes += content.len() as u64;
+        }
+
+        self.channel.send(&PairMessage::Done).await
+    }
+
+    async fn serve(&mut self, indexer: &FileIndexer, summary: &mut PairSummary) -> Result<(), SyncError> {
+        let state = tokio::task::block_in_place(|| indexer.index_directory())?;
+        loop {
+            match self.channel.receive().await? {
+                PairMessage::Index { files } => {
+                    let operations = offer_operations(&state.local_files, &files);
+                    let mut inline = InlineContent::default();
+                    for operation in &operations {
+                        if let SyncOperation::Add(metadata) | SyncOperation::Update(metadata) = operation {
+                            if metadata.size <= InlineContent::MAX_FILE_SIZE {
+                                inline.insert(metadata.path.clone(), &indexer.read_file_content(&metadata.path)?);
+                            }
+                        }
+                    }
+                    summary.sent_files += operations.len();
+                    self.channel.send(&PairMessage::Offer { operations, inline }).await?;
+                }
+                PairMessage::FileRequest { path } => {
+                    // Only indexed files are served, never arbitrary paths
+                    let content = match state.local_files.contains_key(&path) {
+                        true => indexer.read_file_content(&path).ok(),
+                        false => None,
+                    };
+                    self.channel.send(&PairMessage::FileResponse { path, content }).await?;
+                }
+                PairMessage::Done => return Ok(()),
+                other => {
+                    return Err(SyncError::Network(format!("Unexpected pairing message: {:?}", other)));
+                }
+            }
+        }
+    }
+}
+
+/// Files the peer should take from us: ones it lacks, and ones that differ
+/// where our copy is newer.
+pub fn offer_operations(local: &HashMap<PathBuf, FileMetadata>, peer_files: &[FileMetadata]) -> Vec<SyncOperation> {
+    let peer: HashMap<&PathBuf, &FileMetadata> = peer_files.iter().map(|f| (&f.path, f)).collect();
+    let mut operations: Vec<SyncOperation> = local
+        .values()
+        .filter_map(|metadata| match peer.get(&metadata.path) {
+            None => Some(SyncOperation::Add(metadata.clone())),
+            Some(theirs) if theirs.hash != metadata.hash && metadata.compare_modified(theirs) == std::cmp::Ordering::Greater => {
+                Some(SyncOperation::Update(metadata.clone()))
+            }
+            Some(_) => None,
+        })
+        .collect();
+    operations.sort_by(|a, b| operation_path(a).cmp(operation_path(b)));
+    operations
+}
+
+fn operation_path(operation: &SyncOperation) -> &PathBuf {
+    match operation {
+        SyncOperation::Add(metadata) | SyncOperation::Update(metadata) => &metadata.path,
+        SyncOperation::Delete(path) | SyncOperation::MkDir(path) | SyncOperation::RmDir(path) => path,
+        SyncOperation::Rename { to, .. } => &to.path,
+    }
+}
+
+/// Reads the pairing passphrase from the environment, or interactively
+/// without echoing it.
+pub fn read_passphrase() -> Result<String, SyncError> {
+    if let Some(passphrase) = std::env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty()) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Pairing passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(SyncError::Auth("Empty passphrase".to_string()));
+    }
+    Ok(passphrase)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(initiator_passphrase: &str, responder_passphrase: &str) -> (SessionKeys, SessionKeys) {
+        let initiator = Spake2::start(PairRole::Initiator, initiator_passphrase);
+        let responder = Spake2::start(PairRole::Responder, responder_passphrase);
+        let (to_responder, to_initiator) = (initiator.message(), responder.message());
+        (initiator.finish(&to_initiator).unwrap(), responder.finish(&to_responder).unwrap())
+    }
+
+    #[test]
+    fn test_spake2_key_agreement() {
+        let (a, b) = exchange("correct horse", "correct horse");
+        assert_eq!(a.initiator_confirm, b.initiator_confirm);
+        assert_eq!(a.responder_to_initiator, b.responder_to_initiator);
+        assert_ne!(a.initiator_to_responder, a.responder_to_initiator);
+
+        let (a, b) = exchange("correct horse", "battery staple");
+        assert_ne!(a.initiator_confirm, b.initiator_confirm);
+        assert_ne!(a.initiator_to_responder, b.initiator_to_responder);
+    }
+
+    #[tokio::test]
+    async fn test_large_handshake_frame_refused() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stranger = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let responding = tokio::spawn(PairSession::establish(stream, PairRole::Responder, "correct horse", "desk"));
+
+        // Announces a frame far over the handshake limit and never sends it
+        let length = (HANDSHAKE_FRAME_SIZE as u32 + 1).to_be_bytes();
+        stranger.write_all(&length).await.unwrap();
+        let established = tokio::time::timeout(std::time::Duration::from_secs(5), responding).await.unwrap().unwrap();
+        assert!(matches!(established, Err(SyncError::Network(message)) if message.contains("byte frame")));
+    }
+}
@@ -0,0 +1,156 @@
+#![allow(dead_code)]
+
+use crate::overlay::{EndpointKind, OverlayNetworks};
+use crate::types::SyncError;
+use rusqlite::{params, Connection};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+pub const PEERS_DB_NAME: &str = "peers.db";
+
+/// One address a peer was seen at or reached through.
+#[derive(Debug, Clone)]
+pub struct PeerAddress {
+    pub device_id: String,
+    pub address: SocketAddr,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+    pub last_success: Option<chrono::DateTime<chrono::Utc>>,
+    pub failures: u32,
+    pub kind: EndpointKind,
+}
+
+/// Addresses each peer has been reachable at, kept across runs in
+/// `peers.db` next to the config so roaming devices can be found again.
+pub struct PeerRegistry {
+    conn: Connection,
+}
+
+impl PeerRegistry {
+    pub fn open() -> Result<Self, SyncError> {
+        let config_dir = dirs::config_dir()
+            .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))?
+            .join("syncmd");
+        std::fs::create_dir_all(&config_dir)?;
+        Self::open_at(&config_dir.join(PEERS_DB_NAME))
+    }
+
+    pub fn open_at(db_path: &Path) -> Result<Self, SyncError> {
+        let conn = Connection::open(db_path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS peers (
+                device_id TEXT NOT NULL,
+                address TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                last_success TEXT,
+                failures INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (device_id, address)
+            );",
+        )?;
+        Ok(Self { conn })
+    }
+
+    /// Records that a peer announced or was configured at an address.
+    pub fn record_seen(&self, device_id: &str, address: SocketAddr) -> Result<(), SyncError> {
+        self.conn.execute(
+            "INSERT INTO peers (device_id, address, last_seen) VALUES (?1, ?2, ?3)
+             ON CONFLICT(device_id, address) DO UPDATE SET last_seen = excluded.last_seen",
+            params![device_id, address.to_string(), chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_success(&self, device_id: &str, address: SocketAddr) -> Result<(), SyncError> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO peers (device_id, address, last_seen, last_success) VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT(device_id, address) DO UPDATE SET
+                last_seen = excluded.last_seen, last_success = excluded.last_success, failures = 0",
+            params![device_id, address.to_string(), now],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_failure(&self, device_id: &str, address: SocketAddr) -> Result<(), SyncError> {
+        self.conn.execute(
+            "UPDATE peers SET failures = failures + 1 WHERE device_id = ?1 AND address = ?2",
+            params![device_id, address.to_string()],
+        )?;
+        Ok(())
+    }
+
+    pub fn addresses(&self, device_id: &str, overlays: &OverlayNetworks) -> Result<Vec<PeerAddress>, SyncError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT address, last_seen, last_success, failures FROM peers WHERE device_id = ?1",
+        )?;
+        let rows = stmt.query_map(params![device_id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, u32>(3)?,
+            ))
+        })?;
+
+        let mut addresses = Vec::new();
+        for row in rows {
+            let (address, last_seen, last_success, failures) = row?;
+            let Ok(address) = address.parse() else {
+                continue;
+            };
+            addresses.push(PeerAddress {
+                device_id: device_id.to_string(),
+                address,
+                last_seen: parse_timestamp(&last_seen)?,
+                last_success: last_success.as_deref().map(parse_timestamp).transpose()?,
+                failures,
+                kind: overlays.classify(address.ip()),
+            });
+        }
+        Ok(addresses)
+    }
+
+    /// Addresses to try for a peer, best first: private overlay addresses,
+    /// then those on the same network as this machine, then by most recent
+    /// successful connection, then by most recent sighting.
+    pub fn candidates(&self, device_id: &str, overlays: &OverlayNetworks) -> Result<Vec<PeerAddress>, SyncError> {
+        let mut addresses = self.addresses(device_id, overlays)?;
+        let local_ip = local_ip();
+        addresses.sort_by_key(|a| {
+            (
+                a.kind != EndpointKind::Overlay,
+                std::cmp::Reverse(local_ip.map(|ip| same_subnet(ip, a.address.ip())).unwrap_or(false)),
+                std::cmp::Reverse(a.last_success),
+                a.failures,
+                std::cmp::Reverse(a.last_seen),
+            )
+        });
+        Ok(addresses)
+    }
+
+    pub fn forget(&self, device_id: &str) -> Result<usize, SyncError> {
+        Ok(self.conn.execute("DELETE FROM peers WHERE device_id = ?1", params![device_id])?)
+    }
+}
+
+/// The address this machine uses for outgoing traffic. Connecting a UDP
+/// socket sends nothing, it only selects a route.
+pub fn local_ip() -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Whether two addresses share a /24 (IPv4) or /64 (IPv6) network.
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..4] == b.segments()[..4],
+        _ => false,
+    }
+}
+
+fn parse_timestamp(value: &str) -> Result<chrono::DateTime<chrono::Utc>, SyncError> {
+    Ok(chrono::DateTime::parse_from_rfc3339(value)
+        .map_err(|e| SyncError::Index(format!("Invalid peer timestamp: {}", e)))?
+        .with_timezone(&chrono::Utc))
+}
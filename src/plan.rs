@@ -0,0 +1,188 @@
+#![allow(dead_code)]
+
+use crate::style;
+use crate::types::{FileMetadata, SyncOperation};
+use std::collections::HashMap;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+/// How many of the largest items a plan summary lists.
+const LARGEST_ITEMS: usize = 10;
+
+/// What a sync is about to transfer, for printing before it starts.
+#[derive(Debug, Default)]
+pub struct TransferPlan {
+    pub downloads: Vec<(PathBuf, u64)>,
+    pub uploads: Vec<(PathBuf, u64)>,
+    pub deletions: usize,
+    pub renames: usize,
+}
+
+impl TransferPlan {
+    /// Builds a plan from the operations the server sent and the local files
+    /// that changed since the last stored index.
+    pub fn new(
+        operations: &[SyncOperation],
+        local_files: &HashMap<PathBuf, FileMetadata>,
+        previous_files: &HashMap<PathBuf, FileMetadata>,
+    ) -> Self {
+        let mut plan = Self::default();
+        for operation in operations {
+            match operation {
+                SyncOperation::Add(metadata) | SyncOperation::Update(metadata) => {
+                    plan.downloads.push((metadata.path.clone(), metadata.size));
+                }
+                SyncOperation::Delete(_) => plan.deletions += 1,
+                SyncOperation::Rename { .. } => plan.renames += 1,
+                // Nothing to transfer
+                SyncOperation::MkDir(_) | SyncOperation::RmDir(_) => {}
+            }
+        }
+
+        for (path, metadata) in local_files {
+            let changed = previous_files
+                .get(path)
+                .map(|previous| previous.hash != metadata.hash)
+                .unwrap_or(true);
+            let downloading = plan.downloads.iter().any(|(p, _)| p == path);
+            if changed && !downloading {
+                plan.uploads.push((path.clone(), metadata.size));
+            }
+        }
+        plan.uploads.sort();
+        plan
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.downloads.is_empty() && self.uploads.is_empty() && self.deletions == 0 && self.renames == 0
+    }
+
+    pub fn download_bytes(&self) -> u64 {
+        self.downloads.iter().map(|(_, size)| size).sum()
+    }
+
+    pub fn upload_bytes(&self) -> u64 {
+        self.uploads.iter().map(|(_, size)| size).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.download_bytes() + self.upload_bytes()
+    }
+
+    /// Prints the summary: totals per direction, an estimate at the given
+    /// throughput in bytes per second, and the largest items.
+    pub fn print_summary(&self, throughput: Option<f64>) {
+        println!("{}", style::heading("Transfer plan:"));
+        println!("  {}", style::added(format!("{} files / {} down", self.downloads.len(), format_size(self.download_bytes()))));
+        println!("  {} files / {} up", self.uploads.len(), format_size(self.upload_bytes()));
+        if self.deletions > 0 {
+            println!("  {}", style::deleted(format!("{} files to delete", self.deletions)));
+        }
+        if self.renames > 0 {
+            println!("  {} files to move", self.renames);
+        }
+
+        match throughput.filter(|bps| *bps > 0.0) {
+            Some(bps) => println!(
+                "  Estimated time: {} at {}/s",
+                format_duration(self.total_bytes() as f64 / bps),
+                format_size(bps as u64)
+            ),
+            None => println!("  Estimated time: unknown (no recent transfers)"),
+        }
+
+        let mut largest: Vec<(&str, &PathBuf, u64)> = self
+            .downloads
+            .iter()
+            .map(|(path, size)| ("down", path, *size))
+            .chain(self.uploads.iter().map(|(path, size)| ("up", path, *size)))
+            .collect();
+        largest.sort_by_key(|item| std::cmp::Reverse(item.2));
+        if !largest.is_empty() {
+            println!("  Largest items:");
+            for (direction, path, size) in largest.into_iter().take(LARGEST_ITEMS) {
+                let direction = format!("{:<4}", direction);
+                let direction = if direction.starts_with("down") { style::added(direction) } else { direction };
+                println!("    {:>10}  {}  {}", format_size(size), direction, path.display());
+            }
+        }
+    }
+}
+
+/// Parses sizes like `500MB`, `1.5G` or `4096` (bytes), using binary units.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {}", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024 * 1024 * 1024 * 1024,
+        other => return Err(format!("Unknown size unit: {}", other)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.ceil() as u64;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, (seconds % 3600) / 60),
+    }
+}
+
+/// Asks whether to go ahead with a plan that exceeds the threshold. Without a
+/// terminal there is nobody to ask, so the sync is declined.
+pub fn confirm(plan: &TransferPlan, threshold: u64) -> std::io::Result<bool> {
+    if plan.total_bytes() <= threshold {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        eprintln!(
+            "Sync of {} exceeds --confirm-over {} and no terminal is available to confirm",
+            format_size(plan.total_bytes()),
+            format_size(threshold)
+        );
+        return Ok(false);
+    }
+
+    print!("Transfer {}? [y/N] ", format_size(plan.total_bytes()));
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500MB"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_size("1.5g"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("12 parsecs").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+#![allow(dead_code)]
+
+//! Live transfer progress for `syncmd sync`: a bar per file being sent or
+//! received, or with `--json` one JSON object per update on stdout, for
+//! scripts and GUIs wrapping the CLI. Both follow the transfer queue's
+//! progress updates, so any transfer in the process shows up.
+
+use crate::file_transfer::TransferQueue;
+use crate::types::TransferProgress;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+
+const BAR_TEMPLATE: &str = "{msg:30!} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} {eta}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressOutput {
+    /// Bars on stderr, hidden when it isn't a terminal
+    Bars,
+    /// One JSON object per update on stdout
+    Json,
+}
+
+/// Shows the progress of `queue`'s transfers until the process exits.
+pub fn spawn(queue: &TransferQueue, output: ProgressOutput) -> tokio::task::JoinHandle<()> {
+    let mut updates = queue.subscribe();
+    tokio::spawn(async move {
+        let mut bars = Bars::default();
+        loop {
+            let update = match updates.recv().await {
+                Ok(update) => update,
+                // Missed updates are overtaken by the next ones
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            match output {
+                ProgressOutput::Bars => bars.update(&update),
+                ProgressOutput::Json => {
+                    if let Ok(json) = serde_json::to_string(&update) {
+                        println!("{}", json);
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// A bar per transfer still running.
+#[derive(Default)]
+struct Bars {
+    multi: MultiProgress,
+    bars: HashMap<String, ProgressBar>,
+}
+
+impl Bars {
+    fn update(&mut self, update: &TransferProgress) {
+        if update.finished {
+            if let Some(bar) = self.bars.remove(&update.transfer_id) {
+                bar.set_position(update.bytes_transferred);
+                match update.bytes_transferred >= update.total_bytes {
+                    true => bar.finish_and_clear(),
+                    false => bar.abandon_with_message(format!("{} (stopped)", file_name(update))),
+                }
+            }
+            return;
+        }
+        let multi = &self.multi;
+        let bar = self.bars.entry(update.transfer_id.clone()).or_insert_with(|| {
+            let bar = multi.add(ProgressBar::new(update.total_bytes));
+            if let Ok(style) = ProgressStyle::with_template(BAR_TEMPLATE) {
+                bar.set_style(style.progress_chars("=> "));
+            }
+            bar.set_message(file_name(update));
+            bar
+        });
+        bar.set_position(update.bytes_transferred);
+    }
+
+    fn running(&self) -> usize {
+        self.bars.len()
+    }
+}
+
+fn file_name(update: &TransferProgress) -> String {
+    update
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| update.transfer_id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_progress_bars_follow_transfers() {
+        let queue = TransferQueue::default();
+        let mut updates = queue.subscribe();
+        let mut bars = Bars { multi: MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden()), ..Default::default() };
+
+        let handle = queue.register("t1", std::path::Path::new("/notes/big.png"), 1000);
+        handle.set_transferred(400);
+        let update = updates.recv().await.unwrap();
+        assert_eq!((file_name(&update), update.bytes_transferred, update.finished), ("big.png".to_string(), 400, false));
+        bars.update(&update);
+        assert_eq!(bars.running(), 1);
+        assert_eq!(bars.bars["t1"].position(), 400);
+
+        drop(handle);
+        let update = updates.recv().await.unwrap();
+        assert!(update.finished);
+        bars.update(&update);
+        assert_eq!(bars.running(), 0);
+    }
+}
@@ -0,0 +1,520 @@
+#![allow(dead_code)]
+
+//! Protocol traces, turned on with `--trace-protocol <file>` on the client
+//! and both servers. Every connection then runs through a relay on the
+//! loopback interface that splits what passes into messages, JSON or binary
+//! frames, and appends a line per message to the file: when it passed, on
+//! which connection, in which direction, its type, its size and its key
+//! fields. File content, tokens and links are left out, so a trace can be
+//! attached to a bug report.
+//!
+//! `syncmd trace analyze <file>` summarizes a trace: the round trips by
+//! request and reply type with their latency, and the requests whose reply
+//! took long enough to call the protocol stalled.
+
+use crate::types::SyncError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// What the relay reads at once
+const RELAY_BUFFER: usize = 64 * 1024;
+/// Longer strings are cut in traces, they are usually errors or listings
+const MAX_STRING: usize = 120;
+/// Fields whose values are never traced: file content and secrets
+const REDACTED: &[&str] = &["content", "data", "inline", "token", "link", "passphrase", "signature", "secret"];
+/// How long a reply may take before `analyze` calls the wait a stall
+pub const DEFAULT_STALL: Duration = Duration::from_secs(5);
+
+static TRACE: OnceLock<Mutex<std::fs::File>> = OnceLock::new();
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A line of a trace: one message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub at: DateTime<Utc>,
+    /// Numbers the connections of the process tracing, from 0
+    pub connection: u64,
+    pub peer: String,
+    pub direction: Direction,
+    /// The message's variant, `unparsed` for bytes that aren't a message
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Bytes on the wire
+    pub size: usize,
+    /// Sent as a binary frame rather than JSON
+    #[serde(default)]
+    pub binary: bool,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+}
+
+/// Appends a line per message of this process's connections to `path` from
+/// now on.
+pub fn enable(path: &Path) -> Result<(), SyncError> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = TRACE.set(Mutex::new(file));
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    TRACE.get().is_some()
+}
+
+fn write(record: &TraceRecord) {
+    let Some(file) = TRACE.get() else { return };
+    let Ok(mut line) = serde_json::to_vec(record) else { return };
+    line.push(b'\n');
+    if let Ok(mut file) = file.lock() {
+        if let Err(e) = file.write_all(&line) {
+            tracing::debug!("Could not write protocol trace: {}", e);
+        }
+    }
+}
+
+/// The stream to use for a connection with `peer`: `stream` itself, or
+/// while tracing is enabled, one through a relay tracing its messages.
+pub async fn wrap(stream: TcpStream, peer: &str) -> Result<TcpStream, SyncError> {
+    if !enabled() {
+        return Ok(stream);
+    }
+    let (near, relayed) = crate::network::loopback_pair().await?;
+    let connection = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    let peer = peer.to_string();
+    tokio::spawn(async move {
+        let (mut relayed_read, mut relayed_write) = relayed.into_split();
+        let (mut remote_read, mut remote_write) = stream.into_split();
+        let trace = |direction| {
+            let peer = peer.clone();
+            move |message: Message| write(&message.record(connection, &peer, direction))
+        };
+        tokio::join!(
+            relay(&mut relayed_read, &mut remote_write, trace(Direction::Sent)),
+            relay(&mut remote_read, &mut relayed_write, trace(Direction::Received)),
+        );
+    });
+    Ok(near)
+}
+
+/// Forwards what `from` reads to `to` until either side closes, passing
+/// each message to `trace` once it has been forwarded whole.
+async fn relay<R, W, F>(from: &mut R, to: &mut W, mut trace: F)
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+    F: FnMut(Message),
+{
+    let mut buffer = vec![0; RELAY_BUFFER];
+    let mut splitter = Splitter::default();
+    loop {
+        let read = match from.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        if to.write_all(&buffer[..read]).await.is_err() {
+            break;
+        }
+        splitter.push(&buffer[..read]).into_iter().for_each(&mut trace);
+    }
+    let _ = to.shutdown().await;
+}
+
+/// A message the relay saw, before it's timestamped.
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    kind: String,
+    size: usize,
+    binary: bool,
+    fields: Map<String, Value>,
+}
+
+impl Message {
+    fn record(self, connection: u64, peer: &str, direction: Direction) -> TraceRecord {
+        TraceRecord {
+            at: Utc::now(),
+            connection,
+            peer: peer.to_string(),
+            direction,
+            kind: self.kind,
+            size: self.size,
+            binary: self.binary,
+            fields: self.fields,
+        }
+    }
+}
+
+/// Splits one direction of a connection into messages, which may arrive
+/// several to a read or spread across reads.
+#[derive(Default)]
+struct Splitter {
+    buffer: Vec<u8>,
+}
+
+impl Splitter {
+    fn push(&mut self, bytes: &[u8]) -> Vec<Message> {
+        self.buffer.extend_from_slice(bytes);
+        let mut messages = Vec::new();
+        loop {
+            let Some(start) = self.buffer.iter().position(|byte| !byte.is_ascii_whitespace()) else {
+                self.buffer.clear();
+                return messages;
+            };
+            if self.buffer[start] == crate::wire::FRAME_MARKER {
+                match crate::wire::frame_payload(&self.buffer[start..]) {
+                    Ok(Some((payload, used))) => {
+                        messages.push(describe_frame(&payload, used));
+                        self.buffer.drain(..start + used);
+                    }
+                    Ok(None) => return messages,
+                    Err(_) => return self.unparsed(messages),
+                }
+                continue;
+            }
+            // Like the protocol's reader, only parse once a message could
+            // have ended, so large ones aren't parsed again on every read
+            if !matches!(self.buffer.iter().rev().find(|byte| !byte.is_ascii_whitespace()), Some(b'}' | b'"'))
+                && !bytes.contains(&crate::wire::FRAME_MARKER)
+            {
+                return messages;
+            }
+            let mut values = serde_json::Deserializer::from_slice(&self.buffer).into_iter::<Value>();
+            match values.next() {
+                Some(Ok(value)) => {
+                    let used = values.byte_offset();
+                    messages.push(describe_json(value, used - start));
+                    self.buffer.drain(..used);
+                }
+                Some(Err(e)) if !e.is_eof() => return self.unparsed(messages),
+                _ => return messages,
+            }
+        }
+    }
+
+    /// Traces what's buffered as bytes the relay can't make out, e.g. a
+    /// sealed pairing channel, and starts over with the next read.
+    fn unparsed(&mut self, mut messages: Vec<Message>) -> Vec<Message> {
+        messages.push(Message { kind: "unparsed".to_string(), size: self.buffer.len(), binary: false, fields: Map::new() });
+        self.buffer.clear();
+        messages
+    }
+}
+
+/// A JSON message: its variant with its fields, compressed ones opened.
+fn describe_json(value: Value, size: usize) -> Message {
+    let opened = serde_json::from_value::<crate::network::NetworkMessage>(value.clone())
+        .ok()
+        .filter(|message| matches!(message, crate::network::NetworkMessage::Compressed { .. }))
+        .and_then(|message| crate::network::decompress_message(message).ok())
+        .and_then(|message| serde_json::to_value(message).ok());
+    let (kind, mut fields) = variant(opened.as_ref().unwrap_or(&value));
+    if opened.is_some() {
+        fields.insert("compressed".to_string(), Value::Bool(true));
+    }
+    Message { kind, size, binary: false, fields }
+}
+
+/// A binary frame, a network message's or a file transfer's.
+fn describe_frame(payload: &[u8], size: usize) -> Message {
+    let value = match crate::network::frame_message(payload) {
+        Some(message) => serde_json::to_value(message).ok(),
+        None => crate::wire::decode_exact::<crate::file_transfer::FileTransferMessage>(payload).and_then(|mut message| {
+            if let crate::file_transfer::FileTransferMessage::Chunk(chunk) = &mut message {
+                chunk.data = Vec::new();
+            }
+            serde_json::to_value(message).ok()
+        }),
+    };
+    let (kind, fields) = match &value {
+        Some(value) => variant(value),
+        None => ("frame".to_string(), Map::new()),
+    };
+    Message { kind, size, binary: true, fields }
+}
+
+/// The variant name and summarized fields of an externally tagged enum.
+fn variant(value: &Value) -> (String, Map<String, Value>) {
+    match value {
+        Value::String(name) => (name.clone(), Map::new()),
+        Value::Object(object) if object.len() == 1 => {
+            let (name, inner) = object.iter().next().expect("one entry");
+            let fields = match inner {
+                Value::Object(fields) => summarize(fields, 0),
+                Value::Null => Map::new(),
+                other => Map::from_iter([("value".to_string(), summarize_value("value", other, 0))]),
+            };
+            (name.clone(), fields)
+        }
+        _ => ("unknown".to_string(), Map::new()),
+    }
+}
+
+fn summarize(fields: &Map<String, Value>, depth: usize) -> Map<String, Value> {
+    fields.iter().map(|(name, value)| (name.clone(), summarize_value(name, value, depth))).collect()
+}
+
+/// Keeps numbers, flags and short strings, counts the items of lists, and
+/// leaves out redacted fields and anything nested deeper than a level.
+fn summarize_value(name: &str, value: &Value, depth: usize) -> Value {
+    if REDACTED.contains(&name) {
+        return match value {
+            Value::Null => Value::Null,
+            _ => Value::String("<redacted>".to_string()),
+        };
+    }
+    match value {
+        Value::String(text) if text.chars().count() > MAX_STRING => {
+            Value::String(format!("{}…", text.chars().take(MAX_STRING).collect::<String>()))
+        }
+        Value::Array(items) => Value::String(format!("<{} items>", items.len())),
+        Value::Object(fields) if depth == 0 => Value::Object(summarize(fields, depth + 1)),
+        Value::Object(fields) => Value::String(format!("<{} fields>", fields.len())),
+        other => other.clone(),
+    }
+}
+
+/// Reads a trace written by `--trace-protocol`, skipping lines that don't
+/// parse, e.g. one cut short by a crash.
+pub fn read(path: &Path) -> Result<Vec<TraceRecord>, SyncError> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+}
+
+/// Round trips of one request type answered by one reply type.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoundTrips {
+    pub count: usize,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl RoundTrips {
+    pub fn average(&self) -> Duration {
+        self.total / self.count.max(1) as u32
+    }
+}
+
+/// A request whose reply took longer than the stall threshold, or never
+/// came before the connection's last message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stall {
+    pub connection: u64,
+    pub peer: String,
+    pub at: DateTime<Utc>,
+    pub request: String,
+    pub fields: Map<String, Value>,
+    pub reply: Option<String>,
+    pub waited: Duration,
+}
+
+/// A trace summarized by `analyze`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceAnalysis {
+    pub connections: usize,
+    pub messages: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub duration: Duration,
+    /// By request and reply type
+    pub round_trips: BTreeMap<(String, String), RoundTrips>,
+    /// Requests still waiting on a reply when the trace ends, by type
+    pub unanswered: BTreeMap<String, usize>,
+    pub stalls: Vec<Stall>,
+}
+
+/// Pairs each connection's messages into round trips, a message answered
+/// by the first one going the other way, and flags the waits longer than
+/// `stall_after`. Later messages in the same direction, like chunks
+/// following a header, belong to the round trip already open.
+pub fn analyze(records: &[TraceRecord], stall_after: Duration) -> TraceAnalysis {
+    let mut analysis = TraceAnalysis { messages: records.len(), ..Default::default() };
+    let mut by_connection: BTreeMap<(&str, u64), Vec<&TraceRecord>> = BTreeMap::new();
+    for record in records {
+        match record.direction {
+            Direction::Sent => analysis.bytes_sent += record.size,
+            Direction::Received => analysis.bytes_received += record.size,
+        }
+        by_connection.entry((&record.peer, record.connection)).or_default().push(record);
+    }
+    analysis.connections = by_connection.len();
+    if let (Some(first), Some(last)) = (records.iter().map(|r| r.at).min(), records.iter().map(|r| r.at).max()) {
+        analysis.duration = (last - first).to_std().unwrap_or_default();
+    }
+
+    for messages in by_connection.values_mut() {
+        messages.sort_by_key(|record| record.at);
+        let mut open: Option<&TraceRecord> = None;
+        for record in messages.iter() {
+            match open {
+                Some(request) if request.direction != record.direction => {
+                    let waited = (record.at - request.at).to_std().unwrap_or_default();
+                    let trips = analysis.round_trips.entry((request.kind.clone(), record.kind.clone())).or_default();
+                    trips.count += 1;
+                    trips.total += waited;
+                    trips.max = trips.max.max(waited);
+                    if waited > stall_after {
+                        analysis.stalls.push(stall(request, Some(record), waited));
+                    }
+                    open = None;
+                }
+                Some(_) => {}
+                None => open = Some(record),
+            }
+        }
+        if let (Some(request), Some(last)) = (open, messages.last()) {
+            *analysis.unanswered.entry(request.kind.clone()).or_default() += 1;
+            let waited = (last.at - request.at).to_std().unwrap_or_default();
+            if waited > stall_after {
+                analysis.stalls.push(stall(request, None, waited));
+            }
+        }
+    }
+    analysis.stalls.sort_by_key(|stall| stall.at);
+    analysis
+}
+
+fn stall(request: &TraceRecord, reply: Option<&TraceRecord>, waited: Duration) -> Stall {
+    Stall {
+        connection: request.connection,
+        peer: request.peer.clone(),
+        at: request.at,
+        request: request.kind.clone(),
+        fields: request.fields.clone(),
+        reply: reply.map(|reply| reply.kind.clone()),
+        waited,
+    }
+}
+
+impl std::fmt::Display for TraceAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} messages on {} connections over {:.1}s, {} bytes sent and {} received",
+            self.messages,
+            self.connections,
+            self.duration.as_secs_f64(),
+            self.bytes_sent,
+            self.bytes_received
+        )?;
+        if !self.round_trips.is_empty() {
+            writeln!(f, "\nRound trips:")?;
+            for ((request, reply), trips) in &self.round_trips {
+                writeln!(
+                    f,
+                    "  {:<40} {:>6}  avg {:>8.1}ms  max {:>8.1}ms",
+                    format!("{} -> {}", request, reply),
+                    trips.count,
+                    trips.average().as_secs_f64() * 1000.0,
+                    trips.max.as_secs_f64() * 1000.0
+                )?;
+            }
+        }
+        if !self.unanswered.is_empty() {
+            writeln!(f, "\nUnanswered when the trace ends:")?;
+            for (request, count) in &self.unanswered {
+                writeln!(f, "  {:<40} {:>6}", request, count)?;
+            }
+        }
+        match self.stalls.is_empty() {
+            true => writeln!(f, "\nNo stalls")?,
+            false => writeln!(f, "\nStalls:")?,
+        }
+        for stall in &self.stalls {
+            let fields = match stall.fields.is_empty() {
+                true => String::new(),
+                false => format!(" {}", Value::Object(stall.fields.clone())),
+            };
+            let reply = match &stall.reply {
+                Some(reply) => format!("waited {:.1}s for {}", stall.waited.as_secs_f64(), reply),
+                None => format!("no reply after {:.1}s", stall.waited.as_secs_f64()),
+            };
+            writeln!(
+                f,
+                "  {} connection {} with {}: {}{} {}",
+                stall.at.format("%H:%M:%S%.3f"),
+                stall.connection,
+                stall.peer,
+                stall.request,
+                fields,
+                reply
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{encode_message, Encoding, NetworkMessage};
+
+    #[test]
+    fn test_protocol_trace() {
+        let request = serde_json::to_vec(&NetworkMessage::FileRequest { path: "notes/a.md".to_string() }).unwrap();
+        let reply = encode_message(
+            &NetworkMessage::FileResponse {
+                path: "notes/a.md".to_string(),
+                found: true,
+                content: Some(b"secret notes".to_vec()),
+                metadata: None,
+            },
+            Encoding { binary: true, compress: false },
+        )
+        .unwrap();
+
+        // Messages split across reads and several to a read
+        let mut splitter = Splitter::default();
+        assert!(splitter.push(&request[..5]).is_empty());
+        let mut rest = request[5..].to_vec();
+        rest.extend_from_slice(&reply);
+        let messages = splitter.push(&rest);
+        assert_eq!(messages.len(), 2);
+        assert_eq!((messages[0].kind.as_str(), messages[0].size), ("FileRequest", request.len()));
+        assert_eq!(messages[0].fields["path"], "notes/a.md");
+        assert_eq!((messages[1].kind.as_str(), messages[1].binary, messages[1].size), ("FileResponse", true, reply.len()));
+        assert_eq!(messages[1].fields["content"], "<redacted>");
+        assert_eq!(messages[1].fields["found"], true);
+
+        let start = Utc::now();
+        let at = |millis| start + chrono::Duration::milliseconds(millis);
+        let record = |connection, millis, direction, kind: &str| TraceRecord {
+            at: at(millis),
+            connection,
+            peer: "127.0.0.1:9000".to_string(),
+            direction,
+            kind: kind.to_string(),
+            size: 10,
+            binary: false,
+            fields: Map::new(),
+        };
+        let records = vec![
+            record(0, 0, Direction::Sent, "FileRequest"),
+            record(0, 20, Direction::Received, "FileResponse"),
+            record(0, 100, Direction::Sent, "FileRequest"),
+            record(0, 7100, Direction::Received, "FileResponse"),
+            record(1, 0, Direction::Sent, "SyncRequest"),
+            record(1, 6000, Direction::Sent, "Heartbeat"),
+        ];
+        let analysis = analyze(&records, DEFAULT_STALL);
+        assert_eq!((analysis.connections, analysis.messages, analysis.bytes_sent), (2, 6, 40));
+        let trips = &analysis.round_trips[&("FileRequest".to_string(), "FileResponse".to_string())];
+        assert_eq!((trips.count, trips.max), (2, Duration::from_secs(7)));
+        assert_eq!(analysis.unanswered["SyncRequest"], 1);
+        let stalls: Vec<_> = analysis.stalls.iter().map(|stall| (stall.request.as_str(), stall.reply.is_some())).collect();
+        assert_eq!(stalls, vec![("SyncRequest", false), ("FileRequest", true)]);
+    }
+}
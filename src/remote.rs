@@ -0,0 +1,193 @@
+#![allow(dead_code)]
+
+use crate::types::SyncError;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Never use TLS
+    Disabled,
+    /// Use TLS when the transport supports it
+    #[default]
+    Preferred,
+    /// Refuse to connect without TLS
+    Required,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Tcp,
+    #[value(name = "websocket")]
+    WebSocket,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionPreference {
+    /// Compress when both sides support it
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// A named server or peer with its connection requirements.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Remote {
+    pub name: String,
+    pub address: String,
+    #[serde(default)]
+    pub tls: TlsMode,
+    /// Pinned SHA-256 fingerprint of the remote's certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// HTTP proxy to tunnel through, e.g. `http://proxy.local:3128`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub compression: CompressionPreference,
+    /// Time-of-day limits for transfers, unlimited outside them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bandwidth: Vec<crate::bandwidth::BandwidthProfile>,
+}
+
+impl Remote {
+    /// An ad-hoc remote for a plain `host:port` or a `ws://` or `wss://` URL
+    /// given on the command line.
+    pub fn from_address(address: &str) -> Self {
+        let websocket = crate::websocket::is_url(address);
+        Self {
+            name: address.to_string(),
+            address: address.to_string(),
+            tls: match address.starts_with("wss://") {
+                true => TlsMode::Required,
+                false => TlsMode::Preferred,
+            },
+            fingerprint: None,
+            transport: match websocket {
+                true => TransportKind::WebSocket,
+                false => TransportKind::Tcp,
+            },
+            proxy: None,
+            compression: CompressionPreference::Auto,
+            bandwidth: Vec::new(),
+        }
+    }
+
+    /// Checks the remote's requirements against what the transport layer can
+    /// provide, so a misconfigured remote fails before any data is sent.
+    pub fn check_supported(&self) -> Result<(), SyncError> {
+        if self.tls == TlsMode::Required && !self.uses_tls() {
+            return Err(SyncError::Network(format!(
+                "Remote '{}' requires TLS, which only the WebSocket transport provides, with a wss:// address",
+                self.name
+            )));
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            if !self.uses_tls() {
+                return Err(SyncError::Network(format!(
+                    "Remote '{}' pins a certificate fingerprint, which can't be verified without TLS",
+                    self.name
+                )));
+            }
+            crate::websocket::parse_fingerprint(fingerprint)?;
+        }
+        Ok(())
+    }
+
+    /// The URL a WebSocket remote is reached at: its address if that is a
+    /// URL, else one for the `host:port` following its TLS mode.
+    pub fn websocket_url(&self) -> String {
+        match self.address.contains("://") {
+            true => self.address.clone(),
+            false if self.tls == TlsMode::Required => format!("wss://{}/", self.address),
+            false => format!("ws://{}/", self.address),
+        }
+    }
+
+    fn uses_tls(&self) -> bool {
+        self.transport == TransportKind::WebSocket && self.websocket_url().starts_with("wss://")
+    }
+
+    pub async fn connect(&self) -> Result<TcpStream, SyncError> {
+        self.check_supported()?;
+        if self.tls == TlsMode::Preferred && !self.uses_tls() {
+            tracing::warn!("Connecting to '{}' without TLS", self.name);
+        }
+
+        let websocket = self.transport == TransportKind::WebSocket;
+        let (target, proxy) = match websocket {
+            true => (
+                crate::websocket::authority(&self.websocket_url())?,
+                self.proxy.clone().or_else(crate::websocket::proxy_from_env),
+            ),
+            false => (self.address.clone(), self.proxy.clone()),
+        };
+        let stream = match &proxy {
+            Some(proxy) => connect_via_http_proxy(proxy, &target).await?,
+            None => TcpStream::connect(&target)
+                .await
+                .map_err(|e| SyncError::Network(format!("Failed to connect to {}: {}", target, e)))?,
+        };
+        let stream = match websocket {
+            true => crate::websocket::upgrade(&self.websocket_url(), stream, self.fingerprint.as_deref()).await?,
+            false => stream,
+        };
+        let stream = crate::chaos::wrap(stream).await?;
+        let stream = crate::protocol_trace::wrap(stream, &self.address).await?;
+        crate::bandwidth::register(self, &stream);
+        Ok(stream)
+    }
+}
+
+/// The command-line spelling of a setting, for display.
+pub fn setting_name<T: clap::ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// Opens a tunnel with `CONNECT` through an HTTP proxy.
+async fn connect_via_http_proxy(proxy: &str, target: &str) -> Result<TcpStream, SyncError> {
+    let proxy_address = proxy
+        .strip_prefix("http://")
+        .unwrap_or(proxy)
+        .trim_end_matches('/');
+    let mut stream = TcpStream::connect(proxy_address)
+        .await
+        .map_err(|e| SyncError::Network(format!("Failed to connect to proxy {}: {}", proxy_address, e)))?;
+
+    let request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response headers byte-wise through a BufReader on a borrowed
+    // stream, so no tunnel payload is consumed
+    let mut reader = BufReader::with_capacity(1, &mut stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).await?;
+    let status_ok = status_line
+        .split_whitespace()
+        .nth(1)
+        .map(|code| code == "200")
+        .unwrap_or(false);
+    if !status_ok {
+        return Err(SyncError::Network(format!("Proxy refused tunnel: {}", status_line.trim())));
+    }
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    Ok(stream)
+}
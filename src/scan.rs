@@ -0,0 +1,120 @@
+#![allow(dead_code)]
+
+//! Malware scanning of uploads on the server. A share's `scan_cmd`, such as
+//! `clamdscan --no-summary`, is run on every uploaded file before the file
+//! is accepted. Following clamscan, exit status 0 means clean and 1 means
+//! infected; anything else is a failed scan, and the upload is refused as
+//! well, so nothing unscanned gets in. Refused files are kept in the
+//! share's `.syncmd/quarantine/` for the operator to look at.
+
+use crate::file_transfer::run_blocking;
+use crate::index_store::STATE_DIR_NAME;
+use crate::network::{RejectionReason, UploadRejection};
+use crate::types::SyncError;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+pub const QUARANTINE_DIR_NAME: &str = "quarantine";
+const SCAN_DIR_NAME: &str = "scan";
+
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    command: String,
+    /// Where uploads wait while they are scanned
+    scan_dir: PathBuf,
+}
+
+impl Scanner {
+    pub fn new(command: String, storage_path: &Path) -> Self {
+        Self {
+            command,
+            scan_dir: crate::state::dir(storage_path).join(SCAN_DIR_NAME),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Scans an upload's content; `None` if it may be stored.
+    pub async fn check(&self, content: &[u8]) -> Result<Option<UploadRejection>, SyncError> {
+        std::fs::create_dir_all(&self.scan_dir)?;
+        let file = self.scan_dir.join(uuid::Uuid::new_v4().to_string());
+        std::fs::write(&file, content)?;
+        let command = self.command.clone();
+        let scanned = file.clone();
+        let result = run_blocking(move || run_scanner(&command, &scanned)).await;
+        let _ = std::fs::remove_file(&file);
+        result
+    }
+}
+
+/// Runs `command` with the file's path appended, or put where `{}` is.
+fn run_scanner(command: &str, file: &Path) -> Result<Option<UploadRejection>, SyncError> {
+    let quoted = format!("'{}'", file.to_string_lossy().replace('\'', r"'\''"));
+    let command_line = if command.contains("{}") {
+        command.replace("{}", &quoted)
+    } else {
+        format!("{} {}", command, quoted)
+    };
+    let output = crate::filter::shell_command(&command_line)
+        .stdin(Stdio::null())
+        .output()?;
+    // Scanners name the file in their verdict, which means nothing to clients
+    let detail = |output: &[u8]| {
+        let text = String::from_utf8_lossy(output);
+        let line = text.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or_default();
+        line.replace(&quoted, "")
+            .replace(&*file.to_string_lossy(), "")
+            .trim_start_matches(':')
+            .trim()
+            .to_string()
+    };
+    Ok(match output.status.code() {
+        Some(0) => None,
+        Some(1) => Some(UploadRejection {
+            reason: RejectionReason::Infected,
+            detail: detail(&output.stdout),
+        }),
+        _ => Some(UploadRejection {
+            reason: RejectionReason::ScanFailed,
+            detail: match detail(&output.stderr) {
+                detail if detail.is_empty() => format!("scanner exited with {}", output.status),
+                detail => detail,
+            },
+        }),
+    })
+}
+
+/// Where a refused upload of `path` is kept, relative to the share.
+pub fn quarantine_path(path: &str) -> PathBuf {
+    Path::new(STATE_DIR_NAME)
+        .join(QUARANTINE_DIR_NAME)
+        .join(chrono::Utc::now().timestamp_millis().to_string())
+        .join(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scanner_verdicts() {
+        let storage = tempfile::tempdir().unwrap();
+        let scanner = Scanner::new(
+            r#"grep -q EICAR {} && { echo "{}: Eicar-Test-Signature FOUND"; exit 1; } || exit 0"#.to_string(),
+            storage.path(),
+        );
+        assert_eq!(scanner.check(b"# Notes").await.unwrap(), None);
+        let rejection = scanner.check(b"X5O!P%@AP EICAR").await.unwrap().unwrap();
+        assert_eq!(rejection.reason, RejectionReason::Infected);
+        assert_eq!(rejection.detail, "Eicar-Test-Signature FOUND");
+
+        let broken = Scanner::new("test -f {} && echo 'no database' >&2; exit 2".to_string(), storage.path());
+        let rejection = broken.check(b"# Notes").await.unwrap().unwrap();
+        assert_eq!(rejection.reason, RejectionReason::ScanFailed);
+        assert_eq!(rejection.detail, "no database");
+        // Nothing is left waiting
+        assert_eq!(std::fs::read_dir(storage.path().join(".syncmd/scan")).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,327 @@
+#![allow(dead_code)]
+
+//! When a root syncs on its own: every so many seconds or at the times a
+//! cron expression names, outside its quiet hours. During quiet hours no
+//! round runs, not even for local edits or changes pushed by the server;
+//! they wait for the first round after. A flush always runs, so `syncmd
+//! flush` and shutting down aren't held back.
+//!
+//! Times are local. Cron expressions have the usual five fields, minute,
+//! hour, day of month, month and day of week (0 or 7 for Sunday), each `*`,
+//! a number, a range `a-b`, a step `*/n` or `a-b/n`, or a list of those.
+
+use crate::types::SyncError;
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Between periodic rounds of a root without a schedule
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+/// How far ahead a cron expression is searched for its next time, so one
+/// that never matches, e.g. February 30th, doesn't search forever
+const CRON_HORIZON_DAYS: i64 = 4 * 366;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A root's `schedule` in the config.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleSettings {
+    /// Seconds between periodic rounds, 30 if neither this nor `cron` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub every_secs: Option<u64>,
+    /// Cron expression for periodic rounds, instead of `every_secs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    /// Local time ranges such as `08:00-22:00` in which no round runs;
+    /// a range may wrap past midnight
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quiet_hours: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    timing: Timing,
+    quiet_hours: Vec<QuietHours>,
+}
+
+#[derive(Debug, Clone)]
+enum Timing {
+    Every(Duration),
+    Cron(CronExpression),
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self { timing: Timing::Every(DEFAULT_INTERVAL), quiet_hours: Vec::new() }
+    }
+}
+
+impl Scheduler {
+    pub fn new(settings: Option<&ScheduleSettings>) -> Result<Self, SyncError> {
+        let Some(settings) = settings else {
+            return Ok(Self::default());
+        };
+        let timing = match (&settings.cron, settings.every_secs) {
+            (Some(_), Some(_)) => {
+                return Err(SyncError::Config("A schedule has either `cron` or `every_secs`, not both".to_string()));
+            }
+            (Some(expression), None) => Timing::Cron(expression.parse()?),
+            (None, Some(0)) => return Err(SyncError::Config("`every_secs` must be at least 1".to_string())),
+            (None, Some(secs)) => Timing::Every(Duration::from_secs(secs)),
+            (None, None) => Timing::Every(DEFAULT_INTERVAL),
+        };
+        let quiet_hours = settings.quiet_hours.iter().map(|range| range.parse()).collect::<Result<_, _>>()?;
+        Ok(Self { timing, quiet_hours })
+    }
+
+    pub fn is_quiet(&self, at: NaiveDateTime) -> bool {
+        let minute = at.hour() * 60 + at.minute();
+        self.quiet_hours.iter().any(|quiet| quiet.contains(minute))
+    }
+
+    pub fn is_quiet_now(&self) -> bool {
+        self.is_quiet(chrono::Local::now().naive_local())
+    }
+
+    /// When the next periodic round after `after` is due, `None` if there
+    /// is none, e.g. quiet hours that cover the whole day.
+    pub fn next_round(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        match &self.timing {
+            Timing::Every(interval) => {
+                let due = after + ChronoDuration::from_std(*interval).ok()?;
+                self.quiet_until(due)
+            }
+            Timing::Cron(cron) => {
+                let mut at = cron.next_after(after)?;
+                while self.is_quiet(at) {
+                    at = cron.next_after(self.quiet_until(at)? - ChronoDuration::minutes(1))?;
+                }
+                Some(at)
+            }
+        }
+    }
+
+    /// How long until the next periodic round, from now.
+    pub fn next_delay(&self) -> Option<Duration> {
+        let now = chrono::Local::now().naive_local();
+        let next = self.next_round(now)?;
+        Some((next - now).to_std().unwrap_or_default())
+    }
+
+    /// `at`, or the end of the quiet hours it falls in.
+    fn quiet_until(&self, at: NaiveDateTime) -> Option<NaiveDateTime> {
+        if !self.is_quiet(at) {
+            return Some(at);
+        }
+        let mut minute = truncate_to_minute(at);
+        for _ in 0..MINUTES_PER_DAY {
+            minute += ChronoDuration::minutes(1);
+            if !self.is_quiet(minute) {
+                return Some(minute);
+            }
+        }
+        None
+    }
+}
+
+/// A daily range of minutes, from `start` up to `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct QuietHours {
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for QuietHours {
+    type Err = SyncError;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let invalid = || SyncError::Config(format!("Invalid quiet hours {:?}, expected e.g. 22:00-07:00", range));
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let minute = |time: &str| {
+            let (hour, minute) = time.trim().split_once(':')?;
+            let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+            (hour < 24 && minute < 60).then_some(hour * 60 + minute)
+        };
+        let quiet = QuietHours {
+            start: minute(start).ok_or_else(invalid)?,
+            end: minute(end).ok_or_else(invalid)?,
+        };
+        if quiet.start == quiet.end {
+            return Err(invalid());
+        }
+        Ok(quiet)
+    }
+}
+
+/// The times a cron expression names, each field as a bit set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month or of week was `*`; when both are
+    /// restricted, a day matching either is in
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronExpression {
+    fn matches_day(&self, at: NaiveDateTime) -> bool {
+        let day = bit(&self.days, at.day());
+        let weekday = bit(&self.weekdays, at.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// The first time the expression names after `after`.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut at = truncate_to_minute(after) + ChronoDuration::minutes(1);
+        let horizon = at + ChronoDuration::days(CRON_HORIZON_DAYS);
+        while at < horizon {
+            if !bit(&self.months, at.month()) || !self.matches_day(at) {
+                at = at.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !bit(&self.hours, at.hour()) {
+                at = truncate_to_minute(at) + ChronoDuration::minutes(60 - at.minute() as i64);
+            } else if !bit(&self.minutes, at.minute()) {
+                at += ChronoDuration::minutes(1);
+            } else {
+                return Some(at);
+            }
+        }
+        None
+    }
+}
+
+impl std::str::FromStr for CronExpression {
+    type Err = SyncError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(SyncError::Config(format!(
+                "Invalid cron expression {:?}, expected minute hour day month weekday", expression
+            )));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        // 7 is Sunday too
+        if bit(&weekday_bits, 7) {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+/// The values a cron field names between `min` and `max`, as bits.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, SyncError> {
+    let invalid = || SyncError::Config(format!("Invalid cron field {:?}, values are {}-{}", field, min, max));
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse::<u32>().map_err(|_| invalid())?,
+                    end.parse::<u32>().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let value = range.parse::<u32>().map_err(|_| invalid())?;
+                    // `5/15` counts up from 5
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn bit(bits: &u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn truncate_to_minute(at: NaiveDateTime) -> NaiveDateTime {
+    at.with_second(0).and_then(|at| at.with_nanosecond(0)).unwrap_or(at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        // Every 30 seconds, nothing during the day
+        let scheduler = Scheduler::new(Some(&ScheduleSettings {
+            quiet_hours: vec!["08:00-22:00".to_string()],
+            ..Default::default()
+        }))
+        .unwrap();
+        assert!(scheduler.is_quiet(at("2026-03-02 12:00")));
+        assert!(!scheduler.is_quiet(at("2026-03-02 22:00")));
+        assert_eq!(scheduler.next_round(at("2026-03-02 07:59") + ChronoDuration::seconds(45)), Some(at("2026-03-02 22:00")));
+        assert_eq!(scheduler.next_round(at("2026-03-02 23:00")), Some(at("2026-03-02 23:00") + ChronoDuration::seconds(30)));
+
+        // At 02:30 on weekdays, except while a wrapping quiet range lasts
+        let cron: CronExpression = "30 2 * * 1-5".parse().unwrap();
+        assert_eq!(cron.next_after(at("2026-03-06 03:00")), Some(at("2026-03-09 02:30")));
+        let scheduler = Scheduler::new(Some(&ScheduleSettings {
+            cron: Some("*/15 * * * *".to_string()),
+            quiet_hours: vec!["23:50-00:20".to_string()],
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(scheduler.next_round(at("2026-12-31 23:40")), Some(at("2026-12-31 23:45")));
+        assert_eq!(scheduler.next_round(at("2026-12-31 23:45")), Some(at("2027-01-01 00:30")));
+
+        // Day of month or of week, whichever matches first
+        let cron: CronExpression = "0 0 13 * 5".parse().unwrap();
+        assert_eq!(cron.next_after(at("2026-03-01 00:00")), Some(at("2026-03-06 00:00")));
+        assert_eq!(cron.next_after(at("2026-03-12 00:00")), Some(at("2026-03-13 00:00")));
+        assert_eq!("0 0 30 2 *".parse::<CronExpression>().unwrap().next_after(at("2026-01-01 00:00")), None);
+
+        assert!("61 * * * *".parse::<CronExpression>().is_err());
+        assert!("* * *".parse::<CronExpression>().is_err());
+        assert!(Scheduler::new(Some(&ScheduleSettings { quiet_hours: vec!["9-5".to_string()], ..Default::default() })).is_err());
+        let always_quiet = Scheduler::new(Some(&ScheduleSettings {
+            quiet_hours: vec!["00:00-12:00".to_string(), "12:00-00:00".to_string()],
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(always_quiet.next_round(at("2026-03-02 12:00")), None);
+    }
+}
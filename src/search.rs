@@ -0,0 +1,70 @@
+#![allow(dead_code)]
+
+//! Full-text search over notes, including ones only on the server. The
+//! server keeps the distinct words of every text file; clients download
+//! those word lists, never the content, into a local FTS table so
+//! `syncmd search` works offline.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Words shorter than this aren't worth indexing
+const MIN_TERM_LENGTH: usize = 2;
+
+/// The searchable words of one file version.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchEntry {
+    pub path: PathBuf,
+    pub hash: String,
+    pub terms: Vec<String>,
+}
+
+impl SearchEntry {
+    /// `None` for files that aren't text.
+    pub fn from_content(path: PathBuf, hash: String, content: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(content).ok()?;
+        Some(Self { path, hash, terms: terms(text) })
+    }
+}
+
+/// Distinct lowercase words in `text`, sorted.
+pub fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_TERM_LENGTH)
+        .map(str::to_lowercase)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Turns what the user typed into an FTS5 query matching files containing
+/// every word, the last one as a prefix. `None` if there's nothing to search.
+pub fn fts_query(query: &str) -> Option<String> {
+    let words: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word.to_lowercase()))
+        .collect();
+    let (last, rest) = words.split_last()?;
+    let mut parts = rest.to_vec();
+    parts.push(format!("{}*", last));
+    Some(parts.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terms_and_query() {
+        assert_eq!(terms("# Meeting notes\n\nMeeting with Zoë, re: Q3 - a plan."), vec![
+            "meeting", "notes", "plan", "q3", "re", "with", "zoë",
+        ]);
+        assert!(SearchEntry::from_content(PathBuf::from("a.bin"), String::new(), &[0xff, 0xfe]).is_none());
+
+        assert_eq!(fts_query("Meeting pla").as_deref(), Some("\"meeting\" \"pla\"*"));
+        assert_eq!(fts_query("\"OR\" -").as_deref(), Some("\"or\"*"));
+        assert_eq!(fts_query("  -- "), None);
+    }
+}
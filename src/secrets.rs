@@ -0,0 +1,163 @@
+#![allow(dead_code)]
+
+use crate::types::SyncError;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::Hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Environment variable consulted before prompting for the config passphrase.
+pub const PASSPHRASE_ENV: &str = "SYNCMD_PASSPHRASE";
+
+const KDF_NAME: &str = "pbkdf2-sha256";
+const KDF_ITERATIONS: u32 = 200_000;
+/// Blobs asking for fewer iterations than this would make the passphrase
+/// cheap to guess, more than the ceiling would hang the process deriving
+const MIN_KDF_ITERATIONS: u32 = 100_000;
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Secret values that are stored encrypted when config encryption is enabled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Secrets {
+    pub auth_token: Option<String>,
+}
+
+/// The on-disk form of [`Secrets`]: AES-256-GCM with a passphrase-derived key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedSecrets {
+    pub kdf: String,
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// A key derived from the user's passphrase, kept in memory so the config can
+/// be re-encrypted on save without asking again.
+#[derive(Clone)]
+pub struct SecretsKey {
+    key: [u8; 32],
+    salt: Vec<u8>,
+    iterations: u32,
+}
+
+impl std::fmt::Debug for SecretsKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretsKey").field("iterations", &self.iterations).finish_non_exhaustive()
+    }
+}
+
+impl SecretsKey {
+    /// Derives a key with a fresh random salt.
+    pub fn generate(passphrase: &str) -> Self {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self::derive(passphrase, salt, KDF_ITERATIONS)
+    }
+
+    pub fn derive(passphrase: &str, salt: Vec<u8>, iterations: u32) -> Self {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), &salt, iterations, &mut key);
+        Self { key, salt, iterations }
+    }
+
+    /// Derives the key matching an existing encrypted blob.
+    pub fn for_blob(passphrase: &str, blob: &EncryptedSecrets) -> Result<Self, SyncError> {
+        if blob.kdf != KDF_NAME {
+            return Err(SyncError::Secrets(format!("Unsupported key derivation: {}", blob.kdf)));
+        }
+        if !(MIN_KDF_ITERATIONS..=MAX_KDF_ITERATIONS).contains(&blob.iterations) {
+            return Err(SyncError::Secrets(format!(
+                "Encrypted secrets ask for {} key derivation iterations, expected {} to {}",
+                blob.iterations, MIN_KDF_ITERATIONS, MAX_KDF_ITERATIONS
+            )));
+        }
+        Ok(Self::derive(passphrase, decode(&blob.salt)?, blob.iterations))
+    }
+}
+
+pub fn encrypt(secrets: &Secrets, key: &SecretsKey) -> Result<EncryptedSecrets, SyncError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let plaintext = serde_json::to_vec(secrets)?;
+    let cipher = Aes256Gcm::new(Key::from_slice(&key.key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+        .map_err(|_| SyncError::Secrets("Encryption failed".to_string()))?;
+
+    Ok(EncryptedSecrets {
+        kdf: KDF_NAME.to_string(),
+        iterations: key.iterations,
+        salt: BASE64.encode(&key.salt),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+pub fn decrypt(blob: &EncryptedSecrets, key: &SecretsKey) -> Result<Secrets, SyncError> {
+    let nonce = decode(&blob.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(SyncError::Secrets("Invalid nonce length".to_string()));
+    }
+
+    let cipher = Aes256Gcm::new(Key::from_slice(&key.key));
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), decode(&blob.ciphertext)?.as_ref())
+        .map_err(|_| SyncError::Secrets("Wrong passphrase or corrupted secrets".to_string()))?;
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+pub fn passphrase_from_env() -> Option<String> {
+    std::env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty())
+}
+
+/// Reads the passphrase from the environment, or interactively from the
+/// terminal without echoing it.
+pub fn read_passphrase(prompt: &str) -> Result<String, SyncError> {
+    if let Some(passphrase) = passphrase_from_env() {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password(prompt)?;
+    if passphrase.is_empty() {
+        return Err(SyncError::Secrets("Empty passphrase".to_string()));
+    }
+    Ok(passphrase)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, SyncError> {
+    BASE64
+        .decode(value)
+        .map_err(|e| SyncError::Secrets(format!("Invalid base64 in encrypted secrets: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_wrong_passphrase() {
+        let secrets = Secrets { auth_token: Some("syncmd_token".to_string()) };
+        // As few iterations as a blob may have, derived three times
+        let key = SecretsKey::derive("correct horse", vec![7; SALT_LEN], MIN_KDF_ITERATIONS);
+        let blob = encrypt(&secrets, &key).unwrap();
+        assert!(!blob.ciphertext.contains("syncmd_token"));
+
+        let key = SecretsKey::for_blob("correct horse", &blob).unwrap();
+        assert_eq!(decrypt(&blob, &key).unwrap().auth_token.as_deref(), Some("syncmd_token"));
+        let wrong = SecretsKey::for_blob("battery staple", &blob).unwrap();
+        assert!(matches!(decrypt(&blob, &wrong), Err(SyncError::Secrets(_))));
+
+        // Iterations come from the file and are only taken within bounds
+        for iterations in [0, 1, MIN_KDF_ITERATIONS - 1, MAX_KDF_ITERATIONS + 1, u32::MAX] {
+            let tampered = EncryptedSecrets { iterations, ..blob.clone() };
+            assert!(matches!(SecretsKey::for_blob("correct horse", &tampered), Err(SyncError::Secrets(_))));
+        }
+    }
+}
@@ -0,0 +1,203 @@
+#![allow(dead_code)]
+
+//! Experimental section-wise merging of markdown. Files matching a root's
+//! `split_sections` patterns are split at top-level (`# `) headings before a
+//! merge, and each section is merged with its counterpart on the other side
+//! on its own, so edits to different sections never conflict, however close
+//! they are in the file. Sections are matched by heading, a repeated heading
+//! by how often it occurred before.
+
+use crate::text_diff::{self, Merge};
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::Path;
+
+/// Which files are merged section by section.
+#[derive(Debug, Clone, Default)]
+pub struct SectionSplit {
+    matchers: Vec<GlobMatcher>,
+}
+
+impl SectionSplit {
+    pub fn new(patterns: &[String]) -> Self {
+        let matchers = patterns
+            .iter()
+            .filter_map(|pattern| match GlobBuilder::new(pattern).literal_separator(true).build() {
+                Ok(glob) => Some(glob.compile_matcher()),
+                Err(e) => {
+                    eprintln!("Skipping section split pattern {}: {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+        Self { matchers }
+    }
+
+    pub fn applies_to(&self, relative_path: &Path) -> bool {
+        self.matchers.iter().any(|matcher| matcher.is_match(relative_path))
+    }
+}
+
+/// The heading that starts a section and how many sections before it had
+/// the same heading. The text before the first heading has an empty one.
+type SectionKey<'a> = (&'a str, usize);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Section<'a> {
+    key: SectionKey<'a>,
+    /// The heading line and everything up to the next one
+    text: &'a str,
+}
+
+/// Splits `text` at top-level headings outside fenced code blocks. The
+/// sections put together are `text` again.
+fn split(text: &str) -> Vec<Section<'_>> {
+    let mut starts = vec![0];
+    let mut headings = vec![""];
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        let marker = ["```", "~~~"].into_iter().find(|marker| trimmed.trim_start().starts_with(marker));
+        match (fence, marker) {
+            (Some(open), Some(marker)) if open == marker => fence = None,
+            (None, Some(marker)) => fence = Some(marker),
+            (None, None) if trimmed.starts_with("# ") || trimmed == "#" => {
+                starts.push(offset);
+                headings.push(trimmed);
+            }
+            _ => {}
+        }
+        offset += line.len();
+    }
+    starts.push(text.len());
+
+    let mut sections = Vec::new();
+    for (i, heading) in headings.into_iter().enumerate() {
+        let section = &text[starts[i]..starts[i + 1]];
+        if i == 0 && section.is_empty() {
+            continue;
+        }
+        let occurrence = sections.iter().filter(|s: &&Section| s.key.0 == heading).count();
+        sections.push(Section { key: (heading, occurrence), text: section });
+    }
+    sections
+}
+
+fn find<'a>(sections: &[Section<'a>], key: &SectionKey) -> Option<&'a str> {
+    sections.iter().find(|section| section.key == *key).map(|section| section.text)
+}
+
+/// Whether `sections` keep the sections they share with `base` in the
+/// order `base` has them.
+fn keeps_order(sections: &[Section], base: &[Section]) -> bool {
+    let shared: Vec<&SectionKey> = sections.iter()
+        .map(|section| &section.key)
+        .filter(|key| find(base, key).is_some())
+        .collect();
+    let in_base: Vec<&SectionKey> = base.iter()
+        .map(|section| &section.key)
+        .filter(|key| shared.contains(key))
+        .collect();
+    shared == in_base
+}
+
+/// Merges `local` and `remote` section by section, each with the line-based
+/// diff3 of `text_diff::merge3`. Sections follow the local order, or the
+/// remote one if only the remote side moved sections around; a section only
+/// one side has goes after the one it follows there. An edit to a section
+/// wins over its deletion on the other side.
+pub fn merge3(base: &str, local: &str, remote: &str) -> Merge {
+    let base_sections = split(base);
+    let local_sections = split(local);
+    let remote_sections = split(remote);
+    let remote_first = keeps_order(&local_sections, &base_sections) && !keeps_order(&remote_sections, &base_sections);
+    let (first, second) = if remote_first {
+        (&remote_sections, &local_sections)
+    } else {
+        (&local_sections, &remote_sections)
+    };
+    // Merged in the local/remote orientation whichever order is followed
+    let merge = |key: &SectionKey, local: &str, remote: &str| {
+        text_diff::merge3(find(&base_sections, key).unwrap_or_default(), local, remote)
+    };
+
+    let mut merged: Vec<(SectionKey, String)> = Vec::new();
+    let mut conflicts = 0;
+    for section in first {
+        let text = match (find(second, &section.key), remote_first) {
+            (Some(other), false) => merge(&section.key, section.text, other),
+            (Some(other), true) => merge(&section.key, other, section.text),
+            // Deleted on the other side and not changed on this one
+            (None, _) if find(&base_sections, &section.key) == Some(section.text) => continue,
+            (None, _) => Merge { text: section.text.to_string(), conflicts: 0 },
+        };
+        conflicts += text.conflicts;
+        merged.push((section.key, text.text));
+    }
+    for (i, section) in second.iter().enumerate() {
+        let base = find(&base_sections, &section.key);
+        if find(first, &section.key).is_some() || base == Some(section.text) {
+            continue;
+        }
+        let position = second[..i].iter().rev()
+            .find_map(|previous| merged.iter().position(|(key, _)| *key == previous.key))
+            .map_or(0, |position| position + 1);
+        merged.insert(position, (section.key, section.text.to_string()));
+    }
+
+    let mut text = String::new();
+    for (_, section) in &merged {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(section);
+    }
+    Merge { text, conflicts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_keeps_text() {
+        let text = "intro\n# A\n```\n# not a heading\n```\n## A.1\n# B\n# A\nlast";
+        let sections = split(text);
+        let keys: Vec<SectionKey> = sections.iter().map(|s| s.key).collect();
+        assert_eq!(keys, [("", 0), ("# A", 0), ("# B", 0), ("# A", 1)]);
+        assert_eq!(sections.iter().map(|s| s.text).collect::<String>(), text);
+    }
+
+    #[test]
+    fn test_merge_by_section() {
+        let base = "# Monday\nstandup\n\n# Tuesday\nreview\n";
+        // Both sides add a day at the end, which diff3 can't tell apart
+        let local = "# Monday\nstandup, notes\n\n# Tuesday\nreview\n\n# Wednesday\nplanning\n";
+        let remote = "# Monday\nstandup\n\n# Tuesday\nreview\n\n# Thursday\nretro\n";
+        assert_eq!(text_diff::merge3(base, local, remote).conflicts, 1);
+        let merged = merge3(base, local, remote);
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(
+            merged.text,
+            "# Monday\nstandup, notes\n\n# Tuesday\nreview\n\n# Thursday\nretro\n# Wednesday\nplanning\n"
+        );
+        // The same inputs always reassemble the same way
+        assert_eq!(merge3(base, local, remote).text, merged.text);
+
+        // A section deleted on one side goes, unless the other side edited it
+        let local = "# Tuesday\nreview\n";
+        assert_eq!(merge3(base, local, base).text, "# Tuesday\nreview\n");
+        let remote = "# Monday\nstandup, moved\n\n# Tuesday\nreview\n";
+        assert_eq!(merge3(base, local, remote).text, "# Monday\nstandup, moved\n\n# Tuesday\nreview\n");
+
+        // Moving sections on one side is kept
+        let remote = "# Tuesday\nreview\n# Monday\nstandup\n\n";
+        let local = "# Monday\nstandup\n\n# Tuesday\nreview, done\n";
+        assert_eq!(merge3(base, local, remote).text, "# Tuesday\nreview, done\n# Monday\nstandup\n\n");
+
+        // Different edits to one section still conflict
+        let local = base.replace("review", "review A");
+        let remote = base.replace("review", "review B");
+        assert_eq!(merge3(base, &local, &remote).conflicts, 1);
+    }
+}
@@ -1,8 +1,13 @@
 #![allow(dead_code)]
 
 use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+const TOKENS_FILE_NAME: &str = "server-tokens.json";
+/// How long tokens a server issues to devices stay valid
+pub const ISSUED_TOKEN_LIFETIME: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthToken {
     pub token: String,
@@ -13,6 +18,132 @@ pub struct AuthToken {
     pub last_used: chrono::DateTime<chrono::Utc>,
     pub is_revoked: bool,
     pub permissions: Vec<String>,
+    /// Limits the token to these parts of the served shares; without any it
+    /// may do everything everywhere
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grants: Vec<Grant>,
+}
+
+impl AuthToken {
+    /// What the token may do in the share at `share`.
+    pub fn scope(&self, share: &Path) -> Scope {
+        if self.grants.is_empty() {
+            return Scope::default();
+        }
+        let grants = self.grants.iter()
+            .filter(|grant| grant.share.as_deref().is_none_or(|granted| granted == share))
+            .cloned()
+            .collect();
+        Scope { grants: Some(grants) }
+    }
+}
+
+/// Something a client may be allowed to do with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Delete,
+}
+
+/// Access to the files under a path in a share, written `prefix=rwd` on the
+/// command line with the letters of what is allowed, e.g. `shared=r`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    /// The share it applies to, any share for `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share: Option<PathBuf>,
+    /// Relative to the share, the whole share when empty
+    pub prefix: PathBuf,
+    pub read: bool,
+    pub write: bool,
+    /// Removing directories. Servers take no file deletions from devices, a
+    /// file a device dropped is sent back to it, so files only ever change
+    /// through uploads, which need `write`
+    pub delete: bool,
+}
+
+impl Grant {
+    fn permits(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Delete => self.delete,
+        }
+    }
+}
+
+impl std::str::FromStr for Grant {
+    type Err = String;
+
+    fn from_str(grant: &str) -> Result<Self, Self::Err> {
+        let (prefix, letters) = grant.rsplit_once('=')
+            .ok_or_else(|| format!("{} is not PREFIX=ACCESS, e.g. shared=rw", grant))?;
+        let mut parsed = Grant {
+            share: None,
+            prefix: PathBuf::from(prefix.trim_matches('/')),
+            read: false,
+            write: false,
+            delete: false,
+        };
+        for letter in letters.chars() {
+            match letter {
+                'r' => parsed.read = true,
+                'w' => parsed.write = true,
+                'd' => parsed.delete = true,
+                _ => return Err(format!("Unknown access '{}' in {}, use r, w and d", letter, grant)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl std::fmt::Display for Grant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let access: Vec<&str> = [(self.read, "read"), (self.write, "write"), (self.delete, "delete")]
+            .into_iter()
+            .filter_map(|(allowed, name)| allowed.then_some(name))
+            .collect();
+        let access = if access.is_empty() { "nothing".to_string() } else { access.join(", ") };
+        let prefix = match self.prefix.as_os_str().is_empty() {
+            true => "everything".to_string(),
+            false => format!("{}/", self.prefix.display()),
+        };
+        match &self.share {
+            Some(share) => write!(f, "{} in {}: {}", prefix, share.display(), access),
+            None => write!(f, "{}: {}", prefix, access),
+        }
+    }
+}
+
+/// What a connection may do in the share it is connected to. The grant with
+/// the longest prefix containing a path decides; paths no grant contains are
+/// off limits.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    /// `None` for a token without grants
+    grants: Option<Vec<Grant>>,
+}
+
+impl Scope {
+    /// Whether `path`, relative to the share, may be accessed so. Paths
+    /// reaching outside the share never may.
+    pub fn allows(&self, path: &Path, access: Access) -> bool {
+        if !crate::types::is_contained(path) {
+            return false;
+        }
+        let Some(grants) = &self.grants else {
+            return true;
+        };
+        grants.iter()
+            .filter(|grant| path.starts_with(&grant.prefix))
+            .max_by_key(|grant| grant.prefix.components().count())
+            .is_some_and(|grant| grant.permits(access))
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.grants.is_some()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +163,14 @@ pub struct AuthManager {
     token_lifetime: Duration,
     session_timeout: Duration,
     max_tokens_per_client: usize,
+    /// How long after expiry a token can still be exchanged for a new one
+    refresh_grace: Duration,
+}
+
+impl Default for AuthManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AuthManager {
@@ -42,6 +181,7 @@ impl AuthManager {
             token_lifetime: Duration::from_secs(24 * 60 * 60), // 24 hours
             session_timeout: Duration::from_secs(30 * 60), // 30 minutes
             max_tokens_per_client: 5,
+            refresh_grace: Duration::from_secs(7 * 24 * 60 * 60), // 7 days
         }
     }
 
@@ -52,9 +192,15 @@ impl AuthManager {
             token_lifetime,
             session_timeout,
             max_tokens_per_client,
+            refresh_grace: Duration::from_secs(7 * 24 * 60 * 60),
         }
     }
 
+    pub fn with_refresh_grace(mut self, refresh_grace: Duration) -> Self {
+        self.refresh_grace = refresh_grace;
+        self
+    }
+
     pub fn generate_token(&mut self, client_id: String, client_name: String) -> Result<String, SyncError> {
         use uuid::Uuid;
         
@@ -71,7 +217,7 @@ impl AuthManager {
             }
         }
         
-        let token = format!("syncmd_{}", Uuid::new_v4().to_string());
+        let token = format!("syncmd_{}", Uuid::new_v4());
         let now = chrono::Utc::now();
         
         let auth_token = AuthToken {
@@ -83,6 +229,7 @@ impl AuthManager {
             last_used: now,
             is_revoked: false,
             permissions: vec!["read".to_string(), "write".to_string(), "sync".to_string()],
+            grants: Vec::new(),
         };
         
         self.tokens.insert(token.clone(), auth_token);
@@ -90,33 +237,34 @@ impl AuthManager {
     }
 
     pub fn validate_token(&mut self, token: &str) -> Option<&AuthToken> {
+        self.check_token(token).ok()
+    }
+
+    /// Like `validate_token`, but says why a token was refused, so clients
+    /// can tell an expired token they may refresh from a revoked one.
+    pub fn check_token(&mut self, token: &str) -> Result<&AuthToken, SyncError> {
         let now = chrono::Utc::now();
         
-        // Clean up expired tokens first
+        // Clean up tokens past their refresh grace first
         self.cleanup_expired_tokens();
         
-        if let Some(auth_token) = self.tokens.get_mut(token) {
-            if auth_token.is_revoked {
-                return None;
-            }
-            
-            if now > auth_token.expires_at {
-                auth_token.is_revoked = true;
-                return None;
-            }
-            
-            // Update last used time
-            auth_token.last_used = now;
-            
-            // Update session activity
-            if let Some(session) = self.sessions.get_mut(&auth_token.client_id) {
-                session.last_activity = now;
-            }
-            
-            Some(auth_token)
-        } else {
-            None
+        let auth_token = self.tokens.get_mut(token).ok_or(SyncError::InvalidToken)?;
+        if auth_token.is_revoked {
+            return Err(SyncError::TokenRevoked);
         }
+        if now > auth_token.expires_at {
+            return Err(SyncError::TokenExpired);
+        }
+        
+        // Update last used time
+        auth_token.last_used = now;
+        
+        // Update session activity
+        if let Some(session) = self.sessions.get_mut(&auth_token.client_id) {
+            session.last_activity = now;
+        }
+        
+        Ok(auth_token)
     }
 
     pub fn revoke_token(&mut self, token: &str) -> bool {
@@ -150,13 +298,37 @@ impl AuthManager {
         self.tokens.values().collect()
     }
 
+    /// Replaces the tokens with the ones saved at `path`, none if nothing
+    /// was saved yet. Sessions are kept.
+    pub fn load_tokens(&mut self, path: &Path) -> Result<(), SyncError> {
+        let tokens: Vec<AuthToken> = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        self.tokens = tokens.into_iter().map(|t| (t.token.clone(), t)).collect();
+        Ok(())
+    }
+
+    /// Writes the tokens, oldest first, readable by the owner only.
+    pub fn save_tokens(&self, path: &Path) -> Result<(), SyncError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut tokens = self.list_tokens();
+        tokens.sort_by_key(|t| t.created_at);
+        write_private(path, serde_json::to_string_pretty(&tokens)?.as_bytes())
+    }
+
+    /// Drops tokens that can no longer be used or refreshed. Revoked tokens
+    /// are kept until then too, so they are reported as revoked rather than
+    /// unknown.
     pub fn cleanup_expired_tokens(&mut self) -> usize {
         let now = chrono::Utc::now();
         let initial_count = self.tokens.len();
+        let grace = chrono::Duration::from_std(self.refresh_grace).unwrap_or(chrono::Duration::zero());
         
-        self.tokens.retain(|_, token| {
-            !token.is_revoked && token.expires_at > now
-        });
+        self.tokens.retain(|_, token| token.expires_at + grace > now);
         
         initial_count - self.tokens.len()
     }
@@ -260,6 +432,16 @@ impl AuthManager {
         }
     }
 
+    /// Limits the token to `grants`, lifting any limit for none.
+    pub fn set_grants(&mut self, token: &str, grants: Vec<Grant>) -> bool {
+        if let Some(auth_token) = self.tokens.get_mut(token) {
+            auth_token.grants = grants;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn get_token_info(&self, token: &str) -> Option<&AuthToken> {
         self.tokens.get(token)
     }
@@ -273,33 +455,150 @@ impl AuthManager {
         }
     }
 
+    /// Exchanges a valid token, or one that expired less than the refresh
+    /// grace ago, for a new one. The old token is revoked.
     pub fn refresh_token(&mut self, token: &str) -> Result<String, SyncError> {
-        if let Some(auth_token) = self.tokens.get(token) {
-            if auth_token.is_revoked {
-                return Err(SyncError::Network("Token is revoked".to_string()));
-            }
-            
-            let now = chrono::Utc::now();
-            if now > auth_token.expires_at {
-                return Err(SyncError::Network("Token is expired".to_string()));
-            }
-            
-            // Generate new token with same client info
-            self.generate_token(auth_token.client_id.clone(), auth_token.client_name.clone())
-        } else {
-            Err(SyncError::Network("Token not found".to_string()))
+        self.cleanup_expired_tokens();
+        let auth_token = self.tokens.get(token).ok_or(SyncError::InvalidToken)?;
+        if auth_token.is_revoked {
+            return Err(SyncError::TokenRevoked);
         }
+        
+        // Generate new token with same client info and grants
+        let (client_id, client_name) = (auth_token.client_id.clone(), auth_token.client_name.clone());
+        let grants = auth_token.grants.clone();
+        self.revoke_token(token);
+        let renewed = self.generate_token(client_id, client_name)?;
+        self.set_grants(&renewed, grants);
+        Ok(renewed)
     }
 }
 
 pub fn generate_client_id() -> String {
     use uuid::Uuid;
-    format!("client_{}", Uuid::new_v4().to_string())
+    format!("client_{}", Uuid::new_v4())
 }
 
 pub fn generate_secure_random_token() -> String {
     use uuid::Uuid;
-    format!("syncmd_{}", Uuid::new_v4().to_string())
+    format!("syncmd_{}", Uuid::new_v4())
 }
 
-use crate::types::SyncError;
\ No newline at end of file
+/// Replaces `path` with `content`, readable by the owner only. It's written
+/// beside it, synced and renamed over it, so a crash leaves the old version
+/// or the new one and never a truncated file.
+pub fn write_private(path: &Path, content: &[u8]) -> Result<(), SyncError> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)?;
+    let mut temp = tempfile::Builder::new().prefix(".syncmd-").suffix(".tmp").tempfile_in(parent)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        temp.as_file().set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::io::Write::write_all(&mut temp, content)?;
+    temp.as_file().sync_all()?;
+    temp.persist(path).map_err(|e| e.error)?;
+    #[cfg(unix)]
+    std::fs::File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+/// Where a server keeps the tokens it issued, in the config directory.
+pub fn tokens_path() -> Result<PathBuf, SyncError> {
+    let config_dir = dirs::config_dir()
+        .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))?;
+    Ok(config_dir.join("syncmd").join(TOKENS_FILE_NAME))
+}
+
+use crate::types::SyncError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refresh_expired_token() {
+        let mut auth = AuthManager::with_config(Duration::ZERO, Duration::from_secs(60), 5);
+        let token = auth.generate_token("client".to_string(), "laptop".to_string()).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(matches!(auth.check_token(&token), Err(SyncError::TokenExpired)));
+
+        let mut refreshed_auth = AuthManager::new();
+        let fresh = refreshed_auth.generate_token("client".to_string(), "laptop".to_string()).unwrap();
+        let renewed = refreshed_auth.refresh_token(&fresh).unwrap();
+        assert!(refreshed_auth.check_token(&renewed).is_ok());
+        assert!(matches!(refreshed_auth.check_token(&fresh), Err(SyncError::TokenRevoked)));
+
+        // Within the grace period an expired token can still be refreshed,
+        // past it the token is forgotten
+        assert!(auth.refresh_token(&token).is_ok());
+        let mut strict = AuthManager::with_config(Duration::ZERO, Duration::from_secs(60), 5)
+            .with_refresh_grace(Duration::ZERO);
+        let token = strict.generate_token("client".to_string(), "laptop".to_string()).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(matches!(strict.refresh_token(&token), Err(SyncError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_saved_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        let mut issuer = AuthManager::new();
+        let kept = issuer.generate_token("a".to_string(), "laptop".to_string()).unwrap();
+        let revoked = issuer.generate_token("b".to_string(), "phone".to_string()).unwrap();
+        issuer.revoke_token(&revoked);
+        issuer.save_tokens(&path).unwrap();
+
+        let mut server = AuthManager::new();
+        assert!(matches!(server.check_token(&kept), Err(SyncError::InvalidToken)));
+        server.load_tokens(&path).unwrap();
+        assert_eq!(server.check_token(&kept).unwrap().client_name, "laptop");
+        assert!(matches!(server.check_token(&revoked), Err(SyncError::TokenRevoked)));
+        server.load_tokens(&dir.path().join("missing.json")).unwrap();
+        assert!(server.list_tokens().is_empty());
+
+        // Saved again over the old file, private and with nothing left beside it
+        issuer.save_tokens(&path).unwrap();
+        let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["tokens.json"]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_scoped_grants() {
+        let mut auth = AuthManager::new();
+        let token = auth.generate_token("c".to_string(), "partner".to_string()).unwrap();
+        let share = Path::new("/srv/notes");
+        assert!(!auth.get_token_info(&token).unwrap().scope(share).is_restricted());
+
+        let mut elsewhere: Grant = "=rwd".parse().unwrap();
+        elsewhere.share = Some(PathBuf::from("/srv/work"));
+        let grants = vec!["shared=r".parse().unwrap(), "shared/inbox/=rw".parse().unwrap(), elsewhere];
+        auth.set_grants(&token, grants);
+        let token = auth.refresh_token(&token).unwrap();
+        let scope = auth.get_token_info(&token).unwrap().scope(share);
+        assert!(scope.allows(Path::new("shared/a.md"), Access::Read));
+        assert!(!scope.allows(Path::new("shared/a.md"), Access::Write));
+        assert!(scope.allows(Path::new("shared/inbox/b.md"), Access::Write));
+        assert!(!scope.allows(Path::new("shared/inbox/b.md"), Access::Delete));
+        assert!(!scope.allows(Path::new("sharedish.md"), Access::Read));
+        assert!(!scope.allows(Path::new("private/c.md"), Access::Read));
+        assert!("shared=rx".parse::<Grant>().is_err());
+    }
+
+    #[test]
+    fn test_scope_refuses_escaping_paths() {
+        let scope = Scope { grants: Some(vec!["notes=rwd".parse().unwrap()]) };
+        assert!(scope.allows(Path::new("notes/x.md"), Access::Write));
+        assert!(!scope.allows(Path::new("notes/../x"), Access::Write));
+        assert!(!scope.allows(Path::new("notes/../../etc/passwd"), Access::Read));
+        assert!(!scope.allows(Path::new("/notes/x.md"), Access::Write));
+        assert!(!Scope::default().allows(Path::new("../x.md"), Access::Read));
+        assert!(!Scope::default().allows(Path::new(""), Access::Read));
+    }
+}
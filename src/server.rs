@@ -1,26 +1,79 @@
-mod types;
-mod indexer;
-mod sync;
-mod network;
-mod cli;
-mod security;
-
-use clap::Parser;
-use cli::{Cli, Commands, Config};
+
+use syncmd_core::{cli, index_store, indexer, maintenance, network, protocol_trace, server_config, types};
+use clap::{Parser, Subcommand};
+use cli::{Commands, Config};
+use server_config::{ServerConfig, ShareConfig};
+use index_store::IndexStore;
 use indexer::FileIndexer;
 use network::{ClientManager, NetworkManager};
 use std::sync::Arc;
 
+
+#[derive(Parser)]
+#[command(name = "syncmd-server")]
+#[command(about = "Serve a folder to syncmd clients")]
+struct ServerCli {
+    #[command(subcommand)]
+    command: ServerCommand,
+
+    /// Append every protocol message sent or received, content left out,
+    /// as a JSON line to this file; see `syncmd trace analyze`
+    #[arg(long, global = true, value_name = "FILE")]
+    trace_protocol: Option<std::path::PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum ServerCommand {
+    #[command(flatten)]
+    Common(Commands),
+
+    /// Configure how long deleted files are remembered
+    Retention {
+        #[command(subcommand)]
+        action: RetentionAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum RetentionAction {
+    /// Show the default retention and every share's effective value
+    Get {
+        /// Only this share
+        #[arg(long)]
+        share: Option<std::path::PathBuf>,
+    },
+
+    /// Set the default retention, or a share's override
+    Set {
+        days: i64,
+
+        #[arg(long)]
+        share: Option<std::path::PathBuf>,
+    },
+
+    /// Remove a share's override so it uses the default again
+    Unset {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     
-    let cli = Cli::parse();
+    let cli = ServerCli::parse();
+    if let Some(path) = &cli.trace_protocol {
+        protocol_trace::enable(path)?;
+    }
     
     match cli.command {
-        Commands::Sync { path, port, .. } => {
+        ServerCommand::Common(Commands::Sync { path, port, .. }) => {
             start_server(path, port).await?;
         }
+        ServerCommand::Retention { action } => {
+            manage_retention(action)?;
+        }
         _ => {
             println!("Server mode only supports sync command");
         }
@@ -42,11 +95,42 @@ async fn start_server(
     println!("Sync path: {:?}", path);
     println!("Port: {}", port);
     
-    let indexer = FileIndexer::new(client_manager.server_id().to_string(), path.clone());
+    let indexer = FileIndexer::new(client_manager.server_id().to_string(), path.clone())
+        .with_file_types(config.find_sync_root(&path).map(|root| root.file_types()).unwrap_or_default());
     
     // Initial indexing
     let sync_state = indexer.index_directory()?;
     println!("Indexed {} files", sync_state.local_files.len());
+    IndexStore::open(&path)?.save_state(&sync_state)?;
+    
+    let retention_days = ServerConfig::load()?.deleted_retention_days(&path);
+    println!("Deleted files are kept for {} days", retention_days);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(maintenance::GC_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = collect_deleted(&indexer) {
+                eprintln!("Retention GC error: {}", e);
+            }
+        }
+    });
+    
+    tokio::spawn({
+        let client_manager = client_manager.clone();
+        let roots = vec![path.clone()];
+        async move {
+            let mut interval = tokio::time::interval(maintenance::CLEANUP_INTERVAL);
+            let mut totals = maintenance::CleanupStats::default();
+            loop {
+                interval.tick().await;
+                let run = maintenance::run(&client_manager, &roots).await;
+                totals.record(&run);
+                if run.removed() > 0 {
+                    println!("Cleanup removed {} ({} since start)", run, totals);
+                }
+            }
+        }
+    });
     
     let network_manager = NetworkManager::new(
         client_manager.clone(),
@@ -57,4 +141,71 @@ async fn start_server(
     network_manager.start_server().await?;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Records deletions since the last pass, then drops tombstones and deleted
+/// versions past the retention window.
+fn collect_deleted(indexer: &FileIndexer) -> Result<(), types::SyncError> {
+    let mut store = IndexStore::open(indexer.sync_root())?;
+    store.save_state(&indexer.index_directory()?)?;
+    let report = maintenance::purge_deleted(&mut store, indexer.sync_root())?;
+    if report.tombstones_removed > 0 || report.versions_removed > 0 {
+        println!("Retention GC removed {} tombstones and {} deleted versions",
+            report.tombstones_removed, report.versions_removed);
+    }
+    Ok(())
+}
+
+fn manage_retention(action: RetentionAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = ServerConfig::load()?;
+
+    match action {
+        RetentionAction::Get { share } => {
+            let default = config.deleted_retention_days
+                .unwrap_or(index_store::DEFAULT_JOURNAL_RETENTION_DAYS);
+            let roots: Vec<&ShareConfig> = match &share {
+                Some(share) => vec![config.share(share)
+                    .ok_or_else(|| format!("{} is not a configured share", share.display()))?],
+                None => {
+                    println!("Default: {} days", default);
+                    config.shares.iter().collect()
+                }
+            };
+            for root in roots {
+                match root.deleted_retention_days {
+                    Some(days) => println!("{}: {} days", root.path.display(), days),
+                    None => println!("{}: {} days (default)", root.path.display(), default),
+                }
+            }
+        }
+        RetentionAction::Set { days, share } => {
+            if days < 0 {
+                return Err("Retention can't be negative".into());
+            }
+            match share {
+                Some(share) => {
+                    let root = config.share_or_insert(&share.canonicalize()?);
+                    root.deleted_retention_days = Some(days);
+                    println!("{} keeps deleted files for {} days", root.path.display(), days);
+                }
+                None => {
+                    config.deleted_retention_days = Some(days);
+                    println!("Deleted files are kept for {} days by default", days);
+                }
+            }
+            config.save()?;
+        }
+        RetentionAction::Unset { share } => {
+            let root = find_share(&mut config, &share)?;
+            root.deleted_retention_days = None;
+            println!("{} uses the default retention", root.path.display());
+            config.save()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn find_share<'a>(config: &'a mut ServerConfig, share: &std::path::Path) -> Result<&'a mut ShareConfig, Box<dyn std::error::Error>> {
+    Ok(config.share_mut(share).ok_or_else(|| format!("{} is not a configured share", share.display()))?)
+}
@@ -0,0 +1,177 @@
+//! Settings only `syncmd-server` and `syncmd-vps` read, kept in
+//! `server.json` next to the tokens rather than in a device's config: how
+//! long deleted files are kept, when addresses are locked out, and per share
+//! how its files are stored, scanned and served.
+
+use crate::types::SyncError;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The server config's name in the `syncmd` config directory
+pub const SERVER_CONFIG_FILE_NAME: &str = "server.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// Days tombstones and deleted versions are kept, unless a share
+    /// overrides it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_retention_days: Option<i64>,
+    /// Failed authentications from one address before it is locked out,
+    /// see `lockout.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_max_attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shares: Vec<ShareConfig>,
+}
+
+/// A folder a server serves to devices.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShareConfig {
+    pub path: PathBuf,
+    /// Overrides the server's `deleted_retention_days` for this share
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_retention_days: Option<i64>,
+    /// Seal stored files with keys the server keeps, see `at_rest.rs`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt_at_rest: bool,
+    /// Scanner run on every upload before it's stored, e.g.
+    /// `clamdscan --no-summary`, see `scan.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scan_cmd: Option<String>,
+    /// Record which device read which file when, queried with
+    /// `syncmd-vps access-log show`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub access_log: bool,
+    /// HTTP port share links are served on, see `share_links.rs`; devices
+    /// can't get links while unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share_links_port: Option<u16>,
+    /// HTTP port a read-only web file browser of the share is served on,
+    /// for devices' tokens, see `browser.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browser_port: Option<u16>,
+    /// Where the files are stored, the share's folder if unset, see
+    /// `storage.rs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageConfig>,
+}
+
+/// Where a VPS server stores a share's files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum StorageConfig {
+    /// The share's folder on the server's disk
+    Filesystem,
+    /// A bucket of an S3-compatible object store, with the keys in
+    /// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+    S3 {
+        /// e.g. `https://s3.eu-central-1.amazonaws.com` or a MinIO server
+        endpoint: String,
+        bucket: String,
+        /// `us-east-1` if unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<String>,
+        /// Key prefix the share's files are stored under
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        prefix: String,
+    },
+}
+
+impl ServerConfig {
+    /// The server's settings, the defaults before any were saved.
+    pub fn load() -> Result<Self, SyncError> {
+        Self::load_from(&Self::path()?)
+    }
+
+    pub fn save(&self) -> Result<(), SyncError> {
+        self.save_to(&Self::path()?)
+    }
+
+    fn load_from(path: &Path) -> Result<Self, SyncError> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| SyncError::Config(format!("{} is malformed: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_to(&self, path: &Path) -> Result<(), SyncError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn path() -> Result<PathBuf, SyncError> {
+        let config_dir = dirs::config_dir()
+            .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))?;
+        Ok(config_dir.join("syncmd").join(SERVER_CONFIG_FILE_NAME))
+    }
+
+    /// Looks up a share, treating relative and canonical forms of the same
+    /// directory as equal.
+    pub fn share(&self, path: &Path) -> Option<&ShareConfig> {
+        let index = self.share_index(path)?;
+        self.shares.get(index)
+    }
+
+    pub fn share_mut(&mut self, path: &Path) -> Option<&mut ShareConfig> {
+        let index = self.share_index(path)?;
+        self.shares.get_mut(index)
+    }
+
+    /// The share at `path`, added with default settings if it isn't one yet.
+    pub fn share_or_insert(&mut self, path: &Path) -> &mut ShareConfig {
+        let index = self.share_index(path).unwrap_or_else(|| {
+            self.shares.push(ShareConfig { path: path.to_path_buf(), ..Default::default() });
+            self.shares.len() - 1
+        });
+        &mut self.shares[index]
+    }
+
+    fn share_index(&self, path: &Path) -> Option<usize> {
+        let wanted = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.shares.iter().position(|share| {
+            share.path == wanted || share.path.canonicalize().map(|p| p == wanted).unwrap_or(false)
+        })
+    }
+
+    /// Days the server keeps tombstones and deleted versions for a share.
+    pub fn deleted_retention_days(&self, path: &Path) -> i64 {
+        self.share(path)
+            .and_then(|share| share.deleted_retention_days)
+            .or(self.deleted_retention_days)
+            .unwrap_or(crate::index_store::DEFAULT_JOURNAL_RETENTION_DAYS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shares_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SERVER_CONFIG_FILE_NAME);
+        let share = dir.path().join("notes");
+        std::fs::create_dir(&share).unwrap();
+
+        let mut config = ServerConfig::load_from(&path).unwrap();
+        assert!(config.share(&share).is_none());
+        config.deleted_retention_days = Some(7);
+        config.share_or_insert(&share.canonicalize().unwrap()).access_log = true;
+        config.share_or_insert(&share).share_links_port = Some(8443);
+        config.save_to(&path).unwrap();
+
+        let config = ServerConfig::load_from(&path).unwrap();
+        assert_eq!(config.shares.len(), 1);
+        let loaded = config.share(&dir.path().join("notes/../notes")).unwrap();
+        assert!(loaded.access_log && loaded.share_links_port == Some(8443));
+        assert_eq!(config.deleted_retention_days(&share), 7);
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(matches!(ServerConfig::load_from(&path), Err(SyncError::Config(_))));
+    }
+}
@@ -0,0 +1,552 @@
+#![allow(dead_code)]
+
+//! [`SyncSession`], folder sync in a few calls for applications embedding
+//! syncmd: open a session for a folder, connect it to a server, peer or
+//! address the way `syncmd sync --connect` does, and run rounds whenever
+//! the application likes, e.g. after its own editor saved a note.
+//!
+//! A round sends the folder's index, applies the server's operations and
+//! downloads what they need, all-or-nothing like the CLI's rounds. Large
+//! downloads report progress through [`TransferQueue::global`].
+//! [`SyncSession::run`] keeps a folder in sync by running a round whenever a
+//! [`ChangeSource`] reports changes, a [`FileWatcher`](crate::watcher::FileWatcher)
+//! on the folder or the application's own file events.
+
+use crate::capabilities::{self, Capability};
+use crate::cli::Config;
+use crate::encryption::FolderKey;
+use crate::file_transfer::TransferQueue;
+use crate::index_store::IndexStore;
+use crate::indexer::FileIndexer;
+use crate::network::{self, ClientManager, NetworkManager, NetworkMessage};
+use crate::sync::{Resolution, SyncEngine};
+use crate::types::{FileMetadata, InlineContent, SyncError, SyncOperation, SyncState};
+use crate::watcher::ChangeSource;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// What a round changed in the folder.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Files written, from the response or downloaded
+    pub written: Vec<PathBuf>,
+    /// Files moved to the trash
+    pub deleted: Vec<PathBuf>,
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    /// Directories created, empty ones among them
+    pub created_directories: Vec<PathBuf>,
+    /// Directories removed because another device removed them
+    pub removed_directories: Vec<PathBuf>,
+    /// Files the server no longer had, or whose content didn't verify
+    pub skipped: Vec<PathBuf>,
+    pub bytes_received: u64,
+}
+
+impl SyncReport {
+    pub fn is_empty(&self) -> bool {
+        self.written.is_empty()
+            && self.deleted.is_empty()
+            && self.renamed.is_empty()
+            && self.created_directories.is_empty()
+            && self.removed_directories.is_empty()
+    }
+}
+
+/// A folder synced with one server or peer.
+pub struct SyncSession {
+    config: Config,
+    indexer: FileIndexer,
+    engine: SyncEngine,
+    folder_key: Option<FolderKey>,
+    network: NetworkManager,
+    connection: Option<Connection>,
+    /// What `connect` was last given, for reconnecting
+    target: Option<String>,
+}
+
+struct Connection {
+    stream: network::Connection,
+    negotiated: Vec<Capability>,
+}
+
+impl SyncSession {
+    /// A session for `root` with the settings `config` has for it, or the
+    /// defaults for a folder `config` doesn't list.
+    pub fn new(config: Config, root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        let indexer = root_indexer(&config, config.device_id.clone(), &root);
+        let engine = root_engine(&config, config.device_id.clone(), &root);
+        Self {
+            config,
+            indexer,
+            engine,
+            folder_key: None,
+            network: NetworkManager::new(Arc::new(ClientManager::new()), String::new()),
+            connection: None,
+            target: None,
+        }
+    }
+
+    /// A session for `root` with this device's configuration file.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, SyncError> {
+        let config = Config::load().map_err(|e| SyncError::Config(e.to_string()))?;
+        Ok(Self::new(config, root))
+    }
+
+    /// Encrypts what leaves the device with the root's unlocked key, see
+    /// `syncmd encrypt`.
+    pub fn with_folder_key(mut self, key: FolderKey) -> Self {
+        self.folder_key = Some(key);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        self.indexer.sync_root()
+    }
+
+    pub fn indexer(&self) -> &FileIndexer {
+        &self.indexer
+    }
+
+    pub fn engine(&self) -> &SyncEngine {
+        &self.engine
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Indexes the folder and records the result, as every round does.
+    pub fn scan(&self) -> Result<SyncState, SyncError> {
+        let state = self.indexer.index_directory()?;
+        IndexStore::open(self.root())?.save_state(&state)?;
+        Ok(state)
+    }
+
+    /// Connects to a known peer, a named remote or an address, and
+    /// authenticates with the configured token, refreshing it if it expired.
+    pub async fn connect(&mut self, target: &str) -> Result<(), SyncError> {
+        self.connection = None;
+        self.target = Some(target.to_string());
+        let (mut stream, _) = self.network.connect_supervised(&self.config, target).await?;
+        let token = self.config.auth_token.clone()
+            .ok_or_else(|| SyncError::Auth("No authentication token configured".to_string()))?;
+        let name = self.config.device_name.clone();
+        let negotiated = match self.network.send_authentication(&mut stream, token.clone(), name.clone()).await {
+            Err(SyncError::TokenExpired) => {
+                let token = self.network.refresh_token(&mut stream, token).await?;
+                self.config.auth_token = Some(token.clone());
+                self.config.save().map_err(|e| SyncError::Config(e.to_string()))?;
+                self.network.send_authentication(&mut stream, token, name).await?
+            }
+            result => result?,
+        };
+        IndexStore::open(self.root())?.record_capabilities(&negotiated)?;
+        self.connection = Some(Connection { stream, negotiated });
+        Ok(())
+    }
+
+    /// Runs a round now and another whenever `changes` reports changed
+    /// files, until it has no more, handing each round's outcome to
+    /// `on_round`. Rounds index the whole folder, so the paths reported only
+    /// tell when to run one. A lost connection is reestablished to the last
+    /// target `connect` was given before the next round.
+    pub async fn run<C, F>(&mut self, mut changes: C, mut on_round: F) -> Result<(), SyncError>
+    where
+        C: ChangeSource,
+        F: FnMut(Result<SyncReport, SyncError>),
+    {
+        let target = self.target.clone()
+            .ok_or_else(|| SyncError::Network("Not connected, call connect first".to_string()))?;
+        loop {
+            let result = match self.connection {
+                Some(_) => self.sync().await,
+                None => match self.connect(&target).await {
+                    Ok(()) => self.sync().await,
+                    Err(e) => Err(e),
+                },
+            };
+            on_round(result);
+            if changes.next_changes().await.is_none() {
+                break;
+            }
+        }
+        // Changes still coalescing when the source ended
+        if !changes.take_pending().is_empty() {
+            let result = self.sync().await;
+            on_round(result);
+        }
+        Ok(())
+    }
+
+    /// Runs one round. A round that fails leaves the folder as it was; one
+    /// that lost the connection leaves the session disconnected.
+    pub async fn sync(&mut self) -> Result<SyncReport, SyncError> {
+        let connection = self.connection.as_mut()
+            .ok_or_else(|| SyncError::Network("Not connected, call connect first".to_string()))?;
+        let state = self.indexer.index_directory()?;
+        let round = async {
+            let (operations, inline) = request_operations(
+                &state,
+                &mut connection.stream,
+                self.folder_key.as_ref(),
+                &connection.negotiated,
+            )
+            .await?;
+            let operations = self.engine.incoming_operations(operations);
+            apply(&self.indexer, &self.engine, &mut connection.stream, operations, inline, self.folder_key.as_ref()).await
+        };
+        let result = round.await;
+        if matches!(result, Err(SyncError::Io(_) | SyncError::Network(_))) {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+/// Applies `operations` as one staged change, downloading what the response
+/// didn't carry.
+async fn apply(
+    indexer: &FileIndexer,
+    engine: &SyncEngine,
+    stream: &mut network::Connection,
+    operations: Vec<SyncOperation>,
+    mut inline: InlineContent,
+    folder_key: Option<&FolderKey>,
+) -> Result<SyncReport, SyncError> {
+    indexer.begin_apply()?;
+    let mut report = SyncReport::default();
+    // Removed once the files leaving them are gone
+    let mut removed_directories = Vec::new();
+    let staged = async {
+        let mut downloads = Vec::new();
+        for operation in operations {
+            match operation {
+                SyncOperation::Add(metadata) | SyncOperation::Update(metadata) => {
+                    indexer.adopt(&metadata);
+                    match inline.take(&metadata) {
+                        Some(content) => {
+                            write_incoming(indexer, engine, &metadata, &content)?;
+                            report.bytes_received += content.len() as u64;
+                            report.written.push(metadata.path);
+                        }
+                        None => downloads.push(metadata),
+                    }
+                }
+                SyncOperation::Delete(path) => {
+                    indexer.trash_file(&path)?;
+                    report.deleted.push(path);
+                }
+                SyncOperation::Rename { from, to } => {
+                    indexer.adopt(&to);
+                    // A file edited here stays, the new name is downloaded
+                    if indexer.holds(&from, &to.hash)? {
+                        indexer.rename_file(&from, &to.path)?;
+                        report.renamed.push((from, to.path));
+                    } else {
+                        downloads.push(to);
+                    }
+                }
+                SyncOperation::MkDir(path) => {
+                    indexer.create_directory(&path)?;
+                    report.created_directories.push(path);
+                }
+                SyncOperation::RmDir(path) => removed_directories.push(path),
+            }
+        }
+        download(indexer, engine, stream, downloads, folder_key, &mut report).await
+    };
+    if let Err(e) = staged.await {
+        indexer.abort_apply()?;
+        return Err(e);
+    }
+    indexer.commit_apply()?;
+    for path in removed_directories {
+        if indexer.remove_directory(&path)? {
+            report.removed_directories.push(path);
+        }
+    }
+
+    // What's on disk now is the last-synced version for `syncmd diff`
+    let synced = indexer.index_directory()?;
+    let mut store = IndexStore::open(indexer.sync_root())?;
+    store.record_bases(&synced, |path| indexer.read_file_content(path))?;
+    record_shared_directories(&mut store, &synced)?;
+    Ok(report)
+}
+
+/// Remembers the directories a root has after syncing with a server that
+/// syncs them, so the next round can tell which were created and which
+/// removed since.
+pub fn record_shared_directories(store: &mut IndexStore, synced: &SyncState) -> Result<(), SyncError> {
+    if store.capabilities()?.is_some_and(|negotiated| negotiated.contains(&Capability::Directories)) {
+        store.save_shared_directories(&synced.directories)?;
+    }
+    Ok(())
+}
+
+/// Requests every file up front and writes them as the responses come back.
+async fn download(
+    indexer: &FileIndexer,
+    engine: &SyncEngine,
+    stream: &mut network::Connection,
+    downloads: Vec<FileMetadata>,
+    folder_key: Option<&FolderKey>,
+    report: &mut SyncReport,
+) -> Result<(), SyncError> {
+    let queue = TransferQueue::global();
+    let mut pending = std::collections::HashMap::new();
+    for metadata in downloads {
+        let path = remote_path(&metadata, folder_key);
+        stream.write_all(&serde_json::to_vec(&NetworkMessage::FileRequest { path: path.clone() })?).await?;
+        let handle = queue.register(&uuid::Uuid::new_v4().to_string(), &indexer.local_path(&metadata.path), metadata.size);
+        pending.insert(path, (metadata, handle));
+    }
+    while !pending.is_empty() {
+        let message = stream.next().await?
+            .ok_or_else(|| SyncError::Network("Server closed the connection during downloads".to_string()))?;
+        let NetworkMessage::FileResponse { path, found, content, .. } = message else {
+            continue;
+        };
+        let Some((metadata, handle)) = pending.remove(&path) else {
+            continue;
+        };
+        let opened = content
+            .filter(|_| found)
+            .map(|content| open_download(&metadata, content, folder_key));
+        match opened {
+            Some(Ok(content)) => {
+                handle.set_transferred(metadata.size);
+                write_incoming(indexer, engine, &metadata, &content)?;
+                report.bytes_received += content.len() as u64;
+                report.written.push(metadata.path);
+            }
+            Some(Err(_)) | None => report.skipped.push(metadata.path),
+        }
+    }
+    Ok(())
+}
+
+/// Writes another device's version of a file. One changed here too since
+/// the last sync is resolved with the engine's conflict strategy rather
+/// than overwritten, and the resolution returned.
+pub fn write_incoming(
+    indexer: &FileIndexer,
+    engine: &SyncEngine,
+    metadata: &FileMetadata,
+    content: &[u8],
+) -> Result<Option<Resolution>, SyncError> {
+    if !indexer.local_path(&metadata.path).is_file() {
+        indexer.write_received(metadata, content)?;
+        return Ok(None);
+    }
+    let local_content = indexer.read_file_content(&metadata.path)?;
+    let base = IndexStore::open(indexer.sync_root())?.base(&metadata.path)?;
+    let local_hash = blake3::hash(&local_content).to_hex().to_string();
+    if local_content == content || base.as_ref().is_some_and(|base| base.hash == local_hash) {
+        indexer.write_received(metadata, content)?;
+        return Ok(None);
+    }
+
+    let local = indexer.file_metadata(&metadata.path)?;
+    let base = base.map(|base| base.content);
+    let resolution = engine.resolve_conflict(&metadata.path, base.as_deref(), (&local, &local_content), (metadata, content));
+    match &resolution {
+        Resolution::KeepLocal => tracing::info!("Keeping {:?}, changed here since the last sync", metadata.path),
+        Resolution::TakeRemote => indexer.write_received(metadata, content)?,
+        Resolution::Merged(merged) => indexer.write_received(metadata, merged)?,
+        Resolution::KeepBoth { copy } => {
+            tracing::info!("Keeping the local version of {:?} as {:?}", metadata.path, copy);
+            indexer.write_received(&FileMetadata { path: copy.clone(), ..local }, &local_content)?;
+            indexer.write_received(metadata, content)?;
+        }
+    }
+    Ok(Some(resolution))
+}
+
+/// Builds an indexer for a folder, applying the per-root settings from the
+/// configuration if the folder is a registered sync root.
+pub fn root_indexer(config: &Config, device_id: String, path: &Path) -> FileIndexer {
+    let indexer = FileIndexer::new(device_id, path.to_path_buf());
+    match config.find_sync_root(path) {
+        Some(root) => indexer
+            .with_filter(root.filter_command())
+            .with_transforms(root.transform_pipeline())
+            .with_categories(root.categories.clone())
+            .with_file_types(root.file_types())
+            .with_vcs_dirs(root.sync_vcs_dirs)
+            .with_gitignore(root.respect_gitignore)
+            .with_ignore_patterns(root.ignore.clone())
+            .with_selection(root.selection())
+            .with_trash_retention(crate::trash::retention(root.trash_retention_days)),
+        None => indexer,
+    }
+}
+
+/// Builds the sync engine for a folder with the root's conflict settings
+/// and selected subfolders.
+pub fn root_engine(config: &Config, device_id: String, path: &Path) -> SyncEngine {
+    let engine = SyncEngine::new(device_id);
+    match config.find_sync_root(path) {
+        Some(root) => engine
+            .with_frontmatter_policies(root.frontmatter_policies.clone())
+            .with_section_split(crate::sections::SectionSplit::new(&root.split_sections))
+            .with_selection(root.selection())
+            .with_direction(root.direction.unwrap_or_default())
+            .with_conflict_strategy(
+                root.conflict_strategy.unwrap_or_default(),
+                &crate::types::device_slug(&config.device_name),
+                root.conflict_layout.unwrap_or_default(),
+            ),
+        None => engine,
+    }
+}
+
+/// Sends the local index and returns the operations the server wants applied,
+/// along with the content of any small files the server inlined. Servers
+/// with Merkle sync are asked for their tree first, and only the parts of
+/// the index that differ are sent, or nothing at all when both match.
+pub async fn request_operations(
+    sync_state: &SyncState,
+    stream: &mut network::Connection,
+    folder_key: Option<&FolderKey>,
+    negotiated: &[Capability],
+) -> Result<(Vec<SyncOperation>, InlineContent), SyncError> {
+    let mut files: Vec<FileMetadata> = sync_state.local_files.values()
+        .map(|metadata| match folder_key {
+            Some(key) => key.encrypt_metadata(metadata),
+            None => metadata.clone(),
+        })
+        .collect();
+
+    let directories = match negotiated.contains(&Capability::Directories) {
+        true => {
+            let known = IndexStore::open(&sync_state.sync_root)?.shared_directories()?;
+            let encrypt = |paths: &std::collections::BTreeSet<PathBuf>| match folder_key {
+                Some(key) => paths.iter().map(|path| key.encrypt_path(path)).collect(),
+                None => paths.clone(),
+            };
+            Some(network::DirectoryListing { present: encrypt(&sync_state.directories), known: encrypt(&known) })
+        }
+        false => None,
+    };
+
+    let mut scope = None;
+    if negotiated.contains(&capabilities::Capability::MerkleSync) {
+        let differing = network::differing_paths(stream, &crate::merkle::MerkleTree::build(&files)).await?;
+        // The tree only has files, so directories are synced regardless
+        if differing.is_empty() && directories.is_none() {
+            tracing::debug!("Tree matches the server's, nothing to sync");
+            return Ok((Vec::new(), InlineContent::default()));
+        }
+        files.retain(|file| differing.iter().any(|path| file.path.starts_with(path)));
+        tracing::debug!("{} path(s) differ from the server, sending {} file(s)", differing.len(), files.len());
+        scope = Some(differing);
+    }
+
+    // Send sync request, in pages for large roots
+    let encoding = network::Encoding::negotiated(negotiated);
+    network::send_sync_request(stream, &sync_state.device_id, &files, scope, directories, encoding).await?;
+
+    // Read response
+    if let NetworkMessage::SyncResponse { operations, inline } = stream.read_message().await? {
+        println!("Received {} sync operations ({} inline)", operations.len(), inline.len());
+        return Ok(match folder_key {
+            Some(key) => key.decrypt_operations(operations, inline),
+            None => (operations, inline),
+        });
+    }
+
+    Ok((Vec::new(), InlineContent::default()))
+}
+
+/// The path the server knows a file by.
+pub fn remote_path(metadata: &FileMetadata, folder_key: Option<&FolderKey>) -> String {
+    match folder_key {
+        Some(key) => key.encrypt_path(&metadata.path).to_string_lossy().to_string(),
+        None => metadata.path.to_string_lossy().to_string(),
+    }
+}
+
+/// Checks downloaded content against its metadata, decrypting it for an
+/// encrypted root. Encrypted content is checked by decrypting it instead of
+/// by hash; the metadata only carries a keyed hash of the plaintext.
+pub fn open_download(
+    metadata: &FileMetadata,
+    content: Vec<u8>,
+    folder_key: Option<&FolderKey>,
+) -> Result<Vec<u8>, SyncError> {
+    match folder_key {
+        Some(key) => key.decrypt_content(&metadata.path, &content),
+        None if blake3::hash(&content).to_hex().as_str() == metadata.hash => Ok(content),
+        None => Err(SyncError::Conflict(format!("Hash mismatch for {:?}", metadata.path))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conflicts::ConflictLayout;
+    use crate::sync::ConflictStrategy;
+    use crate::types::Timestamp;
+
+    #[tokio::test]
+    async fn test_session_round_applies_operations() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("old.md"), "gone").unwrap();
+        let indexer = FileIndexer::new("laptop".to_string(), root.path().to_path_buf());
+        let (note, big) = (b"# Small".to_vec(), vec![b'x'; 10_000]);
+        let mut inline = InlineContent::default();
+        inline.insert(PathBuf::from("note.md"), &note);
+        let operations = vec![
+            SyncOperation::Add(FileMetadata::for_test("note.md", &note)),
+            SyncOperation::Add(FileMetadata::for_test("big.md", &big)),
+            SyncOperation::Add(FileMetadata::for_test("lost.md", b"?")),
+            SyncOperation::Delete(PathBuf::from("old.md")),
+        ];
+
+        // A server that has only one of the files asked for
+        let (stream, mut server) = network::loopback_pair().await.unwrap();
+        tokio::spawn(async move {
+            let mut reader = network::MessageReader::new();
+            while let Ok(Some(NetworkMessage::FileRequest { path })) = reader.next(&mut server).await {
+                let content = (path == "big.md").then(|| big.clone());
+                let response = NetworkMessage::FileResponse { path, found: content.is_some(), content, metadata: None };
+                server.write_all(&serde_json::to_vec(&response).unwrap()).await.unwrap();
+            }
+        });
+
+        let engine = SyncEngine::new("laptop".to_string());
+        let report = apply(&indexer, &engine, &mut network::Connection::new(stream), operations, inline, None).await.unwrap();
+        assert_eq!(report.written, [PathBuf::from("note.md"), PathBuf::from("big.md")]);
+        assert_eq!((report.deleted, report.skipped), (vec![PathBuf::from("old.md")], vec![PathBuf::from("lost.md")]));
+        assert_eq!(report.bytes_received, 10_007);
+        assert_eq!(std::fs::read(root.path().join("big.md")).unwrap().len(), 10_000);
+        assert!(!root.path().join("old.md").exists());
+    }
+
+    #[test]
+    fn test_write_incoming_resolves_local_edits() {
+        let root = tempfile::tempdir().unwrap();
+        let indexer = FileIndexer::new("laptop".to_string(), root.path().to_path_buf());
+        std::fs::write(root.path().join("note.txt"), "edited here").unwrap();
+        let mut remote = FileMetadata::for_test("note.txt", b"edited there");
+        remote.modified = Timestamp::from(std::time::SystemTime::UNIX_EPOCH);
+
+        // The local edit is the newer one
+        let newest = SyncEngine::new("laptop".to_string());
+        write_incoming(&indexer, &newest, &remote, b"edited there").unwrap();
+        assert_eq!(std::fs::read_to_string(root.path().join("note.txt")).unwrap(), "edited here");
+
+        let keep_both = SyncEngine::new("laptop".to_string())
+            .with_conflict_strategy(ConflictStrategy::KeepBoth, "laptop", ConflictLayout::Sibling);
+        write_incoming(&indexer, &keep_both, &remote, b"edited there").unwrap();
+        assert_eq!(std::fs::read_to_string(root.path().join("note.txt")).unwrap(), "edited there");
+        assert_eq!(std::fs::read_to_string(root.path().join("note.conflict-laptop.txt")).unwrap(), "edited here");
+
+        // Files not here yet are just written
+        write_incoming(&indexer, &newest, &FileMetadata::for_test("new.txt", b"new"), b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(root.path().join("new.txt")).unwrap(), "new");
+    }
+}
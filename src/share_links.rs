@@ -0,0 +1,338 @@
+#![allow(dead_code)]
+
+//! Links that let people without syncmd download a file, or a directory as
+//! a tar archive, from a server until the link expires. A device asks for a
+//! link over its sync connection; the server signs what the link is for
+//! with a key only it has, so links can't be forged or altered, and serves
+//! them over plain HTTP on the share's `share_links_port`.
+//!
+//! A link is for the version that was shared: once the file, or anything
+//! under the directory, changes, the link stops working. Rotating the key
+//! revokes every link of the share.
+
+use crate::types::SyncError;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const KEY_LEN: usize = 32;
+const KEY_DIR_NAME: &str = "share-link-keys";
+/// Links are served under this path
+pub const LINK_PREFIX: &str = "/s/";
+/// Longest a link may be valid for
+pub const MAX_LIFETIME: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+/// Requests with a longer head than this are refused
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// What a link is for, signed into it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkClaims {
+    /// Relative to the share, `/`-separated; empty for the whole share
+    pub path: String,
+    pub directory: bool,
+    /// The file's hash, or `directory_version` of the directory
+    pub version: String,
+    /// Unix seconds
+    pub expires: i64,
+}
+
+/// A link the server issued, for the device to complete with its host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    /// Path and query to request on `port`
+    pub target: String,
+    pub port: u16,
+    pub expires: chrono::DateTime<chrono::Utc>,
+}
+
+/// Why a link isn't served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkError {
+    /// Not a link, or one signed with another key
+    Invalid,
+    Expired,
+    /// What was shared has changed or is gone
+    Changed,
+}
+
+impl LinkError {
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            LinkError::Invalid => (404, "Not Found"),
+            LinkError::Expired | LinkError::Changed => (410, "Gone"),
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            LinkError::Invalid => "No such link",
+            LinkError::Expired => "This link has expired",
+            LinkError::Changed => "What this link was for has changed since it was shared",
+        }
+    }
+}
+
+/// Signs and checks a share's links, and knows where they are served.
+pub struct ShareLinks {
+    key: [u8; KEY_LEN],
+    pub port: u16,
+}
+
+/// Where the link key of `share` is kept, under the config directory like
+/// the at-rest keyrings, so a copy of the storage can't make links.
+pub fn key_path(share: &Path) -> Result<PathBuf, SyncError> {
+    let config_dir = dirs::config_dir()
+        .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))?;
+    let share = share.canonicalize().unwrap_or_else(|_| share.to_path_buf());
+    let id = blake3::hash(share.to_string_lossy().as_bytes()).to_hex();
+    Ok(config_dir.join("syncmd").join(KEY_DIR_NAME).join(&id[..16]))
+}
+
+/// Replaces the share's link key, so links issued so far stop working.
+pub fn rotate_key(share: &Path) -> Result<(), SyncError> {
+    let mut key = [0u8; KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    crate::security::write_private(&key_path(share)?, BASE64.encode(key).as_bytes())
+}
+
+impl ShareLinks {
+    /// Loads the share's link key, creating it the first time.
+    pub fn load_or_create(share: &Path, port: u16) -> Result<Self, SyncError> {
+        let path = key_path(share)?;
+        if !path.exists() {
+            rotate_key(share)?;
+        }
+        let encoded = std::fs::read_to_string(&path)?;
+        let key = BASE64.decode(encoded.trim()).ok()
+            .and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok())
+            .ok_or_else(|| SyncError::Auth(format!("Invalid share link key in {}", path.display())))?;
+        Ok(Self { key, port })
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    pub fn issue(&self, claims: &LinkClaims) -> Result<ShareLink, SyncError> {
+        let payload = BASE64.encode(serde_json::to_vec(claims)?);
+        let signature = BASE64.encode(self.mac(&payload).finalize().into_bytes());
+        Ok(ShareLink {
+            target: format!("{}{}.{}", LINK_PREFIX, payload, signature),
+            port: self.port,
+            expires: chrono::DateTime::from_timestamp(claims.expires, 0).unwrap_or_default(),
+        })
+    }
+
+    /// The claims of a requested link, if it is one of ours and still valid.
+    /// Whether the version still matches is up to the caller.
+    pub fn verify(&self, target: &str) -> Result<LinkClaims, LinkError> {
+        let link = target.strip_prefix(LINK_PREFIX).ok_or(LinkError::Invalid)?;
+        let (payload, signature) = link.split_once('.').ok_or(LinkError::Invalid)?;
+        let signature = BASE64.decode(signature).map_err(|_| LinkError::Invalid)?;
+        self.mac(payload).verify(&signature).map_err(|_| LinkError::Invalid)?;
+        let claims: LinkClaims = BASE64.decode(payload).ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or(LinkError::Invalid)?;
+        if claims.expires <= chrono::Utc::now().timestamp() {
+            return Err(LinkError::Expired);
+        }
+        Ok(claims)
+    }
+}
+
+/// Sums up the files under a directory as `(path, hash)` pairs, so a link
+/// can tell when any of them changed.
+pub fn directory_version<'a>(files: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let mut files: Vec<(&str, &str)> = files.collect();
+    files.sort();
+    let mut hasher = blake3::Hasher::new();
+    for (path, hash) in files {
+        hasher.update(path.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(hash.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Whether `path` is inside `directory`; everything is inside the empty
+/// directory.
+pub fn is_under(path: &str, directory: &str) -> bool {
+    directory.is_empty()
+        || path.strip_prefix(directory).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Packs files into a tar archive under the paths given.
+pub fn tar_archive(files: &[(String, &[u8])]) -> Result<Vec<u8>, SyncError> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(chrono::Utc::now().timestamp() as u64);
+        builder.append_data(&mut header, path, *content)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// A few types browsers can show; everything else is downloaded.
+pub fn content_type(path: &str) -> &'static str {
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    match extension.to_lowercase().as_str() {
+        "md" | "markdown" => "text/markdown; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The method, target and headers of a request.
+#[derive(Debug, Clone, Default)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// The value of the header called `name`, in any case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads a request's head, `None` if the client sent something else or
+/// gave up.
+pub async fn read_head(stream: &mut tokio::net::TcpStream) -> Result<Option<RequestHead>, SyncError> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Ok(None);
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(None);
+    };
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(Some(RequestHead { method: method.to_string(), target: target.to_string(), headers }))
+}
+
+/// Reads a request's method and target, `None` if the client sent
+/// something else or gave up.
+pub async fn read_request(stream: &mut tokio::net::TcpStream) -> Result<Option<(String, String)>, SyncError> {
+    Ok(read_head(stream).await?.map(|head| (head.method, head.target)))
+}
+
+/// Writes a whole response and closes the connection.
+pub async fn respond(
+    stream: &mut tokio::net::TcpStream,
+    status: (u16, &str),
+    content_type: &str,
+    filename: Option<&str>,
+    body: &[u8],
+) -> Result<(), SyncError> {
+    let disposition = filename.map(|filename| format!("inline; filename=\"{}\"", filename.replace(['"', '\\'], "_")));
+    let headers: Vec<(&str, &str)> = disposition.iter().map(|value| ("Content-Disposition", value.as_str())).collect();
+    respond_with(stream, status, content_type, &headers, body).await
+}
+
+/// Like [`respond`], with any extra headers.
+pub async fn respond_with(
+    stream: &mut tokio::net::TcpStream,
+    (status, reason): (u16, &str),
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(), SyncError> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n",
+        status, reason, content_type, body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Parses how long a link stays valid, like `48h`, `30m`, `7d` or `2w`;
+/// a plain number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid duration: {}", value))?;
+    let seconds = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" | "min" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        other => return Err(format!("Unknown duration unit: {}", other)),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links_are_signed() {
+        let links = ShareLinks { key: [7; KEY_LEN], port: 8081 };
+        let claims = LinkClaims {
+            path: "Notes/spec.md".to_string(),
+            directory: false,
+            version: "abc".to_string(),
+            expires: chrono::Utc::now().timestamp() + 60,
+        };
+        let link = links.issue(&claims).unwrap();
+        assert_eq!(links.verify(&link.target), Ok(claims.clone()));
+
+        // Pointing a link at another file breaks the signature
+        let (_, signature) = link.target.rsplit_once('.').unwrap();
+        let other = LinkClaims { path: "Notes/private.md".to_string(), ..claims.clone() };
+        let payload = BASE64.encode(serde_json::to_vec(&other).unwrap());
+        assert_eq!(links.verify(&format!("{}{}.{}", LINK_PREFIX, payload, signature)), Err(LinkError::Invalid));
+        let rotated = ShareLinks { key: [8; KEY_LEN], port: 8081 };
+        assert_eq!(rotated.verify(&link.target), Err(LinkError::Invalid));
+
+        let expired = links.issue(&LinkClaims { expires: 0, ..claims }).unwrap();
+        assert_eq!(links.verify(&expired.target), Err(LinkError::Expired));
+
+        assert_eq!(parse_duration("48h"), Ok(Duration::from_secs(48 * 3600)));
+        assert!(parse_duration("2 fortnights").is_err());
+        assert!(is_under("Notes/spec.md", "Notes") && !is_under("Notes2/a.md", "Notes"));
+    }
+}
@@ -0,0 +1,380 @@
+#![allow(dead_code)]
+
+use crate::conflicts::ConflictLayout;
+use crate::index_store::IndexStore;
+use crate::indexer::FileIndexer;
+use crate::sections::SectionSplit;
+use crate::session;
+use crate::sync::{ConflictStrategy, FrontmatterPolicy, Resolution, SyncEngine};
+use crate::types::{FileMetadata, SyncError};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+/// A scripted sequence of edits and syncs across virtual devices.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub conflict_strategy: ConflictStrategy,
+    #[serde(default)]
+    pub conflict_layout: ConflictLayout,
+    #[serde(default)]
+    pub frontmatter_policies: BTreeMap<String, FrontmatterPolicy>,
+    /// Patterns of files merged section by section
+    #[serde(default)]
+    pub split_sections: Vec<String>,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Step {
+    Write { device: usize, write: PathBuf, content: String },
+    Append { device: usize, append: PathBuf, content: String },
+    Delete { device: usize, delete: PathBuf },
+    Sync { sync: SyncTarget },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SyncTarget {
+    Device(usize),
+    /// Only `all` is accepted
+    All(String),
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, SyncError> {
+        let content = std::fs::read_to_string(path)?;
+        serde_yaml::from_str(&content)
+            .map_err(|e| SyncError::Config(format!("Invalid scenario {}: {}", path.display(), e)))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HubFile {
+    metadata: FileMetadata,
+    content: Vec<u8>,
+}
+
+/// A file that was changed on a device and on the hub since that device last
+/// synced it.
+#[derive(Debug, Clone)]
+pub struct SimulatedConflict {
+    pub step: usize,
+    pub device: usize,
+    pub path: PathBuf,
+    pub resolution: String,
+}
+
+#[derive(Debug, Default)]
+pub struct SimulationReport {
+    pub log: Vec<String>,
+    pub conflicts: Vec<SimulatedConflict>,
+    /// Paths whose content differs between devices after settling
+    pub divergent: Vec<PathBuf>,
+    pub file_count: usize,
+}
+
+impl SimulationReport {
+    pub fn converged(&self) -> bool {
+        self.divergent.is_empty()
+    }
+}
+
+/// A virtual device: a root indexed, resolved and recorded by the same
+/// indexer, engine and index the `syncmd` client uses.
+struct Device {
+    indexer: FileIndexer,
+    engine: SyncEngine,
+    /// Hash of each path as of this device's last sync
+    synced: HashMap<PathBuf, String>,
+}
+
+/// Runs virtual devices in temporary directories that sync through an
+/// in-memory hub, the way clients sync through a server.
+pub struct Simulation {
+    base_dir: PathBuf,
+    devices: Vec<Device>,
+    hub: HashMap<PathBuf, HubFile>,
+    report: SimulationReport,
+}
+
+impl Simulation {
+    pub fn new(device_count: usize, scenario: &Scenario) -> Result<Self, SyncError> {
+        let base_dir = std::env::temp_dir().join(format!("syncmd-simulate-{}", uuid::Uuid::new_v4()));
+        let mut devices = Vec::new();
+        for index in 0..device_count {
+            let name = format!("device-{}", index);
+            let dir = base_dir.join(&name);
+            std::fs::create_dir_all(&dir)?;
+            let engine = SyncEngine::new(name.clone())
+                .with_frontmatter_policies(scenario.frontmatter_policies.clone())
+                .with_section_split(SectionSplit::new(&scenario.split_sections))
+                .with_conflict_strategy(scenario.conflict_strategy, &name, scenario.conflict_layout);
+            devices.push(Device {
+                indexer: FileIndexer::new(name, dir),
+                engine,
+                synced: HashMap::new(),
+            });
+        }
+        Ok(Self {
+            base_dir,
+            devices,
+            hub: HashMap::new(),
+            report: SimulationReport::default(),
+        })
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Runs every step, then lets all devices sync twice so that anything
+    /// still pending has a chance to propagate before convergence is checked.
+    pub fn run(mut self, scenario: &Scenario) -> Result<SimulationReport, SyncError> {
+        for (index, step) in scenario.steps.iter().enumerate() {
+            self.apply_step(index + 1, step)?;
+        }
+
+        let settle_step = scenario.steps.len() + 1;
+        for _ in 0..2 {
+            for device in 0..self.devices.len() {
+                self.sync_device(settle_step, device)?;
+            }
+        }
+        self.check_convergence()?;
+        Ok(self.report)
+    }
+
+    pub fn cleanup(base_dir: &Path) {
+        let _ = std::fs::remove_dir_all(base_dir);
+    }
+
+    fn device(&self, index: usize) -> Result<&Device, SyncError> {
+        self.devices
+            .get(index)
+            .ok_or_else(|| SyncError::Config(format!("Scenario uses device {} but only {} exist", index, self.devices.len())))
+    }
+
+    fn apply_step(&mut self, step: usize, action: &Step) -> Result<(), SyncError> {
+        match action {
+            Step::Write { device, write, content } => {
+                self.device(*device)?.indexer.write_file_content(write, content.as_bytes())?;
+                self.report.log.push(format!("[{}] device {} writes {}", step, device, write.display()));
+            }
+            Step::Append { device, append, content } => {
+                let indexer = &self.device(*device)?.indexer;
+                let mut existing = indexer.read_file_content(append).unwrap_or_default();
+                existing.extend_from_slice(content.as_bytes());
+                indexer.write_file_content(append, &existing)?;
+                self.report.log.push(format!("[{}] device {} appends to {}", step, device, append.display()));
+            }
+            Step::Delete { device, delete } => {
+                self.device(*device)?.indexer.delete_file(delete)?;
+                self.report.log.push(format!("[{}] device {} deletes {}", step, device, delete.display()));
+            }
+            Step::Sync { sync } => {
+                let targets: Vec<usize> = match sync {
+                    SyncTarget::Device(device) => {
+                        self.device(*device)?;
+                        vec![*device]
+                    }
+                    SyncTarget::All(all) if all == "all" => (0..self.devices.len()).collect(),
+                    SyncTarget::All(other) => {
+                        return Err(SyncError::Config(format!("Unknown sync target: {}", other)));
+                    }
+                };
+                for device in targets {
+                    let (up, down) = self.sync_device(step, device)?;
+                    self.report.log.push(format!("[{}] device {} syncs: {} up, {} down", step, device, up, down));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs one device with the hub. What changed on one side only is
+    /// copied to the other; what changed on both is written the way the
+    /// client writes incoming files, resolved against the device's last
+    /// synced version, and the result sent back up.
+    fn sync_device(&mut self, step: usize, index: usize) -> Result<(usize, usize), SyncError> {
+        let state = self.devices[index].indexer.index_directory()?;
+        let paths: BTreeSet<PathBuf> = state
+            .local_files
+            .keys()
+            .chain(self.hub.keys())
+            .chain(self.devices[index].synced.keys())
+            .cloned()
+            .collect();
+
+        let (mut up, mut down) = (0, 0);
+        for path in paths {
+            let local = state.local_files.get(&path);
+            let remote = self.hub.get(&path).cloned();
+            let synced = self.devices[index].synced.get(&path);
+            let local_changed = local.map(|m| &m.hash) != synced;
+            let remote_changed = remote.as_ref().map(|f| &f.metadata.hash) != synced;
+
+            match (local, remote) {
+                (local, remote) if local.map(|m| &m.hash) == remote.as_ref().map(|f| &f.metadata.hash) => {}
+                (Some(local), _) if !remote_changed => {
+                    self.push(index, local)?;
+                    up += 1;
+                }
+                (None, _) if !remote_changed => {
+                    self.hub.remove(&path);
+                    up += 1;
+                }
+                (_, Some(remote)) if !local_changed => {
+                    self.pull(index, &remote)?;
+                    down += 1;
+                }
+                (Some(_), None) if !local_changed => {
+                    self.devices[index].indexer.trash_file(&path)?;
+                    down += 1;
+                }
+                // An edit always wins over a deletion
+                (Some(local), None) => {
+                    self.record(step, index, &path, "kept local edit over remote delete".to_string());
+                    self.push(index, local)?;
+                    up += 1;
+                }
+                (None, Some(remote)) => {
+                    self.record(step, index, &path, "kept remote edit over local delete".to_string());
+                    self.pull(index, &remote)?;
+                    down += 1;
+                }
+                (None, None) => {}
+                (Some(_), Some(remote)) => {
+                    let resolution = self.pull(index, &remote)?;
+                    down += 1;
+                    let resolution = match resolution {
+                        Some(Resolution::KeepLocal) => "kept local".to_string(),
+                        Some(Resolution::TakeRemote) | None => "took remote".to_string(),
+                        Some(Resolution::Merged(merged)) if String::from_utf8_lossy(&merged).contains("<<<<<<<") => {
+                            "merged with conflict markers".to_string()
+                        }
+                        Some(Resolution::Merged(_)) => "merged".to_string(),
+                        Some(Resolution::KeepBoth { copy }) => format!("kept both, local copy at {}", copy.display()),
+                    };
+                    self.record(step, index, &path, resolution);
+                    // Kept or merged here, the result goes back up
+                    let resolved = self.devices[index].indexer.file_metadata(&path)?;
+                    if resolved.hash != remote.metadata.hash {
+                        self.push(index, &resolved)?;
+                        up += 1;
+                    }
+                }
+            }
+        }
+        self.finish_sync(index)?;
+        Ok((up, down))
+    }
+
+    fn push(&mut self, index: usize, metadata: &FileMetadata) -> Result<(), SyncError> {
+        let content = self.devices[index].indexer.read_file_content(&metadata.path)?;
+        self.hub.insert(metadata.path.clone(), HubFile { metadata: metadata.clone(), content });
+        Ok(())
+    }
+
+    fn pull(&mut self, index: usize, remote: &HubFile) -> Result<Option<Resolution>, SyncError> {
+        let device = &self.devices[index];
+        device.indexer.adopt(&remote.metadata);
+        session::write_incoming(&device.indexer, &device.engine, &remote.metadata, &remote.content)
+    }
+
+    fn record(&mut self, step: usize, device: usize, path: &Path, resolution: String) {
+        self.report.conflicts.push(SimulatedConflict {
+            step,
+            device,
+            path: path.to_path_buf(),
+            resolution,
+        });
+    }
+
+    /// Indexes the device as it is after the sync and records its files as
+    /// the last-synced versions, the way the client does after a round.
+    fn finish_sync(&mut self, index: usize) -> Result<(), SyncError> {
+        let device = &mut self.devices[index];
+        let state = device.indexer.index_directory()?;
+        let mut store = IndexStore::open(device.indexer.sync_root())?;
+        store.save_state(&state)?;
+        store.record_bases(&state, |path| device.indexer.read_file_content(path))?;
+        device.synced = state
+            .local_files
+            .iter()
+            .filter(|(path, metadata)| self.hub.get(*path).is_some_and(|file| file.metadata.hash == metadata.hash))
+            .map(|(path, metadata)| (path.clone(), metadata.hash.clone()))
+            .collect();
+        Ok(())
+    }
+
+    fn check_convergence(&mut self) -> Result<(), SyncError> {
+        let mut versions: BTreeMap<PathBuf, BTreeSet<Option<String>>> = BTreeMap::new();
+        let states = self
+            .devices
+            .iter()
+            .map(|device| device.indexer.index_directory())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let all_paths: BTreeSet<PathBuf> = states.iter().flat_map(|s| s.local_files.keys().cloned()).collect();
+        for path in &all_paths {
+            for state in &states {
+                versions
+                    .entry(path.clone())
+                    .or_default()
+                    .insert(state.local_files.get(path).map(|m| m.hash.clone()));
+            }
+        }
+
+        self.report.file_count = all_paths.len();
+        self.report.divergent = versions
+            .into_iter()
+            .filter(|(_, hashes)| hashes.len() > 1)
+            .map(|(path, _)| path)
+            .collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_edits_converge() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("scenario.yaml");
+        std::fs::write(
+            &script,
+            r#"
+conflict_strategy: merge
+steps:
+  - { device: 0, write: notes/todo.md, content: "one\ntwo\nthree\n" }
+  - sync: all
+  - { device: 0, write: notes/todo.md, content: "ONE\ntwo\nthree\n" }
+  - { device: 1, write: notes/todo.md, content: "one\ntwo\nTHREE\n" }
+  - sync: all
+"#,
+        )
+        .unwrap();
+        let scenario = Scenario::load(&script).unwrap();
+        let simulation = Simulation::new(2, &scenario).unwrap();
+        let base_dir = simulation.base_dir().to_path_buf();
+        let report = simulation.run(&scenario).unwrap();
+        let merged = std::fs::read_to_string(base_dir.join("device-1/notes/todo.md"));
+        Simulation::cleanup(&base_dir);
+
+        assert!(report.converged(), "diverged: {:?}", report.divergent);
+        assert_eq!(report.file_count, 1);
+        let [conflict] = report.conflicts.as_slice() else {
+            panic!("expected one conflict, got {:?}", report.conflicts);
+        };
+        assert_eq!((conflict.step, conflict.device), (5, 1));
+        assert_eq!(conflict.path, PathBuf::from("notes/todo.md"));
+        assert_eq!(conflict.resolution, "merged");
+        assert_eq!(merged.unwrap(), "ONE\ntwo\nTHREE\n");
+    }
+}
@@ -0,0 +1,139 @@
+#![allow(dead_code)]
+
+//! Where a root keeps syncmd's own state: the index database, the trash,
+//! partial downloads, writes staged by an interrupted sync and, on servers,
+//! uploads waiting for a scan. By default that's a `.syncmd` directory in
+//! the root. With `state_dir` in the config, every root gets a subfolder
+//! there instead, named after the root and a hash of its path, and the root
+//! holds nothing but synced files. Either way the state is never synced.
+//!
+//! Conflict copies in the `directory` layout and quarantined uploads stay in
+//! the root's `.syncmd`, as they are files for people to look at.
+
+use crate::apply_journal::APPLY_DIR_NAME;
+use crate::file_transfer::PARTIAL_DIR_NAME;
+use crate::index_store::STATE_DIR_NAME;
+use crate::trash::TRASH_DIR_NAME;
+use crate::types::SyncError;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+static CENTRAL: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Keeps the state of every root under `dir` from now on, or in the roots
+/// themselves for `None`. Set once at startup from the config.
+pub fn set_central(dir: Option<PathBuf>) {
+    let dir = dir.map(|dir| std::path::absolute(&dir).unwrap_or(dir));
+    *CENTRAL.write().unwrap() = dir;
+}
+
+pub fn central() -> Option<PathBuf> {
+    CENTRAL.read().unwrap().clone()
+}
+
+/// The state directory of the root at `sync_root`.
+pub fn dir(sync_root: &Path) -> PathBuf {
+    dir_in(central().as_deref(), sync_root)
+}
+
+fn dir_in(central: Option<&Path>, sync_root: &Path) -> PathBuf {
+    match central {
+        Some(central) => central.join(subfolder_name(sync_root)),
+        None => sync_root.join(STATE_DIR_NAME),
+    }
+}
+
+/// The name of a root's folder under the central state directory. The hash
+/// keeps roots with the same name apart.
+fn subfolder_name(sync_root: &Path) -> String {
+    let root = sync_root.canonicalize().unwrap_or_else(|_| sync_root.to_path_buf());
+    let name = root.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let hash = blake3::hash(root.to_string_lossy().as_bytes()).to_hex();
+    format!("{}-{}", name, &hash[..12])
+}
+
+/// Whether `path`, a directory found while walking a root, holds syncmd
+/// state and must not be synced or watched.
+pub fn is_state_dir(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == STATE_DIR_NAME)
+        || central().is_some_and(|central| path == central)
+}
+
+/// Whether `relative_path`, a path in a root as another device names it,
+/// is inside the root's `.syncmd`, which only this device writes.
+pub fn is_state_path(relative_path: &Path) -> bool {
+    relative_path.components().next().is_some_and(|first| first.as_os_str() == STATE_DIR_NAME)
+}
+
+/// What `syncmd state clean` removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanReport {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl fmt::Display for CleanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} file(s), {}", self.files, crate::plan::format_size(self.bytes))
+    }
+}
+
+/// Removes what a root's state can do without: partial downloads, which
+/// start over, staged writes, unless an interrupted sync still needs them,
+/// and with `trash` the trash. The index stays, so nothing has to be hashed
+/// again.
+pub fn clean(sync_root: &Path, trash: bool) -> Result<CleanReport, SyncError> {
+    let state = dir(sync_root);
+    let mut report = CleanReport::default();
+    let mut dirs = vec![state.join(PARTIAL_DIR_NAME)];
+    if !crate::apply_journal::is_pending(sync_root) {
+        dirs.push(state.join(APPLY_DIR_NAME));
+    }
+    if trash {
+        dirs.push(state.join(TRASH_DIR_NAME));
+    }
+    for dir in dirs {
+        if !dir.exists() {
+            continue;
+        }
+        for entry in walkdir::WalkDir::new(&dir).into_iter().filter_map(Result::ok) {
+            if entry.file_type().is_file() {
+                report.files += 1;
+                report.bytes += entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            }
+        }
+        std::fs::remove_dir_all(&dir)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_central_state_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let central = tempfile::tempdir().unwrap();
+        assert_eq!(dir_in(None, root.path()), root.path().join(STATE_DIR_NAME));
+
+        let state = dir_in(Some(central.path()), root.path());
+        assert_eq!(state.parent(), Some(central.path()));
+        let name = root.path().file_name().unwrap().to_string_lossy().to_string();
+        assert!(state.file_name().unwrap().to_string_lossy().starts_with(&name));
+        let other = tempfile::tempdir_in(root.path()).unwrap();
+        assert_ne!(dir_in(Some(central.path()), other.path()), state);
+        assert!(is_state_dir(&root.path().join(STATE_DIR_NAME)));
+        assert!(is_state_path(Path::new(".syncmd/state.db")));
+        assert!(!is_state_path(Path::new("notes/.syncmd/a.md")));
+
+        let partial = dir(root.path()).join(PARTIAL_DIR_NAME);
+        std::fs::create_dir_all(&partial).unwrap();
+        std::fs::write(partial.join("abc123"), [0u8; 100]).unwrap();
+        std::fs::create_dir_all(dir(root.path()).join(TRASH_DIR_NAME)).unwrap();
+        assert_eq!(clean(root.path(), false).unwrap(), CleanReport { files: 1, bytes: 100 });
+        assert!(!partial.exists());
+        assert!(dir(root.path()).join(TRASH_DIR_NAME).exists());
+    }
+}
@@ -0,0 +1,92 @@
+#![allow(dead_code)]
+
+//! Terminal colors for CLI output. Every command styles text through here so
+//! the same kind of change always gets the same color.
+
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal and NO_COLOR isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Files added or downloaded, successful outcomes
+    Added,
+    /// Files deleted, failures
+    Deleted,
+    /// Conflicts and anything needing attention
+    Conflict,
+    /// Section headings
+    Heading,
+    /// Secondary details such as timestamps and ids
+    Dim,
+}
+
+impl Style {
+    fn code(&self) -> &'static str {
+        match self {
+            Style::Added => "32",
+            Style::Deleted => "31",
+            Style::Conflict => "33",
+            Style::Heading => "1",
+            Style::Dim => "2",
+        }
+    }
+}
+
+/// Decides once, at startup, whether output is colored.
+pub fn init(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            // https://no-color.org: any non-empty value disables color
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+            let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+            !no_color && !dumb && std::io::stdout().is_terminal()
+        }
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn paint(style: Style, text: impl Display) -> String {
+    if enabled() {
+        format!("\x1b[{}m{}\x1b[0m", style.code(), text)
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn added(text: impl Display) -> String {
+    paint(Style::Added, text)
+}
+
+pub fn deleted(text: impl Display) -> String {
+    paint(Style::Deleted, text)
+}
+
+pub fn conflict(text: impl Display) -> String {
+    paint(Style::Conflict, text)
+}
+
+pub fn heading(text: impl Display) -> String {
+    paint(Style::Heading, text)
+}
+
+pub fn dim(text: impl Display) -> String {
+    paint(Style::Dim, text)
+}
@@ -0,0 +1,307 @@
+#![allow(dead_code)]
+
+//! Downloads of large files from several servers at once. The file is split
+//! into fixed-size chunks and every source pulls the next missing one when
+//! it is free, so faster sources end up sending more. A source that fails
+//! is dropped and its chunk goes back to the others.
+//!
+//! Received chunks are written into the content-hash keyed partial file
+//! from `file_transfer.rs`, with a bitmap of which chunks are in it next to
+//! it, so an interrupted download continues where it stopped.
+
+use crate::file_transfer::TransferHandle;
+use crate::network;
+use crate::types::SyncError;
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Files smaller than this download fine from a single source
+pub const MIN_FILE_SIZE: u64 = 8 * 1024 * 1024;
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+/// Failed requests after which a source is no longer asked
+const MAX_SOURCE_ERRORS: u32 = 2;
+const BITMAP_SUFFIX: &str = "chunks";
+
+pub struct Source<'a> {
+    pub name: String,
+    pub stream: &'a mut network::Connection,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceStats {
+    pub name: String,
+    pub chunks: u64,
+    pub bytes: u64,
+    /// Time spent waiting on this source's responses
+    pub busy: Duration,
+    pub errors: u32,
+    /// Why the source stopped being used before the download finished
+    pub dropped: Option<String>,
+}
+
+impl SourceStats {
+    /// Bytes per second while the source was busy.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.busy.as_secs_f64().max(0.001)
+    }
+}
+
+/// Which chunks of a file have arrived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkBitmap {
+    bits: Vec<u8>,
+    chunks: u64,
+}
+
+impl ChunkBitmap {
+    pub fn new(chunks: u64) -> Self {
+        Self { bits: vec![0; chunks.div_ceil(8) as usize], chunks }
+    }
+
+    /// The bitmap saved at `path`, or an empty one if there is none or it
+    /// belongs to a different chunk count.
+    pub fn load(path: &Path, chunks: u64) -> Self {
+        match std::fs::read(path) {
+            Ok(bits) if bits.len() == chunks.div_ceil(8) as usize => Self { bits, chunks },
+            _ => Self::new(chunks),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SyncError> {
+        Ok(std::fs::write(path, &self.bits)?)
+    }
+
+    pub fn has(&self, index: u64) -> bool {
+        index < self.chunks && self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: u64) {
+        if index < self.chunks {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn missing(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.chunks).filter(|index| !self.has(*index))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing().next().is_none()
+    }
+}
+
+/// Chunks still to fetch, and how many are being fetched right now.
+struct WorkQueue {
+    pending: VecDeque<u64>,
+    in_flight: usize,
+}
+
+enum Next {
+    Chunk(u64),
+    /// Nothing queued, but a chunk in flight elsewhere may come back
+    Wait,
+    Done,
+}
+
+impl WorkQueue {
+    fn next(&mut self) -> Next {
+        match self.pending.pop_front() {
+            Some(index) => {
+                self.in_flight += 1;
+                Next::Chunk(index)
+            }
+            None if self.in_flight > 0 => Next::Wait,
+            None => Next::Done,
+        }
+    }
+
+    fn finish(&mut self, index: u64, received: bool) {
+        self.in_flight -= 1;
+        if !received {
+            // First in line, so a healthy source picks it up next
+            self.pending.push_front(index);
+        }
+    }
+}
+
+fn bitmap_path(partial: &Path) -> PathBuf {
+    let mut name = partial.as_os_str().to_owned();
+    name.push(".");
+    name.push(BITMAP_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Drops what a download left in `partial`, for one that was cancelled and
+/// won't resume.
+pub fn discard(partial: &Path) {
+    let _ = std::fs::remove_file(partial);
+    let _ = std::fs::remove_file(bitmap_path(partial));
+}
+
+/// Fetches `size` bytes of `remote_path` at version `hash` from all
+/// `sources` in parallel and returns the assembled content, which the
+/// caller still has to verify. Fails, keeping what arrived in `partial`,
+/// when every source dropped out before the file was complete. Once
+/// `progress` is cancelled sources stop between chunks, so no request is
+/// left unanswered on their connections, and what arrived is dropped.
+pub async fn download(
+    sources: Vec<Source<'_>>,
+    remote_path: &str,
+    hash: &str,
+    size: u64,
+    partial: &Path,
+    progress: &TransferHandle,
+) -> Result<(Vec<u8>, Vec<SourceStats>), SyncError> {
+    if let Some(parent) = partial.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let chunks = size.div_ceil(CHUNK_SIZE);
+    let bitmap_path = bitmap_path(partial);
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(partial)?;
+    // A partial left by a sequential transfer has no bitmap and starts over
+    let bitmap = ChunkBitmap::load(&bitmap_path, chunks);
+    file.set_len(size)?;
+
+    let queue = Mutex::new(WorkQueue { pending: bitmap.missing().collect(), in_flight: 0 });
+    // Chunks a cut off download left count as done
+    let missing: u64 = bitmap.missing().map(|index| CHUNK_SIZE.min(size - index * CHUNK_SIZE)).sum();
+    let output = Mutex::new((file, bitmap, size - missing));
+
+    let workers = sources.into_iter().map(|source| {
+        let (queue, output, bitmap_path) = (&queue, &output, &bitmap_path);
+        async move {
+            let mut stats = SourceStats { name: source.name, ..Default::default() };
+            loop {
+                if progress.is_cancelled() {
+                    break;
+                }
+                let next = queue.lock().unwrap().next();
+                let index = match next {
+                    Next::Chunk(index) => index,
+                    Next::Wait => {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        continue;
+                    }
+                    Next::Done => break,
+                };
+                let expected = CHUNK_SIZE.min(size - index * CHUNK_SIZE) as usize;
+                let started = Instant::now();
+                let result = network::fetch_chunk(source.stream, remote_path, hash, index, CHUNK_SIZE).await;
+                stats.busy += started.elapsed();
+
+                let failure = match result {
+                    Ok(Some(data)) if data.len() == expected => {
+                        let mut output = output.lock().unwrap();
+                        let (file, bitmap, done) = &mut *output;
+                        let written = file.seek(SeekFrom::Start(index * CHUNK_SIZE))
+                            .and_then(|_| file.write_all(&data))
+                            .map_err(SyncError::from)
+                            .and_then(|_| {
+                                bitmap.set(index);
+                                bitmap.save(bitmap_path)
+                            });
+                        if let Err(e) = written {
+                            // The disk is the problem, not the source
+                            queue.lock().unwrap().finish(index, false);
+                            return Err(e);
+                        }
+                        stats.chunks += 1;
+                        stats.bytes += data.len() as u64;
+                        *done += data.len() as u64;
+                        progress.set_transferred(*done);
+                        None
+                    }
+                    // Another version or no file, later chunks won't be better
+                    Ok(None) => Some((format!("doesn't have version {}", &hash[..hash.len().min(12)]), true)),
+                    Ok(Some(data)) => Some((format!("sent {} bytes for chunk {}, expected {}", data.len(), index, expected), false)),
+                    Err(e) => Some((e.to_string(), false)),
+                };
+                queue.lock().unwrap().finish(index, failure.is_none());
+                if let Some((reason, fatal)) = failure {
+                    stats.errors += 1;
+                    if fatal || stats.errors >= MAX_SOURCE_ERRORS {
+                        stats.dropped = Some(reason);
+                        break;
+                    }
+                }
+            }
+            Ok(stats)
+        }
+    });
+    let stats = futures_util::future::join_all(workers).await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (mut file, bitmap, _) = output.into_inner().unwrap();
+    if progress.is_cancelled() {
+        drop(file);
+        discard(partial);
+        return Err(SyncError::Cancelled(PathBuf::from(remote_path)));
+    }
+    if !bitmap.is_complete() {
+        let missing = bitmap.missing().count();
+        return Err(SyncError::Network(format!(
+            "All sources failed with {} of {} chunks missing, the rest is fetched next time",
+            missing, chunks
+        )));
+    }
+    let mut content = Vec::with_capacity(size as usize);
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut content)?;
+    drop(file);
+    discard(partial);
+    Ok((content, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_bitmap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.chunks");
+
+        let mut bitmap = ChunkBitmap::new(10);
+        bitmap.set(0);
+        bitmap.set(9);
+        bitmap.set(10);
+        assert_eq!(bitmap.missing().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        bitmap.save(&path).unwrap();
+
+        assert_eq!(ChunkBitmap::load(&path, 10), bitmap);
+        // Saved for a different size, so it can't describe this file
+        assert!(!ChunkBitmap::load(&path, 20).has(0));
+
+        let mut queue = WorkQueue { pending: VecDeque::from([4, 5]), in_flight: 0 };
+        assert!(matches!(queue.next(), Next::Chunk(4)));
+        assert!(matches!(queue.next(), Next::Chunk(5)));
+        assert!(matches!(queue.next(), Next::Wait));
+        queue.finish(4, false);
+        queue.finish(5, true);
+        assert!(matches!(queue.next(), Next::Chunk(4)));
+        queue.finish(4, true);
+        assert!(matches!(queue.next(), Next::Done));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_drops_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let partial = dir.path().join("partial/abc");
+        std::fs::create_dir_all(partial.parent().unwrap()).unwrap();
+        std::fs::write(&partial, vec![1; CHUNK_SIZE as usize]).unwrap();
+        let mut bitmap = ChunkBitmap::new(3);
+        bitmap.set(0);
+        bitmap.save(&bitmap_path(&partial)).unwrap();
+
+        let queue = crate::file_transfer::TransferQueue::default();
+        let handle = queue.register("t1", Path::new("videos/big.mp4"), CHUNK_SIZE * 3);
+        assert_eq!(queue.cancel("big.mp4").len(), 1);
+        let downloaded = download(Vec::new(), "videos/big.mp4", "abc", CHUNK_SIZE * 3, &partial, &handle).await;
+        assert!(matches!(downloaded, Err(SyncError::Cancelled(_))));
+        assert!(!partial.exists() && !bitmap_path(&partial).exists());
+    }
+}
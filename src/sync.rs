@@ -1,16 +1,318 @@
 #![allow(dead_code)]
 
-use crate::types::{SyncError, SyncOperation, FileMetadata};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use crate::conflicts::ConflictLayout;
+use crate::merge_drivers::{MergeDriver, MergeDrivers, MergeOutcome};
+use crate::sections::SectionSplit;
+use crate::types::{Causality, SyncError, SyncOperation, FileMetadata, PathSelection};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// How a sync root resolves files changed on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictStrategy {
+    /// The most recently modified version wins
+    #[default]
+    Newest,
+    /// Files with a merge driver (markdown, JSON, CSV) are merged, other
+    /// files fall back to the newest version. What doesn't merge is kept
+    /// both ways
+    Merge,
+    /// Both versions are kept, the local one under a conflict name
+    KeepBoth,
+}
+
+/// What becomes of a file changed both here and on another device since
+/// they last synced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The local version stays
+    KeepLocal,
+    /// The remote version replaces it
+    TakeRemote,
+    /// This content, made of both, replaces it
+    Merged(Vec<u8>),
+    /// The remote version replaces it and the local one is kept at `copy`
+    KeepBoth { copy: PathBuf },
+}
+
+/// Which way a sync root's changes go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncDirection {
+    /// Changes go both ways
+    #[default]
+    Both,
+    /// Local changes are uploaded, nothing local is ever modified
+    PushOnly,
+    /// Remote changes are applied, nothing is ever uploaded
+    PullOnly,
+    /// Like push-only, except deleting a file here leaves it on the remote
+    Backup,
+}
+
+impl SyncDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SyncDirection::Both => "both",
+            SyncDirection::PushOnly => "push-only",
+            SyncDirection::PullOnly => "pull-only",
+            SyncDirection::Backup => "backup",
+        }
+    }
+
+    pub fn modifies_local(self) -> bool {
+        matches!(self, SyncDirection::Both | SyncDirection::PullOnly)
+    }
+
+    pub fn uploads(self) -> bool {
+        self != SyncDirection::PullOnly
+    }
+
+    pub fn propagates_deletes(self) -> bool {
+        self != SyncDirection::Backup
+    }
+
+    /// Keeps what this direction lets through of the operations for the
+    /// local side and for the remote side.
+    pub fn filter(
+        self,
+        local_operations: Vec<SyncOperation>,
+        remote_operations: Vec<SyncOperation>,
+    ) -> (Vec<SyncOperation>, Vec<SyncOperation>) {
+        let local_operations = if self.modifies_local() { local_operations } else { Vec::new() };
+        let remote_operations = if !self.uploads() {
+            Vec::new()
+        } else if !self.propagates_deletes() {
+            remote_operations
+                .into_iter()
+                .filter_map(|operation| match operation {
+                    SyncOperation::Delete(_) | SyncOperation::RmDir(_) => None,
+                    // The old path stays on the remote, the content is added anew
+                    SyncOperation::Rename { to, .. } => Some(SyncOperation::Add(to)),
+                    operation => Some(operation),
+                })
+                .collect()
+        } else {
+            remote_operations
+        };
+        (local_operations, remote_operations)
+    }
+}
+
+/// How a frontmatter key is resolved when two versions of a note differ
+/// only in frontmatter scalars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrontmatterPolicy {
+    /// Value from the most recently modified version
+    Newest,
+    Local,
+    Remote,
+    /// Larger value, numerically or as text (ISO dates sort as text)
+    Max,
+    /// Smaller value
+    Min,
+}
+
+impl FrontmatterPolicy {
+    /// Configured policy for a key, otherwise the latest of common
+    /// "last modified" stamps, the earliest creation date and the newest
+    /// version for everything else.
+    pub fn for_key(key: &str, configured: &BTreeMap<String, FrontmatterPolicy>) -> Self {
+        if let Some(policy) = configured.get(key) {
+            return *policy;
+        }
+        match key.to_lowercase().as_str() {
+            "modified" | "updated" | "last_modified" | "lastmod" | "date_modified" | "mtime" => FrontmatterPolicy::Max,
+            "created" | "date_created" | "ctime" => FrontmatterPolicy::Min,
+            _ => FrontmatterPolicy::Newest,
+        }
+    }
+}
+
+/// A top-level frontmatter key with its raw lines, including any indented
+/// continuation lines.
+struct FrontmatterEntry<'a> {
+    key: &'a str,
+    lines: Vec<&'a str>,
+}
+
+impl FrontmatterEntry<'_> {
+    /// Single-line `key: value` with a plain or quoted value.
+    fn scalar_value(&self) -> Option<&str> {
+        if self.lines.len() != 1 {
+            return None;
+        }
+        let value = self.lines[0].split_once(':')?.1.trim();
+        if value.is_empty() || value.starts_with(['[', '{', '|', '>', '&', '*']) {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
 
 pub struct SyncEngine {
     device_id: String,
+    frontmatter_policies: BTreeMap<String, FrontmatterPolicy>,
+    selection: PathSelection,
+    section_split: SectionSplit,
+    custom_drivers: Vec<Arc<dyn MergeDriver>>,
+    direction: SyncDirection,
+    conflict_strategy: ConflictStrategy,
+    conflict_layout: ConflictLayout,
+    /// Names this device's conflict copies
+    conflict_label: String,
 }
 
 impl SyncEngine {
     pub fn new(device_id: String) -> Self {
-        Self { device_id }
+        Self {
+            conflict_label: crate::types::device_slug(&device_id),
+            device_id,
+            frontmatter_policies: BTreeMap::new(),
+            selection: PathSelection::default(),
+            section_split: SectionSplit::default(),
+            custom_drivers: Vec::new(),
+            direction: SyncDirection::Both,
+            conflict_strategy: ConflictStrategy::default(),
+            conflict_layout: ConflictLayout::default(),
+        }
+    }
+
+    /// Resolves files changed on both sides with `strategy`, keeping local
+    /// versions that lose as copies labelled `label` where `layout` says.
+    pub fn with_conflict_strategy(mut self, strategy: ConflictStrategy, label: &str, layout: ConflictLayout) -> Self {
+        self.conflict_strategy = strategy;
+        self.conflict_label = label.to_string();
+        self.conflict_layout = layout;
+        self
+    }
+
+    pub fn conflict_strategy(&self) -> ConflictStrategy {
+        self.conflict_strategy
+    }
+
+    /// Ignores operations outside the root's selected subfolders, so files
+    /// this device doesn't sync aren't downloaded.
+    pub fn with_selection(mut self, selection: PathSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// The operations inside the root's selected subfolders.
+    pub fn select_operations(&self, operations: Vec<SyncOperation>) -> Vec<SyncOperation> {
+        self.selection.retain_operations(operations)
+    }
+
+    /// Limits which way changes go, see [`SyncDirection`].
+    pub fn with_direction(mut self, direction: SyncDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    pub fn direction(&self) -> SyncDirection {
+        self.direction
+    }
+
+    /// The operations this device applies out of the ones a peer sent:
+    /// those in its selection, and none if the root's direction keeps local
+    /// files as they are.
+    pub fn incoming_operations(&self, operations: Vec<SyncOperation>) -> Vec<SyncOperation> {
+        self.direction.filter(self.select_operations(operations), Vec::new()).0
+    }
+
+    pub fn with_frontmatter_policies(mut self, policies: BTreeMap<String, FrontmatterPolicy>) -> Self {
+        self.frontmatter_policies = policies;
+        self
+    }
+
+    /// Merges the markdown files it matches section by section.
+    pub fn with_section_split(mut self, section_split: SectionSplit) -> Self {
+        self.section_split = section_split;
+        self
+    }
+
+    /// Merges the files `driver` applies to with it, ahead of the built-in
+    /// drivers and of drivers added before.
+    pub fn with_merge_driver(mut self, driver: Arc<dyn MergeDriver>) -> Self {
+        self.custom_drivers.push(driver);
+        self
+    }
+
+    /// The built-in merge drivers with the added ones in front.
+    pub fn merge_drivers(&self) -> MergeDrivers {
+        let mut drivers = MergeDrivers::builtin(self.section_split.clone());
+        for driver in &self.custom_drivers {
+            drivers.register(driver.clone());
+        }
+        drivers
+    }
+
+    /// Merges a file both sides changed since `base` with the driver for its
+    /// type. `MergeOutcome::Conflict` leaves it to a conflict copy.
+    pub fn merge_files(&self, path: &std::path::Path, base: &[u8], local: &[u8], remote: &[u8]) -> MergeOutcome {
+        self.merge_drivers().merge(path, base, local, remote)
+    }
+
+    /// Resolves a file changed here and on another device since `base`,
+    /// the version both last synced. Notes that differ only in frontmatter
+    /// scalars are settled key by key; otherwise the root's strategy
+    /// decides. Without a base there is nothing to merge against, so both
+    /// versions are kept.
+    pub fn resolve_conflict(
+        &self,
+        path: &Path,
+        base: Option<&[u8]>,
+        (local, local_content): (&FileMetadata, &[u8]),
+        (remote, remote_content): (&FileMetadata, &[u8]),
+    ) -> Resolution {
+        let remote_wins = Self::remote_wins(local, remote);
+        if crate::file_transfer::FileTransferManager::is_markdown_file(path) {
+            let resolved = Self::resolve_frontmatter_conflict(
+                &String::from_utf8_lossy(local_content),
+                &String::from_utf8_lossy(remote_content),
+                !remote_wins,
+                &self.frontmatter_policies,
+            );
+            if let Some(resolved) = resolved {
+                return Resolution::Merged(resolved.into_bytes());
+            }
+        }
+        let keep_both = || Resolution::KeepBoth { copy: crate::conflicts::copy_path(path, &self.conflict_label, self.conflict_layout) };
+        let drivers = self.merge_drivers();
+        match self.conflict_strategy {
+            ConflictStrategy::Merge if drivers.driver_for(path).is_some() => {
+                let Some(base) = base else {
+                    return keep_both();
+                };
+                match drivers.merge(path, base, local_content, remote_content) {
+                    MergeOutcome::Merged(merged) => Resolution::Merged(merged),
+                    MergeOutcome::Conflict => keep_both(),
+                }
+            }
+            ConflictStrategy::KeepBoth => keep_both(),
+            _ if remote_wins => Resolution::TakeRemote,
+            _ => Resolution::KeepLocal,
+        }
+    }
+
+    /// Whether the remote version of a file changed on both sides replaces
+    /// the local one. A version that includes the other's edits wins
+    /// whatever the clocks say; edits made concurrently, or without version
+    /// vectors to tell, go to the later modification time, local on a tie.
+    pub fn remote_wins(local: &FileMetadata, remote: &FileMetadata) -> bool {
+        match local.causality(remote) {
+            Causality::Before => true,
+            Causality::After | Causality::Equal => false,
+            Causality::Concurrent => {
+                tracing::info!("{:?} was edited concurrently here and elsewhere", local.path);
+                local.compare_modified(remote) == Ordering::Less
+            }
+        }
     }
 
     pub fn calculate_sync_operations(
@@ -24,15 +326,10 @@ impl SyncEngine {
         for (path, local_meta) in local_files {
             if let Some(remote_meta) = remote_files.get(path) {
                 // File exists on both sides, check if update is needed
-                if local_meta.hash != remote_meta.hash {
-                    // Conflict resolution: newer version wins based on timestamp
-                    if local_meta.modified > remote_meta.modified {
-                        operations.push(SyncOperation::Update(local_meta.clone()));
-                    } else if remote_meta.modified > local_meta.modified {
-                        operations.push(SyncOperation::Update(remote_meta.clone()));
-                    } else {
-                        // Same timestamp, prefer local version
-                        operations.push(SyncOperation::Update(local_meta.clone()));
+                if !local_meta.same_version(remote_meta) {
+                    match Self::remote_wins(local_meta, remote_meta) {
+                        true => operations.push(SyncOperation::Update(remote_meta.clone())),
+                        false => operations.push(SyncOperation::Update(local_meta.clone())),
                     }
                 }
             } else {
@@ -55,7 +352,7 @@ impl SyncEngine {
             }
         }
 
-        operations
+        detect_renames(operations, local_files)
     }
 
     pub async fn apply_sync_operation(
@@ -81,6 +378,12 @@ impl SyncEngine {
             SyncOperation::Delete(path) => {
                 local_files.remove(&path);
             }
+            SyncOperation::Rename { from, to } => {
+                local_files.remove(&from);
+                local_files.insert(to.path.clone(), to);
+            }
+            // Files are tracked here, directories follow from them
+            SyncOperation::MkDir(_) | SyncOperation::RmDir(_) => {}
         }
         Ok(())
     }
@@ -89,6 +392,25 @@ impl SyncEngine {
         local_content: &str,
         remote_content: &str,
         base_content: &str,
+    ) -> Result<String, SyncError> {
+        Self::merge_markdown_with(local_content, remote_content, base_content, false)
+    }
+
+    /// Like `merge_markdown_content`, but merges the body section by
+    /// section, see `sections.rs`.
+    pub fn merge_markdown_by_section(
+        local_content: &str,
+        remote_content: &str,
+        base_content: &str,
+    ) -> Result<String, SyncError> {
+        Self::merge_markdown_with(local_content, remote_content, base_content, true)
+    }
+
+    fn merge_markdown_with(
+        local_content: &str,
+        remote_content: &str,
+        base_content: &str,
+        by_section: bool,
     ) -> Result<String, SyncError> {
         // Simple 3-way merge for markdown files
         // Extract YAML frontmatter if present
@@ -104,7 +426,7 @@ impl SyncEngine {
         };
 
         // Merge body content using diff3
-        let merged_body = Self::merge_text_content(&local_body, &remote_body, &base_body)?;
+        let merged_body = Self::merge_text_content(&local_body, &remote_body, &base_body, by_section)?;
 
         // Reconstruct the file
         let mut result = String::new();
@@ -118,8 +440,8 @@ impl SyncEngine {
     }
 
     fn extract_frontmatter(content: &str) -> (String, String) {
-        if content.starts_with("---") {
-            if let Some(end_offset) = content[3..].find("---") {
+        if let Some(rest) = content.strip_prefix("---") {
+            if let Some(end_offset) = rest.find("---") {
                 let frontmatter_end = end_offset + 3;
                 let frontmatter = content[..frontmatter_end + 3].to_string();
                 let body = content[frontmatter_end + 3..].trim_start().to_string();
@@ -129,35 +451,18 @@ impl SyncEngine {
         (String::new(), content.to_string())
     }
 
-    fn merge_text_content(local: &str, remote: &str, base: &str) -> Result<String, SyncError> {
-        // Use simple merge for now (similar crate not available)
+    fn merge_text_content(local: &str, remote: &str, base: &str, by_section: bool) -> Result<String, SyncError> {
+        // Without a common ancestor there is nothing to merge against, keep
+        // both versions
         if base.is_empty() {
             return Ok(format!("{}\n\n{}", local, remote));
         }
 
-        // Simple conflict detection and resolution
-        let mut result = String::new();
-        
-        // Check if there are significant changes in both versions
-        let local_changes = local.lines().count() != base.lines().count();
-        let remote_changes = remote.lines().count() != base.lines().count();
-        
-        if local_changes && remote_changes {
-            // Both sides have changes, use conflict markers
-            result.push_str("<<<<<<< LOCAL\n");
-            result.push_str(local);
-            result.push_str("\n=======\n");
-            result.push_str(remote);
-            result.push_str("\n>>>>>>> REMOTE\n");
-        } else if remote_changes {
-            // Only remote has changes
-            result.push_str(remote);
-        } else {
-            // Only local has changes or no changes
-            result.push_str(local);
+        // Only regions both sides changed differently get conflict markers
+        if by_section {
+            return Ok(crate::sections::merge3(base, local, remote).text);
         }
-        
-        Ok(result)
+        Ok(crate::text_diff::merge3(base, local, remote).text)
     }
 
     pub fn calculate_bidirectional_sync(
@@ -171,17 +476,12 @@ impl SyncEngine {
         for (path, local_meta) in local_files {
             if let Some(remote_meta) = remote_files.get(path) {
                 // File exists on both sides
-                if local_meta.hash != remote_meta.hash {
-                    // Conflict resolution based on timestamps
-                    if local_meta.modified > remote_meta.modified {
-                        // Local is newer, push to remote
-                        remote_operations.push(SyncOperation::Update(local_meta.clone()));
-                    } else if remote_meta.modified > local_meta.modified {
+                if !local_meta.same_version(remote_meta) {
+                    match Self::remote_wins(local_meta, remote_meta) {
                         // Remote is newer, pull to local
-                        local_operations.push(SyncOperation::Update(remote_meta.clone()));
-                    } else {
-                        // Same timestamp, prefer local version
-                        remote_operations.push(SyncOperation::Update(local_meta.clone()));
+                        true => local_operations.push(SyncOperation::Update(remote_meta.clone())),
+                        // Local is newer or the same, push to remote
+                        false => remote_operations.push(SyncOperation::Update(local_meta.clone())),
                     }
                 }
             } else {
@@ -212,7 +512,7 @@ impl SyncEngine {
             }
         }
 
-        (local_operations, remote_operations)
+        self.direction.filter(local_operations, remote_operations)
     }
 
     pub fn merge_markdown_files_with_conflict_resolution(
@@ -223,25 +523,45 @@ impl SyncEngine {
         local_meta: &FileMetadata,
         remote_meta: &FileMetadata,
     ) -> Result<String, SyncError> {
+        // A version that includes the other's edits needs no merge
+        if !local_meta.versions.is_empty() && !remote_meta.versions.is_empty() {
+            match local_meta.causality(remote_meta) {
+                Causality::After => return Ok(local_content.to_string()),
+                Causality::Before => return Ok(remote_content.to_string()),
+                Causality::Equal | Causality::Concurrent => {}
+            }
+        }
+
+        // Editor plugins often only bump dates in the frontmatter
+        if let Some(resolved) = Self::resolve_frontmatter_conflict(
+            local_content,
+            remote_content,
+            local_meta.compare_modified(remote_meta) != Ordering::Less,
+            &self.frontmatter_policies,
+        ) {
+            return Ok(resolved);
+        }
+
         // Enhanced conflict resolution with multiple strategies
-        if local_meta.modified > remote_meta.modified {
+        let newer = local_meta.compare_modified(remote_meta);
+        if newer == Ordering::Greater {
             // Local is newer, but still try to merge if there are conflicts
             if Self::has_significant_changes(local_content, base_content) && 
                Self::has_significant_changes(remote_content, base_content) {
                 // Both sides have significant changes, attempt merge
-                match Self::merge_markdown_content(local_content, remote_content, base_content) {
+                match self.merge_markdown(&local_meta.path, local_content, remote_content, base_content) {
                     Ok(merged) => Ok(merged),
                     Err(_) => Ok(local_content.to_string()), // Fall back to local
                 }
             } else {
                 Ok(local_content.to_string())
             }
-        } else if remote_meta.modified > local_meta.modified {
+        } else if newer == Ordering::Less {
             // Remote is newer, but still try to merge if there are conflicts
             if Self::has_significant_changes(local_content, base_content) && 
                Self::has_significant_changes(remote_content, base_content) {
                 // Both sides have significant changes, attempt merge
-                match Self::merge_markdown_content(local_content, remote_content, base_content) {
+                match self.merge_markdown(&local_meta.path, local_content, remote_content, base_content) {
                     Ok(merged) => Ok(merged),
                     Err(_) => Ok(remote_content.to_string()), // Fall back to remote
                 }
@@ -250,8 +570,105 @@ impl SyncEngine {
             }
         } else {
             // Same timestamp, try to merge
-            Self::merge_markdown_content(local_content, remote_content, base_content)
+            self.merge_markdown(&local_meta.path, local_content, remote_content, base_content)
+        }
+    }
+
+    /// Merges a note, section by section if the section split matches it.
+    fn merge_markdown(&self, path: &std::path::Path, local: &str, remote: &str, base: &str) -> Result<String, SyncError> {
+        Self::merge_markdown_with(local, remote, base, self.section_split.applies_to(path))
+    }
+
+    /// Resolves a conflict where both versions have the same body and differ
+    /// only in frontmatter scalars, picking each differing key by policy.
+    /// Returns `None` when the conflict needs a real merge.
+    pub fn resolve_frontmatter_conflict(
+        local_content: &str,
+        remote_content: &str,
+        local_is_newer: bool,
+        policies: &BTreeMap<String, FrontmatterPolicy>,
+    ) -> Option<String> {
+        let (local_inner, local_rest) = Self::split_frontmatter(local_content)?;
+        let (remote_inner, remote_rest) = Self::split_frontmatter(remote_content)?;
+        if local_rest != remote_rest {
+            return None;
+        }
+
+        let local_entries = Self::frontmatter_entries(local_inner)?;
+        let remote_entries = Self::frontmatter_entries(remote_inner)?;
+        let mut lines: Vec<&str> = Vec::new();
+
+        for local in &local_entries {
+            let Some(remote) = remote_entries.iter().find(|r| r.key == local.key) else {
+                lines.extend(&local.lines);
+                continue;
+            };
+            if local.lines == remote.lines {
+                lines.extend(&local.lines);
+                continue;
+            }
+            let (local_value, remote_value) = (local.scalar_value()?, remote.scalar_value()?);
+            let take_local = match FrontmatterPolicy::for_key(local.key, policies) {
+                FrontmatterPolicy::Newest => local_is_newer,
+                FrontmatterPolicy::Local => true,
+                FrontmatterPolicy::Remote => false,
+                FrontmatterPolicy::Max => compare_scalars(local_value, remote_value).is_ge(),
+                FrontmatterPolicy::Min => compare_scalars(local_value, remote_value).is_le(),
+            };
+            lines.extend(if take_local { &local.lines } else { &remote.lines });
+        }
+
+        // Keys only one side added
+        for remote in &remote_entries {
+            if !local_entries.iter().any(|l| l.key == remote.key) {
+                remote.scalar_value()?;
+                lines.extend(&remote.lines);
+            }
+        }
+
+        let mut resolved = String::from("---\n");
+        for line in lines {
+            resolved.push_str(line);
+            resolved.push('\n');
         }
+        resolved.push_str(local_rest);
+        Some(resolved)
+    }
+
+    /// Splits `---\n...\n---` frontmatter into its inner lines and the rest
+    /// of the file, starting at the closing delimiter.
+    fn split_frontmatter(content: &str) -> Option<(&str, &str)> {
+        let inner_start = if content.starts_with("---\n") {
+            4
+        } else if content.starts_with("---\r\n") {
+            5
+        } else {
+            return None;
+        };
+        let mut offset = inner_start;
+        for line in content[inner_start..].split_inclusive('\n') {
+            if line.trim_end() == "---" {
+                return Some((&content[inner_start..offset], &content[offset..]));
+            }
+            offset += line.len();
+        }
+        None
+    }
+
+    fn frontmatter_entries(inner: &str) -> Option<Vec<FrontmatterEntry<'_>>> {
+        let mut entries: Vec<FrontmatterEntry> = Vec::new();
+        for line in inner.lines() {
+            let top_level = !line.starts_with([' ', '\t', '-', '#']) && !line.trim().is_empty();
+            if top_level {
+                let key = line.split_once(':')?.0.trim();
+                entries.push(FrontmatterEntry { key, lines: vec![line] });
+            } else {
+                // Comments and blank lines before the first key can't be
+                // attributed to either side's change
+                entries.last_mut()?.lines.push(line);
+            }
+        }
+        Some(entries)
     }
 
     fn has_significant_changes(content: &str, base: &str) -> bool {
@@ -282,6 +699,9 @@ impl SyncEngine {
                 SyncOperation::Add(meta) => report.push_str(&format!("  + Add: {:?}\n", meta.path)),
                 SyncOperation::Update(meta) => report.push_str(&format!("  * Update: {:?}\n", meta.path)),
                 SyncOperation::Delete(path) => report.push_str(&format!("  - Delete: {:?}\n", path)),
+                SyncOperation::Rename { from, to } => report.push_str(&format!("  > Rename: {:?} -> {:?}\n", from, to.path)),
+                SyncOperation::MkDir(path) => report.push_str(&format!("  + Directory: {:?}\n", path)),
+                SyncOperation::RmDir(path) => report.push_str(&format!("  - Directory: {:?}\n", path)),
             }
         }
         
@@ -291,9 +711,313 @@ impl SyncEngine {
                 SyncOperation::Add(meta) => report.push_str(&format!("  + Add: {:?}\n", meta.path)),
                 SyncOperation::Update(meta) => report.push_str(&format!("  * Update: {:?}\n", meta.path)),
                 SyncOperation::Delete(path) => report.push_str(&format!("  - Delete: {:?}\n", path)),
+                SyncOperation::Rename { from, to } => report.push_str(&format!("  > Rename: {:?} -> {:?}\n", from, to.path)),
+                SyncOperation::MkDir(path) => report.push_str(&format!("  + Directory: {:?}\n", path)),
+                SyncOperation::RmDir(path) => report.push_str(&format!("  - Directory: {:?}\n", path)),
             }
         }
         
         report
     }
-}
\ No newline at end of file
+}
+
+fn compare_scalars(a: &str, b: &str) -> std::cmp::Ordering {
+    let unquote = |v: &str| v.trim_matches(|c| c == '"' || c == '\'').to_string();
+    let (a, b) = (unquote(a), unquote(b));
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+        _ => a.cmp(&b),
+    }
+}
+
+/// Brings a share's directories up to date with a device's and returns what
+/// the device has to create or remove to match. `present` are the device's
+/// directories now and `known` the ones it had after its last sync, so the
+/// difference says which it created and which it removed since. Those it
+/// created change `shared` where the device may `write`, those it removed
+/// where it may `delete`. Of the rest, it is told about the
+/// ones it may `read`: to create those it lacks, and to remove those it
+/// still has that were removed elsewhere. Parents are created before their
+/// children and removed after them.
+pub fn sync_directories(
+    shared: &mut BTreeSet<PathBuf>,
+    present: &BTreeSet<PathBuf>,
+    known: &BTreeSet<PathBuf>,
+    write: impl Fn(&Path) -> bool,
+    delete: impl Fn(&Path) -> bool,
+    read: impl Fn(&Path) -> bool,
+) -> Vec<SyncOperation> {
+    for created in present.difference(known).filter(|path| write(path)) {
+        shared.insert(created.clone());
+    }
+    for removed in known.difference(present).filter(|path| delete(path)) {
+        shared.remove(removed);
+    }
+
+    let mut operations: Vec<SyncOperation> = shared
+        .iter()
+        .filter(|path| !present.contains(*path) && read(path))
+        .map(|path| SyncOperation::MkDir(path.clone()))
+        .collect();
+    operations.extend(
+        present
+            .iter()
+            .rev()
+            .filter(|path| known.contains(*path) && !shared.contains(*path) && read(path))
+            .map(|path| SyncOperation::RmDir(path.clone())),
+    );
+    operations
+}
+
+/// Pairs deletes with adds of the same content at a new path and replaces
+/// each pair with a rename. `removed` has the last known metadata of the
+/// deleted paths; deletes it doesn't cover are left alone.
+pub fn detect_renames(
+    operations: Vec<SyncOperation>,
+    removed: &HashMap<PathBuf, FileMetadata>,
+) -> Vec<SyncOperation> {
+    let mut deleted_by_hash: HashMap<&str, Vec<&PathBuf>> = HashMap::new();
+    for operation in &operations {
+        if let SyncOperation::Delete(path) = operation {
+            if let Some(metadata) = removed.get(path) {
+                deleted_by_hash.entry(metadata.hash.as_str()).or_default().push(path);
+            }
+        }
+    }
+    if deleted_by_hash.is_empty() {
+        return operations;
+    }
+    for paths in deleted_by_hash.values_mut() {
+        // Popped from the end, so the first path in order pairs first
+        paths.sort_by(|a, b| b.cmp(a));
+    }
+
+    let mut renamed_from = std::collections::HashSet::new();
+    let mut renames = HashMap::new();
+    for operation in &operations {
+        let SyncOperation::Add(metadata) = operation else {
+            continue;
+        };
+        if removed.contains_key(&metadata.path) {
+            continue;
+        }
+        let Some(paths) = deleted_by_hash
+            .get_mut(metadata.hash.as_str())
+            .filter(|paths| !paths.is_empty())
+        else {
+            continue;
+        };
+        // A file with the same name moved to another directory is the
+        // likeliest source when several have the same content
+        let position = paths
+            .iter()
+            .position(|path| path.file_name() == metadata.path.file_name())
+            .unwrap_or(paths.len() - 1);
+        let from = paths.remove(position).clone();
+        renamed_from.insert(from.clone());
+        renames.insert(metadata.path.clone(), from);
+    }
+
+    operations
+        .into_iter()
+        .filter_map(|operation| match operation {
+            SyncOperation::Delete(path) if renamed_from.contains(&path) => None,
+            SyncOperation::Add(metadata) => match renames.remove(&metadata.path) {
+                Some(from) => Some(SyncOperation::Rename { from, to: metadata }),
+                None => Some(SyncOperation::Add(metadata)),
+            },
+            operation => Some(operation),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frontmatter_only_conflict() {
+        let local = "---\ntitle: Note\nmodified: 2024-05-02T10:00\nstatus: draft\n---\n\nBody\n";
+        let remote = "---\ntitle: Note\nmodified: 2024-05-01T09:00\nstatus: done\nrating: 3\n---\n\nBody\n";
+        let policies = BTreeMap::new();
+
+        let resolved = SyncEngine::resolve_frontmatter_conflict(local, remote, false, &policies).unwrap();
+        assert_eq!(
+            resolved,
+            "---\ntitle: Note\nmodified: 2024-05-02T10:00\nstatus: done\nrating: 3\n---\n\nBody\n"
+        );
+
+        let policies = BTreeMap::from([("status".to_string(), FrontmatterPolicy::Local)]);
+        let resolved = SyncEngine::resolve_frontmatter_conflict(local, remote, false, &policies).unwrap();
+        assert!(resolved.contains("status: draft"));
+
+        let edited_body = remote.replace("Body", "Changed");
+        assert!(SyncEngine::resolve_frontmatter_conflict(local, &edited_body, false, &policies).is_none());
+        let list_changed = "---\ntitle: Note\ntags:\n  - a\n---\n\nBody\n";
+        let list_other = "---\ntitle: Note\ntags:\n  - b\n---\n\nBody\n";
+        assert!(SyncEngine::resolve_frontmatter_conflict(list_changed, list_other, true, &policies).is_none());
+    }
+
+    #[test]
+    fn test_sync_directions() {
+        let file = |path: &str, content: &str| FileMetadata::for_test(path, content.as_bytes());
+        let local = HashMap::from([(PathBuf::from("mine.md"), file("mine.md", "a"))]);
+        let remote = HashMap::from([(PathBuf::from("theirs.md"), file("theirs.md", "b"))]);
+        let counts = |direction| {
+            let (local_ops, remote_ops) = SyncEngine::new("device".to_string())
+                .with_direction(direction)
+                .calculate_bidirectional_sync(&local, &remote);
+            let deletes = remote_ops.iter().filter(|op| matches!(op, SyncOperation::Delete(_))).count();
+            (local_ops.len(), remote_ops.len(), deletes)
+        };
+
+        assert_eq!(counts(SyncDirection::Both), (2, 2, 1));
+        assert_eq!(counts(SyncDirection::PushOnly), (0, 2, 1));
+        assert_eq!(counts(SyncDirection::PullOnly), (2, 0, 0));
+        assert_eq!(counts(SyncDirection::Backup), (0, 1, 0));
+
+        let engine = SyncEngine::new("device".to_string()).with_direction(SyncDirection::Backup);
+        assert!(engine.incoming_operations(vec![SyncOperation::Add(file("theirs.md", "b"))]).is_empty());
+    }
+
+    #[test]
+    fn test_version_vectors_decide_over_clocks() {
+        let now = crate::types::Timestamp::now();
+        let file = |content: &str, modified_ago: i64, edits: &[&str]| {
+            let mut versions = crate::types::VersionVector::default();
+            edits.iter().for_each(|device| versions.bump(device));
+            FileMetadata {
+                modified: crate::types::Timestamp::from_millis(now.as_millis() - modified_ago),
+                versions,
+                ..FileMetadata::for_test("note.md", content.as_bytes())
+            }
+        };
+
+        // The phone's clock is an hour behind, but its edit followed ours
+        let local = file("a", 0, &["laptop"]);
+        let remote = file("b", 3_600_000, &["laptop", "phone"]);
+        assert_eq!(local.causality(&remote), Causality::Before);
+        assert!(SyncEngine::remote_wins(&local, &remote));
+        assert!(!SyncEngine::remote_wins(&remote, &local));
+
+        // Edited on both sides, the later edit wins
+        let local = file("a", 0, &["laptop", "laptop"]);
+        assert_eq!(local.causality(&remote), Causality::Concurrent);
+        assert!(!SyncEngine::remote_wins(&local, &remote));
+
+        // Without vectors the clocks decide, as before
+        let legacy = file("c", 3_600_000, &[]);
+        assert_eq!(local.causality(&legacy), Causality::After);
+
+        let engine = SyncEngine::new("laptop".to_string());
+        let local = file("a", 0, &["laptop"]);
+        let merged = engine
+            .merge_markdown_files_with_conflict_resolution("mine\n", "theirs\n", "base\n", &local, &remote)
+            .unwrap();
+        assert_eq!(merged, "theirs\n");
+    }
+
+    #[test]
+    fn test_resolve_conflict() {
+        let file = |path: &str, edits: &[&str]| {
+            let mut versions = crate::types::VersionVector::default();
+            edits.iter().for_each(|device| versions.bump(device));
+            FileMetadata { versions, ..FileMetadata::for_test(path, b"") }
+        };
+        let (base, local, remote) = (b"a\nb\nc\n", b"A\nb\nc\n", b"a\nb\nC\n");
+        let path = Path::new("notes/todo.md");
+        let (mine, theirs) = (file("notes/todo.md", &["laptop", "laptop"]), file("notes/todo.md", &["laptop", "phone"]));
+        let resolve = |engine: &SyncEngine, path: &Path, local: &[u8], remote: &[u8]| {
+            engine.resolve_conflict(path, Some(base), (&mine, local), (&theirs, remote))
+        };
+
+        let newest = SyncEngine::new("laptop".to_string());
+        let expected = match SyncEngine::remote_wins(&mine, &theirs) {
+            true => Resolution::TakeRemote,
+            false => Resolution::KeepLocal,
+        };
+        assert_eq!(resolve(&newest, path, local, remote), expected);
+
+        let merge = SyncEngine::new("laptop".to_string())
+            .with_conflict_strategy(ConflictStrategy::Merge, "laptop", ConflictLayout::Sibling);
+        assert_eq!(resolve(&merge, path, local, remote), Resolution::Merged(b"A\nb\nC\n".to_vec()));
+        // Settings changed both ways don't merge
+        let settings = Path::new("settings.json");
+        let resolved = merge.resolve_conflict(settings, Some(br#"{"a":1}"#), (&mine, br#"{"a":2}"#), (&theirs, br#"{"a":3}"#));
+        assert_eq!(resolved, Resolution::KeepBoth { copy: PathBuf::from("settings.conflict-laptop.json") });
+        // Never synced before, there is nothing to merge against
+        let copy = PathBuf::from("notes/todo.conflict-laptop.md");
+        assert_eq!(merge.resolve_conflict(path, None, (&mine, local), (&theirs, remote)), Resolution::KeepBoth { copy: copy.clone() });
+        // Files without a merge driver fall back to the newest version
+        assert_eq!(resolve(&merge, Path::new("photo.bin"), local, remote), expected);
+
+        let keep_both = SyncEngine::new("laptop".to_string())
+            .with_conflict_strategy(ConflictStrategy::KeepBoth, "laptop", ConflictLayout::Sibling);
+        assert_eq!(resolve(&keep_both, path, local, remote), Resolution::KeepBoth { copy });
+    }
+
+    #[test]
+    fn test_sync_directories() {
+        let set = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<BTreeSet<_>>();
+        // Another device created "archive" and removed "projects"
+        let mut shared = set(&["archive", "notes", "notes/old"]);
+
+        // This one created "drafts", removed "notes/old" and still has
+        // "projects"
+        let present = set(&["drafts", "notes", "projects"]);
+        let known = set(&["notes", "notes/old", "projects"]);
+        let operations = sync_directories(&mut shared, &present, &known, |_| true, |_| true, |_| true);
+        assert_eq!(shared, set(&["archive", "drafts", "notes"]));
+        let paths: Vec<String> = operations.iter().map(|operation| match operation {
+            SyncOperation::MkDir(path) => format!("+{}", path.display()),
+            SyncOperation::RmDir(path) => format!("-{}", path.display()),
+            other => panic!("unexpected {:?}", other),
+        }).collect();
+        assert_eq!(paths, ["+archive", "-projects"]);
+
+        // A device that may only read can't remove what others have
+        let mut shared = set(&["notes", "notes/old"]);
+        let operations = sync_directories(&mut shared, &set(&["notes", "tmp"]), &set(&["notes", "notes/old"]), |_| false, |_| false, |_| true);
+        assert_eq!(shared, set(&["notes", "notes/old"]));
+        assert!(matches!(&operations[..], [SyncOperation::MkDir(path)] if path == Path::new("notes/old")));
+
+        // Nor can one that may write but not delete
+        let operations = sync_directories(&mut shared, &set(&["notes", "tmp"]), &set(&["notes", "notes/old"]), |_| true, |_| false, |_| true);
+        assert_eq!(shared, set(&["notes", "notes/old", "tmp"]));
+        assert!(matches!(&operations[..], [SyncOperation::MkDir(path)] if path == Path::new("notes/old")));
+    }
+
+    #[test]
+    fn test_detect_renames() {
+        let file = |path: &str, content: &str| FileMetadata::for_test(path, content.as_bytes());
+        let removed = HashMap::from([
+            (PathBuf::from("old.md"), file("old.md", "a")),
+            (PathBuf::from("inbox/todo.md"), file("inbox/todo.md", "b")),
+            (PathBuf::from("copy.md"), file("copy.md", "b")),
+            (PathBuf::from("gone.md"), file("gone.md", "c")),
+        ]);
+        let operations = vec![
+            SyncOperation::Delete(PathBuf::from("old.md")),
+            SyncOperation::Delete(PathBuf::from("inbox/todo.md")),
+            SyncOperation::Delete(PathBuf::from("copy.md")),
+            SyncOperation::Delete(PathBuf::from("gone.md")),
+            SyncOperation::Add(file("new.md", "a")),
+            SyncOperation::Add(file("done/todo.md", "b")),
+            SyncOperation::Add(file("fresh.md", "d")),
+        ];
+
+        let operations = detect_renames(operations, &removed);
+        let renames: Vec<(&PathBuf, &PathBuf)> = operations.iter()
+            .filter_map(|operation| match operation {
+                SyncOperation::Rename { from, to } => Some((from, &to.path)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(renames, vec![
+            (&PathBuf::from("old.md"), &PathBuf::from("new.md")),
+            (&PathBuf::from("inbox/todo.md"), &PathBuf::from("done/todo.md")),
+        ]);
+        assert_eq!(operations.len(), 5);
+        assert!(operations.iter().any(|op| matches!(op, SyncOperation::Delete(path) if path == std::path::Path::new("copy.md"))));
+    }
+}
@@ -0,0 +1,184 @@
+#![allow(dead_code)]
+
+//! Named snapshots of a sync root, milestones like `v1.0-launch` on top of
+//! the journal. A tag is a snapshot of the index at a journal point that
+//! compaction keeps. The content of every tagged file is copied into a
+//! store under the state directory, named by its hash, so a tag can still
+//! be restored or exported after its files changed. Tags share the copies
+//! of files they have in common.
+
+use crate::index_store::{IndexStore, Tag};
+use crate::indexer::FileIndexer;
+use crate::types::{FileMetadata, SyncError};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Under the state directory, one file per tagged content hash
+pub const OBJECTS_DIR_NAME: &str = "tag-objects";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Only in the current state
+    Added,
+    /// Only in the tag
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: usize,
+    /// Files whose tagged content is nowhere to be found
+    pub missing: Vec<PathBuf>,
+}
+
+fn object_path(sync_root: &Path, hash: &str) -> PathBuf {
+    crate::state::dir(sync_root).join(OBJECTS_DIR_NAME).join(hash)
+}
+
+/// Tags the root's current state, indexing it first so the tag has the
+/// latest changes. Returns the tag and how many files had to be copied.
+pub fn create(indexer: &FileIndexer, name: &str) -> Result<(Tag, usize), SyncError> {
+    let sync_root = indexer.sync_root().as_path();
+    let state = indexer.index_directory()?;
+    let mut store = IndexStore::open(sync_root)?;
+    store.save_state(&state)?;
+    let tag = store.tag(name)?;
+
+    let mut copied = 0;
+    for (path, metadata) in store.load_snapshot(tag.snapshot.id)? {
+        let object = object_path(sync_root, &metadata.hash);
+        if object.exists() {
+            continue;
+        }
+        std::fs::create_dir_all(object.parent().unwrap_or(sync_root))?;
+        // Copied aside first, a file cut off mid-copy must not pass for content
+        let partial = object.with_extension("partial");
+        std::fs::copy(indexer.local_path(&path), &partial)?;
+        std::fs::rename(&partial, &object)?;
+        copied += 1;
+    }
+    Ok((tag, copied))
+}
+
+/// The files of the tag called `name`.
+pub fn files(store: &IndexStore, name: &str) -> Result<HashMap<PathBuf, FileMetadata>, SyncError> {
+    let tag = store.find_tag(name)?.ok_or_else(|| SyncError::Index(format!("No tag named {}", name)))?;
+    store.load_snapshot(tag.snapshot.id)
+}
+
+/// How `current` differs from `tagged`, by path.
+pub fn diff(
+    tagged: &HashMap<PathBuf, FileMetadata>,
+    current: &HashMap<PathBuf, FileMetadata>,
+) -> BTreeMap<PathBuf, Change> {
+    let mut changes = BTreeMap::new();
+    for (path, metadata) in current {
+        match tagged.get(path) {
+            None => {
+                changes.insert(path.clone(), Change::Added);
+            }
+            Some(old) if old.hash != metadata.hash => {
+                changes.insert(path.clone(), Change::Changed);
+            }
+            Some(_) => {}
+        }
+    }
+    for path in tagged.keys().filter(|path| !current.contains_key(*path)) {
+        changes.insert(path.clone(), Change::Removed);
+    }
+    changes
+}
+
+/// The tagged content of a file: its copy, or the file itself while it
+/// hasn't changed since.
+fn content_path(indexer: &FileIndexer, store: &IndexStore, path: &Path, metadata: &FileMetadata) -> Result<Option<PathBuf>, SyncError> {
+    let object = object_path(indexer.sync_root(), &metadata.hash);
+    if object.is_file() {
+        return Ok(Some(object));
+    }
+    let unchanged = store.get(path)?.is_some_and(|current| current.hash == metadata.hash);
+    let local = indexer.local_path(path);
+    Ok((unchanged && local.is_file()).then_some(local))
+}
+
+/// Writes the files of the tag called `name` into `target`, a new folder.
+pub fn restore(indexer: &FileIndexer, name: &str, target: &Path) -> Result<RestoreReport, SyncError> {
+    if target.exists() && std::fs::read_dir(target)?.next().is_some() {
+        return Err(SyncError::Index(format!("{} is not empty, restore into a new folder", target.display())));
+    }
+    let store = IndexStore::open(indexer.sync_root())?;
+    let mut report = RestoreReport::default();
+    for (path, metadata) in files(&store, name)? {
+        let Some(source) = content_path(indexer, &store, &path, &metadata)? else {
+            report.missing.push(path);
+            continue;
+        };
+        let destination = target.join(&path);
+        std::fs::create_dir_all(destination.parent().unwrap_or(target))?;
+        std::fs::copy(source, destination)?;
+        report.restored += 1;
+    }
+    report.missing.sort();
+    Ok(report)
+}
+
+/// Writes the files of the tag called `name` into a tar archive at
+/// `output`. Returns how many files it holds.
+pub fn export(indexer: &FileIndexer, name: &str, output: &Path) -> Result<usize, SyncError> {
+    let store = IndexStore::open(indexer.sync_root())?;
+    let files: BTreeMap<PathBuf, FileMetadata> = files(&store, name)?.into_iter().collect();
+    let mut builder = tar::Builder::new(std::fs::File::create(output)?);
+    let mut exported = 0;
+    for (path, metadata) in &files {
+        if let Some(source) = content_path(indexer, &store, path, metadata)? {
+            builder.append_path_with_name(source, path)?;
+            exported += 1;
+        }
+    }
+    builder.finish()?;
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_survives_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("notes");
+        std::fs::create_dir_all(root.join("ideas")).unwrap();
+        std::fs::write(root.join("plan.md"), "launch on monday").unwrap();
+        std::fs::write(root.join("ideas/a.md"), "idea").unwrap();
+        let indexer = FileIndexer::new("laptop".to_string(), root.clone());
+
+        let (tag, copied) = create(&indexer, "v1.0-launch").unwrap();
+        assert_eq!((tag.snapshot.file_count, copied), (2, 2));
+        assert!(create(&indexer, "v1.0-launch").is_err());
+
+        std::fs::write(root.join("plan.md"), "launch on tuesday").unwrap();
+        std::fs::remove_file(root.join("ideas/a.md")).unwrap();
+        std::fs::write(root.join("new.md"), "new").unwrap();
+        let mut store = IndexStore::open(&root).unwrap();
+        store.save_state(&indexer.index_directory().unwrap()).unwrap();
+        // Compaction thins out snapshots, but not tagged ones
+        store.compact(chrono::Duration::zero()).unwrap();
+        assert_eq!(store.tags().unwrap().len(), 1);
+
+        let current = store.load_state("laptop".to_string(), root.clone()).unwrap().local_files;
+        let changes: Vec<(PathBuf, Change)> = diff(&files(&store, "v1.0-launch").unwrap(), &current).into_iter().collect();
+        assert_eq!(changes, [
+            (PathBuf::from("ideas/a.md"), Change::Removed),
+            (PathBuf::from("new.md"), Change::Added),
+            (PathBuf::from("plan.md"), Change::Changed),
+        ]);
+
+        let target = dir.path().join("restored");
+        let report = restore(&indexer, "v1.0-launch", &target).unwrap();
+        assert_eq!(report.restored, 2);
+        assert_eq!(std::fs::read_to_string(target.join("plan.md")).unwrap(), "launch on monday");
+        assert_eq!(std::fs::read_to_string(target.join("ideas/a.md")).unwrap(), "idea");
+        assert!(restore(&indexer, "v1.0-launch", &target).is_err());
+    }
+}
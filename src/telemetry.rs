@@ -0,0 +1,254 @@
+#![allow(dead_code)]
+
+//! Opt-in reliability metrics. Nothing is collected until the user runs
+//! `syncmd telemetry enable`; from then on the device counts sync rounds
+//! and conflicts, and the daemon posts the totals once a day to the
+//! configured endpoint. Reports carry the counters and the version only, no
+//! device ids, names or paths, and `syncmd telemetry status` shows the
+//! report exactly as it would be sent.
+
+use crate::types::SyncError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+const COUNTERS_FILE_NAME: &str = "telemetry.json";
+/// How often the daemon sends a report
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serializes updates to the counters file between the daemon's roots
+static COUNTERS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetrySettings {
+    pub enabled: bool,
+    /// Plain `http://` URL reports are posted to
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counters {
+    pub syncs_succeeded: u64,
+    pub syncs_failed: u64,
+    pub conflicts: u64,
+}
+
+/// What is kept on disk between reports.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collected {
+    /// Start of the current period
+    pub since: DateTime<Utc>,
+    pub last_sent: Option<DateTime<Utc>>,
+    pub counters: Counters,
+}
+
+/// Everything a report contains.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub version: String,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub syncs_succeeded: u64,
+    pub syncs_failed: u64,
+    pub conflicts: u64,
+    /// Conflicts per successful sync
+    pub conflict_rate: f64,
+}
+
+impl Collected {
+    fn new() -> Self {
+        Self { since: Utc::now(), last_sent: None, counters: Counters::default() }
+    }
+
+    pub fn report(&self) -> Report {
+        let counters = &self.counters;
+        Report {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            period_start: self.since,
+            period_end: Utc::now(),
+            syncs_succeeded: counters.syncs_succeeded,
+            syncs_failed: counters.syncs_failed,
+            conflicts: counters.conflicts,
+            conflict_rate: match counters.syncs_succeeded {
+                0 => 0.0,
+                syncs => counters.conflicts as f64 / syncs as f64,
+            },
+        }
+    }
+
+    /// Whether a report is due, a day after the last one or after collecting
+    /// started.
+    pub fn is_due(&self) -> bool {
+        let last = self.last_sent.unwrap_or(self.since);
+        Utc::now() - last >= chrono::Duration::from_std(REPORT_INTERVAL).unwrap_or(chrono::Duration::MAX)
+    }
+}
+
+fn counters_path() -> Result<PathBuf, SyncError> {
+    let config_dir = dirs::config_dir()
+        .ok_or_else(|| SyncError::NotFound(PathBuf::from("config directory")))?;
+    Ok(config_dir.join("syncmd").join(COUNTERS_FILE_NAME))
+}
+
+/// What was collected since the last report, `None` while telemetry is off.
+pub fn load() -> Result<Option<Collected>, SyncError> {
+    match std::fs::read(counters_path()?) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(collected: &Collected) -> Result<(), SyncError> {
+    let path = counters_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(collected)?)?;
+    Ok(())
+}
+
+/// Starts collecting, keeping counters collected before.
+pub fn start() -> Result<(), SyncError> {
+    let _lock = COUNTERS_LOCK.lock().unwrap();
+    if load()?.is_none() {
+        save(&Collected::new())?;
+    }
+    Ok(())
+}
+
+/// Stops collecting and drops everything collected.
+pub fn stop() -> Result<(), SyncError> {
+    let _lock = COUNTERS_LOCK.lock().unwrap();
+    match std::fs::remove_file(counters_path()?) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Updates the counters if telemetry is on. Never fails a sync: errors are
+/// only logged.
+pub fn record(change: impl FnOnce(&mut Counters)) {
+    let _lock = COUNTERS_LOCK.lock().unwrap();
+    let result = load().and_then(|collected| match collected {
+        Some(mut collected) => {
+            change(&mut collected.counters);
+            save(&collected)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        tracing::debug!("Could not record telemetry: {}", e);
+    }
+}
+
+/// Sends the report if one is due and starts a new period. Returns whether
+/// a report was sent.
+pub async fn send_if_due(settings: &TelemetrySettings) -> Result<bool, SyncError> {
+    let Some(collected) = load()? else {
+        return Ok(false);
+    };
+    if !settings.enabled || !collected.is_due() {
+        return Ok(false);
+    }
+    let report = collected.report();
+    send(&settings.endpoint, &report).await?;
+
+    let _lock = COUNTERS_LOCK.lock().unwrap();
+    // Rounds that finished while sending belong to the next period
+    let mut next = load()?.unwrap_or_else(Collected::new);
+    next.counters.syncs_succeeded = next.counters.syncs_succeeded.saturating_sub(report.syncs_succeeded);
+    next.counters.syncs_failed = next.counters.syncs_failed.saturating_sub(report.syncs_failed);
+    next.counters.conflicts = next.counters.conflicts.saturating_sub(report.conflicts);
+    next.since = report.period_end;
+    next.last_sent = Some(report.period_end);
+    save(&next)?;
+    Ok(true)
+}
+
+/// Checks that `endpoint` is a URL reports can be posted to.
+pub fn parse_endpoint(endpoint: &str) -> Result<url::Url, SyncError> {
+    let url = url::Url::parse(endpoint)
+        .map_err(|e| SyncError::Config(format!("Invalid telemetry endpoint {}: {}", endpoint, e)))?;
+    if url.scheme() != "http" || url.host_str().is_none() {
+        return Err(SyncError::Config(format!(
+            "Telemetry endpoints must be http:// URLs, e.g. a collector on the local network: {}",
+            endpoint
+        )));
+    }
+    Ok(url)
+}
+
+/// Posts `report` as JSON and waits for a 2xx answer.
+pub async fn send(endpoint: &str, report: &Report) -> Result<(), SyncError> {
+    let url = parse_endpoint(endpoint)?;
+    let host = url.host_str().unwrap_or_default();
+    let address = format!("{}:{}", host, url.port_or_known_default().unwrap_or(80));
+    let body = serde_json::to_string(report)?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path(),
+        host,
+        body.len(),
+        body
+    );
+
+    let exchange = async {
+        let mut stream = tokio::net::TcpStream::connect(&address).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut status_line = String::new();
+        BufReader::new(&mut stream).read_line(&mut status_line).await?;
+        Ok::<_, SyncError>(status_line)
+    };
+    let status_line = tokio::time::timeout(SEND_TIMEOUT, exchange)
+        .await
+        .map_err(|_| SyncError::Network(format!("Telemetry endpoint {} didn't answer", endpoint)))??;
+    let accepted = status_line.split_whitespace().nth(1).is_some_and(|code| code.starts_with('2'));
+    if !accepted {
+        return Err(SyncError::Network(format!("Telemetry endpoint refused the report: {}", status_line.trim())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_report_is_aggregate_only() {
+        let collected = Collected {
+            since: Utc::now() - chrono::Duration::days(2),
+            last_sent: None,
+            counters: Counters { syncs_succeeded: 40, syncs_failed: 2, conflicts: 1 },
+        };
+        assert!(collected.is_due());
+        let report = collected.report();
+        assert_eq!(report.conflict_rate, 0.025);
+        let json = serde_json::to_value(&report).unwrap();
+        let mut keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, [
+            "conflict_rate", "conflicts", "period_end", "period_start",
+            "syncs_failed", "syncs_succeeded", "version",
+        ]);
+
+        assert!(parse_endpoint("https://metrics.example.com/syncmd").is_err());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/reports", listener.local_addr().unwrap());
+        let collector = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = vec![0u8; 4096];
+            let n = tokio::io::AsyncReadExt::read(&mut stream, &mut received).await.unwrap();
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8_lossy(&received[..n]).to_string()
+        });
+        send(&endpoint, &report).await.unwrap();
+        let request = collector.await.unwrap();
+        assert!(request.starts_with("POST /reports HTTP/1.1\r\n"));
+        assert!(request.ends_with(&serde_json::to_string(&report).unwrap()));
+    }
+}
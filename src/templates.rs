@@ -0,0 +1,134 @@
+#![allow(dead_code)]
+
+use crate::ignore::IGNORE_FILE_NAME;
+use crate::sync::ConflictStrategy;
+use crate::types::{FileCategory, SyncError};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Starter layouts for a new sync root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RootTemplate {
+    /// Atomic notes with an inbox and references
+    Zettelkasten,
+    /// One folder per project, with active/someday/archive stages
+    Projects,
+    /// Daily and weekly journal entries
+    Journal,
+}
+
+/// Per-root policies a template sets up.
+#[derive(Debug, Clone)]
+pub struct TemplatePolicies {
+    pub conflict_strategy: ConflictStrategy,
+    pub categories: Vec<FileCategory>,
+}
+
+impl RootTemplate {
+    pub fn label(&self) -> &'static str {
+        match self {
+            RootTemplate::Zettelkasten => "zettelkasten",
+            RootTemplate::Projects => "projects",
+            RootTemplate::Journal => "journal",
+        }
+    }
+
+    fn directories(&self) -> &'static [&'static str] {
+        match self {
+            RootTemplate::Zettelkasten => &["inbox", "notes", "references", "attachments"],
+            RootTemplate::Projects => &["active", "someday", "archive", "templates"],
+            RootTemplate::Journal => &["daily", "weekly", "attachments"],
+        }
+    }
+
+    fn files(&self) -> Vec<(&'static str, String)> {
+        match self {
+            RootTemplate::Zettelkasten => vec![
+                ("index.md", "# Index\n\nEntry points into the slip box.\n".to_string()),
+                ("inbox/README.md", "# Inbox\n\nFleeting notes land here until they are processed.\n".to_string()),
+            ],
+            RootTemplate::Projects => vec![
+                ("README.md", "# Projects\n\nMove project folders between active/, someday/ and archive/.\n".to_string()),
+                ("templates/project.md", "---\nstatus: active\ndue:\n---\n\n# Project\n\n## Goal\n\n## Next actions\n".to_string()),
+            ],
+            RootTemplate::Journal => {
+                let today = chrono::Local::now().format("%Y-%m-%d");
+                vec![
+                    ("README.md", "# Journal\n\nOne file per day in daily/, weekly reviews in weekly/.\n".to_string()),
+                    ("daily/{today}.md", format!("# {today}\n\n")),
+                ]
+            }
+        }
+    }
+
+    fn ignore_patterns(&self) -> &'static [&'static str] {
+        match self {
+            RootTemplate::Zettelkasten | RootTemplate::Journal => &["*.tmp", "*~"],
+            RootTemplate::Projects => &["*.tmp", "*~", "*.log", "node_modules/", "target/", "build/"],
+        }
+    }
+
+    pub fn policies(&self) -> TemplatePolicies {
+        match self {
+            RootTemplate::Zettelkasten => TemplatePolicies {
+                conflict_strategy: ConflictStrategy::Merge,
+                categories: vec![FileCategory::Text, FileCategory::Image],
+            },
+            RootTemplate::Projects => TemplatePolicies {
+                conflict_strategy: ConflictStrategy::KeepBoth,
+                categories: vec![
+                    FileCategory::Text,
+                    FileCategory::Image,
+                    FileCategory::Document,
+                    FileCategory::Data,
+                ],
+            },
+            RootTemplate::Journal => TemplatePolicies {
+                conflict_strategy: ConflictStrategy::Merge,
+                categories: vec![FileCategory::Text, FileCategory::Image],
+            },
+        }
+    }
+
+    /// Creates the template's folders, starter files and `.syncignore` under
+    /// `root`. Existing files are never overwritten. Returns what was created.
+    pub fn apply(&self, root: &Path) -> Result<Vec<PathBuf>, SyncError> {
+        let mut created = Vec::new();
+        std::fs::create_dir_all(root)?;
+
+        for dir in self.directories() {
+            let path = root.join(dir);
+            if !path.exists() {
+                std::fs::create_dir_all(&path)?;
+                created.push(PathBuf::from(dir));
+            }
+        }
+
+        let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+        for (relative, content) in self.files() {
+            let relative = relative.replace("{today}", &today);
+            let path = root.join(&relative);
+            if !path.exists() {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, content)?;
+                created.push(PathBuf::from(relative));
+            }
+        }
+
+        let ignore_path = root.join(IGNORE_FILE_NAME);
+        if !ignore_path.exists() {
+            let mut content = format!("# Created by syncmd init --template {}\n", self.label());
+            for pattern in self.ignore_patterns() {
+                content.push_str(pattern);
+                content.push('\n');
+            }
+            std::fs::write(&ignore_path, content)?;
+            created.push(PathBuf::from(IGNORE_FILE_NAME));
+        }
+
+        Ok(created)
+    }
+}
@@ -0,0 +1,248 @@
+#![allow(dead_code)]
+
+/// Lines of unchanged context around each hunk.
+pub const DEFAULT_CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineChange<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Line-level differences between two texts.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<LineChange<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    diff::slice(&old, &new)
+        .into_iter()
+        .map(|change| match change {
+            diff::Result::Both(line, _) => LineChange::Same(line),
+            diff::Result::Left(line) => LineChange::Removed(line),
+            diff::Result::Right(line) => LineChange::Added(line),
+        })
+        .collect()
+}
+
+/// Unified diff in the format `diff -u` prints, empty when the texts are
+/// equal.
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str, context: usize) -> String {
+    let changes = diff_lines(old, new);
+    if changes.iter().all(|c| matches!(c, LineChange::Same(_))) {
+        return String::new();
+    }
+
+    let mut output = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for (start, end) in hunk_ranges(&changes, context) {
+        // Line numbers where the hunk starts on each side
+        let (mut old_line, mut new_line) = (1, 1);
+        for change in &changes[..start] {
+            match change {
+                LineChange::Same(_) => {
+                    old_line += 1;
+                    new_line += 1;
+                }
+                LineChange::Removed(_) => old_line += 1,
+                LineChange::Added(_) => new_line += 1,
+            }
+        }
+
+        let hunk = &changes[start..end];
+        let old_count = hunk.iter().filter(|c| !matches!(c, LineChange::Added(_))).count();
+        let new_count = hunk.iter().filter(|c| !matches!(c, LineChange::Removed(_))).count();
+        output.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_position(old_line, old_count),
+            hunk_position(new_line, new_count)
+        ));
+        for change in hunk {
+            let (prefix, line) = match change {
+                LineChange::Same(line) => (' ', line),
+                LineChange::Removed(line) => ('-', line),
+                LineChange::Added(line) => ('+', line),
+            };
+            output.push(prefix);
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Ranges of `changes` to print: every change plus `context` lines around
+/// it, with overlapping ranges merged.
+fn hunk_ranges(changes: &[LineChange], context: usize) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (index, change) in changes.iter().enumerate() {
+        if matches!(change, LineChange::Same(_)) {
+            continue;
+        }
+        let start = index.saturating_sub(context);
+        let end = (index + context + 1).min(changes.len());
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
+fn hunk_position(line: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", line - 1),
+        1 => line.to_string(),
+        _ => format!("{},{}", line, count),
+    }
+}
+
+/// Outcome of a three-way merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merge {
+    pub text: String,
+    /// Regions both sides changed differently, left between conflict markers
+    pub conflicts: usize,
+}
+
+/// A run of base lines `start..end` that one side replaced with `lines`.
+#[derive(Debug)]
+struct Hunk<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a str>,
+}
+
+fn hunks<'a>(base: &[&'a str], changed: &[&'a str]) -> Vec<Hunk<'a>> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut position = 0;
+    let mut open: Option<Hunk> = None;
+    for change in diff::slice(base, changed) {
+        match change {
+            diff::Result::Both(_, _) => {
+                hunks.extend(open.take());
+                position += 1;
+            }
+            diff::Result::Left(_) => {
+                open.get_or_insert(Hunk { start: position, end: position, lines: Vec::new() }).end += 1;
+                position += 1;
+            }
+            diff::Result::Right(line) => {
+                open.get_or_insert(Hunk { start: position, end: position, lines: Vec::new() }).lines.push(line);
+            }
+        }
+    }
+    hunks.extend(open);
+    hunks
+}
+
+/// Base lines `start..end` with the given hunks applied.
+fn apply<'a>(base: &[&'a str], start: usize, end: usize, hunks: &[&Hunk<'a>]) -> Vec<&'a str> {
+    let mut result = Vec::new();
+    let mut position = start;
+    for hunk in hunks {
+        result.extend_from_slice(&base[position..hunk.start]);
+        result.extend_from_slice(&hunk.lines);
+        position = hunk.end;
+    }
+    result.extend_from_slice(&base[position..end]);
+    result
+}
+
+/// Line-based diff3: changes from `local` and `remote` relative to `base` are
+/// combined, and only regions both sides changed differently become
+/// conflicts. Changes on adjacent lines count as overlapping, as in
+/// `diff3` and git.
+pub fn merge3(base: &str, local: &str, remote: &str) -> Merge {
+    let base_lines: Vec<&str> = base.lines().collect();
+    let local_lines: Vec<&str> = local.lines().collect();
+    let remote_lines: Vec<&str> = remote.lines().collect();
+    let local_hunks = hunks(&base_lines, &local_lines);
+    let remote_hunks = hunks(&base_lines, &remote_lines);
+
+    let mut output: Vec<&str> = Vec::new();
+    let mut conflicts = 0;
+    let mut position = 0;
+    let (mut l, mut r) = (0, 0);
+    while l < local_hunks.len() || r < remote_hunks.len() {
+        // Start a region at the earlier hunk and grow it while hunks from
+        // either side touch it
+        let first = match (local_hunks.get(l), remote_hunks.get(r)) {
+            (Some(a), Some(b)) => if a.start <= b.start { a } else { b },
+            (Some(a), None) => a,
+            (None, Some(b)) => b,
+            (None, None) => break,
+        };
+        let (start, mut end) = (first.start, first.end);
+        let (mut region_local, mut region_remote) = (Vec::new(), Vec::new());
+        loop {
+            if let Some(hunk) = local_hunks.get(l).filter(|h| h.start <= end) {
+                end = end.max(hunk.end);
+                region_local.push(hunk);
+                l += 1;
+            } else if let Some(hunk) = remote_hunks.get(r).filter(|h| h.start <= end) {
+                end = end.max(hunk.end);
+                region_remote.push(hunk);
+                r += 1;
+            } else {
+                break;
+            }
+        }
+
+        output.extend_from_slice(&base_lines[position..start]);
+        let ours = apply(&base_lines, start, end, &region_local);
+        let theirs = apply(&base_lines, start, end, &region_remote);
+        if region_remote.is_empty() || ours == theirs {
+            output.extend(ours);
+        } else if region_local.is_empty() {
+            output.extend(theirs);
+        } else {
+            conflicts += 1;
+            output.push("<<<<<<< LOCAL");
+            output.extend(ours);
+            output.push("=======");
+            output.extend(theirs);
+            output.push(">>>>>>> REMOTE");
+        }
+        position = end;
+    }
+    output.extend_from_slice(&base_lines[position..]);
+
+    let mut text = output.join("\n");
+    if !output.is_empty() && (local.ends_with('\n') || remote.ends_with('\n')) {
+        text.push('\n');
+    }
+    Merge { text, conflicts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        assert_eq!(
+            unified_diff(old, new, "old", "new", 1),
+            "--- old\n+++ new\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n@@ -9 +9,2 @@\n i\n+j\n"
+        );
+        assert_eq!(unified_diff(old, old, "old", "new", 3), "");
+    }
+
+    #[test]
+    fn test_merge3() {
+        let base = "# Title\n\nintro\n\n## A\n\none\n\n## B\n\ntwo\n";
+        let local = "# Title\n\nintro, edited\n\n## A\n\none\n\n## B\n\ntwo\n";
+        let remote = "# Title\n\nintro\n\n## A\n\none\n\n## B\n\ntwo\nthree\n";
+        let merged = merge3(base, local, remote);
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(merged.text, "# Title\n\nintro, edited\n\n## A\n\none\n\n## B\n\ntwo\nthree\n");
+
+        let remote = base.replace("intro", "intro, rewritten");
+        let merged = merge3(base, local, &remote);
+        assert_eq!(merged.conflicts, 1);
+        assert!(merged.text.contains("<<<<<<< LOCAL\nintro, edited\n=======\nintro, rewritten\n>>>>>>> REMOTE\n"));
+
+        // The same change on both sides is not a conflict
+        assert_eq!(merge3(base, local, local).text, local);
+    }
+}
@@ -0,0 +1,331 @@
+#![allow(dead_code)]
+
+use crate::types::FileCategory;
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const EXIF_GPS_IFD_TAG: u16 = 0x8825;
+
+/// Built-in content transforms applied before a file is hashed and uploaded.
+///
+/// Every transform is idempotent, so a file that was already transformed on
+/// one device hashes identically when indexed on another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContentTransform {
+    /// Convert CRLF and lone CR line endings to LF
+    NormalizeLineEndings,
+    /// Remove GPS location data from JPEG EXIF and PNG eXIf chunks
+    StripExifGps,
+    /// Re-deflate PNG image data at maximum compression
+    RecompressPng,
+}
+
+/// Selects which files a list of transforms applies to, by glob pattern,
+/// file category, or both.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransformRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<FileCategory>,
+    pub transforms: Vec<ContentTransform>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TransformPipeline {
+    rules: Vec<(Option<GlobMatcher>, TransformRule)>,
+}
+
+impl TransformPipeline {
+    pub fn new(rules: &[TransformRule]) -> Self {
+        let rules = rules
+            .iter()
+            .filter_map(|rule| {
+                let matcher = match &rule.pattern {
+                    Some(pattern) => match GlobBuilder::new(pattern).literal_separator(true).build() {
+                        Ok(glob) => Some(glob.compile_matcher()),
+                        Err(e) => {
+                            eprintln!("Skipping transform rule with invalid pattern {}: {}", pattern, e);
+                            return None;
+                        }
+                    },
+                    None => None,
+                };
+                Some((matcher, rule.clone()))
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Transforms that apply to a file, in configuration order and without
+    /// duplicates.
+    pub fn transforms_for(&self, relative_path: &Path, category: &FileCategory) -> Vec<ContentTransform> {
+        let mut transforms = Vec::new();
+        for (matcher, rule) in &self.rules {
+            let pattern_matches = matcher.as_ref().map(|m| m.is_match(relative_path)).unwrap_or(true);
+            let category_matches = rule.category.as_ref().map(|c| c == category).unwrap_or(true);
+            if pattern_matches && category_matches {
+                for transform in &rule.transforms {
+                    if !transforms.contains(transform) {
+                        transforms.push(*transform);
+                    }
+                }
+            }
+        }
+        transforms
+    }
+
+    pub fn apply(&self, relative_path: &Path, category: &FileCategory, content: Vec<u8>) -> Vec<u8> {
+        self.transforms_for(relative_path, category)
+            .into_iter()
+            .fold(content, |content, transform| transform.apply(content))
+    }
+}
+
+impl ContentTransform {
+    /// Applies the transform, returning the input unchanged if it doesn't
+    /// understand the format.
+    pub fn apply(&self, content: Vec<u8>) -> Vec<u8> {
+        match self {
+            ContentTransform::NormalizeLineEndings => normalize_line_endings(content),
+            ContentTransform::StripExifGps => {
+                if content.starts_with(PNG_SIGNATURE) {
+                    strip_png_exif(&content).unwrap_or(content)
+                } else if content.starts_with(&[0xFF, 0xD8]) {
+                    strip_jpeg_gps(content)
+                } else {
+                    content
+                }
+            }
+            ContentTransform::RecompressPng => recompress_png(&content).unwrap_or(content),
+        }
+    }
+}
+
+fn normalize_line_endings(content: Vec<u8>) -> Vec<u8> {
+    if !content.contains(&b'\r') || content.contains(&0) {
+        return content;
+    }
+
+    let mut normalized = Vec::with_capacity(content.len());
+    let mut bytes = content.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'\r' {
+            if bytes.peek() == Some(&&b'\n') {
+                bytes.next();
+            }
+            normalized.push(b'\n');
+        } else {
+            normalized.push(byte);
+        }
+    }
+    normalized
+}
+
+struct PngChunk<'a> {
+    kind: [u8; 4],
+    data: &'a [u8],
+}
+
+fn parse_png_chunks(content: &[u8]) -> Option<Vec<PngChunk<'_>>> {
+    let mut chunks = Vec::new();
+    let mut offset = PNG_SIGNATURE.len();
+    while offset + 12 <= content.len() {
+        let length = u32::from_be_bytes(content[offset..offset + 4].try_into().ok()?) as usize;
+        let kind: [u8; 4] = content[offset + 4..offset + 8].try_into().ok()?;
+        let data = content.get(offset + 8..offset + 8 + length)?;
+        chunks.push(PngChunk { kind, data });
+        offset += 12 + length;
+        if &kind == b"IEND" {
+            return Some(chunks);
+        }
+    }
+    None
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+fn strip_png_exif(content: &[u8]) -> Option<Vec<u8>> {
+    let chunks = parse_png_chunks(content)?;
+    if !chunks.iter().any(|c| &c.kind == b"eXIf") {
+        return None;
+    }
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    for chunk in chunks.iter().filter(|c| &c.kind != b"eXIf") {
+        write_png_chunk(&mut out, &chunk.kind, chunk.data);
+    }
+    Some(out)
+}
+
+fn recompress_png(content: &[u8]) -> Option<Vec<u8>> {
+    if !content.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let chunks = parse_png_chunks(content)?;
+
+    let compressed: Vec<u8> = chunks
+        .iter()
+        .filter(|c| &c.kind == b"IDAT")
+        .flat_map(|c| c.data.iter().copied())
+        .collect();
+    let mut raw = Vec::new();
+    flate2::read::ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut raw).ok()?;
+
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&raw).ok()?;
+    let recompressed = encoder.finish().ok()?;
+
+    // All IDAT chunks are replaced by a single one at the first IDAT position
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut idat_written = false;
+    for chunk in &chunks {
+        if &chunk.kind == b"IDAT" {
+            if !idat_written {
+                write_png_chunk(&mut out, b"IDAT", &recompressed);
+                idat_written = true;
+            }
+        } else {
+            write_png_chunk(&mut out, &chunk.kind, chunk.data);
+        }
+    }
+
+    // Never make a file bigger
+    if out.len() < content.len() {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Blanks the GPS IFD of a JPEG's EXIF block in place, keeping all offsets
+/// (and therefore every other EXIF field) intact.
+fn strip_jpeg_gps(mut content: Vec<u8>) -> Vec<u8> {
+    let mut offset = 2;
+    while offset + 4 <= content.len() && content[offset] == 0xFF {
+        let marker = content[offset + 1];
+        // Start of scan: no more metadata segments follow
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([content[offset + 2], content[offset + 3]]) as usize;
+        let segment_start = offset + 4;
+        let segment_end = offset + 2 + length;
+        if segment_end > content.len() || length < 2 {
+            break;
+        }
+
+        if marker == 0xE1 && content[segment_start..segment_end].starts_with(b"Exif\0\0") {
+            let tiff_start = segment_start + 6;
+            blank_gps_ifd(&mut content[tiff_start..segment_end]);
+        }
+
+        offset = segment_end;
+    }
+    content
+}
+
+fn blank_gps_ifd(tiff: &mut [u8]) -> Option<()> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |data: &[u8], at: usize| -> Option<u16> {
+        let bytes: [u8; 2] = data.get(at..at + 2)?.try_into().ok()?;
+        Some(if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    };
+    let read_u32 = |data: &[u8], at: usize| -> Option<u32> {
+        let bytes: [u8; 4] = data.get(at..at + 4)?.try_into().ok()?;
+        Some(if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    };
+
+    let ifd0 = read_u32(tiff, 4)? as usize;
+    let entry_count = read_u16(tiff, ifd0)? as usize;
+    let gps_ifd = (0..entry_count)
+        .map(|i| ifd0 + 2 + i * 12)
+        .find(|&entry| read_u16(tiff, entry) == Some(EXIF_GPS_IFD_TAG))
+        .and_then(|entry| read_u32(tiff, entry + 8))? as usize;
+
+    let gps_count = read_u16(tiff, gps_ifd)? as usize;
+    for i in 0..gps_count {
+        let entry = gps_ifd + 2 + i * 12;
+        let value_type = read_u16(tiff, entry + 2)?;
+        let count = read_u32(tiff, entry + 4)? as usize;
+        let type_size = match value_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => 0,
+        };
+        // Values larger than four bytes live outside the entry
+        let value_size = type_size * count;
+        if value_size > 4 {
+            let value_offset = read_u32(tiff, entry + 8)? as usize;
+            if let Some(value) = tiff.get_mut(value_offset..value_offset + value_size) {
+                value.fill(0);
+            }
+        }
+        if let Some(bytes) = tiff.get_mut(entry..entry + 12) {
+            bytes.fill(0);
+        }
+    }
+
+    // An empty GPS IFD: zero entries, no next IFD
+    tiff.get_mut(gps_ifd..gps_ifd + 2)?.fill(0);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_png(extra_chunk: Option<(&[u8; 4], &[u8])>) -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::none());
+        encoder.write_all(&[0u8; 4096]).unwrap();
+        let idat = encoder.finish().unwrap();
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        write_png_chunk(&mut png, b"IHDR", &[0, 0, 0, 32, 0, 0, 0, 32, 8, 0, 0, 0, 0]);
+        if let Some((kind, data)) = extra_chunk {
+            write_png_chunk(&mut png, kind, data);
+        }
+        write_png_chunk(&mut png, b"IDAT", &idat);
+        write_png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    #[test]
+    fn test_transforms_are_idempotent() {
+        let text = b"line one\r\nline two\rline three\n".to_vec();
+        let once = ContentTransform::NormalizeLineEndings.apply(text);
+        assert_eq!(once, b"line one\nline two\nline three\n");
+        assert_eq!(ContentTransform::NormalizeLineEndings.apply(once.clone()), once);
+
+        let png = test_png(Some((b"eXIf", b"MM\0\x2a gps")));
+        let stripped = ContentTransform::StripExifGps.apply(png.clone());
+        assert!(stripped.len() < png.len());
+        assert!(parse_png_chunks(&stripped).unwrap().iter().all(|c| &c.kind != b"eXIf"));
+
+        let recompressed = ContentTransform::RecompressPng.apply(stripped.clone());
+        assert!(recompressed.len() < stripped.len());
+        assert_eq!(ContentTransform::RecompressPng.apply(recompressed.clone()), recompressed);
+    }
+}
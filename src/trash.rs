@@ -0,0 +1,199 @@
+#![allow(dead_code)]
+
+//! Files deleted by a sync, kept in the root's `.syncmd/trash` so a delete
+//! made by mistake on another device can be undone. Every round of deletes
+//! goes into a directory named after the time it happened, with the files at
+//! their paths relative to the root, and is removed once it is older than
+//! the root's retention.
+
+use crate::types::SyncError;
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const TRASH_DIR_NAME: &str = "trash";
+pub const DEFAULT_RETENTION_DAYS: u32 = 30;
+
+/// The retention for a root's `trash_retention_days` setting.
+pub fn retention(days: Option<u32>) -> Duration {
+    Duration::from_secs(u64::from(days.unwrap_or(DEFAULT_RETENTION_DAYS)) * 24 * 60 * 60)
+}
+
+/// A file in the trash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    /// Where the file was, relative to the root
+    pub path: PathBuf,
+    pub deleted: DateTime<Utc>,
+    pub size: u64,
+    location: PathBuf,
+}
+
+impl TrashEntry {
+    /// Where the file is kept in the trash.
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Trash {
+    sync_root: PathBuf,
+    dir: PathBuf,
+}
+
+impl Trash {
+    pub fn new(sync_root: &Path) -> Self {
+        Self {
+            sync_root: sync_root.to_path_buf(),
+            dir: crate::state::dir(sync_root).join(TRASH_DIR_NAME),
+        }
+    }
+
+    /// Moves the file at `relative_path` into the trash.
+    pub fn put(&self, relative_path: &Path) -> Result<PathBuf, SyncError> {
+        check_contained(relative_path)?;
+        let location = self.dir
+            .join(Utc::now().timestamp_millis().to_string())
+            .join(relative_path);
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.sync_root.join(relative_path), &location)?;
+        Ok(location)
+    }
+
+    /// Everything in the trash, most recently deleted first.
+    pub fn list(&self) -> Result<Vec<TrashEntry>, SyncError> {
+        let mut entries = Vec::new();
+        for (deleted, batch) in self.batches()? {
+            for entry in walkdir::WalkDir::new(&batch).into_iter().filter_map(Result::ok) {
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                entries.push(TrashEntry {
+                    path: entry.path().strip_prefix(&batch)?.to_path_buf(),
+                    deleted,
+                    size: entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+                    location: entry.path().to_path_buf(),
+                });
+            }
+        }
+        entries.sort_by(|a, b| b.deleted.cmp(&a.deleted).then_with(|| a.path.cmp(&b.path)));
+        Ok(entries)
+    }
+
+    /// Puts the most recently deleted version of `relative_path` back. Fails
+    /// rather than overwrite a file that has taken its place.
+    pub fn restore(&self, relative_path: &Path) -> Result<TrashEntry, SyncError> {
+        check_contained(relative_path)?;
+        let entry = self.list()?
+            .into_iter()
+            .find(|entry| entry.path == relative_path)
+            .ok_or_else(|| SyncError::NotFound(relative_path.to_path_buf()))?;
+        let target = self.sync_root.join(relative_path);
+        if target.exists() {
+            return Err(SyncError::Conflict(format!(
+                "{} exists again, move it away before restoring",
+                relative_path.display()
+            )));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&entry.location, &target)?;
+        self.remove_empty_dirs(entry.location.parent());
+        Ok(entry)
+    }
+
+    /// Removes rounds of deletes older than `retention` and returns how many
+    /// files went with them.
+    pub fn expire(&self, retention: Duration) -> Result<u64, SyncError> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+        let mut removed = 0;
+        for (deleted, batch) in self.batches()? {
+            if deleted <= cutoff {
+                removed += walkdir::WalkDir::new(&batch)
+                    .into_iter()
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.file_type().is_file())
+                    .count() as u64;
+                fs::remove_dir_all(&batch)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn batches(&self) -> Result<Vec<(DateTime<Utc>, PathBuf)>, SyncError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut batches = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let deleted = entry.file_name()
+                .to_str()
+                .and_then(|name| name.parse::<i64>().ok())
+                .and_then(DateTime::from_timestamp_millis);
+            if let Some(deleted) = deleted {
+                batches.push((deleted, entry.path()));
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Removes directories left empty by a restore, up to the trash itself.
+    fn remove_empty_dirs(&self, mut dir: Option<&Path>) {
+        while let Some(current) = dir.filter(|dir| *dir != self.dir && dir.starts_with(&self.dir)) {
+            if fs::remove_dir(current).is_err() {
+                break;
+            }
+            dir = current.parent();
+        }
+    }
+}
+
+/// Refuses paths that would take a file from, or put one back, outside the
+/// root.
+fn check_contained(relative_path: &Path) -> Result<(), SyncError> {
+    match crate::types::is_contained(relative_path) {
+        true => Ok(()),
+        false => Err(SyncError::PermissionDenied(format!("{} is outside the sync root", relative_path.display()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trash_and_restore() {
+        let root = tempfile::tempdir().unwrap();
+        let trash = Trash::new(root.path());
+        fs::create_dir_all(root.path().join("notes")).unwrap();
+        fs::write(root.path().join("notes/idea.md"), "# Idea").unwrap();
+        fs::write(root.path().join("todo.md"), "- [ ] x").unwrap();
+
+        trash.put(Path::new("notes/idea.md")).unwrap();
+        trash.put(Path::new("todo.md")).unwrap();
+        assert!(!root.path().join("notes/idea.md").exists());
+        let listed: Vec<PathBuf> = trash.list().unwrap().into_iter().map(|entry| entry.path).collect();
+        assert!(listed.contains(&PathBuf::from("notes/idea.md")) && listed.len() == 2);
+
+        fs::write(root.path().join("todo.md"), "new").unwrap();
+        assert!(matches!(trash.restore(Path::new("todo.md")), Err(SyncError::Conflict(_))));
+        let restored = trash.restore(Path::new("notes/idea.md")).unwrap();
+        assert_eq!(restored.size, 6);
+        assert_eq!(fs::read_to_string(root.path().join("notes/idea.md")).unwrap(), "# Idea");
+        assert!(matches!(trash.restore(Path::new("notes/idea.md")), Err(SyncError::NotFound(_))));
+        assert!(matches!(trash.put(Path::new("../outside.md")), Err(SyncError::PermissionDenied(_))));
+        assert!(matches!(trash.restore(Path::new("/etc/passwd")), Err(SyncError::PermissionDenied(_))));
+
+        assert_eq!(trash.expire(Duration::from_secs(3600)).unwrap(), 0);
+        assert_eq!(trash.expire(Duration::ZERO).unwrap(), 1);
+        assert!(trash.list().unwrap().is_empty());
+    }
+}
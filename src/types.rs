@@ -1,29 +1,557 @@
+use std::cmp::Ordering;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct FileMetadata {
     pub path: PathBuf,
     pub hash: String,
     pub size: u64,
-    pub modified: SystemTime,
-    pub created: SystemTime,
+    pub modified: Timestamp,
+    pub created: Timestamp,
     pub version: u64,
     pub device_id: String,
+    /// Which edits of each device this version includes, empty in metadata
+    /// from before version vectors
+    #[serde(default)]
+    pub versions: VersionVector,
+    /// Restored where the file is written on other devices
+    #[serde(default)]
+    pub permissions: FilePermissions,
+}
+
+/// A file's permissions as synced: its Unix mode bits, on systems that have
+/// them, and whether it is read-only, which is all Windows has. A device
+/// without mode bits keeps what the file had, only making it read-only or
+/// writable; metadata from before permissions were synced changes nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FilePermissions {
+    /// Permission bits, without setuid, setgid and sticky, which no other
+    /// device gets to set
+    pub mode: Option<u32>,
+    pub readonly: bool,
+}
+
+/// The mode bits that are synced
+pub const PERMISSION_BITS: u32 = 0o777;
+
+#[allow(dead_code)]
+impl FilePermissions {
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & PERMISSION_BITS)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        Self { mode, readonly: metadata.permissions().readonly() }
+    }
+
+    /// Whether both say the same, comparing only whether the file is
+    /// read-only where either side doesn't know mode bits.
+    pub fn matches(&self, other: &FilePermissions) -> bool {
+        match (self.mode, other.mode) {
+            (Some(ours), Some(theirs)) => ours & PERMISSION_BITS == theirs & PERMISSION_BITS,
+            _ => self.readonly == other.readonly,
+        }
+    }
+
+    /// What a new copy of `target` gets: these, with the mode bits `target`
+    /// has now where they aren't known.
+    pub fn for_target(&self, target: &std::path::Path) -> Self {
+        #[cfg(unix)]
+        if self.mode.is_none() {
+            use std::os::unix::fs::PermissionsExt;
+            if let Ok(existing) = std::fs::metadata(target) {
+                let mode = existing.permissions().mode() & PERMISSION_BITS;
+                let mode = match (self.readonly, existing.permissions().readonly()) {
+                    (true, _) => mode & !0o222,
+                    (false, true) => mode | 0o200,
+                    (false, false) => mode,
+                };
+                return Self { mode: Some(mode), readonly: self.readonly };
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = target;
+        *self
+    }
+
+    pub fn apply(&self, path: &std::path::Path) -> Result<(), SyncError> {
+        if *self == Self::default() {
+            return Ok(());
+        }
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            match self.mode {
+                Some(mode) => permissions.set_mode(mode & PERMISSION_BITS),
+                // From Windows, which only says whether it's read-only
+                None if self.readonly => permissions.set_mode(permissions.mode() & !0o222),
+                None => return Ok(()),
+            }
+        }
+        #[cfg(not(unix))]
+        permissions.set_readonly(self.readonly);
+        Ok(std::fs::set_permissions(path, permissions)?)
+    }
+}
+
+/// Modification times this close are the same time when deciding which side
+/// is newer. It covers the coarsest filesystem in common use, FAT with its
+/// 2 second steps, and is the clock drift devices are expected to stay
+/// within; past it, "newest wins" trusts the clocks.
+pub const MODIFIED_TOLERANCE: Duration = Duration::from_secs(2);
+
+#[allow(dead_code)]
+impl FileMetadata {
+    /// Whether `other` is this version: the same content with the same
+    /// permissions, so a change of either is synced.
+    pub fn same_version(&self, other: &FileMetadata) -> bool {
+        self.hash == other.hash && self.permissions.matches(&other.permissions)
+    }
+
+    /// Which file was modified later, `Equal` within [`MODIFIED_TOLERANCE`].
+    pub fn compare_modified(&self, other: &FileMetadata) -> Ordering {
+        self.modified.compare_within(other.modified, MODIFIED_TOLERANCE)
+    }
+
+    /// How this version relates to `other` by their version vectors. When
+    /// either side has none the modification times decide, and never find
+    /// the edits concurrent.
+    pub fn causality(&self, other: &FileMetadata) -> Causality {
+        if self.versions.is_empty() || other.versions.is_empty() {
+            return match self.compare_modified(other) {
+                Ordering::Less => Causality::Before,
+                Ordering::Equal => Causality::Equal,
+                Ordering::Greater => Causality::After,
+            };
+        }
+        self.versions.causality(&other.versions)
+    }
+}
+
+#[cfg(test)]
+impl FileMetadata {
+    /// The first version of a file at `path` holding `content`, for tests
+    /// to adjust.
+    pub fn for_test(path: &str, content: &[u8]) -> Self {
+        FileMetadata {
+            path: PathBuf::from(path),
+            hash: blake3::hash(content).to_hex().to_string(),
+            size: content.len() as u64,
+            modified: Timestamp::from_millis(0),
+            created: Timestamp::from_millis(0),
+            version: 1,
+            device_id: String::new(),
+            versions: Default::default(),
+            permissions: Default::default(),
+        }
+    }
+}
+
+/// A counter per device, counting the edits it made to a file. A version
+/// whose counters are all at least another's includes every edit of it and
+/// is strictly newer, whatever the devices' clocks say; when each has an
+/// edit the other lacks they were made concurrently.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(std::collections::BTreeMap<String, u64>);
+
+/// How two versions of a file relate, by their version vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// Strictly older, the other version includes this one's edits
+    Before,
+    /// Strictly newer
+    After,
+    /// Each has edits the other lacks
+    Concurrent,
+}
+
+#[allow(dead_code)]
+impl VersionVector {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, device_id: &str) -> u64 {
+        self.0.get(device_id).copied().unwrap_or(0)
+    }
+
+    /// Counts an edit by `device_id`.
+    pub fn bump(&mut self, device_id: &str) {
+        *self.0.entry(device_id.to_string()).or_default() += 1;
+    }
+
+    /// Includes `other`'s edits, taking the larger counter of each device.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device_id, &counter) in &other.0 {
+            let own = self.0.entry(device_id.clone()).or_default();
+            *own = (*own).max(counter);
+        }
+    }
+
+    pub fn causality(&self, other: &VersionVector) -> Causality {
+        let devices = self.0.keys().chain(other.0.keys());
+        let (mut newer, mut older) = (false, false);
+        for device_id in devices {
+            match self.get(device_id).cmp(&other.get(device_id)) {
+                Ordering::Greater => newer = true,
+                Ordering::Less => older = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (newer, older) {
+            (false, false) => Causality::Equal,
+            (false, true) => Causality::Before,
+            (true, false) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+}
+
+/// A point in time as UTC milliseconds since the Unix epoch, the precision
+/// every platform can represent. Metadata written before timestamps were
+/// normalized held serde's `SystemTime` form, which is still accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+pub struct Timestamp(i64);
+
+impl<'de> serde::Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Binary formats can't tell the forms apart, and only ever carried
+        // the milliseconds
+        if !deserializer.is_human_readable() {
+            return <i64 as serde::Deserialize>::deserialize(deserializer).map(Timestamp);
+        }
+        <TimestampRepr as serde::Deserialize>::deserialize(deserializer).map(Timestamp::from)
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum TimestampRepr {
+    Millis(i64),
+    SystemTime { secs_since_epoch: u64, nanos_since_epoch: u32 },
+}
+
+impl From<TimestampRepr> for Timestamp {
+    fn from(repr: TimestampRepr) -> Self {
+        match repr {
+            TimestampRepr::Millis(millis) => Timestamp(millis),
+            TimestampRepr::SystemTime { secs_since_epoch, nanos_since_epoch } => {
+                Timestamp(secs_since_epoch as i64 * 1000 + (nanos_since_epoch / 1_000_000) as i64)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Timestamp {
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub fn from_millis(millis: i64) -> Self {
+        Timestamp(millis)
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    /// Orders two timestamps, treating ones less than `tolerance` apart as
+    /// equal.
+    pub fn compare_within(&self, other: Timestamp, tolerance: Duration) -> Ordering {
+        if self.0.abs_diff(other.0) < tolerance.as_millis() as u64 {
+            Ordering::Equal
+        } else {
+            self.0.cmp(&other.0)
+        }
+    }
+}
+
+/// Truncates to whole milliseconds, towards the past for times before 1970.
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(after) => Timestamp(after.as_millis() as i64),
+            Err(before) => Timestamp(-(before.duration().as_nanos().div_ceil(1_000_000) as i64)),
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        let offset = Duration::from_millis(timestamp.0.unsigned_abs());
+        if timestamp.0 >= 0 {
+            SystemTime::UNIX_EPOCH + offset
+        } else {
+            SystemTime::UNIX_EPOCH - offset
+        }
+    }
+}
+
+impl From<Timestamp> for chrono::DateTime<chrono::Utc> {
+    fn from(timestamp: Timestamp) -> Self {
+        chrono::DateTime::from_timestamp_millis(timestamp.0).unwrap_or_default()
+    }
+}
+
+/// What a directory looked like when it was indexed. Adding, removing or
+/// renaming an entry changes a directory's modification time, so while it
+/// and the number of entries stay the same, the files directly inside need
+/// no stat; editing a file in place is only seen by a watcher or a deep scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryStamp {
+    pub modified: Timestamp,
+    pub entries: u64,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SyncState {
     pub local_files: std::collections::HashMap<PathBuf, FileMetadata>,
+    /// Every directory under the root that is synced, empty ones included;
+    /// not kept in the persisted index
+    #[serde(default)]
+    pub directories: std::collections::BTreeSet<PathBuf>,
     pub device_id: String,
     pub sync_root: PathBuf,
 }
 
+/// Content of small files carried inside a sync response, keyed by path and
+/// base64-encoded, so short notes don't each need a chunked transfer.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct InlineContent {
+    files: std::collections::HashMap<PathBuf, String>,
+}
+
+#[allow(dead_code)]
+impl InlineContent {
+    /// Files up to this size are inlined
+    pub const MAX_FILE_SIZE: u64 = 4 * 1024;
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Inlines `content` unless it is over the size limit.
+    pub fn insert(&mut self, path: PathBuf, content: &[u8]) -> bool {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+        if content.len() as u64 > Self::MAX_FILE_SIZE {
+            return false;
+        }
+        self.files.insert(path, BASE64.encode(content));
+        true
+    }
+
+    /// Removes and decodes the inlined content for `metadata`, if present and
+    /// matching its hash.
+    pub fn take(&mut self, metadata: &FileMetadata) -> Option<Vec<u8>> {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+        let encoded = self.files.remove(&metadata.path)?;
+        let content = BASE64.decode(encoded).ok()?;
+        (blake3::hash(&content).to_hex().as_str() == metadata.hash).then_some(content)
+    }
+
+    /// Removes and decodes the inlined content for `path` without checking
+    /// it, for content the caller verifies some other way.
+    pub fn take_unverified(&mut self, path: &std::path::Path) -> Option<Vec<u8>> {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+        BASE64.decode(self.files.remove(path)?).ok()
+    }
+}
+
+/// Path prefixes a client wants changes for, so the server can leave out
+/// the rest of a large share. Empty means everything.
+#[derive(Debug, Clone, Default)]
+pub struct Subscription {
+    prefixes: Vec<PathBuf>,
+}
+
+#[allow(dead_code)]
+impl Subscription {
+    pub fn new(prefixes: &[String]) -> Self {
+        let prefixes = prefixes
+            .iter()
+            .map(|prefix| prefix.trim_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        Self { prefixes }
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    /// Whether `path` is under one of the prefixes. Prefixes match whole
+    /// path components, so `notes` covers `notes/a.md` but not `notes-old`.
+    pub fn includes(&self, path: &std::path::Path) -> bool {
+        self.is_everything() || self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// The subfolders of a root this device syncs: everything under an
+/// included path, or the whole root when none is included, except what is
+/// under an excluded path. Paths match whole components, like
+/// [`Subscription`] prefixes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathSelection {
+    include: Vec<PathBuf>,
+    exclude: Vec<PathBuf>,
+}
+
+#[allow(dead_code)]
+impl PathSelection {
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        let paths = |list: &[String]| {
+            list.iter()
+                .map(|path| path.trim_matches('/'))
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        };
+        Self { include: paths(include), exclude: paths(exclude) }
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether a root-relative file is selected.
+    pub fn includes(&self, path: &std::path::Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|include| path.starts_with(include)))
+            && !self.exclude.iter().any(|exclude| path.starts_with(exclude))
+    }
+
+    /// Whether a root-relative directory may hold selected files, either
+    /// because it is selected or because an included path is inside it.
+    pub fn may_contain(&self, dir: &std::path::Path) -> bool {
+        self.includes(dir) || self.include.iter().any(|include| include.starts_with(dir))
+    }
+
+    /// Keeps the operations for selected paths. A rename across the edge of
+    /// the selection becomes a delete of the old path or an add of the new one.
+    pub fn retain_operations(&self, operations: Vec<SyncOperation>) -> Vec<SyncOperation> {
+        if self.is_everything() {
+            return operations;
+        }
+        operations
+            .into_iter()
+            .filter_map(|operation| match operation {
+                SyncOperation::Add(ref metadata) | SyncOperation::Update(ref metadata) => {
+                    self.includes(&metadata.path).then_some(operation)
+                }
+                SyncOperation::Delete(ref path) | SyncOperation::MkDir(ref path) | SyncOperation::RmDir(ref path) => {
+                    self.includes(path).then_some(operation)
+                }
+                SyncOperation::Rename { from, to } => match (self.includes(&from), self.includes(&to.path)) {
+                    (true, true) => Some(SyncOperation::Rename { from, to }),
+                    (true, false) => Some(SyncOperation::Delete(from)),
+                    (false, true) => Some(SyncOperation::Add(to)),
+                    (false, false) => None,
+                },
+            })
+            .collect()
+    }
+}
+
+/// Which files a root syncs by type and size. The indexer and the watcher
+/// share it, so a change is only picked up for files that get synced. By
+/// default only known note, image, code, document and data types are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileTypeFilter {
+    /// Every file, whatever its type
+    pub sync_all_files: bool,
+    /// Larger files are skipped, in bytes
+    pub max_file_size: Option<u64>,
+}
+
+#[allow(dead_code)]
+impl FileTypeFilter {
+    pub fn accepts_type(&self, path: &std::path::Path) -> bool {
+        self.sync_all_files || is_known_file_type(path)
+    }
+
+    /// The size and the limit if `size` is over it.
+    pub fn over_limit(&self, size: u64) -> Option<(u64, u64)> {
+        self.max_file_size.filter(|limit| size > *limit).map(|limit| (size, limit))
+    }
+}
+
+/// Whether `path` names something inside the folder it is relative to: not
+/// empty, and without `..`, a root or a drive, which a peer could use to
+/// reach outside it.
+pub fn is_contained(path: &std::path::Path) -> bool {
+    !path.as_os_str().is_empty() && path.components().all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+/// The file types synced without `sync_all_files`.
+pub fn is_known_file_type(path: &std::path::Path) -> bool {
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        return match ext.to_lowercase().as_str() {
+            // Markdown files
+            "md" | "markdown" | "mdown" | "mkdn" | "mkd" | "mdwn" | "mdtxt" | "mdtext" | "text" => true,
+            // Image files
+            "jpg" | "jpeg" | "png" | "gif" | "svg" | "webp" | "bmp" | "ico" | "tiff" | "tif" => true,
+            // Code files
+            "rs" | "py" | "js" | "ts" | "jsx" | "tsx" | "html" | "css" | "scss" | "json" | "yaml" | "yml" | "toml" | "xml" => true,
+            // Configuration files
+            "ini" | "cfg" | "conf" | "config" | "env" | "env.example" => true,
+            // Documentation files
+            "txt" | "rtf" | "doc" | "docx" | "pdf" => true,
+            // Data files
+            "csv" | "tsv" | "jsonl" => true,
+            _ => false,
+        };
+    }
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    match file_name {
+        // Common dotfiles to sync
+        ".gitignore" | ".gitattributes" | ".editorconfig" | ".env.example" => true,
+        // Project configuration files
+        ".eslintrc" | ".eslintrc.json" | ".eslintrc.js" | ".eslintrc.yml" => true,
+        ".prettierrc" | ".prettierrc.json" | ".prettierrc.js" | ".prettierrc.yml" => true,
+        ".babelrc" | ".babelrc.json" | ".babelrc.js" => true,
+        ".vscode" | ".vscodeignore" => true, // Directory
+        // Package manager files
+        "package.json" | "package-lock.json" | "yarn.lock" | "pnpm-lock.yaml" => true,
+        // Build configuration
+        "Cargo.toml" | "Cargo.lock" | "go.mod" | "go.sum" | "pom.xml" | "build.gradle" => true,
+        // Python project files
+        "requirements.txt" | "pyproject.toml" | "setup.py" | "Pipfile" | "poetry.lock" => true,
+        // Node.js project files
+        "tsconfig.json" | "webpack.config.js" | "vite.config.js" | "next.config.js" => true,
+        // Other common project files
+        "README" | "README.md" | "LICENSE" | "CHANGELOG.md" | "CONTRIBUTING.md" => true,
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum SyncOperation {
     Add(FileMetadata),
     Update(FileMetadata),
     Delete(PathBuf),
+    /// Content that moved to a new path, applied as a move instead of a
+    /// transfer
+    Rename { from: PathBuf, to: FileMetadata },
+    /// A directory another device has, which may be empty
+    MkDir(PathBuf),
+    /// A directory another device removed; kept while files are left in it
+    RmDir(PathBuf),
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -35,7 +563,37 @@ pub struct ClientInfo {
     pub auth_token: String,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[allow(dead_code)]
+impl ClientInfo {
+    pub fn slug(&self) -> String {
+        device_slug(&self.name)
+    }
+}
+
+/// Normalizes a free-form device name into an identifier that is safe in
+/// file names and logs: lowercase ASCII letters, digits and single dashes.
+pub fn device_slug(name: &str) -> String {
+    const MAX_LEN: usize = 32;
+
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if c.is_ascii() && !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        // Emoji and other non-ASCII characters are dropped
+    }
+    slug.truncate(MAX_LEN);
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "device".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum FileCategory {
     Text,
     Code,
@@ -88,6 +646,9 @@ pub struct DetailedFileChanges {
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TransferProgress {
     pub transfer_id: String,
+    /// Local file being sent or received
+    #[serde(default)]
+    pub path: PathBuf,
     pub bytes_transferred: u64,
     pub total_bytes: u64,
     pub progress: f64,
@@ -96,6 +657,9 @@ pub struct TransferProgress {
     pub estimated_remaining_seconds: f64,
     pub chunks_received: u32,
     pub total_chunks: u32,
+    /// The last update of the transfer, whether it completed or not
+    #[serde(default)]
+    pub finished: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -147,4 +711,103 @@ pub enum SyncError {
     
     #[error("Session expired")]
     SessionExpired,
-}
\ No newline at end of file
+    
+    #[error("Index store error: {0}")]
+    Index(String),
+    
+    #[error("Strict mode: {0}")]
+    Strict(String),
+    
+    #[error("Filter command error: {0}")]
+    Filter(String),
+    
+    #[error("Secrets error: {0}")]
+    Secrets(String),
+    
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Configuration error: {0}")]
+    Config(String),
+
+    /// The command finished, but not for every file
+    #[error("Partial failure: {0}")]
+    Partial(String),
+
+    #[error("Transfer cancelled: {0}")]
+    Cancelled(PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_precision_and_legacy_form() {
+        let time = SystemTime::UNIX_EPOCH + Duration::new(1_714_557_600, 123_456_789);
+        let timestamp = Timestamp::from(time);
+        assert_eq!(timestamp.as_millis(), 1_714_557_600_123);
+        assert_eq!(serde_json::to_string(&timestamp).unwrap(), "1714557600123");
+        assert_eq!(SystemTime::from(timestamp), SystemTime::UNIX_EPOCH + Duration::from_millis(1_714_557_600_123));
+
+        // Index entries serialized before the change
+        let legacy: Timestamp = serde_json::from_str(r#"{"secs_since_epoch":1714557600,"nanos_since_epoch":123456789}"#).unwrap();
+        assert_eq!(legacy, timestamp);
+
+        // The same save on ext4 and on FAT, which rounds to 2 seconds
+        let fat = Timestamp::from_millis(1_714_557_600_000);
+        assert_eq!(timestamp.compare_within(fat, MODIFIED_TOLERANCE), Ordering::Equal);
+        let later = Timestamp::from_millis(1_714_557_603_000);
+        assert_eq!(later.compare_within(timestamp, MODIFIED_TOLERANCE), Ordering::Greater);
+        assert_eq!(Timestamp::from(SystemTime::UNIX_EPOCH - Duration::from_micros(1500)).as_millis(), -2);
+    }
+
+    #[test]
+    fn test_device_slug() {
+        assert_eq!(device_slug("Anna's MacBook Pro"), "anna-s-macbook-pro");
+        assert_eq!(device_slug("  🚀 Work PC 🚀 "), "work-pc");
+        assert_eq!(device_slug("📱"), "device");
+        assert_eq!(device_slug("a/../b"), "a-b");
+    }
+
+    #[test]
+    fn test_subscription() {
+        let subscription = Subscription::new(&["/notes/".to_string(), "projects/syncmd".to_string()]);
+        assert!(subscription.includes(std::path::Path::new("notes/a.md")));
+        assert!(subscription.includes(std::path::Path::new("projects/syncmd/todo.md")));
+        assert!(!subscription.includes(std::path::Path::new("notes-old/a.md")));
+        assert!(!subscription.includes(std::path::Path::new("projects/other.md")));
+        assert!(Subscription::new(&[String::new()]).includes(std::path::Path::new("anything.md")));
+    }
+
+    #[test]
+    fn test_path_selection() {
+        use std::path::Path;
+
+        let selection = PathSelection::new(&["notes/".to_string(), "work/projects".to_string()], &["notes/archive/".to_string()]);
+        assert!(selection.includes(Path::new("notes/a.md")));
+        assert!(!selection.includes(Path::new("notes/archive/old.md")));
+        assert!(!selection.includes(Path::new("notes-old/a.md")));
+        assert!(!selection.includes(Path::new("readme.md")));
+        assert!(selection.may_contain(Path::new("work")));
+        assert!(!selection.may_contain(Path::new("notes/archive")));
+        assert!(!selection.may_contain(Path::new("photos")));
+
+        let everything_but = PathSelection::new(&[], &["archive".to_string()]);
+        assert!(everything_but.includes(Path::new("readme.md")));
+        assert!(!everything_but.includes(Path::new("archive/2020.md")));
+
+        let file = |path: &str| FileMetadata::for_test(path, b"");
+        let operations = selection.retain_operations(vec![
+            SyncOperation::Add(file("notes/a.md")),
+            SyncOperation::Update(file("photos/cat.png")),
+            SyncOperation::Rename { from: PathBuf::from("notes/b.md"), to: file("notes/archive/b.md") },
+            SyncOperation::Rename { from: PathBuf::from("inbox/c.md"), to: file("notes/c.md") },
+        ]);
+        assert!(matches!(&operations[..], [
+            SyncOperation::Add(a),
+            SyncOperation::Delete(b),
+            SyncOperation::Add(c),
+        ] if a.path == Path::new("notes/a.md") && b == Path::new("notes/b.md") && c.path == Path::new("notes/c.md")));
+    }
+}
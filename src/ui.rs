@@ -0,0 +1,474 @@
+#![allow(dead_code)]
+
+//! `syncmd ui`, a terminal dashboard of the running daemon: its roots and
+//! what they are connected to, clients of its server, transfers with their
+//! progress, conflict copies and a log of what the sync engine did. All of
+//! it comes from the daemon's control socket, polled twice a second, so the
+//! dashboard can be opened and closed without disturbing syncing. A conflict
+//! can be previewed, the file and its copy rendered next to each other.
+
+use crate::daemon::{self, ClientSummary, ControlRequest, ControlResponse, RootStatus};
+use crate::events::{EventKind, SyncEvent};
+use crate::file_transfer::QueuedTransfer;
+use chrono::{DateTime, Utc};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, LineGauge, List, ListItem, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::Frame;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+/// Log lines kept for the log pane
+const MAX_LOG: usize = 200;
+/// Conflict copies listed, most recent first
+const MAX_CONFLICTS: usize = 50;
+
+/// A conflict copy, from an event or, for those made before the dashboard
+/// opened, found on disk.
+#[derive(Debug, Clone, PartialEq)]
+struct Conflict {
+    root: PathBuf,
+    path: PathBuf,
+    time: Option<DateTime<Utc>>,
+}
+
+/// The two sides of a conflict, read when the preview opens.
+#[derive(Debug, Clone, PartialEq)]
+struct ConflictPreview {
+    /// Of the conflict in `Dashboard::conflicts`
+    index: usize,
+    original: PathBuf,
+    copy: PathBuf,
+    original_text: String,
+    copy_text: String,
+}
+
+#[derive(Default)]
+pub struct Dashboard {
+    /// Pid of the daemon, `None` while none is running
+    pid: Option<u32>,
+    started: Option<DateTime<Utc>>,
+    roots: Vec<RootStatus>,
+    clients: Vec<ClientSummary>,
+    transfers: Vec<QueuedTransfer>,
+    log: VecDeque<SyncEvent>,
+    conflicts: VecDeque<Conflict>,
+    /// Latest event seen, to ask for the ones after it
+    last_event: u64,
+    /// Whether conflict copies already on disk were looked for
+    scanned_conflicts: bool,
+    selected: TableState,
+    /// Shown in place of the conflicts and log while open
+    preview: Option<ConflictPreview>,
+    /// Answer to the last key press
+    message: Option<String>,
+}
+
+/// Runs the dashboard until `q` or Esc is pressed.
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let socket = daemon::socket_path()?;
+    let mut dashboard = Dashboard::default();
+    let mut terminal = ratatui::init();
+    let result = async {
+        loop {
+            dashboard.refresh(&socket).await;
+            terminal.draw(|frame| dashboard.render(frame))?;
+            let next_refresh = Instant::now() + REFRESH_INTERVAL;
+            while let Some(remaining) = next_refresh.checked_duration_since(Instant::now()) {
+                if !tokio::task::block_in_place(|| event::poll(remaining))? {
+                    break;
+                }
+                if let Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    match key.code {
+                        KeyCode::Esc | KeyCode::Char('c') if dashboard.preview.is_some() => dashboard.preview = None,
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Char('c') => dashboard.open_preview(0),
+                        KeyCode::Left | KeyCode::Char('h') => dashboard.step_preview(-1),
+                        KeyCode::Right | KeyCode::Char('l') => dashboard.step_preview(1),
+                        KeyCode::Up | KeyCode::Char('k') => dashboard.select_previous(),
+                        KeyCode::Down | KeyCode::Char('j') => dashboard.select_next(),
+                        KeyCode::Char('p') => dashboard.toggle_pause(&socket).await,
+                        _ => {}
+                    }
+                }
+                terminal.draw(|frame| dashboard.render(frame))?;
+            }
+        }
+    }
+    .await;
+    ratatui::restore();
+    result
+}
+
+impl Dashboard {
+    /// Asks the daemon for everything shown.
+    async fn refresh(&mut self, socket: &Path) {
+        let status = match daemon::query(socket, &ControlRequest::Status).await {
+            Ok(Some(ControlResponse::Status { pid, started, roots, .. })) => (pid, started, roots),
+            _ => {
+                self.pid = None;
+                self.roots.clear();
+                self.clients.clear();
+                self.transfers.clear();
+                return;
+            }
+        };
+        let (pid, started, roots) = status;
+        if self.pid != Some(pid) {
+            // Another daemon numbers its events from the start
+            self.last_event = 0;
+        }
+        self.pid = Some(pid);
+        self.started = Some(started);
+        self.roots = roots;
+        if !self.scanned_conflicts && !self.roots.is_empty() {
+            self.scan_conflicts();
+        }
+        if let Ok(Some(ControlResponse::Clients { clients })) = daemon::query(socket, &ControlRequest::ListClients).await {
+            self.clients = clients;
+        }
+        if let Ok(Some(ControlResponse::Transfers { transfers })) = daemon::query(socket, &ControlRequest::Transfers).await {
+            self.transfers = transfers;
+        }
+        let request = ControlRequest::Events { after: self.last_event };
+        if let Ok(Some(ControlResponse::Events { events })) = daemon::query(socket, &request).await {
+            self.add_events(events);
+        }
+        if self.selected.selected().is_none_or(|selected| selected >= self.roots.len()) {
+            self.selected.select((!self.roots.is_empty()).then_some(0));
+        }
+    }
+
+    fn scan_conflicts(&mut self) {
+        self.scanned_conflicts = true;
+        for root in &self.roots {
+            for copy in crate::conflicts::find(&root.path).unwrap_or_default() {
+                self.conflicts.push_back(Conflict { root: root.path.clone(), path: copy.copy, time: None });
+            }
+        }
+        self.conflicts.truncate(MAX_CONFLICTS);
+    }
+
+    fn add_events(&mut self, events: Vec<SyncEvent>) {
+        for event in events {
+            self.last_event = self.last_event.max(event.seq);
+            if event.kind == EventKind::Conflict {
+                self.conflicts.push_front(Conflict {
+                    root: event.root.clone().unwrap_or_default(),
+                    path: PathBuf::from(&event.message),
+                    time: Some(event.time),
+                });
+                self.conflicts.truncate(MAX_CONFLICTS);
+            }
+            if self.log.len() == MAX_LOG {
+                self.log.pop_front();
+            }
+            self.log.push_back(event);
+        }
+    }
+
+    fn select_previous(&mut self) {
+        if let Some(selected) = self.selected.selected() {
+            self.selected.select(Some(selected.saturating_sub(1)));
+        }
+    }
+
+    fn select_next(&mut self) {
+        if let Some(selected) = self.selected.selected() {
+            self.selected.select(Some((selected + 1).min(self.roots.len().saturating_sub(1))));
+        }
+    }
+
+    /// Previews the conflict at `index`, the most recent being 0.
+    fn open_preview(&mut self, index: usize) {
+        let Some(conflict) = self.conflicts.get(index) else {
+            self.message = Some("No conflicts to preview".to_string());
+            return;
+        };
+        let original = crate::conflicts::original_of(&conflict.path).unwrap_or_else(|| conflict.path.clone());
+        let read = |path: &Path| {
+            std::fs::read_to_string(conflict.root.join(path)).unwrap_or_else(|e| format!("*Can't read {}: {}*", path.display(), e))
+        };
+        self.preview = Some(ConflictPreview {
+            index,
+            original_text: read(&original),
+            copy_text: read(&conflict.path),
+            original,
+            copy: conflict.path.clone(),
+        });
+    }
+
+    /// Previews the next or previous conflict while the preview is open.
+    fn step_preview(&mut self, step: isize) {
+        let Some(preview) = &self.preview else {
+            return;
+        };
+        let index = preview.index.saturating_add_signed(step).min(self.conflicts.len().saturating_sub(1));
+        self.open_preview(index);
+    }
+
+    /// Pauses the selected root, or resumes it if it is paused.
+    async fn toggle_pause(&mut self, socket: &Path) {
+        let Some(root) = self.selected.selected().and_then(|selected| self.roots.get(selected)) else {
+            return;
+        };
+        let path = Some(root.path.clone());
+        let request = if root.paused { ControlRequest::Resume { path } } else { ControlRequest::Pause { path } };
+        self.message = Some(match daemon::query(socket, &request).await {
+            Ok(Some(ControlResponse::Done { message } | ControlResponse::Error { message })) => message,
+            Ok(_) => "Unexpected response from the daemon".to_string(),
+            Err(e) => e.to_string(),
+        });
+        self.refresh(socket).await;
+    }
+
+    pub fn render(&mut self, frame: &mut Frame) {
+        let roots_height = (self.roots.len().max(1) + 3).min(12) as u16;
+        let [header, roots, middle, bottom, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(roots_height),
+            Constraint::Length(8),
+            Constraint::Min(6),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [peers, transfers] = Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(middle);
+        let [conflicts, log] = Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)]).areas(bottom);
+
+        let title = match (self.pid, self.started) {
+            (Some(pid), Some(started)) => format!("syncmd daemon, pid {}, up {}", pid, elapsed(started)),
+            _ => "The daemon isn't running, start it with `syncmd daemon`".to_string(),
+        };
+        frame.render_widget(Paragraph::new(title).style(Style::new().add_modifier(Modifier::BOLD)), header);
+        self.render_roots(frame, roots);
+        self.render_peers(frame, peers);
+        self.render_transfers(frame, transfers);
+        match &self.preview {
+            Some(preview) => render_preview(frame, bottom, preview, self.conflicts.len()),
+            None => {
+                self.render_conflicts(frame, conflicts);
+                self.render_log(frame, log);
+            }
+        }
+
+        let keys = match self.preview {
+            Some(_) => "←/→ previous/next conflict  c close preview",
+            None => "q quit  ↑/↓ select root  p pause/resume  c preview conflicts",
+        };
+        let footer_text = match &self.message {
+            Some(message) => format!("{}  |  {}", keys, message),
+            None => keys.to_string(),
+        };
+        frame.render_widget(Paragraph::new(footer_text).style(Style::new().fg(Color::DarkGray)), footer);
+    }
+
+    fn render_roots(&mut self, frame: &mut Frame, area: Rect) {
+        let rows = self.roots.iter().map(|root| {
+            let (state, color) = if root.stopped {
+                ("stopped".to_string(), Color::Red)
+            } else if root.missing {
+                ("missing".to_string(), Color::Red)
+            } else if root.paused {
+                ("paused".to_string(), Color::DarkGray)
+            } else if root.last_error.is_some() {
+                ("retrying".to_string(), Color::Yellow)
+            } else if let Some(remote) = &root.connected_to {
+                (format!("syncing with {}", remote), Color::Green)
+            } else {
+                ("watching".to_string(), Color::Green)
+            };
+            Row::new(vec![
+                root.path.display().to_string(),
+                state,
+                root.files.to_string(),
+                root.last_sync.map(since).unwrap_or_else(|| "never".to_string()),
+                root.last_error.clone().unwrap_or_default(),
+            ])
+            .style(Style::new().fg(color))
+        });
+        let table = Table::new(rows, [
+            Constraint::Percentage(30),
+            Constraint::Percentage(20),
+            Constraint::Length(7),
+            Constraint::Length(10),
+            Constraint::Fill(1),
+        ])
+        .header(Row::new(["Root", "State", "Files", "Last sync", "Error"]).style(Style::new().add_modifier(Modifier::BOLD)))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" Roots "));
+        frame.render_stateful_widget(table, area, &mut self.selected);
+    }
+
+    fn render_peers(&self, frame: &mut Frame, area: Rect) {
+        let outgoing = self.roots.iter().filter_map(|root| {
+            let remote = root.connected_to.as_ref()?;
+            Some(ListItem::new(format!("→ {} for {}", remote, root_name(&root.path))))
+        });
+        let incoming = self.clients.iter().map(|client| {
+            ListItem::new(format!("← {} at {}, seen {}", client.name, client.address, since(client.last_seen)))
+        });
+        let items: Vec<ListItem> = outgoing.chain(incoming).collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" Peers ")), area);
+    }
+
+    fn render_transfers(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(format!(" Transfers ({}) ", self.transfers.len()));
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let rows = Layout::vertical(vec![Constraint::Length(1); inner.height as usize]).split(inner);
+        for (transfer, row) in self.transfers.iter().zip(rows.iter()) {
+            let ratio = if transfer.size == 0 { 0.0 } else { (transfer.transferred as f64 / transfer.size as f64).min(1.0) };
+            let name = transfer.path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            let gauge = LineGauge::default()
+                .ratio(ratio)
+                .label(format!("{} {}", name, crate::plan::format_size(transfer.size)))
+                .filled_style(Style::new().fg(Color::Cyan));
+            frame.render_widget(gauge, *row);
+        }
+    }
+
+    fn render_conflicts(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self.conflicts
+            .iter()
+            .map(|conflict| {
+                let when = conflict.time.map(since).unwrap_or_default();
+                ListItem::new(Line::from(vec![
+                    Span::styled(format!("{} ", root_name(&conflict.root)), Style::new().fg(Color::DarkGray)),
+                    Span::styled(conflict.path.display().to_string(), Style::new().fg(Color::Yellow)),
+                    Span::styled(format!(" {}", when), Style::new().fg(Color::DarkGray)),
+                ]))
+            })
+            .collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" Conflicts ")), area);
+    }
+
+    fn render_log(&self, frame: &mut Frame, area: Rect) {
+        // Newest at the bottom, as many as fit
+        let visible = area.height.saturating_sub(2) as usize;
+        let items: Vec<ListItem> = self.log
+            .iter()
+            .skip(self.log.len().saturating_sub(visible))
+            .map(|event| {
+                let time = event.time.with_timezone(&chrono::Local).format("%H:%M:%S").to_string();
+                let root = event.root.as_deref().map(root_name).unwrap_or_default();
+                ListItem::new(Line::from(vec![
+                    Span::styled(format!("{} ", time), Style::new().fg(Color::DarkGray)),
+                    Span::raw(format!("{} ", root)),
+                    Span::styled(event.message.clone(), Style::new().fg(color(event.kind))),
+                ]))
+            })
+            .collect();
+        frame.render_widget(List::new(items).block(Block::bordered().title(" Log ")), area);
+    }
+}
+
+/// The file on the left and its conflict copy on the right, rendered.
+fn render_preview(frame: &mut Frame, area: Rect, preview: &ConflictPreview, conflicts: usize) {
+    let [left, right] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(area);
+    let sides = [
+        (left, format!(" {} ", preview.original.display()), &preview.original_text),
+        (right, format!(" {} ({}/{}) ", preview.copy.display(), preview.index + 1, conflicts), &preview.copy_text),
+    ];
+    for (area, title, text) in sides {
+        let block = Block::bordered().title(title);
+        let width = block.inner(area).width as usize;
+        let lines = crate::markdown_preview::render(text, width);
+        frame.render_widget(Paragraph::new(lines).wrap(Wrap { trim: false }).block(block), area);
+    }
+}
+
+fn color(kind: EventKind) -> Color {
+    match kind {
+        EventKind::Synced | EventKind::Downloaded => Color::Green,
+        EventKind::Deleted => Color::Magenta,
+        EventKind::Conflict | EventKind::Disconnected => Color::Yellow,
+        EventKind::Connected | EventKind::RootReturned => Color::Cyan,
+        EventKind::Paused | EventKind::Resumed => Color::DarkGray,
+        EventKind::Error | EventKind::RootMissing => Color::Red,
+    }
+}
+
+fn root_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| path.display().to_string())
+}
+
+/// How long ago `time` was, roughly.
+fn since(time: DateTime<Utc>) -> String {
+    format!("{} ago", elapsed(time))
+}
+
+fn elapsed(time: DateTime<Utc>) -> String {
+    let seconds = (Utc::now() - time).num_seconds().max(0);
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn test_dashboard_renders_daemon_state() {
+        let mut dashboard = Dashboard {
+            pid: Some(42),
+            started: Some(Utc::now()),
+            roots: vec![RootStatus {
+                path: PathBuf::from("/home/me/notes"),
+                connected_to: Some("vps".to_string()),
+                files: 12,
+                ..Default::default()
+            }],
+            transfers: vec![QueuedTransfer {
+                id: "t1".to_string(),
+                path: PathBuf::from("/home/me/notes/big.png"),
+                size: 1000,
+                started: Utc::now(),
+                transferred: 500,
+            }],
+            ..Default::default()
+        };
+        let event = |seq, kind, message: &str| SyncEvent {
+            seq,
+            time: Utc::now(),
+            root: Some(PathBuf::from("/home/me/notes")),
+            kind,
+            message: message.to_string(),
+        };
+        dashboard.add_events(vec![
+            event(1, EventKind::Connected, "Connected to vps"),
+            event(2, EventKind::Conflict, "a.conflict-phone.md"),
+        ]);
+        assert_eq!(dashboard.last_event, 2);
+        assert_eq!(dashboard.conflicts.len(), 1);
+
+        let mut terminal = ratatui::Terminal::new(TestBackend::new(120, 30)).unwrap();
+        terminal.draw(|frame| dashboard.render(frame)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        for expected in ["syncing with vps", "→ vps for notes", "big.png", "a.conflict-phone.md", "Connected to vps"] {
+            assert!(screen.contains(expected), "{} missing", expected);
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.md"), "# Mine\n\n- first\n").unwrap();
+        std::fs::write(root.path().join("a.conflict-phone.md"), "# Theirs\n\n**bold**\n").unwrap();
+        dashboard.conflicts[0].root = root.path().to_path_buf();
+        dashboard.open_preview(0);
+        terminal.draw(|frame| dashboard.render(frame)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        for expected in ["# Mine", "• first", "# Theirs", "bold", "(1/1)"] {
+            assert!(screen.contains(expected), "{} missing from the preview", expected);
+        }
+    }
+}
@@ -1,73 +1,441 @@
 #![allow(dead_code)]
 
-mod types;
-mod indexer;
-mod sync;
-mod network;
-mod cli;
-mod file_transfer;
-mod security;
-
-use clap::Parser;
-use cli::{Cli, Commands, Config};
-// use indexer::FileIndexer;
+mod at_rest;
+mod scan;
+mod idempotency;
+mod storage;
+mod browser;
+mod lockout;
+
+use syncmd_core::{
+    capabilities, cli, file_transfer, filter, index_store, indexer, journal_replication,
+    maintenance, merkle, network, plan, protocol_trace, search, security, server_config,
+    share_links, state, sync, types, websocket,
+};
+use clap::{Parser, Subcommand};
+use cli::{Commands, Config};
+use server_config::{ServerConfig, ShareConfig};
+use index_store::{AccessKind, IndexStore};
 use network::{ClientManager, NetworkManager, NetworkMessage};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+/// What this server implements.
+const VPS_CAPABILITIES: &[capabilities::Capability] = &[
+    capabilities::Capability::TokenRefresh,
+    capabilities::Capability::InlineContent,
+    capabilities::Capability::PagedMetadata,
+    capabilities::Capability::Subscriptions,
+    capabilities::Capability::SearchIndex,
+    capabilities::Capability::ChunkedDownload,
+    capabilities::Capability::Notifications,
+    capabilities::Capability::MerkleSync,
+    capabilities::Capability::CompressionZstd,
+    capabilities::Capability::UploadResults,
+    capabilities::Capability::ShareLinks,
+    capabilities::Capability::JournalReplication,
+    capabilities::Capability::BinaryFrames,
+    capabilities::Capability::IdempotencyKeys,
+    capabilities::Capability::Directories,
+    capabilities::Capability::WindowedTransfer,
+];
+
+/// Reads of a file replaced while it was read, before giving up
+const READ_ATTEMPTS: usize = 3;
+/// How long a burst of changes is collected into one notification
+const NOTIFICATION_BATCH_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+/// Changes buffered per watching connection before it has to resync
+const CHANGE_BACKLOG: usize = 1024;
+/// How long an authenticated client may stay idle
+const SESSION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+/// Issuing more tokens for one device revokes its oldest
+const MAX_TOKENS_PER_DEVICE: usize = 5;
+/// How long a share link request may take to arrive
+const LINK_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// How long a client that hasn't authenticated may take to send a message
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Largest message taken from a client that hasn't authenticated
+const HANDSHAKE_MESSAGE_LEN: usize = 64 * 1024;
+
+/// A file stored on the server, for connections watching for changes.
+#[derive(Debug, Clone)]
+struct ServerChange {
+    path: std::path::PathBuf,
+    /// Device that uploaded it, which needn't be told
+    device_id: String,
+}
+
+/// What the server knows of a share while it runs. File content stays in
+/// the share's storage and is read when a client asks for it, so memory
+/// doesn't grow with the share.
 #[derive(Debug)]
 struct ServerState {
-    files: HashMap<String, Vec<u8>>,  // path -> content
     metadata: HashMap<String, types::FileMetadata>,  // path -> metadata
+    /// Hashes of the stored content by path, which reads check against;
+    /// the metadata's hash is the client's, keyed for encrypted roots
+    content_hashes: HashMap<String, String>,
     clients: HashMap<String, String>,  // device_id -> address
+    client_names: HashMap<String, String>,  // device_id -> display name
+    search_terms: HashMap<String, search::SearchEntry>,  // path -> words, text files only
+    disconnected: HashMap<String, std::time::Instant>,  // device_id -> when its connection ended
+    cleanup: maintenance::CleanupStats,
+    /// Answers to uploads by device and idempotency key
+    idempotency: idempotency::IdempotencyCache<Option<network::UploadRejection>>,
+    /// Failed authentications by source address
+    lockout: lockout::AuthLockout,
+    /// The share's directories, empty ones included, for clients that
+    /// sync them
+    directories: std::collections::BTreeSet<std::path::PathBuf>,
 }
 
 impl ServerState {
     fn new() -> Self {
         Self {
-            files: HashMap::new(),
             metadata: HashMap::new(),
+            content_hashes: HashMap::new(),
             clients: HashMap::new(),
+            client_names: HashMap::new(),
+            search_terms: HashMap::new(),
+            disconnected: HashMap::new(),
+            cleanup: maintenance::CleanupStats::default(),
+            idempotency: idempotency::IdempotencyCache::default(),
+            lockout: lockout::AuthLockout::default(),
+            directories: std::collections::BTreeSet::new(),
         }
     }
 
-    fn add_file(&mut self, path: String, content: Vec<u8>, metadata: types::FileMetadata) {
-        self.files.insert(path.clone(), content);
+    /// Records a stored file, indexing its words for search.
+    fn add_file(&mut self, path: String, content: &[u8], metadata: types::FileMetadata) {
+        match search::SearchEntry::from_content(metadata.path.clone(), metadata.hash.clone(), content) {
+            Some(entry) => self.search_terms.insert(path.clone(), entry),
+            None => self.search_terms.remove(&path),
+        };
+        self.content_hashes.insert(path.clone(), blake3::hash(content).to_hex().to_string());
         self.metadata.insert(path, metadata);
     }
 
-    fn get_file(&self, path: &str) -> Option<&Vec<u8>> {
-        self.files.get(path)
-    }
-
     fn get_metadata(&self, path: &str) -> Option<&types::FileMetadata> {
         self.metadata.get(path)
     }
 
+    /// A stored file's metadata with the hash of its stored content.
+    fn get_version(&self, path: &str) -> Option<(types::FileMetadata, String)> {
+        Some((self.metadata.get(path)?.clone(), self.content_hashes.get(path)?.clone()))
+    }
+
     fn list_files(&self) -> Vec<&types::FileMetadata> {
         self.metadata.values().collect()
     }
 
+    /// The stored files under `directory`, the whole share when it's empty.
+    fn files_under(&self, directory: &str) -> Vec<(&str, &types::FileMetadata)> {
+        self.metadata
+            .iter()
+            .filter(|(path, _)| share_links::is_under(path, directory))
+            .map(|(path, metadata)| (path.as_str(), metadata))
+            .collect()
+    }
+
+    /// What a share link to `path` is for: the file, or the current version
+    /// of every file under the directory. `None` if there's nothing there.
+    fn link_claims(&self, path: &str, expires: i64) -> Option<share_links::LinkClaims> {
+        if let Some(metadata) = self.metadata.get(path) {
+            return Some(share_links::LinkClaims {
+                path: path.to_string(),
+                directory: false,
+                version: metadata.hash.clone(),
+                expires,
+            });
+        }
+        let files = self.files_under(path);
+        if files.is_empty() {
+            return None;
+        }
+        Some(share_links::LinkClaims {
+            path: path.to_string(),
+            directory: true,
+            version: share_links::directory_version(files.iter().map(|(path, metadata)| (*path, metadata.hash.as_str()))),
+            expires,
+        })
+    }
+
+    /// One page of metadata sorted by path, starting after the position
+    /// encoded in `continuation`. Returns `None` for a malformed token.
+    fn list_page(
+        &self,
+        prefix: Option<&str>,
+        continuation: Option<&str>,
+        limit: usize,
+    ) -> Option<(Vec<types::FileMetadata>, Option<String>)> {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD as TOKEN, Engine};
+
+        let after = match continuation {
+            Some(token) => Some(String::from_utf8(TOKEN.decode(token).ok()?).ok()?),
+            None => None,
+        };
+        let limit = limit.clamp(1, network::METADATA_PAGE_SIZE);
+
+        let mut paths: Vec<&String> = self.metadata
+            .keys()
+            .filter(|path| prefix.is_none_or(|prefix| path.starts_with(prefix)))
+            .filter(|path| after.as_ref().is_none_or(|after| path.as_str() > after.as_str()))
+            .collect();
+        paths.sort();
+
+        let more = paths.len() > limit;
+        paths.truncate(limit);
+        let next = if more { paths.last().map(|path| TOKEN.encode(path.as_bytes())) } else { None };
+        let files = paths.into_iter().filter_map(|path| self.metadata.get(path).cloned()).collect();
+        Some((files, next))
+    }
+
     fn add_client(&mut self, device_id: String, address: String) {
         self.clients.insert(device_id, address);
     }
 
     fn remove_client(&mut self, device_id: &str) {
         self.clients.remove(device_id);
+        self.client_names.remove(device_id);
+        self.disconnected.remove(device_id);
+    }
+
+    fn disconnect_client(&mut self, device_id: &str) {
+        self.disconnected.insert(device_id.to_string(), std::time::Instant::now());
+    }
+
+    /// Removes clients whose connection ended more than `max_idle` ago,
+    /// returning how many there were.
+    fn remove_stale_clients(&mut self, max_idle: std::time::Duration) -> usize {
+        let stale: Vec<String> = self.disconnected.iter()
+            .filter(|(_, since)| since.elapsed() >= max_idle)
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+        for device_id in &stale {
+            self.remove_client(device_id);
+        }
+        stale.len()
+    }
+
+    fn set_client_name(&mut self, device_id: &str, name: String) {
+        self.client_names.insert(device_id.to_string(), name);
     }
 }
 
+#[derive(Parser)]
+#[command(name = "syncmd-vps")]
+#[command(about = "Store synced folders for syncmd clients")]
+struct VpsCli {
+    #[command(subcommand)]
+    command: VpsCommand,
+
+    /// Append every protocol message sent or received, content left out,
+    /// as a JSON line to this file; see `syncmd trace analyze`
+    #[arg(long, global = true, value_name = "FILE")]
+    trace_protocol: Option<std::path::PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum VpsCommand {
+    #[command(flatten)]
+    Common(Commands),
+
+    /// Encrypt a share's stored files with keys kept by this server
+    AtRest {
+        #[command(subcommand)]
+        action: AtRestAction,
+    },
+
+    /// Record which device read which file when, and look it up
+    AccessLog {
+        #[command(subcommand)]
+        action: AccessLogAction,
+    },
+
+    /// Issue and revoke the tokens devices authenticate with
+    Token {
+        #[command(subcommand)]
+        action: TokenAction,
+    },
+
+    /// Serve download links devices ask for to people without syncmd
+    ShareLinks {
+        #[command(subcommand)]
+        action: ShareLinksAction,
+    },
+
+    /// Serve a read-only web file browser of a share to devices' tokens
+    Browser {
+        #[command(subcommand)]
+        action: BrowserAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum BrowserAction {
+    /// Serve the share's browser over HTTP on this port from the next start;
+    /// tokens are sent in plaintext, put it behind a TLS proxy
+    Enable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+
+        #[arg(long)]
+        port: u16,
+    },
+
+    /// Stop serving the browser from the next start
+    Disable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ShareLinksAction {
+    /// Serve the share's links over HTTP on this port from the next start
+    Enable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+
+        #[arg(long)]
+        port: u16,
+    },
+
+    /// Stop serving links and issuing new ones from the next start
+    Disable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+
+    /// Make every link issued so far stop working
+    Revoke {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum AccessLogAction {
+    /// Record reads of the share's files from the next start
+    Enable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+
+    /// Stop recording reads; what was recorded is kept
+    Disable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+
+    /// Show the latest reads, newest first
+    Show {
+        #[arg(long)]
+        share: std::path::PathBuf,
+
+        /// Only reads of this file, or of the files under this directory
+        path: Option<std::path::PathBuf>,
+
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+
+    /// Show the latest failed authentications, newest first; these are
+    /// recorded whether or not reads are
+    Failures {
+        #[arg(long)]
+        share: std::path::PathBuf,
+
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenAction {
+    /// Issue a token for a device, to pass to its `syncmd init --auth-token`
+    Issue {
+        /// Name of the device the token is for
+        #[arg(long)]
+        name: String,
+
+        /// Days until the token has to be refreshed
+        #[arg(long, default_value_t = 365)]
+        days: u64,
+
+        /// Only allow access to files under PREFIX, as r(ead), w(rite) and
+        /// d(elete) of directories, e.g. `shared=r`; repeat for more
+        /// prefixes
+        #[arg(long = "grant", value_name = "PREFIX=ACCESS")]
+        grants: Vec<security::Grant>,
+
+        /// The share the grants apply to, every share by default
+        #[arg(long, requires = "grants")]
+        share: Option<std::path::PathBuf>,
+    },
+
+    /// List issued tokens
+    List,
+
+    /// Revoke a token, or every token of a device by name
+    Revoke {
+        target: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum AtRestAction {
+    /// Show which shares are encrypted and with which key
+    Status,
+
+    /// Encrypt the share's files, existing ones on the next server start
+    Enable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+
+    /// Store the share's files in plaintext again from the next start
+    Disable {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+
+    /// Seal new files with a fresh key; existing ones are resealed on the
+    /// next start
+    Rotate {
+        #[arg(long)]
+        share: std::path::PathBuf,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     
-    let cli = Cli::parse();
+    let cli = VpsCli::parse();
+    if let Some(path) = &cli.trace_protocol {
+        protocol_trace::enable(path)?;
+    }
     
     match cli.command {
-        Commands::Sync { path, port, .. } => {
+        VpsCommand::Common(Commands::Sync { path, port, .. }) => {
             start_server(path, port).await?;
         }
+        VpsCommand::AtRest { action } => {
+            manage_at_rest(action)?;
+        }
+        VpsCommand::AccessLog { action } => {
+            manage_access_log(action)?;
+        }
+        VpsCommand::Token { action } => {
+            manage_tokens(action)?;
+        }
+        VpsCommand::ShareLinks { action } => {
+            manage_share_links(action)?;
+        }
+        VpsCommand::Browser { action } => {
+            manage_browser(action)?;
+        }
         _ => {
             println!("Server mode only supports sync command");
         }
@@ -76,27 +444,353 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn manage_at_rest(action: AtRestAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = ServerConfig::load()?;
+
+    match action {
+        AtRestAction::Status => {
+            for root in &config.shares {
+                match at_rest::ShareKeyring::load(&root.path)? {
+                    Some(keyring) if root.encrypt_at_rest => println!(
+                        "{}: encrypted with key {} of {}, created {}",
+                        root.path.display(),
+                        keyring.current_id(),
+                        keyring.key_count(),
+                        keyring.created().map(|t| t.to_rfc2822()).unwrap_or_default(),
+                    ),
+                    Some(_) => println!("{}: plaintext, files still sealed are opened on the next start", root.path.display()),
+                    None if root.encrypt_at_rest => println!("{}: encrypted from the next start", root.path.display()),
+                    None => println!("{}: plaintext", root.path.display()),
+                }
+            }
+        }
+        AtRestAction::Enable { share } => {
+            let share = share.canonicalize()?;
+            let keyring = at_rest::ShareKeyring::load_or_create(&share)?;
+            config.share_or_insert(&share).encrypt_at_rest = true;
+            config.save()?;
+            println!("{} is encrypted at rest with key {}", share.display(), keyring.current_id());
+            println!("Its keyring is {}, back it up: the files can't be read without it",
+                at_rest::ShareKeyring::keyring_path(&share)?.display());
+        }
+        AtRestAction::Disable { share } => {
+            let root = find_share(&mut config, &share)?;
+            root.encrypt_at_rest = false;
+            println!("{} is stored in plaintext from the next start; the keyring is kept to open sealed files",
+                root.path.display());
+            config.save()?;
+        }
+        AtRestAction::Rotate { share } => {
+            let share = find_share(&mut config, &share)?.path.clone();
+            let mut keyring = at_rest::ShareKeyring::load(&share)?
+                .ok_or_else(|| format!("{} is not encrypted at rest", share.display()))?;
+            let id = keyring.rotate();
+            keyring.save()?;
+            println!("New files of {} are sealed with key {}, the rest are resealed on the next start", share.display(), id);
+        }
+    }
+
+    Ok(())
+}
+
+fn manage_access_log(action: AccessLogAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = ServerConfig::load()?;
+
+    match action {
+        AccessLogAction::Enable { share } => {
+            let share = share.canonicalize()?;
+            config.share_or_insert(&share).access_log = true;
+            config.save()?;
+            println!("Reads of {} are recorded from the next start", share.display());
+        }
+        AccessLogAction::Disable { share } => {
+            let root = find_share(&mut config, &share)?;
+            root.access_log = false;
+            println!("Reads of {} are no longer recorded from the next start", root.path.display());
+            config.save()?;
+        }
+        AccessLogAction::Show { share, path, limit } => {
+            let store = IndexStore::open(&share)?;
+            let entries = store.access_log(path.as_deref(), limit)?;
+            if entries.is_empty() {
+                println!("No reads recorded");
+            }
+            for entry in entries {
+                println!("{}  {}  {} ({})  {} {}",
+                    entry.timestamp.to_rfc3339(), entry.path.display(), entry.device,
+                    entry.address, entry.kind.as_str(), &entry.hash[..entry.hash.len().min(12)]);
+            }
+        }
+        AccessLogAction::Failures { share, limit } => {
+            let store = IndexStore::open(&share)?;
+            let entries = store.auth_failures(limit)?;
+            if entries.is_empty() {
+                println!("No failed authentications recorded");
+            }
+            for entry in entries {
+                let device = if entry.device.is_empty() { "-" } else { entry.device.as_str() };
+                println!("{}  {}  as {}  {}", entry.timestamp.to_rfc3339(), entry.address, device, entry.reason);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn manage_share_links(action: ShareLinksAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = ServerConfig::load()?;
+
+    match action {
+        ShareLinksAction::Enable { share, port } => {
+            let share = share.canonicalize()?;
+            config.share_or_insert(&share).share_links_port = Some(port);
+            config.save()?;
+            println!("Links to files of {} are served on port {} from the next start", share.display(), port);
+        }
+        ShareLinksAction::Disable { share } => {
+            let root = find_share(&mut config, &share)?;
+            root.share_links_port = None;
+            println!("Links to files of {} are no longer served from the next start", root.path.display());
+            config.save()?;
+        }
+        ShareLinksAction::Revoke { share } => {
+            let share = find_share(&mut config, &share)?.path.clone();
+            share_links::rotate_key(&share)?;
+            println!("Links issued for {} stop working once the server restarts", share.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn manage_browser(action: BrowserAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = ServerConfig::load()?;
+
+    match action {
+        BrowserAction::Enable { share, port } => {
+            let share = share.canonicalize()?;
+            config.share_or_insert(&share).browser_port = Some(port);
+            config.save()?;
+            println!("{} can be browsed on port {} from the next start, with a device token as the password", share.display(), port);
+        }
+        BrowserAction::Disable { share } => {
+            let root = find_share(&mut config, &share)?;
+            root.browser_port = None;
+            println!("{} is no longer browsable from the next start", root.path.display());
+            config.save()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn manage_tokens(action: TokenAction) -> Result<(), Box<dyn std::error::Error>> {
+    let path = security::tokens_path()?;
+    let mut auth = match &action {
+        TokenAction::Issue { days, .. } => security::AuthManager::with_config(
+            std::time::Duration::from_secs(days * 24 * 60 * 60),
+            SESSION_TIMEOUT,
+            MAX_TOKENS_PER_DEVICE,
+        ),
+        _ => security::AuthManager::new(),
+    };
+    auth.load_tokens(&path)?;
+
+    match action {
+        TokenAction::Issue { name, grants, share, .. } => {
+            // A device's tokens share its id, so reissuing revokes the oldest
+            let client_id = auth.list_active_tokens()
+                .into_iter()
+                .find(|t| t.client_name == name)
+                .map(|t| t.client_id.clone())
+                .unwrap_or_else(security::generate_client_id);
+            let token = auth.generate_token(client_id, name.clone())?;
+            let share = share.map(|share| share.canonicalize()).transpose()?;
+            let grants: Vec<security::Grant> = grants.into_iter()
+                .map(|grant| security::Grant { share: share.clone(), ..grant })
+                .collect();
+            auth.set_grants(&token, grants.clone());
+            auth.save_tokens(&path)?;
+            println!("Token for {}: {}", name, token);
+            for grant in &grants {
+                println!("  may access {}", grant);
+            }
+            println!("On the device: syncmd init --auth-token {}", token);
+        }
+        TokenAction::List => {
+            let mut tokens = auth.list_tokens();
+            tokens.sort_by_key(|t| t.created_at);
+            if tokens.is_empty() {
+                println!("No tokens issued");
+            }
+            let now = chrono::Utc::now();
+            for token in tokens {
+                let status = if token.is_revoked {
+                    "revoked".to_string()
+                } else if token.expires_at < now {
+                    format!("expired {}", token.expires_at.to_rfc2822())
+                } else {
+                    format!("valid until {}", token.expires_at.to_rfc2822())
+                };
+                println!("{}  {}  issued {}, {}",
+                    token.token, token.client_name, token.created_at.to_rfc2822(), status);
+                for grant in &token.grants {
+                    println!("    may access {}", grant);
+                }
+            }
+        }
+        TokenAction::Revoke { target } => {
+            let targets: Vec<String> = auth.list_tokens()
+                .into_iter()
+                .filter(|t| !t.is_revoked && (t.token == target || t.client_name == target))
+                .map(|t| t.token.clone())
+                .collect();
+            if targets.is_empty() {
+                return Err(format!("No valid token is {} or belongs to a device of that name", target).into());
+            }
+            for token in &targets {
+                auth.revoke_token(token);
+            }
+            auth.save_tokens(&path)?;
+            println!("Revoked {} token(s); connections using them are refused from now on", targets.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn find_share<'a>(config: &'a mut ServerConfig, share: &std::path::Path) -> Result<&'a mut ShareConfig, Box<dyn std::error::Error>> {
+    Ok(config.share_mut(share).ok_or_else(|| format!("{} is not a configured share", share.display()))?)
+}
+
 async fn start_server(
     storage_path: std::path::PathBuf,
     port: u16,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::load()?;
-    let client_manager = Arc::new(ClientManager::new());
-    let state = Arc::new(RwLock::new(ServerState::new()));
+    let tokens_path = security::tokens_path()?;
+    let client_manager = Arc::new(ClientManager::with_auth(security::AuthManager::with_config(
+        security::ISSUED_TOKEN_LIFETIME,
+        SESSION_TIMEOUT,
+        MAX_TOKENS_PER_DEVICE,
+    )));
+    client_manager.load_tokens(&tokens_path)?;
+    let server_config = ServerConfig::load()?;
+    let share_config = server_config.share(&storage_path).cloned().unwrap_or_default();
+    let max_attempts = server_config.auth_max_attempts.unwrap_or(lockout::DEFAULT_MAX_ATTEMPTS);
+    let state = Arc::new(RwLock::new(ServerState {
+        lockout: lockout::AuthLockout::new(max_attempts),
+        ..ServerState::new()
+    }));
+    let (changes, _) = tokio::sync::broadcast::channel(CHANGE_BACKLOG);
     
     println!("Starting syncmd VPS server");
     println!("Server ID: {}", client_manager.server_id());
     println!("Server Name: {}", config.device_name);
     println!("Storage path: {:?}", storage_path);
     println!("Port: {}", port);
+    println!("Addresses are locked out after {} failed authentications", max_attempts);
     
     // Initialize storage directory
     if !storage_path.exists() {
         std::fs::create_dir_all(&storage_path)?;
     }
     
+    // A keyring is kept after encryption is disabled, to open sealed files
+    let encrypted = share_config.encrypt_at_rest;
+    let keyring = if encrypted {
+        Some(at_rest::ShareKeyring::load_or_create(&storage_path)?)
+    } else {
+        at_rest::ShareKeyring::load(&storage_path)?
+    };
+    if encrypted {
+        println!("Files are encrypted at rest");
+    }
+    let scanner = share_config.scan_cmd.clone()
+        .map(|command| Arc::new(scan::Scanner::new(command, &storage_path)));
+    if let Some(scanner) = &scanner {
+        println!("Uploads are scanned with: {}", scanner.command());
+    }
+    // Uploads are journaled there, and reads too if the share logs access
+    let journal = Arc::new(std::sync::Mutex::new(IndexStore::open(&storage_path)?));
+    let access_log = match share_config.access_log {
+        true => Some(journal.clone()),
+        false => None,
+    };
+    if access_log.is_some() {
+        println!("Reads are recorded in the access log");
+    }
+    let share_links = match share_config.share_links_port {
+        Some(port) => Some(Arc::new(share_links::ShareLinks::load_or_create(&storage_path, port)?)),
+        None => None,
+    };
+    if !tokens_path.exists() {
+        println!("No tokens issued yet, devices can't connect until one is: syncmd-vps token issue --name <device>");
+    }
+    
+    let backend: Arc<dyn storage::StorageBackend> =
+        storage::open(share_config.storage.as_ref(), &storage_path)?.into();
+    println!("Files are stored in {}", backend.describe());
+    
     // Load existing files from storage
-    load_existing_files(&state, &storage_path).await?;
+    load_existing_files(&state, backend.as_ref(), keyring.as_ref(), encrypted).await?;
+    let directories = journal.lock().unwrap().shared_directories()?;
+    state.write().await.directories = directories;
+    let seal = keyring.filter(|_| encrypted).map(Arc::new);
+    let storage = Storage { path: storage_path.clone(), backend, seal, scanner, journal, access_log, share_links };
+    
+    if let Some(links) = &storage.share_links {
+        let listener = tokio::net::TcpListener::bind(&format!("0.0.0.0:{}", links.port)).await
+            .map_err(|e| format!("Failed to bind share link port {}: {}", links.port, e))?;
+        println!("Share links are served on port {}", links.port);
+        tokio::spawn(serve_share_links(listener, state.clone(), storage.clone()));
+    }
+    if let Some(port) = share_config.browser_port {
+        let listener = tokio::net::TcpListener::bind(&format!("0.0.0.0:{}", port)).await
+            .map_err(|e| format!("Failed to bind file browser port {}: {}", port, e))?;
+        println!("The file browser is served on port {}", port);
+        tokio::spawn(serve_browser(listener, state.clone(), storage.clone(), client_manager.clone(), tokens_path.clone()));
+    }
+    
+    tokio::spawn({
+        let state = state.clone();
+        let client_manager = client_manager.clone();
+        // Windowed uploads leave partial files in the share's state
+        let roots = vec![storage_path.clone()];
+        async move {
+            let mut interval = tokio::time::interval(maintenance::CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let mut run = maintenance::run(&client_manager, &roots).await;
+                let mut state_guard = state.write().await;
+                run.stale_clients += state_guard.remove_stale_clients(maintenance::CLIENT_MAX_IDLE) as u64;
+                state_guard.lockout.forget_idle(std::time::Instant::now());
+                state_guard.cleanup.record(&run);
+                if run.removed() > 0 {
+                    println!("Cleanup removed {} ({} since start)", run, state_guard.cleanup);
+                }
+            }
+        }
+    });
+    
+    tokio::spawn({
+        let journal = storage.journal.clone();
+        let share = storage_path.clone();
+        async move {
+            let mut interval = tokio::time::interval(maintenance::GC_INTERVAL);
+            loop {
+                interval.tick().await;
+                match maintenance::purge_deleted(&mut journal.lock().unwrap(), &share) {
+                    Ok(report) if report.tombstones_removed > 0 || report.versions_removed > 0 => {
+                        println!("Retention GC removed {} tombstones and {} deleted versions",
+                            report.tombstones_removed, report.versions_removed);
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Retention GC error: {}", e),
+                }
+            }
+        }
+    });
     
     let _network_manager = NetworkManager::new(client_manager.clone(), format!("0.0.0.0:{}", port));
     
@@ -110,9 +804,26 @@ async fn start_server(
             Ok((stream, addr)) => {
                 let state = state.clone();
                 let client_manager = client_manager.clone();
+                let changes = changes.clone();
+                let storage = storage.clone();
+                let tokens_path = tokens_path.clone();
                 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client_connection(stream, state, client_manager, addr.to_string()).await {
+                    // Devices behind restrictive firewalls connect with a
+                    // WebSocket upgrade on the same port
+                    let stream = match websocket::is_upgrade(&stream).await {
+                        true => websocket::accept(stream).await,
+                        false => Ok(stream),
+                    };
+                    let stream = match stream {
+                        Ok(stream) => protocol_trace::wrap(stream, &addr.to_string()).await,
+                        Err(e) => Err(e),
+                    };
+                    let result = match stream {
+                        Ok(stream) => handle_client_connection(stream, state, client_manager, &tokens_path, changes, storage, addr.to_string()).await,
+                        Err(e) => Err(e.into()),
+                    };
+                    if let Err(e) = result {
                         eprintln!("Client connection error: {}", e);
                     }
                 });
@@ -122,111 +833,542 @@ async fn start_server(
     }
 }
 
+/// Where uploaded files are written, the keyring sealing them when the
+/// share is encrypted at rest, and the scanner they must pass first.
+#[derive(Clone)]
+struct Storage {
+    /// The share's folder, holding its state and, stored on disk, its files
+    path: std::path::PathBuf,
+    backend: Arc<dyn storage::StorageBackend>,
+    seal: Option<Arc<at_rest::ShareKeyring>>,
+    scanner: Option<Arc<scan::Scanner>>,
+    /// The share's change journal, replicated to clients for history
+    journal: Arc<std::sync::Mutex<IndexStore>>,
+    /// Where reads are recorded when the share logs access
+    access_log: Option<Arc<std::sync::Mutex<IndexStore>>>,
+    /// Set when the share serves share links
+    share_links: Option<Arc<share_links::ShareLinks>>,
+}
+
+impl Storage {
+    async fn write(&self, path: &str, content: &[u8], permissions: &types::FilePermissions) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.seal {
+            Some(keyring) => self.backend.put(path, &keyring.seal(path, content)?, None, permissions).await?,
+            None => self.backend.put(path, content, None, permissions).await?,
+        }
+        Ok(())
+    }
+
+    /// Keeps a refused upload of `path` in the share's quarantine and says
+    /// where. It goes to the share's folder rather than the backend, which
+    /// only stores the share's files.
+    fn quarantine(&self, path: &str, content: &[u8]) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        let quarantined = scan::quarantine_path(path);
+        let content = match &self.seal {
+            Some(keyring) => keyring.seal(&quarantined.to_string_lossy(), content)?,
+            None => content.to_vec(),
+        };
+        let file = self.path.join(&quarantined);
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(file, content)?;
+        Ok(quarantined)
+    }
+
+    /// A stored file's content as it was uploaded, `None` if it's gone.
+    async fn read(&self, path: &str) -> Result<Option<Vec<u8>>, types::SyncError> {
+        match (self.backend.get(path).await?, &self.seal) {
+            (Some(stored), Some(keyring)) => Ok(Some(keyring.open(path, &stored)?)),
+            (stored, _) => Ok(stored),
+        }
+    }
+
+    /// A stored file's content if it is still the version with this
+    /// `content_hash`, `None` if it's gone or was replaced since.
+    async fn read_version(&self, path: &str, content_hash: &str) -> Result<Option<Vec<u8>>, types::SyncError> {
+        let content = self.read(path).await?;
+        Ok(content.filter(|content| blake3::hash(content).to_hex().as_str() == content_hash))
+    }
+
+    /// Up to `len` bytes of a stored file's content from `offset`. Sealed
+    /// files are opened whole, plaintext ones read only that far.
+    async fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<Option<Vec<u8>>, types::SyncError> {
+        if self.seal.is_none() {
+            return self.backend.get_range(path, offset, len).await;
+        }
+        Ok(self.read(path).await?.map(|content| {
+            let start = content.len().min(usize::try_from(offset).unwrap_or(usize::MAX));
+            let end = content.len().min(start.saturating_add(usize::try_from(len).unwrap_or(usize::MAX)));
+            content[start..end].to_vec()
+        }))
+    }
+
+    /// Records a stored upload in the journal, unless it's the version
+    /// already there. A failure to record doesn't fail the upload.
+    fn journal_upload(&self, metadata: &types::FileMetadata, previous: Option<&types::FileMetadata>) {
+        let op = match previous {
+            None => index_store::JournalOp::Add,
+            Some(previous) if !previous.same_version(metadata) => index_store::JournalOp::Update,
+            Some(_) => return,
+        };
+        let journal = self.journal.lock().unwrap();
+        if let Err(e) = journal.append_journal(op, &metadata.path, Some(&metadata.hash), &metadata.device_id) {
+            eprintln!("Could not journal {}: {}", metadata.path.display(), e);
+        }
+    }
+
+    /// Records that `device` read this version of `path`, if the share logs
+    /// access. A failure to log doesn't fail the read.
+    fn log_access(&self, path: &std::path::Path, hash: &str, device: &str, address: &str, kind: AccessKind) {
+        if let Some(log) = &self.access_log {
+            if let Err(e) = log.lock().unwrap().record_access(path, hash, device, address, kind) {
+                eprintln!("Could not log access to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Records a failed authentication in the audit log, which is kept
+    /// whether or not the share logs access.
+    fn log_auth_failure(&self, address: &str, device: &str, error: &types::SyncError) {
+        if let Err(e) = self.journal.lock().unwrap().record_auth_failure(address, device, &error.to_string()) {
+            eprintln!("Could not log the failed authentication from {}: {}", address, e);
+        }
+    }
+}
+
+/// The current version of a stored file with its metadata, if `readable`.
+/// Content and metadata are read apart, so an upload landing in between is
+/// caught by the hash and the read tried again.
+async fn read_current(
+    state: &RwLock<ServerState>,
+    storage: &Storage,
+    path: &str,
+    readable: impl Fn(&types::FileMetadata) -> bool,
+) -> Result<Option<(types::FileMetadata, Vec<u8>)>, types::SyncError> {
+    for _ in 0..READ_ATTEMPTS {
+        let Some((metadata, content_hash)) = state.read().await.get_version(path).filter(|(metadata, _)| readable(metadata)) else {
+            return Ok(None);
+        };
+        if let Some(content) = storage.read_version(path, &content_hash).await? {
+            return Ok(Some((metadata, content)));
+        }
+    }
+    Ok(None)
+}
+
+/// The answer to an address that is locked out after too many failed
+/// authentications, `None` if it may try.
+async fn lockout_response(state: &RwLock<ServerState>, address: std::net::IpAddr) -> Option<NetworkMessage> {
+    let remaining = state.read().await.lockout.locked(address, std::time::Instant::now())?;
+    Some(NetworkMessage::AuthResponse {
+        success: false,
+        client_id: None,
+        message: format!("Too many failed attempts, try again in {}s", remaining.as_secs() + 1),
+        failure: Some(network::AuthFailure::LockedOut),
+        capabilities: Vec::new(),
+    })
+}
+
+/// Answers an HTTP request from a locked out address, `false` if it isn't.
+async fn refuse_locked_out(stream: &mut tokio::net::TcpStream, state: &RwLock<ServerState>, address: std::net::IpAddr) -> Result<bool, types::SyncError> {
+    let Some(remaining) = state.read().await.lockout.locked(address, std::time::Instant::now()) else {
+        return Ok(false);
+    };
+    let retry_after = (remaining.as_secs() + 1).to_string();
+    let message = format!("Too many failed attempts, try again in {}s", retry_after);
+    share_links::respond_with(stream, (429, "Too Many Requests"), "text/plain; charset=utf-8", &[("Retry-After", &retry_after)], message.as_bytes()).await?;
+    Ok(true)
+}
+
+/// Counts a failed authentication against the address it came from and
+/// records it in the audit log.
+async fn auth_failed(state: &RwLock<ServerState>, storage: &Storage, client_addr: &str, device: &str, error: &types::SyncError) {
+    storage.log_auth_failure(client_addr, device, error);
+    let Ok(address) = client_addr.parse::<std::net::SocketAddr>() else {
+        return;
+    };
+    if let Some(lockout) = state.write().await.lockout.record_failure(address.ip(), std::time::Instant::now()) {
+        eprintln!("Locked out {} for {}s after too many failed authentications", address.ip(), lockout.as_secs());
+    }
+}
+
+/// Loads the stored files, opening sealed ones with `keyring`. Files that
+/// don't match the share's setting, plaintext in an encrypted share, sealed
+/// in a plaintext one or sealed with an older key, are rewritten.
 async fn load_existing_files(
     state: &Arc<RwLock<ServerState>>,
-    storage_path: &std::path::PathBuf,
+    backend: &dyn storage::StorageBackend,
+    keyring: Option<&at_rest::ShareKeyring>,
+    encrypted: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut state_guard = state.write().await;
+    let mut rewritten = 0;
     
-    if storage_path.exists() {
-        for entry in std::fs::read_dir(storage_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.is_file() {
-                let relative_path = path.strip_prefix(storage_path)?;
-                let content = std::fs::read(&path)?;
-                let metadata = std::fs::metadata(&path)?;
-                
-                let hash = blake3::hash(&content).to_hex().to_string();
-                
-                let file_metadata = types::FileMetadata {
-                    path: relative_path.to_path_buf(),
-                    hash,
-                    size: metadata.len(),
-                    modified: metadata.modified()?,
-                    created: metadata.created()?,
-                    version: metadata.modified()?.duration_since(std::time::SystemTime::UNIX_EPOCH)?.as_secs(),
-                    device_id: "vps-server".to_string(),
-                };
-                
-                state_guard.add_file(
-                    relative_path.to_string_lossy().to_string(),
-                    content,
-                    file_metadata,
-                );
-            }
+    for name in backend.list().await? {
+        // Gone since it was listed
+        let (Some(stored), Some(metadata)) = (backend.get(&name).await?, backend.metadata(&name).await?) else {
+            continue;
+        };
+        let relative_path = std::path::PathBuf::from(&name);
+        let sealed_with = at_rest::sealed_with(&stored);
+        let content = match (keyring, sealed_with) {
+            (Some(keyring), _) => keyring.open(&name, &stored)?,
+            (None, Some(_)) => return Err(format!("{} is encrypted but the share has no keyring", name).into()),
+            (None, None) => stored,
+        };
+        let reseal = match keyring.filter(|_| encrypted) {
+            Some(keyring) => sealed_with != Some(keyring.current_id()),
+            None => sealed_with.is_some(),
+        };
+        if reseal {
+            let rewritten_content = match keyring.filter(|_| encrypted) {
+                Some(keyring) => keyring.seal(&name, &content)?,
+                None => content.clone(),
+            };
+            // Keep the modification time, clients compare it
+            backend.put(&name, &rewritten_content, Some(metadata.modified), &metadata.permissions).await?;
+            rewritten += 1;
         }
+        
+        let hash = blake3::hash(&content).to_hex().to_string();
+        
+        let file_metadata = types::FileMetadata {
+            path: relative_path,
+            hash,
+            size: content.len() as u64,
+            modified: metadata.modified.into(),
+            created: metadata.created.into(),
+            version: metadata.modified.duration_since(std::time::SystemTime::UNIX_EPOCH)?.as_secs(),
+            device_id: "vps-server".to_string(),
+            versions: Default::default(),
+            permissions: metadata.permissions,
+        };
+        
+        state_guard.add_file(name, &content, file_metadata);
     }
     
-    println!("Loaded {} files from storage", state_guard.files.len());
+    println!("Loaded {} files from storage", state_guard.metadata.len());
+    if rewritten > 0 {
+        let form = if encrypted { "sealed with the current key" } else { "in plaintext" };
+        println!("Rewrote {} stored file(s) {}", rewritten, form);
+    }
     Ok(())
 }
 
+/// The client registered by a connection, marked as disconnected when the
+/// connection ends for whatever reason so cleanup can forget it later.
+struct ClientSession {
+    state: Arc<RwLock<ServerState>>,
+    client_id: Option<String>,
+    /// Name the client's token was issued to, which the access log records
+    device: String,
+    /// What the client's token allows in this share
+    scope: security::Scope,
+}
+
+impl ClientSession {
+    /// Records the client a connection authenticated as. A connection that
+    /// authenticates again has left its earlier client behind.
+    fn set_client(&mut self, client_id: String, device: String, scope: security::Scope) {
+        self.device = device;
+        self.scope = scope;
+        if let Some(previous) = self.client_id.replace(client_id) {
+            self.mark_disconnected(previous);
+        }
+    }
+
+    /// Forgets the client after a failed authentication, so the connection
+    /// isn't left authenticated as it.
+    fn clear_client(&mut self) {
+        self.scope = security::Scope::default();
+        if let Some(previous) = self.client_id.take() {
+            self.mark_disconnected(previous);
+        }
+    }
+
+    fn can_read(&self, path: &std::path::Path) -> bool {
+        self.scope.allows(path, security::Access::Read)
+    }
+
+    fn mark_disconnected(&self, client_id: String) {
+        let state = self.state.clone();
+        tokio::spawn(async move { state.write().await.disconnect_client(&client_id) });
+    }
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        if let Some(client_id) = self.client_id.take() {
+            self.mark_disconnected(client_id);
+        }
+    }
+}
+
 async fn handle_client_connection(
     mut stream: tokio::net::TcpStream,
     state: Arc<RwLock<ServerState>>,
-    _client_manager: Arc<ClientManager>,
+    client_manager: Arc<ClientManager>,
+    tokens_path: &std::path::Path,
+    changes: tokio::sync::broadcast::Sender<ServerChange>,
+    storage: Storage,
     client_addr: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::AsyncWriteExt;
     
-    let mut buffer = vec![0u8; 8192];
+    // Clients pipeline requests, so several can arrive in one read
+    let mut reader = network::MessageReader::new();
+    let mut session = ClientSession {
+        state: state.clone(),
+        client_id: None,
+        device: String::new(),
+        scope: security::Scope::default(),
+    };
+    // Grants name shares by their canonical path
+    let share = storage.path.canonicalize().unwrap_or_else(|_| storage.path.clone());
+    let mut pending_files: Vec<types::FileMetadata> = Vec::new();
+    let mut subscription = types::Subscription::default();
+    let mut negotiated: Vec<capabilities::Capability> = Vec::new();
+    // Large responses go out compressed once the client negotiated it
+    let mut encoding = network::Encoding::default();
+    // Built when a negotiation starts at the root and reused for the
+    // requests that walk down from there
+    let mut tree = merkle::MerkleTree::default();
+    let address = client_addr.parse::<std::net::SocketAddr>()?.ip();
     
     loop {
-        let n = stream.read(&mut buffer).await?;
-        
-        if n == 0 {
+        // Until it authenticates a client can't make the server buffer
+        // large messages or hold the connection open
+        let message = match session.client_id {
+            Some(_) => {
+                reader.set_limit(None);
+                reader.next(&mut stream).await?
+            }
+            None => {
+                reader.set_limit(Some(HANDSHAKE_MESSAGE_LEN));
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, reader.next(&mut stream))
+                    .await
+                    .map_err(|_| types::SyncError::Network(format!("{} did not authenticate in time", client_addr)))??
+            }
+        };
+        let Some(message) = message else {
             println!("Client disconnected: {}", client_addr);
             break;
-        }
+        };
         
-        let message: NetworkMessage = serde_json::from_slice(&buffer[..n])?;
+        // A windowed upload is taken in here, then stored like any other
+        let (message, windowed) = match message {
+            NetworkMessage::WindowedUpload { path, metadata, idempotency_key } if session.client_id.is_some() => {
+                let seen = match &idempotency_key {
+                    Some(key) => state.read().await.idempotency.get(&session.device, key),
+                    None => None,
+                };
+                if let Some(rejection) = seen.or_else(|| upload_refusal(&session, &path, &metadata, &client_addr).map(Some)) {
+                    let response = NetworkMessage::UploadResult { path, rejection };
+                    stream.write_all(&serde_json::to_vec(&response)?).await?;
+                    continue;
+                }
+                stream.write_all(&serde_json::to_vec(&NetworkMessage::UploadReady { path: path.clone() })?).await?;
+                let destination = storage.path.join(&path);
+                let received = network::transfer_manager(&negotiated)
+                    .receive_content(&mut stream, reader.frames(), &storage.path, &destination)
+                    .await;
+                match received {
+                    Ok(content) => (NetworkMessage::FileTransfer { path, content, metadata, idempotency_key }, true),
+                    Err(types::SyncError::Cancelled(_)) => {
+                        println!("{} cancelled the upload of {}", client_addr, path);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            message => (message, false),
+        };
         
         match message {
-            NetworkMessage::Authenticate { token: _, client_name } => {
+            NetworkMessage::Authenticate { token, client_name, capabilities: client_capabilities } => {
                 println!("Authentication request from: {}", client_name);
+                if let Some(response) = lockout_response(&state, address).await {
+                    println!("Refused {} from {}, the address is locked out", client_name, client_addr);
+                    session.clear_client();
+                    stream.write_all(&serde_json::to_vec(&response)?).await?;
+                    continue;
+                }
+                
+                // Tokens are issued and revoked by `syncmd-vps token` while
+                // the server runs, so check against the saved ones
+                let checked = client_manager.load_tokens(tokens_path)
+                    .and_then(|_| client_manager.check_token(&token));
+                let auth_token = match checked {
+                    Ok(auth_token) => auth_token,
+                    Err(e) => {
+                        println!("Authentication failed for client {}: {}", client_name, e);
+                        auth_failed(&state, &storage, &client_addr, &client_name, &e).await;
+                        session.clear_client();
+                        let response = NetworkMessage::AuthResponse {
+                            success: false,
+                            client_id: None,
+                            message: e.to_string(),
+                            failure: Some(network::AuthFailure::from_error(&e)),
+                            capabilities: Vec::new(),
+                        };
+                        stream.write_all(&serde_json::to_vec(&response)?).await?;
+                        continue;
+                    }
+                };
                 
-                // For VPS server, we'll accept any token for now
                 // Add client to state
                 let client_id = format!("client_{}", uuid::Uuid::new_v4());
-                state.write().await.add_client(client_id.clone(), client_addr.clone());
+                {
+                    let mut state_guard = state.write().await;
+                    state_guard.lockout.record_success(address);
+                    state_guard.add_client(client_id.clone(), client_addr.clone());
+                    state_guard.set_client_name(&client_id, client_name);
+                }
+                let scope = auth_token.scope(&share);
+                if scope.is_restricted() {
+                    println!("Token of {} is limited to its grants", auth_token.client_name);
+                }
+                session.set_client(client_id.clone(), auth_token.client_name, scope);
+                negotiated = capabilities::negotiate(VPS_CAPABILITIES, &client_capabilities);
+                encoding = network::Encoding::negotiated(&negotiated);
+                println!("Negotiated capabilities: {}", capabilities::describe(&negotiated));
                 
                 let response = NetworkMessage::AuthResponse {
                     success: true,
                     client_id: Some(client_id),
                     message: "Authentication successful".to_string(),
+                    failure: None,
+                    capabilities: negotiated.clone(),
                 };
                 
                 let response_data = serde_json::to_vec(&response)?;
                 stream.write_all(&response_data).await?;
             }
             
-            NetworkMessage::SyncRequest { client_id, files } => {
+            NetworkMessage::RefreshToken { token } => {
+                if let Some(response) = lockout_response(&state, address).await {
+                    stream.write_all(&serde_json::to_vec(&response)?).await?;
+                    continue;
+                }
+                let refreshed = client_manager.load_tokens(tokens_path)
+                    .and_then(|_| client_manager.refresh_token(&token))
+                    .and_then(|token| client_manager.save_tokens(tokens_path).map(|_| token));
+                let response = match refreshed {
+                    Ok(token) => NetworkMessage::TokenRefreshed { token },
+                    Err(e) => {
+                        auth_failed(&state, &storage, &client_addr, &session.device, &e).await;
+                        NetworkMessage::AuthResponse {
+                            success: false,
+                            client_id: None,
+                            message: e.to_string(),
+                            failure: Some(network::AuthFailure::from_error(&e)),
+                            capabilities: Vec::new(),
+                        }
+                    }
+                };
+                stream.write_all(&serde_json::to_vec(&response)?).await?;
+            }
+            
+            message if session.client_id.is_none() && message.requires_auth() => {
+                println!("Refused a request from unauthenticated client {}", client_addr);
+                let response = NetworkMessage::AuthError {
+                    failure: network::AuthFailure::Unauthenticated,
+                    message: "Authenticate before making requests".to_string(),
+                };
+                stream.write_all(&serde_json::to_vec(&response)?).await?;
+            }
+            
+            NetworkMessage::TreeRequest { paths } => {
+                if paths.iter().any(|path| path.as_os_str().is_empty()) {
+                    let state_guard = state.read().await;
+                    tree = merkle::MerkleTree::build(
+                        state_guard.list_files()
+                            .into_iter()
+                            .filter(|metadata| subscription.includes(&metadata.path) && session.can_read(&metadata.path)),
+                    );
+                }
+                let nodes = paths.iter().map(|path| tree.node(path)).collect();
+                stream.write_all(&network::encode_message(&NetworkMessage::TreeResponse { nodes }, encoding)?).await?;
+            }
+            
+            NetworkMessage::SyncRequest { client_id, files, more, scope, directories } => {
+                // Large indexes arrive in pages; answer once the last is in
+                pending_files.extend(files);
+                if more {
+                    continue;
+                }
+                let files = std::mem::take(&mut pending_files);
                 println!("Sync request from {} with {} files", client_id, files.len());
                 
+                // Directories come first, so files have them to land in
+                let mut operations = Vec::new();
+                if let Some(listing) = directories.filter(|_| negotiated.contains(&capabilities::Capability::Directories)) {
+                    let mut state_guard = state.write().await;
+                    let before = state_guard.directories.clone();
+                    operations = sync::sync_directories(
+                        &mut state_guard.directories,
+                        &listing.present,
+                        &listing.known,
+                        |path| session.scope.allows(path, security::Access::Write),
+                        |path| session.scope.allows(path, security::Access::Delete),
+                        |path| subscription.includes(path) && session.can_read(path),
+                    );
+                    if state_guard.directories != before {
+                        if let Err(e) = storage.journal.lock().unwrap().save_shared_directories(&state_guard.directories) {
+                            eprintln!("Could not save the share's directories: {}", e);
+                        }
+                    }
+                }
+                
                 let state_guard = state.read().await;
-                let server_files = state_guard.list_files();
+                let server_files: Vec<&types::FileMetadata> = state_guard.list_files()
+                    .into_iter()
+                    .filter(|metadata| subscription.includes(&metadata.path) && session.can_read(&metadata.path))
+                    .filter(|metadata| scope.as_ref()
+                        .is_none_or(|scope| scope.iter().any(|path| metadata.path.starts_with(path))))
+                    .collect();
                 
-                // Calculate sync operations
-                let operations = calculate_sync_operations_for_client(&files, &server_files);
+                operations.extend(calculate_sync_operations_for_client(&files, &server_files));
                 
-                let response = NetworkMessage::SyncResponse { operations };
-                let response_data = serde_json::to_vec(&response)?;
+                // Short notes go out with the response instead of as
+                // transfers, for clients that know to look for them
+                let mut inline = types::InlineContent::default();
+                let inline_operations = if negotiated.contains(&capabilities::Capability::InlineContent) {
+                    operations.as_slice()
+                } else {
+                    &[]
+                };
+                for operation in inline_operations {
+                    if let types::SyncOperation::Add(metadata) | types::SyncOperation::Update(metadata) = operation {
+                        if metadata.size <= types::InlineContent::MAX_FILE_SIZE {
+                            let path = metadata.path.to_string_lossy();
+                            let content_hash = state_guard.content_hashes.get(path.as_ref());
+                            if let Some(content) = storage.read_version(&path, content_hash.map_or("", |hash| hash.as_str())).await? {
+                                storage.log_access(&metadata.path, &metadata.hash, &session.device, &client_addr, AccessKind::Inline);
+                                inline.insert(metadata.path.clone(), &content);
+                            }
+                        }
+                    }
+                }
+                
+                let response = NetworkMessage::SyncResponse { operations, inline };
+                let response_data = network::encode_message(&response, encoding)?;
                 stream.write_all(&response_data).await?;
             }
             
             NetworkMessage::FileRequest { path } => {
                 println!("File request for: {}", path);
                 
-                let state_guard = state.read().await;
-                let response = if let Some(content) = state_guard.get_file(&path) {
-                    let metadata = state_guard.get_metadata(&path).cloned();
+                let readable = session.can_read(std::path::Path::new(path.trim_start_matches('/')));
+                let current = read_current(&state, &storage, &path, |_| readable).await?;
+                let response = if let Some((metadata, content)) = current {
+                    storage.log_access(&metadata.path, &metadata.hash, &session.device, &client_addr, AccessKind::Download);
                     NetworkMessage::FileResponse {
                         path: path.clone(),
                         found: true,
-                        content: Some(content.clone()),
-                        metadata,
+                        content: Some(content),
+                        metadata: Some(metadata),
                     }
                 } else {
                     NetworkMessage::FileResponse {
@@ -237,33 +1379,210 @@ async fn handle_client_connection(
                     }
                 };
                 
-                let response_data = serde_json::to_vec(&response)?;
+                let response_data = network::encode_message(&response, encoding)?;
                 stream.write_all(&response_data).await?;
             }
             
-            NetworkMessage::FileTransfer { path, content, metadata } => {
-                println!("Legacy file transfer: {} ({} bytes)", path, content.len());
+            NetworkMessage::WindowedFileRequest { path } => {
+                println!("Windowed file request for: {}", path);
                 
-                // Handle legacy file transfer (for backwards compatibility)
-                let mut state_guard = state.write().await;
-                state_guard.add_file(path.clone(), content, metadata);
-                
-                // Persist to disk
-                let storage_path = std::path::PathBuf::from("./vps_storage");
-                if !storage_path.exists() {
-                    std::fs::create_dir_all(&storage_path)?;
+                let readable = session.can_read(std::path::Path::new(path.trim_start_matches('/')));
+                let current = read_current(&state, &storage, &path, |_| readable).await?;
+                let response = NetworkMessage::FileResponse {
+                    path: path.clone(),
+                    found: current.is_some(),
+                    content: None,
+                    metadata: current.as_ref().map(|(metadata, _)| metadata.clone()),
+                };
+                stream.write_all(&network::encode_message(&response, encoding)?).await?;
+                if let Some((metadata, content)) = current {
+                    let sent = network::transfer_manager(&negotiated)
+                        .send_content(&mut stream, reader.frames(), &path, content, metadata.clone())
+                        .await;
+                    match sent {
+                        Ok(()) => storage.log_access(&metadata.path, &metadata.hash, &session.device, &client_addr, AccessKind::Download),
+                        Err(types::SyncError::Cancelled(_)) => println!("{} cancelled the download of {}", client_addr, path),
+                        Err(e) => return Err(e.into()),
+                    }
                 }
-                
-                let file_path = storage_path.join(&path);
-                if let Some(parent) = file_path.parent() {
-                    std::fs::create_dir_all(parent)?;
+            }
+            
+            NetworkMessage::ChunkRequest { path, hash, index, chunk_size } => {
+                // Uploads store content holding the write lock, so while this
+                // holds the read lock the stored file is the version `hash`
+                // names, and every chunk of a download comes from that one
+                let state_guard = state.read().await;
+                let size = state_guard.get_metadata(&path)
+                    .filter(|metadata| metadata.hash == hash)
+                    .filter(|_| session.can_read(std::path::Path::new(path.trim_start_matches('/'))))
+                    .map(|metadata| metadata.size);
+                let chunk_size = chunk_size.clamp(1, network::MAX_CHUNK_SIZE);
+                let start = index.checked_mul(chunk_size).filter(|start| size.is_some_and(|size| *start <= size));
+                let (data, last) = match (size, start) {
+                    (Some(size), Some(start)) => {
+                        let data = storage.read_range(&path, start, chunk_size).await?;
+                        (data, start.saturating_add(chunk_size) >= size)
+                    }
+                    _ => (None, false),
+                };
+                drop(state_guard);
+                // A chunked download is a read once its last chunk is out
+                if last && data.is_some() {
+                    storage.log_access(std::path::Path::new(&path), &hash, &session.device, &client_addr, AccessKind::Chunked);
                 }
                 
-                if let Some(file_content) = state_guard.get_file(&path) {
-                    std::fs::write(&file_path, file_content)?;
+                let response = NetworkMessage::ChunkResponse { path, index, data };
+                stream.write_all(&network::encode_message(&response, encoding)?).await?;
+            }
+            
+            NetworkMessage::FileTransfer { path, content, metadata, idempotency_key } => {
+                println!("Legacy file transfer: {} ({} bytes)", path, content.len());
+                
+                // Windowed uploads are always answered
+                let results = windowed || negotiated.contains(&capabilities::Capability::UploadResults);
+                // A retried upload that was already handled gets the first answer
+                let seen = match &idempotency_key {
+                    Some(key) => state.read().await.idempotency.get(&session.device, key),
+                    None => None,
+                };
+                let first = seen.is_none();
+                let rejection = match seen {
+                    Some(rejection) => {
+                        println!("Upload of {} from {} was a retry, answered as before", path, client_addr);
+                        rejection
+                    }
+                    None => 'upload: {
+                        if let Some(rejection) = upload_refusal(&session, &path, &metadata, &client_addr) {
+                            break 'upload Some(rejection);
+                        }
+                        
+                        let rejection = match &storage.scanner {
+                            Some(scanner) => scanner.check(&content).await?,
+                            None => None,
+                        };
+                        if let Some(rejection) = rejection {
+                            let quarantined = storage.quarantine(&path, &content)?;
+                            eprintln!("Refused {} from {} ({}), quarantined as {}", path, client_addr, rejection, quarantined.display());
+                            break 'upload Some(rejection);
+                        }
+                        
+                        // Handle legacy file transfer (for backwards compatibility)
+                        let change = ServerChange { path: metadata.path.clone(), device_id: metadata.device_id.clone() };
+                        let mut state_guard = state.write().await;
+                        // Stored before it's listed, so it can be read once it is
+                        storage.write(&path, &content, &metadata.permissions).await?;
+                        storage.journal_upload(&metadata, state_guard.get_metadata(&path));
+                        state_guard.add_file(path.clone(), &content, metadata);
+                        // Nobody watching is fine
+                        let _ = changes.send(change);
+                        
+                        println!("File stored on VPS: {}", path);
+                        None
+                    }
+                };
+                if let (Some(key), true) = (idempotency_key, first) {
+                    state.write().await.idempotency.insert(&session.device, key, rejection.clone());
                 }
+                if results {
+                    let response = NetworkMessage::UploadResult { path, rejection };
+                    stream.write_all(&serde_json::to_vec(&response)?).await?;
+                }
+            }
+            
+            NetworkMessage::ListFiles { prefix, continuation, limit } => {
+                let response = match state.read().await.list_page(prefix.as_deref(), continuation.as_deref(), limit) {
+                    Some((mut files, continuation)) => {
+                        // Pages may come out short, the continuation still holds
+                        files.retain(|metadata| session.can_read(&metadata.path));
+                        NetworkMessage::FileList { files, continuation }
+                    }
+                    None => {
+                        eprintln!("Invalid listing continuation from {}", client_addr);
+                        NetworkMessage::FileList { files: Vec::new(), continuation: None }
+                    }
+                };
+                stream.write_all(&network::encode_message(&response, encoding)?).await?;
+            }
+            
+            NetworkMessage::Subscribe { prefixes } => {
+                println!("Client {} subscribed to {}", client_addr,
+                    if prefixes.is_empty() { "everything".to_string() } else { prefixes.join(", ") });
+                subscription = types::Subscription::new(&prefixes);
+            }
+            
+            NetworkMessage::SearchIndexRequest { known } => {
+                let known: HashMap<std::path::PathBuf, String> = known.into_iter().collect();
+                let state_guard = state.read().await;
+                let available: Vec<&search::SearchEntry> = state_guard.search_terms
+                    .values()
+                    .filter(|entry| subscription.includes(&entry.path) && session.can_read(&entry.path))
+                    .collect();
+                let entries: Vec<search::SearchEntry> = available.iter()
+                    .filter(|entry| known.get(&entry.path) != Some(&entry.hash))
+                    .map(|entry| (*entry).clone())
+                    .collect();
+                let available_paths: std::collections::HashSet<&std::path::PathBuf> =
+                    available.iter().map(|entry| &entry.path).collect();
+                let removed: Vec<std::path::PathBuf> = known.into_keys()
+                    .filter(|path| !available_paths.contains(path))
+                    .collect();
+                println!("Search index for {}: {} entries, {} removed", client_addr, entries.len(), removed.len());
                 
-                println!("File stored on VPS: {}", path);
+                let response = NetworkMessage::SearchIndexUpdate { entries, removed };
+                stream.write_all(&network::encode_message(&response, encoding)?).await?;
+            }
+            
+            NetworkMessage::WatchChanges { device_id } => {
+                if !negotiated.contains(&capabilities::Capability::Notifications) {
+                    eprintln!("Client {} asked for change notifications without negotiating them", client_addr);
+                    continue;
+                }
+                println!("Client {} is watching for changes", client_addr);
+                push_changes(&mut stream, &mut reader, changes.subscribe(), &subscription, &session.scope, &device_id).await?;
+                println!("Client disconnected: {}", client_addr);
+                break;
+            }
+            
+            NetworkMessage::ShareLinkRequest { path, expires_in } => {
+                let path = path.trim_matches('/').to_string();
+                let expires_in = std::time::Duration::from_secs(expires_in).min(share_links::MAX_LIFETIME);
+                let expires = chrono::Utc::now().timestamp() + expires_in.as_secs() as i64;
+                let claims = state.read().await.link_claims(&path, expires)
+                    .filter(|_| session.can_read(std::path::Path::new(&path)));
+                let (link, error) = match (&storage.share_links, claims) {
+                    (None, _) => (None, Some("This server doesn't serve share links".to_string())),
+                    (Some(_), None) => (None, Some(format!("{} is not on the server", path))),
+                    (Some(links), Some(claims)) => {
+                        println!("Client {} shared {} for {}s", client_addr, path, expires_in.as_secs());
+                        (Some(links.issue(&claims)?), None)
+                    }
+                };
+                let response = NetworkMessage::ShareLinkResponse { link, error };
+                stream.write_all(&network::encode_message(&response, encoding)?).await?;
+            }
+            
+            NetworkMessage::JournalRequest { after } => {
+                let batch = {
+                    let journal = storage.journal.lock().unwrap();
+                    // Entries compacted away are skipped, the batch says from where
+                    let after = after.max(journal.compacted_through()?);
+                    let mut entries = journal.journal_page(after, journal_replication::MAX_BATCH + 1)?;
+                    let more = entries.len() > journal_replication::MAX_BATCH;
+                    entries.truncate(journal_replication::MAX_BATCH);
+                    let through = entries.last().map_or(after, |entry| entry.seq);
+                    entries.retain(|entry| subscription.includes(&entry.path) && session.can_read(&entry.path));
+                    journal_replication::JournalBatch::encode(after, through, &entries, more)
+                };
+                let response = NetworkMessage::JournalResponse { batch };
+                stream.write_all(&network::encode_message(&response, encoding)?).await?;
+            }
+            
+            NetworkMessage::RenameDevice { old_name, new_name } => {
+                println!("Client {} renamed from {} to {} [{}]",
+                    client_addr, old_name, new_name, types::device_slug(&new_name));
+                if let Some(client_id) = &session.client_id {
+                    state.write().await.set_client_name(client_id, new_name);
+                }
             }
             
             NetworkMessage::Heartbeat => {
@@ -282,6 +1601,326 @@ async fn handle_client_connection(
     Ok(())
 }
 
+/// Why an upload of `path` from `session` is refused before its content is
+/// looked at, `None` if it may be stored.
+fn upload_refusal(
+    session: &ClientSession,
+    path: &str,
+    metadata: &types::FileMetadata,
+    client_addr: &str,
+) -> Option<network::UploadRejection> {
+    // Stored where it says, and nowhere outside the share or in the
+    // server's own state
+    if !types::is_contained(std::path::Path::new(path))
+        || state::is_state_path(std::path::Path::new(path))
+        || metadata.path != std::path::Path::new(path)
+    {
+        eprintln!("Refused {} from {}, not a path in the share", path, client_addr);
+        return Some(network::UploadRejection {
+            reason: network::RejectionReason::Forbidden,
+            detail: format!("{} is not a path in the share", path),
+        });
+    }
+    if !session.scope.allows(std::path::Path::new(path), security::Access::Write) {
+        eprintln!("Refused {} from {}, its token doesn't allow writing there", path, client_addr);
+        return Some(network::UploadRejection {
+            reason: network::RejectionReason::Forbidden,
+            detail: format!("{} may not write {}", session.device, path),
+        });
+    }
+    None
+}
+
+/// Answers share link downloads until the listener fails.
+async fn serve_share_links(listener: tokio::net::TcpListener, state: Arc<RwLock<ServerState>>, storage: Storage) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let state = state.clone();
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_link_request(stream, state, storage, addr.to_string()).await {
+                        eprintln!("Share link request error: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Accept error: {}", e),
+        }
+    }
+}
+
+/// Serves one share link: the file, or the directory as a tar archive, if
+/// it hasn't changed since the link was issued.
+async fn handle_link_request(
+    mut stream: tokio::net::TcpStream,
+    state: Arc<RwLock<ServerState>>,
+    storage: Storage,
+    address: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(links) = storage.share_links.clone() else {
+        return Ok(());
+    };
+    let Some((method, target)) = tokio::time::timeout(LINK_REQUEST_TIMEOUT, share_links::read_request(&mut stream)).await?? else {
+        return Ok(());
+    };
+    const TEXT: &str = "text/plain; charset=utf-8";
+    if method != "GET" {
+        share_links::respond(&mut stream, (405, "Method Not Allowed"), TEXT, None, b"Only GET is supported").await?;
+        return Ok(());
+    }
+    if refuse_locked_out(&mut stream, &state, address.parse::<std::net::SocketAddr>()?.ip()).await? {
+        return Ok(());
+    }
+    let claims = match links.verify(&target) {
+        Ok(claims) => claims,
+        Err(e) => {
+            // Guessing at signatures is guessing at a key
+            if e == share_links::LinkError::Invalid {
+                auth_failed(&state, &storage, &address, "share link", &types::SyncError::Auth(e.message().to_string())).await;
+            }
+            share_links::respond(&mut stream, e.status(), TEXT, None, e.message().as_bytes()).await?;
+            return Ok(());
+        }
+    };
+    let name = claims.path.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("share").to_string();
+
+    // What the link is for as it's stored now, empty when it changed since
+    let state_guard = state.read().await;
+    let files: Vec<(String, types::FileMetadata)> = if claims.directory {
+        let files = state_guard.files_under(&claims.path);
+        let version = share_links::directory_version(files.iter().map(|(path, metadata)| (*path, metadata.hash.as_str())));
+        match version == claims.version {
+            true => files.into_iter().map(|(path, metadata)| (path.to_string(), metadata.clone())).collect(),
+            false => Vec::new(),
+        }
+    } else {
+        state_guard.get_metadata(&claims.path)
+            .filter(|metadata| metadata.hash == claims.version)
+            .map(|metadata| vec![(claims.path.clone(), metadata.clone())])
+            .unwrap_or_default()
+    };
+    let content_hashes: Vec<String> = files.iter()
+        .map(|(path, _)| state_guard.content_hashes.get(path).cloned().unwrap_or_default())
+        .collect();
+    drop(state_guard);
+    let mut contents = Vec::with_capacity(files.len());
+    for ((path, _), content_hash) in files.iter().zip(&content_hashes) {
+        // Replaced since, the link no longer matches
+        match storage.read_version(path, content_hash).await? {
+            Some(content) => contents.push(content),
+            None => break,
+        }
+    }
+
+    let served = if files.is_empty() || contents.len() < files.len() {
+        None
+    } else if claims.directory {
+        // Extracts into a directory named like the shared one
+        let entries: Vec<(String, &[u8])> = files.iter()
+            .zip(&contents)
+            .filter_map(|((path, _), content)| {
+                let relative = path.strip_prefix(claims.path.as_str())?.trim_start_matches('/');
+                Some((format!("{}/{}", name, relative), content.as_slice()))
+            })
+            .collect();
+        Some((share_links::tar_archive(&entries)?, "application/x-tar", format!("{}.tar", name)))
+    } else {
+        contents.pop().map(|content| (content, share_links::content_type(&claims.path), name))
+    };
+    let Some((body, content_type, filename)) = served else {
+        let refused = share_links::LinkError::Changed;
+        share_links::respond(&mut stream, refused.status(), TEXT, None, refused.message().as_bytes()).await?;
+        return Ok(());
+    };
+    for (_, metadata) in &files {
+        storage.log_access(&metadata.path, &metadata.hash, "share link", &address, AccessKind::Link);
+    }
+    println!("Share link download of {} from {}", claims.path, address);
+    share_links::respond(&mut stream, (200, "OK"), content_type, Some(&filename), &body).await?;
+    Ok(())
+}
+
+/// Answers file browser requests until the listener fails.
+async fn serve_browser(
+    listener: tokio::net::TcpListener,
+    state: Arc<RwLock<ServerState>>,
+    storage: Storage,
+    client_manager: Arc<ClientManager>,
+    tokens_path: std::path::PathBuf,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let state = state.clone();
+                let storage = storage.clone();
+                let client_manager = client_manager.clone();
+                let tokens_path = tokens_path.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_browser_request(stream, state, storage, client_manager, &tokens_path, addr.to_string()).await {
+                        eprintln!("File browser request error: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Accept error: {}", e),
+        }
+    }
+}
+
+/// Serves one file browser page or download, to requests carrying a valid
+/// token and only of what the token may read.
+async fn handle_browser_request(
+    mut stream: tokio::net::TcpStream,
+    state: Arc<RwLock<ServerState>>,
+    storage: Storage,
+    client_manager: Arc<ClientManager>,
+    tokens_path: &std::path::Path,
+    address: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(head) = tokio::time::timeout(LINK_REQUEST_TIMEOUT, share_links::read_head(&mut stream)).await?? else {
+        return Ok(());
+    };
+    const TEXT: &str = "text/plain; charset=utf-8";
+    const HTML: &str = "text/html; charset=utf-8";
+    // Pages are rendered from synced notes, nothing in them may run
+    const PAGE_HEADERS: [(&str, &str); 2] = [
+        ("Content-Security-Policy", "script-src 'none'; object-src 'none'"),
+        ("X-Content-Type-Options", "nosniff"),
+    ];
+    if head.method != "GET" {
+        share_links::respond(&mut stream, (405, "Method Not Allowed"), TEXT, None, b"The file browser is read-only").await?;
+        return Ok(());
+    }
+    let ip = address.parse::<std::net::SocketAddr>()?.ip();
+    if refuse_locked_out(&mut stream, &state, ip).await? {
+        return Ok(());
+    }
+    // Tokens are issued and revoked while the server runs
+    let checked = head.header("Authorization")
+        .and_then(browser::token_from_authorization)
+        .map(|token| client_manager.load_tokens(tokens_path).and_then(|_| client_manager.check_token(&token)));
+    let auth_token = match checked {
+        Some(Ok(auth_token)) => {
+            state.write().await.lockout.record_success(ip);
+            auth_token
+        }
+        failed => {
+            if let Some(Err(e)) = failed {
+                auth_failed(&state, &storage, &address, "file browser", &e).await;
+            }
+            let challenge = [("WWW-Authenticate", "Basic realm=\"syncmd\", charset=\"UTF-8\"")];
+            share_links::respond_with(&mut stream, (401, "Unauthorized"), TEXT, &challenge, b"Sign in with a device token as the password").await?;
+            return Ok(());
+        }
+    };
+    let share = storage.path.canonicalize().unwrap_or_else(|_| storage.path.clone());
+    let scope = auth_token.scope(&share);
+    let readable = |path: &str| scope.allows(std::path::Path::new(path), security::Access::Read);
+    let Some(request) = browser::BrowserRequest::parse(&head.target) else {
+        share_links::respond(&mut stream, (404, "Not Found"), TEXT, None, b"Not found").await?;
+        return Ok(());
+    };
+    let share_name = share.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| "share".to_string());
+
+    if let Some((metadata, content)) = read_current(&state, &storage, &request.path, |_| readable(&request.path)).await? {
+        storage.log_access(&metadata.path, &metadata.hash, &auth_token.client_name, &address, AccessKind::Browser);
+        let content_type = share_links::content_type(&request.path);
+        let name = request.path.rsplit('/').next().unwrap_or_default().replace(['"', '\\'], "_");
+        if content_type.starts_with("text/markdown") && !request.download {
+            let page = browser::note_page(&share_name, &request.path, &String::from_utf8_lossy(&content));
+            share_links::respond_with(&mut stream, (200, "OK"), HTML, &PAGE_HEADERS, page.as_bytes()).await?;
+        } else {
+            let disposition = format!("{}; filename=\"{}\"", if request.download { "attachment" } else { "inline" }, name);
+            // Shown as is, an SVG could run scripts with the token's access
+            let headers = [
+                ("Content-Disposition", disposition.as_str()),
+                ("X-Content-Type-Options", "nosniff"),
+                ("Content-Security-Policy", "sandbox"),
+            ];
+            share_links::respond_with(&mut stream, (200, "OK"), content_type, &headers, &content).await?;
+        }
+        return Ok(());
+    }
+
+    let state_guard = state.read().await;
+    let files: Vec<(&str, u64)> = state_guard.files_under(&request.path).into_iter()
+        .filter(|(path, _)| readable(path))
+        .map(|(path, metadata)| (path, metadata.size))
+        .collect();
+    if files.is_empty() && !request.path.is_empty() {
+        drop(state_guard);
+        share_links::respond(&mut stream, (404, "Not Found"), TEXT, None, b"Not found").await?;
+        return Ok(());
+    }
+    let page = browser::listing_page(&share_name, &request.path, &browser::entries(&request.path, files.into_iter()));
+    drop(state_guard);
+    share_links::respond_with(&mut stream, (200, "OK"), HTML, &PAGE_HEADERS, page.as_bytes()).await?;
+    Ok(())
+}
+
+/// Sends a `ChangeNotification` for every burst of changes to subscribed
+/// files, until the client disconnects. Heartbeats are still answered.
+async fn push_changes(
+    stream: &mut tokio::net::TcpStream,
+    reader: &mut network::MessageReader,
+    mut changes: tokio::sync::broadcast::Receiver<ServerChange>,
+    subscription: &types::Subscription,
+    scope: &security::Scope,
+    device_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+    
+    loop {
+        let first = tokio::select! {
+            message = reader.next(stream) => {
+                match message? {
+                    None => return Ok(()),
+                    Some(NetworkMessage::Heartbeat) => {
+                        stream.write_all(&serde_json::to_vec(&NetworkMessage::Heartbeat)?).await?;
+                    }
+                    Some(_) => eprintln!("Ignoring request on a change notification connection"),
+                }
+                continue;
+            }
+            change = changes.recv() => change,
+        };
+        
+        // A mirror uploads a folder file by file; wait for the rest
+        tokio::time::sleep(NOTIFICATION_BATCH_DELAY).await;
+        let mut received = vec![first];
+        loop {
+            match changes.try_recv() {
+                Ok(change) => received.push(Ok(change)),
+                Err(TryRecvError::Lagged(skipped)) => received.push(Err(RecvError::Lagged(skipped))),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => return Ok(()),
+            }
+        }
+        
+        let mut paths = std::collections::BTreeSet::new();
+        let mut lagged = false;
+        for change in received {
+            match change {
+                Ok(change) if change.device_id != device_id
+                    && subscription.includes(&change.path)
+                    && scope.allows(&change.path, security::Access::Read) => {
+                    paths.insert(change.path);
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => lagged = true,
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+        if paths.is_empty() && !lagged {
+            continue;
+        }
+        // Which paths were missed is unknown, so don't name any
+        let paths = if lagged { Vec::new() } else { paths.into_iter().collect() };
+        let notification = NetworkMessage::ChangeNotification { paths };
+        stream.write_all(&serde_json::to_vec(&notification)?).await?;
+    }
+}
+
 fn calculate_sync_operations_for_client(
     client_files: &[types::FileMetadata],
     server_files: &[&types::FileMetadata],
@@ -298,11 +1937,10 @@ fn calculate_sync_operations_for_client(
     for client_file in client_files {
         if let Some(server_file) = server_file_map.get(&client_file.path) {
             // File exists on both sides
-            if client_file.hash != server_file.hash {
-                // Server version is newer
-                if server_file.version > client_file.version {
-                    operations.push(types::SyncOperation::Update((*server_file).clone()));
-                }
+            // Decided the way the client decides, so both agree on which
+            // side of a concurrent edit wins
+            if !client_file.same_version(server_file) && sync::SyncEngine::remote_wins(client_file, server_file) {
+                operations.push(types::SyncOperation::Update((*server_file).clone()));
             }
         } else {
             // File exists on server but not on client
@@ -323,4 +1961,334 @@ fn calculate_sync_operations_for_client(
     }
     
     operations
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A share in `dir` as the server opens it at start, files loaded from
+    /// storage and share links on.
+    async fn open_share(dir: &std::path::Path) -> (Arc<RwLock<ServerState>>, Storage) {
+        let backend: Arc<dyn storage::StorageBackend> = Arc::new(storage::FilesystemBackend::new(dir));
+        let state = Arc::new(RwLock::new(ServerState::new()));
+        load_existing_files(&state, backend.as_ref(), None, false).await.unwrap();
+        let storage = Storage {
+            path: dir.to_path_buf(),
+            backend,
+            seal: None,
+            scanner: None,
+            journal: Arc::new(std::sync::Mutex::new(IndexStore::open(dir).unwrap())),
+            access_log: None,
+            share_links: Some(Arc::new(share_links::ShareLinks::load_or_create(dir, 0).unwrap())),
+        };
+        (state, storage)
+    }
+
+    /// A connection to the share, authenticated.
+    async fn connect(state: Arc<RwLock<ServerState>>, storage: Storage, tokens: &std::path::Path) -> network::Connection {
+        connect_with_grants(state, storage, tokens, Vec::new()).await
+    }
+
+    /// A connection to the share with a token limited to `grants`.
+    async fn connect_with_grants(
+        state: Arc<RwLock<ServerState>>,
+        storage: Storage,
+        tokens: &std::path::Path,
+        grants: Vec<security::Grant>,
+    ) -> network::Connection {
+        let mut auth = security::AuthManager::new();
+        let token = auth.generate_token("c".to_string(), "laptop".to_string()).unwrap();
+        if !grants.is_empty() {
+            auth.set_grants(&token, grants);
+        }
+        auth.save_tokens(tokens).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let tokens = tokens.to_path_buf();
+        tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let client_manager = Arc::new(ClientManager::with_auth(security::AuthManager::new()));
+            let (changes, _) = tokio::sync::broadcast::channel(CHANGE_BACKLOG);
+            let _ = handle_client_connection(stream, state, client_manager, &tokens, changes, storage, addr.to_string()).await;
+        });
+        let mut stream = network::Connection::new(tokio::net::TcpStream::connect(address).await.unwrap());
+        let capabilities = vec![
+            capabilities::Capability::UploadResults,
+            capabilities::Capability::WindowedTransfer,
+            capabilities::Capability::Directories,
+        ];
+        let authenticate = NetworkMessage::Authenticate { token, client_name: "laptop".to_string(), capabilities };
+        stream.write_all(&serde_json::to_vec(&authenticate).unwrap()).await.unwrap();
+        let response = stream.read_message().await.unwrap();
+        assert!(matches!(response, NetworkMessage::AuthResponse { success: true, .. }));
+        stream
+    }
+
+    async fn request(stream: &mut network::Connection, message: NetworkMessage) -> NetworkMessage {
+        stream.write_all(&serde_json::to_vec(&message).unwrap()).await.unwrap();
+        stream.read_message().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_of_the_listed_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        std::fs::create_dir_all(share.join("notes")).unwrap();
+        std::fs::write(share.join("notes/a.md"), "# A, first").unwrap();
+        let (state, storage) = open_share(&share).await;
+        let hash = state.read().await.get_metadata("notes/a.md").unwrap().hash.clone();
+        let mut stream = connect(state, storage.clone(), &dir.path().join("tokens.json")).await;
+
+        let file = |path: &str| NetworkMessage::FileRequest { path: path.to_string() };
+        let chunk = |hash: &str, index| NetworkMessage::ChunkRequest {
+            path: "notes/a.md".to_string(),
+            hash: hash.to_string(),
+            index,
+            chunk_size: 4,
+        };
+        match request(&mut stream, file("notes/a.md")).await {
+            NetworkMessage::FileResponse { found: true, content: Some(content), metadata: Some(metadata), .. } => {
+                assert_eq!((content.as_slice(), metadata.hash.as_str()), (&b"# A, first"[..], hash.as_str()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(request(&mut stream, chunk(&hash, 1)).await,
+            NetworkMessage::ChunkResponse { data: Some(data), .. } if data == b" fir"));
+        assert!(matches!(request(&mut stream, chunk("another", 0)).await, NetworkMessage::ChunkResponse { data: None, .. }));
+        assert!(matches!(request(&mut stream, file("../share/notes/a.md")).await, NetworkMessage::FileResponse { found: false, .. }));
+
+        // Stored but not listed yet, as an upload landing mid-read: neither
+        // version goes out under the other's metadata
+        storage.backend.put("notes/a.md", b"# A, second", None, &Default::default()).await.unwrap();
+        assert!(matches!(request(&mut stream, file("notes/a.md")).await, NetworkMessage::FileResponse { found: false, .. }));
+        assert!(storage.read_version("notes/a.md", blake3::hash(b"# A, first").to_hex().as_str()).await.unwrap().is_none());
+        assert!(storage.read_version("notes/a.md", blake3::hash(b"# A, second").to_hex().as_str()).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_uploads_into_state_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        std::fs::create_dir_all(&share).unwrap();
+        let (state, storage) = open_share(&share).await;
+        let mut stream = connect(state.clone(), storage, &dir.path().join("tokens.json")).await;
+
+        let index_db = share.join(".syncmd/index.db");
+        let before = std::fs::read(&index_db).unwrap();
+        let upload = NetworkMessage::FileTransfer {
+            path: ".syncmd/index.db".to_string(),
+            content: b"overwritten".to_vec(),
+            metadata: types::FileMetadata {
+                path: ".syncmd/index.db".into(),
+                hash: blake3::hash(b"overwritten").to_hex().to_string(),
+                size: 11,
+                modified: types::Timestamp::now(),
+                created: types::Timestamp::now(),
+                version: 1,
+                device_id: "laptop".to_string(),
+                versions: Default::default(),
+                permissions: Default::default(),
+            },
+            idempotency_key: None,
+        };
+        match request(&mut stream, upload).await {
+            NetworkMessage::UploadResult { rejection: Some(rejection), .. } => {
+                assert_eq!(rejection.reason, network::RejectionReason::Forbidden);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(std::fs::read(&index_db).unwrap(), before);
+        assert!(state.read().await.get_metadata(".syncmd/index.db").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_token_cannot_remove_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        std::fs::create_dir_all(share.join("notes")).unwrap();
+        std::fs::write(share.join("notes/a.md"), "# A").unwrap();
+        let (state, storage) = open_share(&share).await;
+        let metadata = state.read().await.get_metadata("notes/a.md").unwrap().clone();
+        state.write().await.directories.insert("notes".into());
+        let grants = vec!["=r".parse().unwrap()];
+        let mut stream = connect_with_grants(state.clone(), storage, &dir.path().join("tokens.json"), grants).await;
+
+        // The device dropped the file and its directory since its last sync
+        let sync = NetworkMessage::SyncRequest {
+            client_id: "c".to_string(),
+            files: Vec::new(),
+            more: false,
+            scope: None,
+            directories: Some(network::DirectoryListing {
+                present: Default::default(),
+                known: [std::path::PathBuf::from("notes")].into(),
+            }),
+        };
+        match request(&mut stream, sync).await {
+            NetworkMessage::SyncResponse { operations, .. } => {
+                assert!(operations.iter().any(|operation| matches!(operation,
+                    types::SyncOperation::Add(added) if added.path == metadata.path)));
+                assert!(!operations.iter().any(|operation| matches!(operation, types::SyncOperation::RmDir(_))));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Nor can it empty the file
+        let upload = NetworkMessage::FileTransfer {
+            path: "notes/a.md".to_string(),
+            content: Vec::new(),
+            metadata: types::FileMetadata { hash: blake3::hash(b"").to_hex().to_string(), size: 0, ..metadata.clone() },
+            idempotency_key: None,
+        };
+        match request(&mut stream, upload).await {
+            NetworkMessage::UploadResult { rejection: Some(rejection), .. } => {
+                assert_eq!(rejection.reason, network::RejectionReason::Forbidden);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(std::fs::read(share.join("notes/a.md")).unwrap(), b"# A");
+        let state_guard = state.read().await;
+        assert_eq!(state_guard.get_metadata("notes/a.md").unwrap().hash, metadata.hash);
+        assert!(state_guard.directories.contains(std::path::Path::new("notes")));
+    }
+
+    #[tokio::test]
+    async fn test_windowed_upload_and_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        let laptop = dir.path().join("laptop");
+        std::fs::create_dir_all(&share).unwrap();
+        let (state, storage) = open_share(&share).await;
+        let mut stream = connect(state.clone(), storage, &dir.path().join("tokens.json")).await;
+        let negotiated = [capabilities::Capability::UploadResults, capabilities::Capability::WindowedTransfer];
+
+        let content: Vec<u8> = (0..file_transfer::WINDOWED_TRANSFER_SIZE as usize * 2 + 10).map(|i| (i % 251) as u8).collect();
+        let metadata = types::FileMetadata {
+            path: "talks/keynote.mp4".into(),
+            hash: blake3::hash(&content).to_hex().to_string(),
+            size: content.len() as u64,
+            modified: types::Timestamp::now(),
+            created: types::Timestamp::now(),
+            version: 1,
+            device_id: "laptop".to_string(),
+            versions: Default::default(),
+            permissions: Default::default(),
+        };
+        let rejection = network::upload_windowed(&mut stream, &metadata, content.clone(), None, &negotiated).await.unwrap();
+        assert!(rejection.is_none());
+        assert_eq!(std::fs::read(share.join("talks/keynote.mp4")).unwrap(), content);
+        assert_eq!(state.read().await.get_metadata("talks/keynote.mp4").unwrap().hash, metadata.hash);
+
+        let refused = types::FileMetadata { path: ".syncmd/index.db".into(), ..metadata.clone() };
+        let rejection = network::upload_windowed(&mut stream, &refused, content.clone(), None, &negotiated).await.unwrap();
+        assert_eq!(rejection.unwrap().reason, network::RejectionReason::Forbidden);
+
+        let destination = laptop.join("talks/keynote.mp4");
+        let downloaded = network::download_windowed(&mut stream, "talks/keynote.mp4", &laptop, &destination, &negotiated).await;
+        assert_eq!(downloaded.unwrap().unwrap(), content);
+        let missing = network::download_windowed(&mut stream, "talks/other.mp4", &laptop, &destination, &negotiated).await;
+        assert!(missing.unwrap().is_none());
+        assert!(!file_transfer::partial_path(&laptop, &metadata.hash).unwrap().exists());
+        assert!(!file_transfer::partial_path(&share, &metadata.hash).unwrap().exists());
+
+        // The connection carries on with ordinary requests
+        let listed = request(&mut stream, NetworkMessage::FileRequest { path: "talks/keynote.mp4".to_string() }).await;
+        assert!(matches!(listed, NetworkMessage::FileResponse { found: true, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_windowed_download_repairs_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        let laptop = dir.path().join("laptop");
+        std::fs::create_dir_all(share.join("talks")).unwrap();
+        let content: Vec<u8> = (0..file_transfer::WINDOWED_TRANSFER_SIZE as usize + 10).map(|i| (i % 241) as u8).collect();
+        std::fs::write(share.join("talks/keynote.mp4"), &content).unwrap();
+        let (state, storage) = open_share(&share).await;
+        let mut stream = connect(state, storage, &dir.path().join("tokens.json")).await;
+        let negotiated = [capabilities::Capability::WindowedTransfer];
+
+        // An earlier download got all of it, but one chunk rotted since
+        let partial = file_transfer::partial_path(&laptop, &blake3::hash(&content).to_hex()).unwrap();
+        std::fs::create_dir_all(partial.parent().unwrap()).unwrap();
+        let mut rotted = content.clone();
+        rotted[100_000] ^= 0xff;
+        std::fs::write(&partial, &rotted).unwrap();
+
+        let destination = laptop.join("talks/keynote.mp4");
+        let downloaded = network::download_windowed(&mut stream, "talks/keynote.mp4", &laptop, &destination, &negotiated).await;
+        assert_eq!(downloaded.unwrap().unwrap(), content);
+        assert!(!partial.exists());
+    }
+
+    #[tokio::test]
+    async fn test_share_link_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        std::fs::create_dir_all(share.join("notes")).unwrap();
+        std::fs::write(share.join("notes/a.md"), "# A").unwrap();
+        let (state, storage) = open_share(&share).await;
+        let expires = chrono::Utc::now().timestamp() + 3600;
+        let claims = state.read().await.link_claims("notes/a.md", expires).unwrap();
+        let link = storage.share_links.as_ref().unwrap().issue(&claims).unwrap();
+        drop((state, storage));
+
+        // Content and the link key come back from the share's folder
+        let (state, storage) = open_share(&share).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            handle_link_request(stream, state, storage, addr.to_string()).await.unwrap();
+        });
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", link.target).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("# A"));
+    }
+
+    #[tokio::test]
+    async fn test_browser_lockout() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        std::fs::create_dir_all(&share).unwrap();
+        std::fs::write(share.join("a.md"), "# A").unwrap();
+        let (state, storage) = open_share(&share).await;
+        state.write().await.lockout = lockout::AuthLockout::new(2);
+        let tokens = dir.path().join("tokens.json");
+        let mut auth = security::AuthManager::new();
+        let token = auth.generate_token("c".to_string(), "laptop".to_string()).unwrap();
+        auth.save_tokens(&tokens).unwrap();
+
+        let get = |password: String| {
+            let (state, storage, tokens) = (state.clone(), storage.clone(), tokens.clone());
+            async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let address = listener.local_addr().unwrap();
+                tokio::spawn(async move {
+                    let (stream, addr) = listener.accept().await.unwrap();
+                    let client_manager = Arc::new(ClientManager::with_auth(security::AuthManager::new()));
+                    handle_browser_request(stream, state, storage, client_manager, &tokens, addr.to_string()).await.unwrap();
+                });
+                let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+                let credentials = base64::engine::general_purpose::STANDARD.encode(format!("me:{}", password));
+                let head = format!("GET /a.md HTTP/1.1\r\nHost: x\r\nAuthorization: Basic {}\r\n\r\n", credentials);
+                stream.write_all(head.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+        assert!(get(token.clone()).await.starts_with("HTTP/1.1 200"));
+        assert!(get("guess".to_string()).await.starts_with("HTTP/1.1 401"));
+        assert!(get("guess".to_string()).await.starts_with("HTTP/1.1 401"));
+        // Locked out, even with the right token
+        let response = get(token).await;
+        assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+        assert!(response.contains("Retry-After: "));
+    }
+}
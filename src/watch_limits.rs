@@ -0,0 +1,122 @@
+#![allow(dead_code)]
+
+//! Linux caps inotify watches per user (`fs.inotify.max_user_watches`) and
+//! a recursive watch needs one per directory. When a root has more
+//! directories than fit, the watcher polls the rest; this module works out
+//! what to tell the user about it.
+
+use std::fmt;
+use std::path::PathBuf;
+
+const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+
+/// What happened when a root didn't fit into the inotify limit.
+#[derive(Debug, Clone, Default)]
+pub struct WatchLimitReport {
+    /// `fs.inotify.max_user_watches`, when readable
+    pub limit: Option<u64>,
+    /// Watches held by all processes we can inspect, this one included
+    pub in_use: Option<u64>,
+    /// Directories in the root, each needing a watch
+    pub directories: usize,
+    pub watched: usize,
+    /// Top-most directories that are polled instead of watched
+    pub polled: Vec<PathBuf>,
+}
+
+impl WatchLimitReport {
+    /// Collects the system-wide numbers for a root that got `watched` of
+    /// its `directories` watched.
+    pub fn new(directories: usize, watched: usize, polled: Vec<PathBuf>) -> Self {
+        Self {
+            limit: read_limit(),
+            in_use: count_watches_in_use(),
+            directories,
+            watched,
+            polled,
+        }
+    }
+
+    /// A limit that fits every directory of the root on top of the
+    /// watches already in use, rounded up to a power of two.
+    pub fn suggested_limit(&self) -> Option<u64> {
+        let unwatched = self.directories.saturating_sub(self.watched) as u64;
+        let needed = self.in_use.or(self.limit)? + unwatched;
+        Some(needed.next_power_of_two().max(self.limit.unwrap_or(0) * 2))
+    }
+}
+
+impl fmt::Display for WatchLimitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: Option<u64>| value.map_or_else(|| "unknown".to_string(), |v| v.to_string());
+        writeln!(
+            f,
+            "inotify watch limit reached: watching {} of {} directories, polling {} subtree(s) for the rest",
+            self.watched,
+            self.directories,
+            self.polled.len()
+        )?;
+        write!(f, "  fs.inotify.max_user_watches = {}, in use: {}", show(self.limit), show(self.in_use))?;
+        if let Some(suggested) = self.suggested_limit() {
+            write!(f, "\n  Raise it with: sudo sysctl fs.inotify.max_user_watches={}", suggested)?;
+            write!(f, "\n  and persist it in /etc/sysctl.d/ to keep it after a reboot")?;
+        }
+        Ok(())
+    }
+}
+
+fn read_limit() -> Option<u64> {
+    std::fs::read_to_string(MAX_USER_WATCHES).ok()?.trim().parse().ok()
+}
+
+/// Sums the watches of every inotify instance in `/proc/*/fdinfo`. Other
+/// users' processes aren't readable, but their watches don't count against
+/// our limit anyway.
+fn count_watches_in_use() -> Option<u64> {
+    let mut total = 0;
+    for process in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Ok(descriptors) = std::fs::read_dir(process.path().join("fdinfo")) else {
+            continue;
+        };
+        for descriptor in descriptors.flatten() {
+            if let Ok(info) = std::fs::read_to_string(descriptor.path()) {
+                total += count_fdinfo_watches(&info);
+            }
+        }
+    }
+    Some(total)
+}
+
+/// Watches listed in one `fdinfo` file, zero for anything but inotify.
+fn count_fdinfo_watches(info: &str) -> u64 {
+    info.lines().filter(|line| line.starts_with("inotify wd:")).count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_limit_report() {
+        let fdinfo = "pos:\t0\nflags:\t02004000\nmnt_id:\t15\n\
+            inotify wd:2 ino:1a2b sdev:800001 mask:fc6 ignored_mask:0\n\
+            inotify wd:1 ino:1a2c sdev:800001 mask:fc6 ignored_mask:0\n";
+        assert_eq!(count_fdinfo_watches(fdinfo), 2);
+        assert_eq!(count_fdinfo_watches("pos:\t0\nflags:\t02\n"), 0);
+
+        let report = WatchLimitReport {
+            limit: Some(8192),
+            in_use: Some(8192),
+            directories: 12000,
+            watched: 7000,
+            polled: vec![PathBuf::from("/notes/archive")],
+        };
+        assert_eq!(report.suggested_limit(), Some(16384));
+        let message = report.to_string();
+        assert!(message.contains("watching 7000 of 12000 directories"));
+        assert!(message.contains("max_user_watches=16384"));
+
+        let unreadable = WatchLimitReport { directories: 10, ..Default::default() };
+        assert_eq!(unreadable.suggested_limit(), None);
+    }
+}
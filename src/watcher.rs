@@ -1,16 +1,114 @@
 #![allow(dead_code)]
 
-use crate::types::SyncError;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, Event, EventKind};
-use std::path::PathBuf;
+use crate::cli::Config;
+use crate::ignore::IgnoreRules;
+use crate::indexer::is_vcs_dir;
+use crate::open_files::{self, OpenFiles};
+use crate::types::{FileTypeFilter, PathSelection, SyncError};
+use crate::watch_limits::WatchLimitReport;
+use notify::{PollWatcher, RecommendedWatcher, RecursiveMode, Watcher, Event, EventKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
+/// Where the sync pipeline learns which files of a root changed. The
+/// daemon's own is a [`FileWatcher`] on notify; an embedder with file events
+/// of its own, such as an editor's plugin host, provides another, or feeds
+/// its events to a [`FileWatcher::from_events`].
+#[async_trait::async_trait]
+pub trait ChangeSource: Send {
+    /// The next absolute paths that changed and are ready to sync, `None`
+    /// once no more changes will come. Must be safe to cancel.
+    async fn next_changes(&mut self) -> Option<Vec<PathBuf>>;
+
+    /// Changes held back for now, for syncing them right away.
+    fn take_pending(&mut self) -> Vec<PathBuf>;
+}
+
+/// Builds the change source of a root, called whenever the daemon starts
+/// watching it.
+pub type WatcherFactory = Arc<dyn Fn(&Config, &Path) -> Result<Box<dyn ChangeSource>, SyncError> + Send + Sync>;
+
 pub struct FileWatcher {
-    watcher: RecommendedWatcher,
+    /// Unset for a watcher fed events from elsewhere
+    watcher: Option<RecommendedWatcher>,
     event_rx: mpsc::Receiver<WatchEvent>,
     debouncer: std::collections::HashMap<PathBuf, Instant>,
     debounce_duration: Duration,
+    coalescer: ChangeCoalescer,
+    include_vcs_dirs: bool,
+    root: PathBuf,
+    selection: PathSelection,
+    file_types: FileTypeFilter,
+    /// The indexer's ignore rules, so ignored files don't start a sync
+    ignore_rules: Vec<IgnoreRules>,
+    /// Watches subtrees that didn't fit into the inotify limit
+    poller: Option<PollWatcher>,
+    limit_report: Option<WatchLimitReport>,
+    /// Changes to these wait only [`PRIORITY_WINDOW`](crate::open_files::PRIORITY_WINDOW)
+    open_files: Option<OpenFiles>,
+}
+
+/// How often subtrees beyond the inotify limit are scanned for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// How often a daemon checks that its roots are still there
+pub const ROOT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Holds back changed files until they've been quiet for a while, so a
+/// burst of saves to one file becomes a single sync of its final state.
+#[derive(Debug, Clone)]
+pub struct ChangeCoalescer {
+    window: Duration,
+    /// When each waiting file becomes quiet
+    quiet_at: HashMap<PathBuf, Instant>,
+}
+
+impl ChangeCoalescer {
+    pub fn new(window: Duration) -> Self {
+        Self { window, quiet_at: HashMap::new() }
+    }
+
+    pub fn record(&mut self, path: PathBuf, at: Instant) {
+        self.record_within(path, at, self.window);
+    }
+
+    /// Records a change that waits `window` instead of the usual one, such
+    /// as a shorter one for a file open in an editor.
+    pub fn record_within(&mut self, path: PathBuf, at: Instant, window: Duration) {
+        self.quiet_at.insert(path, at + window);
+    }
+
+    /// When the next file becomes quiet, if any are waiting.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.quiet_at.values().min().copied()
+    }
+
+    /// Removes and returns the files that haven't changed for their window.
+    pub fn take_quiet(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut quiet: Vec<PathBuf> = self.quiet_at
+            .iter()
+            .filter(|(_, quiet_at)| now >= **quiet_at)
+            .map(|(path, _)| path.clone())
+            .collect();
+        quiet.sort();
+        for path in &quiet {
+            self.quiet_at.remove(path);
+        }
+        quiet
+    }
+
+    /// Removes and returns every waiting file, quiet or not.
+    pub fn take_all(&mut self) -> Vec<PathBuf> {
+        let mut all: Vec<PathBuf> = self.quiet_at.drain().map(|(path, _)| path).collect();
+        all.sort();
+        all
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.quiet_at.is_empty()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -27,43 +125,202 @@ impl FileWatcher {
     }
 
     pub fn with_debounce(watch_path: PathBuf, debounce_duration: Duration) -> Result<Self, SyncError> {
+        Self::with_options(watch_path, debounce_duration, false)
+    }
+
+    /// Events inside version control directories are dropped before they
+    /// reach the debouncer, unless `include_vcs_dirs` is set.
+    pub fn with_options(
+        watch_path: PathBuf,
+        debounce_duration: Duration,
+        include_vcs_dirs: bool,
+    ) -> Result<Self, SyncError> {
         let (event_tx, event_rx) = mpsc::channel(100);
         
         let mut watcher = RecommendedWatcher::new(
-            move |res: Result<Event, notify::Error>| {
-                if let Ok(event) = res {
-                    if let Some(path) = event.paths.first() {
-                        match event.kind {
-                            EventKind::Create(_) => {
-                                let _ = event_tx.blocking_send(WatchEvent::Created(path.clone()));
-                            }
-                            EventKind::Modify(_) => {
-                                let _ = event_tx.blocking_send(WatchEvent::Modified(path.clone()));
-                            }
-                            EventKind::Remove(_) => {
-                                let _ = event_tx.blocking_send(WatchEvent::Deleted(path.clone()));
-                            }
-                            _ => {
-                                // Handle other events
-                                if event.paths.len() > 1 {
-                                    let _ = event_tx.blocking_send(WatchEvent::Renamed(path.clone(), event.paths[1].clone()));
-                                }
-                            }
-                        }
-                    }
-                }
-            },
+            event_handler(event_tx.clone(), watch_path.clone(), include_vcs_dirs),
             notify::Config::default(),
         )?;
         
-        watcher.watch(&watch_path, RecursiveMode::Recursive)?;
+        let mut poller = None;
+        let mut limit_report = None;
+        match watcher.watch(&watch_path, RecursiveMode::Recursive) {
+            Ok(()) => {}
+            Err(e) if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) => {
+                // notify stops at the first directory over the limit without
+                // saying which ones made it, so start over one by one
+                let _ = watcher.unwatch(&watch_path);
+                let mut poll_watcher = PollWatcher::new(
+                    event_handler(event_tx, watch_path.clone(), include_vcs_dirs),
+                    notify::Config::default().with_poll_interval(POLL_INTERVAL),
+                )?;
+                let (directories, watched, polled) =
+                    watch_directories(&mut watcher, &mut poll_watcher, &watch_path, include_vcs_dirs)?;
+                poller = Some(poll_watcher);
+                limit_report = Some(WatchLimitReport::new(directories, watched, polled));
+            }
+            Err(e) => return Err(e.into()),
+        }
         
-        Ok(Self {
-            watcher,
+        let mut file_watcher = Self::with_receiver(watch_path, event_rx, debounce_duration, include_vcs_dirs);
+        file_watcher.watcher = Some(watcher);
+        file_watcher.poller = poller;
+        file_watcher.limit_report = limit_report;
+        Ok(file_watcher)
+    }
+
+    /// Watches a root with its settings from `config`: which events matter,
+    /// the coalescing window, and open files syncing first.
+    pub fn for_root(config: &Config, path: &Path) -> Result<Self, SyncError> {
+        let root = config.find_sync_root(path);
+        let include_vcs_dirs = root.is_some_and(|root| root.sync_vcs_dirs);
+        Ok(Self::with_options(path.to_path_buf(), Duration::from_millis(500), include_vcs_dirs)?
+            .with_coalescing(config.coalesce_window())
+            .with_open_files(OpenFiles::global().clone())
+            .with_selection(root.map(|root| root.selection()).unwrap_or_default())
+            .with_file_types(root.map(|root| root.file_types()).unwrap_or_default())
+            .with_ignore_rules(IgnoreRules::for_root(
+                path,
+                root.map(|root| root.ignore.as_slice()).unwrap_or_default(),
+                root.is_some_and(|root| root.respect_gitignore),
+            )?))
+    }
+
+    /// A watcher that watches nothing itself but gets its events through
+    /// the returned sender, with absolute paths under `root`. They are
+    /// filtered and coalesced like those of a watched root.
+    pub fn from_events(root: PathBuf) -> (Self, mpsc::Sender<WatchEvent>) {
+        let (event_tx, event_rx) = mpsc::channel(100);
+        (Self::with_receiver(root, event_rx, Duration::from_millis(500), false), event_tx)
+    }
+
+    fn with_receiver(
+        root: PathBuf,
+        event_rx: mpsc::Receiver<WatchEvent>,
+        debounce_duration: Duration,
+        include_vcs_dirs: bool,
+    ) -> Self {
+        Self {
+            watcher: None,
             event_rx,
             debouncer: std::collections::HashMap::new(),
             debounce_duration,
-        })
+            coalescer: ChangeCoalescer::new(Duration::ZERO),
+            include_vcs_dirs,
+            root,
+            selection: PathSelection::default(),
+            file_types: FileTypeFilter::default(),
+            ignore_rules: Vec::new(),
+            poller: None,
+            limit_report: None,
+            open_files: None,
+        }
+    }
+
+    /// Set when the root has more directories than inotify watches are
+    /// available, and some of it is polled instead.
+    pub fn limit_report(&self) -> Option<&WatchLimitReport> {
+        self.limit_report.as_ref()
+    }
+
+    /// Once over the limit, notify no longer reports failing to watch new
+    /// directories, so they are added here and polled if they don't fit.
+    fn watch_new_directory(&mut self, path: &Path) {
+        let (Some(watcher), Some(poller), Some(report)) = (self.watcher.as_mut(), self.poller.as_mut(), self.limit_report.as_mut()) else {
+            return;
+        };
+        if !path.is_dir() || report.polled.iter().any(|polled| path.starts_with(polled)) {
+            return;
+        }
+        match watch_directories(watcher, poller, path, self.include_vcs_dirs) {
+            Ok((directories, watched, polled)) => {
+                if !polled.is_empty() {
+                    tracing::warn!("Polling new directory {} for changes, the inotify limit is reached", path.display());
+                }
+                report.directories += directories;
+                report.watched += watched;
+                report.polled.extend(polled);
+            }
+            Err(e) => tracing::warn!("Failed to watch new directory {}: {}", path.display(), e),
+        }
+    }
+
+    /// Files wait this long without further changes before
+    /// [`next_changes`](Self::next_changes) reports them.
+    pub fn with_coalescing(mut self, window: Duration) -> Self {
+        self.coalescer = ChangeCoalescer::new(window);
+        self
+    }
+
+    /// Syncs changes to open files sooner, and takes editor lock files as a
+    /// sign that the file they stand for is open.
+    pub fn with_open_files(mut self, open_files: OpenFiles) -> Self {
+        self.open_files = Some(open_files);
+        self
+    }
+
+    /// Ignores changes outside the root's selected subfolders.
+    pub fn with_selection(mut self, selection: PathSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Ignores changes to files the root doesn't sync by type or size, the
+    /// same ones the indexer leaves out.
+    pub fn with_file_types(mut self, file_types: FileTypeFilter) -> Self {
+        self.file_types = file_types;
+        self
+    }
+
+    /// Ignores changes to files these rules leave out, see
+    /// [`IgnoreRules::for_root`].
+    pub fn with_ignore_rules(mut self, ignore_rules: Vec<IgnoreRules>) -> Self {
+        self.ignore_rules = ignore_rules;
+        self
+    }
+
+    /// The next files that changed and have since been quiet for the
+    /// coalescing window. Only files [`should_sync_event`](Self::should_sync_event)
+    /// accepts are counted. Safe to cancel: waiting files are kept.
+    pub async fn next_changes(&mut self) -> Option<Vec<PathBuf>> {
+        loop {
+            let deadline = self.coalescer.next_deadline();
+            tokio::select! {
+                event = self.event_rx.recv() => {
+                    let event = event?;
+                    if let WatchEvent::Created(path) = &event {
+                        self.watch_new_directory(&path.clone());
+                    }
+                    if let (Some(open_files), Some(target)) = (&self.open_files, open_files::lock_target(event_path(&event))) {
+                        open_files.report(&target, !matches!(event, WatchEvent::Deleted(_)));
+                    }
+                    if self.should_sync_event(&event) {
+                        let now = Instant::now();
+                        if let WatchEvent::Renamed(old, _) = &event {
+                            self.coalescer.record(old.clone(), now);
+                        }
+                        let path = event_path(&event).to_path_buf();
+                        match &self.open_files {
+                            Some(open_files) if open_files.is_open(&path) => {
+                                self.coalescer.record_within(path, now, open_files::PRIORITY_WINDOW);
+                            }
+                            _ => self.coalescer.record(path, now),
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {}
+            }
+            let quiet = self.coalescer.take_quiet(Instant::now());
+            if !quiet.is_empty() {
+                return Some(quiet);
+            }
+        }
+    }
+
+    /// Files still inside their coalescing window, for flushing them right
+    /// away on request or shutdown.
+    pub fn take_pending(&mut self) -> Vec<PathBuf> {
+        self.coalescer.take_all()
     }
     
     pub async fn next_event(&mut self) -> Option<WatchEvent> {
@@ -99,21 +356,24 @@ impl FileWatcher {
         }
     }
     
+    #[allow(clippy::ptr_arg)]
     pub fn watch_path(&mut self, path: &PathBuf) -> Result<(), SyncError> {
-        self.watcher.watch(path, RecursiveMode::Recursive)?;
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher.watch(path, RecursiveMode::Recursive)?;
+        }
         Ok(())
     }
     
+    #[allow(clippy::ptr_arg)]
     pub fn unwatch_path(&mut self, path: &PathBuf) -> Result<(), SyncError> {
-        self.watcher.unwatch(path)?;
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher.unwatch(path)?;
+        }
         Ok(())
     }
 
     pub fn should_sync_event(&self, event: &WatchEvent) -> bool {
-        let path = match event {
-            WatchEvent::Created(p) | WatchEvent::Modified(p) | WatchEvent::Deleted(p) => p,
-            WatchEvent::Renamed(_, new) => new,
-        };
+        let path = event_path(event);
 
         // Skip hidden files and directories
         if path.file_name()
@@ -124,31 +384,155 @@ impl FileWatcher {
             return false;
         }
 
-        // Check file extension
-        if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
-            match ext.to_lowercase().as_str() {
-                // Markdown files
-                "md" | "markdown" | "mdown" | "mkdn" | "mkd" | "mdwn" | "mdtxt" | "mdtext" | "text" => true,
-                // Image files
-                "jpg" | "jpeg" | "png" | "gif" | "svg" | "webp" | "bmp" | "ico" => true,
-                _ => false,
+        if let Ok(relative) = path.strip_prefix(&self.root) {
+            if !self.selection.includes(relative) {
+                return false;
+            }
+            if self.ignore_rules.iter().any(|rules| rules.is_ignored(relative, path.is_dir())) {
+                return false;
             }
-        } else {
-            false
+        }
+
+        if path.is_dir() || !self.file_types.accepts_type(path) {
+            return false;
+        }
+        // A deleted file has no size left to check
+        match std::fs::metadata(path) {
+            Ok(metadata) => self.file_types.over_limit(metadata.len()).is_none(),
+            Err(_) => true,
         }
     }
 
+    #[allow(clippy::ptr_arg)]
     pub fn get_relative_path(&self, path: &PathBuf, base_path: &PathBuf) -> Option<PathBuf> {
         path.strip_prefix(base_path).ok().map(|p| p.to_path_buf())
     }
 }
 
+#[async_trait::async_trait]
+impl ChangeSource for FileWatcher {
+    async fn next_changes(&mut self) -> Option<Vec<PathBuf>> {
+        FileWatcher::next_changes(self).await
+    }
+
+    fn take_pending(&mut self) -> Vec<PathBuf> {
+        FileWatcher::take_pending(self)
+    }
+}
+
+fn event_handler(
+    event_tx: mpsc::Sender<WatchEvent>,
+    root: PathBuf,
+    include_vcs_dirs: bool,
+) -> impl Fn(Result<Event, notify::Error>) + Send + 'static {
+    move |res: Result<Event, notify::Error>| {
+        if let Ok(event) = res {
+            if let Some(path) = event.paths.first() {
+                if !include_vcs_dirs && in_vcs_dir(&root, path) {
+                    return;
+                }
+                match event.kind {
+                    EventKind::Create(_) => {
+                        let _ = event_tx.blocking_send(WatchEvent::Created(path.clone()));
+                    }
+                    EventKind::Modify(_) => {
+                        let _ = event_tx.blocking_send(WatchEvent::Modified(path.clone()));
+                    }
+                    EventKind::Remove(_) => {
+                        let _ = event_tx.blocking_send(WatchEvent::Deleted(path.clone()));
+                    }
+                    _ => {
+                        // Handle other events
+                        if event.paths.len() > 1 {
+                            let _ = event_tx.blocking_send(WatchEvent::Renamed(path.clone(), event.paths[1].clone()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Watches every directory under `dir` on its own. Directories over the
+/// inotify limit are polled, subtree and all. Returns the number of
+/// directories, how many got a watch, and the polled subtrees.
+fn watch_directories(
+    watcher: &mut RecommendedWatcher,
+    poller: &mut PollWatcher,
+    dir: &Path,
+    include_vcs_dirs: bool,
+) -> Result<(usize, usize, Vec<PathBuf>), SyncError> {
+    let (mut directories, mut watched, mut polled) = (0, 0, Vec::new());
+    let mut walk = walkdir::WalkDir::new(dir).into_iter();
+    while let Some(entry) = walk.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        // Nothing synced lives in syncmd's own state, so it isn't worth a watch
+        if crate::state::is_state_dir(entry.path())
+            || (!include_vcs_dirs && is_vcs_dir(entry.file_name()))
+        {
+            walk.skip_current_dir();
+            continue;
+        }
+        directories += 1;
+        match watcher.watch(entry.path(), RecursiveMode::NonRecursive) {
+            Ok(()) => watched += 1,
+            Err(e) if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) => {
+                poller.watch(entry.path(), RecursiveMode::Recursive)?;
+                directories += walkdir::WalkDir::new(entry.path())
+                    .min_depth(1)
+                    .into_iter()
+                    .flatten()
+                    .filter(|inner| inner.file_type().is_dir())
+                    .count();
+                polled.push(entry.path().to_path_buf());
+                walk.skip_current_dir();
+            }
+            Err(e) => tracing::debug!("Not watching {}: {}", entry.path().display(), e),
+        }
+    }
+    Ok((directories, watched, polled))
+}
+
+/// Whether the root is there to be synced. notify's watch ends without a
+/// word when the root goes away, so callers check this and build a new
+/// watcher once it returns. An unmounted drive leaves an empty mount point
+/// behind, so a root that had `indexed` files and is now empty counts as
+/// gone too, rather than as everything deleted.
+pub fn root_present(root: &Path, indexed: usize) -> bool {
+    match std::fs::read_dir(root) {
+        Ok(mut entries) => indexed == 0 || entries.next().is_some(),
+        Err(_) => false,
+    }
+}
+
+/// The path an event leaves behind: the new one for renames.
+fn event_path(event: &WatchEvent) -> &Path {
+    match event {
+        WatchEvent::Created(p) | WatchEvent::Modified(p) | WatchEvent::Deleted(p) => p,
+        WatchEvent::Renamed(_, new) => new,
+    }
+}
+
+/// Whether `path` is, or is inside, a version control directory of `root`.
+fn in_vcs_dir(root: &Path, path: &Path) -> bool {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .any(|component| is_vcs_dir(component.as_os_str()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use tempfile::TempDir;
     
     #[tokio::test]
+    #[allow(clippy::single_match, clippy::collapsible_match)]
     async fn test_file_watcher() {
         let temp_dir = TempDir::new().unwrap();
         let watch_path = temp_dir.path().to_path_buf();
@@ -219,4 +603,112 @@ mod tests {
         }
         assert!(deleted_received, "Expected Deleted event for test file");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_vcs_events_are_dropped() {
+        let temp_dir = TempDir::new().unwrap();
+        let watch_path = temp_dir.path().to_path_buf();
+        let mut watcher = FileWatcher::new(watch_path.clone()).unwrap();
+
+        std::fs::create_dir(watch_path.join(".git")).unwrap();
+        std::fs::write(watch_path.join(".git/notes.md"), "# Not synced").unwrap();
+        std::fs::write(watch_path.join("note.md"), "# Synced").unwrap();
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), watcher.next_event()).await
+                .expect("Expected an event for note.md")
+                .unwrap();
+            let path = match event {
+                WatchEvent::Created(p) | WatchEvent::Modified(p) | WatchEvent::Deleted(p) => p,
+                WatchEvent::Renamed(_, new) => new,
+            };
+            assert!(!path.components().any(|c| c.as_os_str() == ".git"), "Unexpected event for {:?}", path);
+            if path.ends_with("note.md") {
+                break;
+            }
+        }
+        assert!(in_vcs_dir(Path::new("/root"), Path::new("/root/sub/.hg/store")));
+        assert!(!in_vcs_dir(Path::new("/root"), Path::new("/root/.github/notes.md")));
+    }
+
+    #[test]
+    fn test_change_coalescer() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut coalescer = ChangeCoalescer::new(Duration::from_secs(5));
+        assert_eq!(coalescer.next_deadline(), None);
+
+        // Ten saves of one file, one save of another
+        for second in 0..10 {
+            coalescer.record(PathBuf::from("draft.md"), at(second));
+        }
+        coalescer.record(PathBuf::from("todo.md"), at(2));
+        assert_eq!(coalescer.next_deadline(), Some(at(7)));
+        assert_eq!(coalescer.take_quiet(at(7)), vec![PathBuf::from("todo.md")]);
+        assert!(coalescer.take_quiet(at(13)).is_empty());
+        assert_eq!(coalescer.take_quiet(at(14)), vec![PathBuf::from("draft.md")]);
+
+        // A file open in an editor waits less
+        coalescer.record_within(PathBuf::from("open.md"), at(20), Duration::from_secs(1));
+        assert_eq!(coalescer.next_deadline(), Some(at(21)));
+        assert_eq!(coalescer.take_quiet(at(21)), vec![PathBuf::from("open.md")]);
+
+        coalescer.record(PathBuf::from("draft.md"), at(20));
+        assert_eq!(coalescer.take_all(), vec![PathBuf::from("draft.md")]);
+        assert!(coalescer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_events_from_embedder() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let (watcher, events) = FileWatcher::from_events(root.clone());
+        let mut source: Box<dyn ChangeSource> = Box::new(watcher.with_coalescing(Duration::from_millis(50)));
+        std::fs::write(root.join("a.md"), "a").unwrap();
+
+        events.send(WatchEvent::Modified(root.join("a.md"))).await.unwrap();
+        events.send(WatchEvent::Modified(root.join("a.md"))).await.unwrap();
+        // Filtered like a watched root's events
+        events.send(WatchEvent::Created(root.join(".hidden.md"))).await.unwrap();
+        events.send(WatchEvent::Deleted(root.join("old.md"))).await.unwrap();
+        assert_eq!(source.next_changes().await, Some(vec![root.join("a.md"), root.join("old.md")]));
+
+        events.send(WatchEvent::Modified(root.join("a.md"))).await.unwrap();
+        tokio::time::timeout(Duration::from_millis(20), source.next_changes()).await.unwrap_err();
+        assert_eq!(source.take_pending(), vec![root.join("a.md")]);
+        drop(events);
+        assert_eq!(source.next_changes().await, None);
+    }
+
+    #[test]
+    fn test_ignored_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        std::fs::write(root.join(crate::ignore::IGNORE_FILE_NAME), "!~$keep.md\n").unwrap();
+        let rules = IgnoreRules::for_root(&root, &["drafts/".to_string()], false).unwrap();
+        let (watcher, _events) = FileWatcher::from_events(root.clone());
+        let watcher = watcher.with_ignore_rules(rules);
+        let modified = |path: &str| watcher.should_sync_event(&WatchEvent::Modified(root.join(path)));
+
+        assert!(!modified(".obsidian/workspace.json"));
+        assert!(!modified("~$report.docx"));
+        assert!(!modified("notes.md~"));
+        assert!(!modified("drafts/idea.md"));
+        // .syncignore comes last and takes a default back
+        assert!(modified("~$keep.md"));
+        assert!(modified("notes.md"));
+    }
+
+    #[test]
+    fn test_root_present() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("drive");
+        assert!(!root_present(&root, 0));
+        std::fs::create_dir(&root).unwrap();
+        assert!(root_present(&root, 0));
+        // An empty mount point where indexed files used to be
+        assert!(!root_present(&root, 3));
+        std::fs::write(root.join("a.md"), "a").unwrap();
+        assert!(root_present(&root, 3));
+    }
+}
@@ -0,0 +1,233 @@
+#![allow(dead_code)]
+
+//! The WebSocket transport, for networks that only let HTTP and HTTPS out.
+//! A device connects with `--connect wss://sync.example.com` or a remote
+//! using the `websocket` transport, through the remote's HTTP proxy or the
+//! one in `HTTPS_PROXY`. Servers tell WebSocket upgrades from plain
+//! connections by their first bytes, so one port serves both, also behind
+//! a reverse proxy terminating TLS.
+//!
+//! The protocol isn't changed: its bytes travel in binary messages, and a
+//! relay on the loopback interface gives both sides an ordinary stream.
+//!
+//! A remote pinning a certificate fingerprint trusts exactly that
+//! certificate, self-signed ones included, instead of the web's roots.
+
+use crate::types::SyncError;
+use futures_util::{SinkExt, StreamExt};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// What the relay reads at once, and so the largest message it sends
+const RELAY_BUFFER: usize = 64 * 1024;
+
+/// Whether `address` is a `ws://` or `wss://` URL.
+pub fn is_url(address: &str) -> bool {
+    address.starts_with("ws://") || address.starts_with("wss://")
+}
+
+/// The `host:port` to open a connection to for `url`.
+pub fn authority(url: &str) -> Result<String, SyncError> {
+    let parsed = url::Url::parse(url).map_err(|e| SyncError::Network(format!("Invalid WebSocket URL {}: {}", url, e)))?;
+    let host = parsed.host_str().ok_or_else(|| SyncError::Network(format!("No host in {}", url)))?;
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    Ok(format!("{}:{}", host, port))
+}
+
+/// The HTTP proxy from `HTTPS_PROXY` or `https_proxy`, if set.
+pub fn proxy_from_env() -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy"]
+        .into_iter()
+        .find_map(|name| std::env::var(name).ok())
+        .filter(|proxy| !proxy.is_empty())
+}
+
+/// Upgrades `stream`, open to the host of `url`, to a WebSocket, with TLS
+/// for `wss://`, and returns the stream to speak the protocol over. With a
+/// `fingerprint`, the server's certificate must be the one it names.
+pub async fn upgrade(url: &str, stream: TcpStream, fingerprint: Option<&str>) -> Result<TcpStream, SyncError> {
+    let connector = match fingerprint {
+        Some(fingerprint) => {
+            let config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificate(parse_fingerprint(fingerprint)?)))
+                .with_no_client_auth();
+            Some(tokio_tungstenite::Connector::Rustls(Arc::new(config)))
+        }
+        None => None,
+    };
+    let (socket, _) = tokio_tungstenite::client_async_tls_with_config(url, stream, None, connector)
+        .await
+        .map_err(|e| SyncError::Network(format!("WebSocket handshake with {} failed: {}", url, e)))?;
+    relayed(socket).await
+}
+
+/// A SHA-256 certificate fingerprint as hex, with or without colons, as
+/// `openssl x509 -fingerprint -sha256` prints it.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], SyncError> {
+    let invalid = || SyncError::Network(format!("{} is not a SHA-256 fingerprint", fingerprint));
+    let digits: Vec<u8> = fingerprint.bytes().filter(|c| *c != b':' && !c.is_ascii_whitespace()).collect();
+    if digits.len() != 64 {
+        return Err(invalid());
+    }
+    let mut parsed = [0u8; 32];
+    for (byte, pair) in parsed.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(parsed)
+}
+
+/// Accepts the one certificate with this SHA-256 fingerprint. The server
+/// still has to prove it holds the certificate's key, rustls checks the
+/// handshake signature with it.
+struct PinnedCertificate([u8; 32]);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match Sha256::digest(&end_entity.0)[..] == self.0 {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure)),
+        }
+    }
+}
+
+/// Whether a connection a server accepted opens with an HTTP request, the
+/// start of a WebSocket upgrade, rather than a protocol message.
+pub async fn is_upgrade(stream: &TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    matches!(stream.peek(&mut first).await, Ok(1) if first[0] == b'G')
+}
+
+/// Completes the upgrade a client asked for and returns the stream to
+/// speak the protocol over.
+pub async fn accept(stream: TcpStream) -> Result<TcpStream, SyncError> {
+    let socket = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(|e| SyncError::Network(format!("WebSocket handshake failed: {}", e)))?;
+    relayed(socket).await
+}
+
+async fn relayed<S>(socket: WebSocketStream<S>) -> Result<TcpStream, SyncError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (near, far) = crate::network::loopback_pair().await?;
+    tokio::spawn(relay(socket, far));
+    Ok(near)
+}
+
+/// Forwards between the socket and `local` until either side closes.
+async fn relay<S>(socket: WebSocketStream<S>, local: TcpStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut socket_write, mut socket_read) = socket.split();
+    let (mut local_read, mut local_write) = local.into_split();
+    let outgoing = async {
+        let mut buffer = vec![0; RELAY_BUFFER];
+        loop {
+            let read = match local_read.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            if socket_write.send(Message::Binary(buffer[..read].to_vec())).await.is_err() {
+                return;
+            }
+        }
+        let _ = socket_write.close().await;
+    };
+    let incoming = async {
+        // Pings are answered by the socket itself
+        while let Some(Ok(message)) = socket_read.next().await {
+            match message {
+                Message::Binary(data) if local_write.write_all(&data).await.is_err() => break,
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        let _ = local_write.shutdown().await;
+    };
+    tokio::select! {
+        _ = outgoing => {}
+        _ = incoming => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_websocket_transport() {
+        assert_eq!(authority("wss://sync.example.com").unwrap(), "sync.example.com:443");
+        assert_eq!(authority("ws://127.0.0.1:8080/sync").unwrap(), "127.0.0.1:8080");
+
+        // An echo server taking plain connections and WebSocket upgrades on
+        // one port
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut stream = match is_upgrade(&stream).await {
+                        true => accept(stream).await.unwrap(),
+                        false => stream,
+                    };
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+
+        let url = format!("ws://{}/sync", address);
+        let stream = TcpStream::connect(authority(&url).unwrap()).await.unwrap();
+        let mut stream = upgrade(&url, stream, None).await.unwrap();
+        stream.write_all(b"{\"hello\":1}").await.unwrap();
+        let mut echoed = [0; 11];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"{\"hello\":1}");
+
+        let mut plain = TcpStream::connect(address).await.unwrap();
+        plain.write_all(b"{}").await.unwrap();
+        let mut echoed = [0; 2];
+        plain.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"{}");
+    }
+
+    #[test]
+    fn test_pinned_certificate() {
+        let certificate = rustls::Certificate(b"a self-signed certificate".to_vec());
+        let fingerprint: Vec<String> = Sha256::digest(&certificate.0).iter().map(|b| format!("{:02X}", b)).collect();
+        let pinned = PinnedCertificate(parse_fingerprint(&fingerprint.join(":")).unwrap());
+        let verify = |certificate: &rustls::Certificate| {
+            let name = rustls::ServerName::try_from("sync.example.com").unwrap();
+            pinned.verify_server_cert(certificate, &[], &name, &mut std::iter::empty(), &[], std::time::SystemTime::now())
+        };
+        assert!(verify(&certificate).is_ok());
+        assert!(verify(&rustls::Certificate(b"another certificate".to_vec())).is_err());
+        assert!(parse_fingerprint("AB:CD").is_err());
+
+        // Pinning needs TLS to check against
+        let mut remote = crate::remote::Remote::from_address("wss://sync.example.com");
+        remote.fingerprint = Some(fingerprint.concat().to_lowercase());
+        assert!(remote.check_supported().is_ok());
+        let mut plain = crate::remote::Remote::from_address("ws://sync.example.com");
+        plain.fingerprint = remote.fingerprint.clone();
+        assert!(plain.check_supported().is_err());
+    }
+}
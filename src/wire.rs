@@ -0,0 +1,277 @@
+#![allow(dead_code)]
+
+//! Binary frames for messages carrying file content. JSON writes bytes as
+//! arrays of numbers, about three times their size and slow to parse, so
+//! once both sides negotiated binary-frames such messages go out as bincode
+//! instead. A frame starts with a NUL byte, which JSON text never does, so
+//! a reader takes either kind as it comes:
+//!
+//! ```text
+//! 0x00 | flags: u8 | length: u32 big-endian | payload
+//! ```
+//!
+//! With the zstd flag set the payload is compressed.
+
+use crate::types::SyncError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+
+pub const FRAME_MARKER: u8 = 0;
+const FLAG_ZSTD: u8 = 1;
+const HEADER_LEN: usize = 6;
+/// Larger frames are refused rather than buffered
+pub const MAX_FRAME_LEN: usize = 1024 * 1024 * 1024;
+/// Larger JSON messages are refused rather than buffered. JSON carries
+/// metadata rather than content, a page of which stays well below this.
+pub const MAX_JSON_LEN: usize = 16 * 1024 * 1024;
+
+/// Serializes `message` as a frame, compressed when `compress` is set and
+/// that makes it smaller.
+pub fn encode_frame<T: Serialize>(message: &T, compress: bool) -> Result<Vec<u8>, SyncError> {
+    let payload = bincode::serialize(message).map_err(|e| SyncError::Network(format!("Could not encode message: {}", e)))?;
+    let (flags, payload) = match compress.then(|| crate::compression::compress(&payload)).flatten() {
+        Some(compressed) if compressed.len() < payload.len() => (FLAG_ZSTD, compressed),
+        _ => (0, payload),
+    };
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(SyncError::Network(format!("Message of {} bytes is too large to send", payload.len())));
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.push(FRAME_MARKER);
+    frame.push(flags);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Where a frame starts in `buffer`, if the next message is one.
+pub fn frame_start(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .filter(|start| buffer[*start] == FRAME_MARKER)
+}
+
+/// Decodes the frame at the start of `buffer`, with the number of bytes it
+/// took, or `None` if it hasn't fully arrived yet.
+pub fn decode_frame<T: DeserializeOwned>(buffer: &[u8]) -> Result<Option<(T, usize)>, SyncError> {
+    let Some((payload, used)) = frame_payload(buffer)? else {
+        return Ok(None);
+    };
+    let message = bincode::deserialize(&payload).map_err(|e| SyncError::Network(format!("Malformed binary message: {}", e)))?;
+    Ok(Some((message, used)))
+}
+
+/// A frame's payload and the number of bytes the frame took.
+pub type FramePayload<'a> = (Cow<'a, [u8]>, usize);
+
+/// The payload of the frame at the start of `buffer`, decompressed, with
+/// the number of bytes the frame took, or `None` if it hasn't fully arrived
+/// yet.
+pub fn frame_payload(buffer: &[u8]) -> Result<Option<FramePayload<'_>>, SyncError> {
+    let Some(header) = buffer.get(..HEADER_LEN) else {
+        return Ok(None);
+    };
+    let flags = header[1];
+    let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(SyncError::Network(format!("Refused a message of {} bytes", len)));
+    }
+    let Some(payload) = buffer.get(HEADER_LEN..HEADER_LEN + len) else {
+        return Ok(None);
+    };
+    let payload = match flags & FLAG_ZSTD != 0 {
+        true => Cow::Owned(crate::compression::decompress(payload)?),
+        false => Cow::Borrowed(payload),
+    };
+    Ok(Some((payload, HEADER_LEN + len)))
+}
+
+/// Decodes a frame's payload as `T` only if it is exactly one, so frames
+/// of different messages can be told apart, e.g. in protocol traces.
+pub fn decode_exact<T: DeserializeOwned>(payload: &[u8]) -> Option<T> {
+    use bincode::Options;
+
+    // The options `bincode::serialize` encodes with, plus the check
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(payload)
+        .ok()
+}
+
+/// Splits a stream of back-to-back messages, JSON or frames, which may
+/// arrive several to a read or spread across reads.
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    /// Size of the largest message taken, in place of the usual limits
+    limit: Option<usize>,
+}
+
+impl FrameReader {
+    /// Refuses messages larger than `limit`, and compressed ones, which
+    /// could expand far beyond it, or with `None` goes back to the usual
+    /// limits. For peers that haven't authenticated yet.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    fn json_limit(&self) -> usize {
+        self.limit.unwrap_or(MAX_JSON_LEN)
+    }
+
+    fn frame_limit(&self) -> usize {
+        self.limit.unwrap_or(MAX_FRAME_LEN)
+    }
+
+    /// Refuses the message starting the buffer once it is known to be
+    /// larger than allowed.
+    fn check_limits(&self) -> Result<(), SyncError> {
+        match frame_start(&self.buffer) {
+            Some(start) => {
+                let frame = &self.buffer[start..];
+                if self.limit.is_some() && frame.get(1).is_some_and(|flags| flags & FLAG_ZSTD != 0) {
+                    return Err(SyncError::Network("Refused a compressed message".to_string()));
+                }
+                match frame_len(frame) {
+                    Some(len) if len > self.frame_limit() => {
+                        Err(SyncError::Network(format!("Refused a message of {} bytes", len)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            None if self.buffer.len() > self.json_limit() => Err(SyncError::Network(format!(
+                "Refused a message of more than {} bytes",
+                self.json_limit()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The next message, or `None` once the peer has closed the connection.
+    /// JSON messages are read as `T`, frames as `B` and converted. Safe to
+    /// cancel, nothing read is lost.
+    pub async fn next<T, B, R>(&mut self, stream: &mut R) -> Result<Option<T>, SyncError>
+    where
+        T: DeserializeOwned,
+        B: DeserializeOwned + Into<T>,
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncReadExt;
+
+        let mut chunk = vec![0u8; 64 * 1024];
+        // Leftovers from the last call may already hold a whole message
+        let mut try_parse = !self.buffer.is_empty();
+        loop {
+            if try_parse {
+                if let Some(start) = frame_start(&self.buffer) {
+                    self.check_limits()?;
+                    if let Some((message, used)) = decode_frame::<B>(&self.buffer[start..])? {
+                        self.buffer.drain(..start + used);
+                        return Ok(Some(message.into()));
+                    }
+                } else {
+                    let mut messages = serde_json::Deserializer::from_slice(&self.buffer).into_iter::<T>();
+                    match messages.next() {
+                        Some(Ok(message)) => {
+                            let used = messages.byte_offset();
+                            self.buffer.drain(..used);
+                            return Ok(Some(message));
+                        }
+                        Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+                        // Only the unfinished message is buffered now
+                        _ => self.check_limits()?,
+                    }
+                }
+            }
+
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return if self.buffer.iter().all(u8::is_ascii_whitespace) {
+                    Ok(None)
+                } else {
+                    Err(SyncError::Network("Connection closed mid-message".to_string()))
+                };
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+            // JSON messages are objects, so one can only have finished if
+            // the data ends in a brace or a frame follows it; past the
+            // limit it is refused instead. Frames are checked once their
+            // length is in. Parsing only then keeps large messages, which
+            // arrive over many reads, from being parsed again and again
+            // from the start.
+            try_parse = match frame_start(&self.buffer) {
+                Some(start) => frame_len(&self.buffer[start..]).is_some(),
+                None => {
+                    chunk[..n].contains(&FRAME_MARKER)
+                        || self.buffer.len() > self.json_limit()
+                        || self.buffer.iter().rev().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'}')
+                }
+            };
+        }
+    }
+}
+
+/// The payload length of the frame at the start of `buffer`, once its
+/// header has arrived.
+fn frame_len(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .get(2..HEADER_LEN)
+        .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum Message {
+        Data {
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        },
+        Done,
+    }
+
+    #[tokio::test]
+    async fn test_frames_and_json_mix() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let frame = encode_frame(&Message::Data { data: data.clone() }, false).unwrap();
+        let json = serde_json::to_vec(&Message::Data { data: data.clone() }).unwrap();
+        assert!(frame.len() < data.len() + 32);
+        assert!(json.len() > data.len() * 3);
+        let compressed = encode_frame(&Message::Data { data: vec![7; 100_000] }, true).unwrap();
+        assert!(compressed.len() < 1000);
+
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&frame);
+        stream.extend_from_slice(&serde_json::to_vec(&Message::Done).unwrap());
+        stream.extend_from_slice(&compressed);
+        let mut reader = FrameReader::default();
+        let mut input = stream.as_slice();
+        assert_eq!(reader.next::<Message, Message, _>(&mut input).await.unwrap(), Some(Message::Data { data }));
+        assert_eq!(reader.next::<Message, Message, _>(&mut input).await.unwrap(), Some(Message::Done));
+        assert_eq!(reader.next::<Message, Message, _>(&mut input).await.unwrap(), Some(Message::Data { data: vec![7; 100_000] }));
+        assert_eq!(reader.next::<Message, Message, _>(&mut input).await.unwrap(), None);
+
+        // Cut off mid-frame
+        let mut reader = FrameReader::default();
+        let mut input = &frame[..frame.len() - 1];
+        assert!(reader.next::<Message, Message, _>(&mut input).await.is_err());
+
+        // Limited, large and compressed messages are refused before they
+        // are buffered in full, small ones still come through
+        let small = serde_json::to_vec(&Message::Data { data: vec![1, 2, 3] }).unwrap();
+        for (mut input, allowed) in [(frame.as_slice(), false), (&compressed, false), (&json, false), (&small, true)] {
+            let mut reader = FrameReader::default();
+            reader.set_limit(Some(1024));
+            assert_eq!(reader.next::<Message, Message, _>(&mut input).await.is_ok(), allowed);
+        }
+    }
+}